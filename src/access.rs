@@ -0,0 +1,321 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, info, warn};
+
+use crate::cluster::{self, Entries, SharedState};
+use crate::config::ServerEntry;
+use crate::history;
+use crate::provider::ServerProvider;
+
+/// How long to wait for the webhook to accept a request.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Users' requests for access to servers they cannot reach, and the access admins granted
+/// them for a while. Both are kept in a JSON file in the state directory, so they outlast
+/// restarts, and shared with the other nodes of a cluster, by user and server.
+#[derive(Debug)]
+pub struct AccessRequests {
+    path: PathBuf,
+    state: Mutex<AccessState>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct AccessState {
+    next_id: u64,
+    pending: Vec<AccessRequest>,
+    grants: Vec<AccessGrant>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessRequest {
+    pub id: u64,
+    pub user: String,
+    pub server: String,
+    pub reason: String,
+    /// Unix time the request was made.
+    pub requested: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub user: String,
+    pub server: String,
+    /// Unix time the access ends.
+    pub until: i64,
+    pub approved_by: String,
+}
+
+impl AccessRequests {
+    /// Loads the requests and grants from `path`, starting without any if it does not
+    /// exist. A file that cannot be read is logged and replaced on the next change.
+    pub fn load(path: PathBuf) -> Self {
+        let state = match history::read(&path) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Ignoring saved access requests: {:#}", e);
+                AccessState::default()
+            }
+        };
+        Self {
+            path,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Asks for `user` to be given access to `server`. Returns the request and whether it
+    /// is new, rather than one already waiting for the same server.
+    pub fn request(&self, user: &str, server: &str, reason: &str) -> (AccessRequest, bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state
+            .pending
+            .iter()
+            .find(|request| request.user == user && request.server == server)
+        {
+            return (pending.clone(), false);
+        }
+
+        state.next_id += 1;
+        let request = AccessRequest {
+            id: state.next_id,
+            user: user.to_string(),
+            server: server.to_string(),
+            reason: reason.to_string(),
+            requested: chrono::Utc::now().timestamp(),
+        };
+        state.pending.push(request.clone());
+        self.save(&state);
+        (request, true)
+    }
+
+    /// Requests waiting for an admin, oldest first.
+    pub fn pending(&self) -> Vec<AccessRequest> {
+        self.state.lock().unwrap().pending.clone()
+    }
+
+    /// Access that has not ended yet.
+    pub fn grants(&self) -> Vec<AccessGrant> {
+        let now = chrono::Utc::now().timestamp();
+        let state = self.state.lock().unwrap();
+        state
+            .grants
+            .iter()
+            .filter(|grant| grant.until > now)
+            .cloned()
+            .collect()
+    }
+
+    /// Gives the requester access for `duration`, replacing any access they already have
+    /// to the server.
+    pub fn approve(&self, id: u64, duration: Duration, by: &str) -> anyhow::Result<AccessGrant> {
+        let mut state = self.state.lock().unwrap();
+        let request = state.take(id)?;
+        let now = chrono::Utc::now().timestamp();
+        state.grants.retain(|grant| {
+            grant.until > now && (grant.user != request.user || grant.server != request.server)
+        });
+        let grant = AccessGrant {
+            user: request.user,
+            server: request.server,
+            until: now + duration.as_secs() as i64,
+            approved_by: by.to_string(),
+        };
+        state.grants.push(grant.clone());
+        self.save(&state);
+        Ok(grant)
+    }
+
+    pub fn deny(&self, id: u64) -> anyhow::Result<AccessRequest> {
+        let mut state = self.state.lock().unwrap();
+        let request = state.take(id)?;
+        self.save(&state);
+        Ok(request)
+    }
+
+    pub fn is_granted(&self, user: &str, server: &str) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.state
+            .lock()
+            .unwrap()
+            .grants
+            .iter()
+            .any(|grant| grant.user == user && grant.server == server && grant.until > now)
+    }
+
+    /// The servers `user` has been granted access to, for now.
+    pub fn granted(&self, user: &str) -> Vec<String> {
+        let now = chrono::Utc::now().timestamp();
+        self.state
+            .lock()
+            .unwrap()
+            .grants
+            .iter()
+            .filter(|grant| grant.user == user && grant.until > now)
+            .map(|grant| grant.server.clone())
+            .collect()
+    }
+
+    fn save(&self, state: &AccessState) {
+        if let Err(e) = history::write(&self.path, state) {
+            warn!("Failed to save access requests: {:#}", e);
+        }
+    }
+}
+
+impl SharedState for AccessRequests {
+    fn kind(&self) -> &'static str {
+        "access"
+    }
+
+    fn entries(&self) -> Entries {
+        self.state.lock().unwrap().entries()
+    }
+
+    fn replace(&self, seen: &Entries, entries: Entries) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let current = state.entries();
+        if current != *seen {
+            return false;
+        }
+        if entries == current {
+            return true;
+        }
+        let (mut pending, mut grants) = (Vec::new(), Vec::new());
+        for (key, value) in entries {
+            // Requests and grants are told apart by their key's prefix.
+            let (kind, rest) = key.split_once(':').unwrap_or_default();
+            let entry = Entries::from([(rest.to_string(), value)]);
+            match kind {
+                "request" => pending.extend(
+                    cluster::from_entries::<String, AccessRequest>(self.kind(), entry)
+                        .map(|(_, request)| request),
+                ),
+                "grant" => grants.extend(
+                    cluster::from_entries::<String, AccessGrant>(self.kind(), entry)
+                        .map(|(_, grant)| grant),
+                ),
+                _ => warn!("Ignoring shared access entry {}", key),
+            }
+        }
+        pending.sort_by_key(|request| request.id);
+        state.next_id = pending
+            .iter()
+            .map(|request| request.id)
+            .fold(state.next_id, u64::max);
+        state.pending = pending;
+        state.grants = grants;
+        self.save(&state);
+        true
+    }
+}
+
+impl AccessState {
+    /// The pending requests and grants, each keyed by its kind, user and server.
+    fn entries(&self) -> Entries {
+        let requests = self.pending.iter().map(|request| {
+            let key = format!("request:{}+{}", request.user, request.server);
+            (key, request)
+        });
+        let grants = self.grants.iter().map(|grant| {
+            let key = format!("grant:{}+{}", grant.user, grant.server);
+            (key, grant)
+        });
+        let mut entries = cluster::to_entries(requests);
+        entries.extend(cluster::to_entries(grants));
+        entries
+    }
+
+    fn take(&mut self, id: u64) -> anyhow::Result<AccessRequest> {
+        let i = self
+            .pending
+            .iter()
+            .position(|request| request.id == id)
+            .with_context(|| format!("No access request {id} is waiting"))?;
+        Ok(self.pending.remove(i))
+    }
+}
+
+/// Posts `request` to `url` as JSON in the background, logging failures.
+pub fn notify_webhook(url: String, request: AccessRequest) {
+    tokio::spawn(
+        async move {
+            let body = serde_json::json!({
+                "event": "access_requested",
+                "id": request.id,
+                "user": request.user,
+                "server": request.server,
+                "reason": request.reason,
+                "text": format!(
+                    "{} requests access to {}: {} (approve with `pukeko ctl approve {}`)",
+                    request.user, request.server, request.reason, request.id
+                ),
+            });
+            let result = async {
+                reqwest::Client::builder()
+                    .timeout(WEBHOOK_TIMEOUT)
+                    .build()?
+                    .post(&url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .body(body.to_string())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                anyhow::Ok(())
+            };
+            match result.await {
+                Ok(()) => info!("Sent access request {} to {}", request.id, url),
+                Err(e) => warn!(
+                    "Failed to send access request {} to {}: {:#}",
+                    request.id, url, e
+                ),
+            }
+        }
+        .in_current_span(),
+    );
+}
+
+/// Adds the servers users have been granted access to for now to those `inner` allows.
+pub struct GrantedServers {
+    inner: Arc<dyn ServerProvider>,
+    access: Arc<AccessRequests>,
+}
+
+impl GrantedServers {
+    pub fn new(inner: Arc<dyn ServerProvider>, access: Arc<AccessRequests>) -> Self {
+        Self { inner, access }
+    }
+}
+
+impl ServerProvider for GrantedServers {
+    fn servers(&self, user: &str) -> Vec<ServerEntry> {
+        let mut servers = self.inner.servers(user);
+        for name in self.access.granted(user) {
+            if servers.iter().any(|entry| entry.name == name) {
+                continue;
+            }
+            if let Some(entry) = self.inner.server(&name) {
+                servers.push(entry);
+            }
+        }
+        servers
+    }
+
+    fn server(&self, name: &str) -> Option<ServerEntry> {
+        self.inner.server(name)
+    }
+
+    fn can_access(&self, user: &str, server: &str) -> bool {
+        self.inner.can_access(user, server) || self.access.is_granted(user, server)
+    }
+
+    fn groups(&self, user: &str) -> Vec<String> {
+        self.inner.groups(user)
+    }
+
+    fn inventory(&self) -> Vec<ServerEntry> {
+        self.inner.inventory()
+    }
+}
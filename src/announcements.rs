@@ -0,0 +1,97 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Announcement;
+use crate::history;
+
+/// Announcements posted from the control socket, kept in a JSON file in the state
+/// directory until they end or an admin removes them.
+#[derive(Debug)]
+pub struct Announcements {
+    path: PathBuf,
+    posted: Mutex<Vec<PostedAnnouncement>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PostedAnnouncement {
+    pub id: u64,
+    #[serde(flatten)]
+    pub announcement: Announcement,
+}
+
+impl Announcements {
+    /// Loads the announcements from `path`, starting without any if it does not exist. A
+    /// file that cannot be read is logged and replaced on the next change.
+    pub fn load(path: PathBuf) -> Self {
+        let posted = match history::read(&path) {
+            Ok(posted) => posted,
+            Err(e) => {
+                warn!("Ignoring saved announcements: {:#}", e);
+                Vec::new()
+            }
+        };
+        Self {
+            path,
+            posted: Mutex::new(posted),
+        }
+    }
+
+    /// Posts `announcement`, returning the id it can be removed by.
+    pub fn post(&self, announcement: Announcement) -> anyhow::Result<u64> {
+        let now = chrono::Utc::now().timestamp();
+        if announcement.ended(now) {
+            anyhow::bail!("Announcement must end in the future");
+        }
+        let mut posted = self.posted.lock().unwrap();
+        let id = posted.iter().map(|posted| posted.id).max().unwrap_or(0) + 1;
+        posted.retain(|posted| !posted.announcement.ended(now));
+        posted.push(PostedAnnouncement { id, announcement });
+        self.save(&posted);
+        Ok(id)
+    }
+
+    /// Removes the announcement with `id`, returning whether there was one.
+    pub fn remove(&self, id: u64) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let mut posted = self.posted.lock().unwrap();
+        let removed = posted
+            .iter()
+            .any(|posted| posted.id == id && !posted.announcement.ended(now));
+        posted.retain(|posted| posted.id != id && !posted.announcement.ended(now));
+        self.save(&posted);
+        removed
+    }
+
+    /// Announcements that have not ended yet, including those still to be shown.
+    pub fn posted(&self) -> Vec<PostedAnnouncement> {
+        let now = chrono::Utc::now().timestamp();
+        self.posted
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|posted| !posted.announcement.ended(now))
+            .cloned()
+            .collect()
+    }
+
+    /// The announcements to show now, `configured` first.
+    pub fn shown(&self, configured: &[Announcement]) -> Vec<Announcement> {
+        let now = chrono::Utc::now().timestamp();
+        let posted = self.posted.lock().unwrap();
+        configured
+            .iter()
+            .chain(posted.iter().map(|posted| &posted.announcement))
+            .filter(|announcement| announcement.shown(now))
+            .cloned()
+            .collect()
+    }
+
+    fn save(&self, posted: &[PostedAnnouncement]) {
+        if let Err(e) = history::write(&self.path, &posted) {
+            warn!("Failed to save announcements: {:#}", e);
+        }
+    }
+}
@@ -0,0 +1,415 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{Context, bail};
+use russh::keys::PublicKey;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::history;
+
+/// At most this many keys wait for approval for each user, so a client cannot fill the
+/// list by offering keys. Further keys are refused until an admin has dealt with these,
+/// rather than dropping the oldest, which would let anyone flush the user's own.
+const MAX_PENDING_PER_USER: usize = 5;
+
+/// Most made-up requests kept for users that do not exist, the oldest forgotten first.
+const MAX_DECOYS: usize = 1000;
+
+/// Keys of known users that were not on file, waiting for an admin to approve them, and
+/// the keys approved that are not kept in a database. Both are kept in a JSON file in the
+/// state directory, so they outlast restarts.
+#[derive(Debug)]
+pub struct KeyApprovals {
+    path: PathBuf,
+    state: Mutex<ApprovalState>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ApprovalState {
+    next_id: u64,
+    pending: Vec<PendingKey>,
+    approved: Vec<ApprovedKey>,
+    /// Made up for users that do not exist, never shown to admins nor saved.
+    #[serde(skip)]
+    decoys: VecDeque<PendingKey>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingKey {
+    pub id: u64,
+    pub user: String,
+    /// The OpenSSH public key, without its comment.
+    pub key: String,
+    pub fingerprint: String,
+    /// Address the key was last seen from.
+    pub peer: Option<String>,
+    /// Unix time the key was last seen.
+    pub seen: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApprovedKey {
+    pub user: String,
+    /// The OpenSSH public key, without its comment.
+    pub key: String,
+}
+
+impl ApprovedKey {
+    pub fn fingerprint(&self) -> Option<String> {
+        PublicKey::from_openssh(&self.key)
+            .ok()
+            .map(|key| key.fingerprint(Default::default()).to_string())
+    }
+}
+
+impl KeyApprovals {
+    /// Loads the keys from `path`, starting without any if it does not exist. A file that
+    /// cannot be read is logged and replaced on the next change.
+    pub fn load(path: PathBuf) -> Self {
+        let state = match history::read(&path) {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Ignoring saved key approvals: {:#}", e);
+                ApprovalState::default()
+            }
+        };
+        Self {
+            path,
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Records that `user` logged in with `key`, which is not on file, from `peer`.
+    /// Returns the pending key and whether it is new, rather than seen before. Fails if
+    /// too many of the user's keys are waiting already.
+    pub fn record(
+        &self,
+        user: &str,
+        key: &PublicKey,
+        peer: Option<String>,
+    ) -> anyhow::Result<(PendingKey, bool)> {
+        let mut stripped = key.clone();
+        stripped.set_comment("");
+        let openssh = stripped.to_openssh()?;
+        let now = chrono::Utc::now().timestamp();
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending) = state
+            .pending
+            .iter_mut()
+            .find(|pending| pending.user == user && pending.key == openssh)
+        {
+            pending.peer = peer;
+            pending.seen = now;
+            let pending = pending.clone();
+            self.save(&state);
+            return Ok((pending, false));
+        }
+
+        let waiting = state.pending.iter().filter(|key| key.user == user).count();
+        if waiting >= MAX_PENDING_PER_USER {
+            bail!("{waiting} keys of {user} are already awaiting approval");
+        }
+        state.next_id += 1;
+        let pending = PendingKey {
+            id: state.next_id,
+            user: user.to_string(),
+            key: openssh,
+            fingerprint: key.fingerprint(Default::default()).to_string(),
+            peer,
+            seen: now,
+        };
+        state.pending.push(pending.clone());
+        self.save(&state);
+        Ok((pending, true))
+    }
+
+    /// Stands in for [`record`](Self::record) for a `user` that does not exist, so telling
+    /// them the key is awaiting approval does not give away which users do. The request
+    /// takes the next id as a real one would, and keeps it for the user and key, but
+    /// nothing waits for an admin. Fails as `record` would once the user has as many.
+    pub fn decoy(&self, user: &str, key: &PublicKey) -> anyhow::Result<PendingKey> {
+        let mut stripped = key.clone();
+        stripped.set_comment("");
+        let openssh = stripped.to_openssh()?;
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(decoy) = state
+            .decoys
+            .iter()
+            .find(|decoy| decoy.user == user && decoy.key == openssh)
+        {
+            return Ok(decoy.clone());
+        }
+        let waiting = state.decoys.iter().filter(|key| key.user == user).count();
+        if waiting >= MAX_PENDING_PER_USER {
+            bail!("{waiting} keys of {user} are already awaiting approval");
+        }
+        state.next_id += 1;
+        let decoy = PendingKey {
+            id: state.next_id,
+            user: user.to_string(),
+            key: openssh,
+            fingerprint: key.fingerprint(Default::default()).to_string(),
+            peer: None,
+            seen: chrono::Utc::now().timestamp(),
+        };
+        if state.decoys.len() >= MAX_DECOYS {
+            state.decoys.pop_front();
+        }
+        state.decoys.push_back(decoy.clone());
+        // Saved as a real request would be, so the next id is not handed out again.
+        self.save(&state);
+        Ok(decoy)
+    }
+
+    /// Keys waiting for an admin, oldest first.
+    pub fn pending(&self) -> Vec<PendingKey> {
+        self.state.lock().unwrap().pending.clone()
+    }
+
+    pub fn get(&self, id: u64) -> anyhow::Result<PendingKey> {
+        let state = self.state.lock().unwrap();
+        let i = state.position(id)?;
+        Ok(state.pending[i].clone())
+    }
+
+    /// Stops the key waiting, and unless it was `stored` elsewhere lets the user log in
+    /// with it from now on.
+    pub fn approve(&self, id: u64, stored: bool) -> anyhow::Result<PendingKey> {
+        let mut state = self.state.lock().unwrap();
+        let i = state.position(id)?;
+        let pending = state.pending.remove(i);
+        if !stored {
+            state.approved.push(ApprovedKey {
+                user: pending.user.clone(),
+                key: pending.key.clone(),
+            });
+        }
+        self.save(&state);
+        Ok(pending)
+    }
+
+    pub fn reject(&self, id: u64) -> anyhow::Result<PendingKey> {
+        let mut state = self.state.lock().unwrap();
+        let i = state.position(id)?;
+        let pending = state.pending.remove(i);
+        self.save(&state);
+        Ok(pending)
+    }
+
+    /// Whether an admin approved `key` for `user`, without it being stored elsewhere.
+    pub fn is_approved(&self, user: &str, key: &PublicKey) -> bool {
+        let mut stripped = key.clone();
+        stripped.set_comment("");
+        let Ok(openssh) = stripped.to_openssh() else {
+            return false;
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .approved
+            .iter()
+            .any(|approved| approved.user == user && approved.key == openssh)
+    }
+
+    /// Every key approved that is not stored elsewhere, by user.
+    pub fn approved_keys(&self) -> Vec<ApprovedKey> {
+        self.state.lock().unwrap().approved.clone()
+    }
+
+    /// Stops `user` logging in with the approved key that is `key` in OpenSSH form, or
+    /// has `key` as its fingerprint.
+    pub fn revoke(&self, user: &str, key: &str) -> anyhow::Result<ApprovedKey> {
+        let mut state = self.state.lock().unwrap();
+        let i = state
+            .approved
+            .iter()
+            .position(|approved| {
+                approved.user == user
+                    && (approved.key == key || approved.fingerprint().as_deref() == Some(key))
+            })
+            .with_context(|| format!("No approved key {key} of {user}"))?;
+        let approved = state.approved.remove(i);
+        self.save(&state);
+        Ok(approved)
+    }
+
+    /// Forgets the keys, approved or waiting, of users for whom `exists` is false, such as
+    /// those removed from the config. Returns the users whose keys were forgotten.
+    pub fn retain_users(&self, exists: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut removed: Vec<String> = state
+            .approved
+            .iter()
+            .map(|approved| &approved.user)
+            .chain(state.pending.iter().map(|pending| &pending.user))
+            .filter(|user| !exists(user))
+            .cloned()
+            .collect();
+        if removed.is_empty() {
+            return removed;
+        }
+        removed.sort();
+        removed.dedup();
+        state.approved.retain(|approved| exists(&approved.user));
+        state.pending.retain(|pending| exists(&pending.user));
+        self.save(&state);
+        removed
+    }
+
+    /// Keys approved for `user` that are not stored elsewhere, in OpenSSH form.
+    pub fn approved(&self, user: &str) -> Vec<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .approved
+            .iter()
+            .filter(|approved| approved.user == user)
+            .map(|approved| approved.key.clone())
+            .collect()
+    }
+
+    /// Lets `user` log in with `key` without it waiting for an admin, such as one the user
+    /// added from the menu.
+    pub fn add(&self, user: &str, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if !state
+            .approved
+            .iter()
+            .any(|approved| approved.user == user && approved.key == key)
+        {
+            state.approved.push(ApprovedKey {
+                user: user.to_string(),
+                key: key.to_string(),
+            });
+            self.save(&state);
+        }
+    }
+
+    /// Forgets `key` of `user`, whether approved or waiting. Returns whether it was found.
+    pub fn remove(&self, user: &str, key: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let before = state.approved.len() + state.pending.len();
+        state
+            .approved
+            .retain(|approved| approved.user != user || approved.key != key);
+        state
+            .pending
+            .retain(|pending| pending.user != user || pending.key != key);
+        let removed = state.approved.len() + state.pending.len() < before;
+        if removed {
+            self.save(&state);
+        }
+        removed
+    }
+
+    fn save(&self, state: &ApprovalState) {
+        if let Err(e) = history::write(&self.path, state) {
+            warn!("Failed to save key approvals: {:#}", e);
+        }
+    }
+}
+
+impl ApprovalState {
+    fn position(&self, id: u64) -> anyhow::Result<usize> {
+        self.pending
+            .iter()
+            .position(|pending| pending.id == id)
+            .with_context(|| format!("No key {id} is waiting for approval"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::{OsRng, RngCore};
+    use russh::keys::{Algorithm, PrivateKey};
+
+    use super::*;
+
+    fn approvals() -> KeyApprovals {
+        KeyApprovals::load(std::env::temp_dir().join(format!(
+            "pukeko-keys-{}-{}.json",
+            std::process::id(),
+            OsRng.next_u32()
+        )))
+    }
+
+    fn key() -> PublicKey {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+            .unwrap()
+            .public_key()
+            .clone()
+    }
+
+    #[test]
+    fn further_keys_are_refused_rather_than_flushing_waiting_ones() {
+        let approvals = approvals();
+        let first = approvals.record("alice", &key(), None).unwrap().0;
+        for _ in 1..MAX_PENDING_PER_USER {
+            approvals.record("alice", &key(), None).unwrap();
+        }
+        assert!(approvals.record("alice", &key(), None).is_err());
+        assert_eq!(approvals.get(first.id).unwrap(), first);
+        // A key already waiting is still seen again, and other users are not held up.
+        assert!(
+            !approvals
+                .record("alice", &first_key(&first), None)
+                .unwrap()
+                .1
+        );
+        assert!(approvals.record("bob", &key(), None).is_ok());
+        let _ = std::fs::remove_file(&approvals.path);
+    }
+
+    fn first_key(pending: &PendingKey) -> PublicKey {
+        PublicKey::from_openssh(&pending.key).unwrap()
+    }
+
+    #[test]
+    fn approved_keys_can_be_revoked_and_are_forgotten_with_their_user() {
+        let approvals = approvals();
+        let (alice, bob) = (key(), key());
+        let pending = approvals.record("alice", &alice, None).unwrap().0;
+        approvals.approve(pending.id, false).unwrap();
+        let pending = approvals.record("bob", &bob, None).unwrap().0;
+        approvals.approve(pending.id, false).unwrap();
+        assert!(approvals.is_approved("alice", &alice));
+
+        let fingerprint = alice.fingerprint(Default::default()).to_string();
+        assert!(approvals.revoke("bob", &fingerprint).is_err());
+        assert_eq!(
+            approvals.revoke("alice", &fingerprint).unwrap().user,
+            "alice"
+        );
+        assert!(!approvals.is_approved("alice", &alice));
+
+        assert_eq!(approvals.retain_users(|user| user != "bob"), ["bob"]);
+        assert!(!approvals.is_approved("bob", &bob));
+        assert!(approvals.approved_keys().is_empty());
+        let _ = std::fs::remove_file(&approvals.path);
+    }
+
+    #[test]
+    fn unknown_users_get_requests_like_real_ones_that_wait_for_no_one() {
+        let approvals = approvals();
+        let (real, decoy) = (key(), key());
+        let first = approvals.record("alice", &real, None).unwrap().0;
+        let made_up = approvals.decoy("mallory", &decoy).unwrap();
+        assert_eq!(made_up.id, first.id + 1);
+        assert_eq!(approvals.decoy("mallory", &decoy).unwrap().id, made_up.id);
+        assert_eq!(
+            approvals.record("alice", &key(), None).unwrap().0.id,
+            made_up.id + 1
+        );
+        assert!(approvals.get(made_up.id).is_err());
+        assert_eq!(approvals.pending().len(), 2);
+
+        for _ in 1..MAX_PENDING_PER_USER {
+            approvals.decoy("mallory", &key()).unwrap();
+        }
+        assert!(approvals.decoy("mallory", &key()).is_err());
+    }
+}
@@ -0,0 +1,993 @@
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use data_encoding::{BASE64, HEXLOWER};
+use russh::keys::signature::{Signer, Verifier};
+use russh::keys::ssh_key::Signature;
+use russh::keys::{Algorithm, PrivateKey, PublicKey};
+use serde::Serialize;
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use tracing::{Instrument, debug, info, warn};
+
+use crate::config::{AuditConfig, InspectAction, WebhookConfig, WebhookEvent, WebhookFormat};
+use crate::geoip::Location;
+use crate::inspect::Direction;
+
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// `authpriv.info`, the facility sshd logs authentication to.
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u8 = 10 * 8 + 6;
+/// How long to wait for a webhook to accept an event.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How much of the end of the audit log is read for its last line when it is opened.
+const TAIL_READ: u64 = 64 * 1024;
+
+/// Security relevant events, written as one JSON object per line.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent<'a> {
+    ConnectionRejected {
+        peer: SocketAddr,
+        #[serde(flatten)]
+        location: Option<&'a Location>,
+        reason: String,
+    },
+    /// An address was banned after failing authentication too many times, for the
+    /// `count`th time in a row.
+    AddressBanned {
+        address: IpAddr,
+        duration_secs: u64,
+        count: u32,
+    },
+    Auth {
+        session: usize,
+        peer: Option<SocketAddr>,
+        #[serde(flatten)]
+        location: Option<&'a Location>,
+        user: &'a str,
+        method: &'a str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<String>,
+        accepted: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        attributes: Option<&'a BTreeMap<String, String>>,
+    },
+    /// A connection was let into the honeypot as `login`, for a decoy login or after too
+    /// many wrong passwords.
+    HoneypotEntered {
+        session: usize,
+        peer: Option<SocketAddr>,
+        #[serde(flatten)]
+        location: Option<&'a Location>,
+        login: &'a str,
+        method: &'a str,
+    },
+    /// A line typed in the honeypot, at its menu or on one of its fake `server`s, or a
+    /// command a client asked it to run.
+    HoneypotInput {
+        session: usize,
+        login: &'a str,
+        server: Option<&'a str>,
+        input: &'a str,
+    },
+    MenuSelection {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+    },
+    /// A user was refused outside their schedule, or `disconnected` as it closed.
+    OutsideSchedule {
+        session: usize,
+        user: &'a str,
+        schedule: &'a str,
+        disconnected: bool,
+    },
+    /// A plugin refused a user who had authenticated.
+    PluginRefused {
+        session: usize,
+        user: &'a str,
+        plugin: &'a str,
+        reason: &'a str,
+    },
+    /// A forward was refused, or with `disconnected` a session ended, for going over one
+    /// of the user's quotas.
+    QuotaExceeded {
+        session: usize,
+        user: &'a str,
+        server: Option<&'a str>,
+        quota: &'a str,
+        disconnected: bool,
+    },
+    AccessDenied {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+    },
+    /// A forward or jump refused by the authorization hook, or because it could not be
+    /// asked.
+    AuthorizationDenied {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+        kind: &'a str,
+        reason: &'a str,
+    },
+    /// A command, shell or subsystem refused by a server's allowed commands.
+    CommandDenied {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+        kind: &'a str,
+        command: Option<String>,
+    },
+    /// A user asked an admin for access to a server.
+    AccessRequested {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+        reason: &'a str,
+        request: u64,
+    },
+    /// A user without a TOTP secret was refused, logging in from a country outside
+    /// `trusted_countries`.
+    UntrustedCountry {
+        session: usize,
+        user: &'a str,
+        country: &'a str,
+    },
+    /// A known user logged in with, or added from the menu, a key that is not on file,
+    /// which waits for an admin to approve it.
+    KeyAwaitingApproval {
+        session: usize,
+        user: &'a str,
+        fingerprint: &'a str,
+        request: u64,
+    },
+    /// A user was issued a certificate with `pukeko-cert`, valid until `valid_before`
+    /// seconds past the epoch.
+    CertificateIssued {
+        session: usize,
+        user: &'a str,
+        fingerprint: &'a str,
+        serial: u64,
+        principals: &'a [String],
+        valid_before: u64,
+    },
+    /// A user `added` or removed one of their own keys from the menu.
+    KeyChanged {
+        session: usize,
+        user: &'a str,
+        fingerprint: &'a str,
+        added: bool,
+    },
+    ForwardStart {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+        kind: &'a str,
+        command: Option<String>,
+    },
+    ForwardEnd {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+        bytes_to_upstream: u64,
+        bytes_from_upstream: u64,
+        error: Option<String>,
+        /// How the command on the server ended, when it said.
+        exit_status: Option<u32>,
+        exit_signal: Option<String>,
+    },
+    /// An inspector found something in data forwarded between a client and a server.
+    ContentMatched {
+        session: usize,
+        user: &'a str,
+        server: &'a str,
+        rule: &'a str,
+        direction: Direction,
+        action: InspectAction,
+    },
+    /// A client started listening on a port of the bastion with `ssh -R`.
+    RemoteForward {
+        session: usize,
+        user: &'a str,
+        address: &'a str,
+        port: u32,
+    },
+    /// A session was disconnected for holding more of a resource than `limit` allows.
+    ResourceLimitExceeded {
+        session: usize,
+        user: &'a str,
+        limit: &'a str,
+        usage: usize,
+        max: usize,
+    },
+    SessionTerminated {
+        session: usize,
+        user: &'a str,
+        terminated: usize,
+        /// The node of the cluster the terminated session was on, when another.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        node: Option<&'a str>,
+    },
+    /// An admin started (`watching`) or stopped watching another session's output.
+    SessionShadowed {
+        session: usize,
+        user: &'a str,
+        shadowed: usize,
+        watching: bool,
+    },
+    Broadcast {
+        session: usize,
+        user: &'a str,
+        message: &'a str,
+    },
+    /// A change requested over the control socket.
+    ControlRequest {
+        method: &'a str,
+        params: &'a serde_json::Value,
+    },
+    /// A connection closed, having forwarded `bytes_up` from the client and `bytes_down`
+    /// to it.
+    Disconnect {
+        session: usize,
+        peer: Option<SocketAddr>,
+        #[serde(flatten)]
+        location: Option<&'a Location>,
+        reason: String,
+        bytes_up: u64,
+        bytes_down: u64,
+    },
+}
+
+impl AuditEvent<'_> {
+    /// The kind of event this is for webhooks, if they are sent it at all.
+    fn webhook_event(&self) -> Option<WebhookEvent> {
+        match self {
+            AuditEvent::Auth { accepted: true, .. } => Some(WebhookEvent::Login),
+            AuditEvent::ForwardStart { .. } => Some(WebhookEvent::ForwardStart),
+            AuditEvent::ForwardEnd { .. } => Some(WebhookEvent::ForwardEnd),
+            AuditEvent::AddressBanned { .. } => Some(WebhookEvent::AuthFailures),
+            AuditEvent::ContentMatched { .. } => Some(WebhookEvent::ContentMatched),
+            AuditEvent::SessionTerminated { .. }
+            | AuditEvent::SessionShadowed { .. }
+            | AuditEvent::Broadcast { .. }
+            | AuditEvent::ControlRequest { .. } => Some(WebhookEvent::Admin),
+            _ => None,
+        }
+    }
+
+    /// What happened, for webhooks without a template for the event.
+    fn describe(&self) -> String {
+        match self {
+            AuditEvent::Auth {
+                user,
+                peer,
+                location,
+                method,
+                ..
+            } => match (peer, location) {
+                (Some(peer), Some(location)) => {
+                    format!(
+                        "{user} logged in from {} ({location}) with {method}",
+                        peer.ip()
+                    )
+                }
+                (Some(peer), None) => {
+                    format!("{user} logged in from {} with {method}", peer.ip())
+                }
+                (None, _) => format!("{user} logged in with {method}"),
+            },
+            AuditEvent::ForwardStart { user, server, .. } => {
+                format!("{user} connected to {server}")
+            }
+            AuditEvent::ForwardEnd {
+                user,
+                server,
+                error: Some(error),
+                ..
+            } => format!("{user} was disconnected from {server}: {error}"),
+            AuditEvent::ForwardEnd { user, server, .. } => {
+                format!("{user} disconnected from {server}")
+            }
+            AuditEvent::AddressBanned {
+                address,
+                duration_secs,
+                count,
+            } => format!(
+                "Banned {address} for {duration_secs}s after repeated authentication \
+                 failures, ban {count} in a row"
+            ),
+            AuditEvent::ContentMatched {
+                user,
+                server,
+                rule,
+                direction,
+                action,
+                ..
+            } => {
+                let action = match action {
+                    InspectAction::Alert => "forwarded",
+                    InspectAction::Redact => "redacted",
+                    InspectAction::Block => "blocked",
+                };
+                format!(
+                    "{rule} matched {} between {user} and {server}, which was {action}",
+                    direction.name()
+                )
+            }
+            AuditEvent::SessionTerminated {
+                user,
+                terminated,
+                node: None,
+                ..
+            } => format!("{user} terminated session {terminated}"),
+            AuditEvent::SessionTerminated {
+                user,
+                terminated,
+                node: Some(node),
+                ..
+            } => format!("{user} terminated session {terminated} on {node}"),
+            AuditEvent::SessionShadowed {
+                user,
+                shadowed,
+                watching: true,
+                ..
+            } => format!("{user} started watching session {shadowed}"),
+            AuditEvent::SessionShadowed { user, shadowed, .. } => {
+                format!("{user} stopped watching session {shadowed}")
+            }
+            AuditEvent::Broadcast { user, message, .. } => {
+                format!("{user} messaged every session: {message}")
+            }
+            AuditEvent::ControlRequest { method, params } => {
+                format!("Control request {method} {params}")
+            }
+            event => format!("{event:?}"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a AuditEvent<'a>,
+}
+
+/// Receives each audit event. The config's file, syslog and webhooks are sinks, and others
+/// can be added with [`PukekoServerBuilder::audit_sink`](crate::PukekoServerBuilder::audit_sink)
+/// to route events into an existing pipeline, such as object storage or a message queue.
+pub trait AuditSink: Send + Sync {
+    /// `line` is the event as the JSON object written to the audit file, without the fields
+    /// chaining adds. Events are recorded in order, one at a time, so anything slow should
+    /// be done in the background.
+    fn record(&self, event: &AuditEvent, line: &str);
+}
+
+#[derive(Default)]
+pub struct AuditLog {
+    sinks: Mutex<Sinks>,
+    /// Sinks added by the embedder, kept when the config is reloaded.
+    added: Vec<Arc<dyn AuditSink>>,
+    /// Also sent every event, such as the deployment's log for a realm's.
+    parent: Option<Arc<AuditLog>>,
+}
+
+#[derive(Default)]
+struct Sinks {
+    /// Kept apart from the others to continue its chain when it is reopened.
+    file: Option<FileSink>,
+    others: Vec<Box<dyn AuditSink>>,
+}
+
+struct FileSink {
+    file: File,
+    /// Set when the file's lines are chained.
+    chain: Option<Mutex<Chain>>,
+}
+
+impl AuditSink for FileSink {
+    fn record(&self, event: &AuditEvent, line: &str) {
+        let Some(chain) = &self.chain else {
+            if let Err(e) = writeln!(&self.file, "{line}") {
+                debug!("Failed to write audit log: {:?}", e);
+            }
+            return;
+        };
+        let mut chain = chain.lock().unwrap();
+        let line = match chain.link(line.to_string()) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to sign audit event {:?}: {:?}", event, e);
+                return;
+            }
+        };
+        match writeln!(&self.file, "{line}") {
+            Ok(()) => chain.last = Some(line_hash(&line)),
+            Err(e) => debug!("Failed to write audit log: {:?}", e),
+        }
+    }
+}
+
+#[cfg(unix)]
+struct SyslogSink(UnixDatagram);
+
+#[cfg(unix)]
+impl SyslogSink {
+    fn connect() -> anyhow::Result<Self> {
+        let socket = UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(SYSLOG_SOCKET).map(|()| socket))
+            .with_context(|| format!("Failed to connect to syslog at {SYSLOG_SOCKET}"))?;
+        Ok(Self(socket))
+    }
+}
+
+#[cfg(unix)]
+impl AuditSink for SyslogSink {
+    fn record(&self, _event: &AuditEvent, line: &str) {
+        let message = format!(
+            "<{SYSLOG_PRIORITY}>{}[{}]: {line}",
+            env!("CARGO_PKG_NAME"),
+            std::process::id()
+        );
+        if let Err(e) = self.0.send(message.as_bytes()) {
+            debug!("Failed to send audit event to syslog: {:?}", e);
+        }
+    }
+}
+
+struct WebhookSink {
+    client: reqwest::Client,
+    webhooks: Vec<WebhookConfig>,
+}
+
+impl AuditSink for WebhookSink {
+    fn record(&self, event: &AuditEvent, line: &str) {
+        if let Some(kind) = event.webhook_event() {
+            send_webhooks(&self.client, &self.webhooks, kind, event, line);
+        }
+    }
+}
+
+/// Links each line of the audit log to the one before it by recording its hash as `prev`,
+/// empty for the first line of a log, and signs each line when there is a key, as the last
+/// field `signature`.
+#[derive(Debug)]
+struct Chain {
+    /// Hash of the last line written.
+    last: Option<String>,
+    key: Option<PrivateKey>,
+}
+
+impl Chain {
+    fn link(&self, mut line: String) -> anyhow::Result<String> {
+        line.pop();
+        line.push_str(&format!(
+            r#","prev":"{}"}}"#,
+            self.last.as_deref().unwrap_or_default()
+        ));
+        if let Some(key) = &self.key {
+            let signature = key.try_sign(line.as_bytes())?;
+            line.pop();
+            line.push_str(&format!(
+                r#","signature":"{}"}}"#,
+                BASE64.encode(signature.as_bytes())
+            ));
+        }
+        Ok(line)
+    }
+}
+
+fn line_hash(line: &str) -> String {
+    HEXLOWER.encode(&Sha256::digest(line.as_bytes()))
+}
+
+/// The last line of the file at `path`, read from its end as audit logs grow large.
+fn last_line(path: &Path) -> anyhow::Result<Option<String>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let length = file.metadata()?.len();
+    let mut start = length.saturating_sub(TAIL_READ);
+    loop {
+        file.seek(SeekFrom::Start(start))?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail)?;
+        let mut lines = tail.split(|&byte| byte == b'\n').rev();
+        let last = lines.find(|line| !line.is_empty());
+        // The last line started before the part read unless another line precedes it.
+        if start == 0 || lines.next().is_some() {
+            return Ok(last.map(|line| String::from_utf8_lossy(line).into_owned()));
+        }
+        start = start.saturating_sub(TAIL_READ * 16);
+    }
+}
+
+fn load_signing_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    let key = PrivateKey::read_openssh_file(path)
+        .with_context(|| format!("Failed to load audit signing key {}", path.display()))?;
+    if key.algorithm() != Algorithm::Ed25519 {
+        bail!("Audit signing key {} is not an ed25519 key", path.display());
+    }
+    Ok(key)
+}
+
+impl AuditLog {
+    pub fn new(added: Vec<Arc<dyn AuditSink>>) -> Self {
+        Self {
+            sinks: Mutex::default(),
+            added,
+            parent: None,
+        }
+    }
+
+    /// A log whose events are also recorded by `parent`.
+    pub fn within(parent: Arc<AuditLog>) -> Self {
+        Self {
+            sinks: Mutex::default(),
+            added: Vec::new(),
+            parent: Some(parent),
+        }
+    }
+
+    /// Whether any sink would receive an event.
+    fn has_sinks(&self) -> bool {
+        let sinks = self.sinks.lock().unwrap();
+        sinks.file.is_some()
+            || !sinks.others.is_empty()
+            || !self.added.is_empty()
+            || self
+                .parent
+                .as_ref()
+                .is_some_and(|parent| parent.has_sinks())
+    }
+
+    /// Opens the configured sinks, replacing any previous ones. Reopening the file on
+    /// every reload lets it be rotated with a SIGHUP.
+    pub fn configure(&self, config: &AuditConfig) -> anyhow::Result<()> {
+        let file = config
+            .file
+            .as_ref()
+            .map(|path| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("Failed to open audit log {}", path.display()))
+            })
+            .transpose()?;
+
+        let mut others: Vec<Box<dyn AuditSink>> = Vec::new();
+        // The config refuses syslog off Unix.
+        #[cfg(unix)]
+        if config.syslog {
+            others.push(Box::new(SyslogSink::connect()?));
+        }
+
+        let chain = (config.file.is_some() && (config.chain || config.signing_key.is_some()))
+            .then(|| {
+                let key = config
+                    .signing_key
+                    .as_deref()
+                    .map(load_signing_key)
+                    .transpose()?;
+                // A rotated log is continued from the last line of the one before.
+                let last = match config.file.as_deref().map(last_line).transpose()?.flatten() {
+                    Some(line) => Some(line_hash(&line)),
+                    None => self
+                        .sinks
+                        .lock()
+                        .unwrap()
+                        .file
+                        .as_ref()
+                        .and_then(|file| file.chain.as_ref())
+                        .and_then(|chain| chain.lock().unwrap().last.clone()),
+                };
+                anyhow::Ok(Mutex::new(Chain { last, key }))
+            })
+            .transpose()?;
+
+        let client = (!config.webhooks.is_empty())
+            .then(|| reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build())
+            .transpose()
+            .context("Failed to create the webhook client")?;
+
+        if let Some(path) = &config.file {
+            info!("Writing audit log to {}", path.display());
+        }
+        if let Some(client) = client {
+            others.push(Box::new(WebhookSink {
+                client,
+                webhooks: config.webhooks.clone(),
+            }));
+        }
+        *self.sinks.lock().unwrap() = Sinks {
+            file: file.map(|file| FileSink { file, chain }),
+            others,
+        };
+        Ok(())
+    }
+
+    pub fn record(&self, event: AuditEvent) {
+        if !self.has_sinks() {
+            return;
+        }
+
+        let record = AuditRecord {
+            timestamp: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            event: &event,
+        };
+        let line = match serde_json::to_string(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                debug!("Failed to serialize audit event {:?}: {:?}", event, e);
+                return;
+            }
+        };
+        self.send(&event, &line);
+    }
+
+    fn send(&self, event: &AuditEvent, line: &str) {
+        let sinks = self.sinks.lock().unwrap();
+        let sinks = sinks.file.iter().map(|file| file as &dyn AuditSink).chain(
+            sinks
+                .others
+                .iter()
+                .map(|sink| sink.as_ref())
+                .chain(self.added.iter().map(|sink| sink.as_ref())),
+        );
+        for sink in sinks {
+            sink.record(event, line);
+        }
+        if let Some(parent) = &self.parent {
+            parent.send(event, line);
+        }
+    }
+}
+
+/// What `verify` found in an audit log.
+#[derive(Debug)]
+pub struct Verified {
+    /// Lines that were chained, and signed when checked against a key.
+    pub lines: usize,
+    /// Lines at the start written before chaining was enabled.
+    pub unchained: usize,
+}
+
+/// Checks that each line of the audit log at `path` records the hash of the line before
+/// it and, given `key`, that it was signed with it. The first line must start a chain,
+/// with an empty `prev`, or follow the last line of `follows`, the log it was rotated
+/// from, so lines removed from the start are found too.
+///
+/// Lines at the start that are not chained were written before chaining was enabled.
+/// Without a key they are counted and skipped, as nothing can be checked about them. With
+/// one they are refused unless `allow_unchained`, as they could have been added since.
+pub fn verify(
+    path: &Path,
+    key: Option<&PublicKey>,
+    follows: Option<&Path>,
+    allow_unchained: bool,
+) -> anyhow::Result<Verified> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read audit log {}", path.display()))?;
+    let start = match follows {
+        Some(follows) => line_hash(
+            &last_line(follows)?
+                .with_context(|| format!("Audit log {} is empty", follows.display()))?,
+        ),
+        None => String::new(),
+    };
+    let mut verified = Verified {
+        lines: 0,
+        unchained: 0,
+    };
+    let mut previous: Option<&str> = None;
+    for (i, line) in text.lines().enumerate() {
+        let number = i + 1;
+        let fields: Map<String, Value> = serde_json::from_str(line)
+            .with_context(|| format!("Line {number} is not a JSON object"))?;
+        let prev = fields.get("prev").and_then(Value::as_str);
+        let signature = fields.get("signature").and_then(Value::as_str);
+        let chained = prev.is_some() || signature.is_some();
+        if !chained && verified.lines == 0 {
+            if key.is_some() && !allow_unchained {
+                bail!(
+                    "Line {number} is not chained, allow lines written before chaining was \
+                     enabled with --allow-unchained"
+                );
+            }
+            verified.unchained += 1;
+            previous = Some(line);
+            continue;
+        }
+
+        let Some(prev) = prev else {
+            bail!("Line {number} is not chained");
+        };
+        match previous {
+            Some(previous) if prev != line_hash(previous) => {
+                bail!("Line {number} does not follow line {}", number - 1)
+            }
+            None if prev != start => match follows {
+                Some(follows) => bail!(
+                    "Line {number} does not follow the last line of {}",
+                    follows.display()
+                ),
+                None => bail!(
+                    "Line {number} does not start the log, lines may have been removed before \
+                     it. Give the log it was rotated from with --follows"
+                ),
+            },
+            _ => {}
+        }
+        if let Some(key) = key {
+            let Some((signed, signature)) = signature
+                .zip(line.rfind(r#","signature":""#))
+                .map(|(signature, at)| (format!("{}}}", &line[..at]), signature))
+            else {
+                bail!("Line {number} is not signed");
+            };
+            let signature = BASE64
+                .decode(signature.as_bytes())
+                .ok()
+                .and_then(|bytes| Signature::new(Algorithm::Ed25519, bytes).ok())
+                .with_context(|| format!("Line {number} has an invalid signature"))?;
+            if Verifier::verify(key, signed.as_bytes(), &signature).is_err() {
+                bail!("The signature of line {number} does not match");
+            }
+        }
+        verified.lines += 1;
+        previous = Some(line);
+    }
+    Ok(verified)
+}
+
+/// Posts the event to each webhook that is sent its kind, in the background.
+fn send_webhooks(
+    client: &reqwest::Client,
+    webhooks: &[WebhookConfig],
+    kind: WebhookEvent,
+    event: &AuditEvent,
+    line: &str,
+) {
+    let Ok(fields) = serde_json::from_str::<Map<String, Value>>(line) else {
+        return;
+    };
+    for webhook in webhooks.iter().filter(|webhook| webhook.sends(kind)) {
+        let text = match webhook.templates.get(&kind) {
+            Some(template) => fill_template(template, &fields),
+            None => event.describe(),
+        };
+        let body = match webhook.format {
+            WebhookFormat::Json => {
+                let mut body = fields.clone();
+                body.insert("text".to_string(), Value::String(text));
+                Value::Object(body)
+            }
+            WebhookFormat::Slack => json!({ "text": text }),
+        };
+        let request = client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        // Webhook URLs often contain a secret, so only the host is logged.
+        let host = reqwest::Url::parse(&webhook.url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_default();
+        tokio::spawn(
+            async move {
+                let result = request
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status);
+                if let Err(e) = result {
+                    warn!(
+                        "Failed to send audit event to webhook at {}: {:#}",
+                        host,
+                        anyhow::Error::from(e.without_url())
+                    );
+                }
+            }
+            .in_current_span(),
+        );
+    }
+}
+
+/// Replaces each `{field}` in `template` with the value of the field. Unknown fields are
+/// left as they are.
+fn fill_template(template: &str, fields: &Map<String, Value>) -> String {
+    let mut text = template.to_string();
+    for (name, value) in fields {
+        let value = match value {
+            Value::String(value) => value.clone(),
+            Value::Null => String::new(),
+            value => value.to_string(),
+        };
+        text = text.replace(&format!("{{{name}}}"), &value);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use rand_core::{OsRng, RngCore};
+
+    use super::*;
+
+    /// A log in its own directory, written as the file sink would write it.
+    struct Log {
+        directory: PathBuf,
+        key: PrivateKey,
+    }
+
+    impl Log {
+        fn new() -> Self {
+            let directory = std::env::temp_dir().join(format!(
+                "pukeko-audit-{}-{}",
+                std::process::id(),
+                OsRng.next_u32()
+            ));
+            std::fs::create_dir_all(&directory).unwrap();
+            Self {
+                directory,
+                key: PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap(),
+            }
+        }
+
+        /// `count` signed lines chained on from `last`, numbered from `first`.
+        fn lines(&self, last: Option<&str>, first: usize, count: usize) -> Vec<String> {
+            let mut chain = Chain {
+                last: last.map(line_hash),
+                key: Some(self.key.clone()),
+            };
+            (first..first + count)
+                .map(|number| {
+                    let line = chain
+                        .link(format!(r#"{{"event":"login","user":"user{number}"}}"#))
+                        .unwrap();
+                    chain.last = Some(line_hash(&line));
+                    line
+                })
+                .collect()
+        }
+
+        fn write(&self, name: &str, lines: &[String]) -> PathBuf {
+            let path = self.directory.join(name);
+            let text: String = lines.iter().map(|line| format!("{line}\n")).collect();
+            std::fs::write(&path, text).unwrap();
+            path
+        }
+
+        fn verify(&self, path: &Path) -> anyhow::Result<Verified> {
+            verify(path, Some(self.key.public_key()), None, false)
+        }
+    }
+
+    impl Drop for Log {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[test]
+    fn verifies_an_intact_log() {
+        let log = Log::new();
+        let path = log.write("audit.jsonl", &log.lines(None, 1, 3));
+        let verified = log.verify(&path).unwrap();
+        assert_eq!((verified.lines, verified.unchained), (3, 0));
+        assert_eq!(verify(&path, None, None, false).unwrap().lines, 3);
+    }
+
+    #[test]
+    fn finds_changed_lines() {
+        let log = Log::new();
+        let mut lines = log.lines(None, 1, 3);
+        lines[1] = lines[1].replace("user2", "user9");
+        let path = log.write("audit.jsonl", &lines);
+        let error = log.verify(&path).unwrap_err().to_string();
+        assert_eq!(error, "The signature of line 2 does not match");
+        // Without the key, the next line no longer follows it.
+        let error = verify(&path, None, None, false).unwrap_err().to_string();
+        assert_eq!(error, "Line 3 does not follow line 2");
+    }
+
+    #[test]
+    fn finds_removed_lines() {
+        let log = Log::new();
+        let lines = log.lines(None, 1, 4);
+
+        let path = log.write("middle.jsonl", &[&lines[..1], &lines[2..]].concat());
+        let error = log.verify(&path).unwrap_err().to_string();
+        assert_eq!(error, "Line 2 does not follow line 1");
+
+        let path = log.write("start.jsonl", &lines[2..]);
+        let error = log.verify(&path).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Line 1 does not start the log"),
+            "{error}"
+        );
+        let error = verify(&path, None, None, false).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Line 1 does not start the log"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn finds_added_lines() {
+        let log = Log::new();
+        let lines = log.lines(None, 1, 2);
+
+        let forged = r#"{"event":"login","user":"mallory"}"#.to_string();
+        let path = log.write(
+            "unchained.jsonl",
+            &[vec![forged.clone()], lines.clone()].concat(),
+        );
+        let error = log.verify(&path).unwrap_err().to_string();
+        assert!(error.starts_with("Line 1 is not chained"), "{error}");
+
+        // A line chained by someone without the key is not signed.
+        let forged = Chain {
+            last: None,
+            key: None,
+        }
+        .link(forged)
+        .unwrap();
+        let path = log.write("chained.jsonl", &[vec![forged], lines].concat());
+        let error = log.verify(&path).unwrap_err().to_string();
+        assert_eq!(error, "Line 1 is not signed");
+    }
+
+    #[test]
+    fn allows_unchained_lines_when_asked() {
+        let log = Log::new();
+        let before = r#"{"event":"login","user":"before"}"#.to_string();
+        let lines = log.lines(Some(&before), 1, 2);
+        let path = log.write("audit.jsonl", &[vec![before], lines].concat());
+        let verified = verify(&path, Some(log.key.public_key()), None, true).unwrap();
+        assert_eq!((verified.lines, verified.unchained), (2, 1));
+        assert_eq!(verify(&path, None, None, false).unwrap().unchained, 1);
+    }
+
+    #[test]
+    fn follows_the_log_rotated_from() {
+        let log = Log::new();
+        let old = log.lines(None, 1, 2);
+        let new = log.lines(old.last().map(String::as_str), 3, 2);
+        let old = log.write("audit.jsonl.1", &old);
+        let new = log.write("audit.jsonl", &new);
+
+        let verified = verify(&new, Some(log.key.public_key()), Some(&old), false).unwrap();
+        assert_eq!(verified.lines, 2);
+        let error = log.verify(&new).unwrap_err().to_string();
+        assert!(
+            error.starts_with("Line 1 does not start the log"),
+            "{error}"
+        );
+
+        let other = log.write("other.jsonl", &log.lines(None, 1, 1));
+        let error = verify(&new, None, Some(&other), false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("Line 1 does not follow the last line of"),
+            "{error}"
+        );
+    }
+}
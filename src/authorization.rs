@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+use std::process::Stdio;
+
+use anyhow::{Context, bail};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::{AuthorizationConfig, AuthorizationHook};
+use crate::geoip::Location;
+
+/// A forward or jump about to start, or a certificate about to be issued for a server, as
+/// sent to the authorization hook.
+#[derive(Debug, Serialize)]
+pub struct AuthorizationRequest<'a> {
+    pub session: usize,
+    pub user: &'a str,
+    pub peer: Option<SocketAddr>,
+    #[serde(flatten)]
+    pub location: Option<&'a Location>,
+    pub server: &'a str,
+    pub host: &'a str,
+    pub port: u16,
+    pub group: Option<&'a str>,
+    pub tags: &'a [String],
+    /// `shell`, `exec`, `subsystem`, `jump` or `certificate`.
+    pub kind: &'a str,
+    pub command: Option<String>,
+    /// Whether the user logged in with a verification code as well as their key.
+    pub mfa: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// Allowed only to users who logged in with a verification code.
+    MfaRequired,
+}
+
+/// The hook's answer, with the reason given to users it denies.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Verdict {
+    pub decision: Decision,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+/// A verdict as answered, or wrapped in `result` as Open Policy Agent returns a rule's
+/// value.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Answer {
+    Verdict(Verdict),
+    Result { result: Verdict },
+}
+
+/// Asks the hook whether to allow `request`, failing if it does not answer in time or its
+/// answer is not a verdict.
+pub async fn authorize(
+    config: &AuthorizationConfig,
+    request: &AuthorizationRequest<'_>,
+) -> anyhow::Result<Verdict> {
+    let body = serde_json::to_vec(request)?;
+    let answer = match &config.hook {
+        AuthorizationHook::Exec { command } => {
+            tokio::time::timeout(config.timeout, run(command, &body)).await
+        }
+        AuthorizationHook::Http { url, headers } => {
+            let post = async {
+                let mut post = reqwest::Client::builder()
+                    .timeout(config.timeout)
+                    .build()?
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json");
+                for (name, value) in headers {
+                    post = post.header(name, value);
+                }
+                let answer = post
+                    .body(body)
+                    .send()
+                    .await
+                    .and_then(reqwest::Response::error_for_status)
+                    .with_context(|| format!("Failed to post to {url}"))?
+                    .bytes()
+                    .await?;
+                anyhow::Ok(answer.to_vec())
+            };
+            tokio::time::timeout(config.timeout, post).await
+        }
+    }
+    .map_err(|_| anyhow::anyhow!("Timed out after {:?}", config.timeout))??;
+
+    let answer: Answer = serde_json::from_slice(&answer).context("The answer is not a decision")?;
+    Ok(match answer {
+        Answer::Verdict(verdict) | Answer::Result { result: verdict } => verdict,
+    })
+}
+
+/// Runs `command` with `input` on its stdin, returning its output.
+async fn run(command: &[String], input: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let Some((program, args)) = command.split_first() else {
+        bail!("No command to run");
+    };
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .with_context(|| format!("Failed to run {program}"))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // A hook that decides without reading it closes its stdin early.
+        let _ = stdin.write_all(input).await;
+    }
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.trim() {
+            "" => bail!("{} exited with {}", program, output.status),
+            stderr => bail!("{} exited with {}: {}", program, output.status, stderr),
+        }
+    }
+    Ok(output.stdout)
+}
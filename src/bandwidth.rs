@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::config::{PukekoConfig, ServerEntry};
+
+/// Limits data to `rate` bytes per second, allowing bursts of up to a second's worth.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Goes negative when more is taken than is available, delaying whoever takes next.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits until `bytes` may be sent.
+    pub async fn take(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.updated).as_secs_f64() * self.rate as f64;
+            state.tokens = (state.tokens + refill).min(self.rate as f64) - bytes as f64;
+            state.updated = now;
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// The buckets shared between sessions, for the bastion as a whole, each user and each
+/// server. Buckets are replaced when their limit is changed by a reload.
+#[derive(Debug, Default)]
+pub struct BandwidthLimits {
+    total: Mutex<Option<Arc<TokenBucket>>>,
+    users: Mutex<HashMap<String, Arc<TokenBucket>>>,
+    servers: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl BandwidthLimits {
+    /// The limits that apply to a new forward by `user` to `server`.
+    pub fn forward(&self, config: &PukekoConfig, user: &str, server: &ServerEntry) -> Bandwidth {
+        let mut buckets = Vec::new();
+        if let Some(rate) = config.limits.session_bandwidth {
+            buckets.push(Arc::new(TokenBucket::new(rate)));
+        }
+        if let Some(rate) = config.user(user).and_then(|entry| entry.bandwidth) {
+            buckets.push(shared(&mut self.users.lock().unwrap(), user, rate));
+        }
+        if let Some(rate) = server.bandwidth {
+            buckets.push(shared(
+                &mut self.servers.lock().unwrap(),
+                &server.name,
+                rate,
+            ));
+        }
+        if let Some(rate) = config.limits.bandwidth {
+            let mut total = self.total.lock().unwrap();
+            let bucket = total
+                .take()
+                .filter(|bucket| bucket.rate == rate)
+                .unwrap_or_else(|| Arc::new(TokenBucket::new(rate)));
+            *total = Some(bucket.clone());
+            buckets.push(bucket);
+        }
+        Bandwidth { buckets }
+    }
+}
+
+fn shared(
+    buckets: &mut HashMap<String, Arc<TokenBucket>>,
+    name: &str,
+    rate: u64,
+) -> Arc<TokenBucket> {
+    match buckets.get(name) {
+        Some(bucket) if bucket.rate == rate => bucket.clone(),
+        _ => {
+            let bucket = Arc::new(TokenBucket::new(rate));
+            buckets.insert(name.to_string(), bucket.clone());
+            bucket
+        }
+    }
+}
+
+/// The buckets a forward's data passes through, in both directions.
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Bandwidth {
+    pub async fn take(&self, bytes: usize) {
+        for bucket in &self.buckets {
+            bucket.take(bytes).await;
+        }
+    }
+}
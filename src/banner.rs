@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+
+use crate::geoip::Location;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastLogin {
+    pub at: DateTime<Utc>,
+    pub from: Option<IpAddr>,
+}
+
+/// The most recent login of each user since the server started.
+#[derive(Debug, Default)]
+pub struct LastLogins {
+    logins: Mutex<HashMap<String, LastLogin>>,
+}
+
+impl LastLogins {
+    /// Records a login by `user`, returning the one before it.
+    pub fn record(&self, user: &str, from: Option<IpAddr>) -> Option<LastLogin> {
+        let login = LastLogin {
+            at: Utc::now(),
+            from,
+        };
+        self.logins.lock().unwrap().insert(user.to_string(), login)
+    }
+}
+
+/// Fills in `{user}`, `{source_ip}` and `{last_login}` in a banner template.
+pub fn render(
+    template: &str,
+    user: &str,
+    source_ip: Option<IpAddr>,
+    last_login: Option<&LastLogin>,
+) -> String {
+    let source_ip = source_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let last_login = match last_login {
+        Some(LastLogin {
+            at,
+            from: Some(from),
+        }) => {
+            format!("{} from {}", at.format("%Y-%m-%d %H:%M:%S UTC"), from)
+        }
+        Some(LastLogin { at, from: None }) => at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+        None => "never".to_string(),
+    };
+
+    template
+        .replace("{user}", user)
+        .replace("{source_ip}", &source_ip)
+        .replace("{last_login}", &last_login)
+}
+
+/// Fills in `{source_ip}`, `{location}` and `{date}` in the banner sent before login, when
+/// nothing is known of the user yet.
+pub fn render_pre_auth(
+    template: &str,
+    source_ip: Option<IpAddr>,
+    location: Option<&Location>,
+    now: DateTime<Utc>,
+) -> String {
+    let source_ip = source_ip.map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    let location = location.map_or_else(|| "unknown".to_string(), ToString::to_string);
+
+    template
+        .replace("{source_ip}", &source_ip)
+        .replace("{location}", &location)
+        .replace("{date}", &now.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+}
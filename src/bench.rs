@@ -0,0 +1,339 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use russh::keys::{PrivateKey, PrivateKeyWithHashAlg, PublicKey};
+use russh::{Channel, ChannelMsg, client};
+use tokio::task::JoinSet;
+
+/// Opens and closes the preferences with every key preset, redrawing the menu each time.
+const PREFERENCES: &[u8] = b"\x0f";
+
+/// How long output has to pause for the screen to count as drawn.
+const SETTLE: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchOptions {
+    /// Address of the pukeko to load, as host:port. Its host key is not checked.
+    pub target: String,
+    pub user: String,
+    /// Private key the clients authenticate with.
+    pub key: PathBuf,
+    /// Clients connected at once, each starting a new session as soon as one ends.
+    pub clients: usize,
+    /// How long clients keep starting sessions.
+    pub duration: Duration,
+    /// Keys sent to the menu in each session.
+    pub keystrokes: usize,
+    /// Server each session also connects straight to as `user+server`, to send traffic
+    /// through.
+    pub server: Option<String>,
+    /// Command run on the server, which has to echo its input back.
+    pub command: String,
+    /// Bytes sent through to the server in each session, a chunk at a time.
+    pub traffic: usize,
+    pub chunk: usize,
+    /// Longest wait for any one step of a session before it fails.
+    pub timeout: Duration,
+}
+
+impl Default for BenchOptions {
+    fn default() -> Self {
+        Self {
+            target: "127.0.0.1:22".to_string(),
+            user: String::new(),
+            key: PathBuf::new(),
+            clients: 10,
+            duration: Duration::from_secs(60),
+            keystrokes: 10,
+            server: None,
+            command: "cat".to_string(),
+            traffic: 1024 * 1024,
+            chunk: 16 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A step of a session whose latency is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    /// Connecting and authenticating.
+    Connect,
+    /// From asking for a shell to the menu being drawn.
+    Menu,
+    /// From sending a key to the menu redrawing.
+    Keystroke,
+    /// From sending a chunk to the server to all of it coming back.
+    Echo,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            Operation::Connect => "connect",
+            Operation::Menu => "menu",
+            Operation::Keystroke => "keystroke",
+            Operation::Echo => "echo",
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Sessions that went through every step.
+    pub sessions: u64,
+    /// Sessions that failed, by error.
+    pub failures: BTreeMap<String, u64>,
+    pub elapsed: Duration,
+    latencies: BTreeMap<Operation, Vec<Duration>>,
+}
+
+impl Report {
+    pub fn failed(&self) -> u64 {
+        self.failures.values().sum()
+    }
+
+    pub fn count(&self, operation: Operation) -> usize {
+        self.latencies.get(&operation).map_or(0, Vec::len)
+    }
+
+    /// The latency `percentile` (0 to 100) of `operation`s were at most.
+    pub fn percentile(&self, operation: Operation, percentile: f64) -> Option<Duration> {
+        let latencies = self.latencies.get(&operation)?;
+        let i = ((latencies.len() - 1) as f64 * percentile / 100.0).round() as usize;
+        latencies.get(i).copied()
+    }
+
+    fn record(&mut self, operation: Operation, latency: Duration) {
+        self.latencies.entry(operation).or_default().push(latency);
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} sessions in {:.1?}, {} failed",
+            self.sessions,
+            self.elapsed,
+            self.failed()
+        )?;
+        writeln!(
+            f,
+            "{:<10} {:>8} {:>10} {:>10} {:>10} {:>10}",
+            "", "count", "p50", "p90", "p99", "max"
+        )?;
+        for &operation in self.latencies.keys() {
+            let [p50, p90, p99, max] = [50.0, 90.0, 99.0, 100.0].map(|percentile| {
+                let latency = self.percentile(operation, percentile).unwrap_or_default();
+                format!("{latency:.1?}")
+            });
+            writeln!(
+                f,
+                "{:<10} {:>8} {:>10} {:>10} {:>10} {:>10}",
+                operation,
+                self.count(operation),
+                p50,
+                p90,
+                p99,
+                max
+            )?;
+        }
+        for (error, count) in &self.failures {
+            writeln!(f, "{count:>8} failed: {error}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads a running pukeko with `clients` simulated users for `duration`. Each session
+/// connects, waits for the menu and presses keys in it, then sends traffic through to
+/// `server` if there is one.
+pub async fn run(options: BenchOptions) -> anyhow::Result<Report> {
+    if options.clients == 0 {
+        bail!("At least one client is needed");
+    }
+    if options.server.is_some() && options.chunk == 0 {
+        bail!("Traffic has to be sent in chunks of at least a byte");
+    }
+    let key = PrivateKey::read_openssh_file(&options.key)
+        .with_context(|| format!("Failed to read key {}", options.key.display()))?;
+    let bench = Arc::new(Bench {
+        config: Arc::new(client::Config {
+            inactivity_timeout: None,
+            ..Default::default()
+        }),
+        key: Arc::new(key),
+        report: Default::default(),
+        options,
+    });
+
+    let started = Instant::now();
+    let deadline = started + bench.options.duration;
+    let mut clients = JoinSet::new();
+    for _ in 0..bench.options.clients {
+        let bench = bench.clone();
+        clients.spawn(async move {
+            while Instant::now() < deadline {
+                let result = bench.session().await;
+                let mut report = bench.report.lock().unwrap();
+                match result {
+                    Ok(()) => report.sessions += 1,
+                    Err(e) => *report.failures.entry(format!("{e:#}")).or_default() += 1,
+                }
+            }
+        });
+    }
+    while let Some(result) = clients.join_next().await {
+        result?;
+    }
+
+    let mut report = std::mem::take(&mut *bench.report.lock().unwrap());
+    report.elapsed = started.elapsed();
+    for latencies in report.latencies.values_mut() {
+        latencies.sort();
+    }
+    Ok(report)
+}
+
+struct Bench {
+    options: BenchOptions,
+    config: Arc<client::Config>,
+    key: Arc<PrivateKey>,
+    report: Mutex<Report>,
+}
+
+impl Bench {
+    async fn session(&self) -> anyhow::Result<()> {
+        let handle = self.connect(&self.options.user).await?;
+        self.menu(&handle).await.context("Menu")?;
+        let _ = handle
+            .disconnect(russh::Disconnect::ByApplication, "", "")
+            .await;
+
+        if let Some(server) = &self.options.server {
+            let handle = self
+                .connect(&format!("{}+{}", self.options.user, server))
+                .await?;
+            self.traffic(&handle).await.context("Traffic")?;
+            let _ = handle
+                .disconnect(russh::Disconnect::ByApplication, "", "")
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn connect(&self, user: &str) -> anyhow::Result<client::Handle<Client>> {
+        let started = Instant::now();
+        let handle = self
+            .within(async {
+                let mut handle =
+                    client::connect(self.config.clone(), self.options.target.as_str(), Client)
+                        .await?;
+                let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+                let auth = handle
+                    .authenticate_publickey(
+                        user,
+                        PrivateKeyWithHashAlg::new(self.key.clone(), hash_alg),
+                    )
+                    .await?;
+                if !auth.success() {
+                    bail!("{user} was refused");
+                }
+                Ok(handle)
+            })
+            .await
+            .context("Connect")?;
+        self.record(Operation::Connect, started.elapsed());
+        Ok(handle)
+    }
+
+    async fn menu(&self, handle: &client::Handle<Client>) -> anyhow::Result<()> {
+        let mut channel = handle.channel_open_session().await?;
+        channel
+            .request_pty(false, "xterm-256color", 80, 24, 0, 0, &[])
+            .await?;
+        let started = Instant::now();
+        channel.request_shell(false).await?;
+        self.within(next_output(&mut channel)).await?;
+        self.record(Operation::Menu, started.elapsed());
+        settle(&mut channel).await?;
+
+        for _ in 0..self.options.keystrokes {
+            let started = Instant::now();
+            channel.data(PREFERENCES).await?;
+            self.within(next_output(&mut channel)).await?;
+            self.record(Operation::Keystroke, started.elapsed());
+            settle(&mut channel).await?;
+        }
+        channel.close().await?;
+        Ok(())
+    }
+
+    async fn traffic(&self, handle: &client::Handle<Client>) -> anyhow::Result<()> {
+        let mut channel = handle.channel_open_session().await?;
+        channel.exec(false, self.options.command.as_str()).await?;
+        let chunk = vec![b'.'; self.options.chunk];
+        let mut sent = 0;
+        while sent < self.options.traffic {
+            let len = chunk.len().min(self.options.traffic - sent);
+            let started = Instant::now();
+            channel.data(&chunk[..len]).await?;
+            let mut echoed = 0;
+            while echoed < len {
+                echoed += self.within(next_output(&mut channel)).await?;
+            }
+            self.record(Operation::Echo, started.elapsed());
+            sent += len;
+        }
+        channel.eof().await?;
+        Ok(())
+    }
+
+    async fn within<T>(&self, step: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+        match tokio::time::timeout(self.options.timeout, step).await {
+            Ok(result) => result,
+            Err(_) => bail!("Timed out after {:?}", self.options.timeout),
+        }
+    }
+
+    fn record(&self, operation: Operation, latency: Duration) {
+        self.report.lock().unwrap().record(operation, latency);
+    }
+}
+
+/// Waits for output on `channel`, returning how much there was.
+async fn next_output(channel: &mut Channel<client::Msg>) -> anyhow::Result<usize> {
+    loop {
+        match channel.wait().await {
+            Some(ChannelMsg::Data { data }) => return Ok(data.len()),
+            Some(ChannelMsg::ExitStatus { exit_status }) => {
+                bail!("Exited with status {exit_status}")
+            }
+            Some(ChannelMsg::Eof | ChannelMsg::Close) | None => bail!("Closed by the server"),
+            Some(_) => {}
+        }
+    }
+}
+
+/// Reads output until it pauses, so the next output is a response to what is sent next.
+async fn settle(channel: &mut Channel<client::Msg>) -> anyhow::Result<()> {
+    while let Ok(output) = tokio::time::timeout(SETTLE, next_output(channel)).await {
+        output?;
+    }
+    Ok(())
+}
+
+struct Client;
+
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
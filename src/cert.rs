@@ -0,0 +1,339 @@
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, bail};
+use rand_core::{OsRng, RngCore};
+use russh::keys::ssh_key::HashAlg;
+use russh::keys::ssh_key::certificate::{Builder, CertType};
+use russh::keys::{Certificate, PrivateKey, PublicKey};
+
+use crate::config::{AddressRange, ProxyEntry};
+
+/// Extension of certificates issued to trusted proxies, naming the user the proxy connects
+/// on behalf of.
+pub const ORIGINAL_USER: &str = "original-user@pukeko";
+/// Extension of certificates issued to trusted proxies, with the address the user connected
+/// to the proxy from.
+pub const ORIGINAL_ADDRESS: &str = "original-address@pukeko";
+
+/// What a certificate issued with `pukeko-cert` lets its holder do besides log in, each
+/// granted as the `permit-*` extension `ssh-keygen` would grant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Permissions {
+    pub x11_forwarding: bool,
+    pub agent_forwarding: bool,
+    pub port_forwarding: bool,
+}
+
+impl Permissions {
+    fn extensions(self) -> impl Iterator<Item = &'static str> {
+        [
+            ("permit-X11-forwarding", self.x11_forwarding),
+            ("permit-agent-forwarding", self.agent_forwarding),
+            ("permit-port-forwarding", self.port_forwarding),
+            ("permit-pty", true),
+            ("permit-user-rc", true),
+        ]
+        .into_iter()
+        .filter_map(|(extension, permitted)| permitted.then_some(extension))
+    }
+}
+
+/// How long before it is issued a certificate is valid from, for servers with clocks
+/// slightly behind.
+const CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Signs a user certificate for `key` with `authority`, valid for `principals` from now
+/// until `validity` has passed, with the extensions of `permissions`. Its serial is random.
+pub fn issue_user_certificate(
+    authority: &PrivateKey,
+    key: &PublicKey,
+    key_id: &str,
+    principals: &[String],
+    validity: Duration,
+    permissions: Permissions,
+) -> anyhow::Result<Certificate> {
+    if principals.is_empty() {
+        bail!("No principals to issue a certificate for");
+    }
+    let now = SystemTime::now();
+    let mut builder = Builder::new_with_validity_times(
+        OsRng.next_u64().to_be_bytes().repeat(2),
+        key.key_data().clone(),
+        now - CLOCK_SKEW,
+        now + validity,
+    )?;
+    builder
+        .serial(OsRng.next_u64())?
+        .cert_type(CertType::User)?
+        .key_id(key_id)?;
+    for principal in principals {
+        builder.valid_principal(principal)?;
+    }
+    for extension in permissions.extensions() {
+        builder.extension(extension, "")?;
+    }
+    builder
+        .sign(authority)
+        .context("Failed to sign the certificate")
+}
+
+/// Checks that `certificate` is a user certificate issued to `principal` by one of
+/// `authorities`, that it is currently valid, and that its critical options allow a
+/// login from `peer`.
+///
+/// Only the `source-address` critical option is understood. Certificates carrying any
+/// other critical option are refused, as OpenSSH requires.
+pub fn validate_user_certificate(
+    certificate: &Certificate,
+    principal: &str,
+    peer: Option<IpAddr>,
+    authorities: &[PublicKey],
+) -> anyhow::Result<()> {
+    validate(certificate, peer, authorities)?;
+    if !certificate
+        .valid_principals()
+        .iter()
+        .any(|valid| valid == principal)
+    {
+        bail!("Certificate is not valid for {}", principal);
+    }
+    Ok(())
+}
+
+/// Checks that `certificate` was issued by one of `authorities` to a proxy connecting on
+/// behalf of `user`, as its `original-user@pukeko` extension says, and is otherwise valid as
+/// with [`validate_user_certificate`]. One of its principals must name one of `proxies`
+/// that may connect for `user`, so an authority's proxies cannot assert any user. Returns
+/// that proxy's name.
+pub fn validate_proxy_certificate(
+    certificate: &Certificate,
+    user: &str,
+    peer: Option<IpAddr>,
+    authorities: &[PublicKey],
+    proxies: &[ProxyEntry],
+) -> anyhow::Result<String> {
+    validate(certificate, peer, authorities)?;
+    if certificate
+        .extensions()
+        .get(ORIGINAL_USER)
+        .map(String::as_str)
+        != Some(user)
+    {
+        bail!("Certificate is not issued on behalf of {}", user);
+    }
+    certificate
+        .valid_principals()
+        .iter()
+        .find(|principal| {
+            proxies
+                .iter()
+                .any(|proxy| &proxy.name == *principal && proxy.allows(user))
+        })
+        .cloned()
+        .with_context(|| format!("Certificate does not name a proxy allowed to connect for {user}"))
+}
+
+fn validate(
+    certificate: &Certificate,
+    peer: Option<IpAddr>,
+    authorities: &[PublicKey],
+) -> anyhow::Result<()> {
+    let fingerprints: Vec<_> = authorities
+        .iter()
+        .map(|authority| authority.fingerprint(HashAlg::Sha256))
+        .collect();
+    certificate
+        .validate(&fingerprints)
+        .context("Certificate is not signed by a trusted authority or is not valid now")?;
+
+    if !certificate.cert_type().is_user() {
+        bail!("Certificate is not a user certificate");
+    }
+
+    for (name, value) in certificate.critical_options().iter() {
+        match name.as_str() {
+            "source-address" => {
+                let allowed = peer.is_some_and(|peer| {
+                    value
+                        .split(',')
+                        .filter_map(|pattern| AddressRange::parse(pattern.trim()).ok())
+                        .any(|range| range.contains(peer))
+                });
+                if !allowed {
+                    bail!("Certificate is not valid from {:?}", peer);
+                }
+            }
+            _ => bail!("Certificate has unsupported critical option {}", name),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use russh::keys::Algorithm;
+
+    use super::*;
+
+    fn key() -> PrivateKey {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()
+    }
+
+    /// Starts a certificate for a new key, valid for `alice` from `from` until `until`.
+    fn builder(from: SystemTime, until: SystemTime) -> Builder {
+        let mut builder = Builder::new_with_validity_times(
+            [0; 16],
+            key().public_key().key_data().clone(),
+            from,
+            until,
+        )
+        .unwrap();
+        builder.valid_principal("alice").unwrap();
+        builder
+    }
+
+    fn current() -> Builder {
+        let now = SystemTime::now();
+        builder(now - CLOCK_SKEW, now + CLOCK_SKEW)
+    }
+
+    fn accepts(certificate: &Certificate, peer: &str, authority: &PrivateKey) -> bool {
+        validate_user_certificate(
+            certificate,
+            "alice",
+            Some(peer.parse().unwrap()),
+            &[authority.public_key().clone()],
+        )
+        .is_ok()
+    }
+
+    #[test]
+    fn issued_certificates_are_valid_for_their_principals() {
+        let ca = key();
+        let certificate = issue_user_certificate(
+            &ca,
+            key().public_key(),
+            "alice",
+            &["alice".to_string()],
+            Duration::from_secs(300),
+            Permissions::default(),
+        )
+        .unwrap();
+        assert!(accepts(&certificate, "192.0.2.1", &ca));
+        let authorities = [ca.public_key().clone()];
+        assert!(validate_user_certificate(&certificate, "bob", None, &authorities).is_err());
+        assert!(
+            issue_user_certificate(
+                &ca,
+                key().public_key(),
+                "alice",
+                &[],
+                Duration::from_secs(300),
+                Permissions::default(),
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn certificates_from_other_authorities_or_times_are_refused() {
+        let ca = key();
+        let certificate = current().sign(&key()).unwrap();
+        assert!(!accepts(&certificate, "192.0.2.1", &ca));
+
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let expired = builder(now - 2 * hour, now - hour).sign(&ca).unwrap();
+        assert!(!accepts(&expired, "192.0.2.1", &ca));
+        let early = builder(now + hour, now + 2 * hour).sign(&ca).unwrap();
+        assert!(!accepts(&early, "192.0.2.1", &ca));
+    }
+
+    #[test]
+    fn host_certificates_are_refused() {
+        let ca = key();
+        let mut builder = current();
+        builder.cert_type(CertType::Host).unwrap();
+        assert!(!accepts(&builder.sign(&ca).unwrap(), "192.0.2.1", &ca));
+    }
+
+    #[test]
+    fn source_addresses_limit_where_certificates_are_used_from() {
+        let ca = key();
+        let mut builder = current();
+        builder
+            .critical_option("source-address", "10.0.0.0/8, 192.0.2.1")
+            .unwrap();
+        let certificate = builder.sign(&ca).unwrap();
+        assert!(accepts(&certificate, "10.1.2.3", &ca));
+        assert!(accepts(&certificate, "192.0.2.1", &ca));
+        assert!(!accepts(&certificate, "192.0.2.2", &ca));
+        // Without a known peer the option cannot be met.
+        let authorities = [ca.public_key().clone()];
+        assert!(validate_user_certificate(&certificate, "alice", None, &authorities).is_err());
+    }
+
+    #[test]
+    fn unknown_critical_options_are_refused() {
+        let ca = key();
+        let mut builder = current();
+        builder.critical_option("force-command", "true").unwrap();
+        assert!(!accepts(&builder.sign(&ca).unwrap(), "192.0.2.1", &ca));
+    }
+
+    /// A certificate from `ca` for a proxy with `principals`, connecting for `user`.
+    fn proxied(ca: &PrivateKey, principals: &[&str], user: &str) -> Certificate {
+        let now = SystemTime::now();
+        let mut builder = Builder::new_with_validity_times(
+            [0; 16],
+            key().public_key().key_data().clone(),
+            now - CLOCK_SKEW,
+            now + CLOCK_SKEW,
+        )
+        .unwrap();
+        for principal in principals {
+            builder.valid_principal(*principal).unwrap();
+        }
+        builder.extension(ORIGINAL_USER, user).unwrap();
+        builder.sign(ca).unwrap()
+    }
+
+    fn proxy(name: &str, users: &[&str]) -> ProxyEntry {
+        ProxyEntry {
+            name: name.to_string(),
+            users: users.iter().map(|user| user.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn proxies_connect_only_for_the_users_they_are_allowed() {
+        let ca = key();
+        let authorities = [ca.public_key().clone()];
+        let proxies = [proxy("bastion-a", &["alice"]), proxy("bastion-b", &["*"])];
+        let check = |certificate: &Certificate, user: &str| {
+            validate_proxy_certificate(certificate, user, None, &authorities, &proxies).ok()
+        };
+
+        let certificate = proxied(&ca, &["bastion-a"], "alice");
+        assert_eq!(check(&certificate, "alice").as_deref(), Some("bastion-a"));
+        // The login has to be the user the certificate is for.
+        assert_eq!(check(&certificate, "bob"), None);
+        assert_eq!(check(&proxied(&ca, &["bastion-a"], "bob"), "bob"), None);
+        assert_eq!(
+            check(&proxied(&ca, &["bastion-b"], "bob"), "bob").as_deref(),
+            Some("bastion-b")
+        );
+        // Principals that name no listed proxy are passed over.
+        assert_eq!(
+            check(&proxied(&ca, &["bastion-c", "bastion-a"], "alice"), "alice").as_deref(),
+            Some("bastion-a")
+        );
+        assert_eq!(check(&proxied(&ca, &["bastion-c"], "alice"), "alice"), None);
+        assert_eq!(
+            check(&proxied(&key(), &["bastion-a"], "alice"), "alice"),
+            None
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::config::{AddressRange, PukekoConfig};
+
+/// Something wrong with a config that loads, found by `pukeko check`.
+#[derive(Debug)]
+pub struct Problem {
+    /// Line of the config it is on, from 1, when it can be found.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Loads the config at `path`, failing as the server would on start, then looks for
+/// mistakes it accepts: servers or users defined twice, servers nobody is granted, address
+/// ranges that overlap and keys that have expired.
+pub fn check(path: &Path) -> anyhow::Result<Vec<Problem>> {
+    let config = PukekoConfig::load(path)?;
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config {}", path.display()))?;
+    let mut problems = Vec::new();
+
+    for (table, names) in [
+        (
+            "servers",
+            config
+                .servers
+                .iter()
+                .map(|server| &server.name)
+                .collect::<Vec<_>>(),
+        ),
+        (
+            "users",
+            config.users.iter().map(|user| &user.name).collect(),
+        ),
+    ] {
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) && !names[i + 1..].contains(name) {
+                let lines = definitions(&text, table, name);
+                problems.push(Problem {
+                    line: lines.get(1).copied(),
+                    message: format!(
+                        "{} {name} is defined {} times, only the first is used",
+                        if table == "servers" { "Server" } else { "User" },
+                        names.iter().filter(|other| other == &name).count()
+                    ),
+                });
+            }
+        }
+    }
+
+    // Stored users and groups from a directory may be granted servers the config does not.
+    if config.database.is_none() && config.ldap.is_none() && config.oidc.is_none() {
+        for server in &config.servers {
+            let granted = config
+                .users
+                .iter()
+                .any(|user| config.can_access_server(&user.name, server));
+            if !granted {
+                problems.push(Problem {
+                    line: definitions(&text, "servers", &server.name).first().copied(),
+                    message: format!("No user is granted access to server {}", server.name),
+                });
+            }
+        }
+    }
+
+    for (field, ranges) in [
+        ("allow_cidrs", &config.allow_cidrs),
+        ("deny_cidrs", &config.deny_cidrs),
+    ] {
+        for (i, range) in ranges.iter().enumerate() {
+            if let Some(other) = ranges[..i].iter().find(|other| other.overlaps(range)) {
+                problems.push(Problem {
+                    line: range_line(&text, field, range),
+                    message: format!(
+                        "{} overlaps {} in {field}",
+                        display_range(range),
+                        display_range(other)
+                    ),
+                });
+            }
+        }
+    }
+    for allowed in &config.allow_cidrs {
+        if let Some(denied) = config
+            .deny_cidrs
+            .iter()
+            .find(|denied| denied.prefix <= allowed.prefix && denied.overlaps(allowed))
+        {
+            problems.push(Problem {
+                line: range_line(&text, "allow_cidrs", allowed),
+                message: format!(
+                    "{} in allow_cidrs is all denied by {} in deny_cidrs",
+                    display_range(allowed),
+                    display_range(denied)
+                ),
+            });
+        }
+    }
+
+    let now = chrono::Utc::now();
+    for user in &config.users {
+        for key in &user.keys {
+            if let Some(expiry) = config
+                .key_expiry
+                .expiry(key)
+                .filter(|&expiry| expiry <= now)
+            {
+                let encoded = key.key.to_openssh().unwrap_or_default();
+                let data = encoded.split_whitespace().nth(1).unwrap_or_default();
+                // Users may share a key, so it is looked for from where the user is defined.
+                let start = definitions(&text, "users", &user.name)
+                    .first()
+                    .map_or(0, |line| line - 1);
+                problems.push(Problem {
+                    line: text
+                        .lines()
+                        .skip(start)
+                        .position(|line| !data.is_empty() && line.contains(data))
+                        .map(|i| start + i + 1),
+                    message: format!(
+                        "Key {} of user {} expired on {}",
+                        key.key.fingerprint(Default::default()),
+                        user.name,
+                        expiry.format("%Y-%m-%d")
+                    ),
+                });
+            }
+        }
+    }
+
+    problems.sort_by_key(|problem| problem.line);
+    Ok(problems)
+}
+
+/// Lines, from 1, setting `name` as the name of a `[[table]]`.
+fn definitions(text: &str, table: &str, name: &str) -> Vec<usize> {
+    let header = format!("[[{table}]]");
+    let mut in_table = false;
+    let mut lines = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('[') {
+            in_table = trimmed == header;
+        } else if in_table
+            && toml::from_str::<toml::Table>(trimmed)
+                .ok()
+                .and_then(|entry| entry.get("name").cloned())
+                .is_some_and(|value| value.as_str() == Some(name))
+        {
+            lines.push(i + 1);
+        }
+    }
+    lines
+}
+
+/// The line `range` is written on in `field`, or that of the field.
+fn range_line(text: &str, field: &str, range: &AddressRange) -> Option<usize> {
+    let start = text
+        .lines()
+        .position(|line| line.trim_start().starts_with(field))?;
+    let written = [
+        format!("\"{}\"", display_range(range)),
+        format!("\"{}\"", range.network),
+    ];
+    let found = text
+        .lines()
+        .skip(start)
+        .position(|line| written.iter().any(|written| line.contains(written)))
+        .unwrap_or(0);
+    Some(start + found + 1)
+}
+
+fn display_range(range: &AddressRange) -> String {
+    format!("{}/{}", range.network, range.prefix)
+}
@@ -0,0 +1,325 @@
+//! Clustering: several nodes behind a load balancer sharing their state through a
+//! coordination backend. Bans, access requests and grants, and menu history are kept there
+//! as entries that every node syncs with, so they are the same on each node and outlast
+//! restarts. Each node also publishes its sessions there, so admins see and manage those
+//! of the whole cluster and limits count them.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::config::ClusterConfig;
+use crate::geoip::Location;
+use crate::limits::ConnectionLimiter;
+use crate::provider::BoxFuture;
+use crate::sessions::{SessionInfo, SessionRegistry};
+
+#[cfg(feature = "postgres")]
+mod postgres;
+
+/// Entries of one kind, by key.
+pub type Entries = BTreeMap<String, Value>;
+
+/// A change to an entry: its new value, or None once removed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Write {
+    pub kind: &'static str,
+    pub key: String,
+    pub value: Option<Value>,
+}
+
+/// A session as published by the node it is on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeSession {
+    pub id: usize,
+    pub user: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub location: Option<Location>,
+    pub target: Option<String>,
+    /// Unix time the session connected.
+    pub started: i64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub throughput: u64,
+    pub channels: usize,
+}
+
+/// The sessions each node published, with its name.
+pub type NodeSessions = Vec<(String, Vec<NodeSession>)>;
+
+/// Where the nodes of a cluster keep what they share.
+pub trait Coordinator: Send + Sync {
+    /// Applies changes to entries, all or none of them.
+    fn write<'a>(&'a self, writes: &'a [Write]) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Every entry of `kind`.
+    fn entries<'a>(&'a self, kind: &'a str) -> BoxFuture<'a, anyhow::Result<Entries>>;
+
+    /// Replaces the sessions published for `node`, recording that it is alive.
+    fn heartbeat<'a>(
+        &'a self,
+        node: &'a str,
+        sessions: &'a [NodeSession],
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// The sessions of the nodes other than `node` alive within `timeout`, by node.
+    fn sessions<'a>(
+        &'a self,
+        node: &'a str,
+        timeout: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<NodeSessions>>;
+
+    /// Asks `node` to disconnect its session `id`.
+    fn disconnect<'a>(
+        &'a self,
+        node: &'a str,
+        id: usize,
+        reason: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+
+    /// Takes the sessions `node` has been asked to disconnect, with why.
+    fn take_disconnects<'a>(
+        &'a self,
+        node: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<(usize, String)>>>;
+}
+
+/// State a module shares with the other nodes, as entries of one kind.
+pub trait SharedState: Send + Sync {
+    fn kind(&self) -> &'static str;
+
+    /// Every entry as this node has it now.
+    fn entries(&self) -> Entries;
+
+    /// Replaces this node's entries with the cluster's, unless they have changed from
+    /// `seen` since. Returns whether they were replaced.
+    fn replace(&self, seen: &Entries, entries: Entries) -> bool;
+}
+
+/// Serializes a module's entries, skipping any that cannot be.
+pub fn to_entries<'a, K: ToString + 'a, V: Serialize + 'a>(
+    entries: impl IntoIterator<Item = (K, &'a V)>,
+) -> Entries {
+    entries
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), serde_json::to_value(value).ok()?)))
+        .collect()
+}
+
+/// Reads back entries of `kind`, logging and skipping any that are invalid.
+pub fn from_entries<K: FromStr, V: DeserializeOwned>(
+    kind: &str,
+    entries: Entries,
+) -> impl Iterator<Item = (K, V)> {
+    entries.into_iter().filter_map(move |(key, value)| {
+        let Ok(parsed) = key.parse() else {
+            warn!("Ignoring shared {} with invalid key {}", kind, key);
+            return None;
+        };
+        match serde_json::from_value(value) {
+            Ok(value) => Some((parsed, value)),
+            Err(e) => {
+                warn!("Ignoring invalid shared {} {}: {}", kind, key, e);
+                None
+            }
+        }
+    })
+}
+
+/// This node's part in a cluster: it syncs the shared state and sessions with the
+/// backend every `sync_interval`.
+pub struct Cluster {
+    config: ClusterConfig,
+    coordinator: Box<dyn Coordinator>,
+    sessions: Arc<SessionRegistry>,
+    limiter: Arc<ConnectionLimiter>,
+    shared: Vec<Arc<dyn SharedState>>,
+    /// The entries of each kind as last read from the backend, None before the first sync.
+    synced: Mutex<HashMap<&'static str, Entries>>,
+}
+
+impl Cluster {
+    /// Connects to the backend and syncs with it once, so the node starts with the
+    /// cluster's state. A backend without entries of a kind yet is given this node's.
+    pub async fn join(
+        config: &ClusterConfig,
+        sessions: Arc<SessionRegistry>,
+        limiter: Arc<ConnectionLimiter>,
+        shared: Vec<Arc<dyn SharedState>>,
+    ) -> anyhow::Result<Arc<Self>> {
+        let cluster = Arc::new(Self {
+            config: config.clone(),
+            coordinator: connect(config).await?,
+            sessions,
+            limiter,
+            shared,
+            synced: Mutex::default(),
+        });
+        cluster.sync().await?;
+        info!("Joined the cluster as {}", config.node);
+        Ok(cluster)
+    }
+
+    /// Syncs with the backend until the process exits, logging failures. State changes
+    /// while the backend cannot be reached are kept, and written once it can.
+    pub async fn run(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(self.config.sync_interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sync().await {
+                warn!("Failed to sync with the cluster: {:#}", e);
+            }
+        }
+    }
+
+    async fn sync(&self) -> anyhow::Result<()> {
+        for state in &self.shared {
+            self.sync_state(state.as_ref()).await?;
+        }
+
+        let node = &self.config.node;
+        let now = chrono::Utc::now();
+        let published: Vec<NodeSession> = self
+            .sessions
+            .list()
+            .into_iter()
+            .map(|info| NodeSession {
+                id: info.id,
+                user: info.user,
+                peer: info.peer,
+                location: info.location,
+                target: info.target,
+                started: (now - chrono::Duration::from_std(info.duration).unwrap_or_default())
+                    .timestamp(),
+                bytes_up: info.bytes_up,
+                bytes_down: info.bytes_down,
+                throughput: info.throughput,
+                channels: info.channels,
+            })
+            .collect();
+        self.coordinator.heartbeat(node, &published).await?;
+
+        let remote = self
+            .coordinator
+            .sessions(node, self.config.node_timeout)
+            .await?;
+        let mut per_ip: HashMap<IpAddr, usize> = HashMap::new();
+        let mut forwards: HashMap<String, Vec<String>> = HashMap::new();
+        let mut infos = Vec::new();
+        for (node, sessions) in remote {
+            for session in sessions {
+                if let Some(peer) = session.peer {
+                    *per_ip.entry(peer.ip()).or_default() += 1;
+                }
+                if let (Some(user), Some(target)) = (&session.user, &session.target) {
+                    forwards
+                        .entry(user.clone())
+                        .or_default()
+                        .push(target.clone());
+                }
+                infos.push(session_info(&node, session, now.timestamp()));
+            }
+        }
+        self.limiter.set_remote(per_ip);
+        self.sessions.quotas().set_remote(forwards);
+        self.sessions.set_remote(infos);
+
+        for (node, id, reason) in self.sessions.take_remote_disconnects() {
+            self.coordinator.disconnect(&node, id, &reason).await?;
+        }
+        for (id, reason) in self.coordinator.take_disconnects(node).await? {
+            if self.sessions.disconnect(id, &reason).await {
+                info!("Disconnected session {} as asked by another node", id);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes what changed on this node since the last sync, then takes the cluster's
+    /// entries, in which changes made on other nodes meanwhile win.
+    async fn sync_state(&self, state: &dyn SharedState) -> anyhow::Result<()> {
+        let kind = state.kind();
+        let local = state.entries();
+        let synced = self.synced.lock().unwrap().get(kind).cloned();
+        let writes = match &synced {
+            Some(synced) => changes(kind, synced, &local),
+            None => Vec::new(),
+        };
+        if !writes.is_empty() {
+            self.coordinator.write(&writes).await?;
+        }
+
+        let mut entries = self.coordinator.entries(kind).await?;
+        if synced.is_none() && entries.is_empty() && !local.is_empty() {
+            let writes = changes(kind, &Entries::new(), &local);
+            self.coordinator.write(&writes).await?;
+            info!("Gave the cluster this node's {} entries", kind);
+            entries = local.clone();
+        }
+        if state.replace(&local, entries.clone()) {
+            self.synced.lock().unwrap().insert(kind, entries);
+        }
+        Ok(())
+    }
+}
+
+/// What changed from `before` to `after`.
+fn changes(kind: &'static str, before: &Entries, after: &Entries) -> Vec<Write> {
+    let changed = after
+        .iter()
+        .filter(|(key, value)| before.get(*key) != Some(value))
+        .map(|(key, value)| Write {
+            kind,
+            key: key.clone(),
+            value: Some(value.clone()),
+        });
+    let removed = before
+        .keys()
+        .filter(|key| !after.contains_key(*key))
+        .map(|key| Write {
+            kind,
+            key: key.clone(),
+            value: None,
+        });
+    changed.chain(removed).collect()
+}
+
+fn session_info(node: &str, session: NodeSession, now: i64) -> SessionInfo {
+    SessionInfo {
+        id: session.id,
+        node: Some(node.to_string()),
+        peer: session.peer,
+        location: session.location,
+        user: session.user,
+        target: session.target,
+        duration: Duration::from_secs(now.saturating_sub(session.started).max(0) as u64),
+        bytes_up: session.bytes_up,
+        bytes_down: session.bytes_down,
+        throughput: session.throughput,
+        buffered: 0,
+        channels: session.channels,
+        quota_usage: Default::default(),
+    }
+}
+
+#[cfg(feature = "postgres")]
+async fn connect(config: &ClusterConfig) -> anyhow::Result<Box<dyn Coordinator>> {
+    Ok(Box::new(
+        postgres::PostgresCoordinator::connect(config).await?,
+    ))
+}
+
+#[cfg(not(feature = "postgres"))]
+async fn connect(_config: &ClusterConfig) -> anyhow::Result<Box<dyn Coordinator>> {
+    // The config refuses a cluster without the feature.
+    anyhow::bail!("pukeko was built without the postgres feature")
+}
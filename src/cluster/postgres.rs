@@ -0,0 +1,299 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Context;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use tokio::sync::Mutex;
+use tokio_postgres::Client;
+use tokio_postgres::config::SslMode;
+use tokio_postgres::types::Json;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tracing::{info, warn};
+
+use super::{Coordinator, Entries, NodeSession, NodeSessions, Write};
+use crate::config::ClusterConfig;
+use crate::provider::BoxFuture;
+
+/// Schema changes, applied in order to bring a database up to date. The number applied so
+/// far is kept in `pukeko_schema`, so entries must never be changed or removed.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE pukeko_state (
+        kind TEXT NOT NULL,
+        key TEXT NOT NULL,
+        value JSONB NOT NULL,
+        PRIMARY KEY (kind, key)
+    );
+    CREATE TABLE pukeko_nodes (
+        node TEXT PRIMARY KEY,
+        seen TIMESTAMPTZ NOT NULL,
+        sessions JSONB NOT NULL
+    );
+    CREATE TABLE pukeko_disconnects (
+        id BIGSERIAL PRIMARY KEY,
+        node TEXT NOT NULL,
+        session BIGINT NOT NULL,
+        reason TEXT NOT NULL
+    );
+    CREATE INDEX pukeko_disconnects_node ON pukeko_disconnects (node);
+    "];
+
+/// The advisory lock held while migrating, "pukeko" in ASCII.
+const MIGRATION_LOCK: i64 = 0x70756b656b6f;
+
+/// Keeps a cluster's state in a PostgreSQL database, reconnecting when the connection
+/// is lost. The connection needs TLS unless the URL's `sslmode` says otherwise.
+pub struct PostgresCoordinator {
+    config: tokio_postgres::Config,
+    tls: MakeRustlsConnect,
+    client: Mutex<Client>,
+}
+
+impl PostgresCoordinator {
+    pub async fn connect(cluster: &ClusterConfig) -> anyhow::Result<Self> {
+        let mut config: tokio_postgres::Config =
+            cluster.url.parse().context("Invalid cluster url")?;
+        if let Some(password) = &cluster.password {
+            config.password(password);
+        }
+        // The URL leaves TLS to be tried and given up on by default, which would let
+        // anyone between the nodes and the database read and change the cluster's state.
+        if !cluster.url.contains("sslmode=") {
+            config.ssl_mode(SslMode::Require);
+        }
+        let tls = tls(cluster)?;
+        let client = open(&config, &tls).await?;
+        migrate(&client).await?;
+        Ok(Self {
+            config,
+            tls,
+            client: Mutex::new(client),
+        })
+    }
+
+    /// The connection, opened again if it was lost.
+    async fn client(&self) -> anyhow::Result<tokio::sync::MutexGuard<'_, Client>> {
+        let mut client = self.client.lock().await;
+        if client.is_closed() {
+            *client = open(&self.config, &self.tls).await?;
+            info!("Reconnected to the cluster database");
+        }
+        Ok(client)
+    }
+}
+
+/// Verifies the database's certificate against `ca_file`, or the system's authorities.
+fn tls(cluster: &ClusterConfig) -> anyhow::Result<MakeRustlsConnect> {
+    let mut roots = rustls::RootCertStore::empty();
+    match &cluster.ca_file {
+        Some(path) => {
+            let certificates = CertificateDer::pem_file_iter(path)
+                .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            for certificate in certificates {
+                roots.add(certificate)?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for e in native.errors {
+                warn!("Failed to load a system certificate: {}", e);
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(MakeRustlsConnect::new(config))
+}
+
+async fn open(config: &tokio_postgres::Config, tls: &MakeRustlsConnect) -> anyhow::Result<Client> {
+    let (client, connection) = config
+        .connect(tls.clone())
+        .await
+        .context("Failed to connect to the cluster database")?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("Lost the connection to the cluster database: {}", e);
+        }
+    });
+    Ok(client)
+}
+
+async fn migrate(client: &Client) -> anyhow::Result<()> {
+    // Nodes starting together take turns, so each migration is applied once. Creating the
+    // same table at once can fail, so the lock is taken before that too.
+    client
+        .batch_execute(&format!(
+            "BEGIN; SELECT pg_advisory_xact_lock({MIGRATION_LOCK})"
+        ))
+        .await?;
+    let result = async {
+        client
+            .batch_execute("CREATE TABLE IF NOT EXISTS pukeko_schema (version INTEGER NOT NULL)")
+            .await?;
+        let version: i32 = client
+            .query_opt("SELECT version FROM pukeko_schema", &[])
+            .await?
+            .map_or(0, |row| row.get(0));
+        let applied = usize::try_from(version)?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(applied) {
+            client
+                .batch_execute(migration)
+                .await
+                .with_context(|| format!("Failed to apply cluster database migration {}", i + 1))?;
+        }
+        if applied < MIGRATIONS.len() {
+            let version = MIGRATIONS.len() as i32;
+            client.execute("DELETE FROM pukeko_schema", &[]).await?;
+            client
+                .execute(
+                    "INSERT INTO pukeko_schema (version) VALUES ($1)",
+                    &[&version],
+                )
+                .await?;
+            info!("Migrated the cluster database to version {}", version);
+        }
+        anyhow::Ok(())
+    }
+    .await;
+    let end = if result.is_ok() { "COMMIT" } else { "ROLLBACK" };
+    client.batch_execute(end).await?;
+    result
+}
+
+impl Coordinator for PostgresCoordinator {
+    fn write<'a>(&'a self, writes: &'a [Write]) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let mut client = self.client().await?;
+            let transaction = client.transaction().await?;
+            for write in writes {
+                match &write.value {
+                    Some(value) => {
+                        transaction
+                            .execute(
+                                "INSERT INTO pukeko_state (kind, key, value) VALUES ($1, $2, $3)
+                                 ON CONFLICT (kind, key) DO UPDATE SET value = excluded.value",
+                                &[&write.kind, &write.key, &Json(value)],
+                            )
+                            .await?;
+                    }
+                    None => {
+                        transaction
+                            .execute(
+                                "DELETE FROM pukeko_state WHERE kind = $1 AND key = $2",
+                                &[&write.kind, &write.key],
+                            )
+                            .await?;
+                    }
+                }
+            }
+            transaction.commit().await?;
+            Ok(())
+        })
+    }
+
+    fn entries<'a>(&'a self, kind: &'a str) -> BoxFuture<'a, anyhow::Result<Entries>> {
+        Box::pin(async move {
+            let rows = self
+                .client()
+                .await?
+                .query(
+                    "SELECT key, value FROM pukeko_state WHERE kind = $1",
+                    &[&kind],
+                )
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| {
+                    let Json(value) = row.get(1);
+                    (row.get(0), value)
+                })
+                .collect())
+        })
+    }
+
+    fn heartbeat<'a>(
+        &'a self,
+        node: &'a str,
+        sessions: &'a [NodeSession],
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.client()
+                .await?
+                .execute(
+                    "INSERT INTO pukeko_nodes (node, seen, sessions) VALUES ($1, now(), $2)
+                     ON CONFLICT (node) DO UPDATE SET seen = now(), sessions = excluded.sessions",
+                    &[&node, &Json(sessions)],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn sessions<'a>(
+        &'a self,
+        node: &'a str,
+        timeout: Duration,
+    ) -> BoxFuture<'a, anyhow::Result<NodeSessions>> {
+        Box::pin(async move {
+            let rows = self
+                .client()
+                .await?
+                .query(
+                    "SELECT node, sessions FROM pukeko_nodes
+                     WHERE node <> $1 AND seen > now() - $2 * interval '1 second'
+                     ORDER BY node",
+                    &[&node, &timeout.as_secs_f64()],
+                )
+                .await?;
+            Ok(rows
+                .into_iter()
+                .map(|row| {
+                    let Json(sessions) = row.get(1);
+                    (row.get(0), sessions)
+                })
+                .collect())
+        })
+    }
+
+    fn disconnect<'a>(
+        &'a self,
+        node: &'a str,
+        id: usize,
+        reason: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.client()
+                .await?
+                .execute(
+                    "INSERT INTO pukeko_disconnects (node, session, reason) VALUES ($1, $2, $3)",
+                    &[&node, &i64::try_from(id)?, &reason],
+                )
+                .await?;
+            Ok(())
+        })
+    }
+
+    fn take_disconnects<'a>(
+        &'a self,
+        node: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<Vec<(usize, String)>>> {
+        Box::pin(async move {
+            let rows = self
+                .client()
+                .await?
+                .query(
+                    "DELETE FROM pukeko_disconnects WHERE node = $1 RETURNING session, reason",
+                    &[&node],
+                )
+                .await?;
+            rows.into_iter()
+                .map(|row| Ok((usize::try_from(row.get::<_, i64>(0))?, row.get(1))))
+                .collect()
+        })
+    }
+}
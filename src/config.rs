@@ -1,8 +1,4310 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use chrono::{DateTime, Datelike, DurationRound, NaiveDate, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
+use rand_core::OsRng;
+use ratatui::style::Color;
+use regex::Regex;
+use russh::keys::ssh_key::{Algorithm, EcdsaCurve, LineEnding};
 use russh::keys::{PrivateKey, PublicKey};
+use russh::{Preferred, cipher, kex, mac};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::inspect::Direction;
+use crate::keymap::{Key, KeyMap, KeyPreset, MenuAction};
+use crate::messages::{self, Locales, Messages};
+use crate::store::{self, Store};
+use crate::{password, totp};
+
+pub type ConfigReceiver = watch::Receiver<Arc<PukekoConfig>>;
 
 #[derive(Debug, Clone)]
 pub struct PukekoConfig {
-    pub server_key: PrivateKey,
+    /// Host keys presented to clients, which negotiate the algorithm they support.
+    pub host_keys: Vec<PrivateKey>,
+
+    /// Where generated host keys are kept.
+    pub state_directory: PathBuf,
+
+    /// Host keys generated in `state_directory`, which `pukeko keys rotate` replaces. Empty
+    /// when `host_keys` are configured.
+    pub generated_host_keys: Vec<PathBuf>,
+
+    pub upstream_key: Option<PrivateKey>,
+
+    pub known_hosts: PathBuf,
+
+    /// Path of per-user authorized_keys files, with `%u` replaced by the user name.
+    pub authorized_keys: Option<String>,
+
+    /// Certificate authorities trusted to sign user certificates.
+    pub trusted_user_ca_keys: Vec<PublicKey>,
+
+    /// Certificate authorities of bastions in front of pukeko, trusted to sign certificates
+    /// that name the user they connect on behalf of.
+    pub trusted_proxy_ca_keys: Vec<PublicKey>,
+
+    /// The bastions that may connect with certificates from `trusted_proxy_ca_keys`, and
+    /// the users each may connect for.
+    pub proxies: Vec<ProxyEntry>,
+
+    pub host_key_policy: HostKeyPolicy,
+
+    pub users: Vec<UserEntry>,
+
+    pub groups: Vec<GroupEntry>,
+
+    /// When the users and groups that name them may log in.
+    pub schedules: Vec<Schedule>,
+
+    pub servers: Vec<ServerEntry>,
+
+    /// External sources of more servers.
+    pub inventory: Vec<InventoryConfig>,
+
+    /// SQLite database of more users and servers, and of past sessions. Opened when the
+    /// server starts.
+    pub database: Option<PathBuf>,
+
+    /// Directory users may also log in through.
+    pub ldap: Option<LdapConfig>,
+
+    /// Identity provider users may sign in to instead of using a key.
+    pub oidc: Option<OidcConfig>,
+
+    /// Backend the nodes of a cluster share bans, access requests, menu history and their
+    /// sessions through. Connected to when the server starts.
+    pub cluster: Option<ClusterConfig>,
+
+    pub shutdown_grace_period: Duration,
+
+    pub metrics_address: Option<SocketAddr>,
+
+    /// OpenTelemetry collector spans and metrics are exported to. Read when the server
+    /// starts.
+    pub telemetry: Option<TelemetryConfig>,
+
+    /// Unix socket serving the JSON-RPC admin API used by `pukeko ctl`.
+    pub control_socket: Option<PathBuf>,
+
+    /// Unix socket a newer pukeko started with the same config takes the listening sockets
+    /// from, after which this one drains its sessions and exits. Read when the server
+    /// starts.
+    pub upgrade_socket: Option<PathBuf>,
+
+    /// Expect a PROXY protocol header on every connection, as sent by HAProxy or a load
+    /// balancer, and use the client address it carries.
+    pub proxy_protocol: bool,
+
+    /// Addresses to accept connections on, all at once. The server's own listen address is
+    /// used when empty. Only read at startup.
+    pub listeners: Vec<ListenerConfig>,
+
+    /// Client addresses that may connect. Every address may when empty.
+    pub allow_cidrs: Vec<AddressRange>,
+
+    /// Client addresses that may not connect, even if allowed.
+    pub deny_cidrs: Vec<AddressRange>,
+
+    pub geoip: Option<GeoIpConfig>,
+
+    pub limits: LimitsConfig,
+
+    /// Limits on each user's forwards, which their groups may raise or lower.
+    pub quotas: QuotaConfig,
+
+    pub timeouts: TimeoutsConfig,
+
+    pub banner: BannerConfig,
+
+    pub health: HealthConfig,
+
+    pub keepalive: KeepaliveConfig,
+
+    pub pool: PoolConfig,
+
+    /// Algorithms offered to clients and upstreams, most preferred first.
+    pub algorithms: Preferred,
+
+    pub theme: ThemeConfig,
+    /// Themes users can pick from the menu instead of `theme`, by name.
+    pub themes: BTreeMap<String, ThemeConfig>,
+
+    /// Keys used in the menu.
+    pub keys: KeyMap,
+
+    /// Typed at the start of a line of an interactive session, followed by `s`, shows the
+    /// session's status. None when disabled.
+    pub escape_char: Option<u8>,
+
+    /// Typed in panes before a key that switches, resizes or closes them.
+    pub pane_prefix: Key,
+
+    /// Environment variables passed on from clients to upstreams, by name. A trailing `*`
+    /// matches any ending. Servers can set their own instead.
+    pub forward_env: Vec<String>,
+
+    /// What is done with clipboard escape sequences servers send, unless a server sets its
+    /// own.
+    pub clipboard: ClipboardPolicy,
+
+    /// Text of the menu, replaced by a catalogue in `locales` for clients that ask for
+    /// its locale.
+    pub messages: Arc<Messages>,
+    pub locales: Locales,
+
+    pub audit: AuditConfig,
+
+    pub shadow: ShadowConfig,
+
+    pub proxy_jump: ProxyJumpConfig,
+
+    pub privileges: PrivilegesConfig,
+
+    pub sandbox: SandboxConfig,
+
+    /// Hold keys of known users that are not on file for an admin to approve, instead of
+    /// rejecting them.
+    pub key_approval: bool,
+
+    pub key_expiry: KeyExpiryConfig,
+
+    /// Lets users ask for temporary access to servers they cannot reach.
+    pub access_requests: Option<AccessRequestsConfig>,
+
+    /// An external policy asked before each forward and jump whether to allow it.
+    pub authorization: Option<AuthorizationConfig>,
+
+    /// Lets users run `pukeko-cert` to be issued certificates for logging in to servers
+    /// directly.
+    pub certificates: Option<CertificatesConfig>,
+
+    /// Lets decoy logins, and connections that keep trying wrong passwords, into a fake
+    /// menu that is slow to respond, recording what they type.
+    pub honeypot: Option<HoneypotConfig>,
+
+    /// Lets users list, add and remove their own keys from the menu.
+    pub key_management: Option<KeyManagementConfig>,
+
+    /// Servers with any of these tags ask for their name to be typed before connecting
+    /// from the menu, as if they set `confirm`.
+    pub confirm_tags: Vec<String>,
+
+    /// Patterns looked for in forwarded data.
+    pub inspect: Vec<InspectRule>,
+
+    /// Shown in the menu, along with those posted from the control socket.
+    pub announcements: Vec<Announcement>,
+
+    /// WebAssembly plugins, asked in turn at each hook they export.
+    pub plugins: Vec<PluginConfig>,
+
+    /// Separate sets of users and servers, each unable to see the others'.
+    pub realms: Vec<RealmConfig>,
+}
+
+/// How a node of a cluster reaches the coordination backend, and how often it syncs with
+/// it. Only read at startup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterConfig {
+    /// A `postgres://` URL. Connections need TLS unless it sets another `sslmode`.
+    pub url: String,
+    pub password: Option<String>,
+    /// PEM certificates of the authorities trusted to sign the database's certificate, in
+    /// place of the system's.
+    pub ca_file: Option<PathBuf>,
+    /// Name of this node, shown beside its sessions. Defaults to the hostname.
+    pub node: String,
+    pub sync_interval: Duration,
+    /// How long after a node last synced its sessions stop counting.
+    pub node_timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TelemetryConfig {
+    /// Base URL of the collector's OTLP/HTTP endpoint, such as `http://localhost:4318`.
+    /// Defaults to `OTEL_EXPORTER_OTLP_ENDPOINT`.
+    pub endpoint: Option<String>,
+    pub service_name: String,
+    pub metrics_interval: Duration,
+}
+
+/// The user pukeko switches to once its sockets are bound, so it can listen on port 22
+/// without running as root. Only read at startup.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    pub user: Option<String>,
+    /// Defaults to the user's primary group.
+    pub group: Option<String>,
+    /// Change root to the state directory before switching user.
+    #[serde(default)]
+    pub chroot: bool,
+}
+
+/// Restrictions pukeko puts on itself once started, needing the sandbox feature. Only read
+/// at startup.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SandboxConfig {
+    /// Only let pukeko open the files it needs, with Landlock.
+    pub landlock: bool,
+    /// Directories and files that may be read: those of the files the config names, and
+    /// any more listed.
+    pub read_paths: Vec<PathBuf>,
+    /// Directories and files that may be written besides the state directory.
+    pub write_paths: Vec<PathBuf>,
+    /// Refuse system calls pukeko never makes, such as starting programs, with seccomp.
+    pub seccomp: bool,
+}
+
+/// An address SSH connections are accepted on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: SocketAddr,
+    /// Overrides `proxy_protocol` for connections to this address.
+    #[serde(default)]
+    pub proxy_protocol: Option<bool>,
+    /// Realm every connection to this address logs in to.
+    #[serde(default)]
+    pub realm: Option<String>,
+}
+
+/// Users and servers kept apart from the rest, logged in to as `user@realm` or through a
+/// listener set to the realm. Its users are named `user@realm` throughout, so sessions,
+/// quotas and history never mix with those of another realm.
+#[derive(Debug, Clone)]
+pub struct RealmConfig {
+    pub name: String,
+    /// What the realm's connections run with: the deployment's config with the realm's
+    /// users, groups, schedules, servers, themes, audit sinks, authorization hook and
+    /// certificate authority instead of its own, and nothing that finds users or servers
+    /// elsewhere, such as inventory, LDAP or authorized_keys files.
+    pub config: Arc<PukekoConfig>,
+}
+
+/// MaxMind databases client addresses are looked up in, and the countries and networks
+/// they may connect from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoIpConfig {
+    /// A country or city database, such as GeoLite2-Country.mmdb.
+    pub country_database: Option<PathBuf>,
+    pub asn_database: Option<PathBuf>,
+    /// ISO country codes clients may connect from. Every country may when empty.
+    pub allow_countries: Vec<String>,
+    pub deny_countries: Vec<String>,
+    pub deny_asns: Vec<u32>,
+    /// Countries users may log in from without a second factor. Elsewhere they must enter
+    /// a TOTP code, and are refused without a secret. Every country is trusted when empty.
+    pub trusted_countries: Vec<String>,
+}
+
+/// Admins watching other users' sessions from the sessions view.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowConfig {
+    /// Tell users when an admin starts and stops watching their session.
+    #[serde(default)]
+    pub notify: bool,
+}
+
+/// Clients connecting through pukeko to a server's own SSH port with `ssh -J`, which opens
+/// a direct-tcpip channel to it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyJumpConfig {
+    /// Bridge the channel when it is to the address and port of an SSH server the user
+    /// may access.
+    #[serde(default)]
+    pub enabled: bool,
+    /// Also accept a server's name, on any port, connecting to its configured address and
+    /// port instead, as in `ssh -J pukeko web-01`.
+    #[serde(default)]
+    pub rewrite: bool,
+}
+
+/// Requests for access, which admins approve or deny with `pukeko ctl`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRequestsConfig {
+    /// URL each request is posted to as JSON, such as a chat webhook.
+    pub webhook: Option<String>,
+    /// How long approved access lasts unless the approval says otherwise.
+    pub duration: Duration,
+    /// The longest access an approval may grant.
+    pub max_duration: Duration,
+}
+
+/// A WebAssembly plugin, and the most it may use on each call to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginConfig {
+    /// A `.wasm` module, or its text format in a `.wat` file.
+    pub path: PathBuf,
+    /// Fuel each call may burn, about one per instruction, before it is stopped.
+    pub fuel: u64,
+    /// Bytes of linear memory the plugin may grow to.
+    pub max_memory: usize,
+}
+
+/// Who is let into the honeypot, and what they find there. Nothing in it is connected to
+/// the real servers, users or sessions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HoneypotConfig {
+    /// Logins let in with any password or key.
+    pub users: Vec<String>,
+    /// Wrong passwords a connection may try before the next wrong one lets it in.
+    pub after_failures: Option<usize>,
+    /// Names of the servers the fake menu lists.
+    pub servers: Vec<String>,
+    /// Pause after each character of output.
+    pub delay: Duration,
+}
+
+/// The authority `pukeko-cert` signs user certificates with.
+#[derive(Debug, Clone)]
+pub struct CertificatesConfig {
+    pub ca_key: PrivateKey,
+    /// How long certificates are valid for after they are issued.
+    pub validity: Duration,
+}
+
+/// An external policy asked before each forward and jump. Forwards it does not answer
+/// within `timeout`, or answers with anything but a decision, are denied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthorizationConfig {
+    pub hook: AuthorizationHook,
+    pub timeout: Duration,
+}
+
+/// Where each forward is sent as a JSON object, answered with a JSON `decision`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthorizationHook {
+    /// A command run for each forward, given it on stdin and printing the decision.
+    Exec { command: Vec<String> },
+    /// An HTTP endpoint each forward is posted to, responding with the decision.
+    Http {
+        url: String,
+        headers: BTreeMap<String, String>,
+    },
+}
+
+/// Where structured audit events are written. Nothing is recorded when none are set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuditConfig {
+    pub file: Option<PathBuf>,
+    /// Whether each line of `file` carries the hash of the line before it, so that lines
+    /// changed or removed since are detected.
+    #[serde(default)]
+    pub chain: bool,
+    /// Ed25519 key each line of `file` is signed with, which also chains them.
+    pub signing_key: Option<PathBuf>,
+    #[serde(default)]
+    pub syslog: bool,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+/// A URL some of the audit events are posted to as they happen.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default)]
+    pub format: WebhookFormat,
+    /// The kinds of event sent. Every kind is when empty.
+    #[serde(default)]
+    pub events: Vec<WebhookEvent>,
+    /// Text sent for each kind of event instead of the default, with `{field}` replaced by
+    /// the event's fields, such as `{user}`, `{server}` and `{timestamp}`.
+    #[serde(default)]
+    pub templates: BTreeMap<WebhookEvent, String>,
+}
+
+impl WebhookConfig {
+    pub fn sends(&self, event: WebhookEvent) -> bool {
+        self.events.is_empty() || self.events.contains(&event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookFormat {
+    /// The event's fields plus the text, as a JSON object.
+    #[default]
+    Json,
+    /// Only the text, as a Slack incoming webhook expects. Mattermost and Rocket.Chat
+    /// accept the same.
+    Slack,
+}
+
+/// The kinds of audit event sent to webhooks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    Login,
+    ForwardStart,
+    ForwardEnd,
+    /// An address was banned for failing authentication too many times.
+    AuthFailures,
+    /// Admins terminating, watching or messaging sessions, and control socket changes.
+    Admin,
+    /// An `inspect` rule matched forwarded data.
+    ContentMatched,
+}
+
+/// An LDAP or Active Directory server users are looked up in when they are not in the
+/// config or offer a key it does not list. They log in with the keys in their entry and
+/// get the grants of the config groups their directory groups are mapped to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LdapConfig {
+    /// `ldap://`, `ldaps://` or `ldapi://` URL of the server.
+    pub url: String,
+    /// Who to bind as before searching. Searches are anonymous when unset.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    /// Filter matching a user's entry, with `{user}` replaced by the escaped user name.
+    pub user_filter: String,
+    /// Attribute holding the user's OpenSSH public keys.
+    pub key_attribute: String,
+    /// Attribute listing the DNs of the user's groups.
+    pub group_attribute: String,
+    pub timeout: Duration,
+    /// Config group each directory group is mapped to, keyed by lowercased DN.
+    pub groups: BTreeMap<String, String>,
+    /// How long the groups a user was mapped to at login are kept.
+    pub group_ttl: Duration,
+    /// How long a user's entry is kept once searched for, for every key they offer.
+    pub search_interval: Duration,
+    /// Searches of the directory allowed a minute, for all users together.
+    pub max_searches: usize,
+}
+
+/// An OpenID Connect provider users sign in to with the device authorization grant, shown
+/// a code to enter in their browser as keyboard-interactive authentication. They log in as
+/// the user named by `user_claim`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OidcConfig {
+    /// Issuer URL, where `/.well-known/openid-configuration` is fetched from.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    /// Claim holding the pukeko user name.
+    pub user_claim: String,
+    /// Sign-ins a connection may start.
+    pub max_flows_per_connection: usize,
+    /// Sign-ins an address may start within `flow_window`, over all its connections.
+    pub max_flows_per_ip: usize,
+    pub flow_window: Duration,
+}
+
+/// Connection and authentication limits. Every limit but `max_in_flight` is disabled when
+/// unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LimitsConfig {
+    pub max_sessions: Option<usize>,
+    pub max_sessions_per_ip: Option<usize>,
+    /// Connections that have not logged in yet, from every address together.
+    pub max_unauthenticated: Option<usize>,
+    /// Channels open at once on a connection, counting its session and each connection
+    /// to the ports it listens on with `ssh -R`.
+    pub max_channels: Option<usize>,
+    /// Failed authentication attempts within `auth_failure_window` before an address is banned.
+    pub max_auth_failures: Option<usize>,
+    pub auth_failure_window: Duration,
+    /// Length of the first ban, doubled for each ban after it.
+    pub ban_duration: Duration,
+    /// Longest a ban can grow to, and how long after one ends before the next starts at
+    /// `ban_duration` again.
+    pub max_ban_duration: Duration,
+    /// Bytes per second forwarded by every session together, in both directions.
+    pub bandwidth: Option<u64>,
+    /// Bytes per second forwarded by each session, in both directions.
+    pub session_bandwidth: Option<u64>,
+    /// Bytes of a client's input each forward holds before the client's window is shut,
+    /// until the upstream takes them.
+    pub max_in_flight: usize,
+    /// Bytes of a client's input its forwards hold together, past which the session is
+    /// disconnected.
+    pub max_session_buffered: Option<usize>,
+}
+
+/// Limits on a user's forwards and jumps, set for everyone under `[quotas]` and for the
+/// members of a group by its `quotas`. Every limit is disabled when unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct QuotaConfig {
+    /// Forwards open at once, across all of the user's sessions.
+    pub max_forwards: Option<usize>,
+    /// Forwards open at once to any one server.
+    pub max_forwards_per_server: Option<usize>,
+    /// Minutes a day, in UTC, the user may spend connected to servers, past which their
+    /// forwarding sessions are disconnected.
+    pub daily_minutes: Option<u64>,
+}
+
+/// How long sessions may sit idle before they are closed. The idle timeouts are disabled
+/// when unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutsConfig {
+    /// Time from connecting to logging in before the connection is dropped.
+    pub login: Duration,
+    /// Time without input at the menu before the session is closed.
+    pub menu_idle: Option<Duration>,
+    /// How long before the menu is closed to start showing a countdown.
+    pub menu_idle_warning: Duration,
+    /// Time without traffic in either direction before a forwarded session is closed.
+    pub forward_idle: Option<Duration>,
+    /// How long a shell is kept running after its client disconnects without closing it,
+    /// for the user to resume from the menu at their next login.
+    pub detached: Option<Duration>,
+}
+
+/// Background probes of each upstream, shown as status indicators in the menu.
+/// Disabled when `interval` is unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthConfig {
+    pub interval: Option<Duration>,
+    pub timeout: Duration,
+    pub probe: HealthProbe,
+}
+
+/// SSH keepalives sent to clients and upstreams that have been quiet for `interval`, so
+/// NAT gateways keep idle connections open. Disabled when `interval` is unset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeepaliveConfig {
+    pub interval: Option<Duration>,
+    /// Unanswered keepalives before the peer is considered dead and disconnected.
+    pub max_missed: usize,
+}
+
+/// Upstream connections authenticated with pukeko's own keys, shared by the sessions to
+/// the same server as the same user, which then only open a channel on them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    pub enabled: bool,
+    /// Channels open on one connection at most, kept within the upstream's MaxSessions.
+    pub max_channels: usize,
+    /// Connections shared for each server and user at most. Sessions beyond them connect
+    /// on their own.
+    pub max_connections: usize,
+    /// How long a connection without channels is kept open.
+    pub idle_timeout: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthProbe {
+    /// A TCP connection is accepted.
+    Tcp,
+    /// The upstream sends an SSH version banner.
+    #[default]
+    Banner,
+}
+
+/// A login notice, with `{user}`, `{source_ip}` and `{last_login}` filled in.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BannerConfig {
+    pub text: Option<String>,
+    pub mode: BannerMode,
+    /// Sent as the SSH authentication banner before any credentials, beside `text`, with
+    /// `{source_ip}`, `{location}` and `{date}` filled in.
+    pub pre_auth: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BannerMode {
+    /// Sent as the SSH authentication banner, before the user is known.
+    Auth,
+    /// Shown in the terminal before the menu until a key is pressed.
+    #[default]
+    Splash,
+}
+
+/// A source of servers outside the config file, fetched every `interval`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InventoryConfig {
+    pub name: String,
+    pub source: InventorySource,
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+/// Where an inventory's servers are fetched from, as a JSON array of objects with the
+/// `name`, `host`, `port`, `user`, `group` and `tags` of each server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InventorySource {
+    /// A command that prints the servers.
+    Exec { command: Vec<String> },
+    /// An HTTP endpoint that responds with the servers to a GET request.
+    Http {
+        url: String,
+        headers: BTreeMap<String, String>,
+    },
+    /// Running EC2 instances, listed with the AWS CLI.
+    Ec2(Ec2Config),
+    /// Running containers, listed through the Docker API.
+    Docker(DockerConfig),
+}
+
+/// Which EC2 instances are listed and how they appear in the menu. Instances are named
+/// by their `Name` tag and reached at their private IP address.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ec2Config {
+    pub region: Option<String>,
+    /// Profile from the AWS CLI config to authenticate as.
+    pub profile: Option<String>,
+    /// Only instances with every one of these tags are listed. A value of `*` matches any.
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Tag whose value instances are grouped by in the menu.
+    pub group_tag: Option<String>,
+    /// Username to log in to the instances as.
+    pub user: Option<String>,
+}
+
+/// Which containers of a Docker daemon are listed and how they appear in the menu.
+/// Containers are listed by name and exec'd into through the same daemon.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DockerConfig {
+    /// As for [`ContainerTarget::docker`].
+    pub docker: Option<String>,
+    /// Only containers with every one of these labels are listed. A value of `*` matches
+    /// any.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Label whose value containers are grouped by in the menu.
+    pub group_label: Option<String>,
+    /// User to run as in the containers.
+    pub user: Option<String>,
+    #[serde(default = "default_shell")]
+    pub shell: Vec<String>,
+}
+
+/// Colors and decorations of the menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThemeConfig {
+    /// Title of the server list.
+    pub title: String,
+    pub accent: Color,
+    pub highlight_fg: Color,
+    pub highlight_bg: Color,
+    pub highlight_symbol: String,
+    /// Notices, dialogs and broadcast messages.
+    pub notice: Color,
+    pub healthy: Color,
+    pub unhealthy: Color,
+    /// Secondary details such as upstream latency.
+    pub muted: Color,
+    pub borders: BorderStyle,
+    pub color: ColorMode,
+    pub indicators: Indicators,
+}
+
+/// Colors a theme starts from, before the ones it sets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Palette {
+    #[default]
+    Default,
+    /// Bright colors that stand out from a dark background, blue and orange rather than
+    /// green and red for servers up and down, and symbols for their status.
+    HighContrast,
+}
+
+/// How servers being up, down or under maintenance are shown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Indicators {
+    /// Dots told apart by their color, or symbols on terminals without colors.
+    #[default]
+    Dots,
+    /// ✓, ✗ and ◌, which can be told apart without seeing colors.
+    Symbols,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BorderStyle {
+    #[default]
+    Plain,
+    Rounded,
+    Double,
+    Thick,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// As many colors as the client's terminal type has, if any.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// What an upstream speaks. Clients always reach pukeko over SSH; for the other protocols
+/// their session is bridged to a plain connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Ssh,
+    Tcp,
+    Telnet,
+    /// Exec into a container of a pod, found through the kubeconfig.
+    Kubernetes,
+    /// Exec into a container through the Docker API.
+    Docker,
+}
+
+impl Protocol {
+    /// The port used when a server does not set one. Raw TCP has none.
+    pub fn default_port(self) -> Option<u16> {
+        match self {
+            Protocol::Ssh => Some(22),
+            Protocol::Tcp => None,
+            Protocol::Telnet => Some(23),
+            Protocol::Kubernetes | Protocol::Docker => None,
+        }
+    }
+
+    /// Checks server `name` has the address its protocol needs, returning its host and
+    /// port. Pods and containers have neither, so their name stands in for the host.
+    pub fn address(
+        self,
+        name: &str,
+        host: Option<String>,
+        port: Option<u16>,
+        pod: Option<&PodTarget>,
+        container: Option<&ContainerTarget>,
+    ) -> anyhow::Result<(String, u16)> {
+        if pod.is_some() && self != Protocol::Kubernetes {
+            bail!("Server {name} sets a pod, which only kubernetes servers use");
+        }
+        if container.is_some() && self != Protocol::Docker {
+            bail!("Server {name} sets a container, which only docker servers use");
+        }
+        let (target, kind, feature, built) = match self {
+            Protocol::Ssh | Protocol::Tcp | Protocol::Telnet => {
+                let Some(host) = host else {
+                    bail!("Server {name} has no host")
+                };
+                let Some(port) = port.or(self.default_port()) else {
+                    bail!("Server {name} has no port")
+                };
+                return Ok((host, port));
+            }
+            Protocol::Kubernetes => (
+                pod.map(|pod| &pod.name),
+                "pod",
+                "kubernetes",
+                cfg!(feature = "kubernetes"),
+            ),
+            Protocol::Docker => (
+                container.map(|container| &container.name),
+                "container",
+                "docker",
+                cfg!(feature = "docker"),
+            ),
+        };
+        if !built {
+            bail!(
+                "Server {name} uses {feature}, but pukeko was built without the {feature} feature"
+            );
+        }
+        let Some(target) = target else {
+            bail!("Server {name} uses {feature} but has no {kind}")
+        };
+        if host.is_some() || port.is_some() {
+            bail!("Server {name} is a {kind}, which has no host or port");
+        }
+        Ok((target.clone(), 0))
+    }
+}
+
+/// How an upstream is reached, when it is not connected to directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Via {
+    /// A SOCKS5 proxy at `host:port`, with the user and password it asks for if any.
+    Socks5 {
+        address: String,
+        credentials: Option<(String, String)>,
+    },
+    /// An HTTP proxy at `host:port`, which is asked to CONNECT to the upstream.
+    Http {
+        address: String,
+        credentials: Option<(String, String)>,
+    },
+    /// Another SSH server in the config, which forwards the connection as with `ssh -J`.
+    Jump(String),
+}
+
+impl Via {
+    /// Parses `socks5://[user:password@]host:port`, `http://[user:password@]host:port` or
+    /// the name of a server.
+    fn parse(via: &str) -> anyhow::Result<Self> {
+        let proxy = |rest: &str| {
+            let (credentials, address) = match rest.rsplit_once('@') {
+                Some((credentials, address)) => {
+                    let (user, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+                    (Some((user.to_string(), password.to_string())), address)
+                }
+                None => (None, rest),
+            };
+            let address = address.trim_end_matches('/');
+            match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok((address.to_string(), credentials))
+                }
+                _ => Err(anyhow::anyhow!("Proxy has no host and port")),
+            }
+        };
+        if let Some(rest) = via.strip_prefix("socks5://") {
+            let (address, credentials) = proxy(rest)?;
+            return Ok(Via::Socks5 {
+                address,
+                credentials,
+            });
+        }
+        if let Some(rest) = via.strip_prefix("http://") {
+            let (address, credentials) = proxy(rest)?;
+            return Ok(Via::Http {
+                address,
+                credentials,
+            });
+        }
+        if via.contains("://") {
+            bail!("Unsupported proxy, expected socks5:// or http://");
+        }
+        Ok(Via::Jump(via.to_string()))
+    }
+}
+
+/// The container a kubernetes server execs into.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PodTarget {
+    pub name: String,
+    /// Defaults to the namespace of the kubeconfig context.
+    pub namespace: Option<String>,
+    /// Needed when the pod has more than one container.
+    pub container: Option<String>,
+    /// Kubeconfig context to use instead of the current one.
+    pub context: Option<String>,
+    /// Run for interactive sessions. Commands are run with `/bin/sh -c`.
+    #[serde(default = "default_shell")]
+    pub shell: Vec<String>,
+}
+
+/// The container a docker server execs into.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ContainerTarget {
+    /// Name or id of the container.
+    pub name: String,
+    /// The daemon to use, such as `unix:///var/run/docker.sock` or `tcp://10.0.0.5:2375`.
+    /// Defaults to `$DOCKER_HOST`, or the local socket.
+    pub docker: Option<String>,
+    /// User to run as, instead of the container's.
+    pub user: Option<String>,
+    /// Run for interactive sessions. Commands are run with `/bin/sh -c`.
+    #[serde(default = "default_shell")]
+    pub shell: Vec<String>,
+}
+
+/// How upstream host keys missing from known_hosts are treated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HostKeyPolicy {
+    /// Refuse to connect to hosts that are not already in known_hosts.
+    #[default]
+    Strict,
+    /// Ask the user to confirm unknown keys and record them on acceptance.
+    Tofu,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserEntry {
+    pub name: String,
+    pub keys: Vec<UserKey>,
+    pub groups: Vec<String>,
+    pub servers: Vec<String>,
+    /// Secret for a TOTP code that is required after the key, when set.
+    pub totp_secret: Option<Vec<u8>>,
+    /// Argon2id hash of a password the user may log in with instead of a key.
+    pub password_hash: Option<String>,
+    /// Whether logging in takes both a key and the password, rather than either.
+    pub password_and_key: bool,
+    /// Whether the user can see and terminate every session from the menu.
+    pub admin: bool,
+    /// Addresses the user may listen on with `ssh -R`. Denied unless one matches.
+    pub remote_forwards: Vec<ForwardRule>,
+    /// Whether the user may forward X11 (`ssh -X`) to servers that allow it.
+    pub x11_forwarding: bool,
+    /// Whether the user may forward their agent (`ssh -A`) on to servers.
+    pub agent_forwarding: bool,
+    /// Bytes per second forwarded by all of the user's sessions together.
+    pub bandwidth: Option<u64>,
+    /// Name of the schedule the user may log in within.
+    pub schedule: Option<String>,
+}
+
+/// A key a user may log in with, and for how long.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserKey {
+    pub key: PublicKey,
+    pub added: Option<DateTime<Utc>>,
+    /// When the key stops being accepted, unless `max_age_days` ends it sooner.
+    pub expires: Option<DateTime<Utc>>,
+    /// What the key is for, such as a laptop. Defaults to the key's own comment.
+    pub comment: Option<String>,
+}
+
+impl UserKey {
+    pub fn new(key: PublicKey) -> Self {
+        let comment = Some(key.comment().to_string()).filter(|comment| !comment.is_empty());
+        Self {
+            key,
+            added: None,
+            expires: None,
+            comment,
+        }
+    }
+}
+
+/// When users' keys expire, and how long before they are warned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyExpiryConfig {
+    /// Days before their key expires that users are warned in the menu.
+    #[serde(default = "default_key_warn_days")]
+    pub warn_days: u32,
+    /// Days after a key was added that it expires, so users must rotate their keys. Keys
+    /// without an added date are exempt.
+    #[serde(default)]
+    pub max_age_days: Option<u32>,
+}
+
+/// What users may do to their own keys from the menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KeyManagementConfig {
+    /// Whether keys users add wait for an admin to approve them, as with `key_approval`.
+    #[serde(default = "default_key_management_approval")]
+    pub approval: bool,
+    /// Keys users must keep, not counting those awaiting approval.
+    #[serde(default = "default_min_keys")]
+    pub min_keys: usize,
+}
+
+impl Default for KeyExpiryConfig {
+    fn default() -> Self {
+        Self {
+            warn_days: default_key_warn_days(),
+            max_age_days: None,
+        }
+    }
+}
+
+impl KeyExpiryConfig {
+    /// When `key` stops being accepted: its own expiry or the end of its maximum age,
+    /// whichever is first.
+    pub fn expiry(&self, key: &UserKey) -> Option<DateTime<Utc>> {
+        let aged = self
+            .max_age_days
+            .zip(key.added)
+            .map(|(days, added)| added + chrono::Duration::days(days.into()));
+        match (key.expires, aged) {
+            (Some(expires), Some(aged)) => Some(expires.min(aged)),
+            (expires, aged) => expires.or(aged),
+        }
+    }
+
+    pub fn is_expired(&self, key: &UserKey, now: DateTime<Utc>) -> bool {
+        self.expiry(key).is_some_and(|expiry| expiry <= now)
+    }
+
+    /// When `key` expires, if that is soon enough to warn its user.
+    pub fn warning(&self, key: &UserKey, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.expiry(key).filter(|&expiry| {
+            expiry > now && expiry - now <= chrono::Duration::days(self.warn_days.into())
+        })
+    }
+}
+
+/// A message shown in the menu for a while, above the server list or next to a server.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Announcement {
+    pub message: String,
+    /// The server it is shown next to, or `None` to show it above the list.
+    pub server: Option<String>,
+    /// Unix time it is shown from, or `None` for right away.
+    pub from: Option<i64>,
+    /// Unix time it stops being shown, or `None` for as long as it is kept.
+    pub until: Option<i64>,
+}
+
+impl Announcement {
+    /// Reads `from` and `until` with [`parse_time`].
+    pub fn parse(
+        message: String,
+        server: Option<String>,
+        from: Option<&str>,
+        until: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let time = |time: Option<&str>| {
+            anyhow::Ok(
+                time.map(parse_time)
+                    .transpose()?
+                    .map(|time| time.timestamp()),
+            )
+        };
+        let announcement = Self {
+            message,
+            server,
+            from: time(from)?,
+            until: time(until)?,
+        };
+        if let (Some(from), Some(until)) = (announcement.from, announcement.until)
+            && until <= from
+        {
+            bail!("Announcement must end after it starts");
+        }
+        Ok(announcement)
+    }
+
+    pub fn shown(&self, now: i64) -> bool {
+        self.from.is_none_or(|from| from <= now) && self.until.is_none_or(|until| until > now)
+    }
+
+    pub fn ended(&self, now: i64) -> bool {
+        self.until.is_some_and(|until| until <= now)
+    }
+}
+
+/// Parses a date such as `2025-06-30`, meaning the start of that day in UTC, or an RFC 3339
+/// time.
+pub fn parse_time(time: &str) -> anyhow::Result<DateTime<Utc>> {
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Ok(date.and_time(NaiveTime::MIN).and_utc());
+    }
+    DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .with_context(|| format!("Invalid time {time}, expected a date such as 2025-06-30"))
+}
+
+/// An address and range of ports that remote forwards may listen on, written as
+/// `address:port`, `address:first-last` or `address:*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardRule {
+    pub address: String,
+    pub first_port: u16,
+    pub last_port: u16,
+}
+
+/// An address, or a CIDR block such as `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressRange {
+    pub network: IpAddr,
+    pub prefix: u32,
+}
+
+/// A named set of servers that users can be granted access to by membership.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupEntry {
+    pub name: String,
+    #[serde(default)]
+    pub servers: Vec<String>,
+    /// Name of the schedule members may log in within.
+    #[serde(default)]
+    pub schedule: Option<String>,
+    /// Replaces the global quotas for members, for each limit it sets.
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+}
+
+/// A bastion in front of pukeko, named by the principal of its certificates, and the users
+/// it may connect on behalf of. `*` allows it every user.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyEntry {
+    pub name: String,
+    pub users: Vec<String>,
+}
+
+/// Days and hours in a time zone during which users may log in. Windows that end before
+/// they start run past midnight, into the next day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub name: String,
+    pub days: Vec<Weekday>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub timezone: Tz,
+    /// Whether sessions are disconnected when the window closes, rather than left to
+    /// finish.
+    pub terminate: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerEntry {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub protocol: Protocol,
+    /// Set for kubernetes servers, which are reached through the cluster instead of `host`.
+    pub pod: Option<Arc<PodTarget>>,
+    /// Set for docker servers, which are reached through the daemon instead of `host`.
+    pub container: Option<Arc<ContainerTarget>>,
+    /// Username to log in to the upstream as, instead of the connecting user's name.
+    pub user: Option<String>,
+    /// Key to authenticate to this upstream with, instead of `upstream_key`.
+    pub key: Option<Arc<PrivateKey>>,
+    /// Folder the server is listed under in the menu. Unrelated to access `groups`.
+    pub group: Option<String>,
+    pub tags: Vec<String>,
+    /// Bytes per second forwarded by all sessions to this server together.
+    pub bandwidth: Option<u64>,
+    /// Set when only some commands may be run on this server.
+    pub commands: Option<Arc<CommandPolicy>>,
+    /// Whether sessions may share connections to this server, when pooling is enabled.
+    pub pool: bool,
+    /// Typed into interactive shells on this server before the client's input.
+    pub login_command: Option<String>,
+    /// Environment variables passed on from clients, instead of the global `forward_env`.
+    pub forward_env: Option<Vec<String>>,
+    /// Whether users allowed to forward X11 may do so to this server.
+    pub x11_forwarding: bool,
+    /// Whether the menu asks for the server's name to be typed before connecting.
+    pub confirm: bool,
+    /// Replaces the global `clipboard` policy for this server.
+    pub clipboard: Option<ClipboardPolicy>,
+    /// Set when the server is reached through a proxy or another server.
+    pub via: Option<Via>,
+    /// Whether `host` is a DNS SRV name, looked up for the host and port to connect to.
+    pub srv: bool,
+    /// Set when the server has addresses besides `host` to connect to.
+    pub backends: Option<Arc<Backends>>,
+}
+
+/// The addresses a server can be reached at, tried in turn until one answers.
+#[derive(Debug)]
+pub struct Backends {
+    /// The server's `host` and `port`, then the others as listed.
+    pub addresses: Vec<(String, u16)>,
+    pub policy: BackendPolicy,
+    /// The address round-robin starts from next.
+    next: AtomicUsize,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendPolicy {
+    /// Always start from the first address, moving on only when it cannot be reached.
+    #[default]
+    Failover,
+    /// Start each connection from the address after the one the last started from.
+    RoundRobin,
+}
+
+impl Backends {
+    /// Parses `addresses` as `host` or `host:port`, the port defaulting to `port`. IPv6
+    /// addresses with a port are written in brackets.
+    fn parse(
+        host: &str,
+        port: u16,
+        addresses: &[String],
+        policy: BackendPolicy,
+    ) -> anyhow::Result<Self> {
+        let mut parsed = vec![(host.to_string(), port)];
+        for address in addresses {
+            let (backend, backend_port) = match address.rsplit_once(':') {
+                Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+                    let port = port
+                        .parse()
+                        .with_context(|| format!("Invalid port in backend {address}"))?;
+                    (host.trim_start_matches('[').trim_end_matches(']'), port)
+                }
+                _ => (address.as_str(), port),
+            };
+            if backend.is_empty() {
+                bail!("Backend {address:?} has no host");
+            }
+            parsed.push((backend.to_string(), backend_port));
+        }
+        Ok(Self {
+            addresses: parsed,
+            policy,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// The addresses in the order the next connection tries them.
+    pub fn order(&self) -> Vec<&(String, u16)> {
+        let start = match self.policy {
+            BackendPolicy::Failover => 0,
+            BackendPolicy::RoundRobin => {
+                self.next.fetch_add(1, Ordering::Relaxed) % self.addresses.len()
+            }
+        };
+        self.addresses[start..]
+            .iter()
+            .chain(&self.addresses[..start])
+            .collect()
+    }
+}
+
+impl PartialEq for Backends {
+    fn eq(&self, other: &Self) -> bool {
+        self.addresses == other.addresses && self.policy == other.policy
+    }
+}
+
+impl Eq for Backends {}
+
+/// The commands that may be run on a server. Shells and subsystems such as sftp are
+/// refused, as they would allow anything.
+#[derive(Debug, Clone)]
+pub struct CommandPolicy {
+    /// Allowed exactly as written.
+    pub commands: Vec<String>,
+    /// Matched against the whole command.
+    pub patterns: Vec<Regex>,
+}
+
+impl CommandPolicy {
+    fn parse(name: &str, commands: Vec<String>, patterns: Vec<String>) -> anyhow::Result<Self> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Regex::new(&format!("^(?:{pattern})$")).with_context(|| {
+                    format!("Invalid command pattern {pattern:?} for server {name}")
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { commands, patterns })
+    }
+
+    pub fn allows(&self, command: &str) -> bool {
+        self.commands.iter().any(|allowed| allowed == command)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| pattern.is_match(command))
+    }
+}
+
+impl PartialEq for CommandPolicy {
+    fn eq(&self, other: &Self) -> bool {
+        self.commands == other.commands
+            && self.patterns.len() == other.patterns.len()
+            && self
+                .patterns
+                .iter()
+                .zip(&other.patterns)
+                .all(|(a, b)| a.as_str() == b.as_str())
+    }
+}
+
+impl Eq for CommandPolicy {}
+
+/// A pattern looked for in the data forwarded between clients and servers, and what is
+/// done when it is found. Each chunk is inspected as it is relayed, so a match split
+/// between two chunks is missed.
+#[derive(Debug, Clone)]
+pub struct InspectRule {
+    pub name: String,
+    pub pattern: regex::bytes::Regex,
+    pub direction: InspectDirection,
+    pub action: InspectAction,
+    /// Written in place of redacted matches.
+    pub replacement: String,
+}
+
+impl PartialEq for InspectRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+            && self.pattern.as_str() == other.pattern.as_str()
+            && self.direction == other.direction
+            && self.action == other.action
+            && self.replacement == other.replacement
+    }
+}
+
+impl Eq for InspectRule {}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InspectDirection {
+    /// What clients send to servers.
+    Input,
+    /// What servers send to clients.
+    Output,
+    #[default]
+    Both,
+}
+
+impl InspectDirection {
+    pub fn covers(self, direction: Direction) -> bool {
+        matches!(
+            (self, direction),
+            (InspectDirection::Both, _)
+                | (InspectDirection::Input, Direction::Input)
+                | (InspectDirection::Output, Direction::Output)
+        )
+    }
+}
+
+/// What is done with OSC 52 escape sequences, which set the client's clipboard, in output
+/// from servers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipboardPolicy {
+    /// Forward them untouched.
+    #[default]
+    Allow,
+    /// Forward them, but log and audit each.
+    Log,
+    /// Remove them before forwarding the output, logging and auditing each.
+    Strip,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InspectAction {
+    /// Forward the data unchanged, but log and audit the match.
+    #[default]
+    Alert,
+    /// Replace the match before forwarding the data.
+    Redact,
+    /// Close the forward instead of forwarding the data.
+    Block,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    server_key: Option<PathBuf>,
+    #[serde(default)]
+    host_keys: Vec<PathBuf>,
+    #[serde(default = "default_state_directory")]
+    state_directory: PathBuf,
+    #[serde(default = "default_host_key_algorithms")]
+    host_key_algorithms: Vec<HostKeyAlgorithm>,
+    upstream_key: Option<PathBuf>,
+    #[serde(default = "default_known_hosts")]
+    known_hosts: PathBuf,
+    authorized_keys: Option<String>,
+    trusted_user_ca_keys: Option<PathBuf>,
+    trusted_proxy_ca_keys: Option<PathBuf>,
+    #[serde(default)]
+    proxies: Vec<ProxyEntry>,
+    #[serde(default)]
+    host_key_policy: HostKeyPolicy,
+    #[serde(default)]
+    users: Vec<UserFile>,
+    #[serde(default)]
+    groups: Vec<GroupEntry>,
+    #[serde(default)]
+    schedules: Vec<ScheduleFile>,
+    #[serde(default)]
+    servers: Vec<ServerFile>,
+    #[serde(default)]
+    inventory: Vec<InventoryFile>,
+    database: Option<PathBuf>,
+    ldap: Option<LdapFile>,
+    oidc: Option<OidcFile>,
+    cluster: Option<ClusterFile>,
+    #[serde(default = "default_shutdown_grace_period")]
+    shutdown_grace_period: u64,
+    metrics_address: Option<SocketAddr>,
+    telemetry: Option<TelemetryFile>,
+    control_socket: Option<PathBuf>,
+    upgrade_socket: Option<PathBuf>,
+    #[serde(default)]
+    proxy_protocol: bool,
+    #[serde(default)]
+    listeners: Vec<ListenerConfig>,
+    #[serde(default)]
+    allow_cidrs: Vec<String>,
+    #[serde(default)]
+    deny_cidrs: Vec<String>,
+    geoip: Option<GeoIpFile>,
+    #[serde(default)]
+    limits: LimitsFile,
+    #[serde(default)]
+    quotas: QuotaConfig,
+    #[serde(default)]
+    timeouts: TimeoutsFile,
+    #[serde(default)]
+    banner: BannerFile,
+    #[serde(default)]
+    health: HealthFile,
+    #[serde(default)]
+    keepalive: KeepaliveFile,
+    #[serde(default)]
+    pool: PoolFile,
+    #[serde(default)]
+    algorithms: AlgorithmsFile,
+    #[serde(default)]
+    theme: ThemeFile,
+    #[serde(default)]
+    themes: BTreeMap<String, ThemeFile>,
+    #[serde(default)]
+    keys: KeysFile,
+    #[serde(default)]
+    audit: AuditConfig,
+    #[serde(default)]
+    shadow: ShadowConfig,
+    #[serde(default)]
+    proxy_jump: ProxyJumpConfig,
+    #[serde(default)]
+    privileges: PrivilegesConfig,
+    #[serde(default)]
+    sandbox: SandboxFile,
+    #[serde(default)]
+    key_approval: bool,
+    #[serde(default)]
+    key_expiry: KeyExpiryConfig,
+    access_requests: Option<AccessRequestsFile>,
+    authorization: Option<AuthorizationFile>,
+    certificates: Option<CertificatesFile>,
+    honeypot: Option<HoneypotFile>,
+    key_management: Option<KeyManagementConfig>,
+    #[serde(default)]
+    confirm_tags: Vec<String>,
+    #[serde(default)]
+    inspect: Vec<InspectFile>,
+    #[serde(default)]
+    announcements: Vec<AnnouncementFile>,
+    #[serde(default)]
+    plugins: Vec<PluginFile>,
+    #[serde(default)]
+    realms: Vec<RealmFile>,
+    #[serde(default = "default_forward_env")]
+    forward_env: Vec<String>,
+    #[serde(default)]
+    clipboard: ClipboardPolicy,
+    #[serde(default)]
+    messages: toml::Table,
+    messages_directory: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct LimitsFile {
+    max_sessions: Option<usize>,
+    max_sessions_per_ip: Option<usize>,
+    max_unauthenticated: Option<usize>,
+    max_channels: Option<usize>,
+    max_auth_failures: Option<usize>,
+    auth_failure_window: u64,
+    ban_duration: u64,
+    max_ban_duration: u64,
+    bandwidth: Option<u64>,
+    session_bandwidth: Option<u64>,
+    max_in_flight: usize,
+    max_session_buffered: Option<usize>,
+}
+
+impl Default for LimitsFile {
+    fn default() -> Self {
+        Self {
+            max_sessions: None,
+            max_sessions_per_ip: None,
+            max_unauthenticated: None,
+            max_channels: None,
+            max_auth_failures: None,
+            auth_failure_window: 60,
+            ban_duration: 600,
+            max_ban_duration: 86400,
+            bandwidth: None,
+            session_bandwidth: None,
+            max_in_flight: 1_048_576,
+            max_session_buffered: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BannerFile {
+    text: Option<String>,
+    file: Option<PathBuf>,
+    #[serde(default)]
+    mode: BannerMode,
+    pre_auth: Option<String>,
+    pre_auth_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct TimeoutsFile {
+    login: u64,
+    menu_idle: Option<u64>,
+    menu_idle_warning: u64,
+    forward_idle: Option<u64>,
+    detached: Option<u64>,
+}
+
+impl Default for TimeoutsFile {
+    fn default() -> Self {
+        Self {
+            login: 60,
+            menu_idle: None,
+            menu_idle_warning: 60,
+            forward_idle: None,
+            detached: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct HealthFile {
+    interval: Option<u64>,
+    timeout: u64,
+    probe: HealthProbe,
+}
+
+impl Default for HealthFile {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            timeout: 5,
+            probe: HealthProbe::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct KeepaliveFile {
+    interval: Option<u64>,
+    max_missed: usize,
+}
+
+impl Default for KeepaliveFile {
+    fn default() -> Self {
+        Self {
+            interval: None,
+            max_missed: 3,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct PoolFile {
+    enabled: bool,
+    max_channels: usize,
+    max_connections: usize,
+    idle_timeout: u64,
+}
+
+impl Default for PoolFile {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_channels: 10,
+            max_connections: 4,
+            idle_timeout: 300,
+        }
+    }
+}
+
+/// Lists replacing russh's defaults, which are used for those left unset.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AlgorithmsFile {
+    kex: Option<Vec<String>>,
+    ciphers: Option<Vec<String>>,
+    macs: Option<Vec<String>>,
+    host_keys: Option<Vec<String>>,
+}
+
+/// Key exchange names russh uses to advertise extensions rather than algorithms. They are
+/// kept when `kex` is set, as strict key exchange guards against prefix truncation.
+const KEX_EXTENSIONS: &[kex::Name] = &[
+    kex::EXTENSION_SUPPORT_AS_CLIENT,
+    kex::EXTENSION_SUPPORT_AS_SERVER,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+    kex::EXTENSION_OPENSSH_STRICT_KEX_AS_SERVER,
+];
+
+impl AlgorithmsFile {
+    fn parse(self, host_keys: &[PrivateKey]) -> anyhow::Result<Preferred> {
+        let mut preferred = Preferred::default();
+        if let Some(names) = self.kex {
+            let mut kex =
+                algorithm_names("key exchange", &names, |name| kex::Name::try_from(name))?;
+            if kex.iter().all(|name| KEX_EXTENSIONS.contains(name)) {
+                bail!("No key exchange algorithms are allowed");
+            }
+            for extension in KEX_EXTENSIONS {
+                if !kex.contains(extension) {
+                    kex.push(*extension);
+                }
+            }
+            preferred.kex = kex.into();
+        }
+        if let Some(names) = self.ciphers {
+            preferred.cipher =
+                algorithm_names("cipher", &names, |name| cipher::Name::try_from(name))?.into();
+        }
+        if let Some(names) = self.macs {
+            preferred.mac =
+                algorithm_names("MAC", &names, |name| mac::Name::try_from(name))?.into();
+        }
+        if let Some(names) = self.host_keys {
+            let algorithms = algorithm_names("host key", &names, |name| {
+                Algorithm::new(name).map_err(|_| ())
+            })?;
+            let usable = host_keys.iter().any(|key| {
+                algorithms.iter().any(|algorithm| match algorithm {
+                    Algorithm::Rsa { .. } => key.algorithm().is_rsa(),
+                    algorithm => key.algorithm() == *algorithm,
+                })
+            });
+            if !usable {
+                bail!("None of the host keys has an allowed host key algorithm");
+            }
+            preferred.key = algorithms.into();
+        }
+        Ok(preferred)
+    }
+}
+
+/// Parses the algorithm names of one list, refusing unknown names and "none", which would
+/// leave connections unencrypted or unauthenticated.
+fn algorithm_names<T>(
+    kind: &str,
+    names: &[String],
+    parse: impl Fn(&str) -> Result<T, ()>,
+) -> anyhow::Result<Vec<T>> {
+    if names.is_empty() {
+        bail!("No {kind} algorithms are allowed");
+    }
+    names
+        .iter()
+        .map(|name| {
+            if name == "none" || name == "clear" {
+                bail!("The {kind} algorithm {name} is not allowed");
+            }
+            parse(name).map_err(|()| anyhow::anyhow!("Unknown {kind} algorithm {name}"))
+        })
+        .collect()
+}
+
+/// Colors left unset take those of the palette.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct ThemeFile {
+    palette: Palette,
+    title: Option<String>,
+    accent: Option<String>,
+    highlight_fg: Option<String>,
+    highlight_bg: Option<String>,
+    highlight_symbol: Option<String>,
+    notice: Option<String>,
+    healthy: Option<String>,
+    unhealthy: Option<String>,
+    muted: Option<String>,
+    borders: BorderStyle,
+    color: ColorMode,
+    indicators: Option<Indicators>,
+}
+
+impl ThemeFile {
+    fn parse(self) -> anyhow::Result<ThemeConfig> {
+        // accent, highlight_fg, highlight_bg, notice, healthy, unhealthy and muted.
+        let defaults = match self.palette {
+            Palette::Default => [
+                "green",
+                "black",
+                "lightgreen",
+                "yellow",
+                "green",
+                "red",
+                "darkgray",
+            ],
+            Palette::HighContrast => [
+                "white", "black", "yellow", "yellow", "#5fafff", "#ff8700", "gray",
+            ],
+        };
+        let color = |name: &str, value: Option<String>, default: &str| {
+            let value = value.as_deref().unwrap_or(default);
+            value
+                .parse::<Color>()
+                .map_err(|_| anyhow::anyhow!("Invalid theme color {name} = {value:?}"))
+        };
+
+        Ok(ThemeConfig {
+            accent: color("accent", self.accent, defaults[0])?,
+            highlight_fg: color("highlight_fg", self.highlight_fg, defaults[1])?,
+            highlight_bg: color("highlight_bg", self.highlight_bg, defaults[2])?,
+            notice: color("notice", self.notice, defaults[3])?,
+            healthy: color("healthy", self.healthy, defaults[4])?,
+            unhealthy: color("unhealthy", self.unhealthy, defaults[5])?,
+            muted: color("muted", self.muted, defaults[6])?,
+            title: self.title.unwrap_or_else(|| "Select Server".to_string()),
+            highlight_symbol: self.highlight_symbol.unwrap_or_else(|| ">> ".to_string()),
+            borders: self.borders,
+            color: self.color,
+            indicators: self.indicators.unwrap_or(match self.palette {
+                Palette::Default => Indicators::Dots,
+                Palette::HighContrast => Indicators::Symbols,
+            }),
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+struct KeysFile {
+    preset: KeyPreset,
+    /// Keys for each action, replacing those of the preset.
+    bindings: BTreeMap<MenuAction, Vec<String>>,
+    /// `~` when unset, or "none".
+    escape: Option<String>,
+    /// Ctrl-b when unset.
+    pane_prefix: Option<String>,
+}
+
+impl KeysFile {
+    fn parse(self) -> anyhow::Result<KeyMap> {
+        let mut keymap = KeyMap::preset(self.preset);
+        for (action, keys) in self.bindings {
+            let keys = keys
+                .iter()
+                .map(|key| key.parse())
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Invalid key for {}", action.name()))?;
+            keymap.bind(action, keys);
+        }
+        keymap.validate()?;
+        Ok(keymap)
+    }
+
+    fn escape_char(&self) -> anyhow::Result<Option<u8>> {
+        match self.escape.as_deref() {
+            None => Ok(Some(b'~')),
+            Some("none") => Ok(None),
+            Some(escape) => match escape.as_bytes() {
+                &[char] if char.is_ascii_graphic() => Ok(Some(char)),
+                _ => bail!("keys escape must be a single printable ASCII character or \"none\""),
+            },
+        }
+    }
+
+    fn pane_prefix(&self) -> anyhow::Result<Key> {
+        let Some(prefix) = &self.pane_prefix else {
+            return Ok(Key::Ctrl('b'));
+        };
+        match prefix.parse() {
+            Ok(key @ Key::Ctrl(_)) => Ok(key),
+            _ => bail!("keys pane_prefix must be a letter with Ctrl, such as \"Ctrl-b\""),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserFile {
+    name: String,
+    #[serde(default)]
+    keys: Vec<KeyFile>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    servers: Vec<String>,
+    totp_secret: Option<String>,
+    password_hash: Option<String>,
+    #[serde(default)]
+    password_and_key: bool,
+    #[serde(default)]
+    admin: bool,
+    #[serde(default)]
+    remote_forwards: Vec<String>,
+    #[serde(default)]
+    x11_forwarding: bool,
+    #[serde(default = "default_agent_forwarding")]
+    agent_forwarding: bool,
+    bandwidth: Option<u64>,
+    schedule: Option<String>,
+}
+
+/// A key written as an OpenSSH public key, or as a table with when it was added and
+/// expires.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum KeyFile {
+    Key(String),
+    Entry(KeyEntryFile),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntryFile {
+    key: String,
+    added: Option<String>,
+    expires: Option<String>,
+    comment: Option<String>,
+}
+
+impl KeyFile {
+    fn parse(&self) -> anyhow::Result<UserKey> {
+        let entry = match self {
+            KeyFile::Key(key) => return Ok(UserKey::new(PublicKey::from_openssh(key)?)),
+            KeyFile::Entry(entry) => entry,
+        };
+        let mut key = UserKey::new(PublicKey::from_openssh(&entry.key)?);
+        key.added = entry.added.as_deref().map(parse_time).transpose()?;
+        key.expires = entry.expires.as_deref().map(parse_time).transpose()?;
+        if entry.comment.is_some() {
+            key.comment = entry.comment.clone();
+        }
+        Ok(key)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnnouncementFile {
+    message: String,
+    server: Option<String>,
+    from: Option<String>,
+    until: Option<String>,
+}
+
+impl AnnouncementFile {
+    fn parse(self) -> anyhow::Result<Announcement> {
+        Announcement::parse(
+            self.message,
+            self.server,
+            self.from.as_deref(),
+            self.until.as_deref(),
+        )
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFile {
+    name: String,
+    /// Days such as "mon" or ranges such as "mon-fri", every day when empty.
+    #[serde(default)]
+    days: Vec<String>,
+    start: String,
+    end: String,
+    #[serde(default = "default_timezone")]
+    timezone: String,
+    #[serde(default)]
+    terminate: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServerFile {
+    name: String,
+    host: Option<String>,
+    #[serde(default)]
+    protocol: Protocol,
+    port: Option<u16>,
+    pod: Option<PodTarget>,
+    container: Option<ContainerTarget>,
+    user: Option<String>,
+    key_file: Option<PathBuf>,
+    key: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    bandwidth: Option<u64>,
+    commands: Option<Vec<String>>,
+    command_patterns: Option<Vec<String>>,
+    pool: Option<bool>,
+    login_command: Option<String>,
+    forward_env: Option<Vec<String>>,
+    #[serde(default)]
+    x11_forwarding: bool,
+    #[serde(default)]
+    confirm: bool,
+    clipboard: Option<ClipboardPolicy>,
+    via: Option<String>,
+    srv: Option<String>,
+    /// Addresses tried after `host`.
+    backends: Option<Vec<String>>,
+    #[serde(default)]
+    backend_policy: BackendPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InventoryFile {
+    name: String,
+    command: Option<Vec<String>>,
+    url: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    ec2: Option<Ec2Config>,
+    docker: Option<DockerConfig>,
+    #[serde(default = "default_inventory_interval")]
+    interval: u64,
+    #[serde(default = "default_inventory_timeout")]
+    timeout: u64,
+}
+
+impl InventoryFile {
+    fn parse(self) -> anyhow::Result<InventoryConfig> {
+        let sources = [
+            self.command.is_some(),
+            self.url.is_some(),
+            self.ec2.is_some(),
+            self.docker.is_some(),
+        ];
+        if sources.into_iter().filter(|&set| set).count() > 1 {
+            bail!(
+                "Inventory {} sets more than one of command, url, ec2 and docker",
+                self.name
+            );
+        }
+        if !self.headers.is_empty() && self.url.is_none() {
+            bail!("Inventory {} sets headers without a url", self.name);
+        }
+
+        let source = match (self.command, self.url, self.ec2, self.docker) {
+            (Some(command), ..) => {
+                if command.is_empty() {
+                    bail!("Inventory {} has an empty command", self.name);
+                }
+                InventorySource::Exec { command }
+            }
+            (_, Some(url), ..) => InventorySource::Http {
+                url,
+                headers: self.headers,
+            },
+            (_, _, Some(ec2), _) => InventorySource::Ec2(ec2),
+            (.., Some(docker)) => {
+                if cfg!(not(feature = "docker")) {
+                    bail!(
+                        "Inventory {} uses docker, but pukeko was built without the docker feature",
+                        self.name
+                    );
+                }
+                InventorySource::Docker(docker)
+            }
+            (None, None, None, None) => {
+                bail!(
+                    "Inventory {} needs a command, url, ec2 or docker",
+                    self.name
+                )
+            }
+        };
+        if self.interval == 0 {
+            bail!(
+                "Inventory {} interval must be at least one second",
+                self.name
+            );
+        }
+
+        Ok(InventoryConfig {
+            name: self.name,
+            source,
+            interval: Duration::from_secs(self.interval),
+            timeout: Duration::from_secs(self.timeout),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LdapFile {
+    url: String,
+    bind_dn: Option<String>,
+    bind_password: Option<String>,
+    bind_password_file: Option<PathBuf>,
+    base_dn: String,
+    #[serde(default = "default_ldap_user_filter")]
+    user_filter: String,
+    #[serde(default = "default_ldap_key_attribute")]
+    key_attribute: String,
+    #[serde(default = "default_ldap_group_attribute")]
+    group_attribute: String,
+    #[serde(default = "default_ldap_timeout")]
+    timeout: u64,
+    #[serde(default)]
+    groups: BTreeMap<String, String>,
+    #[serde(default = "default_ldap_group_ttl")]
+    group_ttl: u64,
+    #[serde(default = "default_ldap_search_interval")]
+    search_interval: u64,
+    #[serde(default = "default_ldap_max_searches")]
+    max_searches: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RealmFile {
+    name: String,
+    #[serde(default)]
+    users: Vec<UserFile>,
+    #[serde(default)]
+    groups: Vec<GroupEntry>,
+    #[serde(default)]
+    schedules: Vec<ScheduleFile>,
+    #[serde(default)]
+    servers: Vec<ServerFile>,
+    theme: Option<ThemeFile>,
+    #[serde(default)]
+    themes: BTreeMap<String, ThemeFile>,
+    #[serde(default)]
+    audit: AuditConfig,
+    authorization: Option<AuthorizationFile>,
+    certificates: Option<CertificatesFile>,
+}
+
+impl RealmFile {
+    /// Gives the realm `deployment`'s config with its own users and servers in place.
+    fn parse(self, deployment: &PukekoConfig, base: &Path) -> anyhow::Result<RealmConfig> {
+        let name = self.name;
+        if name.is_empty() || name.contains(['@', '+']) {
+            bail!("Realm name {name:?} must not be empty or contain @ or +");
+        }
+        let context = || format!("Invalid realm {name}");
+        let schedules =
+            parse_schedules(self.schedules, &self.users, &self.groups).with_context(context)?;
+        let mut users = parse_users(self.users).with_context(context)?;
+        for user in &mut users {
+            user.name = format!("{}@{name}", user.name);
+        }
+        let servers = parse_servers(self.servers, base).with_context(context)?;
+        let theme = match self.theme {
+            Some(theme) => theme.parse().with_context(context)?,
+            None => deployment.theme.clone(),
+        };
+        let themes = if self.themes.is_empty() {
+            deployment.themes.clone()
+        } else {
+            self.themes
+                .into_iter()
+                .map(|(theme_name, theme)| {
+                    let theme = theme
+                        .parse()
+                        .with_context(|| format!("Invalid theme {theme_name} of realm {name}"))?;
+                    Ok((theme_name, theme))
+                })
+                .collect::<anyhow::Result<_>>()?
+        };
+        let audit = parse_audit(self.audit, base).with_context(context)?;
+        let authorization = self
+            .authorization
+            .map(AuthorizationFile::parse)
+            .transpose()
+            .with_context(context)?;
+        let certificates = self
+            .certificates
+            .map(|certificates| certificates.parse(base))
+            .transpose()
+            .with_context(context)?;
+        let config = PukekoConfig {
+            users,
+            groups: self.groups,
+            schedules,
+            servers,
+            theme,
+            themes,
+            audit,
+            inventory: Vec::new(),
+            database: None,
+            ldap: None,
+            oidc: None,
+            authorized_keys: None,
+            trusted_user_ca_keys: Vec::new(),
+            trusted_proxy_ca_keys: Vec::new(),
+            proxies: Vec::new(),
+            honeypot: None,
+            key_approval: false,
+            key_management: None,
+            access_requests: None,
+            authorization,
+            certificates,
+            // Plugins are loaded once, from the deployment's config, for every realm.
+            plugins: Vec::new(),
+            realms: Vec::new(),
+            ..deployment.clone()
+        };
+        Ok(RealmConfig {
+            name,
+            config: Arc::new(config),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginFile {
+    path: PathBuf,
+    #[serde(default = "default_plugin_fuel")]
+    fuel: u64,
+    #[serde(default = "default_plugin_max_memory")]
+    max_memory: usize,
+}
+
+impl PluginFile {
+    fn parse(self, base: &Path) -> anyhow::Result<PluginConfig> {
+        // A page of WebAssembly memory.
+        const PAGE: usize = 65536;
+
+        if cfg!(not(feature = "wasm")) {
+            bail!("plugins are configured, but pukeko was built without the wasm feature");
+        }
+        if self.fuel == 0 {
+            bail!("Plugin {} fuel must be at least one", self.path.display());
+        }
+        if self.max_memory < PAGE {
+            bail!(
+                "Plugin {} max_memory must be at least {PAGE}",
+                self.path.display()
+            );
+        }
+        Ok(PluginConfig {
+            path: base.join(self.path),
+            fuel: self.fuel,
+            max_memory: self.max_memory,
+        })
+    }
+}
+
+impl LdapFile {
+    fn parse(self, base: &Path) -> anyhow::Result<LdapConfig> {
+        if cfg!(not(feature = "ldap")) {
+            bail!("ldap is configured, but pukeko was built without the ldap feature");
+        }
+        if !self.user_filter.contains("{user}") {
+            bail!("ldap user_filter must contain {{user}}");
+        }
+        let bind_password = match (self.bind_password, self.bind_password_file) {
+            (Some(_), Some(_)) => bail!("ldap sets both bind_password and bind_password_file"),
+            (Some(password), None) => Some(password),
+            (None, Some(path)) => {
+                let path = base.join(path);
+                let password = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                Some(password.trim_end_matches(['\r', '\n']).to_string())
+            }
+            (None, None) => None,
+        };
+        if bind_password.is_some() && self.bind_dn.is_none() {
+            bail!("ldap sets a bind password without a bind_dn");
+        }
+
+        Ok(LdapConfig {
+            url: self.url,
+            bind_dn: self.bind_dn,
+            bind_password,
+            base_dn: self.base_dn,
+            user_filter: self.user_filter,
+            key_attribute: self.key_attribute,
+            group_attribute: self.group_attribute,
+            timeout: Duration::from_secs(self.timeout),
+            groups: self
+                .groups
+                .into_iter()
+                .map(|(dn, group)| (dn.to_lowercase(), group))
+                .collect(),
+            group_ttl: Duration::from_secs(self.group_ttl),
+            search_interval: Duration::from_secs(self.search_interval),
+            max_searches: self.max_searches,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClusterFile {
+    url: String,
+    password_file: Option<PathBuf>,
+    ca_file: Option<PathBuf>,
+    node: Option<String>,
+    #[serde(default = "default_sync_interval")]
+    sync_interval: u64,
+    #[serde(default = "default_node_timeout")]
+    node_timeout: u64,
+}
+
+impl ClusterFile {
+    fn parse(self, base: &Path) -> anyhow::Result<ClusterConfig> {
+        if cfg!(not(feature = "postgres")) {
+            bail!("cluster is configured, but pukeko was built without the postgres feature");
+        }
+        if !["postgres://", "postgresql://"]
+            .iter()
+            .any(|scheme| self.url.starts_with(scheme))
+        {
+            bail!("cluster url must be a postgres:// URL");
+        }
+        let password = match self.password_file {
+            Some(path) => {
+                let path = base.join(path);
+                let password = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                Some(password.trim_end_matches(['\r', '\n']).to_string())
+            }
+            None => None,
+        };
+        let node = match self.node {
+            Some(node) => node,
+            None => hostname().context("cluster needs a node name, the hostname is unknown")?,
+        };
+        if node.is_empty() {
+            bail!("cluster node name is empty");
+        }
+        if self.sync_interval == 0 {
+            bail!("cluster sync_interval must be at least 1 second");
+        }
+        if self.node_timeout <= self.sync_interval {
+            bail!("cluster node_timeout must be longer than sync_interval");
+        }
+        Ok(ClusterConfig {
+            url: self.url,
+            password,
+            ca_file: self.ca_file.map(|path| base.join(path)),
+            node,
+            sync_interval: Duration::from_secs(self.sync_interval),
+            node_timeout: Duration::from_secs(self.node_timeout),
+        })
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer is valid for its length, which gethostname does not write past.
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len()) } != 0 {
+        return None;
+    }
+    let end = name.iter().position(|&b| b == 0)?;
+    String::from_utf8(name[..end].to_vec()).ok()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TelemetryFile {
+    endpoint: Option<String>,
+    #[serde(default = "default_service_name")]
+    service_name: String,
+    #[serde(default = "default_metrics_interval")]
+    metrics_interval: u64,
+}
+
+impl TelemetryFile {
+    fn parse(self) -> anyhow::Result<TelemetryConfig> {
+        if cfg!(not(feature = "otel")) {
+            bail!("telemetry is configured, but pukeko was built without the otel feature");
+        }
+        if self.metrics_interval == 0 {
+            bail!("telemetry metrics_interval must be at least 1 second");
+        }
+        Ok(TelemetryConfig {
+            endpoint: self
+                .endpoint
+                .map(|endpoint| endpoint.trim_end_matches('/').to_string()),
+            service_name: self.service_name,
+            metrics_interval: Duration::from_secs(self.metrics_interval),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GeoIpFile {
+    country_database: Option<PathBuf>,
+    asn_database: Option<PathBuf>,
+    #[serde(default)]
+    allow_countries: Vec<String>,
+    #[serde(default)]
+    deny_countries: Vec<String>,
+    #[serde(default)]
+    deny_asns: Vec<u32>,
+    #[serde(default)]
+    trusted_countries: Vec<String>,
+}
+
+impl GeoIpFile {
+    fn parse(self, base: &Path) -> anyhow::Result<GeoIpConfig> {
+        if cfg!(not(feature = "geoip")) {
+            bail!("geoip is configured, but pukeko was built without the geoip feature");
+        }
+        if self.country_database.is_none() && self.asn_database.is_none() {
+            bail!("geoip needs a country_database or an asn_database");
+        }
+        let countries = |countries: Vec<String>| -> anyhow::Result<Vec<String>> {
+            countries
+                .into_iter()
+                .map(|country| {
+                    if country.len() != 2 || !country.chars().all(|c| c.is_ascii_alphabetic()) {
+                        bail!("Invalid country {country}, expected an ISO code such as NZ");
+                    }
+                    Ok(country.to_ascii_uppercase())
+                })
+                .collect()
+        };
+        let has_countries = !self.allow_countries.is_empty()
+            || !self.deny_countries.is_empty()
+            || !self.trusted_countries.is_empty();
+        if has_countries && self.country_database.is_none() {
+            bail!("geoip countries need a country_database");
+        }
+        if !self.deny_asns.is_empty() && self.asn_database.is_none() {
+            bail!("geoip deny_asns needs an asn_database");
+        }
+        Ok(GeoIpConfig {
+            country_database: self.country_database.map(|path| base.join(path)),
+            asn_database: self.asn_database.map(|path| base.join(path)),
+            allow_countries: countries(self.allow_countries)?,
+            deny_countries: countries(self.deny_countries)?,
+            deny_asns: self.deny_asns,
+            trusted_countries: countries(self.trusted_countries)?,
+        })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SandboxFile {
+    #[serde(default)]
+    landlock: bool,
+    #[serde(default)]
+    read_paths: Vec<PathBuf>,
+    #[serde(default)]
+    write_paths: Vec<PathBuf>,
+    #[serde(default)]
+    seccomp: bool,
+}
+
+impl SandboxFile {
+    /// `read` and `write` are the files the config names, whose directories are allowed.
+    fn parse(
+        self,
+        base: &Path,
+        read: Vec<PathBuf>,
+        write: Vec<PathBuf>,
+        inventory: &[InventoryConfig],
+        authorization: Option<&AuthorizationConfig>,
+    ) -> anyhow::Result<SandboxConfig> {
+        if !self.landlock && !self.seccomp {
+            return Ok(SandboxConfig::default());
+        }
+        if cfg!(not(target_os = "linux")) {
+            bail!("sandbox is configured, but Landlock and seccomp are only available on Linux");
+        }
+        if cfg!(not(feature = "sandbox")) {
+            bail!("sandbox is configured, but pukeko was built without the sandbox feature");
+        }
+        if let Some(source) = inventory.iter().find(|source| {
+            matches!(
+                source.source,
+                InventorySource::Exec { .. } | InventorySource::Ec2(_)
+            )
+        }) {
+            bail!(
+                "Inventory {} runs a program, which the sandbox does not allow",
+                source.name
+            );
+        }
+        if authorization.is_some_and(|authorization| {
+            matches!(authorization.hook, AuthorizationHook::Exec { .. })
+        }) {
+            bail!("authorization runs a program, which the sandbox does not allow");
+        }
+        let directories = |files: Vec<PathBuf>| {
+            let mut directories: Vec<PathBuf> = files
+                .iter()
+                .filter_map(|file| file.parent())
+                .map(Path::to_path_buf)
+                .collect();
+            directories.sort();
+            directories.dedup();
+            directories
+        };
+        let mut read_paths = directories(read);
+        read_paths.insert(0, base.to_path_buf());
+        read_paths.extend(self.read_paths.into_iter().map(|path| base.join(path)));
+        let mut write_paths = directories(write);
+        write_paths.extend(self.write_paths.into_iter().map(|path| base.join(path)));
+        Ok(SandboxConfig {
+            landlock: self.landlock,
+            read_paths,
+            write_paths,
+            seccomp: self.seccomp,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccessRequestsFile {
+    webhook: Option<String>,
+    #[serde(default = "default_access_duration")]
+    duration: u64,
+    #[serde(default = "default_max_access_duration")]
+    max_duration: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HoneypotFile {
+    #[serde(default)]
+    users: Vec<String>,
+    after_failures: Option<usize>,
+    #[serde(default = "default_honeypot_servers")]
+    servers: Vec<String>,
+    /// Milliseconds.
+    #[serde(default = "default_honeypot_delay")]
+    delay: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CertificatesFile {
+    ca_key: PathBuf,
+    #[serde(default = "default_certificate_validity")]
+    validity: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AuthorizationFile {
+    command: Option<Vec<String>>,
+    url: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default = "default_authorization_timeout")]
+    timeout: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InspectFile {
+    name: String,
+    pattern: String,
+    #[serde(default)]
+    direction: InspectDirection,
+    #[serde(default)]
+    action: InspectAction,
+    #[serde(default = "default_replacement")]
+    replacement: String,
+}
+
+fn default_replacement() -> String {
+    "[redacted]".to_string()
+}
+
+impl InspectFile {
+    fn parse(self) -> anyhow::Result<InspectRule> {
+        let pattern = regex::bytes::Regex::new(&self.pattern).with_context(|| {
+            format!(
+                "Invalid pattern {:?} for inspect rule {}",
+                self.pattern, self.name
+            )
+        })?;
+        Ok(InspectRule {
+            name: self.name,
+            pattern,
+            direction: self.direction,
+            action: self.action,
+            replacement: self.replacement,
+        })
+    }
+}
+
+impl HoneypotFile {
+    fn parse(self, users: &[UserEntry]) -> anyhow::Result<HoneypotConfig> {
+        if self.users.is_empty() && self.after_failures.is_none() {
+            bail!("honeypot needs users or after_failures to let anyone in");
+        }
+        if let Some(user) = self
+            .users
+            .iter()
+            .find(|decoy| users.iter().any(|user| &user.name == *decoy))
+        {
+            bail!("honeypot user {user} is a real user");
+        }
+        if self.servers.is_empty() {
+            bail!("honeypot needs servers to list");
+        }
+        Ok(HoneypotConfig {
+            users: self.users,
+            after_failures: self.after_failures,
+            servers: self.servers,
+            delay: Duration::from_millis(self.delay),
+        })
+    }
+}
+
+impl CertificatesFile {
+    fn parse(self, base: &Path) -> anyhow::Result<CertificatesConfig> {
+        let path = base.join(&self.ca_key);
+        let ca_key = PrivateKey::read_openssh_file(&path)
+            .with_context(|| format!("Failed to load certificate authority {}", path.display()))?;
+        if ca_key.is_encrypted() {
+            bail!("Certificate authority {} is encrypted", path.display());
+        }
+        if self.validity == 0 {
+            bail!("certificates validity must be at least one second");
+        }
+        Ok(CertificatesConfig {
+            ca_key,
+            validity: Duration::from_secs(self.validity),
+        })
+    }
+}
+
+impl AuthorizationFile {
+    fn parse(self) -> anyhow::Result<AuthorizationConfig> {
+        if !self.headers.is_empty() && self.url.is_none() {
+            bail!("authorization sets headers without a url");
+        }
+        let hook = match (self.command, self.url) {
+            (Some(_), Some(_)) => bail!("authorization sets both command and url"),
+            (Some(command), None) => {
+                if command.is_empty() {
+                    bail!("authorization has an empty command");
+                }
+                AuthorizationHook::Exec { command }
+            }
+            (None, Some(url)) => {
+                reqwest::Url::parse(&url)
+                    .with_context(|| format!("Invalid authorization URL {url}"))?;
+                AuthorizationHook::Http {
+                    url,
+                    headers: self.headers,
+                }
+            }
+            (None, None) => bail!("authorization needs a command or url"),
+        };
+        if self.timeout == 0 {
+            bail!("authorization timeout must be at least one second");
+        }
+        Ok(AuthorizationConfig {
+            hook,
+            timeout: Duration::from_secs(self.timeout),
+        })
+    }
+}
+
+impl AccessRequestsFile {
+    fn parse(self) -> anyhow::Result<AccessRequestsConfig> {
+        if self.duration == 0 {
+            bail!("access_requests duration must be at least 1 second");
+        }
+        if self.max_duration < self.duration {
+            bail!("access_requests max_duration must be at least duration");
+        }
+        Ok(AccessRequestsConfig {
+            webhook: self.webhook,
+            duration: Duration::from_secs(self.duration),
+            max_duration: Duration::from_secs(self.max_duration),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct OidcFile {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    client_secret_file: Option<PathBuf>,
+    #[serde(default = "default_oidc_scopes")]
+    scopes: Vec<String>,
+    user_claim: Option<String>,
+    #[serde(default = "default_oidc_max_flows_per_connection")]
+    max_flows_per_connection: usize,
+    #[serde(default = "default_oidc_max_flows_per_ip")]
+    max_flows_per_ip: usize,
+    #[serde(default = "default_oidc_flow_window")]
+    flow_window: u64,
+}
+
+impl OidcFile {
+    fn parse(self, base: &Path) -> anyhow::Result<OidcConfig> {
+        let client_secret = match (self.client_secret, self.client_secret_file) {
+            (Some(_), Some(_)) => bail!("oidc sets both client_secret and client_secret_file"),
+            (Some(secret), None) => Some(secret),
+            (None, Some(path)) => {
+                let path = base.join(path);
+                let secret = std::fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read {}", path.display()))?;
+                Some(secret.trim_end_matches(['\r', '\n']).to_string())
+            }
+            (None, None) => None,
+        };
+        // Claims such as preferred_username can often be changed by their users, so which
+        // one names the pukeko user is left to the admin.
+        let Some(user_claim) = self.user_claim else {
+            bail!(
+                "oidc must set user_claim to a claim users cannot change themselves, such as \"sub\""
+            );
+        };
+
+        Ok(OidcConfig {
+            issuer: self.issuer,
+            client_id: self.client_id,
+            client_secret,
+            scopes: self.scopes,
+            user_claim,
+            max_flows_per_connection: self.max_flows_per_connection,
+            max_flows_per_ip: self.max_flows_per_ip,
+            flow_window: Duration::from_secs(self.flow_window),
+        })
+    }
+}
+
+/// Host keys generated when none are configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HostKeyAlgorithm {
+    Ed25519,
+    Ecdsa,
+    Rsa,
+}
+
+impl HostKeyAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            HostKeyAlgorithm::Ed25519 => "ed25519",
+            HostKeyAlgorithm::Ecdsa => "ecdsa",
+            HostKeyAlgorithm::Rsa => "rsa",
+        }
+    }
+
+    fn algorithm(self) -> Algorithm {
+        match self {
+            HostKeyAlgorithm::Ed25519 => Algorithm::Ed25519,
+            HostKeyAlgorithm::Ecdsa => Algorithm::Ecdsa {
+                curve: EcdsaCurve::NistP256,
+            },
+            HostKeyAlgorithm::Rsa => Algorithm::Rsa { hash: None },
+        }
+    }
+}
+
+fn default_state_directory() -> PathBuf {
+    PathBuf::from("state")
+}
+
+fn default_host_key_algorithms() -> Vec<HostKeyAlgorithm> {
+    vec![HostKeyAlgorithm::Ed25519]
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn default_shell() -> Vec<String> {
+    vec!["/bin/sh".to_string()]
+}
+
+fn default_inventory_interval() -> u64 {
+    300
+}
+
+fn default_inventory_timeout() -> u64 {
+    30
+}
+
+fn default_ldap_user_filter() -> String {
+    "(uid={user})".to_string()
+}
+
+fn default_ldap_key_attribute() -> String {
+    "sshPublicKey".to_string()
+}
+
+fn default_ldap_group_attribute() -> String {
+    "memberOf".to_string()
+}
+
+fn default_ldap_timeout() -> u64 {
+    10
+}
+
+fn default_ldap_group_ttl() -> u64 {
+    8 * 3600
+}
+
+fn default_ldap_search_interval() -> u64 {
+    30
+}
+
+fn default_ldap_max_searches() -> usize {
+    600
+}
+
+fn default_oidc_scopes() -> Vec<String> {
+    vec!["openid".to_string(), "profile".to_string()]
+}
+
+fn default_oidc_max_flows_per_connection() -> usize {
+    3
+}
+
+fn default_oidc_max_flows_per_ip() -> usize {
+    10
+}
+
+fn default_oidc_flow_window() -> u64 {
+    600
+}
+
+fn default_known_hosts() -> PathBuf {
+    PathBuf::from("known_hosts")
+}
+
+fn default_forward_env() -> Vec<String> {
+    vec!["LANG".to_string(), "LC_*".to_string(), "TZ".to_string()]
+}
+
+fn default_shutdown_grace_period() -> u64 {
+    30
+}
+
+fn default_service_name() -> String {
+    "pukeko".to_string()
+}
+
+fn default_key_warn_days() -> u32 {
+    14
+}
+
+fn default_key_management_approval() -> bool {
+    true
+}
+
+fn default_agent_forwarding() -> bool {
+    true
+}
+
+fn default_min_keys() -> usize {
+    1
+}
+
+fn default_honeypot_servers() -> Vec<String> {
+    ["db-prod-01", "vault-01", "backup-02", "jenkins"]
+        .map(String::from)
+        .to_vec()
+}
+
+fn default_honeypot_delay() -> u64 {
+    100
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+fn default_plugin_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+fn default_certificate_validity() -> u64 {
+    600
+}
+
+fn default_authorization_timeout() -> u64 {
+    5
+}
+
+fn default_access_duration() -> u64 {
+    3600
+}
+
+fn default_max_access_duration() -> u64 {
+    28800
+}
+
+fn default_metrics_interval() -> u64 {
+    60
+}
+
+fn default_sync_interval() -> u64 {
+    2
+}
+
+fn default_node_timeout() -> u64 {
+    15
+}
+
+impl PukekoConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config {}", path.display()))?;
+        let file: ConfigFile = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse config {}", path.display()))?;
+
+        // Relative paths inside the config are resolved against the config's own directory,
+        // made absolute as the working directory changes when privileges.chroot is set.
+        let path = std::path::absolute(path)
+            .with_context(|| format!("Failed to resolve config {}", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new("/"));
+
+        let state_directory = base.join(&file.state_directory);
+        let host_key_paths: Vec<PathBuf> = file
+            .server_key
+            .into_iter()
+            .chain(file.host_keys)
+            .map(|path| base.join(path))
+            .collect();
+        let generated_host_keys: Vec<PathBuf> = if host_key_paths.is_empty() {
+            file.host_key_algorithms
+                .iter()
+                .map(|algorithm| state_directory.join(format!("ssh_host_{}_key", algorithm.name())))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let host_keys = if host_key_paths.is_empty() {
+            file.host_key_algorithms
+                .iter()
+                .zip(&generated_host_keys)
+                .map(|(&algorithm, path)| generated_host_key(&state_directory, path, algorithm))
+                .collect::<anyhow::Result<Vec<_>>>()?
+        } else {
+            host_key_paths
+                .iter()
+                .map(|path| {
+                    PrivateKey::read_openssh_file(path)
+                        .with_context(|| format!("Failed to load host key {}", path.display()))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+        };
+        if host_keys.is_empty() {
+            bail!("No host keys are configured");
+        }
+        let algorithms = file.algorithms.parse(&host_keys)?;
+
+        let upstream_key = file
+            .upstream_key
+            .as_ref()
+            .map(|path| {
+                let path = base.join(path);
+                PrivateKey::read_openssh_file(&path)
+                    .with_context(|| format!("Failed to load upstream key {}", path.display()))
+            })
+            .transpose()?;
+
+        let trusted_user_ca_keys = file
+            .trusted_user_ca_keys
+            .as_ref()
+            .map(|path| load_public_keys(&base.join(path)))
+            .transpose()?
+            .unwrap_or_default();
+        let trusted_proxy_ca_keys = file
+            .trusted_proxy_ca_keys
+            .as_ref()
+            .map(|path| load_public_keys(&base.join(path)))
+            .transpose()?
+            .unwrap_or_default();
+        if !trusted_proxy_ca_keys.is_empty() && file.proxies.is_empty() {
+            bail!("trusted_proxy_ca_keys needs [[proxies]] with the users each may connect for");
+        }
+
+        if file.health.interval == Some(0) {
+            bail!("Health check interval must be at least one second");
+        }
+        if file.keepalive.interval == Some(0) {
+            bail!("Keepalive interval must be at least one second");
+        }
+        if file.keepalive.max_missed == 0 {
+            bail!("Keepalive max_missed must be at least one");
+        }
+        if file.pool.max_channels == 0 || file.pool.max_connections == 0 {
+            bail!("Pool max_channels and max_connections must be at least one");
+        }
+        if file.limits.max_ban_duration < file.limits.ban_duration {
+            bail!("Limits max_ban_duration must be at least ban_duration");
+        }
+        if file.limits.max_in_flight == 0 {
+            bail!("Limits max_in_flight must be at least one");
+        }
+        if file.limits.max_session_buffered == Some(0) {
+            bail!("Limits max_session_buffered must be at least one");
+        }
+
+        let banner_text = match (file.banner.text, file.banner.file) {
+            (Some(_), Some(_)) => bail!("Banner sets both text and file"),
+            (Some(text), None) => Some(text),
+            (None, Some(path)) => {
+                let path = base.join(path);
+                Some(
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read banner {}", path.display()))?,
+                )
+            }
+            (None, None) => None,
+        };
+        let pre_auth_banner = match (file.banner.pre_auth, file.banner.pre_auth_file) {
+            (Some(_), Some(_)) => bail!("Banner sets both pre_auth and pre_auth_file"),
+            (Some(text), None) => Some(text),
+            (None, Some(path)) => {
+                let path = base.join(path);
+                Some(
+                    std::fs::read_to_string(&path)
+                        .with_context(|| format!("Failed to read banner {}", path.display()))?,
+                )
+            }
+            (None, None) => None,
+        };
+        if pre_auth_banner.is_some() && file.banner.mode == BannerMode::Auth {
+            bail!("Banner sets pre_auth with mode \"auth\", only one can be sent before login");
+        }
+
+        let inventory: Vec<InventoryConfig> = file
+            .inventory
+            .into_iter()
+            .map(InventoryFile::parse)
+            .collect::<anyhow::Result<_>>()?;
+        for (i, source) in inventory.iter().enumerate() {
+            if inventory[..i].iter().any(|other| other.name == source.name) {
+                bail!("Inventory {} is defined more than once", source.name);
+            }
+        }
+
+        let servers = parse_servers(file.servers, base)?;
+
+        let schedules = parse_schedules(file.schedules, &file.users, &file.groups)?;
+
+        let users = parse_users(file.users)?;
+        let honeypot = file
+            .honeypot
+            .map(|honeypot| honeypot.parse(&users))
+            .transpose()?;
+
+        if file.privileges.user.is_none()
+            && (file.privileges.group.is_some() || file.privileges.chroot)
+        {
+            bail!("privileges need a user to switch to");
+        }
+        if cfg!(not(unix)) && file.privileges.user.is_some() {
+            bail!("privileges can only be dropped on Unix");
+        }
+
+        for (i, listener) in file.listeners.iter().enumerate() {
+            if file.listeners[..i]
+                .iter()
+                .any(|other| other.address == listener.address)
+            {
+                bail!("Listener {} is configured twice", listener.address);
+            }
+        }
+
+        // Files read or written again on reload, or as pukeko runs.
+        let read = host_key_paths
+            .iter()
+            .chain(&file.upstream_key)
+            .chain(&file.trusted_user_ca_keys)
+            .chain(&file.trusted_proxy_ca_keys)
+            .chain(
+                file.certificates
+                    .iter()
+                    .chain(file.realms.iter().flat_map(|realm| &realm.certificates))
+                    .map(|certificates| &certificates.ca_key),
+            )
+            .chain(&file.audit.signing_key)
+            .chain(
+                file.realms
+                    .iter()
+                    .flat_map(|realm| &realm.audit.signing_key),
+            )
+            .chain(
+                file.cluster
+                    .iter()
+                    .flat_map(|cluster| &cluster.password_file),
+            )
+            .chain(&file.messages_directory)
+            .chain(
+                file.geoip
+                    .iter()
+                    .flat_map(|geoip| geoip.country_database.iter().chain(&geoip.asn_database)),
+            )
+            .map(|path| base.join(path))
+            .chain(file.authorized_keys.iter().map(|template| {
+                // The directory of every user's file is the one before the first %u.
+                let fixed = template.split('%').next().unwrap_or_default();
+                base.join(fixed).join("%u")
+            }))
+            .collect();
+        let write = [&file.known_hosts]
+            .into_iter()
+            .chain(&file.database)
+            .chain(&file.control_socket)
+            .chain(&file.upgrade_socket)
+            .chain(&file.audit.file)
+            .chain(file.realms.iter().flat_map(|realm| &realm.audit.file))
+            .map(|path| base.join(path))
+            .collect();
+        let authorization = file
+            .authorization
+            .map(AuthorizationFile::parse)
+            .transpose()?;
+        let sandbox = file
+            .sandbox
+            .parse(base, read, write, &inventory, authorization.as_ref())?;
+        let messages_directory = file
+            .messages_directory
+            .map(|directory| base.join(directory));
+        let (messages, locales) = Messages::load(file.messages, messages_directory.as_deref())?;
+
+        let mut config = Self {
+            host_keys,
+            state_directory,
+            generated_host_keys,
+            upstream_key,
+            known_hosts: base.join(file.known_hosts),
+            authorized_keys: file
+                .authorized_keys
+                .map(|template| base.join(template).to_string_lossy().into_owned()),
+            trusted_user_ca_keys,
+            trusted_proxy_ca_keys,
+            proxies: file.proxies,
+            host_key_policy: file.host_key_policy,
+            users,
+            groups: file.groups,
+            schedules,
+            servers,
+            inventory,
+            database: file.database.map(|path| base.join(path)),
+            ldap: file.ldap.map(|ldap| ldap.parse(base)).transpose()?,
+            oidc: file.oidc.map(|oidc| oidc.parse(base)).transpose()?,
+            cluster: file
+                .cluster
+                .map(|cluster| cluster.parse(base))
+                .transpose()?,
+            shutdown_grace_period: Duration::from_secs(file.shutdown_grace_period),
+            metrics_address: file.metrics_address,
+            telemetry: file.telemetry.map(TelemetryFile::parse).transpose()?,
+            control_socket: file.control_socket.map(|path| base.join(path)),
+            upgrade_socket: file.upgrade_socket.map(|path| base.join(path)),
+            proxy_protocol: file.proxy_protocol,
+            listeners: file.listeners,
+            allow_cidrs: parse_ranges(&file.allow_cidrs).context("Invalid allow_cidrs")?,
+            deny_cidrs: parse_ranges(&file.deny_cidrs).context("Invalid deny_cidrs")?,
+            geoip: file.geoip.map(|geoip| geoip.parse(base)).transpose()?,
+            limits: LimitsConfig {
+                max_sessions: file.limits.max_sessions,
+                max_sessions_per_ip: file.limits.max_sessions_per_ip,
+                max_unauthenticated: file.limits.max_unauthenticated,
+                max_channels: file.limits.max_channels,
+                max_auth_failures: file.limits.max_auth_failures,
+                auth_failure_window: Duration::from_secs(file.limits.auth_failure_window),
+                ban_duration: Duration::from_secs(file.limits.ban_duration),
+                max_ban_duration: Duration::from_secs(file.limits.max_ban_duration),
+                bandwidth: check_bandwidth(file.limits.bandwidth, "limits.bandwidth")?,
+                session_bandwidth: check_bandwidth(
+                    file.limits.session_bandwidth,
+                    "limits.session_bandwidth",
+                )?,
+                max_in_flight: file.limits.max_in_flight,
+                max_session_buffered: file.limits.max_session_buffered,
+            },
+            quotas: file.quotas,
+            timeouts: TimeoutsConfig {
+                login: Duration::from_secs(file.timeouts.login),
+                menu_idle: file.timeouts.menu_idle.map(Duration::from_secs),
+                menu_idle_warning: Duration::from_secs(file.timeouts.menu_idle_warning),
+                forward_idle: file.timeouts.forward_idle.map(Duration::from_secs),
+                detached: file.timeouts.detached.map(Duration::from_secs),
+            },
+            banner: BannerConfig {
+                text: banner_text,
+                mode: file.banner.mode,
+                pre_auth: pre_auth_banner,
+            },
+            health: HealthConfig {
+                interval: file.health.interval.map(Duration::from_secs),
+                timeout: Duration::from_secs(file.health.timeout),
+                probe: file.health.probe,
+            },
+            keepalive: KeepaliveConfig {
+                interval: file.keepalive.interval.map(Duration::from_secs),
+                max_missed: file.keepalive.max_missed,
+            },
+            pool: PoolConfig {
+                enabled: file.pool.enabled,
+                max_channels: file.pool.max_channels,
+                max_connections: file.pool.max_connections,
+                idle_timeout: Duration::from_secs(file.pool.idle_timeout),
+            },
+            algorithms,
+            theme: file.theme.parse()?,
+            themes: file
+                .themes
+                .into_iter()
+                .map(|(name, theme)| {
+                    let theme = theme
+                        .parse()
+                        .with_context(|| format!("Invalid theme {name}"))?;
+                    Ok((name, theme))
+                })
+                .collect::<anyhow::Result<_>>()?,
+            escape_char: file.keys.escape_char()?,
+            pane_prefix: file.keys.pane_prefix()?,
+            forward_env: file.forward_env,
+            clipboard: file.clipboard,
+            messages,
+            locales,
+            keys: file.keys.parse()?,
+            audit: parse_audit(file.audit, base)?,
+            shadow: file.shadow,
+            proxy_jump: file.proxy_jump,
+            privileges: file.privileges,
+            sandbox,
+            key_approval: file.key_approval,
+            key_expiry: file.key_expiry,
+            access_requests: file
+                .access_requests
+                .map(AccessRequestsFile::parse)
+                .transpose()?,
+            authorization,
+            certificates: file
+                .certificates
+                .map(|certificates| certificates.parse(base))
+                .transpose()?,
+            honeypot,
+            key_management: file.key_management,
+            confirm_tags: file.confirm_tags,
+            inspect: file
+                .inspect
+                .into_iter()
+                .map(InspectFile::parse)
+                .collect::<anyhow::Result<_>>()?,
+            announcements: file
+                .announcements
+                .into_iter()
+                .map(AnnouncementFile::parse)
+                .collect::<anyhow::Result<_>>()?,
+            plugins: file
+                .plugins
+                .into_iter()
+                .map(|plugin| plugin.parse(base))
+                .collect::<anyhow::Result<_>>()?,
+            realms: Vec::new(),
+        };
+
+        let realms: Vec<RealmConfig> = file
+            .realms
+            .into_iter()
+            .map(|realm| realm.parse(&config, base))
+            .collect::<anyhow::Result<_>>()?;
+        for (i, realm) in realms.iter().enumerate() {
+            if realms[..i].iter().any(|other| other.name == realm.name) {
+                bail!("Realm {} is defined more than once", realm.name);
+            }
+            let sandboxed = config.sandbox.landlock || config.sandbox.seccomp;
+            if sandboxed
+                && realm
+                    .config
+                    .authorization
+                    .as_ref()
+                    .is_some_and(|authorization| {
+                        matches!(authorization.hook, AuthorizationHook::Exec { .. })
+                    })
+            {
+                bail!(
+                    "authorization of realm {} runs a program, which the sandbox does not allow",
+                    realm.name
+                );
+            }
+            // Health, maintenance and pooled connections are kept by server name.
+            for server in &realm.config.servers {
+                let taken = config.server(&server.name).is_some()
+                    || realms[..i]
+                        .iter()
+                        .any(|other| other.config.server(&server.name).is_some());
+                if taken {
+                    bail!(
+                        "Server {} of realm {} is also defined outside it",
+                        server.name,
+                        realm.name
+                    );
+                }
+            }
+        }
+        for user in &config.users {
+            if let Some((_, realm)) = user.name.rsplit_once('@')
+                && realms.iter().any(|other| other.name == realm)
+            {
+                bail!("User {} is named as if in realm {}", user.name, realm);
+            }
+        }
+        for listener in &config.listeners {
+            if let Some(realm) = &listener.realm
+                && !realms.iter().any(|other| &other.name == realm)
+            {
+                bail!("Listener {} has unknown realm {}", listener.address, realm);
+            }
+        }
+        config.realms = realms;
+        Ok(config)
+    }
+
+    /// Why connections from `address` are refused by `allow_cidrs` or `deny_cidrs`, if
+    /// they are.
+    pub fn refuses(&self, address: IpAddr) -> Option<&'static str> {
+        if self.deny_cidrs.iter().any(|range| range.contains(address)) {
+            Some("address is in deny_cidrs")
+        } else if !self.allow_cidrs.is_empty()
+            && !self.allow_cidrs.iter().any(|range| range.contains(address))
+        {
+            Some("address is not in allow_cidrs")
+        } else {
+            None
+        }
+    }
+
+    /// Whether the variable `name` set by a client is passed on to `entry`.
+    pub fn forwards_env(&self, entry: &ServerEntry, name: &str) -> bool {
+        entry
+            .forward_env
+            .as_ref()
+            .unwrap_or(&self.forward_env)
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == pattern,
+            })
+    }
+
+    /// The messages for a client that set `env`, in its locale if there is a catalogue.
+    pub fn messages(&self, env: &[(String, String)]) -> Arc<Messages> {
+        messages::locale_names(env)
+            .into_iter()
+            .find_map(|name| self.locales.get(name))
+            .unwrap_or(&self.messages)
+            .clone()
+    }
+
+    pub fn clipboard_policy(&self, entry: &ServerEntry) -> ClipboardPolicy {
+        entry.clipboard.unwrap_or(self.clipboard)
+    }
+
+    pub fn user(&self, name: &str) -> Option<&UserEntry> {
+        self.users.iter().find(|user| user.name == name)
+    }
+
+    pub fn server(&self, name: &str) -> Option<&ServerEntry> {
+        self.servers.iter().find(|server| server.name == name)
+    }
+
+    pub fn realm(&self, name: &str) -> Option<&RealmConfig> {
+        self.realms.iter().find(|realm| realm.name == name)
+    }
+
+    /// The config of the realm `user` is in, named `user@realm`, or this one for users
+    /// outside any realm.
+    pub fn for_user(&self, user: &str) -> &PukekoConfig {
+        user.rsplit_once('@')
+            .and_then(|(_, realm)| self.realm(realm))
+            .map_or(self, |realm| &realm.config)
+    }
+
+    /// The first of the schedules `user` logs in within, their own and their groups', that
+    /// is closed at `at`. Their groups are those the config puts them in and `groups`,
+    /// those they are mapped to from outside it, such as from their directory groups.
+    pub fn closed_schedule(
+        &self,
+        user: &str,
+        groups: &[String],
+        at: DateTime<Utc>,
+    ) -> Option<&Schedule> {
+        let user = self.user(user);
+        let groups = self
+            .groups
+            .iter()
+            .filter(|group| {
+                groups.contains(&group.name)
+                    || user.is_some_and(|user| user.groups.contains(&group.name))
+            })
+            .filter_map(|group| group.schedule.as_ref());
+        user.and_then(|user| user.schedule.as_ref())
+            .into_iter()
+            .chain(groups)
+            .filter_map(|name| {
+                self.schedules
+                    .iter()
+                    .find(|schedule| &schedule.name == name)
+            })
+            .find(|schedule| !schedule.allows(at))
+    }
+
+    /// When the first of `user`'s schedules closes after `from`, if it does within
+    /// `within`. Schedules open and close on the minute.
+    pub fn schedule_closes(
+        &self,
+        user: &str,
+        groups: &[String],
+        from: DateTime<Utc>,
+        within: Duration,
+    ) -> Option<DateTime<Utc>> {
+        let start = from.duration_trunc(chrono::TimeDelta::minutes(1)).ok()?;
+        let until = from + chrono::TimeDelta::from_std(within).ok()?;
+        (1..)
+            .map(|minutes| start + chrono::TimeDelta::minutes(minutes))
+            .take_while(|at| *at < until)
+            .find(|at| self.closed_schedule(user, groups, *at).is_some())
+    }
+
+    /// The quotas `user` is held to. A limit set by any of the user's groups replaces the
+    /// global one, the most generous of them if several set it.
+    pub fn quotas(&self, user: &str) -> QuotaConfig {
+        let groups: Vec<QuotaConfig> = match self.user(user) {
+            Some(user) => self
+                .groups
+                .iter()
+                .filter(|group| user.groups.contains(&group.name))
+                .map(|group| group.quotas)
+                .collect(),
+            None => Vec::new(),
+        };
+        fn most<T: Ord>(global: Option<T>, groups: impl Iterator<Item = Option<T>>) -> Option<T> {
+            groups.flatten().max().or(global)
+        }
+        QuotaConfig {
+            max_forwards: most(
+                self.quotas.max_forwards,
+                groups.iter().map(|quotas| quotas.max_forwards),
+            ),
+            max_forwards_per_server: most(
+                self.quotas.max_forwards_per_server,
+                groups.iter().map(|quotas| quotas.max_forwards_per_server),
+            ),
+            daily_minutes: most(
+                self.quotas.daily_minutes,
+                groups.iter().map(|quotas| quotas.daily_minutes),
+            ),
+        }
+    }
+
+    /// Returns whether `user` may connect to `server`, either directly or through a group.
+    /// Grants name a server, `tag:<tag>` for every server with the tag, or `*` for all.
+    /// Users without any grants are denied everything.
+    pub fn can_access(&self, user: &str, server: &str) -> bool {
+        let tags = self
+            .server(server)
+            .map(|entry| entry.tags.as_slice())
+            .unwrap_or_default();
+        self.grants(user, &[], server, tags)
+    }
+
+    /// Like [`can_access`](Self::can_access), for servers that may not be in the config
+    /// such as those from an inventory.
+    pub fn can_access_server(&self, user: &str, server: &ServerEntry) -> bool {
+        self.can_access_server_with_groups(user, &[], server)
+    }
+
+    /// Like [`can_access_server`](Self::can_access_server), with the grants of `groups`
+    /// added to the user's own, such as groups from a directory. Users that are not in the
+    /// config get only those.
+    pub fn can_access_server_with_groups(
+        &self,
+        user: &str,
+        groups: &[String],
+        server: &ServerEntry,
+    ) -> bool {
+        self.grants(user, groups, &server.name, &server.tags)
+    }
+
+    fn grants(&self, user: &str, groups: &[String], server: &str, tags: &[String]) -> bool {
+        let (servers, user_groups) = match self.user(user) {
+            Some(user) => (user.servers.as_slice(), user.groups.as_slice()),
+            None => (&[][..], &[][..]),
+        };
+
+        let grants = servers.iter().chain(
+            self.groups
+                .iter()
+                .filter(|group| user_groups.contains(&group.name) || groups.contains(&group.name))
+                .flat_map(|group| group.servers.iter()),
+        );
+
+        grants.into_iter().any(|grant| {
+            grant == "*"
+                || grant == server
+                || grant
+                    .strip_prefix("tag:")
+                    .is_some_and(|tag| tags.iter().any(|t| t == tag))
+        })
+    }
+
+    pub fn accessible_servers(&self, user: &str) -> Vec<ServerEntry> {
+        self.servers
+            .iter()
+            .filter(|server| self.can_access_server(user, server))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Loads a file of OpenSSH public keys, one per line, skipping blank lines and comments.
+fn load_public_keys(path: &Path) -> anyhow::Result<Vec<PublicKey>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            PublicKey::from_openssh(line)
+                .with_context(|| format!("Invalid public key in {}", path.display()))
+        })
+        .collect()
+}
+
+/// Loads the host key for `algorithm` from the state directory, generating it on first start.
+fn generated_host_key(
+    state_directory: &Path,
+    path: &Path,
+    algorithm: HostKeyAlgorithm,
+) -> anyhow::Result<PrivateKey> {
+    if path.exists() {
+        return PrivateKey::read_openssh_file(path)
+            .with_context(|| format!("Failed to load host key {}", path.display()));
+    }
+
+    #[cfg(unix)]
+    let created = {
+        use std::os::unix::fs::DirBuilderExt;
+
+        std::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(state_directory)
+    };
+    #[cfg(not(unix))]
+    let created = std::fs::create_dir_all(state_directory);
+    created.with_context(|| format!("Failed to create {}", state_directory.display()))?;
+
+    let key = PrivateKey::random(&mut OsRng, algorithm.algorithm())
+        .with_context(|| format!("Failed to generate {} host key", algorithm.name()))?;
+    key.write_openssh_file(path, LineEnding::LF)
+        .with_context(|| format!("Failed to write host key {}", path.display()))?;
+    key.public_key()
+        .write_openssh_file(&path.with_extension("pub"))
+        .with_context(|| format!("Failed to write host key {}.pub", path.display()))?;
+    info!(
+        "Generated {} host key {}",
+        algorithm.name(),
+        key.public_key().fingerprint(Default::default())
+    );
+    Ok(key)
+}
+
+/// Loads a per-server key, refusing files that other users can read as OpenSSH does.
+fn load_server_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode = std::fs::metadata(path)
+            .with_context(|| format!("Failed to read server key {}", path.display()))?
+            .permissions()
+            .mode();
+        if mode & 0o077 != 0 {
+            bail!(
+                "Permissions {:o} for server key {} are too open",
+                mode & 0o777,
+                path.display()
+            );
+        }
+    }
+
+    PrivateKey::read_openssh_file(path)
+        .with_context(|| format!("Failed to load server key {}", path.display()))
+}
+
+/// Parses servers, checking those reached through others are reached through known ssh
+/// servers without going round in a loop.
+fn parse_servers(servers: Vec<ServerFile>, base: &Path) -> anyhow::Result<Vec<ServerEntry>> {
+    let servers = servers
+        .into_iter()
+        .map(|server| {
+            let key = match (server.key_file, server.key) {
+                (Some(_), Some(_)) => {
+                    bail!("Server {} sets both key_file and key", server.name)
+                }
+                (Some(path), None) => Some(load_server_key(&base.join(path))?),
+                (None, Some(pem)) => Some(
+                    PrivateKey::from_openssh(pem)
+                        .with_context(|| format!("Invalid key for server {}", server.name))?,
+                ),
+                (None, None) => None,
+            };
+            if key.as_ref().is_some_and(PrivateKey::is_encrypted) {
+                bail!(
+                    "Key for server {} is encrypted, which is not supported",
+                    server.name
+                );
+            }
+            let bandwidth = check_bandwidth(
+                server.bandwidth,
+                &format!("Bandwidth of server {}", server.name),
+            )?;
+            let commands = match (server.commands, server.command_patterns) {
+                (None, None) => None,
+                (commands, patterns) => Some(Arc::new(CommandPolicy::parse(
+                    &server.name,
+                    commands.unwrap_or_default(),
+                    patterns.unwrap_or_default(),
+                )?)),
+            };
+            let srv = server.srv.is_some();
+            if srv && (server.host.is_some() || server.port.is_some()) {
+                bail!(
+                    "Server {} sets srv, so takes its host and port from DNS",
+                    server.name
+                );
+            }
+            // The SRV name stands in for the host until it is looked up.
+            let (host, port) = server.protocol.address(
+                &server.name,
+                server.host.or(server.srv),
+                if srv { Some(0) } else { server.port },
+                server.pod.as_ref(),
+                server.container.as_ref(),
+            )?;
+            if server.protocol != Protocol::Ssh && (server.user.is_some() || key.is_some()) {
+                bail!(
+                    "Server {} sets a user or key, which only ssh servers use",
+                    server.name
+                );
+            }
+            if server.protocol != Protocol::Ssh && server.x11_forwarding {
+                bail!(
+                    "Server {} allows X11 forwarding, which only ssh servers support",
+                    server.name
+                );
+            }
+            let via = server
+                .via
+                .as_deref()
+                .map(Via::parse)
+                .transpose()
+                .with_context(|| format!("Invalid via of server {}", server.name))?;
+            if via.is_some() && matches!(server.protocol, Protocol::Kubernetes | Protocol::Docker) {
+                bail!(
+                    "Server {} sets via, which only ssh, tcp and telnet servers use",
+                    server.name
+                );
+            }
+            let backends = match server.backends {
+                Some(_) if srv => {
+                    bail!("Server {} sets srv, so cannot list backends", server.name)
+                }
+                Some(_) if matches!(server.protocol, Protocol::Kubernetes | Protocol::Docker) => {
+                    bail!(
+                        "Server {} lists backends, which only ssh, tcp and telnet servers use",
+                        server.name
+                    )
+                }
+                Some(backends) => Some(Arc::new(
+                    Backends::parse(&host, port, &backends, server.backend_policy)
+                        .with_context(|| format!("Invalid backends of server {}", server.name))?,
+                )),
+                None => None,
+            };
+            if commands.is_some() && server.login_command.is_some() {
+                bail!(
+                    "Server {} sets a login_command, but only allows commands, not shells",
+                    server.name
+                );
+            }
+            Ok(ServerEntry {
+                name: server.name,
+                host,
+                port,
+                protocol: server.protocol,
+                pod: server.pod.map(Arc::new),
+                container: server.container.map(Arc::new),
+                user: server.user,
+                key: key.map(Arc::new),
+                group: server.group,
+                tags: server.tags,
+                bandwidth,
+                commands,
+                pool: server.pool.unwrap_or(true),
+                login_command: server.login_command,
+                forward_env: server.forward_env,
+                x11_forwarding: server.x11_forwarding,
+                confirm: server.confirm,
+                clipboard: server.clipboard,
+                via,
+                srv,
+                backends,
+            })
+        })
+        .collect::<anyhow::Result<Vec<ServerEntry>>>()?;
+    for server in &servers {
+        let mut hops = vec![server.name.as_str()];
+        let mut next = server;
+        while let Some(Via::Jump(jump)) = &next.via {
+            let Some(hop) = servers.iter().find(|other| &other.name == jump) else {
+                bail!(
+                    "Server {} is reached through unknown server {}",
+                    server.name,
+                    jump
+                );
+            };
+            if hop.protocol != Protocol::Ssh {
+                bail!(
+                    "Server {} is reached through {}, which is not an ssh server",
+                    server.name,
+                    jump
+                );
+            }
+            if hops.contains(&jump.as_str()) {
+                bail!(
+                    "Server {} is reached through a loop of servers",
+                    server.name
+                );
+            }
+            hops.push(jump);
+            next = hop;
+        }
+    }
+    Ok(servers)
+}
+
+/// Parses schedules, checking the users and groups that name one name a known one.
+fn parse_schedules(
+    schedules: Vec<ScheduleFile>,
+    users: &[UserFile],
+    groups: &[GroupEntry],
+) -> anyhow::Result<Vec<Schedule>> {
+    let schedules: Vec<Schedule> = schedules
+        .into_iter()
+        .map(ScheduleFile::parse)
+        .collect::<anyhow::Result<_>>()?;
+    for (i, schedule) in schedules.iter().enumerate() {
+        if schedules[..i]
+            .iter()
+            .any(|other| other.name == schedule.name)
+        {
+            bail!("Schedule {} is defined more than once", schedule.name);
+        }
+    }
+    let owners = users
+        .iter()
+        .map(|user| ("User", &user.name, &user.schedule))
+        .chain(
+            groups
+                .iter()
+                .map(|group| ("Group", &group.name, &group.schedule)),
+        );
+    for (kind, owner, name) in owners {
+        if let Some(name) = name
+            && !schedules.iter().any(|schedule| &schedule.name == name)
+        {
+            bail!("{kind} {owner} has unknown schedule {name}");
+        }
+    }
+    Ok(schedules)
+}
+
+fn parse_users(users: Vec<UserFile>) -> anyhow::Result<Vec<UserEntry>> {
+    users
+        .into_iter()
+        .map(|user| {
+            let keys = user
+                .keys
+                .iter()
+                .map(KeyFile::parse)
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Invalid public key for user {}", user.name))?;
+            let totp_secret = user
+                .totp_secret
+                .map(|secret| totp::decode_secret(&secret))
+                .transpose()
+                .with_context(|| format!("Invalid TOTP secret for user {}", user.name))?;
+            if let Some(hash) = &user.password_hash {
+                password::validate(hash)
+                    .with_context(|| format!("Invalid password for user {}", user.name))?;
+            } else if user.password_and_key {
+                bail!(
+                    "User {} sets password_and_key without a password_hash",
+                    user.name
+                );
+            }
+            let remote_forwards = user
+                .remote_forwards
+                .iter()
+                .map(|rule| ForwardRule::parse(rule))
+                .collect::<anyhow::Result<_>>()
+                .with_context(|| format!("Invalid remote forward for user {}", user.name))?;
+            let bandwidth =
+                check_bandwidth(user.bandwidth, &format!("Bandwidth of user {}", user.name))?;
+            Ok(UserEntry {
+                name: user.name,
+                keys,
+                groups: user.groups,
+                servers: user.servers,
+                totp_secret,
+                password_hash: user.password_hash,
+                password_and_key: user.password_and_key,
+                admin: user.admin,
+                remote_forwards,
+                x11_forwarding: user.x11_forwarding,
+                agent_forwarding: user.agent_forwarding,
+                bandwidth,
+                schedule: user.schedule,
+            })
+        })
+        .collect()
+}
+
+fn check_bandwidth(bandwidth: Option<u64>, name: &str) -> anyhow::Result<Option<u64>> {
+    if bandwidth == Some(0) {
+        bail!("{name} must be greater than 0, or unset for no limit");
+    }
+    Ok(bandwidth)
+}
+
+/// Resolves the audit log's paths and checks its sinks can be used.
+fn parse_audit(audit: AuditConfig, base: &Path) -> anyhow::Result<AuditConfig> {
+    if cfg!(not(unix)) && audit.syslog {
+        bail!("Audit syslog is only available on Unix");
+    }
+    Ok(AuditConfig {
+        file: audit.file.map(|path| base.join(path)),
+        signing_key: audit.signing_key.map(|path| base.join(path)),
+        webhooks: check_webhooks(audit.webhooks)?,
+        ..audit
+    })
+}
+
+fn check_webhooks(webhooks: Vec<WebhookConfig>) -> anyhow::Result<Vec<WebhookConfig>> {
+    for webhook in &webhooks {
+        reqwest::Url::parse(&webhook.url)
+            .with_context(|| format!("Invalid audit webhook URL {}", webhook.url))?;
+    }
+    Ok(webhooks)
+}
+
+impl UserEntry {
+    pub fn has_key(&self, public_key: &PublicKey) -> bool {
+        self.key(public_key).is_some()
+    }
+
+    pub fn key(&self, public_key: &PublicKey) -> Option<&UserKey> {
+        // Comments are not part of the key material, so compare the key data only.
+        self.keys
+            .iter()
+            .find(|key| key.key.key_data() == public_key.key_data())
+    }
+
+    /// Whether the user may listen on `address`, which has been passed through
+    /// [`ForwardRule::normalize_address`]. Port 0, which lets the server pick, is only
+    /// allowed by rules that allow every port.
+    pub fn may_listen(&self, address: &str, port: u32) -> bool {
+        self.remote_forwards
+            .iter()
+            .any(|rule| rule.allows(address, port))
+    }
+}
+
+impl ForwardRule {
+    fn parse(rule: &str) -> anyhow::Result<Self> {
+        let Some((address, ports)) = rule.rsplit_once(':') else {
+            bail!("{rule} should be address:port");
+        };
+        let address = address.trim_start_matches('[').trim_end_matches(']');
+        let (first_port, last_port) = match ports {
+            "*" => (0, u16::MAX),
+            ports => {
+                let (first, last) = ports.split_once('-').unwrap_or((ports, ports));
+                let first: u16 = first
+                    .parse()
+                    .with_context(|| format!("Invalid port in {rule}"))?;
+                let last: u16 = last
+                    .parse()
+                    .with_context(|| format!("Invalid port in {rule}"))?;
+                if first == 0 || first > last {
+                    bail!("Invalid port range in {rule}");
+                }
+                (first, last)
+            }
+        };
+        Ok(Self {
+            address: Self::normalize_address(address).to_string(),
+            first_port,
+            last_port,
+        })
+    }
+
+    /// Maps the names clients use for every address and for loopback to the address
+    /// that is listened on.
+    pub fn normalize_address(address: &str) -> &str {
+        match address {
+            "" | "*" => "0.0.0.0",
+            "localhost" => "127.0.0.1",
+            address => address,
+        }
+    }
+
+    fn allows(&self, address: &str, port: u32) -> bool {
+        self.address == address
+            && if port == 0 {
+                self.first_port == 0 && self.last_port == u16::MAX
+            } else {
+                (u32::from(self.first_port)..=u32::from(self.last_port)).contains(&port)
+            }
+    }
+}
+
+impl ProxyEntry {
+    /// Whether the proxy may connect on behalf of `user`.
+    pub fn allows(&self, user: &str) -> bool {
+        self.users
+            .iter()
+            .any(|allowed| allowed == "*" || allowed == user)
+    }
+}
+
+impl AddressRange {
+    pub fn parse(range: &str) -> anyhow::Result<Self> {
+        let (network, prefix) = match range.split_once('/') {
+            Some((network, prefix)) => (network, Some(prefix)),
+            None => (range, None),
+        };
+        let network: IpAddr = network
+            .parse()
+            .with_context(|| format!("Invalid address in {range}"))?;
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => bits,
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|&prefix| prefix <= bits)
+                .with_context(|| format!("Invalid prefix length in {range}"))?,
+        };
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (network.to_bits() ^ address.to_bits()).leading_zeros() >= self.prefix
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (network.to_bits() ^ address.to_bits()).leading_zeros() >= self.prefix
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether an address is in both ranges, which is when one contains the other.
+    pub fn overlaps(&self, other: &AddressRange) -> bool {
+        let (wider, narrower) = if self.prefix <= other.prefix {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        wider.contains(narrower.network)
+    }
+}
+
+impl ScheduleFile {
+    fn parse(self) -> anyhow::Result<Schedule> {
+        let name = &self.name;
+        let time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .with_context(|| format!("Schedule {name} has invalid time {time}, use HH:MM"))
+        };
+        let day = |day: &str| {
+            day.trim()
+                .parse::<Weekday>()
+                .map_err(|_| anyhow::anyhow!("Schedule {name} has invalid day {day}"))
+        };
+
+        let mut days = Vec::new();
+        for range in &self.days {
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let (mut day, last) = (day(first)?, day(last)?);
+            loop {
+                if !days.contains(&day) {
+                    days.push(day);
+                }
+                if day == last {
+                    break;
+                }
+                day = day.succ();
+            }
+        }
+        if days.is_empty() {
+            days = vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+                Weekday::Sat,
+                Weekday::Sun,
+            ];
+        }
+
+        let timezone = self.timezone.parse().map_err(|_| {
+            anyhow::anyhow!("Schedule {name} has unknown timezone {}", self.timezone)
+        })?;
+        Ok(Schedule {
+            start: time(&self.start)?,
+            end: time(&self.end)?,
+            name: self.name,
+            days,
+            timezone,
+            terminate: self.terminate,
+        })
+    }
+}
+
+impl Schedule {
+    /// Whether users may log in at `at`.
+    pub fn allows(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone);
+        let (day, time) = (local.weekday(), local.time());
+        if self.start < self.end {
+            self.days.contains(&day) && (self.start..self.end).contains(&time)
+        } else {
+            (self.days.contains(&day) && time >= self.start)
+                || (self.days.contains(&day.pred()) && time < self.end)
+        }
+    }
+
+    /// The days and hours, such as `Mon,Tue 09:00-18:00 Pacific/Auckland`.
+    pub fn describe(&self) -> String {
+        let days = if self.days.len() == 7 {
+            "every day".to_string()
+        } else {
+            let mut days = self.days.clone();
+            days.sort_by_key(Weekday::num_days_from_monday);
+            days.iter()
+                .map(Weekday::to_string)
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        format!(
+            "{days} {}-{} {}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M"),
+            self.timezone
+        )
+    }
+}
+
+fn parse_ranges(ranges: &[String]) -> anyhow::Result<Vec<AddressRange>> {
+    ranges
+        .iter()
+        .map(|range| AddressRange::parse(range))
+        .collect()
+}
+
+/// Publishes changes to the running config, either reloaded from its file or made in place.
+#[derive(Debug, Clone)]
+pub struct ConfigUpdater {
+    path: PathBuf,
+    sender: watch::Sender<Arc<PukekoConfig>>,
+    store: Option<Arc<dyn Store>>,
+}
+
+impl ConfigUpdater {
+    pub fn new(path: PathBuf, sender: watch::Sender<Arc<PukekoConfig>>) -> Self {
+        Self {
+            path,
+            sender,
+            store: None,
+        }
+    }
+
+    /// Adds the users and servers in `store` to the config each time it is reloaded.
+    pub fn with_store(mut self, store: Arc<dyn Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    pub fn store(&self) -> Option<&Arc<dyn Store>> {
+        self.store.as_ref()
+    }
+
+    /// Loads the config file again, keeping the previous config if it is invalid.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let mut config = PukekoConfig::load(&self.path)?;
+        if let Some(store) = &self.store {
+            store::merge(&mut config, store.as_ref())?;
+        }
+        info!(
+            "Reloaded config with {} users and {} servers",
+            config.users.len(),
+            config.servers.len()
+        );
+        self.sender.send_replace(Arc::new(config));
+        Ok(())
+    }
+
+    /// Changes the running config. Changes last until the file is next reloaded.
+    pub fn modify(
+        &self,
+        change: impl FnOnce(&mut PukekoConfig) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let mut result = Ok(());
+        self.sender.send_if_modified(|config| {
+            let mut changed = PukekoConfig::clone(config);
+            match change(&mut changed) {
+                Ok(()) => {
+                    *config = Arc::new(changed);
+                    true
+                }
+                Err(e) => {
+                    result = Err(e);
+                    false
+                }
+            }
+        });
+        result
+    }
+}
+
+#[cfg(unix)]
+pub async fn reload_on_sighup(updater: ConfigUpdater) -> anyhow::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+    use tracing::error;
+
+    let mut sighup = signal(SignalKind::hangup())?;
+    while sighup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading {}", updater.path.display());
+        if let Err(e) = updater.reload() {
+            error!("Keeping previous config, reload failed: {:?}", e);
+        }
+    }
+    Ok(())
+}
+
+/// There is no SIGHUP off Unix, the config is reloaded with `pukeko ctl reload` instead.
+#[cfg(not(unix))]
+pub async fn reload_on_sighup(_updater: ConfigUpdater) -> anyhow::Result<()> {
+    bail!("SIGHUP is only available on Unix, use `pukeko ctl reload`")
+}
+
+#[cfg(test)]
+mod tests {
+    use rand_core::{OsRng, RngCore};
+
+    use super::*;
+
+    /// Loads `config` from a directory of its own, where its host key is generated.
+    fn load(config: &str) -> PukekoConfig {
+        let directory = std::env::temp_dir().join(format!(
+            "pukeko-config-{}-{}",
+            std::process::id(),
+            OsRng.next_u32()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("pukeko.toml");
+        std::fs::write(&path, config).unwrap();
+        let config = PukekoConfig::load(&path);
+        let _ = std::fs::remove_dir_all(&directory);
+        config.unwrap()
+    }
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn address_ranges_contain_their_block() {
+        let range = AddressRange::parse("10.0.0.0/8").unwrap();
+        assert!(range.contains(ip("10.0.0.0")));
+        assert!(range.contains(ip("10.255.255.255")));
+        assert!(!range.contains(ip("11.0.0.0")));
+        assert!(!range.contains(ip("9.255.255.255")));
+        // IPv4 clients accepted on an IPv6 socket are matched as IPv4.
+        assert!(range.contains(ip("::ffff:10.1.2.3")));
+        assert!(!range.contains(ip("::a01:203")));
+
+        let range = AddressRange::parse("2001:db8::/32").unwrap();
+        assert!(range.contains(ip("2001:db8:ffff::1")));
+        assert!(!range.contains(ip("2001:db9::1")));
+        assert!(!range.contains(ip("10.0.0.1")));
+
+        let range = AddressRange::parse("0.0.0.0/0").unwrap();
+        assert!(range.contains(ip("203.0.113.7")));
+        assert!(!range.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn bare_addresses_are_ranges_of_one() {
+        let range = AddressRange::parse("192.0.2.1").unwrap();
+        assert_eq!(range.prefix, 32);
+        assert!(range.contains(ip("192.0.2.1")));
+        assert!(!range.contains(ip("192.0.2.2")));
+        assert_eq!(AddressRange::parse("2001:db8::1").unwrap().prefix, 128);
+    }
+
+    #[test]
+    fn invalid_address_ranges_are_refused() {
+        for range in [
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "10.0.0.0/",
+            "10.0.0.0/-1",
+            "10.0.0.0/eight",
+            "10.0.0/8",
+            "example.com",
+            "",
+        ] {
+            assert!(AddressRange::parse(range).is_err(), "{range}");
+        }
+    }
+
+    /// A schedule open from `start` to `end` on `days`, in Auckland.
+    fn schedule(days: &[Weekday], start: &str, end: &str) -> Schedule {
+        Schedule {
+            name: "test".to_string(),
+            days: days.to_vec(),
+            start: NaiveTime::parse_from_str(start, "%H:%M").unwrap(),
+            end: NaiveTime::parse_from_str(end, "%H:%M").unwrap(),
+            timezone: "Pacific/Auckland".parse().unwrap(),
+            terminate: false,
+        }
+    }
+
+    /// `time` on `day` of March 2025 in Auckland, the 3rd being a Monday.
+    fn auckland(day: u32, time: &str) -> DateTime<Utc> {
+        let time = NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        let tz: Tz = "Pacific/Auckland".parse().unwrap();
+        chrono::NaiveDate::from_ymd_opt(2025, 3, day)
+            .unwrap()
+            .and_time(time)
+            .and_local_timezone(tz)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn schedules_allow_their_days_and_hours_in_their_timezone() {
+        let schedule = schedule(&[Weekday::Mon, Weekday::Tue], "09:00", "17:00");
+        assert!(schedule.allows(auckland(3, "09:00")));
+        assert!(schedule.allows(auckland(4, "16:59")));
+        assert!(!schedule.allows(auckland(3, "08:59")));
+        assert!(!schedule.allows(auckland(3, "17:00")));
+        assert!(!schedule.allows(auckland(5, "12:00")));
+        // Monday at noon in Auckland is still Sunday in UTC.
+        assert!(schedule.allows(auckland(3, "12:00")));
+    }
+
+    #[test]
+    fn overnight_schedules_run_into_the_next_day() {
+        let schedule = schedule(&[Weekday::Fri], "22:00", "06:00");
+        assert!(schedule.allows(auckland(7, "22:00")));
+        assert!(schedule.allows(auckland(7, "23:59")));
+        assert!(schedule.allows(auckland(8, "00:00")));
+        assert!(schedule.allows(auckland(8, "05:59")));
+        assert!(!schedule.allows(auckland(8, "06:00")));
+        assert!(!schedule.allows(auckland(7, "21:59")));
+        // The morning of the listed day belongs to the night before, which is not listed.
+        assert!(!schedule.allows(auckland(7, "03:00")));
+        assert!(!schedule.allows(auckland(8, "23:00")));
+    }
+
+    #[test]
+    fn users_reach_servers_granted_to_them_or_their_groups() {
+        let config = load(
+            r#"
+[[servers]]
+name = "web"
+host = "127.0.0.1"
+tags = ["prod"]
+
+[[servers]]
+name = "db"
+host = "127.0.0.1"
+tags = ["prod", "data"]
+
+[[servers]]
+name = "scratch"
+host = "127.0.0.1"
+
+[[groups]]
+name = "dba"
+servers = ["tag:data"]
+
+[[groups]]
+name = "everyone"
+servers = ["*"]
+
+[[users]]
+name = "alice"
+servers = ["web"]
+groups = ["dba"]
+
+[[users]]
+name = "bob"
+servers = ["tag:prod"]
+
+[[users]]
+name = "carol"
+servers = []
+groups = ["everyone"]
+
+[[users]]
+name = "dave"
+servers = []
+"#,
+        );
+        assert!(config.can_access("alice", "web"));
+        assert!(config.can_access("alice", "db"));
+        assert!(!config.can_access("alice", "scratch"));
+        assert!(config.can_access("bob", "web"));
+        assert!(config.can_access("bob", "db"));
+        assert!(!config.can_access("bob", "scratch"));
+        assert!(config.can_access("carol", "scratch"));
+        // Grants of `*` reach servers that are not in the config too.
+        assert!(config.can_access("carol", "elsewhere"));
+        assert!(!config.can_access("bob", "elsewhere"));
+        // Users without grants, and users that are not in the config, reach nothing.
+        assert!(!config.can_access("dave", "web"));
+        assert!(!config.can_access("mallory", "web"));
 
-    pub user_key: PublicKey,
+        // Groups from a directory add their grants, even for users not in the config.
+        let db = config.server("db").unwrap();
+        assert!(!config.can_access_server("mallory", db));
+        assert!(config.can_access_server_with_groups("mallory", &["dba".to_string()], db));
+        assert!(!config.can_access_server_with_groups("mallory", &["web".to_string()], db));
+        assert_eq!(
+            config
+                .accessible_servers("alice")
+                .iter()
+                .map(|server| server.name.as_str())
+                .collect::<Vec<_>>(),
+            ["web", "db"]
+        );
+    }
 }
@@ -0,0 +1,1002 @@
+use std::net::IpAddr;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::Path;
+#[cfg(windows)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+#[cfg(unix)]
+use rand_core::{OsRng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+#[cfg(windows)]
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeServer, ServerOptions};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tracing::{debug, info};
+
+use crate::access::AccessRequests;
+use crate::announcements::Announcements;
+use crate::approval::KeyApprovals;
+use crate::audit::{AuditEvent, AuditLog};
+use crate::config::{self, Announcement, ConfigReceiver, ConfigUpdater};
+use crate::limits::ConnectionLimiter;
+use crate::maintenance::{Maintenance, MaintenanceWindow};
+use crate::metrics::Metrics;
+use crate::sessions::{self, SessionRegistry};
+use crate::store::{self, Grantee, Store, StoreChange, StoredServer};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A request that was understood but could not be carried out.
+const REQUEST_FAILED: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Notifications have no id and get no response.
+    id: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(e: anyhow::Error) -> Self {
+        Self::new(REQUEST_FAILED, format!("{e:#}"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SessionParams {
+    id: usize,
+    /// The node of the cluster the session is on, when another.
+    #[serde(default)]
+    node: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BroadcastParams {
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NameParams {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddUserParams {
+    name: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyParams {
+    user: String,
+    key: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddKeyParams {
+    user: String,
+    key: String,
+    /// A date such as `2025-06-30`, or an RFC 3339 time.
+    expires: Option<String>,
+    comment: Option<String>,
+}
+
+/// Grants to either a user or a group.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GrantParams {
+    user: Option<String>,
+    group: Option<String>,
+    server: String,
+}
+
+impl GrantParams {
+    fn grantee(self) -> Result<(Grantee, String), RpcError> {
+        match (self.user, self.group) {
+            (Some(user), None) => Ok((Grantee::User(user), self.server)),
+            (None, Some(group)) => Ok((Grantee::Group(group), self.server)),
+            _ => Err(RpcError::new(
+                INVALID_PARAMS,
+                "Exactly one of user and group is required",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemberParams {
+    user: String,
+    group: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AddressParams {
+    address: IpAddr,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HistoryParams {
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ApproveParams {
+    id: u64,
+    /// Defaults to the configured `duration`.
+    duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RequestParams {
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MaintenanceParams {
+    /// Every server when unset.
+    server: Option<String>,
+    /// A date such as `2025-06-30`, or an RFC 3339 time. Until ended when unset.
+    until: Option<String>,
+    reason: Option<String>,
+    #[serde(default)]
+    allow_admins: bool,
+    #[serde(default)]
+    hide: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EndMaintenanceParams {
+    server: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AnnounceParams {
+    message: String,
+    /// Shown above the server list when unset.
+    server: Option<String>,
+    /// Dates such as `2025-06-30`, or RFC 3339 times.
+    from: Option<String>,
+    until: Option<String>,
+}
+
+fn default_history_limit() -> usize {
+    50
+}
+
+fn rfc3339(time: i64) -> String {
+    chrono::DateTime::from_timestamp(time, 0)
+        .unwrap_or_default()
+        .to_rfc3339()
+}
+
+/// What the control API operates on.
+pub(crate) struct Control {
+    pub config: ConfigReceiver,
+    pub updater: Option<ConfigUpdater>,
+    pub sessions: Arc<SessionRegistry>,
+    pub metrics: Arc<Metrics>,
+    pub audit: Arc<AuditLog>,
+    pub limiter: Arc<ConnectionLimiter>,
+    pub access: Arc<AccessRequests>,
+    pub approvals: Arc<KeyApprovals>,
+    pub maintenance: Arc<Maintenance>,
+    pub announcements: Arc<Announcements>,
+}
+
+/// Binds the control socket at `path`, replacing a stale one, so only the owner can use it.
+#[cfg(unix)]
+pub(crate) fn bind(path: &Path) -> anyhow::Result<UnixListener> {
+    let listener = bind_private(path)
+        .with_context(|| format!("Failed to bind control socket {}", path.display()))?;
+    info!("Serving control API on {}", path.display());
+    Ok(listener)
+}
+
+/// Binds a Unix socket at `path` that only the owner can connect to, replacing a stale one.
+///
+/// The socket is created with the umask's permissions, so it is bound in a new directory
+/// only the owner can enter and moved to `path` once it is restricted.
+#[cfg(unix)]
+pub(crate) fn bind_private(path: &Path) -> anyhow::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)
+            .with_context(|| format!("Failed to remove stale socket {}", path.display()))?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => {}
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let directory = parent.join(format!(".pukeko-{}", OsRng.next_u32()));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&directory)
+        .with_context(|| format!("Failed to create {}", directory.display()))?;
+    let staged = directory.join("socket");
+    let bound = UnixListener::bind(&staged)
+        .map_err(anyhow::Error::from)
+        .and_then(|listener| {
+            std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+            std::fs::rename(&staged, path)?;
+            Ok(listener)
+        });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&directory);
+    bound
+}
+
+/// Serves newline delimited JSON-RPC 2.0 requests on a Unix socket that only the
+/// owner can connect to.
+#[cfg(unix)]
+pub(crate) async fn serve(listener: UnixListener, control: Arc<Control>) -> anyhow::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &control).await {
+                debug!("Control connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+/// On Windows the control socket is a named pipe such as `\\.\pipe\pukeko`, with an
+/// instance waiting for the next client.
+#[cfg(windows)]
+pub(crate) struct PipeListener {
+    path: PathBuf,
+    next: NamedPipeServer,
+}
+
+/// Creates the named pipe at `path`. Without a security descriptor only its creator,
+/// administrators and LocalSystem can write to it, and remote clients are rejected.
+#[cfg(windows)]
+pub(crate) fn bind(path: &Path) -> anyhow::Result<PipeListener> {
+    let next = ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(path)
+        .with_context(|| format!("Failed to create control pipe {}", path.display()))?;
+    info!("Serving control API on {}", path.display());
+    Ok(PipeListener {
+        path: path.to_path_buf(),
+        next,
+    })
+}
+
+#[cfg(windows)]
+pub(crate) async fn serve(mut listener: PipeListener, control: Arc<Control>) -> anyhow::Result<()> {
+    loop {
+        listener.next.connect().await?;
+        let next = ServerOptions::new().create(&listener.path)?;
+        let stream = std::mem::replace(&mut listener.next, next);
+        let control = control.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &control).await {
+                debug!("Control connection failed: {:?}", e);
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite,
+    control: &Control,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) if request.jsonrpc != "2.0" => Some(Response::error(
+                request.id.unwrap_or(Value::Null),
+                RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported"),
+            )),
+            Ok(request) => {
+                let result = dispatch(control, &request.method, request.params).await;
+                request.id.map(|id| match result {
+                    Ok(result) => Response::result(id, result),
+                    Err(error) => Response::error(id, error),
+                })
+            }
+            Err(e) => Some(Response::error(
+                Value::Null,
+                RpcError::new(PARSE_ERROR, e.to_string()),
+            )),
+        };
+
+        if let Some(response) = response {
+            let mut line = serde_json::to_vec(&response)?;
+            line.push(b'\n');
+            writer.write_all(&line).await?;
+        }
+    }
+    Ok(())
+}
+
+impl Response {
+    fn result(id: Value, result: Value) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            result: Some(result),
+            error: None,
+            id,
+        }
+    }
+
+    fn error(id: Value, error: RpcError) -> Self {
+        Self {
+            jsonrpc: "2.0".to_string(),
+            result: None,
+            error: Some(error),
+            id,
+        }
+    }
+}
+
+fn params<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn updater(control: &Control) -> Result<&ConfigUpdater, RpcError> {
+    control.updater.as_ref().ok_or_else(|| {
+        RpcError::new(
+            REQUEST_FAILED,
+            "This server does not support changing its config",
+        )
+    })
+}
+
+fn store(control: &Control) -> Result<&Arc<dyn Store>, RpcError> {
+    control
+        .updater
+        .as_ref()
+        .and_then(ConfigUpdater::store)
+        .ok_or_else(|| RpcError::new(REQUEST_FAILED, "No database is configured"))
+}
+
+/// The name of this node of the cluster, if pukeko is clustered.
+fn this_node(control: &Control) -> Option<String> {
+    let config = control.config.borrow();
+    config.cluster.as_ref().map(|cluster| cluster.node.clone())
+}
+
+/// Makes a change to the store and reloads the config so that it takes effect.
+fn change_store(control: &Control, change: StoreChange) -> Result<Value, RpcError> {
+    store(control)?.apply(&change)?;
+    updater(control)?.reload()?;
+    info!("Changed the database from the control socket: {:?}", change);
+    Ok(Value::Null)
+}
+
+async fn dispatch(control: &Control, method: &str, params_value: Value) -> Result<Value, RpcError> {
+    let read_only = matches!(
+        method,
+        "sessions.list"
+            | "servers.list"
+            | "users.list"
+            | "history.list"
+            | "bans.list"
+            | "access.list"
+            | "keys.pending"
+            | "keys.approved"
+            | "maintenance.list"
+            | "announcements.list"
+            | "metrics"
+    );
+    if !read_only {
+        control.audit.record(AuditEvent::ControlRequest {
+            method,
+            params: &params_value,
+        });
+    }
+
+    match method {
+        "sessions.list" => {
+            let node = this_node(control);
+            let sessions: Vec<Value> = control
+                .sessions
+                .cluster_in(None)
+                .into_iter()
+                .map(|info| {
+                    json!({
+                        "id": info.id,
+                        "node": info.node.as_ref().or(node.as_ref()),
+                        "user": info.user,
+                        "peer": info.peer,
+                        "location": info.location,
+                        "target": info.target,
+                        "duration_secs": info.duration.as_secs(),
+                        "bytes": info.bytes(),
+                        "bytes_up": info.bytes_up,
+                        "bytes_down": info.bytes_down,
+                        "throughput": info.throughput,
+                        "buffered": info.buffered,
+                        "channels": info.channels,
+                        "forwards": info.quota_usage.forwards,
+                        "minutes_today": info.quota_usage.minutes_today,
+                    })
+                })
+                .collect();
+            Ok(Value::Array(sessions))
+        }
+        "sessions.kill" => {
+            const REASON: &str = "Terminated by an administrator";
+            let SessionParams { id, node } = params(params_value)?;
+            let node = node.filter(|node| Some(node) != this_node(control).as_ref());
+            let name = sessions::session_name(node.as_deref(), id);
+            let disconnected = match &node {
+                Some(node) => control.sessions.disconnect_remote(node, id, REASON),
+                None => control.sessions.disconnect(id, REASON).await,
+            };
+            if !disconnected {
+                return Err(RpcError::new(
+                    REQUEST_FAILED,
+                    format!("Session {name} is not connected"),
+                ));
+            }
+            info!("Terminated session {} from the control socket", name);
+            Ok(Value::Null)
+        }
+        "sessions.broadcast" => {
+            let BroadcastParams { message } = params(params_value)?;
+            let sessions = control.sessions.broadcast("administrator", None, &message);
+            info!("Messaged {} sessions from the control socket", sessions);
+            Ok(json!({ "sessions": sessions }))
+        }
+        "config.reload" => {
+            updater(control)?.reload()?;
+            Ok(Value::Null)
+        }
+        "servers.list" => {
+            let servers: Vec<Value> = control
+                .config
+                .borrow()
+                .servers
+                .iter()
+                .map(|server| {
+                    json!({
+                        "name": server.name,
+                        "host": server.host,
+                        "port": server.port,
+                        "user": server.user,
+                        "group": server.group,
+                        "tags": server.tags,
+                    })
+                })
+                .collect();
+            Ok(Value::Array(servers))
+        }
+        "servers.add" => {
+            let server: StoredServer = params(params_value)?;
+            if control.config.borrow().server(&server.name).is_some() {
+                return Err(RpcError::new(
+                    REQUEST_FAILED,
+                    format!("Server {} already exists", server.name),
+                ));
+            }
+            if store(control).is_ok() {
+                return change_store(control, StoreChange::AddServer(server));
+            }
+            updater(control)?.modify(|config| {
+                if config.server(&server.name).is_some() {
+                    bail!("Server {} already exists", server.name);
+                }
+                config.servers.push(server.entry());
+                Ok(())
+            })?;
+            info!("Added server {} from the control socket", server.name);
+            Ok(Value::Null)
+        }
+        "servers.remove" => {
+            let NameParams { name } = params(params_value)?;
+            let stored = match store(control) {
+                Ok(store) => store.servers()?.iter().any(|server| server.name == name),
+                Err(_) => false,
+            };
+            if stored {
+                return change_store(control, StoreChange::RemoveServer { name });
+            }
+            updater(control)?.modify(|config| {
+                let before = config.servers.len();
+                config.servers.retain(|server| server.name != name);
+                if config.servers.len() == before {
+                    bail!("No server named {}", name);
+                }
+                Ok(())
+            })?;
+            info!("Removed server {} from the control socket", name);
+            Ok(Value::Null)
+        }
+        "users.list" => {
+            Ok(serde_json::to_value(store(control)?.users()?).map_err(anyhow::Error::from)?)
+        }
+        "users.add" => {
+            let AddUserParams { name, admin } = params(params_value)?;
+            change_store(control, StoreChange::AddUser { name, admin })
+        }
+        "users.remove" => {
+            let NameParams { name } = params(params_value)?;
+            change_store(control, StoreChange::RemoveUser { name })
+        }
+        "keys.add" => {
+            let AddKeyParams {
+                user,
+                key,
+                expires,
+                comment,
+            } = params(params_value)?;
+            let key = store::normalize_key(&key)?;
+            let expires = expires
+                .as_deref()
+                .map(config::parse_time)
+                .transpose()?
+                .map(|expires| expires.timestamp());
+            change_store(
+                control,
+                StoreChange::AddKey {
+                    user,
+                    key,
+                    expires,
+                    comment,
+                },
+            )
+        }
+        "keys.remove" => {
+            let KeyParams { user, key } = params(params_value)?;
+            let key = store::normalize_key(&key)?;
+            change_store(control, StoreChange::RemoveKey { user, key })
+        }
+        "grants.add" => {
+            let (grantee, server) = params::<GrantParams>(params_value)?.grantee()?;
+            change_store(control, StoreChange::Grant { grantee, server })
+        }
+        "grants.remove" => {
+            let (grantee, server) = params::<GrantParams>(params_value)?.grantee()?;
+            change_store(control, StoreChange::Revoke { grantee, server })
+        }
+        "members.add" => {
+            let MemberParams { user, group } = params(params_value)?;
+            change_store(control, StoreChange::AddMember { user, group })
+        }
+        "members.remove" => {
+            let MemberParams { user, group } = params(params_value)?;
+            change_store(control, StoreChange::RemoveMember { user, group })
+        }
+        "history.list" => {
+            let HistoryParams { limit } = match params_value {
+                Value::Null => params(json!({}))?,
+                params_value => params(params_value)?,
+            };
+            let sessions = store(control)?.sessions(limit)?;
+            Ok(serde_json::to_value(sessions).map_err(anyhow::Error::from)?)
+        }
+        "bans.list" => {
+            let bans: Vec<Value> = control
+                .limiter
+                .bans()
+                .into_iter()
+                .map(|(address, ban)| {
+                    let until = chrono::DateTime::from_timestamp(ban.until, 0).unwrap_or_default();
+                    json!({
+                        "address": address,
+                        "until": until.to_rfc3339(),
+                        "duration_secs": ban.duration,
+                        "count": ban.count,
+                    })
+                })
+                .collect();
+            Ok(Value::Array(bans))
+        }
+        "bans.remove" => {
+            let AddressParams { address } = params(params_value)?;
+            if !control.limiter.unban(address) {
+                return Err(RpcError::new(
+                    REQUEST_FAILED,
+                    format!("{address} is not banned"),
+                ));
+            }
+            info!("Unbanned {} from the control socket", address);
+            Ok(Value::Null)
+        }
+        "access.list" => {
+            let time = |at: i64| {
+                chrono::DateTime::from_timestamp(at, 0)
+                    .unwrap_or_default()
+                    .to_rfc3339()
+            };
+            let pending: Vec<Value> = control
+                .access
+                .pending()
+                .into_iter()
+                .map(|request| {
+                    json!({
+                        "id": request.id,
+                        "user": request.user,
+                        "server": request.server,
+                        "reason": request.reason,
+                        "requested": time(request.requested),
+                    })
+                })
+                .collect();
+            let grants: Vec<Value> = control
+                .access
+                .grants()
+                .into_iter()
+                .map(|grant| {
+                    json!({
+                        "user": grant.user,
+                        "server": grant.server,
+                        "until": time(grant.until),
+                        "approved_by": grant.approved_by,
+                    })
+                })
+                .collect();
+            Ok(json!({ "pending": pending, "grants": grants }))
+        }
+        "access.approve" => {
+            let ApproveParams { id, duration_secs } = params(params_value)?;
+            let Some(settings) = control.config.borrow().access_requests.clone() else {
+                return Err(RpcError::new(
+                    REQUEST_FAILED,
+                    "Access requests are not enabled",
+                ));
+            };
+            let duration = duration_secs.map_or(settings.duration, Duration::from_secs);
+            if duration.is_zero() || duration > settings.max_duration {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!(
+                        "duration_secs must be between 1 and {}",
+                        settings.max_duration.as_secs()
+                    ),
+                ));
+            }
+            let grant = control.access.approve(id, duration, "administrator")?;
+            info!(
+                "Gave {} access to {} for {:?} from the control socket",
+                grant.user, grant.server, duration
+            );
+            let until = chrono::DateTime::from_timestamp(grant.until, 0).unwrap_or_default();
+            control.sessions.message(
+                &grant.user,
+                "administrator",
+                &format!(
+                    "Your access to {} was approved until {}",
+                    grant.server,
+                    until.format("%Y-%m-%d %H:%M UTC")
+                ),
+            );
+            Ok(json!({
+                "user": grant.user,
+                "server": grant.server,
+                "until": until.to_rfc3339(),
+            }))
+        }
+        "access.deny" => {
+            let RequestParams { id } = params(params_value)?;
+            let request = control.access.deny(id)?;
+            info!(
+                "Denied {} access to {} from the control socket",
+                request.user, request.server
+            );
+            control.sessions.message(
+                &request.user,
+                "administrator",
+                &format!("Your request for access to {} was denied", request.server),
+            );
+            Ok(Value::Null)
+        }
+        "keys.pending" => {
+            let pending: Vec<Value> = control
+                .approvals
+                .pending()
+                .into_iter()
+                .map(|pending| {
+                    let seen =
+                        chrono::DateTime::from_timestamp(pending.seen, 0).unwrap_or_default();
+                    json!({
+                        "id": pending.id,
+                        "user": pending.user,
+                        "fingerprint": pending.fingerprint,
+                        "key": pending.key,
+                        "peer": pending.peer,
+                        "seen": seen.to_rfc3339(),
+                    })
+                })
+                .collect();
+            Ok(Value::Array(pending))
+        }
+        "keys.approve" => {
+            let RequestParams { id } = params(params_value)?;
+            let pending = control.approvals.get(id)?;
+            // Users in the database get the key added there, like any other key.
+            let stored = match store(control) {
+                Ok(store) => store.users()?.iter().any(|user| user.name == pending.user),
+                Err(_) => false,
+            };
+            if stored {
+                change_store(
+                    control,
+                    StoreChange::AddKey {
+                        user: pending.user.clone(),
+                        key: pending.key.clone(),
+                        expires: None,
+                        comment: None,
+                    },
+                )?;
+            }
+            control.approvals.approve(id, stored)?;
+            info!(
+                "Approved key {} of {} from the control socket",
+                pending.fingerprint, pending.user
+            );
+            Ok(json!({
+                "user": pending.user,
+                "fingerprint": pending.fingerprint,
+                "stored": stored,
+            }))
+        }
+        "maintenance.list" => {
+            let windows: Vec<Value> = control
+                .maintenance
+                .windows()
+                .into_iter()
+                .map(|window| {
+                    json!({
+                        "server": window.server,
+                        "until": window.until.map(rfc3339),
+                        "reason": window.reason,
+                        "allow_admins": window.admins,
+                        "hide": window.hidden,
+                    })
+                })
+                .collect();
+            Ok(Value::Array(windows))
+        }
+        "maintenance.start" => {
+            let MaintenanceParams {
+                server,
+                until,
+                reason,
+                allow_admins,
+                hide,
+            } = params(params_value)?;
+            let until = until
+                .as_deref()
+                .map(config::parse_time)
+                .transpose()?
+                .map(|until| until.timestamp());
+            control.maintenance.start(MaintenanceWindow {
+                server: server.clone(),
+                until,
+                reason,
+                admins: allow_admins,
+                hidden: hide,
+            })?;
+            info!(
+                "Started maintenance of {} from the control socket",
+                server.as_deref().unwrap_or("every server")
+            );
+            Ok(Value::Null)
+        }
+        "maintenance.end" => {
+            let EndMaintenanceParams { server } = params(params_value)?;
+            let name = server.as_deref().unwrap_or("every server");
+            if !control.maintenance.end(server.as_deref()) {
+                return Err(RpcError::new(
+                    REQUEST_FAILED,
+                    format!("{name} is not under maintenance"),
+                ));
+            }
+            info!("Ended maintenance of {} from the control socket", name);
+            Ok(Value::Null)
+        }
+        "announcements.list" => {
+            let announcement = |id: Option<u64>, announcement: &Announcement| {
+                json!({
+                    "id": id,
+                    "message": announcement.message,
+                    "server": announcement.server,
+                    "from": announcement.from.map(rfc3339),
+                    "until": announcement.until.map(rfc3339),
+                })
+            };
+            let now = chrono::Utc::now().timestamp();
+            // Those in the config have no id, as they cannot be removed from here.
+            let configured = control.config.borrow().announcements.clone();
+            let configured = configured
+                .iter()
+                .filter(|configured| !configured.ended(now))
+                .map(|configured| announcement(None, configured));
+            let posted = control.announcements.posted();
+            let posted = posted
+                .iter()
+                .map(|posted| announcement(Some(posted.id), &posted.announcement));
+            Ok(Value::Array(configured.chain(posted).collect()))
+        }
+        "announcements.post" => {
+            let AnnounceParams {
+                message,
+                server,
+                from,
+                until,
+            } = params(params_value)?;
+            let announcement =
+                Announcement::parse(message, server, from.as_deref(), until.as_deref())?;
+            let id = control.announcements.post(announcement)?;
+            info!("Posted announcement {} from the control socket", id);
+            Ok(json!({ "id": id }))
+        }
+        "announcements.remove" => {
+            let RequestParams { id } = params(params_value)?;
+            if !control.announcements.remove(id) {
+                return Err(RpcError::new(
+                    REQUEST_FAILED,
+                    format!("No announcement {id}"),
+                ));
+            }
+            info!("Removed announcement {} from the control socket", id);
+            Ok(Value::Null)
+        }
+        "keys.approved" => {
+            let approved: Vec<Value> = control
+                .approvals
+                .approved_keys()
+                .into_iter()
+                .map(|approved| {
+                    json!({
+                        "user": approved.user,
+                        "fingerprint": approved.fingerprint(),
+                        "key": approved.key,
+                    })
+                })
+                .collect();
+            Ok(Value::Array(approved))
+        }
+        "keys.revoke" => {
+            let KeyParams { user, key } = params(params_value)?;
+            let revoked = control.approvals.revoke(&user, key.trim())?;
+            info!(
+                "Revoked approved key {} of {} from the control socket",
+                revoked.fingerprint().unwrap_or(revoked.key),
+                revoked.user
+            );
+            Ok(Value::Null)
+        }
+        "keys.reject" => {
+            let RequestParams { id } = params(params_value)?;
+            let pending = control.approvals.reject(id)?;
+            info!(
+                "Rejected key {} of {} from the control socket",
+                pending.fingerprint, pending.user
+            );
+            Ok(Value::Null)
+        }
+        "metrics" => {
+            Ok(serde_json::to_value(control.metrics.snapshot()).map_err(anyhow::Error::from)?)
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {method}"),
+        )),
+    }
+}
+
+/// Sends one request to a running server's control socket and returns its result.
+pub async fn call(socket: &Path, method: &str, params: Value) -> anyhow::Result<Value> {
+    #[cfg(unix)]
+    let stream = UnixStream::connect(socket).await;
+    #[cfg(windows)]
+    let stream = ClientOptions::new().open(socket);
+    let stream = stream.with_context(|| format!("Failed to connect to {}", socket.display()))?;
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    let request = json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": 1,
+    });
+    let mut line = serde_json::to_vec(&request)?;
+    line.push(b'\n');
+    writer.write_all(&line).await?;
+
+    let Some(line) = BufReader::new(reader).lines().next_line().await? else {
+        bail!("Server closed the control socket without responding");
+    };
+    let response: Response = serde_json::from_str(&line).context("Invalid response")?;
+    match (response.result, response.error) {
+        (_, Some(error)) => bail!("{}", error.message),
+        (result, None) => Ok(result.unwrap_or(Value::Null)),
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sockets_are_bound_for_the_owner_only() {
+        let directory = std::env::temp_dir().join(format!(
+            "pukeko-control-{}-{}",
+            std::process::id(),
+            OsRng.next_u32()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("control.sock");
+
+        let stale = bind_private(&path).unwrap();
+        drop(stale);
+        let _listener = bind_private(&path).unwrap();
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        UnixStream::connect(&path).await.unwrap();
+        // Nothing is left of the directories the sockets were bound in.
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        assert!(bind_private(&path).is_err());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
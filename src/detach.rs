@@ -0,0 +1,109 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::forward::Forward;
+
+/// Interactive sessions whose client disconnected without closing them, kept running for
+/// a while for the user to resume from the menu at their next login.
+#[derive(Default)]
+pub struct DetachedSessions {
+    state: Mutex<DetachedState>,
+}
+
+#[derive(Default)]
+struct DetachedState {
+    next_id: u64,
+    sessions: Vec<Detached>,
+}
+
+struct Detached {
+    id: u64,
+    user: String,
+    since: Instant,
+    forward: Forward,
+}
+
+/// A detached session offered to its user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachedInfo {
+    pub id: u64,
+    pub target: String,
+    /// How long ago the client disconnected.
+    pub age: Duration,
+}
+
+impl DetachedSessions {
+    /// Keeps `forward` running for `grace`, closing it then unless `user` resumed it.
+    pub fn detach(self: &Arc<Self>, user: &str, forward: Forward, grace: Duration) {
+        if forward.detach().is_err() {
+            return;
+        }
+        info!(
+            "Keeping {}'s session to {} for {}s",
+            user,
+            forward.target(),
+            grace.as_secs()
+        );
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            state.next_id += 1;
+            let id = state.next_id;
+            state.sessions.push(Detached {
+                id,
+                user: user.to_string(),
+                since: Instant::now(),
+                forward,
+            });
+            id
+        };
+
+        let sessions = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            if let Some(forward) = sessions.take(id) {
+                info!("Closing detached session to {}", forward.target());
+            }
+        });
+    }
+
+    /// The session `user` most recently detached from that is still running.
+    pub fn latest(&self, user: &str) -> Option<DetachedInfo> {
+        let mut state = self.state.lock().unwrap();
+        state
+            .sessions
+            .retain(|detached| !detached.forward.is_closed());
+        state
+            .sessions
+            .iter()
+            .rev()
+            .find(|detached| detached.user == user)
+            .map(|detached| DetachedInfo {
+                id: detached.id,
+                target: detached.forward.target().to_string(),
+                age: detached.since.elapsed(),
+            })
+    }
+
+    /// Takes the session `id` back to resume it, if it is still running and was detached
+    /// from `user`.
+    pub fn resume(&self, id: u64, user: &str) -> Option<Forward> {
+        let mut state = self.state.lock().unwrap();
+        let i = state
+            .sessions
+            .iter()
+            .position(|detached| detached.id == id && detached.user == user)?;
+        let detached = state.sessions.remove(i);
+        (!detached.forward.is_closed()).then_some(detached.forward)
+    }
+
+    fn take(&self, id: u64) -> Option<Forward> {
+        let mut state = self.state.lock().unwrap();
+        let i = state
+            .sessions
+            .iter()
+            .position(|detached| detached.id == id)?;
+        Some(state.sessions.remove(i).forward)
+    }
+}
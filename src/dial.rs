@@ -0,0 +1,295 @@
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+
+use anyhow::{Context, bail};
+use data_encoding::BASE64;
+use russh::ChannelStream;
+use russh::client::Msg;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+use crate::config::{PukekoConfig, ServerEntry, Via};
+use crate::provider::BoxFuture;
+use crate::upstream::{self, FailureReason, UpstreamHandle};
+
+/// A connection to an upstream, made directly or through whatever it is reached via.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Connects to `entry`, through its proxy or jump server if it has one. Jump servers are
+/// logged in to as `user`, unless they set their own.
+pub fn dial<'a>(
+    entry: &'a ServerEntry,
+    user: &'a str,
+    config: &'a PukekoConfig,
+) -> BoxFuture<'a, anyhow::Result<Box<dyn Stream>>> {
+    Box::pin(async move {
+        let stream: Box<dyn Stream> = match &entry.via {
+            Some(Via::Jump(server)) => Box::new(jump(entry, server, user, config).await?),
+            _ => Box::new(connect(entry).await?),
+        };
+        Ok(stream)
+    })
+}
+
+/// Runs `attempt` on `entry` pointed at each of its backends in turn until one succeeds,
+/// returning what it returned and the backend's host and port. Servers without backends
+/// are attempted once as they are. An upstream that rejects its host key or the login is
+/// not moved on from, as the others would be expected to as well.
+pub async fn each_backend<T, F, Fut>(
+    entry: &ServerEntry,
+    mut attempt: F,
+) -> anyhow::Result<(T, Option<(String, u16)>)>
+where
+    F: FnMut(ServerEntry) -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let Some(backends) = &entry.backends else {
+        return Ok((attempt(entry.clone()).await?, None));
+    };
+    let mut order = backends.order().into_iter().peekable();
+    while let Some((host, port)) = order.next() {
+        let mut backend = entry.clone();
+        backend.host = host.clone();
+        backend.port = *port;
+        match attempt(backend).await {
+            Ok(reached) => return Ok((reached, Some((host.clone(), *port)))),
+            Err(e)
+                if order.peek().is_some()
+                    && !matches!(
+                        FailureReason::of(&e),
+                        FailureReason::HostKey | FailureReason::Authentication
+                    ) =>
+            {
+                warn!(
+                    "Backend {}:{} of {} failed, trying the next: {:#}",
+                    host, port, entry.name, e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    bail!("{} has no backends", entry.name)
+}
+
+/// Connects to `entry` directly or through its proxy. Servers behind a jump server cannot
+/// be reached without logging in to it.
+pub async fn connect(entry: &ServerEntry) -> anyhow::Result<TcpStream> {
+    let address = (entry.host.as_str(), entry.port);
+    let stream = match &entry.via {
+        None => TcpStream::connect(address).await?,
+        Some(Via::Socks5 {
+            address: proxy,
+            credentials,
+        }) => {
+            debug!("Reaching {} through SOCKS5 proxy {}", entry.name, proxy);
+            let mut stream = TcpStream::connect(proxy.as_str())
+                .await
+                .with_context(|| format!("Failed to connect to proxy {proxy}"))?;
+            socks5(&mut stream, entry, credentials.as_ref())
+                .await
+                .with_context(|| format!("Proxy {proxy} failed"))?;
+            stream
+        }
+        Some(Via::Http {
+            address: proxy,
+            credentials,
+        }) => {
+            debug!("Reaching {} through HTTP proxy {}", entry.name, proxy);
+            let mut stream = TcpStream::connect(proxy.as_str())
+                .await
+                .with_context(|| format!("Failed to connect to proxy {proxy}"))?;
+            http_connect(&mut stream, entry, credentials.as_ref())
+                .await
+                .with_context(|| format!("Proxy {proxy} failed"))?;
+            stream
+        }
+        Some(Via::Jump(server)) => bail!("{} is only reachable through {}", entry.name, server),
+    };
+    let _ = stream.set_nodelay(true);
+    Ok(stream)
+}
+
+/// Asks a SOCKS5 proxy to connect to `entry`, see RFC 1928 and RFC 1929.
+async fn socks5(
+    stream: &mut TcpStream,
+    entry: &ServerEntry,
+    credentials: Option<&(String, String)>,
+) -> anyhow::Result<()> {
+    const VERSION: u8 = 5;
+    const NO_AUTHENTICATION: u8 = 0;
+    const PASSWORD: u8 = 2;
+    const CONNECT: u8 = 1;
+
+    let methods: &[u8] = match credentials {
+        Some(_) => &[NO_AUTHENTICATION, PASSWORD],
+        None => &[NO_AUTHENTICATION],
+    };
+    let mut greeting = vec![VERSION, methods.len() as u8];
+    greeting.extend_from_slice(methods);
+    stream.write_all(&greeting).await?;
+
+    let mut choice = [0; 2];
+    stream.read_exact(&mut choice).await?;
+    match (choice, credentials) {
+        ([VERSION, NO_AUTHENTICATION], _) => {}
+        ([VERSION, PASSWORD], Some((user, password))) => {
+            if user.len() > 255 || password.len() > 255 {
+                bail!("Proxy user or password is too long");
+            }
+            let mut request = vec![1, user.len() as u8];
+            request.extend_from_slice(user.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            stream.write_all(&request).await?;
+            let mut status = [0; 2];
+            stream.read_exact(&mut status).await?;
+            if status[1] != 0 {
+                bail!("Proxy rejected the user and password");
+            }
+        }
+        _ => bail!("Proxy asks for authentication that is not supported"),
+    }
+
+    let mut request = vec![VERSION, CONNECT, 0];
+    match entry.host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if entry.host.len() > 255 {
+                bail!("Host {} is too long", entry.host);
+            }
+            request.extend_from_slice(&[3, entry.host.len() as u8]);
+            request.extend_from_slice(entry.host.as_bytes());
+        }
+    }
+    request.extend_from_slice(&entry.port.to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        let reason = match reply[1] {
+            2 => "connection not allowed",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            _ => "general failure",
+        };
+        bail!("Proxy could not connect to {}: {}", entry.name, reason);
+    }
+    // The address the proxy bound, which is of no use here.
+    let address = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => stream.read_u8().await? as usize,
+        kind => bail!("Proxy replied with unknown address type {kind}"),
+    };
+    let mut bound = vec![0; address + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+/// Asks an HTTP proxy to CONNECT to `entry`.
+async fn http_connect(
+    stream: &mut TcpStream,
+    entry: &ServerEntry,
+    credentials: Option<&(String, String)>,
+) -> anyhow::Result<()> {
+    // Long enough for any proxy's headers, which should be few.
+    const MAX_RESPONSE: usize = 8192;
+
+    let target = match entry.host.parse::<IpAddr>() {
+        Ok(IpAddr::V6(ip)) => format!("[{ip}]:{}", entry.port),
+        _ => format!("{}:{}", entry.host, entry.port),
+    };
+    let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+    if let Some((user, password)) = credentials {
+        let encoded = BASE64.encode(format!("{user}:{password}").as_bytes());
+        request.push_str(&format!("Proxy-Authorization: Basic {encoded}\r\n"));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time, so nothing the upstream sends after the headers is lost.
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_RESPONSE {
+            bail!("Proxy response is too long");
+        }
+        response.push(stream.read_u8().await?);
+    }
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!("Proxy refused to connect to {}: {}", entry.name, status),
+    }
+}
+
+/// Connects to `entry` through the channel `server` forwards to it, as `ssh -J` does.
+async fn jump(
+    entry: &ServerEntry,
+    server: &str,
+    user: &str,
+    config: &PukekoConfig,
+) -> anyhow::Result<Hop> {
+    let hop = config
+        .server(server)
+        .with_context(|| format!("Unknown server {server}"))?;
+    debug!("Reaching {} through {}", entry.name, server);
+    let upstream = upstream::connect(hop, user, config, None, None).await?;
+    let channel = upstream
+        .channel_open_direct_tcpip(entry.host.clone(), entry.port.into(), "127.0.0.1", 0)
+        .await
+        .with_context(|| format!("{server} failed to forward to {}", entry.name))?;
+    Ok(Hop {
+        stream: channel.into_stream(),
+        _upstream: upstream,
+    })
+}
+
+/// A channel a jump server forwards, which keeps the connection to it open.
+struct Hop {
+    stream: ChannelStream<Msg>,
+    _upstream: UpstreamHandle,
+}
+
+impl AsyncRead for Hop {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Hop {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::{Context, bail};
+use bollard::Docker;
+use bollard::container::LogOutput;
+use bollard::exec::{StartExecOptions, StartExecResults};
+use bollard::models::{ContainerSummary, ExecConfig};
+use bollard::query_parameters::{ListContainersOptions, ResizeExecOptions};
+use futures_util::{Stream, StreamExt};
+use tracing::debug;
+
+use crate::config::{ContainerTarget, DockerConfig, Protocol, ServerEntry};
+use crate::flow::{UpstreamWriter, Written};
+use crate::forward::{ForwardKind, PtyRequest, UpstreamOutput, UpstreamSession};
+use crate::inventory::ServerSource;
+use crate::provider::BoxFuture;
+use crate::upstream::CONNECT_TIMEOUT;
+
+/// The extended data type SSH gives stderr.
+const STDERR: u32 = 1;
+
+type Output = Pin<Box<dyn Stream<Item = Result<LogOutput, bollard::errors::Error>> + Send>>;
+
+fn connect(docker: Option<&str>) -> anyhow::Result<Docker> {
+    let client = match docker {
+        Some(host) => Docker::connect_with_host(host),
+        None => Docker::connect_with_defaults(),
+    };
+    client.context("Failed to connect to docker")
+}
+
+/// A command run in a container, as `docker exec` runs it.
+pub struct ContainerSession {
+    docker: Docker,
+    exec: String,
+    output: Output,
+    input: Option<UpstreamWriter>,
+    ended: bool,
+    eof: bool,
+}
+
+impl ContainerSession {
+    /// Runs the shell for `kind` in the container, or the command with `/bin/sh -c`.
+    pub async fn open(
+        entry: &ServerEntry,
+        kind: &ForwardKind,
+        pty: Option<&PtyRequest>,
+        env: &[(String, String)],
+    ) -> anyhow::Result<Self> {
+        let Some(container) = &entry.container else {
+            bail!("{} is not a container", entry.name)
+        };
+        let command = match kind {
+            ForwardKind::Shell => container.shell.clone(),
+            ForwardKind::Exec(command) => vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                String::from_utf8_lossy(command).into_owned(),
+            ],
+            ForwardKind::Subsystem(name) => {
+                bail!(
+                    "{} is a container, which has no {} subsystem",
+                    entry.name,
+                    name
+                )
+            }
+        };
+
+        let docker = connect(container.docker.as_deref())?;
+        let config = ExecConfig {
+            attach_stdin: Some(true),
+            attach_stdout: Some(true),
+            attach_stderr: Some(true),
+            tty: Some(pty.is_some()),
+            console_size: pty.map(|pty| vec![pty.row_height as usize, pty.col_width as usize]),
+            env: Some(
+                pty.map(|pty| ("TERM", pty.term.as_str()))
+                    .into_iter()
+                    .chain(
+                        env.iter()
+                            .map(|(name, value)| (name.as_str(), value.as_str())),
+                    )
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect(),
+            ),
+            cmd: Some(command),
+            user: container.user.clone(),
+            ..Default::default()
+        };
+        debug!(
+            "Executing {:?} in container {} for {}",
+            config.cmd, container.name, entry.name
+        );
+        let start = async {
+            let exec = docker.create_exec(&container.name, config).await?.id;
+            let options = StartExecOptions {
+                tty: pty.is_some(),
+                ..Default::default()
+            };
+            match docker.start_exec(&exec, Some(options)).await? {
+                StartExecResults::Attached { output, input } => anyhow::Ok((exec, output, input)),
+                StartExecResults::Detached => bail!("Docker did not attach to the command"),
+            }
+        };
+        let (exec, output, input) = tokio::time::timeout(CONNECT_TIMEOUT, start)
+            .await
+            .with_context(|| format!("Timed out connecting to {}", entry.name))?
+            .with_context(|| format!("Failed to exec into {}", entry.name))?;
+
+        Ok(Self {
+            docker,
+            exec,
+            output,
+            input: Some(UpstreamWriter::spawn(input)),
+            ended: false,
+            eof: false,
+        })
+    }
+}
+
+impl UpstreamSession for ContainerSession {
+    fn recv(&mut self) -> BoxFuture<'_, Option<UpstreamOutput>> {
+        Box::pin(async move {
+            loop {
+                if self.ended {
+                    if !self.eof {
+                        self.eof = true;
+                        return Some(UpstreamOutput::Eof);
+                    }
+                    return None;
+                }
+                match self.output.next().await {
+                    Some(Ok(LogOutput::StdOut { message } | LogOutput::Console { message })) => {
+                        return Some(UpstreamOutput::Data(message.to_vec()));
+                    }
+                    Some(Ok(LogOutput::StdErr { message })) => {
+                        return Some(UpstreamOutput::ExtendedData(message.to_vec(), STDERR));
+                    }
+                    Some(Ok(LogOutput::StdIn { .. })) => {}
+                    Some(Err(e)) => {
+                        debug!("Failed to read from the container: {}", e);
+                        self.ended = true;
+                    }
+                    None => {
+                        self.ended = true;
+                        let exit_code = self
+                            .docker
+                            .inspect_exec(&self.exec)
+                            .await
+                            .ok()
+                            .and_then(|exec| exec.exit_code);
+                        if let Some(exit_code) = exit_code {
+                            return Some(UpstreamOutput::ExitStatus(exit_code as u32));
+                        }
+                    }
+                }
+            }
+        })
+    }
+
+    fn send(&mut self, data: Vec<u8>, written: Written) -> anyhow::Result<()> {
+        let input = self
+            .input
+            .as_ref()
+            .context("Input to the container was closed")?;
+        input.write(data, Some(written))
+    }
+
+    fn eof(&mut self) -> BoxFuture<'_, ()> {
+        if let Some(input) = self.input.take() {
+            input.shutdown();
+        }
+        Box::pin(std::future::ready(()))
+    }
+
+    fn window_change(
+        &mut self,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+    ) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let options = ResizeExecOptions {
+                h: row_height.min(i32::MAX as u32) as i32,
+                w: col_width.min(i32::MAX as u32) as i32,
+            };
+            if let Err(e) = self.docker.resize_exec(&self.exec, options).await {
+                debug!("Failed to resize the container's terminal: {}", e);
+            }
+        })
+    }
+
+    /// Dropping the connection ends the exec.
+    fn close(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Lists the running containers of a Docker daemon.
+///
+/// Each container's labels become server tags of the form `key:value`, except those set
+/// by Docker itself and OCI image annotations.
+#[derive(Debug, Clone)]
+pub struct DockerSource {
+    config: DockerConfig,
+}
+
+impl DockerSource {
+    pub fn new(config: DockerConfig) -> Self {
+        Self { config }
+    }
+
+    fn server(&self, container: ContainerSummary) -> Option<ServerEntry> {
+        let name = match container.names.as_deref().and_then(<[String]>::first) {
+            Some(name) => name.trim_start_matches('/').to_string(),
+            None => container.id?.chars().take(12).collect(),
+        };
+        let labels = container.labels.unwrap_or_default();
+        let group = self
+            .config
+            .group_label
+            .as_ref()
+            .and_then(|label| labels.get(label).cloned());
+        let mut tags: Vec<String> = labels
+            .iter()
+            .filter(|(key, _)| {
+                !key.starts_with("com.docker.") && !key.starts_with("org.opencontainers.")
+            })
+            .map(|(key, value)| format!("{key}:{value}"))
+            .collect();
+        tags.sort();
+        let target = ContainerTarget {
+            name: name.clone(),
+            docker: self.config.docker.clone(),
+            user: self.config.user.clone(),
+            shell: self.config.shell.clone(),
+        };
+        Some(ServerEntry {
+            name: name.clone(),
+            host: name,
+            port: 0,
+            protocol: Protocol::Docker,
+            pod: None,
+            container: Some(Arc::new(target)),
+            user: None,
+            key: None,
+            group,
+            tags,
+            bandwidth: None,
+            commands: None,
+            pool: true,
+            login_command: None,
+            forward_env: None,
+            x11_forwarding: false,
+            confirm: false,
+            clipboard: None,
+            via: None,
+            srv: false,
+            backends: None,
+        })
+    }
+}
+
+impl ServerSource for DockerSource {
+    fn fetch(&self) -> BoxFuture<'_, anyhow::Result<Vec<ServerEntry>>> {
+        Box::pin(async move {
+            let docker = connect(self.config.docker.as_deref())?;
+            let labels = self
+                .config
+                .labels
+                .iter()
+                .map(|(key, value)| match value.as_str() {
+                    "*" => key.clone(),
+                    value => format!("{key}={value}"),
+                })
+                .collect();
+            let options = ListContainersOptions {
+                filters: Some(HashMap::from([("label".to_string(), labels)]))
+                    .filter(|_| !self.config.labels.is_empty()),
+                ..Default::default()
+            };
+            let containers = docker
+                .list_containers(Some(options))
+                .await
+                .context("Failed to list containers")?;
+            let mut servers: Vec<ServerEntry> = containers
+                .into_iter()
+                .filter_map(|container| self.server(container))
+                .collect();
+            servers.sort_by(|a, b| a.name.cmp(&b.name));
+            Ok(servers)
+        })
+    }
+}
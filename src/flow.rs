@@ -0,0 +1,192 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use russh::server::{Handle, Msg};
+use russh::{ChannelId, ChannelWriteHalf};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, mpsc};
+use tracing::{Instrument, trace};
+
+use crate::sessions::SessionUsage;
+
+const STDERR: u32 = 1;
+
+/// The window clients are given to send in, widened again as their input arrives.
+pub const CLIENT_WINDOW: u32 = 2 * 1024 * 1024;
+
+/// The window a client is widened to while its forward has no room for more input. russh
+/// widens windows only as input arrives, and only once they are below half of this, so it
+/// must be at least two for the client to go on sending anything and be widened again.
+pub const PAUSED_WINDOW: u32 = 2;
+
+/// Input a client has sent that is yet to be written to its upstream. Past the limit, the
+/// client's window is kept shut so that a slow upstream makes the client wait rather than
+/// filling memory. It is also counted in the session's usage, with that of its other
+/// forwards.
+#[derive(Debug)]
+pub struct InFlight {
+    limit: usize,
+    bytes: AtomicUsize,
+    usage: Arc<SessionUsage>,
+}
+
+impl InFlight {
+    pub fn new(limit: usize, usage: Arc<SessionUsage>) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            bytes: AtomicUsize::new(0),
+            usage,
+        })
+    }
+
+    pub fn has_room(&self) -> bool {
+        self.bytes.load(Ordering::Relaxed) < self.limit
+    }
+
+    /// Counts `len` bytes of input until the returned guard is dropped, once they have
+    /// been written.
+    pub fn take(self: &Arc<Self>, len: usize) -> Written {
+        self.bytes.fetch_add(len, Ordering::Relaxed);
+        self.usage.buffered.fetch_add(len, Ordering::Relaxed);
+        Written {
+            in_flight: self.clone(),
+            len,
+        }
+    }
+}
+
+/// Input counted by [`InFlight`], released when dropped.
+#[derive(Debug)]
+pub struct Written {
+    in_flight: Arc<InFlight>,
+    len: usize,
+}
+
+impl Drop for Written {
+    fn drop(&mut self) {
+        self.in_flight.bytes.fetch_sub(self.len, Ordering::Relaxed);
+        self.in_flight
+            .usage
+            .buffered
+            .fetch_sub(self.len, Ordering::Relaxed);
+    }
+}
+
+/// Writes to an upstream from a task of its own, so that the upstream's output goes on
+/// being read while it is slow to take input. Otherwise an upstream waiting for its output
+/// to be read before it reads more would never be written to again.
+#[derive(Debug)]
+pub struct UpstreamWriter {
+    /// Writes for the task, with None to shut the writer down. Sending never waits.
+    writes: mpsc::UnboundedSender<Option<(Vec<u8>, Option<Written>)>>,
+}
+
+impl UpstreamWriter {
+    pub fn spawn<W: AsyncWrite + Send + Unpin + 'static>(mut writer: W) -> Self {
+        let (writes, mut pending) = mpsc::unbounded_channel::<Option<(Vec<u8>, _)>>();
+        tokio::spawn(
+            async move {
+                while let Some(write) = pending.recv().await {
+                    let result = match write {
+                        // Released once written, or dropped.
+                        Some((data, _written)) => writer.write_all(&data).await,
+                        None => {
+                            let _ = writer.shutdown().await;
+                            break;
+                        }
+                    };
+                    if let Err(e) = result {
+                        trace!("Failed to write upstream: {}", e);
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+        Self { writes }
+    }
+
+    /// Queues `data`, releasing `written` once it has been written.
+    pub fn write(&self, data: Vec<u8>, written: Option<Written>) -> anyhow::Result<()> {
+        if !data.is_empty() {
+            self.writes
+                .send(Some((data, written)))
+                .map_err(|_| anyhow::anyhow!("Upstream connection closed"))?;
+        }
+        Ok(())
+    }
+
+    /// Shuts the writer down after what is queued, sending the upstream an EOF.
+    pub fn shutdown(&self) {
+        let _ = self.writes.send(None);
+    }
+}
+
+/// Writes output to the client's channel as its window allows, so that output for a
+/// client slow to read it waits rather than piling up.
+pub struct ChannelOutput {
+    handle: Handle,
+    channel: ChannelId,
+    data: Box<dyn AsyncWrite + Send + Unpin>,
+    stderr: Box<dyn AsyncWrite + Send + Unpin>,
+    /// Notified when the client is gone, as its window will not open again.
+    gone: Arc<Notify>,
+}
+
+impl ChannelOutput {
+    pub fn new(handle: Handle, writer: &ChannelWriteHalf<Msg>) -> Self {
+        Self {
+            handle,
+            channel: writer.id(),
+            data: Box::new(writer.make_writer()),
+            stderr: Box::new(writer.make_writer_ext(Some(STDERR))),
+            gone: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    pub fn channel(&self) -> ChannelId {
+        self.channel
+    }
+
+    /// Fails any write waiting for the client's window once notified, and every write
+    /// after it.
+    pub fn gone(&self) -> Arc<Notify> {
+        self.gone.clone()
+    }
+
+    pub async fn data(&mut self, data: &[u8]) -> std::io::Result<()> {
+        write(&mut self.data, data, &self.gone).await
+    }
+
+    /// Writes to stderr, or any other stream the client has only through the handle.
+    pub async fn extended_data(&mut self, ext: u32, data: &[u8]) -> std::io::Result<()> {
+        if ext != STDERR {
+            return self
+                .handle
+                .extended_data(self.channel, ext, data.into())
+                .await
+                .map_err(|_| std::io::ErrorKind::BrokenPipe.into());
+        }
+        write(&mut self.stderr, data, &self.gone).await
+    }
+}
+
+/// Writes `data`, giving up if the client is gone while it waits for the client's window.
+async fn write(
+    writer: &mut (dyn AsyncWrite + Send + Unpin),
+    data: &[u8],
+    gone: &Notify,
+) -> std::io::Result<()> {
+    tokio::select! {
+        written = writer.write_all(data) => written,
+        () = gone.notified() => {
+            // Kept for the writes after this one.
+            gone.notify_one();
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+    }
+}
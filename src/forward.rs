@@ -0,0 +1,1035 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use russh::server::Handle;
+use russh::{Pty, Sig};
+use tokio::sync::{Notify, broadcast, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{Instrument, Span, debug, field, info_span, trace, warn};
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::bandwidth::Bandwidth;
+use crate::config::{InspectAction, Protocol, PukekoConfig, ServerEntry};
+use crate::dial;
+#[cfg(feature = "docker")]
+use crate::docker::ContainerSession;
+use crate::flow::{ChannelOutput, InFlight, Written};
+use crate::inspect::{Chunk, Direction, StreamInspector};
+#[cfg(feature = "kubernetes")]
+use crate::kubernetes::PodSession;
+use crate::metrics::Metrics;
+use crate::pool::UpstreamPool;
+use crate::provider::BoxFuture;
+use crate::quotas::QuotaPermit;
+use crate::sessions::{SessionBytes, SessionEvent, SessionUsage, in_realm};
+use crate::tcp::TcpSession;
+use crate::upstream::SshSession;
+
+#[derive(Debug, Clone)]
+pub struct PtyRequest {
+    pub term: String,
+    pub col_width: u32,
+    pub row_height: u32,
+    pub pix_width: u32,
+    pub pix_height: u32,
+    pub modes: Vec<(Pty, u32)>,
+}
+
+/// The client's `ssh -X` request, repeated to the upstream with the same cookie so that
+/// the client checks it when the X11 connections are relayed back.
+#[derive(Debug, Clone)]
+pub struct X11Request {
+    pub single_connection: bool,
+    pub protocol: String,
+    pub cookie: String,
+    pub screen: u32,
+}
+
+#[derive(Debug, Clone)]
+pub enum ForwardKind {
+    Shell,
+    Exec(Vec<u8>),
+    Subsystem(String),
+}
+
+impl ForwardKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ForwardKind::Shell => "shell",
+            ForwardKind::Exec(_) => "exec",
+            ForwardKind::Subsystem(_) => "subsystem",
+        }
+    }
+
+    pub fn command(&self) -> Option<String> {
+        match self {
+            ForwardKind::Shell => None,
+            ForwardKind::Exec(command) => Some(String::from_utf8_lossy(command).into_owned()),
+            ForwardKind::Subsystem(name) => Some(name.clone()),
+        }
+    }
+}
+
+/// What to forward a downstream channel to, and on behalf of whom.
+pub struct ForwardRequest {
+    pub session: usize,
+    pub entry: ServerEntry,
+    pub user: String,
+    pub config: Arc<PukekoConfig>,
+    pub kind: ForwardKind,
+    pub pty: Option<PtyRequest>,
+    pub agent_forwarding: bool,
+    /// Set when the client asked for X11 forwarding and the server allows it.
+    pub x11: Option<X11Request>,
+    /// Counts bytes in both directions for the session.
+    pub bytes: Arc<SessionBytes>,
+    /// Counts the input held for the upstream with the session's other forwards.
+    pub usage: Arc<SessionUsage>,
+    /// Limits how fast data is relayed.
+    pub bandwidth: Bandwidth,
+    /// Session events, of which broadcast messages are written to the client's stderr.
+    pub events: broadcast::Receiver<SessionEvent>,
+    /// Where output to the client is copied for admins watching the session.
+    pub output: Option<broadcast::Sender<Vec<u8>>>,
+    /// Run on the data relayed in both directions.
+    pub inspectors: Vec<Arc<dyn StreamInspector>>,
+    /// Where output goes for a pane, which draws it on the client's channel with the
+    /// output of other panes. The channel is left to the pane.
+    pub pane: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Connections shared between sessions to the same server.
+    pub pool: Arc<UpstreamPool>,
+    /// Once connected, the upstream's output is held until this fires or is dropped, for
+    /// the menu to be cleared away first.
+    pub held: Option<oneshot::Receiver<()>>,
+    /// Environment variables set by the client that are passed on to the server.
+    pub env: Vec<(String, String)>,
+    /// Counts the forward towards the user's quotas while it is open.
+    pub quota: QuotaPermit,
+}
+
+/// Output from an upstream, relayed to the client's channel.
+#[derive(Debug, Clone)]
+pub enum UpstreamOutput {
+    Data(Vec<u8>),
+    /// Output on another stream, such as stderr.
+    ExtendedData(Vec<u8>, u32),
+    ExitStatus(u32),
+    /// The command was killed by `signal` rather than exiting.
+    ExitSignal {
+        signal: Sig,
+        core_dumped: bool,
+        message: String,
+        lang: String,
+    },
+    Eof,
+}
+
+/// The far end of a forward, driven in the terms of an SSH channel whatever protocol
+/// the server speaks.
+pub trait UpstreamSession: Send {
+    /// The next output, or None once the upstream has closed.
+    fn recv(&mut self) -> BoxFuture<'_, Option<UpstreamOutput>>;
+
+    /// Queues `data` to be written, releasing `written` once it has been. Never waits, so
+    /// that the upstream's output goes on being relayed meanwhile.
+    fn send(&mut self, data: Vec<u8>, written: Written) -> anyhow::Result<()>;
+
+    fn eof(&mut self) -> BoxFuture<'_, ()>;
+
+    /// Tells the upstream the client's terminal was resized, if it can be told.
+    fn window_change(
+        &mut self,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+    ) -> BoxFuture<'_, ()>;
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, ()>;
+}
+
+/// Connects to `entry` over its protocol and starts the session `kind` asks for, with the
+/// environment variables `env` where the protocol can set them.
+#[allow(clippy::too_many_arguments)]
+async fn open_upstream(
+    entry: &ServerEntry,
+    user: &str,
+    config: &PukekoConfig,
+    kind: &ForwardKind,
+    pty: Option<&PtyRequest>,
+    env: &[(String, String)],
+    agent: Option<&Handle>,
+    x11: Option<(&X11Request, &Handle)>,
+    pool: &Arc<UpstreamPool>,
+) -> anyhow::Result<Box<dyn UpstreamSession>> {
+    Ok(match entry.protocol {
+        Protocol::Ssh => {
+            Box::new(SshSession::open(entry, user, config, kind, pty, env, agent, x11, pool).await?)
+        }
+        Protocol::Tcp | Protocol::Telnet => {
+            Box::new(TcpSession::open(entry, user, config, kind, pty).await?)
+        }
+        #[cfg(feature = "kubernetes")]
+        Protocol::Kubernetes => Box::new(PodSession::open(entry, kind, pty).await?),
+        // The config refuses pods without the feature, so this is never reached.
+        #[cfg(not(feature = "kubernetes"))]
+        Protocol::Kubernetes => {
+            anyhow::bail!("pukeko was built without the kubernetes feature")
+        }
+        #[cfg(feature = "docker")]
+        Protocol::Docker => Box::new(ContainerSession::open(entry, kind, pty, env).await?),
+        // Likewise for containers.
+        #[cfg(not(feature = "docker"))]
+        Protocol::Docker => anyhow::bail!("pukeko was built without the docker feature"),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub enum ForwardStatus {
+    Connecting,
+    Connected,
+    Failed(Arc<anyhow::Error>),
+}
+
+enum ForwardInput {
+    Data(Vec<u8>, Written),
+    Eof,
+    Close,
+    /// A message for the client from pukeko itself.
+    Message(String),
+    /// Stops relaying the upstream's output, which is left waiting, until resumed.
+    Suspend,
+    Resume,
+    /// The client disconnected. The upstream's output is read and the latest of it kept
+    /// until another client attaches.
+    Detach,
+    Attach(ChannelOutput),
+    /// The client's terminal was resized.
+    WindowChange {
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+    },
+}
+
+/// A downstream channel relayed to an upstream server.
+///
+/// The upstream connection is made on a background task so that the client's session
+/// keeps running meanwhile, which is required for authenticating with a forwarded agent.
+/// Input sent before the upstream is ready is queued and delivered once it is.
+pub struct Forward {
+    target: String,
+    metrics: Arc<Metrics>,
+    bytes: Arc<SessionBytes>,
+    input: mpsc::UnboundedSender<ForwardInput>,
+    status: watch::Receiver<ForwardStatus>,
+    task: JoinHandle<()>,
+    messages: JoinHandle<()>,
+    /// Set for interactive sessions when the escape character is enabled.
+    escape: Option<Mutex<Escape>>,
+    /// Leaves the client's channel open when the upstream closes, for the menu to be
+    /// shown on it again.
+    keep_channel: Arc<AtomicBool>,
+    /// Whether this is a shell with a pty, which a client can detach from and resume.
+    interactive: bool,
+    /// Input yet to be written to the upstream.
+    in_flight: Arc<InFlight>,
+    /// Notified when the client the output goes to is gone.
+    output_gone: Mutex<Arc<Notify>>,
+}
+
+/// An escape typed by the client, see [`Escape`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EscapeCommand {
+    Status,
+    Suspend,
+}
+
+impl Forward {
+    pub fn start(
+        request: ForwardRequest,
+        downstream: ChannelOutput,
+        metrics: Arc<Metrics>,
+        audit: Arc<AuditLog>,
+    ) -> Self {
+        let (input, input_rx) = mpsc::unbounded_channel();
+        let in_flight = InFlight::new(request.config.limits.max_in_flight, request.usage.clone());
+        let output_gone = Mutex::new(downstream.gone());
+        if let (Some(command), Some(_), ForwardKind::Shell) =
+            (&request.entry.login_command, &request.pty, &request.kind)
+        {
+            // Delivered once the shell is open, like anything the client types meanwhile.
+            let command = format!("{command}\r").into_bytes();
+            let written = in_flight.take(command.len());
+            let _ = input.send(ForwardInput::Data(command, written));
+        }
+        let (status_tx, status) = watch::channel(ForwardStatus::Connecting);
+        let target = request.entry.name.clone();
+        let bytes = request.bytes.clone();
+        // Panes are escaped from with their own prefix key.
+        let interactive = request.pty.is_some()
+            && request.pane.is_none()
+            && matches!(request.kind, ForwardKind::Shell);
+        let escape = request
+            .config
+            .escape_char
+            .filter(|_| interactive)
+            .map(|escape| Mutex::new(Escape::new(escape)));
+        let messages = tokio::spawn(
+            forward_messages(
+                request.session,
+                request.user.clone(),
+                request.config.shadow.notify,
+                request.events.resubscribe(),
+                input.clone(),
+            )
+            .in_current_span(),
+        );
+
+        let span = info_span!(
+            "forward",
+            server.address = %request.entry.host,
+            server.port = request.entry.port,
+            bytes_to_upstream = field::Empty,
+            bytes_from_upstream = field::Empty,
+        );
+        let keep_channel = Arc::new(AtomicBool::new(false));
+        let task = {
+            let metrics = metrics.clone();
+            let keep_channel = keep_channel.clone();
+            tokio::spawn(
+                async move {
+                    let ForwardRequest {
+                        session,
+                        entry,
+                        user,
+                        config,
+                        kind,
+                        pty,
+                        agent_forwarding,
+                        x11,
+                        bytes,
+                        usage: _,
+                        bandwidth,
+                        events: _,
+                        output,
+                        inspectors,
+                        pane,
+                        pool,
+                        held,
+                        env,
+                        quota: _quota,
+                    } = request;
+                    let mut downstream = Downstream {
+                        output: downstream,
+                        pane,
+                    };
+                    let mut transfer = Transfer {
+                        audit,
+                        session,
+                        user,
+                        server: entry.name.clone(),
+                        bytes_to_upstream: 0,
+                        bytes_from_upstream: 0,
+                        session_bytes: bytes,
+                        output,
+                        inspectors,
+                        error: None,
+                        exit_status: None,
+                        exit_signal: None,
+                    };
+
+                    let handle = downstream.output.handle().clone();
+                    let agent = agent_forwarding.then_some(&handle);
+                    let x11 = x11.as_ref().map(|x11| (x11, &handle));
+                    let opened = dial::each_backend(&entry, |backend| {
+                        let (user, config, kind, pty, env, pool) =
+                            (&transfer.user, &config, &kind, pty.as_ref(), &env, &pool);
+                        async move {
+                            open_upstream(&backend, user, config, kind, pty, env, agent, x11, pool)
+                                .await
+                        }
+                    })
+                    .await;
+                    let upstream = match opened {
+                        Ok((opened, backend)) => {
+                            // Users of a shell are told which of the server's addresses it
+                            // is on, in case it matters which one they reached.
+                            if let (Some((host, port)), Some(_)) = (backend, &pty) {
+                                let message = format!(
+                                    "[pukeko] Connected to {} at {}:{}\r\n",
+                                    entry.name, host, port
+                                );
+                                let _ = downstream.extended_data(1, message.as_bytes()).await;
+                            }
+                            opened
+                        }
+                        Err(e) => {
+                            debug!("Upstream {} failed: {:?}", entry.name, e);
+                            transfer.error = Some(format!("{e:#}"));
+                            status_tx.send_replace(ForwardStatus::Failed(Arc::new(e)));
+                            return;
+                        }
+                    };
+                    status_tx.send_replace(ForwardStatus::Connected);
+                    if let Some(held) = held {
+                        let _ = held.await;
+                    }
+
+                    let watched = relay(
+                        upstream,
+                        input_rx,
+                        &mut downstream,
+                        &metrics,
+                        config.timeouts.forward_idle,
+                        &bandwidth,
+                        &keep_channel,
+                        &mut transfer,
+                    )
+                    .await;
+
+                    debug!("Upstream {} closed", entry.name);
+                    if !keep_channel.load(Ordering::Relaxed) {
+                        // Clients would otherwise have to guess whether the command worked.
+                        if transfer.exit_status.is_none() && transfer.exit_signal.is_none() {
+                            downstream.exit_status(NO_EXIT_STATUS).await;
+                        }
+                        downstream.close().await;
+                    } else if watched {
+                        let message = format!(
+                            "\r\n[pukeko] Disconnected from {}, press any key to return to \
+                             the menu\r\n",
+                            entry.name
+                        );
+                        let _ = downstream.extended_data(1, message.as_bytes()).await;
+                    }
+                }
+                .instrument(span),
+            )
+        };
+
+        Self {
+            target,
+            metrics,
+            bytes,
+            input,
+            status,
+            task,
+            messages,
+            escape,
+            keep_channel,
+            interactive,
+            in_flight,
+            output_gone,
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        &self.target
+    }
+
+    pub fn status(&self) -> ForwardStatus {
+        self.status.borrow().clone()
+    }
+
+    /// Resolves once the upstream is either connected or has failed.
+    pub fn ready(&self) -> impl Future<Output = ForwardStatus> + Send + 'static {
+        let mut status = self.status.clone();
+        async move {
+            let _ = status
+                .wait_for(|status| !matches!(status, ForwardStatus::Connecting))
+                .await;
+            status.borrow().clone()
+        }
+    }
+
+    /// Whether the upstream has taken enough of the client's input for the client to send
+    /// more.
+    pub fn has_room(&self) -> bool {
+        self.in_flight.has_room()
+    }
+
+    /// Forwards the client's input, returning any escape it typed.
+    pub fn data(&self, data: &[u8]) -> anyhow::Result<Option<EscapeCommand>> {
+        let (data, command) = match &self.escape {
+            Some(escape) => escape.lock().unwrap().scan(data),
+            None => (data.to_vec(), None),
+        };
+        if !data.is_empty() {
+            self.metrics
+                .bytes_forwarded(&self.target, Direction::Input, data.len() as u64);
+            self.bytes
+                .up
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+            let written = self.in_flight.take(data.len());
+            self.send(ForwardInput::Data(data, written))?;
+        }
+        Ok(command)
+    }
+
+    /// Stops relaying the upstream's output until resumed. Messages for the client are
+    /// held until then too.
+    pub fn suspend(&self) -> anyhow::Result<()> {
+        self.send(ForwardInput::Suspend)
+    }
+
+    pub fn resume(&self) -> anyhow::Result<()> {
+        self.send(ForwardInput::Resume)
+    }
+
+    /// Leaves the client's channel open once the upstream closes, telling the client to
+    /// press a key to return to the menu if they were watching it.
+    pub fn keep_channel(&self) {
+        self.keep_channel.store(true, Ordering::Relaxed);
+    }
+
+    pub fn keeps_channel(&self) -> bool {
+        self.keep_channel.load(Ordering::Relaxed)
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    /// Keeps the upstream running after the client disconnected, for another client to
+    /// attach to.
+    pub fn detach(&self) -> anyhow::Result<()> {
+        self.output_gone.lock().unwrap().notify_one();
+        self.send(ForwardInput::Detach)
+    }
+
+    /// Relays to the client's channel `output` from now on, starting with the latest output
+    /// since the last client detached.
+    pub fn attach(&self, output: ChannelOutput) -> anyhow::Result<()> {
+        *self.output_gone.lock().unwrap() = output.gone();
+        self.send(ForwardInput::Attach(output))
+    }
+
+    /// Whether the upstream has closed, or failed to connect.
+    pub fn is_closed(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Writes a message from pukeko to the client, between the upstream's output.
+    pub fn message(&self, message: String) -> anyhow::Result<()> {
+        self.send(ForwardInput::Message(message))
+    }
+
+    pub fn eof(&self) -> anyhow::Result<()> {
+        self.send(ForwardInput::Eof)
+    }
+
+    pub fn close(&self) -> anyhow::Result<()> {
+        self.send(ForwardInput::Close)
+    }
+
+    /// Resizes the upstream's pty. Resizes before it is connected are applied once it is.
+    pub fn window_change(
+        &self,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+    ) -> anyhow::Result<()> {
+        self.send(ForwardInput::WindowChange {
+            col_width,
+            row_height,
+            pix_width,
+            pix_height,
+        })
+    }
+
+    fn send(&self, input: ForwardInput) -> anyhow::Result<()> {
+        self.input
+            .send(input)
+            .map_err(|_| anyhow::anyhow!("Upstream {} is no longer connected", self.target))
+    }
+}
+
+impl Drop for Forward {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.messages.abort();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    LineStart,
+    Line,
+    /// The escape character was typed at the start of a line, and is held back until the
+    /// next key shows whether it was an escape.
+    Escaped,
+}
+
+/// Escapes typed at the start of a line of interactive input, as OpenSSH recognizes
+/// them: the escape character followed by `s` asks for the session's status and by `m`
+/// suspends it, and typed twice sends it once. Anything else after it is forwarded along
+/// with it.
+#[derive(Debug)]
+struct Escape {
+    escape: u8,
+    state: EscapeState,
+}
+
+impl Escape {
+    fn new(escape: u8) -> Self {
+        Self {
+            escape,
+            state: EscapeState::LineStart,
+        }
+    }
+
+    /// Returns the input to forward and the escape typed, if any. Input after a suspend is
+    /// dropped, as it was meant for pukeko rather than the upstream.
+    fn scan(&mut self, input: &[u8]) -> (Vec<u8>, Option<EscapeCommand>) {
+        let mut data = Vec::with_capacity(input.len() + 1);
+        let mut command = None;
+        for &byte in input {
+            match self.state {
+                EscapeState::LineStart if byte == self.escape => {
+                    self.state = EscapeState::Escaped;
+                    continue;
+                }
+                EscapeState::Escaped if byte == b's' => {
+                    command = Some(EscapeCommand::Status);
+                    self.state = EscapeState::LineStart;
+                    continue;
+                }
+                EscapeState::Escaped if byte == b'm' => {
+                    self.state = EscapeState::LineStart;
+                    return (data, Some(EscapeCommand::Suspend));
+                }
+                EscapeState::Escaped if byte == self.escape => {
+                    data.push(byte);
+                    self.state = EscapeState::Line;
+                    continue;
+                }
+                EscapeState::Escaped => data.push(self.escape),
+                EscapeState::LineStart | EscapeState::Line => {}
+            }
+            data.push(byte);
+            self.state = match byte {
+                b'\r' | b'\n' => EscapeState::LineStart,
+                _ => EscapeState::Line,
+            };
+        }
+        (data, command)
+    }
+}
+
+/// Queues broadcast messages to `user`, and with `notify_shadowing` notices of admins
+/// watching the session, to be written to the client between upstream output.
+async fn forward_messages(
+    session: usize,
+    user: String,
+    notify_shadowing: bool,
+    mut events: broadcast::Receiver<SessionEvent>,
+    input: mpsc::UnboundedSender<ForwardInput>,
+) {
+    loop {
+        let message = match events.recv().await {
+            Ok(SessionEvent::Broadcast {
+                from,
+                realm,
+                message,
+            }) if in_realm(Some(&user), realm.as_deref()) => {
+                format!("\r\n[pukeko] Message from {from}: {message}\r\n")
+            }
+            Ok(SessionEvent::Shadowed { id, by, watching })
+                if id == session && notify_shadowing =>
+            {
+                if watching {
+                    format!("\r\n[pukeko] {by} is watching this session\r\n")
+                } else {
+                    format!("\r\n[pukeko] {by} stopped watching this session\r\n")
+                }
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => break,
+        };
+        if input.send(ForwardInput::Message(message)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Where a forward's output is written: the client's channel, or a pane drawn on it.
+struct Downstream {
+    output: ChannelOutput,
+    pane: Option<mpsc::UnboundedSender<Vec<u8>>>,
+}
+
+impl Downstream {
+    async fn data(&mut self, data: &[u8]) -> Result<(), ()> {
+        match &self.pane {
+            Some(pane) => pane.send(data.to_vec()).map_err(|_| ()),
+            None => self.output.data(data).await.map_err(|_| ()),
+        }
+    }
+
+    /// Panes show other streams, such as stderr, along with the output as a terminal would.
+    async fn extended_data(&mut self, ext: u32, data: &[u8]) -> Result<(), ()> {
+        match &self.pane {
+            Some(pane) => pane.send(data.to_vec()).map_err(|_| ()),
+            None => self.output.extended_data(ext, data).await.map_err(|_| ()),
+        }
+    }
+
+    async fn exit_status(&mut self, exit_status: u32) {
+        if self.pane.is_none() {
+            let _ = self
+                .output
+                .handle()
+                .exit_status_request(self.output.channel(), exit_status)
+                .await;
+        }
+    }
+
+    async fn exit_signal(&mut self, signal: Sig, core_dumped: bool, message: String, lang: String) {
+        if self.pane.is_none() {
+            let _ = self
+                .output
+                .handle()
+                .exit_signal_request(self.output.channel(), signal, core_dumped, message, lang)
+                .await;
+        }
+    }
+
+    async fn eof(&mut self) {
+        if self.pane.is_none() {
+            let _ = self.output.handle().eof(self.output.channel()).await;
+        }
+    }
+
+    /// Closes the channel. A pane sees its forward has closed when its output ends.
+    async fn close(&mut self) {
+        if self.pane.is_none() {
+            let _ = self.output.handle().close(self.output.channel()).await;
+        }
+    }
+}
+
+/// Byte counts of a forward, recorded in the audit log when dropped so that forwards
+/// aborted by the client disconnecting are recorded too.
+struct Transfer {
+    audit: Arc<AuditLog>,
+    session: usize,
+    user: String,
+    server: String,
+    bytes_to_upstream: u64,
+    bytes_from_upstream: u64,
+    /// The session's running total, shown to admins.
+    session_bytes: Arc<SessionBytes>,
+    output: Option<broadcast::Sender<Vec<u8>>>,
+    inspectors: Vec<Arc<dyn StreamInspector>>,
+    error: Option<String>,
+    /// How the upstream's command ended, once it has.
+    exit_status: Option<u32>,
+    exit_signal: Option<String>,
+}
+
+impl Transfer {
+    /// Runs data through the inspectors, returning what to forward in its place, or None
+    /// if an inspector blocked it. Inspectors, plugins among them, run on a blocking
+    /// thread rather than holding up the runtime for each chunk.
+    async fn inspect(&mut self, direction: Direction, data: Vec<u8>) -> Option<Vec<u8>> {
+        if self.inspectors.is_empty() {
+            return Some(data);
+        }
+        let inspectors = self.inspectors.clone();
+        let (session, user, server) = (self.session, self.user.clone(), self.server.clone());
+        let inspected = tokio::task::spawn_blocking(move || {
+            let mut data = data;
+            let mut findings = Vec::new();
+            for inspector in &inspectors {
+                let inspection = inspector.inspect(&Chunk {
+                    session,
+                    user: &user,
+                    server: &server,
+                    direction,
+                    data: &data,
+                });
+                let blocked = inspection
+                    .findings
+                    .iter()
+                    .any(|finding| finding.action == InspectAction::Block);
+                findings.extend(inspection.findings);
+                if blocked {
+                    return (None, findings);
+                }
+                if let Some(replaced) = inspection.data {
+                    data = replaced;
+                }
+            }
+            (Some(data), findings)
+        })
+        .await;
+        let (data, findings) = match inspected {
+            Ok(inspected) => inspected,
+            Err(e) => {
+                self.error = Some(format!("inspection failed: {e}"));
+                return None;
+            }
+        };
+        for finding in &findings {
+            warn!(
+                "{} matched {} between {} and {} ({:?})",
+                finding.rule,
+                direction.name(),
+                self.user,
+                self.server,
+                finding.action
+            );
+            self.audit.record(AuditEvent::ContentMatched {
+                session: self.session,
+                user: &self.user,
+                server: &self.server,
+                rule: &finding.rule,
+                direction,
+                action: finding.action,
+            });
+        }
+        if data.is_none() {
+            let rule = findings
+                .iter()
+                .find(|finding| finding.action == InspectAction::Block)
+                .map(|finding| finding.rule.as_str())
+                .unwrap_or_default();
+            self.error = Some(format!("blocked by {rule}"));
+        }
+        data
+    }
+
+    /// Counts output from the upstream, copying it to any admins watching.
+    fn received(&mut self, metrics: &Metrics, data: &[u8]) {
+        metrics.bytes_forwarded(&self.server, Direction::Output, data.len() as u64);
+        self.session_bytes
+            .down
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.bytes_from_upstream += data.len() as u64;
+        if let Some(output) = &self.output
+            && output.receiver_count() > 0
+        {
+            let _ = output.send(data.to_vec());
+        }
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        // Dropped at the end of the forward task, inside its span.
+        let span = Span::current();
+        span.record("bytes_to_upstream", self.bytes_to_upstream);
+        span.record("bytes_from_upstream", self.bytes_from_upstream);
+        self.audit.record(AuditEvent::ForwardEnd {
+            session: self.session,
+            user: &self.user,
+            server: &self.server,
+            bytes_to_upstream: self.bytes_to_upstream,
+            bytes_from_upstream: self.bytes_from_upstream,
+            error: self.error.take(),
+            exit_status: self.exit_status,
+            exit_signal: self.exit_signal.take(),
+        });
+    }
+}
+
+/// Written to the client's stderr when an inspector closes the forward.
+const BLOCKED: &str = "\r\npukeko: closing session, it forwarded data that is not allowed\r\n";
+
+/// Reported when the upstream ends without saying how its command did, as OpenSSH does
+/// when it loses the connection.
+const NO_EXIT_STATUS: u32 = 255;
+
+/// Output kept for the next client while detached, the latest of it.
+const DETACHED_BACKLOG: usize = 64 * 1024;
+
+/// Relays between the client's channel and the upstream until either closes, then closes
+/// the upstream. Returns whether it ended other than by the client closing it, while the
+/// client was watching rather than suspended.
+#[allow(clippy::too_many_arguments)]
+async fn relay(
+    mut upstream: Box<dyn UpstreamSession>,
+    mut input: mpsc::UnboundedReceiver<ForwardInput>,
+    downstream: &mut Downstream,
+    metrics: &Metrics,
+    idle_timeout: Option<Duration>,
+    bandwidth: &Bandwidth,
+    keep_channel: &AtomicBool,
+    transfer: &mut Transfer,
+) -> bool {
+    let target = transfer.server.clone();
+    let mut last_activity = Instant::now();
+    let mut suspended = false;
+    // Messages that came while suspended, for when the client is watching again.
+    let mut held = Vec::new();
+    let mut detached = false;
+    let mut backlog = Vec::new();
+
+    let watched = loop {
+        let idle = async {
+            match idle_timeout {
+                // The client is somewhere else while suspended, rather than idle.
+                Some(timeout) if !suspended && !detached => {
+                    tokio::time::sleep_until(last_activity + timeout).await
+                }
+                _ => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = idle => {
+                debug!("Closing idle forward to {}", target);
+                let message = "\r\npukeko: closing idle session\r\n";
+                let _ = downstream.extended_data(1, message.as_bytes()).await;
+                transfer.error = Some("idle timeout".to_string());
+                break true;
+            }
+            output = upstream.recv(), if !suspended => match output {
+                Some(UpstreamOutput::Data(data)) => {
+                    last_activity = Instant::now();
+                    let Some(data) = transfer.inspect(Direction::Output, data).await else {
+                        let _ = downstream.extended_data(1, BLOCKED.as_bytes()).await;
+                        break true;
+                    };
+                    transfer.received(metrics, &data);
+                    bandwidth.take(data.len()).await;
+                    if detached {
+                        keep_latest(&mut backlog, &data);
+                    } else if downstream.data(&data).await.is_err() {
+                        // A client that disconnected is detached from the forward or closes
+                        // it once its connection ends. Panes go with their forward.
+                        if downstream.pane.is_some() {
+                            break true;
+                        }
+                        detached = true;
+                    }
+                }
+                Some(UpstreamOutput::ExtendedData(data, ext)) => {
+                    last_activity = Instant::now();
+                    let Some(data) = transfer.inspect(Direction::Output, data).await else {
+                        let _ = downstream.extended_data(1, BLOCKED.as_bytes()).await;
+                        break true;
+                    };
+                    transfer.received(metrics, &data);
+                    bandwidth.take(data.len()).await;
+                    if detached {
+                        keep_latest(&mut backlog, &data);
+                    } else if downstream.extended_data(ext, &data).await.is_err() {
+                        if downstream.pane.is_some() {
+                            break true;
+                        }
+                        detached = true;
+                    }
+                }
+                // Nothing can be sent on a channel after its EOF, so a channel kept for
+                // the menu is not told the upstream finished.
+                Some(UpstreamOutput::ExitStatus(exit_status)) => {
+                    trace!("Upstream {} exited with {}", target, exit_status);
+                    transfer.exit_status = Some(exit_status);
+                    if !keep_channel.load(Ordering::Relaxed) {
+                        downstream.exit_status(exit_status).await;
+                    }
+                }
+                Some(UpstreamOutput::ExitSignal {
+                    signal,
+                    core_dumped,
+                    message,
+                    lang,
+                }) => {
+                    let name = signal_name(&signal);
+                    trace!("Upstream {} was killed by {}", target, name);
+                    transfer.exit_signal = Some(name);
+                    if !keep_channel.load(Ordering::Relaxed) {
+                        downstream
+                            .exit_signal(signal, core_dumped, message, lang)
+                            .await;
+                    }
+                }
+                Some(UpstreamOutput::Eof) => {
+                    if !keep_channel.load(Ordering::Relaxed) {
+                        downstream.eof().await;
+                    }
+                }
+                None => break true,
+            },
+            input = input.recv() => match input {
+                Some(ForwardInput::Data(data, written)) => {
+                    last_activity = Instant::now();
+                    let Some(data) = transfer.inspect(Direction::Input, data).await else {
+                        let _ = downstream.extended_data(1, BLOCKED.as_bytes()).await;
+                        break true;
+                    };
+                    transfer.bytes_to_upstream += data.len() as u64;
+                    bandwidth.take(data.len()).await;
+                    if upstream.send(data, written).is_err() {
+                        break true;
+                    }
+                }
+                Some(ForwardInput::Eof) => upstream.eof().await,
+                Some(ForwardInput::Message(message)) if suspended || detached => {
+                    held.push(message)
+                }
+                Some(ForwardInput::Message(message)) => {
+                    let _ = downstream.extended_data(1, message.as_bytes()).await;
+                }
+                Some(ForwardInput::Suspend) => suspended = true,
+                Some(ForwardInput::Resume) => {
+                    suspended = false;
+                    last_activity = Instant::now();
+                    for message in held.drain(..) {
+                        let _ = downstream.extended_data(1, message.as_bytes()).await;
+                    }
+                }
+                Some(ForwardInput::Detach) => {
+                    debug!("Client detached from {}", target);
+                    detached = true;
+                }
+                Some(ForwardInput::Attach(output)) => {
+                    debug!("Client attached to {}", target);
+                    downstream.output = output;
+                    detached = false;
+                    last_activity = Instant::now();
+                    let backlog = std::mem::take(&mut backlog);
+                    if !backlog.is_empty() && downstream.data(&backlog).await.is_err() {
+                        break true;
+                    }
+                }
+                Some(ForwardInput::WindowChange {
+                    col_width,
+                    row_height,
+                    pix_width,
+                    pix_height,
+                }) => {
+                    upstream
+                        .window_change(col_width, row_height, pix_width, pix_height)
+                        .await;
+                }
+                Some(ForwardInput::Close) | None => break false,
+            },
+        }
+    };
+    upstream.close().await;
+    watched && !suspended
+}
+
+/// The name of `signal` without its `SIG` prefix, as sent in SSH.
+fn signal_name(signal: &Sig) -> String {
+    match signal {
+        Sig::Custom(name) => name.clone(),
+        // The other variants are named after the signal.
+        signal => format!("{signal:?}"),
+    }
+}
+
+/// Appends `data` to `backlog`, dropping the oldest output beyond [`DETACHED_BACKLOG`].
+fn keep_latest(backlog: &mut Vec<u8>, data: &[u8]) {
+    backlog.extend_from_slice(data);
+    if backlog.len() > DETACHED_BACKLOG {
+        backlog.drain(..backlog.len() - DETACHED_BACKLOG);
+    }
+}
@@ -0,0 +1,30 @@
+/// Scores how well `pattern` matches `candidate` as a case-insensitive subsequence.
+///
+/// Returns `None` when not every character of the pattern appears in order. Consecutive
+/// matches and matches at the start of a word score higher, gaps between matches lower.
+pub fn score(pattern: &str, candidate: &str) -> Option<i64> {
+    let candidate: Vec<char> = candidate.chars().flat_map(char::to_lowercase).collect();
+
+    let mut score = 0;
+    let mut position = 0;
+    let mut last = None;
+
+    for p in pattern.chars().flat_map(char::to_lowercase) {
+        let found = position + candidate[position..].iter().position(|&c| c == p)?;
+
+        score += 1;
+        score += match last {
+            Some(last) if found == last + 1 => 5,
+            Some(last) => -((found - last - 1) as i64),
+            None => -(found as i64),
+        };
+        if found == 0 || !candidate[found - 1].is_alphanumeric() {
+            score += 3;
+        }
+
+        last = Some(found);
+        position = found + 1;
+    }
+
+    Some(score)
+}
@@ -0,0 +1,144 @@
+use std::fmt;
+use std::net::IpAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::GeoIpConfig;
+
+/// Where a client address is, as far as the MaxMind databases know.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    /// ISO 3166 country code, such as NZ.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asn: Option<u32>,
+    /// The organization the autonomous system is registered to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub as_organization: Option<String>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let asn = self.asn.map(|asn| format!("AS{asn}"));
+        let parts: Vec<&str> = [self.country.as_deref(), asn.as_deref()]
+            .into_iter()
+            .flatten()
+            .collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+impl Location {
+    /// Why connections from here are refused by the country and ASN lists, if they are.
+    /// Addresses without a country, such as private ones, are only refused by ASN.
+    pub fn refused_by(&self, config: &GeoIpConfig) -> Option<String> {
+        if let Some(asn) = self.asn.filter(|asn| config.deny_asns.contains(asn)) {
+            return Some(format!("AS{asn} is in deny_asns"));
+        }
+        let country = self.country.as_ref()?;
+        if config.deny_countries.contains(country) {
+            Some(format!("country {country} is in deny_countries"))
+        } else if !config.allow_countries.is_empty() && !config.allow_countries.contains(country) {
+            Some(format!("country {country} is not in allow_countries"))
+        } else {
+            None
+        }
+    }
+
+    /// The country logins from here come from, if it is outside `trusted_countries` so
+    /// they need a second factor.
+    pub fn untrusted_country<'a>(&'a self, config: &GeoIpConfig) -> Option<&'a str> {
+        if config.trusted_countries.is_empty() {
+            return None;
+        }
+        self.country
+            .as_deref()
+            .filter(|country| !config.trusted_countries.iter().any(|c| c == country))
+    }
+}
+
+/// Looks client addresses up in the configured MaxMind databases.
+#[derive(Default)]
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    databases: std::sync::RwLock<Databases>,
+}
+
+#[cfg(feature = "geoip")]
+#[derive(Default)]
+struct Databases {
+    country: Option<maxminddb::Reader<Vec<u8>>>,
+    asn: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+#[cfg(feature = "geoip")]
+impl GeoIp {
+    /// Opens the configured databases, replacing any previous ones. Reopening them on every
+    /// reload picks up updated databases with a SIGHUP.
+    pub fn configure(&self, config: Option<&GeoIpConfig>) -> anyhow::Result<()> {
+        use anyhow::Context;
+
+        let open = |path: Option<&std::path::PathBuf>| {
+            path.map(|path| {
+                maxminddb::Reader::open_readfile(path)
+                    .with_context(|| format!("Failed to open GeoIP database {}", path.display()))
+            })
+            .transpose()
+        };
+        let databases = Databases {
+            country: open(config.and_then(|config| config.country_database.as_ref()))?,
+            asn: open(config.and_then(|config| config.asn_database.as_ref()))?,
+        };
+        *self.databases.write().unwrap() = databases;
+        Ok(())
+    }
+
+    /// Where `address` is, if either database knows.
+    pub fn lookup(&self, address: IpAddr) -> Option<Location> {
+        use maxminddb::geoip2;
+
+        let databases = self.databases.read().unwrap();
+        let mut location = Location::default();
+        if let Some(reader) = &databases.country {
+            match reader
+                .lookup(address)
+                .and_then(|result| result.decode::<geoip2::Country>())
+            {
+                Ok(record) => {
+                    location.country = record
+                        .and_then(|record| record.country.iso_code)
+                        .map(str::to_string);
+                }
+                Err(e) => tracing::debug!("Failed to look up the country of {}: {}", address, e),
+            }
+        }
+        if let Some(reader) = &databases.asn {
+            match reader
+                .lookup(address)
+                .and_then(|result| result.decode::<geoip2::Asn>())
+            {
+                Ok(Some(record)) => {
+                    location.asn = record.autonomous_system_number;
+                    location.as_organization =
+                        record.autonomous_system_organization.map(str::to_string);
+                }
+                Ok(None) => {}
+                Err(e) => tracing::debug!("Failed to look up the ASN of {}: {}", address, e),
+            }
+        }
+        (location != Location::default()).then_some(location)
+    }
+}
+
+#[cfg(not(feature = "geoip"))]
+impl GeoIp {
+    /// Without the geoip feature no databases can be configured; the config refuses them.
+    pub fn configure(&self, _config: Option<&GeoIpConfig>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    pub fn lookup(&self, _address: IpAddr) -> Option<Location> {
+        None
+    }
+}
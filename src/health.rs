@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+
+use crate::config::{ConfigReceiver, HealthProbe, Protocol, ServerEntry, Via};
+use crate::dial;
+use crate::provider::ServerProvider;
+use crate::srv;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Health {
+    Up { latency: Duration },
+    Down,
+}
+
+/// The result of the latest probe of each upstream, by server name.
+#[derive(Debug)]
+pub struct HealthMonitor {
+    statuses: Mutex<HashMap<String, Health>>,
+    updated: watch::Sender<()>,
+}
+
+impl Default for HealthMonitor {
+    fn default() -> Self {
+        Self {
+            statuses: Mutex::default(),
+            updated: watch::Sender::new(()),
+        }
+    }
+}
+
+impl HealthMonitor {
+    /// Returns `None` until the server has been probed, or while checks are disabled.
+    pub fn status(&self, server: &str) -> Option<Health> {
+        self.statuses.lock().unwrap().get(server).cloned()
+    }
+
+    /// Sets `server`'s status as if it had been probed.
+    #[cfg(test)]
+    pub fn set_status(&self, server: &str, health: Health) {
+        self.statuses
+            .lock()
+            .unwrap()
+            .insert(server.to_string(), health);
+    }
+
+    /// Notified after every round of probes.
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.updated.subscribe()
+    }
+
+    /// Probes every server on the configured interval, following config reloads. Servers
+    /// of realms are probed along with those of `servers`.
+    pub async fn run(
+        self: Arc<Self>,
+        mut config: ConfigReceiver,
+        servers: Arc<dyn ServerProvider>,
+    ) {
+        loop {
+            let (health, realms) = {
+                let config = config.borrow_and_update();
+                let realms: Vec<ServerEntry> = config
+                    .realms
+                    .iter()
+                    .flat_map(|realm| realm.config.servers.clone())
+                    .collect();
+                (config.health, realms)
+            };
+            let Some(interval) = health.interval else {
+                self.statuses.lock().unwrap().clear();
+                self.updated.send_replace(());
+                // A reload may enable them.
+                if config.changed().await.is_err() {
+                    return;
+                }
+                continue;
+            };
+
+            let mut probes = JoinSet::new();
+            for entry in servers.inventory().into_iter().chain(realms) {
+                // Pods and containers are reached through an API, so there is nothing to
+                // connect to, and servers behind another need a login to it.
+                if matches!(entry.protocol, Protocol::Kubernetes | Protocol::Docker)
+                    || matches!(entry.via, Some(Via::Jump(_)))
+                {
+                    continue;
+                }
+                probes.spawn(async move {
+                    let result = probe(&entry, health.probe, health.timeout).await;
+                    (entry.name, result)
+                });
+            }
+
+            let mut statuses = HashMap::new();
+            while let Some(Ok((name, result))) = probes.join_next().await {
+                let status = match result {
+                    Ok(latency) => Health::Up { latency },
+                    Err(e) => {
+                        debug!("Health check of {} failed: {:#}", name, e);
+                        Health::Down
+                    }
+                };
+                match (self.status(&name), &status) {
+                    (Some(Health::Down), Health::Up { .. }) => info!("Upstream {} is up", name),
+                    (Some(Health::Up { .. }), Health::Down) => warn!("Upstream {} is down", name),
+                    _ => {}
+                }
+                statuses.insert(name, status);
+            }
+
+            *self.statuses.lock().unwrap() = statuses;
+            self.updated.send_replace(());
+
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                changed = config.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Connects to the upstream, reading its SSH version line for the banner probe, and
+/// returns how long it took. Servers that do not speak SSH only have to accept the
+/// connection. Servers with backends are up while any of them is, each given `timeout`.
+async fn probe(
+    entry: &ServerEntry,
+    kind: HealthProbe,
+    timeout: Duration,
+) -> anyhow::Result<Duration> {
+    let (latency, _) = dial::each_backend(entry, |backend| async move {
+        tokio::time::timeout(timeout, probe_address(&backend, kind))
+            .await
+            .context("Timed out")
+            .flatten()
+    })
+    .await?;
+    Ok(latency)
+}
+
+async fn probe_address(entry: &ServerEntry, kind: HealthProbe) -> anyhow::Result<Duration> {
+    let start = Instant::now();
+    let stream = dial::connect(&*srv::resolve(entry).await?).await?;
+
+    if kind == HealthProbe::Banner && entry.protocol == Protocol::Ssh {
+        let mut lines = BufReader::new(stream).lines();
+        // Servers may send other lines before the version, see RFC 4253 section 4.2.
+        loop {
+            let Some(line) = lines.next_line().await? else {
+                bail!("Connection closed before the SSH banner");
+            };
+            if line.starts_with("SSH-") {
+                break;
+            }
+        }
+    }
+
+    Ok(start.elapsed())
+}
@@ -0,0 +1,224 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::cluster::{self, Entries, SharedState};
+use crate::keymap::KeyPreset;
+
+/// How the menu orders servers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortMode {
+    /// The order the servers are listed in.
+    #[default]
+    Listed,
+    Name,
+    /// Most recently connected first.
+    Recent,
+    /// Most connected to first.
+    Used,
+}
+
+impl SortMode {
+    /// The mode after this one, as the sort key cycles through them.
+    pub fn next(self) -> Self {
+        match self {
+            SortMode::Listed => SortMode::Name,
+            SortMode::Name => SortMode::Recent,
+            SortMode::Recent => SortMode::Used,
+            SortMode::Used => SortMode::Listed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerUsage {
+    /// Unix time of the last successful connection.
+    pub last_connected: i64,
+    pub connections: u64,
+}
+
+/// A user's connections, favorites and preferences, applied each time they log in.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserHistory {
+    pub servers: BTreeMap<String, ServerUsage>,
+    /// Servers pinned to the top of the menu.
+    pub favorites: BTreeSet<String>,
+    pub sort: SortMode,
+    /// The tag the menu was last filtered by.
+    pub tag: Option<String>,
+    /// Name of the theme picked from `[themes]`, instead of `[theme]`.
+    pub theme: Option<String>,
+    /// Key preset picked instead of the config's.
+    pub keys: Option<KeyPreset>,
+}
+
+impl UserHistory {
+    pub fn usage(&self, server: &str) -> ServerUsage {
+        self.servers.get(server).copied().unwrap_or_default()
+    }
+
+    pub fn is_favorite(&self, server: &str) -> bool {
+        self.favorites.contains(server)
+    }
+
+    /// Orders two servers by the sort mode, after favorites. Servers that compare equal,
+    /// such as those never connected to, keep their listed order.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        let favorites = self.is_favorite(b).cmp(&self.is_favorite(a));
+        favorites.then_with(|| match self.sort {
+            SortMode::Listed => Ordering::Equal,
+            SortMode::Name => a.cmp(b),
+            SortMode::Recent => self
+                .usage(b)
+                .last_connected
+                .cmp(&self.usage(a).last_connected),
+            SortMode::Used => self.usage(b).connections.cmp(&self.usage(a).connections),
+        })
+    }
+}
+
+/// Every user's history, kept in a JSON file in the state directory and rewritten on
+/// every change. In a cluster it is shared with the other nodes, each user's as a whole.
+#[derive(Debug)]
+pub struct History {
+    path: PathBuf,
+    users: Mutex<HashMap<String, UserHistory>>,
+}
+
+impl History {
+    /// Loads the history from `path`, starting afresh if it does not exist. A file that
+    /// cannot be read is logged and replaced on the next change.
+    pub fn load(path: PathBuf) -> Self {
+        let users = match read(&path) {
+            Ok(users) => users,
+            Err(e) => {
+                warn!("Ignoring menu history: {:#}", e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path,
+            users: Mutex::new(users),
+        }
+    }
+
+    pub fn user(&self, user: &str) -> UserHistory {
+        self.users
+            .lock()
+            .unwrap()
+            .get(user)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn record_connection(&self, user: &str, server: &str) {
+        self.update(user, |history| {
+            let usage = history.servers.entry(server.to_string()).or_default();
+            usage.last_connected = chrono::Utc::now().timestamp();
+            usage.connections += 1;
+        });
+    }
+
+    /// Adds or removes `server` from `user`'s favorites.
+    pub fn toggle_favorite(&self, user: &str, server: &str) {
+        self.update(user, |history| {
+            if !history.favorites.remove(server) {
+                history.favorites.insert(server.to_string());
+            }
+        });
+    }
+
+    pub fn set_sort(&self, user: &str, sort: SortMode) {
+        self.update(user, |history| history.sort = sort);
+    }
+
+    pub fn set_tag(&self, user: &str, tag: Option<String>) {
+        self.update(user, |history| history.tag = tag);
+    }
+
+    pub fn set_theme(&self, user: &str, theme: Option<String>) {
+        self.update(user, |history| history.theme = theme);
+    }
+
+    pub fn set_keys(&self, user: &str, keys: KeyPreset) {
+        self.update(user, |history| history.keys = Some(keys));
+    }
+
+    fn update(&self, user: &str, change: impl FnOnce(&mut UserHistory)) {
+        let mut users = self.users.lock().unwrap();
+        change(users.entry(user.to_string()).or_default());
+        self.save(&users);
+    }
+
+    fn save(&self, users: &HashMap<String, UserHistory>) {
+        if let Err(e) = write(&self.path, users) {
+            warn!("Failed to save menu history: {:#}", e);
+        }
+    }
+}
+
+impl SharedState for History {
+    fn kind(&self) -> &'static str {
+        "history"
+    }
+
+    fn entries(&self) -> Entries {
+        cluster::to_entries(&*self.users.lock().unwrap())
+    }
+
+    fn replace(&self, seen: &Entries, entries: Entries) -> bool {
+        let mut users = self.users.lock().unwrap();
+        let current = cluster::to_entries(&*users);
+        if current != *seen {
+            return false;
+        }
+        if entries != current {
+            *users = cluster::from_entries(self.kind(), entries).collect();
+            self.save(&users);
+        }
+        true
+    }
+}
+
+/// Reads a JSON file from the state directory, or the default if it does not exist.
+pub(crate) fn read<T: DeserializeOwned + Default>(path: &Path) -> anyhow::Result<T> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    serde_json::from_str(&contents).with_context(|| format!("Invalid JSON in {}", path.display()))
+}
+
+/// Writes to a temporary file first, so a crash cannot leave the file half written.
+pub(crate) fn write<T: Serialize>(path: &Path, value: &T) -> anyhow::Result<()> {
+    if let Some(directory) = path.parent() {
+        #[cfg(unix)]
+        let created = {
+            use std::os::unix::fs::DirBuilderExt;
+
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(directory)
+        };
+        #[cfg(not(unix))]
+        let created = std::fs::create_dir_all(directory);
+        created.with_context(|| format!("Failed to create {}", directory.display()))?;
+    }
+    let temporary = path.with_extension("json.tmp");
+    std::fs::write(&temporary, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("Failed to write {}", temporary.display()))?;
+    std::fs::rename(&temporary, path)
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,307 @@
+//! A fake menu for decoy logins and connections that keep guessing passwords. It lists
+//! servers that do not exist, refuses every command run on them and answers a character
+//! at a time, recording each line typed. Input that comes faster than it is answered is
+//! ignored, and a connection's lines stop being recorded after the first thousand.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use russh::ChannelId;
+use russh::server::Handle;
+use tokio::sync::mpsc;
+use tracing::{Instrument, info};
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::config::HoneypotConfig;
+use crate::sessions::ChannelPermit;
+
+/// Longest line kept, the rest of it is ignored.
+const MAX_LINE: usize = 1024;
+
+/// Pieces of output waiting to be written to a channel, mostly single characters echoed.
+/// Input that arrives while this many are waiting is ignored, as the output is written
+/// far slower than a client can send.
+const MAX_QUEUED: usize = 1024;
+
+/// Lines recorded from a connection, after which what it types is no longer audited.
+const MAX_RECORDED: usize = 1000;
+
+enum Output {
+    Data(Vec<u8>),
+    Exit(u32),
+}
+
+/// The honeypot a connection was let into, as `login`.
+pub struct Honeypot {
+    session: usize,
+    login: String,
+    config: HoneypotConfig,
+    audit: Arc<AuditLog>,
+    channels: HashMap<ChannelId, Decoy>,
+    /// Lines recorded so far, up to `MAX_RECORDED`.
+    recorded: usize,
+}
+
+/// A session channel open in the honeypot.
+struct Decoy {
+    /// The fake server logged in to, or `None` at the menu.
+    server: Option<String>,
+    line: String,
+    /// Whether the last character was a carriage return, so a line feed after it does
+    /// not end another line.
+    return_pressed: bool,
+    output: mpsc::Sender<Output>,
+    _permit: ChannelPermit,
+}
+
+impl Honeypot {
+    pub fn new(
+        session: usize,
+        login: String,
+        config: HoneypotConfig,
+        audit: Arc<AuditLog>,
+    ) -> Self {
+        Self {
+            session,
+            login,
+            config,
+            audit,
+            channels: HashMap::new(),
+            recorded: 0,
+        }
+    }
+
+    /// Opens `channel`, whose output is written through `handle` as slowly as configured.
+    pub fn open(&mut self, channel: ChannelId, permit: ChannelPermit, handle: Handle) {
+        let (output, receiver) = mpsc::channel(MAX_QUEUED);
+        tokio::spawn(drip(channel, handle, self.config.delay, receiver).in_current_span());
+        self.channels.insert(
+            channel,
+            Decoy {
+                server: None,
+                line: String::new(),
+                return_pressed: false,
+                output,
+                _permit: permit,
+            },
+        );
+    }
+
+    /// Shows the menu on `channel`.
+    pub fn shell(&mut self, channel: ChannelId) {
+        let menu = self.menu();
+        if let Some(decoy) = self.channels.get(&channel) {
+            decoy.send(menu);
+        }
+    }
+
+    /// Refuses to run `command`, recording it.
+    pub fn exec(&mut self, channel: ChannelId, command: &str) {
+        self.record(None, command);
+        let Some(decoy) = self.channels.get(&channel) else {
+            return;
+        };
+        let name = command.split_whitespace().next().unwrap_or_default();
+        decoy.send(format!("{name}: Permission denied\n"));
+        decoy.exit(1);
+    }
+
+    /// Echoes what the client typed on `channel`, acting on each line it ends.
+    pub fn data(&mut self, channel: ChannelId, data: &[u8]) {
+        for c in String::from_utf8_lossy(data).chars() {
+            let Some(decoy) = self.channels.get_mut(&channel) else {
+                return;
+            };
+            if decoy.output.capacity() == 0 {
+                return;
+            }
+            let return_pressed = std::mem::replace(&mut decoy.return_pressed, c == '\r');
+            match c {
+                '\n' if return_pressed => {}
+                '\r' | '\n' => {
+                    decoy.send("\r\n");
+                    let line = std::mem::take(&mut decoy.line);
+                    self.enter(channel, line.trim());
+                }
+                // Ctrl-C
+                '\x03' => {
+                    decoy.line.clear();
+                    let prompt = prompt(&self.login, decoy.server.as_deref());
+                    decoy.send(format!("^C\r\n{prompt}"));
+                }
+                // Ctrl-D
+                '\x04' if decoy.line.is_empty() => {
+                    decoy.send("\r\n");
+                    self.enter(channel, "exit");
+                }
+                '\x7f' | '\x08' if decoy.line.pop().is_some() => decoy.send("\x08 \x08"),
+                c if !c.is_control() && decoy.line.len() < MAX_LINE => {
+                    decoy.line.push(c);
+                    decoy.send(c.to_string());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Ends `channel` once what is left of its output is written, as the client will
+    /// type nothing more.
+    pub fn eof(&mut self, channel: ChannelId) {
+        if let Some(decoy) = self.channels.get(&channel) {
+            decoy.exit(0);
+        }
+    }
+
+    pub fn close(&mut self, channel: ChannelId) {
+        self.channels.remove(&channel);
+    }
+
+    /// Acts on `line`, typed at the menu or on a fake server.
+    fn enter(&mut self, channel: ChannelId, line: &str) {
+        let Some(server) = self
+            .channels
+            .get(&channel)
+            .map(|decoy| decoy.server.clone())
+        else {
+            return;
+        };
+        if !line.is_empty() {
+            self.record(server.as_deref(), line);
+        }
+        let output = match (&server, line) {
+            (_, "") => prompt(&self.login, server.as_deref()),
+            (None, "q" | "quit" | "exit") => {
+                if let Some(decoy) = self.channels.get(&channel) {
+                    decoy.exit(0);
+                }
+                return;
+            }
+            (None, line) => {
+                let selected = line
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|i| i.checked_sub(1))
+                    .and_then(|i| self.config.servers.get(i))
+                    .or_else(|| self.config.servers.iter().find(|name| *name == line))
+                    .cloned();
+                match selected {
+                    Some(selected) => {
+                        let output = format!(
+                            "Connecting to {selected}...\r\n\r\n{}",
+                            prompt(&self.login, Some(&selected))
+                        );
+                        if let Some(decoy) = self.channels.get_mut(&channel) {
+                            decoy.server = Some(selected);
+                        }
+                        output
+                    }
+                    None => format!("Unknown server {line}\r\n{}", prompt(&self.login, None)),
+                }
+            }
+            (Some(server), "exit" | "logout") => {
+                let output = format!("Connection to {server} closed.\r\n{}", self.menu());
+                if let Some(decoy) = self.channels.get_mut(&channel) {
+                    decoy.server = None;
+                }
+                output
+            }
+            (Some(server), line) => {
+                let name = line.split_whitespace().next().unwrap_or_default();
+                format!(
+                    "{name}: Permission denied\r\n{}",
+                    prompt(&self.login, Some(server))
+                )
+            }
+        };
+        if let Some(decoy) = self.channels.get(&channel) {
+            decoy.send(output);
+        }
+    }
+
+    fn menu(&self) -> String {
+        let mut menu = String::from("Select a server:\r\n");
+        for (i, server) in self.config.servers.iter().enumerate() {
+            menu.push_str(&format!("  {}) {}\r\n", i + 1, server));
+        }
+        menu.push_str("\r\n");
+        menu.push_str(&prompt(&self.login, None));
+        menu
+    }
+
+    fn record(&mut self, server: Option<&str>, input: &str) {
+        if self.recorded >= MAX_RECORDED {
+            return;
+        }
+        self.recorded += 1;
+        if self.recorded == MAX_RECORDED {
+            info!(
+                "Recorded {} lines from {}, ignoring the rest",
+                MAX_RECORDED, self.login
+            );
+        }
+        info!("Honeypot input from {}: {:?}", self.login, input);
+        self.audit.record(AuditEvent::HoneypotInput {
+            session: self.session,
+            login: &self.login,
+            server,
+            input,
+        });
+    }
+}
+
+impl Decoy {
+    /// Queues `output`, dropping it if too much is waiting already. The channel is gone if
+    /// the writer has stopped.
+    fn send(&self, output: impl Into<String>) {
+        let _ = self
+            .output
+            .try_send(Output::Data(output.into().into_bytes()));
+    }
+
+    /// Ends the channel once the output before it is written, waiting for room to queue
+    /// it if need be.
+    fn exit(&self, status: u32) {
+        if let Err(mpsc::error::TrySendError::Full(exit)) =
+            self.output.try_send(Output::Exit(status))
+        {
+            let output = self.output.clone();
+            tokio::spawn(async move { output.send(exit).await });
+        }
+    }
+}
+
+fn prompt(login: &str, server: Option<&str>) -> String {
+    match server {
+        Some(server) => format!("{login}@{server}:~$ "),
+        None => "Server: ".to_string(),
+    }
+}
+
+/// Writes the output sent for `channel` a byte at a time, pausing for `delay` after each,
+/// until its channel exits or is closed.
+async fn drip(
+    channel: ChannelId,
+    handle: Handle,
+    delay: Duration,
+    mut output: mpsc::Receiver<Output>,
+) {
+    while let Some(output) = output.recv().await {
+        match output {
+            Output::Data(data) => {
+                for byte in data {
+                    if handle.data(channel, vec![byte].into()).await.is_err() {
+                        return;
+                    }
+                    tokio::time::sleep(delay).await;
+                }
+            }
+            Output::Exit(status) => {
+                let _ = handle.exit_status_request(channel, status).await;
+                let _ = handle.eof(channel).await;
+                let _ = handle.close(channel).await;
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, bail};
+use rand_core::OsRng;
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{PrivateKey, PublicKey};
+use tracing::info;
+
+use crate::config::PukekoConfig;
+
+/// Where the replacement for the host key at `path` waits until it is promoted.
+fn staged_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".next")
+}
+
+/// Where the host key at `path` is kept once its replacement is promoted.
+fn retired_path(path: &Path) -> PathBuf {
+    with_suffix(path, ".old")
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    name.into()
+}
+
+/// Generates a replacement for each host key pukeko generated, with the same algorithm,
+/// and stages it beside the current one for operators to add to clients' known_hosts
+/// before promoting it.
+pub fn rotate(config: &PukekoConfig) -> anyhow::Result<Vec<PublicKey>> {
+    let paths = generated_paths(config)?;
+    if let Some(path) = paths
+        .iter()
+        .map(|path| staged_path(path))
+        .find(|path| path.exists())
+    {
+        bail!(
+            "{} is already staged, promote it or remove it first",
+            path.display()
+        );
+    }
+
+    let mut staged = Vec::new();
+    for path in paths {
+        let current = PrivateKey::read_openssh_file(path)
+            .with_context(|| format!("Failed to load host key {}", path.display()))?;
+        let key = PrivateKey::random(&mut OsRng, current.algorithm())
+            .with_context(|| format!("Failed to generate a key to replace {}", path.display()))?;
+        let next = staged_path(path);
+        key.write_openssh_file(&next, LineEnding::LF)
+            .with_context(|| format!("Failed to write host key {}", next.display()))?;
+        key.public_key()
+            .write_openssh_file(&with_suffix(&next, ".pub"))
+            .with_context(|| format!("Failed to write host key {}.pub", next.display()))?;
+        info!(
+            "Staged {} to replace {}",
+            key.public_key().fingerprint(Default::default()),
+            path.display()
+        );
+        staged.push(key.public_key().clone());
+    }
+    Ok(staged)
+}
+
+/// Puts the staged host keys in place of the current ones, which are kept with an `.old`
+/// suffix. Clients are presented the new keys once pukeko restarts.
+pub fn promote(config: &PukekoConfig) -> anyhow::Result<Vec<PublicKey>> {
+    let paths = generated_paths(config)?;
+    if let Some(path) = paths.iter().find(|path| !staged_path(path).exists()) {
+        bail!(
+            "No replacement is staged for {}, run `pukeko keys rotate` first",
+            path.display()
+        );
+    }
+
+    let mut promoted = Vec::new();
+    for path in paths {
+        let next = staged_path(path);
+        let key = PrivateKey::read_openssh_file(&next)
+            .with_context(|| format!("Failed to load host key {}", next.display()))?;
+        let public = with_suffix(path, ".pub");
+        // Public keys are only written for operators, so they may have been removed.
+        for (from, to, required) in [
+            (path.clone(), retired_path(path), true),
+            (public.clone(), retired_path(&public), false),
+            (next.clone(), path.clone(), true),
+            (with_suffix(&next, ".pub"), public, false),
+        ] {
+            if required || from.exists() {
+                std::fs::rename(&from, &to).with_context(|| {
+                    format!("Failed to move {} to {}", from.display(), to.display())
+                })?;
+            }
+        }
+        info!(
+            "Promoted {} to {}",
+            key.public_key().fingerprint(Default::default()),
+            path.display()
+        );
+        promoted.push(key.public_key().clone());
+    }
+    Ok(promoted)
+}
+
+fn generated_paths(config: &PukekoConfig) -> anyhow::Result<&[PathBuf]> {
+    if config.generated_host_keys.is_empty() {
+        bail!("Host keys are configured with host_keys, replace those files instead");
+    }
+    Ok(&config.generated_host_keys)
+}
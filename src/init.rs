@@ -0,0 +1,81 @@
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::{Context, bail};
+use rand_core::OsRng;
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{Algorithm, PrivateKey, PublicKey};
+
+use crate::config::PukekoConfig;
+
+/// What `pukeko init` asks for.
+#[derive(Debug)]
+pub struct InitOptions {
+    pub listen: SocketAddr,
+    pub admin: String,
+    pub admin_key: PublicKey,
+    /// Replace a config already at the path.
+    pub force: bool,
+}
+
+/// Writes a starter config to `path` with a listener and an admin user, generates the key
+/// pukeko authenticates to servers with beside it unless there is one, then loads the
+/// config to check it, which generates the host keys.
+pub fn write_config(path: &Path, options: &InitOptions) -> anyhow::Result<PukekoConfig> {
+    if path.exists() && !options.force {
+        bail!(
+            "{} already exists, pass --force to replace it",
+            path.display()
+        );
+    }
+    let key = options.admin_key.to_openssh()?;
+    let config = format!(
+        r#"# Written by `pukeko init`. pukeko.example.toml describes every option.
+
+# A host key for each algorithm is generated in state_directory on first start.
+state_directory = "state"
+host_key_algorithms = ["ed25519"]
+
+# Key pukeko authenticates to servers with, add upstream_key.pub to their authorized_keys.
+upstream_key = "upstream_key"
+
+# Users are asked to confirm the host key of a server they first connect to.
+known_hosts = "known_hosts"
+host_key_policy = "tofu"
+
+listeners = [{{ address = {listen} }}]
+
+[[users]]
+name = {admin}
+admin = true
+keys = [{key}]
+
+# Servers users can connect to, for example:
+# [[servers]]
+# name = "web-01"
+# host = "10.0.0.10"
+# port = 22
+"#,
+        listen = toml::Value::String(options.listen.to_string()),
+        admin = toml::Value::String(options.admin.clone()),
+        key = toml::Value::String(key),
+    );
+
+    let directory = path.parent().unwrap_or(Path::new(""));
+    if !directory.as_os_str().is_empty() {
+        std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create {}", directory.display()))?;
+    }
+    let upstream_key = directory.join("upstream_key");
+    if !upstream_key.exists() {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+            .context("Failed to generate the upstream key")?;
+        key.write_openssh_file(&upstream_key, LineEnding::LF)
+            .with_context(|| format!("Failed to write {}", upstream_key.display()))?;
+        key.public_key()
+            .write_openssh_file(&directory.join("upstream_key.pub"))
+            .with_context(|| format!("Failed to write {}.pub", upstream_key.display()))?;
+    }
+    std::fs::write(path, config).with_context(|| format!("Failed to write {}", path.display()))?;
+    PukekoConfig::load(path)
+}
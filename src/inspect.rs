@@ -0,0 +1,207 @@
+use std::borrow::Cow;
+use std::sync::Mutex;
+
+use regex::bytes::NoExpand;
+use serde::Serialize;
+
+use crate::config::{ClipboardPolicy, InspectAction, InspectRule};
+
+/// Which way a chunk of forwarded data is going.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the client to the server.
+    Input,
+    /// From the server to the client.
+    Output,
+}
+
+impl Direction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::Input => "input",
+            Direction::Output => "output",
+        }
+    }
+}
+
+/// A chunk of data about to be forwarded.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunk<'a> {
+    pub session: usize,
+    pub user: &'a str,
+    pub server: &'a str,
+    pub direction: Direction,
+    pub data: &'a [u8],
+}
+
+/// What an inspector made of a chunk.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Inspection {
+    /// Forwarded in place of the chunk, such as when something was redacted.
+    pub data: Option<Vec<u8>>,
+    /// Each is logged and audited. The forward is closed if any blocks.
+    pub findings: Vec<Finding>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    /// Name of what was found, such as the rule that matched.
+    pub rule: String,
+    pub action: InspectAction,
+}
+
+/// Looks at data as it is forwarded between clients and servers. Inspectors run in turn
+/// on every chunk, each seeing the data the ones before it forward.
+pub trait StreamInspector: Send + Sync {
+    fn inspect(&self, chunk: &Chunk) -> Inspection;
+}
+
+/// Inspects data with the config's `inspect` rules.
+#[derive(Debug, Clone)]
+pub struct PatternInspector {
+    rules: Vec<InspectRule>,
+}
+
+impl PatternInspector {
+    pub fn new(rules: Vec<InspectRule>) -> Self {
+        Self { rules }
+    }
+}
+
+impl StreamInspector for PatternInspector {
+    fn inspect(&self, chunk: &Chunk) -> Inspection {
+        let mut data = Cow::Borrowed(chunk.data);
+        let mut findings = Vec::new();
+        for rule in &self.rules {
+            if !rule.direction.covers(chunk.direction) || !rule.pattern.is_match(&data) {
+                continue;
+            }
+            findings.push(Finding {
+                rule: rule.name.clone(),
+                action: rule.action,
+            });
+            if rule.action == InspectAction::Redact {
+                let redacted = rule
+                    .pattern
+                    .replace_all(&data, NoExpand(rule.replacement.as_bytes()))
+                    .into_owned();
+                data = Cow::Owned(redacted);
+            }
+        }
+        Inspection {
+            data: match data {
+                Cow::Owned(data) => Some(data),
+                Cow::Borrowed(_) => None,
+            },
+            findings,
+        }
+    }
+}
+
+/// Starts an OSC 52 sequence, which sets the clipboard of the client's terminal.
+const OSC_52: &[u8] = b"\x1b]52;";
+
+/// Finds OSC 52 sequences in output from a server, following them across chunks, and logs
+/// or strips them as the forward's `clipboard` policy says.
+#[derive(Debug)]
+pub struct ClipboardInspector {
+    strip: bool,
+    state: Mutex<ClipboardState>,
+}
+
+#[derive(Debug, Default)]
+struct ClipboardState {
+    /// Whether the last chunk ended inside a sequence.
+    in_sequence: bool,
+    /// The end of the last chunk, which could be the start of a sequence, held back when
+    /// stripping until the next chunk shows whether it is.
+    held: Vec<u8>,
+}
+
+impl ClipboardInspector {
+    /// An inspector for a forward with `policy`, or None if sequences are allowed.
+    pub fn new(policy: ClipboardPolicy) -> Option<Self> {
+        let strip = match policy {
+            ClipboardPolicy::Allow => return None,
+            ClipboardPolicy::Log => false,
+            ClipboardPolicy::Strip => true,
+        };
+        Some(Self {
+            strip,
+            state: Mutex::default(),
+        })
+    }
+}
+
+impl StreamInspector for ClipboardInspector {
+    fn inspect(&self, chunk: &Chunk) -> Inspection {
+        if chunk.direction != Direction::Output {
+            return Inspection::default();
+        }
+        let mut state = self.state.lock().unwrap();
+        let mut data = std::mem::take(&mut state.held);
+        data.extend_from_slice(chunk.data);
+
+        let mut kept = Vec::with_capacity(data.len());
+        let mut found = false;
+        let mut i = 0;
+        while i < data.len() {
+            if state.in_sequence {
+                // Ended by BEL or ST. The ESC of ST is left to end it for the terminal too.
+                match data[i..].iter().position(|&b| b == 0x07 || b == 0x1b) {
+                    Some(end) => {
+                        i += end + usize::from(data[i + end] == 0x07);
+                        state.in_sequence = false;
+                    }
+                    None => i = data.len(),
+                }
+                continue;
+            }
+            match data[i..]
+                .windows(OSC_52.len())
+                .position(|window| window == OSC_52)
+            {
+                Some(start) => {
+                    kept.extend_from_slice(&data[i..i + start]);
+                    i += start + OSC_52.len();
+                    state.in_sequence = true;
+                    found = true;
+                }
+                None => {
+                    let partial = (1..OSC_52.len())
+                        .rev()
+                        .find(|&len| data[i..].ends_with(&OSC_52[..len]))
+                        .unwrap_or(0);
+                    kept.extend_from_slice(&data[i..data.len() - partial]);
+                    state.held = data[data.len() - partial..].to_vec();
+                    break;
+                }
+            }
+        }
+
+        let findings = if found {
+            vec![Finding {
+                rule: "clipboard".to_string(),
+                action: if self.strip {
+                    InspectAction::Redact
+                } else {
+                    InspectAction::Alert
+                },
+            }]
+        } else {
+            Vec::new()
+        };
+        if !self.strip {
+            // Only the start of a sequence is looked for in what is held, it was forwarded.
+            return Inspection {
+                data: None,
+                findings,
+            };
+        }
+        Inspection {
+            data: (kept != chunk.data).then_some(kept),
+            findings,
+        }
+    }
+}
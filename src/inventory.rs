@@ -0,0 +1,390 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use tokio::process::Command;
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+
+use crate::config::{
+    ConfigReceiver, ContainerTarget, Ec2Config, InventoryConfig, InventorySource, PodTarget,
+    Protocol, ServerEntry,
+};
+#[cfg(feature = "docker")]
+use crate::docker::DockerSource;
+use crate::provider::BoxFuture;
+
+/// Fetches servers from outside the config file.
+pub trait ServerSource: Send + Sync {
+    fn fetch(&self) -> BoxFuture<'_, anyhow::Result<Vec<ServerEntry>>>;
+}
+
+/// A server as listed by an inventory source.
+#[derive(Debug, Deserialize)]
+struct InventoryEntry {
+    name: String,
+    host: Option<String>,
+    #[serde(default)]
+    protocol: Protocol,
+    port: Option<u16>,
+    pod: Option<PodTarget>,
+    container: Option<ContainerTarget>,
+    user: Option<String>,
+    group: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    bandwidth: Option<u64>,
+    login_command: Option<String>,
+}
+
+fn parse_servers(json: &[u8]) -> anyhow::Result<Vec<ServerEntry>> {
+    let entries: Vec<InventoryEntry> = serde_json::from_slice(json)?;
+    entries
+        .into_iter()
+        .map(|entry| {
+            let (host, port) = entry.protocol.address(
+                &entry.name,
+                entry.host,
+                entry.port,
+                entry.pod.as_ref(),
+                entry.container.as_ref(),
+            )?;
+            Ok(ServerEntry {
+                name: entry.name,
+                host,
+                port,
+                protocol: entry.protocol,
+                pod: entry.pod.map(Arc::new),
+                container: entry.container.map(Arc::new),
+                user: entry.user,
+                key: None,
+                group: entry.group,
+                tags: entry.tags,
+                // Like an unset limit, as it is in the config.
+                bandwidth: entry.bandwidth.filter(|&bandwidth| bandwidth > 0),
+                commands: None,
+                pool: true,
+                login_command: entry.login_command,
+                forward_env: None,
+                x11_forwarding: false,
+                confirm: false,
+                clipboard: None,
+                via: None,
+                srv: false,
+                backends: None,
+            })
+        })
+        .collect()
+}
+
+/// Runs a command and reads the servers from its output.
+#[derive(Debug, Clone)]
+pub struct ExecSource {
+    command: Vec<String>,
+}
+
+impl ExecSource {
+    pub fn new(command: Vec<String>) -> Self {
+        Self { command }
+    }
+}
+
+impl ServerSource for ExecSource {
+    fn fetch(&self) -> BoxFuture<'_, anyhow::Result<Vec<ServerEntry>>> {
+        Box::pin(async move {
+            let Some((program, args)) = self.command.split_first() else {
+                bail!("No command to run");
+            };
+            let output = run(program, args).await?;
+            parse_servers(&output).with_context(|| format!("Invalid output from {program}"))
+        })
+    }
+}
+
+/// Runs a command to completion, returning its output.
+async fn run(program: &str, args: &[String]) -> anyhow::Result<Vec<u8>> {
+    let output = Command::new(program)
+        .args(args)
+        .kill_on_drop(true)
+        .output()
+        .await
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.trim() {
+            "" => bail!("{} exited with {}", program, output.status),
+            stderr => bail!("{} exited with {}: {}", program, output.status, stderr),
+        }
+    }
+    Ok(output.stdout)
+}
+
+/// Reads the servers from the response to a GET request.
+#[derive(Debug, Clone)]
+pub struct HttpSource {
+    client: reqwest::Client,
+    url: String,
+    headers: BTreeMap<String, String>,
+}
+
+impl HttpSource {
+    pub fn new(url: impl Into<String>, headers: BTreeMap<String, String>) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: url.into(),
+            headers,
+        }
+    }
+}
+
+impl ServerSource for HttpSource {
+    fn fetch(&self) -> BoxFuture<'_, anyhow::Result<Vec<ServerEntry>>> {
+        Box::pin(async move {
+            let mut request = self.client.get(&self.url);
+            for (name, value) in &self.headers {
+                request = request.header(name, value);
+            }
+            let body = request
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .with_context(|| format!("Failed to fetch {}", self.url))?
+                .bytes()
+                .await?;
+            parse_servers(&body).with_context(|| format!("Invalid response from {}", self.url))
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DescribeInstances {
+    reservations: Vec<Reservation>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Reservation {
+    instances: Vec<Instance>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Instance {
+    instance_id: String,
+    private_ip_address: Option<String>,
+    #[serde(default)]
+    tags: Vec<Tag>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Tag {
+    key: String,
+    value: String,
+}
+
+/// Lists running EC2 instances with the AWS CLI, which finds credentials the usual ways.
+///
+/// Each instance's tags become server tags of the form `Key:Value`, so access can be
+/// granted with `tag:Environment:production`. Tags reserved by AWS are skipped.
+#[derive(Debug, Clone)]
+pub struct Ec2Source {
+    config: Ec2Config,
+}
+
+impl Ec2Source {
+    pub fn new(config: Ec2Config) -> Self {
+        Self { config }
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args: Vec<String> = ["ec2", "describe-instances", "--output", "json"]
+            .map(String::from)
+            .into();
+        if let Some(region) = &self.config.region {
+            args.extend(["--region".to_string(), region.clone()]);
+        }
+        if let Some(profile) = &self.config.profile {
+            args.extend(["--profile".to_string(), profile.clone()]);
+        }
+        args.push("--filters".to_string());
+        args.push("Name=instance-state-name,Values=running".to_string());
+        args.extend(
+            self.config
+                .tags
+                .iter()
+                .map(|(key, value)| format!("Name=tag:{key},Values={value}")),
+        );
+        args
+    }
+
+    fn servers(&self, instances: Vec<Instance>) -> Vec<ServerEntry> {
+        let name = |instance: &Instance| {
+            instance
+                .tags
+                .iter()
+                .find(|tag| tag.key == "Name")
+                .map_or_else(|| instance.instance_id.clone(), |tag| tag.value.clone())
+        };
+        let mut names: HashMap<String, usize> = HashMap::new();
+        for instance in &instances {
+            *names.entry(name(instance)).or_default() += 1;
+        }
+
+        instances
+            .into_iter()
+            .filter_map(|instance| {
+                let host = instance.private_ip_address.clone()?;
+                let mut name = name(&instance);
+                // Instances sharing a name, such as those of an auto scaling group, are told
+                // apart by their id.
+                if names[&name] > 1 {
+                    name = format!("{name}-{}", instance.instance_id);
+                }
+                let group = self.config.group_tag.as_ref().and_then(|key| {
+                    instance
+                        .tags
+                        .iter()
+                        .find(|tag| tag.key == *key)
+                        .map(|tag| tag.value.clone())
+                });
+                let tags = instance
+                    .tags
+                    .iter()
+                    .filter(|tag| tag.key != "Name" && !tag.key.starts_with("aws:"))
+                    .map(|tag| format!("{}:{}", tag.key, tag.value))
+                    .collect();
+                Some(ServerEntry {
+                    name,
+                    host,
+                    port: 22,
+                    protocol: Protocol::Ssh,
+                    pod: None,
+                    container: None,
+                    user: self.config.user.clone(),
+                    key: None,
+                    group,
+                    tags,
+                    bandwidth: None,
+                    commands: None,
+                    pool: true,
+                    login_command: None,
+                    forward_env: None,
+                    x11_forwarding: false,
+                    confirm: false,
+                    clipboard: None,
+                    via: None,
+                    srv: false,
+                    backends: None,
+                })
+            })
+            .collect()
+    }
+}
+
+impl ServerSource for Ec2Source {
+    fn fetch(&self) -> BoxFuture<'_, anyhow::Result<Vec<ServerEntry>>> {
+        Box::pin(async move {
+            let output = run("aws", &self.args()).await?;
+            let response: DescribeInstances =
+                serde_json::from_slice(&output).context("Invalid output from aws")?;
+            let instances = response
+                .reservations
+                .into_iter()
+                .flat_map(|reservation| reservation.instances)
+                .collect();
+            Ok(self.servers(instances))
+        })
+    }
+}
+
+/// Servers from every configured inventory source. A source that fails to refresh keeps
+/// the servers it last returned.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    servers: Mutex<BTreeMap<String, Vec<ServerEntry>>>,
+}
+
+impl Inventory {
+    /// Every server, by source name. Names listed by an earlier source are skipped.
+    pub fn servers(&self) -> Vec<ServerEntry> {
+        let mut names = HashSet::new();
+        self.servers
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .filter(|entry| names.insert(entry.name.clone()))
+            .cloned()
+            .collect()
+    }
+
+    pub fn server(&self, name: &str) -> Option<ServerEntry> {
+        self.servers
+            .lock()
+            .unwrap()
+            .values()
+            .flatten()
+            .find(|entry| entry.name == name)
+            .cloned()
+    }
+
+    /// Refreshes each source on its interval, following config reloads.
+    pub async fn run(self: Arc<Self>, mut config: ConfigReceiver) {
+        loop {
+            let sources = config.borrow_and_update().inventory.clone();
+            self.servers
+                .lock()
+                .unwrap()
+                .retain(|name, _| sources.iter().any(|source| source.name == *name));
+
+            let mut refreshes = JoinSet::new();
+            for source in sources {
+                refreshes.spawn(self.clone().refresh(source));
+            }
+
+            // Dropping the refreshes stops them, to be started again from the new config.
+            if config.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn refresh(self: Arc<Self>, config: InventoryConfig) {
+        let source: Box<dyn ServerSource> = match config.source {
+            InventorySource::Exec { command } => Box::new(ExecSource::new(command)),
+            InventorySource::Http { url, headers } => Box::new(HttpSource::new(url, headers)),
+            InventorySource::Ec2(ec2) => Box::new(Ec2Source::new(ec2)),
+            #[cfg(feature = "docker")]
+            InventorySource::Docker(docker) => Box::new(DockerSource::new(docker)),
+            // The config refuses docker without the feature, so this is never reached.
+            #[cfg(not(feature = "docker"))]
+            InventorySource::Docker(_) => return,
+        };
+
+        loop {
+            let result = tokio::time::timeout(config.timeout, source.fetch())
+                .await
+                .context("Timed out")
+                .flatten();
+            match result {
+                Ok(servers) => {
+                    let count = servers.len();
+                    debug!("Fetched {} servers from inventory {}", count, config.name);
+                    let previous = self
+                        .servers
+                        .lock()
+                        .unwrap()
+                        .insert(config.name.clone(), servers);
+                    if previous.is_none_or(|previous| previous.len() != count) {
+                        info!("Inventory {} lists {} servers", config.name, count);
+                    }
+                }
+                Err(e) => warn!("Failed to refresh inventory {}: {:#}", config.name, e),
+            }
+            tokio::time::sleep(config.interval).await;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+use russh::Channel;
+use russh::server::Msg;
+use tracing::{debug, trace};
+
+use crate::config::{PukekoConfig, ServerEntry};
+use crate::dial::{self, Stream};
+use crate::inspect::Direction;
+use crate::metrics::Metrics;
+use crate::sessions::{ChannelPermit, SessionBytes};
+use crate::srv;
+use crate::upstream::CONNECT_TIMEOUT;
+
+/// Connects to the SSH port of `entry` for a client jumping to it with `ssh -J`, logging
+/// in to any server it is reached through as `user`.
+pub async fn connect(
+    entry: &ServerEntry,
+    user: &str,
+    config: &PukekoConfig,
+) -> anyhow::Result<Box<dyn Stream>> {
+    debug!(
+        "Jumping to upstream {} at {}:{}",
+        entry.name, entry.host, entry.port
+    );
+    let (stream, _) = dial::each_backend(entry, |backend| async move {
+        let resolved = srv::resolve(&backend)
+            .await
+            .with_context(|| format!("Failed to connect to {}", entry.name))?;
+        tokio::time::timeout(CONNECT_TIMEOUT, dial::dial(&resolved, user, config))
+            .await
+            .with_context(|| format!("Timed out connecting to {}", entry.name))?
+            .with_context(|| format!("Failed to connect to {}", entry.name))
+    })
+    .await?;
+    Ok(stream)
+}
+
+/// Bridges the direct-tcpip channel the client opened to the server's SSH port. The
+/// client's own SSH session to the server runs inside it, so pukeko only counts the bytes.
+pub async fn relay(
+    channel: Channel<Msg>,
+    mut stream: Box<dyn Stream>,
+    server: &str,
+    bytes: Arc<SessionBytes>,
+    metrics: Arc<Metrics>,
+    // Held until the jump closes.
+    _permit: ChannelPermit,
+) -> anyhow::Result<()> {
+    let mut channel = channel.into_stream();
+    let (down, up) = tokio::io::copy_bidirectional(&mut stream, &mut channel).await?;
+    bytes.down.fetch_add(down, Ordering::Relaxed);
+    bytes.up.fetch_add(up, Ordering::Relaxed);
+    metrics.bytes_forwarded(server, Direction::Output, down);
+    metrics.bytes_forwarded(server, Direction::Input, up);
+    trace!("Jump to {} closed", server);
+    Ok(())
+}
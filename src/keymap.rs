@@ -0,0 +1,456 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use termwiz::escape::csi::{CSI, Cursor, Unspecified};
+use termwiz::escape::{Action, ControlCode};
+
+/// A key pressed in the menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Char(char),
+    /// A letter pressed with Ctrl, other than those with keys of their own such as Tab.
+    Ctrl(char),
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Tab,
+    Backspace,
+}
+
+impl Key {
+    /// Recognizes a key from a parsed action. `after_ss3` is whether the previous action
+    /// was the `ESC O` that terminals in application mode send cursor keys with.
+    pub fn parse(action: &Action, after_ss3: bool) -> Option<Self> {
+        match action {
+            Action::Print(c) if after_ss3 => match c {
+                'A' => Some(Key::Up),
+                'B' => Some(Key::Down),
+                'C' => Some(Key::Right),
+                'D' => Some(Key::Left),
+                'H' => Some(Key::Home),
+                'F' => Some(Key::End),
+                _ => None,
+            },
+            Action::Print(c) => Some(Key::Char(*c)),
+            Action::Control(ControlCode::CarriageReturn | ControlCode::LineFeed) => {
+                Some(Key::Enter)
+            }
+            Action::Control(ControlCode::HorizontalTab) => Some(Key::Tab),
+            Action::Control(ControlCode::Backspace) => Some(Key::Backspace),
+            Action::Control(code) => match *code as u8 {
+                code @ 1..=26 => Some(Key::Ctrl((b'a' + code - 1) as char)),
+                _ => None,
+            },
+            Action::CSI(CSI::Cursor(cursor)) => match cursor {
+                Cursor::Up(_) => Some(Key::Up),
+                Cursor::Down(_) => Some(Key::Down),
+                Cursor::Left(_) => Some(Key::Left),
+                Cursor::Right(_) => Some(Key::Right),
+                Cursor::Position { .. } => Some(Key::Home),
+                Cursor::PrecedingLine(_) => Some(Key::End),
+                _ => None,
+            },
+            // Paging keys are sequences the parser leaves uninterpreted.
+            Action::CSI(CSI::Unspecified(unspecified)) => {
+                let Unspecified {
+                    params, control, ..
+                } = unspecified.as_ref();
+                match (params.first().and_then(|param| param.as_integer()), control) {
+                    (Some(5), '~') => Some(Key::PageUp),
+                    (Some(6), '~') => Some(Key::PageDown),
+                    (Some(1 | 7), '~') => Some(Key::Home),
+                    (Some(4 | 8), '~') => Some(Key::End),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Char(' ') => f.write_str("Space"),
+            Key::Char(c) => write!(f, "{c}"),
+            Key::Ctrl(c) => write!(f, "Ctrl-{c}"),
+            Key::Up => f.write_str("↑"),
+            Key::Down => f.write_str("↓"),
+            Key::Left => f.write_str("←"),
+            Key::Right => f.write_str("→"),
+            Key::PageUp => f.write_str("PgUp"),
+            Key::PageDown => f.write_str("PgDn"),
+            Key::Home => f.write_str("Home"),
+            Key::End => f.write_str("End"),
+            Key::Enter => f.write_str("Enter"),
+            Key::Tab => f.write_str("Tab"),
+            Key::Backspace => f.write_str("Backspace"),
+        }
+    }
+}
+
+/// Parses keys as written in the config: a single character, a name such as `Up`,
+/// `PageDown` or `Space`, or a letter with Ctrl as `Ctrl-n`.
+impl FromStr for Key {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut chars = s.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(Key::Char(c));
+        }
+        if let Some(letter) = s
+            .strip_prefix("Ctrl-")
+            .or_else(|| s.strip_prefix("ctrl-"))
+            .or_else(|| s.strip_prefix("C-"))
+        {
+            let mut chars = letter.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_alphabetic() => Ok(Key::Ctrl(c.to_ascii_lowercase())),
+                _ => bail!("Only letters can be used with Ctrl, not {s:?}"),
+            };
+        }
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "space" => Key::Char(' '),
+            "up" => Key::Up,
+            "down" => Key::Down,
+            "left" => Key::Left,
+            "right" => Key::Right,
+            "pageup" | "pgup" => Key::PageUp,
+            "pagedown" | "pgdn" => Key::PageDown,
+            "home" => Key::Home,
+            "end" => Key::End,
+            "enter" | "return" => Key::Enter,
+            "tab" => Key::Tab,
+            "backspace" => Key::Backspace,
+            _ => bail!("Unknown key {s:?}"),
+        })
+    }
+}
+
+/// Something a key does in the menu.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MenuAction {
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    First,
+    Last,
+    Collapse,
+    Expand,
+    Select,
+    Pane,
+    Filter,
+    Tags,
+    Sort,
+    Favorite,
+    Request,
+    Keys,
+    Preferences,
+    Help,
+    Quit,
+    Sessions,
+    Terminate,
+    Message,
+    Shadow,
+}
+
+/// Where an action applies, which is also how the help lists them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyContext {
+    Navigation,
+    Servers,
+    Menu,
+    /// The admin's list of every session.
+    Sessions,
+}
+
+impl MenuAction {
+    /// Whether the action applies in the servers view, the sessions view, or both.
+    fn applies(self, sessions_view: bool) -> bool {
+        match self {
+            MenuAction::Collapse
+            | MenuAction::Expand
+            | MenuAction::Select
+            | MenuAction::Pane
+            | MenuAction::Filter
+            | MenuAction::Tags
+            | MenuAction::Sort
+            | MenuAction::Favorite
+            | MenuAction::Request
+            | MenuAction::Keys => !sessions_view,
+            MenuAction::Terminate | MenuAction::Message | MenuAction::Shadow => sessions_view,
+            _ => true,
+        }
+    }
+
+    pub fn context(self) -> KeyContext {
+        match self {
+            MenuAction::Up
+            | MenuAction::Down
+            | MenuAction::PageUp
+            | MenuAction::PageDown
+            | MenuAction::First
+            | MenuAction::Last => KeyContext::Navigation,
+            MenuAction::Collapse
+            | MenuAction::Expand
+            | MenuAction::Select
+            | MenuAction::Pane
+            | MenuAction::Filter
+            | MenuAction::Tags
+            | MenuAction::Sort
+            | MenuAction::Favorite
+            | MenuAction::Request
+            | MenuAction::Keys => KeyContext::Servers,
+            MenuAction::Preferences | MenuAction::Help | MenuAction::Quit => KeyContext::Menu,
+            MenuAction::Sessions
+            | MenuAction::Terminate
+            | MenuAction::Message
+            | MenuAction::Shadow => KeyContext::Sessions,
+        }
+    }
+
+    /// The action's name in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            MenuAction::Up => "up",
+            MenuAction::Down => "down",
+            MenuAction::PageUp => "page_up",
+            MenuAction::PageDown => "page_down",
+            MenuAction::First => "first",
+            MenuAction::Last => "last",
+            MenuAction::Collapse => "collapse",
+            MenuAction::Expand => "expand",
+            MenuAction::Select => "select",
+            MenuAction::Pane => "pane",
+            MenuAction::Filter => "filter",
+            MenuAction::Tags => "tags",
+            MenuAction::Sort => "sort",
+            MenuAction::Favorite => "favorite",
+            MenuAction::Request => "request",
+            MenuAction::Keys => "keys",
+            MenuAction::Preferences => "preferences",
+            MenuAction::Help => "help",
+            MenuAction::Quit => "quit",
+            MenuAction::Sessions => "sessions",
+            MenuAction::Terminate => "terminate",
+            MenuAction::Message => "message",
+            MenuAction::Shadow => "shadow",
+        }
+    }
+}
+
+/// A starting set of bindings, which the config can change one action at a time. The
+/// arrow and paging keys are bound in every preset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyPreset {
+    /// Arrows plus `hjkl`, and letters such as `q` to quit.
+    #[default]
+    Default,
+    /// Only arrows and named keys, so no letter does anything unexpected.
+    Arrows,
+    /// The default keys plus `g`/`G` and Ctrl-b/Ctrl-f to page.
+    Vim,
+    /// Ctrl-p/Ctrl-n to move, Ctrl-b/Ctrl-f to fold, Ctrl-s to filter and Ctrl-g to quit,
+    /// instead of `hjkl`.
+    Emacs,
+}
+
+impl KeyPreset {
+    /// The preset after this one, as users cycle through them in their preferences.
+    pub fn next(self) -> Self {
+        match self {
+            KeyPreset::Default => KeyPreset::Arrows,
+            KeyPreset::Arrows => KeyPreset::Vim,
+            KeyPreset::Vim => KeyPreset::Emacs,
+            KeyPreset::Emacs => KeyPreset::Default,
+        }
+    }
+
+    /// The preset's name in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyPreset::Default => "default",
+            KeyPreset::Arrows => "arrows",
+            KeyPreset::Vim => "vim",
+            KeyPreset::Emacs => "emacs",
+        }
+    }
+}
+
+/// The keys bound to each action, in the order the help lists them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMap {
+    base: KeyPreset,
+    bindings: Vec<(MenuAction, Vec<Key>)>,
+    /// Bindings replacing the preset's, kept to apply to another preset.
+    overrides: Vec<(MenuAction, Vec<Key>)>,
+}
+
+impl Default for KeyMap {
+    fn default() -> Self {
+        Self::preset(KeyPreset::Default)
+    }
+}
+
+impl KeyMap {
+    pub fn preset(preset: KeyPreset) -> Self {
+        use MenuAction::*;
+
+        let mut keymap = Self {
+            base: preset,
+            overrides: Vec::new(),
+            bindings: vec![
+                (Up, vec![Key::Up]),
+                (Down, vec![Key::Down]),
+                (PageUp, vec![Key::PageUp]),
+                (PageDown, vec![Key::PageDown]),
+                (First, vec![Key::Home]),
+                (Last, vec![Key::End]),
+                (Select, vec![Key::Enter]),
+                (Pane, vec![]),
+                (Collapse, vec![Key::Left]),
+                (Expand, vec![Key::Right]),
+                (Filter, vec![]),
+                (Tags, vec![]),
+                (Sort, vec![]),
+                (Favorite, vec![]),
+                (Request, vec![]),
+                (Keys, vec![]),
+                (Preferences, vec![Key::Ctrl('o')]),
+                (Help, vec![]),
+                (Quit, vec![]),
+                (Sessions, vec![Key::Tab]),
+                (Terminate, vec![]),
+                (Message, vec![]),
+                (Shadow, vec![Key::Enter]),
+            ],
+        };
+
+        if preset != KeyPreset::Arrows {
+            keymap.add(Pane, Key::Char('p'));
+            keymap.add(Filter, Key::Char('/'));
+            keymap.add(Tags, Key::Char('t'));
+            keymap.add(Sort, Key::Char('s'));
+            keymap.add(Favorite, Key::Char('f'));
+            keymap.add(Request, Key::Char('a'));
+            keymap.add(Keys, Key::Char('K'));
+            keymap.add(Preferences, Key::Char('o'));
+            keymap.add(Help, Key::Char('?'));
+            keymap.add(Quit, Key::Char('q'));
+            keymap.add(Terminate, Key::Char('t'));
+            keymap.add(Message, Key::Char('m'));
+            keymap.add(Shadow, Key::Char('w'));
+        }
+        if matches!(preset, KeyPreset::Default | KeyPreset::Vim) {
+            keymap.add(Up, Key::Char('k'));
+            keymap.add(Down, Key::Char('j'));
+            keymap.add(Collapse, Key::Char('h'));
+            keymap.add(Expand, Key::Char('l'));
+        }
+        match preset {
+            KeyPreset::Vim => {
+                keymap.add(PageUp, Key::Ctrl('b'));
+                keymap.add(PageDown, Key::Ctrl('f'));
+                keymap.add(First, Key::Char('g'));
+                keymap.add(Last, Key::Char('G'));
+            }
+            KeyPreset::Emacs => {
+                keymap.add(Up, Key::Ctrl('p'));
+                keymap.add(Down, Key::Ctrl('n'));
+                keymap.add(PageDown, Key::Ctrl('v'));
+                keymap.add(Collapse, Key::Ctrl('b'));
+                keymap.add(Expand, Key::Ctrl('f'));
+                keymap.add(Filter, Key::Ctrl('s'));
+                keymap.add(Quit, Key::Ctrl('g'));
+            }
+            KeyPreset::Default | KeyPreset::Arrows => {}
+        }
+        keymap
+    }
+
+    fn add(&mut self, action: MenuAction, key: Key) {
+        if let Some((_, keys)) = self.bindings.iter_mut().find(|(bound, _)| *bound == action) {
+            keys.push(key);
+        }
+    }
+
+    /// Replaces the keys bound to `action`. An empty list leaves it unbound.
+    pub fn bind(&mut self, action: MenuAction, keys: Vec<Key>) {
+        if let Some((_, bound)) = self.bindings.iter_mut().find(|(bound, _)| *bound == action) {
+            *bound = keys.clone();
+        }
+        self.overrides.retain(|(bound, _)| *bound != action);
+        self.overrides.push((action, keys));
+    }
+
+    /// The preset the bindings started from.
+    pub fn base(&self) -> KeyPreset {
+        self.base
+    }
+
+    /// These bindings starting from `preset` instead, failing if they then conflict.
+    pub fn with_preset(&self, preset: KeyPreset) -> anyhow::Result<Self> {
+        let mut keymap = Self::preset(preset);
+        for (action, keys) in &self.overrides {
+            keymap.bind(*action, keys.clone());
+        }
+        keymap.validate()?;
+        Ok(keymap)
+    }
+
+    /// Fails if a key is bound to two actions that apply in the same view.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (i, (action, keys)) in self.bindings.iter().enumerate() {
+            for (other, other_keys) in &self.bindings[i + 1..] {
+                let overlap = [false, true]
+                    .into_iter()
+                    .any(|sessions| action.applies(sessions) && other.applies(sessions));
+                if let Some(key) = keys.iter().find(|key| other_keys.contains(key))
+                    && overlap
+                {
+                    bail!(
+                        "Key {key} is bound to both {} and {}",
+                        action.name(),
+                        other.name()
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The action `key` is bound to in the view that is showing.
+    pub fn action(&self, key: Key, sessions_view: bool) -> Option<MenuAction> {
+        self.bindings
+            .iter()
+            .find(|(action, keys)| action.applies(sessions_view) && keys.contains(&key))
+            .map(|&(action, _)| action)
+    }
+
+    /// The keys bound to `action`, for hints such as "Press 'q' to quit".
+    pub fn keys(&self, action: MenuAction) -> &[Key] {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| *bound == action)
+            .map(|(_, keys)| keys.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn bindings(&self) -> impl Iterator<Item = (MenuAction, &[Key])> {
+        self.bindings
+            .iter()
+            .map(|(action, keys)| (*action, keys.as_slice()))
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, bail};
+use k8s_openapi::api::core::v1::Pod;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
+use kube::api::{Api, AttachParams, AttachedProcess, TerminalSize};
+use kube::config::KubeConfigOptions;
+use kube::{Client, Config};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tracing::debug;
+
+use crate::config::ServerEntry;
+use crate::flow::{UpstreamWriter, Written};
+use crate::forward::{ForwardKind, PtyRequest, UpstreamOutput, UpstreamSession};
+use crate::provider::BoxFuture;
+use crate::upstream::CONNECT_TIMEOUT;
+
+/// The extended data type SSH gives stderr.
+const STDERR: u32 = 1;
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+
+/// A command run in a pod's container, as `kubectl exec` runs it.
+pub struct PodSession {
+    /// Kept for the task relaying the streams, which stops when this is dropped.
+    _process: AttachedProcess,
+    stdin: Option<UpstreamWriter>,
+    stdout: Option<Reader>,
+    /// Only separate without a tty, which writes it to stdout.
+    stderr: Option<Reader>,
+    resize: Option<Box<dyn FnMut(TerminalSize) + Send>>,
+    status: Option<BoxFuture<'static, Option<Status>>>,
+    eof: bool,
+}
+
+impl PodSession {
+    /// Runs the shell for `kind` in the pod, or the command with `/bin/sh -c`.
+    pub async fn open(
+        entry: &ServerEntry,
+        kind: &ForwardKind,
+        pty: Option<&PtyRequest>,
+    ) -> anyhow::Result<Self> {
+        let Some(pod) = &entry.pod else {
+            bail!("{} is not a pod", entry.name)
+        };
+        let command = match kind {
+            ForwardKind::Shell => pod.shell.clone(),
+            ForwardKind::Exec(command) => vec![
+                "/bin/sh".to_string(),
+                "-c".to_string(),
+                String::from_utf8_lossy(command).into_owned(),
+            ],
+            ForwardKind::Subsystem(name) => {
+                bail!("{} is a pod, which has no {} subsystem", entry.name, name)
+            }
+        };
+
+        let options = KubeConfigOptions {
+            context: pod.context.clone(),
+            ..Default::default()
+        };
+        let config = match Config::from_kubeconfig(&options).await {
+            Ok(config) => config,
+            // Running inside the cluster, without a kubeconfig.
+            Err(_) if pod.context.is_none() => {
+                Config::incluster().context("Failed to find a kubeconfig or in-cluster config")?
+            }
+            Err(e) => return Err(e).context("Failed to read the kubeconfig"),
+        };
+        let namespace = pod
+            .namespace
+            .clone()
+            .unwrap_or_else(|| config.default_namespace.clone());
+        let client = Client::try_from(config).context("Failed to create a kubernetes client")?;
+        let pods: Api<Pod> = Api::namespaced(client, &namespace);
+
+        let mut params = AttachParams::default()
+            .stdin(true)
+            .stdout(true)
+            .stderr(pty.is_none())
+            .tty(pty.is_some());
+        if let Some(container) = &pod.container {
+            params = params.container(container);
+        }
+        debug!(
+            "Executing {:?} in pod {}/{} for {}",
+            command, namespace, pod.name, entry.name
+        );
+        let mut process =
+            tokio::time::timeout(CONNECT_TIMEOUT, pods.exec(&pod.name, command, &params))
+                .await
+                .with_context(|| format!("Timed out connecting to {}", entry.name))?
+                .with_context(|| format!("Failed to exec into {}", entry.name))?;
+
+        let mut resize = process.terminal_size().map(|mut sender| {
+            Box::new(move |size| {
+                let _ = sender.try_send(size);
+            }) as Box<dyn FnMut(TerminalSize) + Send>
+        });
+        if let (Some(resize), Some(pty)) = (&mut resize, pty) {
+            resize(terminal_size(pty.col_width, pty.row_height));
+        }
+        Ok(Self {
+            stdin: process.stdin().map(UpstreamWriter::spawn),
+            stdout: process.stdout().map(|stdout| Box::new(stdout) as _),
+            stderr: process.stderr().map(|stderr| Box::new(stderr) as _),
+            resize,
+            status: process
+                .take_status()
+                .map(|status| Box::pin(status) as BoxFuture<'static, Option<Status>>),
+            eof: false,
+            _process: process,
+        })
+    }
+}
+
+impl UpstreamSession for PodSession {
+    fn recv(&mut self) -> BoxFuture<'_, Option<UpstreamOutput>> {
+        Box::pin(async move {
+            let (mut output, mut errors) = ([0; 8192], [0; 8192]);
+            loop {
+                if self.stdout.is_none() && self.stderr.is_none() {
+                    if let Some(status) = &mut self.status {
+                        let status = status.await;
+                        self.status = None;
+                        if let Some(exit_status) = exit_status(status) {
+                            return Some(UpstreamOutput::ExitStatus(exit_status));
+                        }
+                    }
+                    if !self.eof {
+                        self.eof = true;
+                        return Some(UpstreamOutput::Eof);
+                    }
+                    return None;
+                }
+                tokio::select! {
+                    read = read(&mut self.stdout, &mut output) => match read {
+                        0 => self.stdout = None,
+                        read => return Some(UpstreamOutput::Data(output[..read].to_vec())),
+                    },
+                    read = read(&mut self.stderr, &mut errors) => match read {
+                        0 => self.stderr = None,
+                        read => {
+                            let data = errors[..read].to_vec();
+                            return Some(UpstreamOutput::ExtendedData(data, STDERR));
+                        }
+                    },
+                }
+            }
+        })
+    }
+
+    fn send(&mut self, data: Vec<u8>, written: Written) -> anyhow::Result<()> {
+        let stdin = self.stdin.as_ref().context("Input to the pod was closed")?;
+        stdin.write(data, Some(written))
+    }
+
+    fn eof(&mut self) -> BoxFuture<'_, ()> {
+        if let Some(stdin) = self.stdin.take() {
+            stdin.shutdown();
+        }
+        Box::pin(std::future::ready(()))
+    }
+
+    fn window_change(
+        &mut self,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+    ) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(resize) = &mut self.resize {
+                resize(terminal_size(col_width, row_height));
+            }
+        })
+    }
+
+    /// Dropping the process ends the exec.
+    fn close(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
+
+/// Reads into `buffer`, returning 0 at the end of the stream or on an error. Waits
+/// forever on a stream that has already ended.
+async fn read(stream: &mut Option<Reader>, buffer: &mut [u8]) -> usize {
+    match stream {
+        Some(stream) => stream.read(buffer).await.unwrap_or(0),
+        None => std::future::pending().await,
+    }
+}
+
+fn terminal_size(cols: u32, rows: u32) -> TerminalSize {
+    TerminalSize {
+        width: cols.min(u16::MAX as u32) as u16,
+        height: rows.min(u16::MAX as u32) as u16,
+    }
+}
+
+/// The command's exit status, from the status kubernetes sends when it ends.
+fn exit_status(status: Option<Status>) -> Option<u32> {
+    let status = status?;
+    if status.status.as_deref() == Some("Success") {
+        return Some(0);
+    }
+    let code = status
+        .details?
+        .causes?
+        .into_iter()
+        .find(|cause| cause.reason.as_deref() == Some("ExitCode"))
+        .and_then(|cause| cause.message?.parse().ok());
+    Some(code.unwrap_or(1))
+}
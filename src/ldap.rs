@@ -0,0 +1,232 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use russh::keys::PublicKey;
+use tracing::{debug, warn};
+
+use crate::config::{ConfigReceiver, LdapConfig};
+use crate::provider::{AuthDecision, AuthProvider, BoxFuture, Identity};
+
+/// A user's entry in the directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct LdapUser {
+    dn: String,
+    keys: Vec<String>,
+    /// DNs of the groups the user is in.
+    groups: Vec<String>,
+}
+
+/// Accepts keys listed in users' entries in the `ldap` directory of the config, following
+/// reloads. Rejects everyone while it is unset.
+///
+/// The config groups a user is mapped to are remembered from their last login for
+/// `group_ttl`, so changes to their directory groups take effect when they next log in or
+/// once that has passed. Each user's entry is searched for at most once per
+/// `search_interval`, however many keys their client offers, and the directory at most
+/// `max_searches` times a minute.
+#[derive(Debug)]
+pub struct LdapProvider {
+    config: ConfigReceiver,
+    cache: Mutex<Cache>,
+}
+
+/// What has been learnt from the directory under `ldap`, forgotten when it changes.
+#[derive(Debug, Default)]
+struct Cache {
+    ldap: Option<LdapConfig>,
+    /// The config groups each user was mapped to, and when.
+    groups: HashMap<String, (Vec<String>, Instant)>,
+    /// Each user's entry, or None if they had none, and when it was searched for.
+    entries: HashMap<String, (Option<LdapUser>, Instant)>,
+    /// When the directory was searched in the last minute.
+    searches: VecDeque<Instant>,
+}
+
+impl Cache {
+    /// Forgets everything learnt under another config than `ldap`.
+    fn follow(&mut self, ldap: &LdapConfig) {
+        if self.ldap.as_ref() != Some(ldap) {
+            *self = Self {
+                ldap: Some(ldap.clone()),
+                ..Self::default()
+            };
+        }
+    }
+}
+
+impl LdapProvider {
+    pub fn new(config: ConfigReceiver) -> Self {
+        Self {
+            config,
+            cache: Mutex::default(),
+        }
+    }
+
+    /// The config groups `user` was mapped to when they last logged in, unless that was
+    /// longer than `group_ttl` ago.
+    pub fn groups(&self, user: &str) -> Vec<String> {
+        let Some(ldap) = self.config.borrow().ldap.clone() else {
+            return Vec::new();
+        };
+        let mut cache = self.cache.lock().unwrap();
+        cache.follow(&ldap);
+        cache
+            .groups
+            .get(user)
+            .filter(|(_, at)| at.elapsed() < ldap.group_ttl)
+            .map(|(groups, _)| groups.clone())
+            .unwrap_or_default()
+    }
+
+    /// `user`'s entry, searched for again only once `search_interval` has passed since it
+    /// last was.
+    async fn entry(&self, ldap: &LdapConfig, user: &str) -> anyhow::Result<Option<LdapUser>> {
+        {
+            let mut cache = self.cache.lock().unwrap();
+            cache.follow(ldap);
+            let now = Instant::now();
+            cache
+                .entries
+                .retain(|_, (_, at)| now.duration_since(*at) < ldap.search_interval);
+            if let Some((entry, _)) = cache.entries.get(user) {
+                return Ok(entry.clone());
+            }
+            while cache
+                .searches
+                .front()
+                .is_some_and(|at| now.duration_since(*at).as_secs() >= 60)
+            {
+                cache.searches.pop_front();
+            }
+            if cache.searches.len() >= ldap.max_searches {
+                warn!("Not searching {} for {}: too many searches", ldap.url, user);
+                return Ok(None);
+            }
+            cache.searches.push_back(now);
+        }
+        let entry = search(ldap, user).await?;
+        let mut cache = self.cache.lock().unwrap();
+        cache.follow(ldap);
+        cache
+            .entries
+            .insert(user.to_string(), (entry.clone(), Instant::now()));
+        Ok(entry)
+    }
+}
+
+impl AuthProvider for LdapProvider {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        public_key: &'a PublicKey,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let ldap = self.config.borrow().ldap.clone();
+        Box::pin(async move {
+            let Some(ldap) = ldap else {
+                return Ok(AuthDecision::Reject);
+            };
+            let Some(entry) = self.entry(&ldap, user).await? else {
+                debug!("No directory entry for {}", user);
+                return Ok(AuthDecision::Reject);
+            };
+
+            let has_key = entry
+                .keys
+                .iter()
+                .any(|key| match PublicKey::from_openssh(key.trim()) {
+                    Ok(key) => key.key_data() == public_key.key_data(),
+                    Err(e) => {
+                        debug!("Skipping invalid key in {}: {}", entry.dn, e);
+                        false
+                    }
+                });
+            if !has_key {
+                return Ok(AuthDecision::Reject);
+            }
+
+            let groups: Vec<String> = entry
+                .groups
+                .iter()
+                .filter_map(|dn| ldap.groups.get(&dn.to_lowercase()).cloned())
+                .collect();
+            debug!("Directory user {} is in groups {:?}", user, groups);
+            let mut cache = self.cache.lock().unwrap();
+            cache.follow(&ldap);
+            cache
+                .groups
+                .insert(user.to_string(), (groups, Instant::now()));
+
+            Ok(AuthDecision::Accept(
+                Identity::new(user).with_attribute("ldap_dn", entry.dn),
+            ))
+        })
+    }
+}
+
+/// Finds `user`'s entry. Users matching more than one entry are treated as missing.
+#[cfg(feature = "ldap")]
+async fn search(ldap: &LdapConfig, user: &str) -> anyhow::Result<Option<LdapUser>> {
+    use anyhow::Context;
+    use ldap3::{LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+    let settings = LdapConnSettings::new().set_conn_timeout(ldap.timeout);
+    let (connection, mut client) = LdapConnAsync::with_settings(settings, &ldap.url)
+        .await
+        .with_context(|| format!("Failed to connect to {}", ldap.url))?;
+    ldap3::drive!(connection);
+
+    if let Some(bind_dn) = &ldap.bind_dn {
+        client
+            .with_timeout(ldap.timeout)
+            .simple_bind(bind_dn, ldap.bind_password.as_deref().unwrap_or_default())
+            .await?
+            .success()
+            .with_context(|| format!("Failed to bind to {} as {}", ldap.url, bind_dn))?;
+    }
+
+    let filter = ldap
+        .user_filter
+        .replace("{user}", &ldap3::ldap_escape(user));
+    let (entries, _) = client
+        .with_timeout(ldap.timeout)
+        .search(
+            &ldap.base_dn,
+            Scope::Subtree,
+            &filter,
+            vec![ldap.key_attribute.as_str(), ldap.group_attribute.as_str()],
+        )
+        .await?
+        .success()
+        .with_context(|| format!("Failed to search {} for {}", ldap.url, filter))?;
+    let _ = client.unbind().await;
+
+    let mut entries = entries.into_iter().map(SearchEntry::construct);
+    let (Some(entry), None) = (entries.next(), entries.next()) else {
+        return Ok(None);
+    };
+    // Servers may return attribute names in a different case than they were asked for.
+    let attribute = |name: &str| {
+        entry
+            .attrs
+            .iter()
+            .find(|(attribute, _)| attribute.eq_ignore_ascii_case(name))
+            .map(|(_, values)| values.clone())
+            .unwrap_or_default()
+    };
+    Ok(Some(LdapUser {
+        keys: attribute(&ldap.key_attribute),
+        groups: attribute(&ldap.group_attribute),
+        dn: entry.dn,
+    }))
+}
+
+/// The config refuses `ldap` without the feature, so this is never reached.
+#[cfg(not(feature = "ldap"))]
+async fn search(ldap: &LdapConfig, user: &str) -> anyhow::Result<Option<LdapUser>> {
+    let _ = user;
+    anyhow::bail!(
+        "Cannot search {}: pukeko was built without the ldap feature",
+        ldap.url
+    )
+}
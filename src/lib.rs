@@ -0,0 +1,98 @@
+//! An SSH bastion that lets users pick an upstream server from a menu, or connect
+//! straight to one as `user+server`.
+//!
+//! The server can be embedded in another application, optionally with its own sources
+//! of users and servers:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use pukeko::{PukekoConfig, PukekoServer};
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! let config = PukekoConfig::load("pukeko.toml".as_ref())?;
+//! let (_config_sender, config) = tokio::sync::watch::channel(Arc::new(config));
+//!
+//! let mut server = PukekoServer::builder(config)
+//!     .listen_address(([127, 0, 0, 1], 2222).into())
+//!     .build();
+//! server.run().await
+//! # }
+//! ```
+
+mod access;
+mod announcements;
+mod approval;
+pub mod audit;
+mod authorization;
+mod bandwidth;
+mod banner;
+pub mod bench;
+mod cert;
+pub mod check;
+mod cluster;
+pub mod config;
+pub mod control;
+mod detach;
+mod dial;
+#[cfg(feature = "docker")]
+mod docker;
+mod flow;
+mod forward;
+mod fuzzy;
+mod geoip;
+mod health;
+mod history;
+mod honeypot;
+pub mod hostkeys;
+pub mod init;
+mod inspect;
+pub mod inventory;
+mod jump;
+pub mod keymap;
+#[cfg(feature = "kubernetes")]
+mod kubernetes;
+mod ldap;
+mod limits;
+mod maintenance;
+pub mod messages;
+mod metrics;
+mod oidc;
+mod pane;
+pub mod password;
+mod plugins;
+mod pool;
+mod privileges;
+pub mod provider;
+mod proxy;
+mod quotas;
+mod realms;
+mod remote_forward;
+pub mod replay;
+pub mod sandbox;
+mod sessions;
+mod shadow;
+mod shutdown;
+mod srv;
+mod ssh;
+pub mod store;
+mod systemd;
+mod tcp;
+pub mod telemetry;
+mod term;
+mod totp;
+mod tui;
+mod upgrade;
+mod upstream;
+mod vt;
+
+pub use audit::{AuditEvent, AuditSink};
+pub use config::PukekoConfig;
+pub use inspect::{Chunk, Direction, Finding, Inspection, StreamInspector};
+pub use inventory::ServerSource;
+pub use provider::{
+    AuthDecision, AuthProvider, AuthorizedKeysProvider, ConfigProvider, Identity, ServerProvider,
+};
+pub use sessions::SessionEvent;
+pub use ssh::{PukekoServer, PukekoServerBuilder};
+pub use store::Store;
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::cluster::{self, Entries, SharedState};
+use crate::config::LimitsConfig;
+use crate::history;
+
+/// Tracks concurrent sessions and authentication failures per source address. Bans are
+/// kept in a JSON file in the state directory, so they outlast restarts, and shared with the
+/// other nodes of a cluster.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    path: PathBuf,
+    state: Mutex<LimiterState>,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    total: usize,
+    unauthenticated: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    failures: HashMap<IpAddr, VecDeque<Instant>>,
+    /// When each address started its recent OIDC sign-ins.
+    sign_ins: HashMap<IpAddr, VecDeque<Instant>>,
+    bans: HashMap<IpAddr, Ban>,
+    /// Sessions from each address on the other nodes of the cluster, counted towards
+    /// `max_sessions_per_ip`.
+    remote_per_ip: HashMap<IpAddr, usize>,
+}
+
+/// An address's latest ban, remembered for `max_ban_duration` after it ends so that
+/// banning it again takes longer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ban {
+    /// Unix time the ban ends.
+    pub until: i64,
+    /// Length of the ban in seconds.
+    pub duration: u64,
+    /// Times the address has been banned in a row.
+    pub count: u32,
+}
+
+/// Counts towards the session limits until dropped, and towards `max_unauthenticated`
+/// until [`authenticated`](Self::authenticated) is called.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+    authenticated: bool,
+}
+
+impl ConnectionLimiter {
+    /// Loads the bans from `path`, starting without any if it does not exist. A file that
+    /// cannot be read is logged and replaced on the next ban.
+    pub fn load(path: PathBuf) -> Self {
+        let bans = match history::read(&path) {
+            Ok(bans) => bans,
+            Err(e) => {
+                warn!("Ignoring saved bans: {:#}", e);
+                HashMap::new()
+            }
+        };
+        Self {
+            path,
+            state: Mutex::new(LimiterState {
+                bans,
+                ..LimiterState::default()
+            }),
+        }
+    }
+
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        ip: IpAddr,
+        limits: &LimitsConfig,
+    ) -> anyhow::Result<ConnectionPermit> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.is_banned(ip) {
+            bail!("{} is banned", ip);
+        }
+        if limits.max_sessions.is_some_and(|max| state.total >= max) {
+            bail!("too many sessions");
+        }
+        let from_ip = state.per_ip.get(&ip).copied().unwrap_or_default()
+            + state.remote_per_ip.get(&ip).copied().unwrap_or_default();
+        if limits.max_sessions_per_ip.is_some_and(|max| from_ip >= max) {
+            bail!("too many sessions from {}", ip);
+        }
+        if limits
+            .max_unauthenticated
+            .is_some_and(|max| state.unauthenticated >= max)
+        {
+            bail!("too many connections logging in");
+        }
+
+        state.total += 1;
+        state.unauthenticated += 1;
+        *state.per_ip.entry(ip).or_default() += 1;
+        Ok(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+            authenticated: false,
+        })
+    }
+
+    /// Counts an OIDC sign-in started from `ip`, unless it has started `max` within
+    /// `window` already, in which case it may not start another.
+    pub fn sign_in_started(&self, ip: IpAddr, max: usize, window: Duration) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.sign_ins.retain(|_, started| {
+            while started
+                .front()
+                .is_some_and(|at| now.duration_since(*at) >= window)
+            {
+                started.pop_front();
+            }
+            !started.is_empty()
+        });
+        let started = state.sign_ins.entry(ip).or_default();
+        if started.len() >= max {
+            return false;
+        }
+        started.push_back(now);
+        true
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        self.state.lock().unwrap().is_banned(ip)
+    }
+
+    /// Records a failed authentication attempt, banning `ip` once it has failed
+    /// `max_auth_failures` times within `auth_failure_window`. Each ban is twice as long as
+    /// the one before, up to `max_ban_duration`, unless the last ended longer ago than that.
+    /// Returns the ban if one was made.
+    pub fn auth_failed(&self, ip: IpAddr, limits: &LimitsConfig) -> Option<Ban> {
+        let max_failures = limits.max_auth_failures?;
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state
+            .failures
+            .retain(|_, failures| prune(failures, now, limits));
+
+        let failures = state.failures.entry(ip).or_default();
+        failures.push_back(now);
+        if failures.len() < max_failures {
+            return None;
+        }
+        state.failures.remove(&ip);
+
+        let now = chrono::Utc::now().timestamp();
+        let forget = limits.max_ban_duration.as_secs() as i64;
+        state.bans.retain(|_, ban| ban.until + forget > now);
+        let count = state.bans.get(&ip).map_or(0, |ban| ban.count) + 1;
+        let duration = limits
+            .ban_duration
+            .saturating_mul(1 << (count - 1).min(31))
+            .min(limits.max_ban_duration);
+        let ban = Ban {
+            until: now + duration.as_secs() as i64,
+            duration: duration.as_secs(),
+            count,
+        };
+        state.bans.insert(ip, ban);
+        warn!(
+            "Banning {} for {:?} after {} failed authentication attempts, ban {} in a row",
+            ip, duration, max_failures, count
+        );
+        self.save(&state);
+        Some(ban)
+    }
+
+    /// The addresses banned now, and until when.
+    pub fn bans(&self) -> Vec<(IpAddr, Ban)> {
+        let now = chrono::Utc::now().timestamp();
+        let state = self.state.lock().unwrap();
+        let mut bans: Vec<_> = state
+            .bans
+            .iter()
+            .filter(|(_, ban)| ban.until > now)
+            .map(|(ip, ban)| (*ip, *ban))
+            .collect();
+        bans.sort_by_key(|(ip, _)| *ip);
+        bans
+    }
+
+    /// Lifts the ban on `ip` and forgets it, so the next one is not any longer. Returns
+    /// whether it was banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let banned = state.is_banned(ip);
+        state.failures.remove(&ip);
+        if state.bans.remove(&ip).is_some() {
+            self.save(&state);
+        }
+        banned
+    }
+
+    /// Replaces the sessions from each address on the other nodes of the cluster.
+    pub fn set_remote(&self, per_ip: HashMap<IpAddr, usize>) {
+        self.state.lock().unwrap().remote_per_ip = per_ip;
+    }
+
+    fn save(&self, state: &LimiterState) {
+        if let Err(e) = history::write(&self.path, &state.bans) {
+            warn!("Failed to save bans: {:#}", e);
+        }
+    }
+}
+
+impl SharedState for ConnectionLimiter {
+    fn kind(&self) -> &'static str {
+        "ban"
+    }
+
+    fn entries(&self) -> Entries {
+        cluster::to_entries(&self.state.lock().unwrap().bans)
+    }
+
+    fn replace(&self, seen: &Entries, entries: Entries) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let current = cluster::to_entries(&state.bans);
+        if current != *seen {
+            return false;
+        }
+        if entries != current {
+            state.bans = cluster::from_entries(self.kind(), entries).collect();
+            self.save(&state);
+        }
+        true
+    }
+}
+
+impl LimiterState {
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        self.bans.get(&ip).is_some_and(|ban| ban.until > now)
+    }
+}
+
+/// Drops failures that fell out of the window, returning whether any are left.
+fn prune(failures: &mut VecDeque<Instant>, now: Instant, limits: &LimitsConfig) -> bool {
+    while failures
+        .front()
+        .is_some_and(|at| now.duration_since(*at) > limits.auth_failure_window)
+    {
+        failures.pop_front();
+    }
+    !failures.is_empty()
+}
+
+impl ConnectionPermit {
+    /// Stops counting the connection as logging in.
+    pub fn authenticated(&mut self) {
+        if !std::mem::replace(&mut self.authenticated, true) {
+            let mut state = self.limiter.state.lock().unwrap();
+            state.unauthenticated = state.unauthenticated.saturating_sub(1);
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.authenticated();
+        let mut state = self.limiter.state.lock().unwrap();
+        state.total = state.total.saturating_sub(1);
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
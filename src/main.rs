@@ -1,47 +1,778 @@
-mod config;
-mod ssh;
-mod tui;
+use std::io::{IsTerminal, Write};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
-use std::fs::File;
-use std::io::Read;
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use pukeko::PukekoServer;
+use pukeko::bench::{self, BenchOptions};
+use pukeko::config::{self, ConfigUpdater, PukekoConfig};
+use pukeko::init::InitOptions;
+use pukeko::replay::{self, ReplayOptions};
+use pukeko::telemetry::{Telemetry, TelemetryLayer};
+use pukeko::{audit, check, control, hostkeys, password, sandbox, store};
+use russh::keys::PublicKey;
+use serde_json::{Value, json};
+use tracing::error;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 
-use config::PukekoConfig;
-use russh::keys::{PrivateKey, PublicKey};
-use ssh::PukekoServer;
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// Path to the pukeko config file.
+    #[arg(short, long, default_value = "pukeko.toml")]
+    config: PathBuf,
 
-async fn start_server(config: PukekoConfig) -> anyhow::Result<()> {
-    let mut server = PukekoServer::new(config);
+    /// How the server writes its logs to stderr.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    /// One JSON object per line, with the fields of the session each event happened in.
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Manage a running server through its control socket.
+    Ctl {
+        /// Path of the control socket, instead of `control_socket` from the config.
+        #[arg(long)]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+    /// Check the config for errors and likely mistakes, exiting with an error if there are
+    /// any.
+    Check,
+    /// Write a starter config with a listener and an admin user, and generate the keys it
+    /// needs. Whatever is not passed is asked for.
+    Init {
+        /// Address to accept SSH connections on.
+        #[arg(long)]
+        listen: Option<SocketAddr>,
+        /// Name of the admin user.
+        #[arg(long)]
+        admin: Option<String>,
+        /// The admin's OpenSSH public key, or a file containing it.
+        #[arg(long)]
+        admin_key: Option<String>,
+        /// Replace an existing config.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Read a password from stdin and print its hash for a user's `password_hash`.
+    HashPassword,
+    /// Check audit logs for tampering.
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+    /// Replace the host keys generated in the state directory.
+    Keys {
+        #[command(subcommand)]
+        command: KeysCommand,
+    },
+    /// Play back an asciicast recording. Space pauses, + and - change the speed, the arrow
+    /// keys seek and q quits.
+    Replay {
+        recording: PathBuf,
+        /// Playback speed, where 2 is twice as fast.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Longest pause between output, in seconds.
+        #[arg(long)]
+        idle_limit: Option<f64>,
+    },
+    /// Load a running server with simulated clients, which connect, move around the menu
+    /// and optionally send traffic through to a server, then report latencies and
+    /// failures.
+    Bench {
+        /// Address of the server, as host:port. Its host key is not checked.
+        target: String,
+        /// User the clients log in as.
+        #[arg(long)]
+        user: String,
+        /// Private key the clients authenticate with.
+        #[arg(long)]
+        key: PathBuf,
+        /// Clients connected at once.
+        #[arg(long, default_value_t = 10)]
+        clients: usize,
+        /// How long to keep starting sessions, in seconds.
+        #[arg(long, default_value_t = 60)]
+        duration: u64,
+        /// Keys pressed in the menu in each session.
+        #[arg(long, default_value_t = 10)]
+        keystrokes: usize,
+        /// Server to also connect straight to in each session, sending traffic that
+        /// `--command` echoes back.
+        #[arg(long)]
+        server: Option<String>,
+        /// Command run on the server, which has to echo its input back.
+        #[arg(long, default_value = "cat")]
+        command: String,
+        /// Bytes sent through to the server in each session.
+        #[arg(long, default_value_t = 1024 * 1024)]
+        traffic: usize,
+        /// Longest wait for any step of a session, in seconds.
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AuditCommand {
+    /// Check that no line of a chained audit log was changed or removed, except at its end.
+    Verify {
+        file: PathBuf,
+        /// Public key of the audit signing key, to also check each line's signature.
+        #[arg(long)]
+        key: Option<PathBuf>,
+        /// The log this one was rotated from, whose last line the first line follows.
+        #[arg(long)]
+        follows: Option<PathBuf>,
+        /// Accept lines at the start written before chaining was enabled, which a key
+        /// cannot check.
+        #[arg(long)]
+        allow_unchained: bool,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum KeysCommand {
+    /// Generate and stage a replacement for each host key, printing the new public keys to
+    /// add to clients' known_hosts.
+    Rotate,
+    /// Replace the host keys with the staged ones, keeping the old ones with an `.old`
+    /// suffix. Clients are presented the new keys once pukeko restarts.
+    Promote,
+}
+
+#[derive(Debug, Subcommand)]
+enum CtlCommand {
+    /// List connected sessions, on every node of a cluster.
+    Sessions,
+    /// Disconnect a session.
+    Kill {
+        id: usize,
+        /// The node of the cluster the session is on, when another.
+        #[arg(long)]
+        node: Option<String>,
+    },
+    /// Show a message to every connected session.
+    Broadcast {
+        message: String,
+    },
+    /// Reload the config file.
+    Reload,
+    /// List servers.
+    Servers,
+    /// Add a server, to the database if one is configured and otherwise until the config
+    /// is next reloaded.
+    AddServer {
+        name: String,
+        host: String,
+        #[arg(long, default_value_t = 22)]
+        port: u16,
+        #[arg(long)]
+        user: Option<String>,
+        /// Folder to list the server under in the menu.
+        #[arg(long)]
+        group: Option<String>,
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+    },
+    /// Remove a server, from the database if it is stored there and otherwise until the
+    /// config is next reloaded.
+    RemoveServer {
+        name: String,
+    },
+    /// List the users in the database.
+    Users,
+    /// Add a user to the database.
+    AddUser {
+        name: String,
+        #[arg(long)]
+        admin: bool,
+    },
+    /// Remove a user and their keys and grants from the database.
+    RemoveUser {
+        name: String,
+    },
+    /// Add an OpenSSH public key to a stored user.
+    AddKey {
+        user: String,
+        key: String,
+        /// Date the key stops being accepted, such as 2025-06-30.
+        #[arg(long)]
+        expires: Option<String>,
+        /// What the key is for, such as a laptop.
+        #[arg(long)]
+        comment: Option<String>,
+    },
+    RemoveKey {
+        user: String,
+        key: String,
+    },
+    /// Grant a stored user or a group access to a server, `tag:<tag>` or `*`.
+    Grant {
+        server: String,
+        #[command(flatten)]
+        grantee: Grantee,
+    },
+    /// Revoke a grant made with `grant`.
+    Revoke {
+        server: String,
+        #[command(flatten)]
+        grantee: Grantee,
+    },
+    /// Add a stored user to a group.
+    AddMember {
+        user: String,
+        group: String,
+    },
+    RemoveMember {
+        user: String,
+        group: String,
+    },
+    /// Show the sessions that have ended, most recent first.
+    History {
+        #[arg(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// List the addresses banned for failing authentication.
+    Bans,
+    /// Lift the ban on an address.
+    Unban {
+        address: IpAddr,
+    },
+    /// List requests for access waiting for an admin, and the access already given.
+    Requests,
+    /// Give a user the access they requested.
+    Approve {
+        id: u64,
+        /// How long the access lasts, in seconds. Defaults to the configured duration.
+        #[arg(long)]
+        duration: Option<u64>,
+    },
+    /// Turn down a request for access.
+    Deny {
+        id: u64,
+    },
+    /// List keys of known users that are waiting for approval.
+    PendingKeys,
+    /// Let a user log in with a key that was waiting for approval.
+    ApproveKey {
+        id: u64,
+    },
+    /// Turn down a key that was waiting for approval.
+    RejectKey {
+        id: u64,
+    },
+    /// List keys approved with approve-key that are not kept in a database.
+    ApprovedKeys,
+    /// Stop a user logging in with a key approved with approve-key.
+    RevokeKey {
+        user: String,
+        /// The OpenSSH public key, or its SHA256 fingerprint.
+        key: String,
+    },
+    /// List the servers under maintenance.
+    Maintenance,
+    /// Put a server, or every server, under maintenance: the menu marks it and new
+    /// connections to it are refused.
+    StartMaintenance {
+        /// Every server when not given.
+        server: Option<String>,
+        /// Date or RFC 3339 time the maintenance ends, such as 2025-06-30T18:00:00Z.
+        /// Until `end-maintenance` when not given.
+        #[arg(long)]
+        until: Option<String>,
+        /// Shown to users in the menu.
+        #[arg(long)]
+        reason: Option<String>,
+        /// Let admins connect anyway.
+        #[arg(long)]
+        allow_admins: bool,
+        /// Leave the server out of the menu instead of marking it.
+        #[arg(long)]
+        hide: bool,
+    },
+    /// End the maintenance of a server, or that of every server.
+    EndMaintenance {
+        server: Option<String>,
+    },
+    /// List the announcements shown in the menu, or to be.
+    Announcements,
+    /// Show a message in the menu, above the server list or next to a server.
+    Announce {
+        message: String,
+        /// The server to show it next to.
+        #[arg(long)]
+        server: Option<String>,
+        /// Date or RFC 3339 time to show it from. Right away when not given.
+        #[arg(long)]
+        from: Option<String>,
+        /// Date or RFC 3339 time to stop showing it, such as 2025-06-30T18:00:00Z. Until
+        /// `remove-announcement` when not given.
+        #[arg(long)]
+        until: Option<String>,
+    },
+    /// Stop showing an announcement posted with `announce`.
+    RemoveAnnouncement {
+        id: u64,
+    },
+    /// Show the current metrics.
+    Metrics,
+}
+
+#[derive(Debug, clap::Args)]
+#[group(required = true, multiple = false)]
+struct Grantee {
+    #[arg(long)]
+    user: Option<String>,
+    #[arg(long)]
+    group: Option<String>,
+}
+
+impl CtlCommand {
+    fn request(self) -> (&'static str, Value) {
+        match self {
+            CtlCommand::Sessions => ("sessions.list", Value::Null),
+            CtlCommand::Kill { id, node } => ("sessions.kill", json!({ "id": id, "node": node })),
+            CtlCommand::Broadcast { message } => {
+                ("sessions.broadcast", json!({ "message": message }))
+            }
+            CtlCommand::Reload => ("config.reload", Value::Null),
+            CtlCommand::Servers => ("servers.list", Value::Null),
+            CtlCommand::AddServer {
+                name,
+                host,
+                port,
+                user,
+                group,
+                tags,
+            } => (
+                "servers.add",
+                json!({
+                    "name": name,
+                    "host": host,
+                    "port": port,
+                    "user": user,
+                    "group": group,
+                    "tags": tags,
+                }),
+            ),
+            CtlCommand::RemoveServer { name } => ("servers.remove", json!({ "name": name })),
+            CtlCommand::Users => ("users.list", Value::Null),
+            CtlCommand::AddUser { name, admin } => {
+                ("users.add", json!({ "name": name, "admin": admin }))
+            }
+            CtlCommand::RemoveUser { name } => ("users.remove", json!({ "name": name })),
+            CtlCommand::AddKey {
+                user,
+                key,
+                expires,
+                comment,
+            } => (
+                "keys.add",
+                json!({ "user": user, "key": key, "expires": expires, "comment": comment }),
+            ),
+            CtlCommand::RemoveKey { user, key } => {
+                ("keys.remove", json!({ "user": user, "key": key }))
+            }
+            CtlCommand::Grant { server, grantee } => (
+                "grants.add",
+                json!({ "user": grantee.user, "group": grantee.group, "server": server }),
+            ),
+            CtlCommand::Revoke { server, grantee } => (
+                "grants.remove",
+                json!({ "user": grantee.user, "group": grantee.group, "server": server }),
+            ),
+            CtlCommand::AddMember { user, group } => {
+                ("members.add", json!({ "user": user, "group": group }))
+            }
+            CtlCommand::RemoveMember { user, group } => {
+                ("members.remove", json!({ "user": user, "group": group }))
+            }
+            CtlCommand::History { limit } => ("history.list", json!({ "limit": limit })),
+            CtlCommand::Bans => ("bans.list", Value::Null),
+            CtlCommand::Unban { address } => ("bans.remove", json!({ "address": address })),
+            CtlCommand::Requests => ("access.list", Value::Null),
+            CtlCommand::Approve { id, duration } => (
+                "access.approve",
+                json!({ "id": id, "duration_secs": duration }),
+            ),
+            CtlCommand::Deny { id } => ("access.deny", json!({ "id": id })),
+            CtlCommand::PendingKeys => ("keys.pending", Value::Null),
+            CtlCommand::ApproveKey { id } => ("keys.approve", json!({ "id": id })),
+            CtlCommand::RejectKey { id } => ("keys.reject", json!({ "id": id })),
+            CtlCommand::ApprovedKeys => ("keys.approved", Value::Null),
+            CtlCommand::RevokeKey { user, key } => {
+                ("keys.revoke", json!({ "user": user, "key": key }))
+            }
+            CtlCommand::Maintenance => ("maintenance.list", Value::Null),
+            CtlCommand::StartMaintenance {
+                server,
+                until,
+                reason,
+                allow_admins,
+                hide,
+            } => (
+                "maintenance.start",
+                json!({
+                    "server": server,
+                    "until": until,
+                    "reason": reason,
+                    "allow_admins": allow_admins,
+                    "hide": hide,
+                }),
+            ),
+            CtlCommand::EndMaintenance { server } => {
+                ("maintenance.end", json!({ "server": server }))
+            }
+            CtlCommand::Announcements => ("announcements.list", Value::Null),
+            CtlCommand::Announce {
+                message,
+                server,
+                from,
+                until,
+            } => (
+                "announcements.post",
+                json!({
+                    "message": message,
+                    "server": server,
+                    "from": from,
+                    "until": until,
+                }),
+            ),
+            CtlCommand::RemoveAnnouncement { id } => ("announcements.remove", json!({ "id": id })),
+            CtlCommand::Metrics => ("metrics", Value::Null),
+        }
+    }
+}
+
+async fn ctl(config: &Path, socket: Option<PathBuf>, command: CtlCommand) -> anyhow::Result<()> {
+    let socket = match socket {
+        Some(socket) => socket,
+        None => PukekoConfig::load(config)?
+            .control_socket
+            .context("No control_socket is configured, pass --socket")?,
+    };
+
+    let (method, params) = command.request();
+    let result = control::call(&socket, method, params).await?;
+    if !result.is_null() {
+        println!("{}", serde_json::to_string_pretty(&result)?);
+    }
+    Ok(())
+}
+
+fn init(
+    config: &Path,
+    listen: Option<SocketAddr>,
+    admin: Option<String>,
+    admin_key: Option<String>,
+    force: bool,
+) -> anyhow::Result<()> {
+    let interactive = std::io::stdin().is_terminal();
+    let listen = match listen {
+        Some(listen) => listen,
+        None if interactive => prompt("Address to listen on", Some("0.0.0.0:2222"))?
+            .parse()
+            .context("Invalid address")?,
+        None => SocketAddr::from(([0, 0, 0, 0], 2222)),
+    };
+    let admin = match admin {
+        Some(admin) => admin,
+        None if interactive => prompt("Admin user name", Some("admin"))?,
+        None => "admin".to_string(),
+    };
+    let admin_key = match admin_key {
+        Some(key) => key,
+        None if interactive => {
+            let default = std::env::home_dir()
+                .map(|home| home.join(".ssh").join("id_ed25519.pub"))
+                .filter(|path| path.exists());
+            prompt(
+                "Admin public key, or a file containing it",
+                default.as_deref().and_then(Path::to_str),
+            )?
+        }
+        None => anyhow::bail!("Pass --admin-key when not run from a terminal"),
+    };
+    let admin_key = match PublicKey::from_openssh(&admin_key) {
+        Ok(key) => key,
+        Err(_) => PublicKey::read_openssh_file(Path::new(&admin_key))
+            .with_context(|| format!("{admin_key} is not a public key or a file with one"))?,
+    };
+
+    let options = InitOptions {
+        listen,
+        admin,
+        admin_key,
+        force,
+    };
+    let loaded = pukeko::init::write_config(config, &options)?;
+    println!("Wrote {}", config.display());
+    println!("Host keys, for clients' known_hosts:");
+    for key in &loaded.host_keys {
+        println!("  {}", key.public_key().to_openssh()?);
+    }
+    if let Some(key) = &loaded.upstream_key {
+        println!("Add this key to the authorized_keys of servers users connect to:");
+        println!("  {}", key.public_key().to_openssh()?);
+    }
+    println!(
+        "Add servers to the config, then start pukeko with `pukeko -c {}`",
+        config.display()
+    );
+    Ok(())
+}
+
+/// Asks a question on the terminal, returning the default for an empty answer.
+fn prompt(question: &str, default: Option<&str>) -> anyhow::Result<String> {
+    loop {
+        match default {
+            Some(default) => print!("{question} [{default}]: "),
+            None => print!("{question}: "),
+        }
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            anyhow::bail!("No answer was given");
+        }
+        match (line.trim(), default) {
+            ("", Some(default)) => return Ok(default.to_string()),
+            ("", None) => continue,
+            (answer, _) => return Ok(answer.to_string()),
+        }
+    }
+}
+
+fn check(config: &Path) -> anyhow::Result<()> {
+    let problems = check::check(config)?;
+    for problem in &problems {
+        println!("{}: {problem}", config.display());
+    }
+    match problems.len() {
+        0 => {
+            println!("{} is valid", config.display());
+            Ok(())
+        }
+        1 => anyhow::bail!("Found 1 problem"),
+        count => anyhow::bail!("Found {count} problems"),
+    }
+}
+
+fn hash_password() -> anyhow::Result<()> {
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .context("Failed to read the password")?;
+    let password = line.trim_end_matches(['\r', '\n']);
+    if password.is_empty() {
+        anyhow::bail!("No password was given on stdin");
+    }
+    println!("{}", password::hash(password)?);
+    Ok(())
+}
+
+fn verify_audit_log(
+    file: &Path,
+    key: Option<&Path>,
+    follows: Option<&Path>,
+    allow_unchained: bool,
+) -> anyhow::Result<()> {
+    let key = key
+        .map(|path| {
+            PublicKey::read_openssh_file(path)
+                .with_context(|| format!("Failed to load public key {}", path.display()))
+        })
+        .transpose()?;
+    let verified = audit::verify(file, key.as_ref(), follows, allow_unchained)?;
+    if verified.unchained > 0 {
+        println!(
+            "The first {} lines were written before chaining was enabled",
+            verified.unchained
+        );
+    }
+    match key {
+        Some(_) => println!("Verified {} chained and signed lines", verified.lines),
+        None => println!("Verified {} chained lines", verified.lines),
+    }
+    Ok(())
+}
+
+fn keys(config: &Path, command: KeysCommand) -> anyhow::Result<()> {
+    let config = PukekoConfig::load(config)?;
+    let keys = match command {
+        KeysCommand::Rotate => hostkeys::rotate(&config)?,
+        KeysCommand::Promote => hostkeys::promote(&config)?,
+    };
+    for key in keys {
+        println!("{}", key.to_openssh()?);
+    }
+    Ok(())
+}
+
+async fn bench(options: BenchOptions) -> anyhow::Result<()> {
+    let report = bench::run(options).await?;
+    print!("{report}");
+    if report.failed() > 0 {
+        anyhow::bail!("{} sessions failed", report.failed());
+    }
+    Ok(())
+}
+
+async fn start_server(
+    config: config::ConfigReceiver,
+    updater: ConfigUpdater,
+) -> anyhow::Result<()> {
+    let mut server = PukekoServer::builder(config)
+        .config_updater(updater)
+        .build();
     server.run().await.expect("Failed running server");
 
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let subscriber = tracing_subscriber::FmtSubscriber::builder()
-        .with_env_filter(
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Some(Command::Ctl { socket, command }) => {
+            return tokio::runtime::Runtime::new()?.block_on(ctl(&args.config, socket, command));
+        }
+        Some(Command::Check) => return check(&args.config),
+        Some(Command::Init {
+            listen,
+            admin,
+            admin_key,
+            force,
+        }) => return init(&args.config, listen, admin, admin_key, force),
+        Some(Command::HashPassword) => return hash_password(),
+        Some(Command::Audit {
+            command:
+                AuditCommand::Verify {
+                    file,
+                    key,
+                    follows,
+                    allow_unchained,
+                },
+        }) => {
+            return verify_audit_log(&file, key.as_deref(), follows.as_deref(), allow_unchained);
+        }
+        Some(Command::Keys { command }) => return keys(&args.config, command),
+        Some(Command::Replay {
+            recording,
+            speed,
+            idle_limit,
+        }) => {
+            return replay::play(&recording, &ReplayOptions { speed, idle_limit });
+        }
+        Some(Command::Bench {
+            target,
+            user,
+            key,
+            clients,
+            duration,
+            keystrokes,
+            server,
+            command,
+            traffic,
+            timeout,
+        }) => {
+            let options = BenchOptions {
+                target,
+                user,
+                key,
+                clients,
+                duration: Duration::from_secs(duration),
+                keystrokes,
+                server,
+                command,
+                traffic,
+                timeout: Duration::from_secs(timeout),
+                ..Default::default()
+            };
+            return tokio::runtime::Runtime::new()?.block_on(bench(options));
+        }
+        None => {}
+    }
+
+    // Telemetry is configured in the config file, which is read after logging starts.
+    let (telemetry_layer, telemetry_reload) =
+        tracing_subscriber::reload::Layer::new(None::<TelemetryLayer>);
+    let format = args.log_format;
+    tracing_subscriber::registry()
+        .with(telemetry_layer)
+        .with((format == LogFormat::Text).then(tracing_subscriber::fmt::layer))
+        .with((format == LogFormat::Json).then(|| tracing_subscriber::fmt::layer().json()))
+        .with(
             tracing_subscriber::EnvFilter::builder()
                 .with_default_directive(tracing::Level::TRACE.into())
                 .from_env_lossy(),
         )
-        .finish();
+        .try_init()
+        .expect("setting default subscriber failed");
 
-    tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
-
-    let server_key = {
-        let mut buffer = Vec::new();
-        let mut bytes = File::open("./test_data/keys/server_key")?;
-        bytes.read_to_end(&mut buffer)?;
+    let mut config = PukekoConfig::load(&args.config)?;
+    let store = store::open(&config)?;
+    if let Some(store) = &store {
+        store::merge(&mut config, store.as_ref())?;
+    }
+    // The runtime's threads only inherit the restriction if it is made before they start.
+    sandbox::restrict_files(&config.sandbox, &config.state_directory)?;
+    tokio::runtime::Runtime::new()?.block_on(serve(args.config, config, store, telemetry_reload))
+}
 
-        PrivateKey::from_openssh(buffer)?
+async fn serve(
+    path: PathBuf,
+    config: PukekoConfig,
+    store: Option<Arc<dyn store::Store>>,
+    telemetry_reload: tracing_subscriber::reload::Handle<
+        Option<TelemetryLayer>,
+        tracing_subscriber::Registry,
+    >,
+) -> anyhow::Result<()> {
+    let telemetry = match &config.telemetry {
+        Some(telemetry_config) => {
+            let (telemetry, layer) = Telemetry::start(telemetry_config)?;
+            telemetry_reload.reload(Some(layer))?;
+            Some(telemetry)
+        }
+        None => None,
     };
+    let (config_sender, config_receiver) = tokio::sync::watch::channel(Arc::new(config));
+    let mut updater = ConfigUpdater::new(path, config_sender);
+    if let Some(store) = store {
+        updater = updater.with_store(store);
+    }
 
-    let config = PukekoConfig {
-        server_key,
-        user_key: PublicKey::from_openssh(
-            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIAcvtaYueykiTr1naUH2LrQcQ/R2/U8iPDQpEwTmDCpM",
-        )?,
-    };
+    {
+        let updater = updater.clone();
+        tokio::spawn(async move {
+            if let Err(e) = config::reload_on_sighup(updater).await {
+                error!("Config reloading disabled: {:?}", e);
+            }
+        });
+    }
 
-    start_server(config).await
+    let result = start_server(config_receiver, updater).await;
+    if let Some(telemetry) = telemetry {
+        telemetry.shutdown();
+    }
+    result
 }
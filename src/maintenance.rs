@@ -0,0 +1,130 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::history;
+
+/// Servers under maintenance, which the menu marks or hides and new forwards are refused
+/// to, until an admin ends it or its time runs out. Kept in a JSON file in the state
+/// directory, so it outlasts restarts.
+#[derive(Debug)]
+pub struct Maintenance {
+    path: PathBuf,
+    windows: Mutex<Vec<MaintenanceWindow>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MaintenanceWindow {
+    /// The server under maintenance, or `None` for every server.
+    pub server: Option<String>,
+    /// Unix time the maintenance ends, or `None` until an admin ends it.
+    pub until: Option<i64>,
+    pub reason: Option<String>,
+    /// Whether admins may still connect.
+    pub admins: bool,
+    /// Whether the menu leaves the servers out rather than marking them.
+    pub hidden: bool,
+}
+
+impl MaintenanceWindow {
+    fn active(&self, now: i64) -> bool {
+        self.until.is_none_or(|until| until > now)
+    }
+
+    /// When the maintenance ends, for people to read.
+    pub fn ends(&self) -> Option<String> {
+        self.until.map(|until| {
+            chrono::DateTime::from_timestamp(until, 0)
+                .unwrap_or_default()
+                .format("%Y-%m-%d %H:%M UTC")
+                .to_string()
+        })
+    }
+}
+
+impl Maintenance {
+    /// Loads the maintenance from `path`, starting without any if it does not exist. A
+    /// file that cannot be read is logged and replaced on the next change.
+    pub fn load(path: PathBuf) -> Self {
+        let windows = match history::read(&path) {
+            Ok(windows) => windows,
+            Err(e) => {
+                warn!("Ignoring saved maintenance: {:#}", e);
+                Vec::new()
+            }
+        };
+        Self {
+            path,
+            windows: Mutex::new(windows),
+        }
+    }
+
+    /// Puts the window's server, or every server, under maintenance, replacing what was
+    /// set for it before.
+    pub fn start(&self, window: MaintenanceWindow) -> anyhow::Result<()> {
+        let now = chrono::Utc::now().timestamp();
+        if !window.active(now) {
+            bail!("Maintenance must end in the future");
+        }
+        let mut windows = self.windows.lock().unwrap();
+        windows.retain(|other| other.active(now) && other.server != window.server);
+        windows.push(window);
+        self.save(&windows);
+        Ok(())
+    }
+
+    /// Ends the maintenance of `server`, or that of every server for `None`, leaving the
+    /// other. Returns whether there was any.
+    pub fn end(&self, server: Option<&str>) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        let mut windows = self.windows.lock().unwrap();
+        let ended = windows
+            .iter()
+            .any(|window| window.server.as_deref() == server && window.active(now));
+        windows.retain(|window| window.active(now) && window.server.as_deref() != server);
+        self.save(&windows);
+        ended
+    }
+
+    /// Maintenance that has not ended yet, every server's first.
+    pub fn windows(&self) -> Vec<MaintenanceWindow> {
+        let now = chrono::Utc::now().timestamp();
+        let mut windows: Vec<_> = self
+            .windows
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|window| window.active(now))
+            .cloned()
+            .collect();
+        windows.sort_by(|a, b| a.server.cmp(&b.server));
+        windows
+    }
+
+    /// The maintenance `server` is under, its own before that of every server.
+    pub fn get(&self, server: &str) -> Option<MaintenanceWindow> {
+        let now = chrono::Utc::now().timestamp();
+        let windows = self.windows.lock().unwrap();
+        let active = |name: Option<&str>| {
+            windows
+                .iter()
+                .find(|window| window.server.as_deref() == name && window.active(now))
+        };
+        active(Some(server)).or_else(|| active(None)).cloned()
+    }
+
+    /// The maintenance that keeps users from `server`, which lets admins through when it
+    /// allows them.
+    pub fn blocks(&self, server: &str, admin: bool) -> Option<MaintenanceWindow> {
+        self.get(server).filter(|window| !(admin && window.admins))
+    }
+
+    fn save(&self, windows: &[MaintenanceWindow]) {
+        if let Err(e) = history::write(&self.path, &windows) {
+            warn!("Failed to save maintenance: {:#}", e);
+        }
+    }
+}
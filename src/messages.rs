@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use crate::history::SortMode;
+use crate::keymap::{KeyContext, MenuAction};
+use crate::tui::KeySource;
+use crate::upstream::FailureReason;
+
+/// Catalogues by locale name, such as `ja` or `pt_BR`.
+pub type Locales = HashMap<String, Arc<Messages>>;
+
+/// Declares [`Messages`] with a field per message and the English text it defaults to.
+macro_rules! messages {
+    ($($name:ident = $text:literal,)*) => {
+        /// Text of the menu and the notices and prompts shown in it. Placeholders in braces,
+        /// such as `{server}`, are filled in with [`fill`].
+        #[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+        #[serde(default, deny_unknown_fields)]
+        pub struct Messages {
+            $(pub $name: String,)*
+        }
+
+        impl Default for Messages {
+            fn default() -> Self {
+                Self {
+                    $($name: $text.to_string(),)*
+                }
+            }
+        }
+    };
+}
+
+messages! {
+    // Shown in place of the config's banner when set.
+    banner = "",
+
+    hints = "Press {keys}",
+    hint_quit = "to quit",
+    hint_help = "for help",
+    hint_filter = "to filter",
+    hint_tags = "for tags",
+    hint_fold = "to fold groups",
+    hint_request = "to request access",
+    hint_keys = "for your keys",
+    hint_sessions = "for sessions",
+    hint_servers = "for servers",
+    hint_watch = "to watch",
+    hint_terminate = "to terminate",
+    hint_message = "to message everyone",
+    sort_listed = "as listed",
+    sort_name = "by name",
+    sort_recent = "most recent",
+    sort_used = "most used",
+    down = "down",
+    maintenance = "maintenance",
+    yes_no = "[y/N]",
+    splash_title = "Press any key to continue",
+
+    no_tags = "No servers are tagged",
+    all_servers = "All servers",
+    tag_title = "Tag",
+    request_title = "Request access",
+    request_reason = "Why do you need {server}?",
+    nothing_to_request = "There are no other servers to request",
+    requests_disabled = "Access requests are not enabled",
+    already_requested = "You already requested {server} as request {id}",
+    requested = "Requested access to {server} as request {id}, an admin will review it",
+
+    keys_title = "Your keys",
+    add_key = "Add a key",
+    paste_key = "Paste the public key:",
+    remove_key = "Remove key {fingerprint}?",
+    key_config = "config",
+    key_stored = "stored",
+    key_approved = "approved",
+    key_pending = "awaiting approval",
+    key_added = "Added key {fingerprint}",
+    key_awaiting_approval = "Key {fingerprint} is awaiting approval by an admin",
+    key_removed = "Removed key {fingerprint}",
+    key_exists = "You already have key {fingerprint}",
+    invalid_key = "That is not an OpenSSH public key",
+    config_key = "Keys in the config can only be changed by an admin",
+    min_keys = "You must keep at least {count} of your keys",
+    keys_failed = "Failed to change your keys",
+
+    preferences_title = "Preferences",
+    preference_theme = "Theme: {theme}",
+    preference_keys = "Keys: {preset}",
+    default_theme = "default",
+
+    confirm_server = "You are connecting to {server}. Type its name to continue:",
+    wrong_server = "That is not {server}, not connecting",
+    under_maintenance = "{server} is under maintenance",
+    plain_prompt = "Enter a number or name to connect, or q to quit: ",
+    plain_unknown = "No server {server}",
+    under_maintenance_until = "{server} is under maintenance until {until}",
+    quota_forwards = "You already have as many connections open as allowed ({max})",
+    quota_forwards_to_server = "You already have as many connections to {server} open as allowed ({max})",
+    quota_daily_minutes = "You have used your {minutes} minutes of connections for today",
+    connecting_title = "Connecting",
+    connecting = "Connecting to {server}",
+    trust_host_key = "Unknown host key {fingerprint} for {server}. Trust it and connect?",
+    timed_out = "Connection timed out",
+    refused = "Connection refused",
+    host_key_not_trusted = "Host key not trusted",
+    authentication_failed = "Authentication failed",
+    connection_failed = "Connection failed",
+    retry = "Press 'r' or Enter to retry, any other key to go back",
+    panes_need_terminal = "Panes need a terminal",
+    panes_open = "Panes open: {servers}",
+    return_to_panes = "{panes}. Quit to return to them",
+    suspended = "Suspended: {servers}. Select one to return to it, or quit to return to {server}",
+    suspended_prompt = "[pukeko] {server} is suspended: r to return, d to disconnect",
+    suspended_menu = ", m for the menu",
+
+    message_title = "Message from {from}",
+    key_expires = "Your key expires on {date}. Ask an admin to add a new one",
+    resume = "Resume your session on {server}, disconnected {age} ago?",
+    resume_ended = "The session has already ended",
+    idle_warning = "Idle, disconnecting in {seconds}s",
+    idle_disconnected = "Disconnected after being idle",
+    shutting_down = "Server shutting down",
+
+    session_count = "{count} sessions",
+    message_everyone = "Message to everyone: {text}",
+    column_id = "ID",
+    column_user = "User",
+    column_from = "From",
+    column_target = "Target",
+    column_duration = "Duration",
+    column_up = "Up",
+    column_down = "Down",
+    column_rate = "Rate",
+    column_forwards = "Open",
+    column_today = "Today",
+    at_menu = "menu",
+    unauthenticated = "unauthenticated user",
+    terminate_session = "Terminate session {id} of {user}?",
+    terminated = "Terminated session {id}",
+    already_ended = "Session {id} has already ended",
+    not_forwarding = "Session {id} is not forwarding",
+    watch_remote = "Session {id} is on another node, watch it from there",
+    watching = "[pukeko] Watching session {id}, press q to stop",
+    admins_terminate = "Only admins can terminate sessions",
+    admins_message = "Only admins can message everyone",
+    admins_watch = "Only admins can watch sessions",
+
+    help_title = "Press any key to close",
+    help_navigation = "Navigation",
+    help_servers = "Servers",
+    help_menu = "Menu",
+    help_sessions = "Sessions (admins)",
+    help_escape = "Close, or clear the filter",
+    action_up = "Move up",
+    action_down = "Move down",
+    action_page_up = "Move up a page",
+    action_page_down = "Move down a page",
+    action_first = "Go to the first row",
+    action_last = "Go to the last row",
+    action_collapse = "Fold the group",
+    action_expand = "Unfold the group",
+    action_select = "Connect, or fold and unfold a group",
+    action_pane = "Connect in a new pane, next to those open",
+    action_filter = "Filter servers by name, group or tag",
+    action_tags = "List servers with a tag",
+    action_sort = "Sort by name, most recent or most used",
+    action_favorite = "Pin the server to the top, or unpin it",
+    action_request = "Ask an admin for access to another server",
+    action_keys = "List, add and remove your keys",
+    action_preferences = "Pick the theme and keys, kept for next time",
+    action_help = "Show this help",
+    action_quit = "Quit",
+    action_sessions = "Switch between servers and sessions",
+    action_terminate = "Terminate the session",
+    action_message = "Message every session",
+    action_shadow = "Watch the session's output",
+}
+
+impl Messages {
+    /// Reads the `[messages]` table of the config and a catalogue for each locale in
+    /// `directory`, named like `ja.toml` or `pt_BR.toml`. Each replaces only the messages
+    /// it sets, locales falling back on the config's.
+    pub fn load(
+        table: toml::Table,
+        directory: Option<&Path>,
+    ) -> anyhow::Result<(Arc<Messages>, Locales)> {
+        let messages: Messages = table
+            .clone()
+            .try_into()
+            .context("Invalid [messages] in config")?;
+        let mut locales = HashMap::new();
+        let Some(directory) = directory else {
+            return Ok((Arc::new(messages), locales));
+        };
+
+        let entries = std::fs::read_dir(directory)
+            .with_context(|| format!("Failed to read messages {}", directory.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            let Some(locale) = path.file_stem().and_then(|stem| stem.to_str()).filter(|_| {
+                path.extension()
+                    .is_some_and(|extension| extension == "toml")
+            }) else {
+                continue;
+            };
+            let text = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read messages {}", path.display()))?;
+            let mut merged = table.clone();
+            merged.extend(
+                toml::from_str::<toml::Table>(&text)
+                    .with_context(|| format!("Failed to parse messages {}", path.display()))?,
+            );
+            let catalogue: Messages = merged
+                .try_into()
+                .with_context(|| format!("Invalid messages in {}", path.display()))?;
+            locales.insert(locale.to_string(), Arc::new(catalogue));
+        }
+        Ok((Arc::new(messages), locales))
+    }
+
+    pub(crate) fn sort(&self, sort: SortMode) -> &str {
+        match sort {
+            SortMode::Listed => &self.sort_listed,
+            SortMode::Name => &self.sort_name,
+            SortMode::Recent => &self.sort_recent,
+            SortMode::Used => &self.sort_used,
+        }
+    }
+
+    pub(crate) fn failure(&self, reason: FailureReason) -> &str {
+        match reason {
+            FailureReason::Timeout => &self.timed_out,
+            FailureReason::Refused => &self.refused,
+            FailureReason::HostKey => &self.host_key_not_trusted,
+            FailureReason::Authentication => &self.authentication_failed,
+            FailureReason::Other => &self.connection_failed,
+        }
+    }
+
+    pub(crate) fn key_source(&self, source: KeySource) -> &str {
+        match source {
+            KeySource::Config => &self.key_config,
+            KeySource::Stored => &self.key_stored,
+            KeySource::Approved => &self.key_approved,
+            KeySource::Pending => &self.key_pending,
+        }
+    }
+
+    pub fn context(&self, context: KeyContext) -> &str {
+        match context {
+            KeyContext::Navigation => &self.help_navigation,
+            KeyContext::Servers => &self.help_servers,
+            KeyContext::Menu => &self.help_menu,
+            KeyContext::Sessions => &self.help_sessions,
+        }
+    }
+
+    pub fn action(&self, action: MenuAction) -> &str {
+        match action {
+            MenuAction::Up => &self.action_up,
+            MenuAction::Down => &self.action_down,
+            MenuAction::PageUp => &self.action_page_up,
+            MenuAction::PageDown => &self.action_page_down,
+            MenuAction::First => &self.action_first,
+            MenuAction::Last => &self.action_last,
+            MenuAction::Collapse => &self.action_collapse,
+            MenuAction::Expand => &self.action_expand,
+            MenuAction::Select => &self.action_select,
+            MenuAction::Pane => &self.action_pane,
+            MenuAction::Filter => &self.action_filter,
+            MenuAction::Tags => &self.action_tags,
+            MenuAction::Sort => &self.action_sort,
+            MenuAction::Favorite => &self.action_favorite,
+            MenuAction::Request => &self.action_request,
+            MenuAction::Keys => &self.action_keys,
+            MenuAction::Preferences => &self.action_preferences,
+            MenuAction::Help => &self.action_help,
+            MenuAction::Quit => &self.action_quit,
+            MenuAction::Sessions => &self.action_sessions,
+            MenuAction::Terminate => &self.action_terminate,
+            MenuAction::Message => &self.action_message,
+            MenuAction::Shadow => &self.action_shadow,
+        }
+    }
+}
+
+/// The locale a client asked for with `LC_ALL`, `LC_MESSAGES` or `LANG`, in that order, as
+/// the names of catalogues to look for: `ja_JP.UTF-8` gives `ja_JP` then `ja`.
+pub fn locale_names(env: &[(String, String)]) -> Vec<&str> {
+    let Some(locale) = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .find_map(|variable| {
+            env.iter()
+                .find(|(name, value)| name == variable && !value.is_empty())
+                .map(|(_, value)| value.as_str())
+        })
+    else {
+        return Vec::new();
+    };
+    let locale = locale.split(['.', '@']).next().unwrap_or_default();
+    let mut names = vec![locale];
+    if let Some((language, _)) = locale.split_once('_') {
+        names.push(language);
+    }
+    names
+}
+
+/// Fills in `{name}` placeholders in `template` with `values`, leaving others as written.
+pub fn fill(template: &str, values: &[(&str, &dyn std::fmt::Display)]) -> String {
+    let mut text = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        text.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder.find('}').and_then(|end| {
+            let name = &placeholder[1..end];
+            values
+                .iter()
+                .find(|(value_name, _)| *value_name == name)
+                .map(|(_, value)| (end, value))
+        });
+        match value {
+            Some((end, value)) => {
+                text.push_str(&value.to_string());
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                text.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    text.push_str(rest);
+    text
+}
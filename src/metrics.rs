@@ -0,0 +1,228 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::inspect::Direction;
+
+/// The current value of every metric, as returned by the control socket.
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub active_sessions: u64,
+    pub total_connections: u64,
+    pub auth_failures: u64,
+    /// Bytes forwarded per upstream in both directions.
+    pub bytes_forwarded: BTreeMap<String, u64>,
+    /// Bytes sent to each upstream by clients.
+    pub bytes_to_upstream: BTreeMap<String, u64>,
+    /// Bytes sent to clients by each upstream.
+    pub bytes_from_upstream: BTreeMap<String, u64>,
+    pub menu_selections: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    active_sessions: AtomicU64,
+    total_connections: AtomicU64,
+    auth_failures: AtomicU64,
+    bytes_to_upstream: Mutex<BTreeMap<String, u64>>,
+    bytes_from_upstream: Mutex<BTreeMap<String, u64>>,
+    menu_selections: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.total_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_started(&self) {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn session_ended(&self) {
+        self.active_sessions.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self) {
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_forwarded(&self, upstream: &str, direction: Direction, bytes: u64) {
+        let counters = match direction {
+            Direction::Input => &self.bytes_to_upstream,
+            Direction::Output => &self.bytes_from_upstream,
+        };
+        *counters
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_default() += bytes;
+    }
+
+    pub fn menu_selected(&self, item: &str) {
+        *self
+            .menu_selections
+            .lock()
+            .unwrap()
+            .entry(item.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let bytes_to_upstream = self.bytes_to_upstream.lock().unwrap().clone();
+        let bytes_from_upstream = self.bytes_from_upstream.lock().unwrap().clone();
+        let mut bytes_forwarded = bytes_to_upstream.clone();
+        for (upstream, bytes) in &bytes_from_upstream {
+            *bytes_forwarded.entry(upstream.clone()).or_default() += bytes;
+        }
+        MetricsSnapshot {
+            active_sessions: self.active_sessions.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            bytes_forwarded,
+            bytes_to_upstream,
+            bytes_from_upstream,
+            menu_selections: self.menu_selections.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        write_metric(
+            &mut out,
+            "pukeko_active_sessions",
+            "gauge",
+            "Number of currently connected SSH sessions.",
+            self.active_sessions.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "pukeko_connections_total",
+            "counter",
+            "Total number of accepted connections.",
+            self.total_connections.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "pukeko_auth_failures_total",
+            "counter",
+            "Total number of rejected authentication attempts.",
+            self.auth_failures.load(Ordering::Relaxed),
+        );
+        write_forwarded_bytes(
+            &mut out,
+            &self.bytes_to_upstream.lock().unwrap(),
+            &self.bytes_from_upstream.lock().unwrap(),
+        );
+        write_labeled_metric(
+            &mut out,
+            "pukeko_menu_selections_total",
+            "Total number of times each menu entry was selected.",
+            "item",
+            &self.menu_selections.lock().unwrap(),
+        );
+
+        out
+    }
+}
+
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// Bytes forwarded per upstream, labelled with the direction they went in.
+fn write_forwarded_bytes(
+    out: &mut String,
+    to_upstream: &BTreeMap<String, u64>,
+    from_upstream: &BTreeMap<String, u64>,
+) {
+    let name = "pukeko_forwarded_bytes_total";
+    let _ = writeln!(out, "# HELP {name} Total bytes forwarded per upstream.");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (direction, values) in [
+        (Direction::Input, to_upstream),
+        (Direction::Output, from_upstream),
+    ] {
+        for (upstream, value) in values {
+            let _ = writeln!(
+                out,
+                "{name}{{upstream=\"{}\",direction=\"{}\"}} {value}",
+                escape_label(upstream),
+                direction.name()
+            );
+        }
+    }
+}
+
+fn write_labeled_metric(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: &BTreeMap<String, u64>,
+) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} counter");
+    for (key, value) in values {
+        let key = escape_label(key);
+        let _ = writeln!(out, "{name}{{{label}=\"{key}\"}} {value}");
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+pub async fn bind(address: SocketAddr) -> anyhow::Result<TcpListener> {
+    let listener = TcpListener::bind(address).await?;
+    info!("Serving metrics on http://{}/metrics", address);
+    Ok(listener)
+}
+
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer_addr) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                debug!("Metrics request from {} failed: {:?}", peer_addr, e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    let mut buffer = [0u8; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", metrics.render()),
+        ["GET", _] => ("404 Not Found", String::new()),
+        _ => {
+            warn!("Unsupported metrics request {:?}", request.lines().next());
+            ("405 Method Not Allowed", String::new())
+        }
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,360 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use tracing::debug;
+
+use crate::config::OidcConfig;
+
+/// How long to wait for each request to the identity provider.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The parts of the issuer's discovery document the device flow uses.
+#[derive(Debug, Deserialize)]
+struct Discovery {
+    device_authorization_endpoint: Option<String>,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    // Some providers still use the name from drafts of RFC 8628.
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    id_token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenError {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// Who signed in, from the claims of their ID token or the userinfo endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OidcIdentity {
+    /// The value of the configured `user_claim`, the pukeko user they log in as.
+    pub user: String,
+    pub subject: String,
+    pub issuer: String,
+}
+
+/// An OAuth 2.0 device authorization grant (RFC 8628) in progress: the user signs in to
+/// the identity provider in a browser while the bastion polls for the result.
+#[derive(Debug)]
+pub struct DeviceLogin {
+    client: reqwest::Client,
+    config: OidcConfig,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+    device_code: String,
+    user_code: String,
+    verification_uri: String,
+    verification_uri_complete: Option<String>,
+    interval: Duration,
+    expires_at: Instant,
+}
+
+impl DeviceLogin {
+    /// Looks up the issuer's endpoints and asks it for a code for the user to enter.
+    pub async fn start(config: &OidcConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()?;
+
+        let discovery_url = format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer.trim_end_matches('/')
+        );
+        let discovery: Discovery = get_json(client.get(&discovery_url))
+            .await
+            .with_context(|| format!("Failed to fetch {discovery_url}"))?;
+        let Some(device_endpoint) = discovery.device_authorization_endpoint else {
+            bail!(
+                "Issuer {} does not support the device authorization grant",
+                config.issuer
+            );
+        };
+
+        let scope = config.scopes.join(" ");
+        let mut form = vec![("client_id", config.client_id.as_str()), ("scope", &scope)];
+        if let Some(secret) = &config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let authorization: DeviceAuthorization =
+            get_json(client.post(&device_endpoint).form(&form))
+                .await
+                .with_context(|| {
+                    format!("Failed to request a device code from {device_endpoint}")
+                })?;
+
+        Ok(Self {
+            client,
+            config: config.clone(),
+            token_endpoint: discovery.token_endpoint,
+            userinfo_endpoint: discovery.userinfo_endpoint,
+            device_code: authorization.device_code,
+            user_code: authorization.user_code,
+            verification_uri: authorization.verification_uri,
+            verification_uri_complete: authorization.verification_uri_complete,
+            interval: Duration::from_secs(authorization.interval.max(1)),
+            expires_at: Instant::now() + Duration::from_secs(authorization.expires_in),
+        })
+    }
+
+    /// What to tell the user to do, shown by their SSH client.
+    pub fn instructions(&self) -> String {
+        match &self.verification_uri_complete {
+            Some(uri) => format!(
+                "Sign in at {uri}\r\nor open {} and enter the code {}",
+                self.verification_uri, self.user_code
+            ),
+            None => format!(
+                "Sign in at {} and enter the code {}",
+                self.verification_uri, self.user_code
+            ),
+        }
+    }
+
+    /// Polls the token endpoint for up to `wait`, returning who signed in, or `None` if the
+    /// user has not finished yet. Fails once the code has expired or sign-in was denied.
+    pub async fn poll(&mut self, wait: Duration) -> anyhow::Result<Option<OidcIdentity>> {
+        let deadline = Instant::now() + wait;
+        loop {
+            if Instant::now() >= self.expires_at {
+                bail!("The code {} expired", self.user_code);
+            }
+            if let Some(token) = self.request_token().await? {
+                return self.identity(token).await.map(Some);
+            }
+            if Instant::now() + self.interval > deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(self.interval).await;
+        }
+    }
+
+    async fn request_token(&mut self) -> anyhow::Result<Option<TokenResponse>> {
+        let mut form = vec![
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+            ("device_code", &self.device_code),
+            ("client_id", &self.config.client_id),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let response = self
+            .client
+            .post(&self.token_endpoint)
+            .form(&form)
+            .send()
+            .await
+            .with_context(|| format!("Failed to reach {}", self.token_endpoint))?;
+        let status = response.status();
+        let body = response.bytes().await?;
+        if status.is_success() {
+            return serde_json::from_slice(&body)
+                .with_context(|| format!("Invalid token response from {}", self.token_endpoint))
+                .map(Some);
+        }
+
+        let error: TokenError = serde_json::from_slice(&body)
+            .with_context(|| format!("{} responded with {}", self.token_endpoint, status))?;
+        match error.error.as_str() {
+            "authorization_pending" => Ok(None),
+            "slow_down" => {
+                self.interval += Duration::from_secs(5);
+                Ok(None)
+            }
+            _ => bail!(
+                "Sign-in failed: {}",
+                error.error_description.unwrap_or(error.error)
+            ),
+        }
+    }
+
+    /// Reads the claims from the ID token, which came straight from the issuer over TLS so
+    /// its signature need not be checked, or from the userinfo endpoint without one. The ID
+    /// token must still have been issued by the issuer, for this client, and not expired.
+    async fn identity(&self, token: TokenResponse) -> anyhow::Result<OidcIdentity> {
+        let claims = match &token.id_token {
+            Some(id_token) => {
+                let claims = id_token_claims(id_token)?;
+                check_id_token(&claims, &self.config, chrono::Utc::now().timestamp())?;
+                claims
+            }
+            None => {
+                let Some(endpoint) = &self.userinfo_endpoint else {
+                    bail!("Issuer returned no ID token and has no userinfo endpoint");
+                };
+                get_json(self.client.get(endpoint).bearer_auth(&token.access_token))
+                    .await
+                    .with_context(|| format!("Failed to fetch {endpoint}"))?
+            }
+        };
+        debug!("Signed in with claims {:?}", claims);
+
+        let claim = |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_string);
+        let Some(user) = claim(&self.config.user_claim) else {
+            bail!("Identity has no {} claim", self.config.user_claim);
+        };
+        Ok(OidcIdentity {
+            user,
+            subject: claim("sub").unwrap_or_default(),
+            issuer: claim("iss").unwrap_or_else(|| self.config.issuer.clone()),
+        })
+    }
+}
+
+async fn get_json<T: for<'de> Deserialize<'de>>(
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<T> {
+    let body = request
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)?
+        .bytes()
+        .await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Checks the ID token's claims were issued by the configured issuer, for its client, and
+/// have not expired at `now`, as OpenID Connect Core 1.0 section 3.1.3.7 requires.
+fn check_id_token(
+    claims: &Map<String, Value>,
+    config: &OidcConfig,
+    now: i64,
+) -> anyhow::Result<()> {
+    let issuer = claims
+        .get("iss")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    if issuer.trim_end_matches('/') != config.issuer.trim_end_matches('/') {
+        bail!("ID token was issued by {:?}, not {}", issuer, config.issuer);
+    }
+    let audience: Vec<&str> = match claims.get("aud") {
+        Some(Value::String(audience)) => vec![audience],
+        Some(Value::Array(audience)) => audience.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if !audience.contains(&config.client_id.as_str()) {
+        bail!("ID token is for {:?}, not {}", audience, config.client_id);
+    }
+    let party = claims.get("azp").and_then(Value::as_str);
+    if party.is_some_and(|party| party != config.client_id)
+        || (audience.len() > 1 && party.is_none())
+    {
+        bail!(
+            "ID token was authorized for {:?}, not {}",
+            party,
+            config.client_id
+        );
+    }
+    match claims.get("exp").and_then(Value::as_i64) {
+        Some(expires) if expires > now => Ok(()),
+        Some(_) => bail!("ID token has expired"),
+        None => bail!("ID token has no expiry"),
+    }
+}
+
+fn id_token_claims(id_token: &str) -> anyhow::Result<Map<String, Value>> {
+    let payload = id_token
+        .split('.')
+        .nth(1)
+        .context("ID token is not a JWT")?;
+    let payload = data_encoding::BASE64URL_NOPAD
+        .decode(payload.trim_end_matches('=').as_bytes())
+        .context("ID token is not valid base64")?;
+    serde_json::from_slice(&payload).context("ID token claims are not a JSON object")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+
+    use super::*;
+
+    const NOW: i64 = 1_750_000_000;
+
+    fn config() -> OidcConfig {
+        OidcConfig {
+            issuer: "https://sso.example.com/realms/ops".to_string(),
+            client_id: "pukeko".to_string(),
+            client_secret: None,
+            scopes: vec!["openid".to_string()],
+            user_claim: "sub".to_string(),
+            max_flows_per_connection: 3,
+            max_flows_per_ip: 10,
+            flow_window: Duration::from_secs(600),
+        }
+    }
+
+    fn check(claims: Value) -> anyhow::Result<()> {
+        let Value::Object(claims) = claims else {
+            unreachable!()
+        };
+        check_id_token(&claims, &config(), NOW)
+    }
+
+    #[test]
+    fn id_tokens_for_this_client_from_the_issuer_are_accepted() {
+        check(json!({
+            "iss": "https://sso.example.com/realms/ops/",
+            "aud": "pukeko",
+            "exp": NOW + 60,
+        }))
+        .unwrap();
+        check(json!({
+            "iss": "https://sso.example.com/realms/ops",
+            "aud": ["pukeko", "account"],
+            "azp": "pukeko",
+            "exp": NOW + 60,
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn id_tokens_from_other_issuers_or_for_other_clients_are_refused() {
+        let refused = [
+            json!({"iss": "https://evil.example.com", "aud": "pukeko", "exp": NOW + 60}),
+            json!({"aud": "pukeko", "exp": NOW + 60}),
+            json!({"iss": "https://sso.example.com/realms/ops", "aud": "other", "exp": NOW + 60}),
+            json!({"iss": "https://sso.example.com/realms/ops", "exp": NOW + 60}),
+            json!({
+                "iss": "https://sso.example.com/realms/ops",
+                "aud": ["pukeko", "other"],
+                "exp": NOW + 60,
+            }),
+            json!({
+                "iss": "https://sso.example.com/realms/ops",
+                "aud": "pukeko",
+                "azp": "other",
+                "exp": NOW + 60,
+            }),
+            json!({"iss": "https://sso.example.com/realms/ops", "aud": "pukeko", "exp": NOW}),
+            json!({"iss": "https://sso.example.com/realms/ops", "aud": "pukeko"}),
+        ];
+        for claims in refused {
+            assert!(check(claims.clone()).is_err(), "{claims}");
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use std::sync::{Arc, Mutex};
+
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Paragraph};
+use tokio::sync::{Notify, mpsc};
+use tracing::{Instrument, info};
+
+use crate::forward::Forward;
+use crate::keymap::Key;
+use crate::vt::VirtualTerminal;
+
+/// How much of the screen a new pane gets, relative to the others.
+const DEFAULT_WEIGHT: u16 = 4;
+const MAX_WEIGHT: u16 = 16;
+
+/// What the client's input to the panes asked pukeko to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaneCommand {
+    /// Show the menu, leaving the panes running behind it.
+    Menu,
+    /// The last pane was closed.
+    Closed,
+}
+
+/// The terminal a pane's output is drawn on, shared with the task that feeds it.
+struct PaneScreen {
+    terminal: VirtualTerminal,
+    /// Set once the upstream has closed.
+    closed: bool,
+}
+
+struct Pane {
+    forward: Forward,
+    screen: Arc<Mutex<PaneScreen>>,
+    /// Share of the screen along the split, relative to the other panes.
+    weight: u16,
+    /// Left out of input typed in other panes while broadcasting.
+    excluded: bool,
+}
+
+/// Several forwards drawn side by side, or stacked, on one session channel, with input
+/// going to the focused one. Keys after the prefix key switch focus, resize, close, flip
+/// the split and return to the menu; the prefix typed twice is sent to the pane. While
+/// broadcasting, input goes to every pane not excluded from it, as with clusterssh.
+pub struct Panes {
+    panes: Vec<Pane>,
+    focus: usize,
+    broadcast: bool,
+    side_by_side: bool,
+    prefix: Key,
+    /// Whether the prefix was just typed, so the next key is a command.
+    prefixed: bool,
+    /// The client's whole terminal.
+    area: Rect,
+    /// Whether the panes are on the client's terminal, rather than the menu or a forward.
+    showing: bool,
+    /// Woken whenever the panes need drawing again.
+    redraw: Arc<Notify>,
+}
+
+impl Panes {
+    pub fn new(prefix: Key, area: Rect) -> Self {
+        Self {
+            panes: Vec::new(),
+            focus: 0,
+            broadcast: false,
+            side_by_side: true,
+            prefix,
+            prefixed: false,
+            area,
+            showing: false,
+            redraw: Arc::new(Notify::new()),
+        }
+    }
+
+    pub fn redraw(&self) -> Arc<Notify> {
+        self.redraw.clone()
+    }
+
+    pub fn showing(&self) -> bool {
+        self.showing
+    }
+
+    pub fn show(&mut self, showing: bool) {
+        self.showing = showing;
+        self.prefixed = false;
+        self.redraw.notify_one();
+    }
+
+    pub fn targets(&self) -> Vec<&str> {
+        self.panes
+            .iter()
+            .map(|pane| pane.forward.target())
+            .collect()
+    }
+
+    /// The size of the terminal inside the pane added next, to start its upstream with.
+    pub fn next_size(&self) -> (u16, u16) {
+        let weights: Vec<u16> = self
+            .panes
+            .iter()
+            .map(|pane| pane.weight)
+            .chain([DEFAULT_WEIGHT])
+            .collect();
+        let area = self.layout(&weights).last().copied().unwrap_or_default();
+        inner_size(area)
+    }
+
+    /// Adds a pane for `forward`, whose output arrives on `output`, and focuses it.
+    pub fn add(&mut self, forward: Forward, mut output: mpsc::UnboundedReceiver<Vec<u8>>) {
+        let (width, height) = self.next_size();
+        let screen = Arc::new(Mutex::new(PaneScreen {
+            terminal: VirtualTerminal::new(width, height),
+            closed: false,
+        }));
+        let redraw = self.redraw.clone();
+        let fed = screen.clone();
+        tokio::spawn(
+            async move {
+                while let Some(data) = output.recv().await {
+                    fed.lock().unwrap().terminal.feed(&data);
+                    redraw.notify_one();
+                }
+                fed.lock().unwrap().closed = true;
+                redraw.notify_one();
+            }
+            .in_current_span(),
+        );
+        self.panes.push(Pane {
+            forward,
+            screen,
+            weight: DEFAULT_WEIGHT,
+            excluded: false,
+        });
+        self.focus = self.panes.len() - 1;
+        self.relayout();
+    }
+
+    /// Fits the panes to the client's terminal after it was resized.
+    pub fn resize(&mut self, area: Rect) {
+        self.area = area;
+        self.relayout();
+    }
+
+    /// Handles the client's input: commands after the prefix, and the rest sent to the
+    /// focused pane. Any key in a pane whose upstream has closed closes the pane.
+    pub fn input(&mut self, data: &[u8]) -> anyhow::Result<Option<PaneCommand>> {
+        let prefix = prefix_byte(self.prefix);
+        let mut forwarded = Vec::with_capacity(data.len());
+        let mut rest = data;
+        while let Some((&byte, after)) = rest.split_first() {
+            rest = after;
+            if !self.prefixed {
+                if Some(byte) == prefix {
+                    self.prefixed = true;
+                } else {
+                    forwarded.push(byte);
+                }
+                continue;
+            }
+
+            self.prefixed = false;
+            if Some(byte) == prefix {
+                forwarded.push(byte);
+                continue;
+            }
+            // Commands act on the focused pane, so input before one goes to it first.
+            if let Some(command) = self.send(std::mem::take(&mut forwarded))? {
+                return Ok(Some(command));
+            }
+            let command = match byte {
+                // Arrow keys, in both the normal and application cursor modes.
+                b'\x1b' if matches!(rest, [b'[' | b'O', b'A'..=b'D', ..]) => {
+                    let arrow = rest[1];
+                    rest = &rest[2..];
+                    match arrow {
+                        b'A' | b'D' => self.focus_previous(),
+                        _ => self.focus_next(),
+                    }
+                    None
+                }
+                b'o' | b'\t' => {
+                    self.focus_next();
+                    None
+                }
+                b'x' => self.close_focused(),
+                b'>' | b'+' => {
+                    self.grow(true);
+                    None
+                }
+                b'<' | b'-' => {
+                    self.grow(false);
+                    None
+                }
+                b' ' => {
+                    self.side_by_side = !self.side_by_side;
+                    self.relayout();
+                    None
+                }
+                b'b' => {
+                    self.broadcast = !self.broadcast;
+                    if self.broadcast {
+                        info!("Broadcasting input to {} panes", self.receivers().count());
+                    }
+                    None
+                }
+                b'e' => {
+                    if let Some(pane) = self.panes.get_mut(self.focus) {
+                        pane.excluded = !pane.excluded;
+                    }
+                    None
+                }
+                b'm' => Some(PaneCommand::Menu),
+                _ => None,
+            };
+            self.redraw.notify_one();
+            if command.is_some() {
+                return Ok(command);
+            }
+        }
+        self.send(forwarded)
+    }
+
+    /// Draws every pane, with the focused one's cursor, and a line of hints at the bottom.
+    /// While broadcasting, every pane input goes to is highlighted and the hints say so.
+    pub fn render(&self, frame: &mut Frame, block: Block<'static>, focused: Style) {
+        let weights: Vec<u16> = self.panes.iter().map(|pane| pane.weight).collect();
+        let areas = self.layout(&weights);
+        for (i, (pane, area)) in self.panes.iter().zip(areas).enumerate() {
+            let screen = pane.screen.lock().unwrap();
+            let title = if screen.closed {
+                format!(" {} (closed, press any key) ", pane.forward.target())
+            } else if self.broadcast && pane.excluded {
+                format!(" {} (excluded) ", pane.forward.target())
+            } else {
+                format!(" {} ", pane.forward.target())
+            };
+            let mut block = block.clone().title(title);
+            if i == self.focus || (self.broadcast && !pane.excluded && !screen.closed) {
+                block = block.border_style(focused).title_style(focused);
+            }
+            let inner = block.inner(area);
+            frame.render_widget(block, area);
+            screen.terminal.render(inner, frame.buffer_mut());
+            if i == self.focus
+                && !screen.closed
+                && let Some(cursor) = screen.terminal.cursor()
+                && cursor.x < inner.width
+                && cursor.y < inner.height
+            {
+                frame.set_cursor_position((inner.x + cursor.x, inner.y + cursor.y));
+            }
+        }
+
+        let prefix = Span::styled(format!(" {} ", self.prefix), focused);
+        let hints = if self.broadcast {
+            let receivers = self.receivers().count();
+            Line::from(vec![
+                Span::styled(
+                    format!(" BROADCASTING TO {receivers} PANES "),
+                    focused.add_modifier(Modifier::REVERSED),
+                ),
+                Span::raw(" "),
+                prefix,
+                Span::raw(" then b: stop, e: exclude pane, o: next pane, x: close, m: menu"),
+            ])
+        } else {
+            Line::from(vec![
+                prefix,
+                Span::raw(
+                    " then o: next pane, <>: resize, Space: flip, b: broadcast, x: close, m: menu",
+                ),
+            ])
+        };
+        frame.render_widget(Paragraph::new(hints), self.hints_area());
+    }
+
+    /// Where each pane goes, given their weights.
+    fn layout(&self, weights: &[u16]) -> Vec<Rect> {
+        let area = Rect {
+            height: self.area.height.saturating_sub(1),
+            ..self.area
+        };
+        let direction = if self.side_by_side {
+            Direction::Horizontal
+        } else {
+            Direction::Vertical
+        };
+        Layout::default()
+            .direction(direction)
+            .constraints(weights.iter().map(|&weight| Constraint::Fill(weight)))
+            .split(area)
+            .to_vec()
+    }
+
+    fn hints_area(&self) -> Rect {
+        Rect {
+            y: self.area.bottom().saturating_sub(1),
+            height: self.area.height.min(1),
+            ..self.area
+        }
+    }
+
+    /// Resizes each pane's terminal, and its upstream's, to where it now goes.
+    fn relayout(&mut self) {
+        let weights: Vec<u16> = self.panes.iter().map(|pane| pane.weight).collect();
+        let areas = self.layout(&weights);
+        for (pane, area) in self.panes.iter().zip(areas) {
+            let (width, height) = inner_size(area);
+            let mut screen = pane.screen.lock().unwrap();
+            if screen.terminal.size() != (width, height) {
+                screen.terminal.resize(width, height);
+                let _ = pane
+                    .forward
+                    .window_change(width.into(), height.into(), 0, 0);
+            }
+        }
+        self.redraw.notify_one();
+    }
+
+    fn send(&mut self, data: Vec<u8>) -> anyhow::Result<Option<PaneCommand>> {
+        if data.is_empty() {
+            return Ok(None);
+        }
+        let Some(pane) = self.panes.get(self.focus) else {
+            return Ok(None);
+        };
+        if pane.forward.is_closed() || pane.screen.lock().unwrap().closed {
+            self.redraw.notify_one();
+            return Ok(self.close_focused());
+        }
+        pane.forward.data(&data)?;
+        if self.broadcast {
+            for (i, pane) in self.panes.iter().enumerate() {
+                if i != self.focus && !pane.excluded && !pane.forward.is_closed() {
+                    pane.forward.data(&data)?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The open panes that input goes to while broadcasting.
+    fn receivers(&self) -> impl Iterator<Item = &Pane> {
+        self.panes
+            .iter()
+            .filter(|pane| !pane.excluded && !pane.forward.is_closed())
+    }
+
+    fn focus_next(&mut self) {
+        if !self.panes.is_empty() {
+            self.focus = (self.focus + 1) % self.panes.len();
+        }
+    }
+
+    fn focus_previous(&mut self) {
+        if !self.panes.is_empty() {
+            self.focus = (self.focus + self.panes.len() - 1) % self.panes.len();
+        }
+    }
+
+    /// Closes the focused pane and its upstream, returning [`PaneCommand::Closed`] if it
+    /// was the last.
+    fn close_focused(&mut self) -> Option<PaneCommand> {
+        if self.focus < self.panes.len() {
+            self.panes.remove(self.focus);
+        }
+        if self.panes.is_empty() {
+            return Some(PaneCommand::Closed);
+        }
+        self.focus = self.focus.min(self.panes.len() - 1);
+        self.relayout();
+        None
+    }
+
+    fn grow(&mut self, grow: bool) {
+        if let Some(pane) = self.panes.get_mut(self.focus) {
+            pane.weight = if grow {
+                (pane.weight + 1).min(MAX_WEIGHT)
+            } else {
+                pane.weight.saturating_sub(1).max(1)
+            };
+            self.relayout();
+        }
+    }
+}
+
+impl Drop for Panes {
+    /// Wakes the task drawing the panes, so it sees they are gone and ends.
+    fn drop(&mut self) {
+        self.redraw.notify_one();
+    }
+}
+
+/// The size of the terminal inside a pane's border.
+fn inner_size(area: Rect) -> (u16, u16) {
+    let inner = Block::default().borders(Borders::ALL).inner(area);
+    (inner.width.max(1), inner.height.max(1))
+}
+
+/// The byte a Ctrl key sends.
+fn prefix_byte(key: Key) -> Option<u8> {
+    match key {
+        Key::Ctrl(c) if c.is_ascii_lowercase() => Some(c as u8 - b'a' + 1),
+        _ => None,
+    }
+}
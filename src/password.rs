@@ -0,0 +1,32 @@
+use anyhow::{anyhow, bail};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2};
+use rand_core::OsRng;
+
+/// Hashes a password with argon2id and a random salt, as a PHC string for the config.
+pub fn hash(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Failed to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+/// Checks that `hash` is an argon2id PHC string, such as those made by [`hash`].
+pub fn validate(hash: &str) -> anyhow::Result<()> {
+    let parsed = PasswordHash::new(hash).map_err(|e| anyhow!("Invalid password hash: {e}"))?;
+    if parsed.algorithm != Algorithm::Argon2id.ident() {
+        bail!("Password hash must use argon2id, not {}", parsed.algorithm);
+    }
+    Ok(())
+}
+
+/// Checks `password` against a hash that has passed [`validate`]. This takes a while by
+/// design, so it should not be called on an async task.
+pub fn verify(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
@@ -0,0 +1,528 @@
+//! Plugins compiled to WebAssembly, for site-specific logic at logins, in the menu, as
+//! sessions start and end and on forwarded data, without changing pukeko. A plugin sees
+//! only the JSON it is passed, and each call to it is limited in fuel and memory.
+//!
+//! # Guest API, version 1
+//!
+//! A plugin exports `memory`, `pukeko_api_version() -> i32` returning 1, and
+//! `pukeko_alloc(len: i32) -> i32` returning where `len` bytes may be written in its
+//! memory. Each hook it exports is called with the pointer and length of a JSON object
+//! written there, and returns `(ptr << 32) | len` of a JSON reply in its memory as an
+//! `i64`, or 0 for no reply:
+//!
+//! - `pukeko_on_auth(ptr, len) -> i64` is passed `{user, login, target, peer, location,
+//!   attributes}` once a user has authenticated. `{"allow": false, "reason": "..."}`
+//!   refuses the login.
+//! - `pukeko_decorate(ptr, len) -> i64` is passed `{user, server: {name, host, port,
+//!   group, tags}}` for each server in a user's menu. `{"label": "..."}` is shown after
+//!   the server's name.
+//! - `pukeko_on_session_start(ptr, len) -> i64` is passed `{session, user, peer}` once a
+//!   session has logged in.
+//! - `pukeko_on_session_end(ptr, len) -> i64` is passed `{session, user}` once it has
+//!   disconnected.
+//! - `pukeko_inspect(ptr, len) -> i64` is passed `{session, user, server, direction,
+//!   data}` for each chunk of forwarded data, `data` in base64. `{"data": "...",
+//!   "findings": [{"rule": "...", "action": "alert"}]}` replaces the chunk and reports
+//!   what was found, as `inspect` rules do.
+//!
+//! The only import offered is `pukeko.log(ptr: i32, len: i32)`, which logs a message. A
+//! call that runs out of fuel, grows its memory past the limit, traps or replies with
+//! something other than the JSON expected is logged, and the plugin is instantiated again
+//! for the next call. Such a call is taken as no reply, except by `pukeko_on_auth`, where
+//! it refuses the login rather than letting it in unchecked.
+//!
+//! Plugins are called off the async runtime, on tokio's blocking threads, so a slow
+//! plugin holds up only the login, menu or forward that called it.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+
+use data_encoding::BASE64;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+use crate::config::{InspectAction, PluginConfig, ServerEntry};
+use crate::geoip::Location;
+use crate::inspect::{Chunk, Finding, Inspection, StreamInspector};
+use crate::sessions::SessionEvent;
+
+#[cfg(feature = "wasm")]
+use self::wasm::Plugin;
+
+const ON_AUTH: &str = "pukeko_on_auth";
+const DECORATE: &str = "pukeko_decorate";
+const ON_SESSION_START: &str = "pukeko_on_session_start";
+const ON_SESSION_END: &str = "pukeko_on_session_end";
+const INSPECT: &str = "pukeko_inspect";
+
+/// A user who has authenticated, for plugins to let in or refuse.
+#[derive(Debug, Serialize)]
+pub struct LoginAttempt<'a> {
+    pub user: &'a str,
+    pub login: &'a str,
+    pub target: Option<&'a str>,
+    pub peer: Option<SocketAddr>,
+    pub location: Option<&'a Location>,
+    pub attributes: &'a BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct AuthReply {
+    allow: bool,
+    reason: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DecorateReply {
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct InspectReply {
+    data: Option<String>,
+    #[serde(default)]
+    findings: Vec<FindingReply>,
+}
+
+#[derive(Deserialize)]
+struct FindingReply {
+    rule: String,
+    #[serde(default)]
+    action: InspectAction,
+}
+
+/// The configured plugins, loaded again on every reload.
+#[derive(Default)]
+pub struct Plugins {
+    loaded: RwLock<Vec<Arc<Plugin>>>,
+}
+
+impl Plugins {
+    fn loaded(&self) -> Vec<Arc<Plugin>> {
+        self.loaded.read().unwrap().clone()
+    }
+
+    /// Whether any plugin exports `hook`.
+    fn hooks(&self, hook: &str) -> bool {
+        self.loaded
+            .read()
+            .unwrap()
+            .iter()
+            .any(|plugin| plugin.exports(hook))
+    }
+
+    /// Asks each plugin exporting `hook` in turn, returning their names and replies. A
+    /// call that failed is returned as `Err`; one with no reply is left out.
+    fn replies<'a, T: DeserializeOwned>(
+        &self,
+        hook: &'a str,
+        input: &'a Value,
+    ) -> impl Iterator<Item = (String, Result<T, ()>)> + 'a {
+        self.loaded().into_iter().filter_map(move |plugin| {
+            let reply = ask(&plugin, hook, input).transpose()?;
+            Some((plugin.name().to_string(), reply))
+        })
+    }
+
+    /// The first plugin to refuse `login`, with why. A plugin that fails to answer refuses
+    /// it too.
+    pub async fn refuses(self: &Arc<Self>, login: &LoginAttempt<'_>) -> Option<(String, String)> {
+        if !self.hooks(ON_AUTH) {
+            return None;
+        }
+        let input = json!(login);
+        let plugins = self.clone();
+        let refused = tokio::task::spawn_blocking(move || {
+            plugins
+                .replies::<AuthReply>(ON_AUTH, &input)
+                .find_map(|(name, reply)| match reply {
+                    Ok(reply) if reply.allow => None,
+                    Ok(reply) => {
+                        let reason = reply.reason.unwrap_or_else(|| "Access denied".to_string());
+                        Some((name, reason))
+                    }
+                    Err(()) => Some((name, "Access denied".to_string())),
+                })
+        })
+        .await;
+        refused.unwrap_or_else(|_| Some(("plugins".to_string(), "Access denied".to_string())))
+    }
+
+    /// The labels plugins give each of `servers` in `user`'s menu, by server name.
+    pub async fn labels(
+        self: &Arc<Self>,
+        user: &str,
+        servers: Vec<ServerEntry>,
+    ) -> HashMap<String, String> {
+        if !self.hooks(DECORATE) {
+            return HashMap::new();
+        }
+        let plugins = self.clone();
+        let user = user.to_string();
+        let labels = tokio::task::spawn_blocking(move || {
+            let mut labels = HashMap::new();
+            for entry in servers {
+                let input = json!({
+                    "user": user,
+                    "server": {
+                        "name": entry.name,
+                        "host": entry.host,
+                        "port": entry.port,
+                        "group": entry.group,
+                        "tags": entry.tags,
+                    },
+                });
+                let label: Vec<String> = plugins
+                    .replies::<DecorateReply>(DECORATE, &input)
+                    .filter_map(|(_, reply)| reply.ok()?.label)
+                    .filter(|label| !label.is_empty())
+                    .collect();
+                if !label.is_empty() {
+                    labels.insert(entry.name, label.join(" "));
+                }
+            }
+            labels
+        });
+        labels.await.unwrap_or_default()
+    }
+
+    /// Inspects forwarded data with the plugins that export `pukeko_inspect`, if any do.
+    pub fn inspector(self: &Arc<Self>) -> Option<Arc<dyn StreamInspector>> {
+        self.hooks(INSPECT).then(|| {
+            Arc::new(PluginInspector {
+                plugins: self.clone(),
+            }) as Arc<dyn StreamInspector>
+        })
+    }
+
+    /// Calls `hook` on each plugin exporting it, for what it does rather than its reply.
+    async fn notify(self: &Arc<Self>, hook: &'static str, input: Value) {
+        let plugins = self.clone();
+        let _ = tokio::task::spawn_blocking(move || {
+            plugins.replies::<Value>(hook, &input).for_each(drop);
+        })
+        .await;
+    }
+
+    /// Tells plugins of sessions logging in and disconnecting, until the server stops.
+    pub async fn run(self: Arc<Self>, mut events: broadcast::Receiver<SessionEvent>) {
+        // The address of each session, and its user once it has logged in.
+        let mut sessions: HashMap<usize, (Option<SocketAddr>, Option<String>)> = HashMap::new();
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("Plugins missed {} session events", missed);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            };
+            match event {
+                SessionEvent::Connected { id, peer } => {
+                    sessions.insert(id, (peer, None));
+                }
+                SessionEvent::Authenticated { id, user } => {
+                    let session = sessions.entry(id).or_default();
+                    session.1 = Some(user.clone());
+                    if self.hooks(ON_SESSION_START) {
+                        let input = json!({ "session": id, "user": user, "peer": session.0 });
+                        self.notify(ON_SESSION_START, input).await;
+                    }
+                }
+                SessionEvent::Disconnected { id } => {
+                    if let Some((_, Some(user))) = sessions.remove(&id)
+                        && self.hooks(ON_SESSION_END)
+                    {
+                        let input = json!({ "session": id, "user": user });
+                        self.notify(ON_SESSION_END, input).await;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Calls `hook` on `plugin`, logging a call that fails or a reply that is not a `T`. `Err`
+/// is a call that failed, `Ok(None)` one with no reply.
+fn ask<T: DeserializeOwned>(plugin: &Plugin, hook: &str, input: &Value) -> Result<Option<T>, ()> {
+    let reply = plugin.call(hook, input).map_err(|e| {
+        warn!("Plugin {} failed in {}: {:#}", plugin.name(), hook, e);
+    })?;
+    let Some(reply) = reply else {
+        return Ok(None);
+    };
+    serde_json::from_value(reply).map(Some).map_err(|e| {
+        warn!(
+            "Plugin {} replied to {} wrongly: {}",
+            plugin.name(),
+            hook,
+            e
+        );
+    })
+}
+
+/// Runs each plugin that exports `pukeko_inspect` on forwarded data, each seeing the data
+/// the ones before it replaced.
+struct PluginInspector {
+    plugins: Arc<Plugins>,
+}
+
+impl StreamInspector for PluginInspector {
+    fn inspect(&self, chunk: &Chunk) -> Inspection {
+        let mut data: Option<Vec<u8>> = None;
+        let mut findings = Vec::new();
+        for plugin in self.plugins.loaded() {
+            let input = json!({
+                "session": chunk.session,
+                "user": chunk.user,
+                "server": chunk.server,
+                "direction": chunk.direction,
+                "data": BASE64.encode(data.as_deref().unwrap_or(chunk.data)),
+            });
+            let Ok(Some(reply)) = ask::<InspectReply>(&plugin, INSPECT, &input) else {
+                continue;
+            };
+            if let Some(replaced) = reply.data {
+                match BASE64.decode(replaced.as_bytes()) {
+                    Ok(replaced) => data = Some(replaced),
+                    Err(e) => warn!(
+                        "Plugin {} replaced data with bad base64: {}",
+                        plugin.name(),
+                        e
+                    ),
+                }
+            }
+            findings.extend(reply.findings.into_iter().map(|finding| Finding {
+                rule: finding.rule,
+                action: finding.action,
+            }));
+        }
+        Inspection { data, findings }
+    }
+}
+
+/// Without the wasm feature no plugins can be configured; the config refuses them.
+#[cfg(not(feature = "wasm"))]
+enum Plugin {}
+
+#[cfg(not(feature = "wasm"))]
+impl Plugin {
+    fn name(&self) -> &str {
+        match *self {}
+    }
+
+    fn exports(&self, _hook: &str) -> bool {
+        match *self {}
+    }
+
+    fn call(&self, _hook: &str, _input: &Value) -> anyhow::Result<Option<Value>> {
+        match *self {}
+    }
+}
+
+#[cfg(not(feature = "wasm"))]
+impl Plugins {
+    pub fn configure(&self, _plugins: &[PluginConfig]) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "wasm")]
+impl Plugins {
+    /// Loads the configured plugins, replacing any previous ones. Loading them again on
+    /// every reload picks up rebuilt plugins with a SIGHUP.
+    pub fn configure(&self, plugins: &[PluginConfig]) -> anyhow::Result<()> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let plugins = plugins
+            .iter()
+            .map(|plugin| Plugin::load(&engine, plugin).map(Arc::new))
+            .collect::<anyhow::Result<_>>()?;
+        *self.loaded.write().unwrap() = plugins;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+
+    use anyhow::{Context, bail};
+    use serde_json::Value;
+    use tracing::info;
+    use wasmtime::{
+        Caller, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits,
+        StoreLimitsBuilder, TypedFunc,
+    };
+
+    use crate::config::PluginConfig;
+
+    const API_VERSION: u32 = 1;
+
+    pub struct Plugin {
+        name: String,
+        config: PluginConfig,
+        engine: Engine,
+        module: Module,
+        linker: Linker<State>,
+        exports: HashSet<String>,
+        /// Kept between calls, so a plugin can remember things, until a call fails.
+        instance: Mutex<Option<Loaded>>,
+    }
+
+    struct State {
+        name: String,
+        limits: StoreLimits,
+    }
+
+    struct Loaded {
+        store: Store<State>,
+        instance: Instance,
+        memory: Memory,
+        alloc: TypedFunc<u32, u32>,
+    }
+
+    impl Plugin {
+        /// Compiles and instantiates the plugin, so one that cannot run is refused with
+        /// the config.
+        pub fn load(engine: &Engine, config: &PluginConfig) -> anyhow::Result<Self> {
+            let path = config.path.display();
+            let module = Module::from_file(engine, &config.path)
+                .map_err(anyhow::Error::from)
+                .with_context(|| format!("Failed to load plugin {path}"))?;
+            let mut linker = Linker::new(engine);
+            linker.func_wrap("pukeko", "log", log)?;
+            let plugin = Self {
+                name: config
+                    .path
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned(),
+                config: config.clone(),
+                engine: engine.clone(),
+                exports: module
+                    .exports()
+                    .map(|export| export.name().to_string())
+                    .collect(),
+                module,
+                linker,
+                instance: Mutex::new(None),
+            };
+            let loaded = plugin
+                .instantiate()
+                .with_context(|| format!("Failed to start plugin {path}"))?;
+            *plugin.instance.lock().unwrap() = Some(loaded);
+            Ok(plugin)
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        pub fn exports(&self, hook: &str) -> bool {
+            self.exports.contains(hook)
+        }
+
+        /// Calls `hook` with `input` if the plugin exports it, returning its reply. A call
+        /// that fails leaves the plugin to be instantiated again for the next.
+        pub fn call(&self, hook: &str, input: &Value) -> anyhow::Result<Option<Value>> {
+            if !self.exports(hook) {
+                return Ok(None);
+            }
+            let mut instance = self.instance.lock().unwrap();
+            let loaded = match instance.take() {
+                Some(loaded) => Ok(loaded),
+                None => self.instantiate(),
+            };
+            loaded.and_then(|mut loaded| {
+                let reply = loaded.call(hook, input, self.config.fuel)?;
+                *instance = Some(loaded);
+                Ok(reply)
+            })
+        }
+
+        fn instantiate(&self) -> anyhow::Result<Loaded> {
+            let limits = StoreLimitsBuilder::new()
+                .memory_size(self.config.max_memory)
+                .build();
+            let mut store = Store::new(
+                &self.engine,
+                State {
+                    name: self.name.clone(),
+                    limits,
+                },
+            );
+            store.limiter(|state| &mut state.limits);
+            store.set_fuel(self.config.fuel)?;
+            let instance = self.linker.instantiate(&mut store, &self.module)?;
+            let memory = instance
+                .get_memory(&mut store, "memory")
+                .context("Plugin does not export memory")?;
+            let alloc = instance.get_typed_func::<u32, u32>(&mut store, "pukeko_alloc")?;
+            let version = instance
+                .get_typed_func::<(), u32>(&mut store, "pukeko_api_version")?
+                .call(&mut store, ())?;
+            if version != API_VERSION {
+                bail!("Plugin uses API version {version}, but only {API_VERSION} is supported");
+            }
+            Ok(Loaded {
+                store,
+                instance,
+                memory,
+                alloc,
+            })
+        }
+    }
+
+    impl Loaded {
+        fn call(&mut self, hook: &str, input: &Value, fuel: u64) -> anyhow::Result<Option<Value>> {
+            self.store.set_fuel(fuel)?;
+            let input = serde_json::to_vec(input)?;
+            let len = u32::try_from(input.len()).context("Input is too long")?;
+            let ptr = self.alloc.call(&mut self.store, len)?;
+            self.memory
+                .write(&mut self.store, ptr as usize, &input)
+                .context("pukeko_alloc returned memory out of bounds")?;
+            let reply = self
+                .instance
+                .get_typed_func::<(u32, u32), u64>(&mut self.store, hook)?
+                .call(&mut self.store, (ptr, len))?;
+            if reply == 0 {
+                return Ok(None);
+            }
+            let (ptr, len) = ((reply >> 32) as usize, (reply & 0xffff_ffff) as usize);
+            let output = self
+                .memory
+                .data(&self.store)
+                .get(ptr..)
+                .and_then(|data| data.get(..len))
+                .context("Reply is out of bounds")?;
+            Ok(Some(
+                serde_json::from_slice(output).context("Reply is not JSON")?,
+            ))
+        }
+    }
+
+    /// `pukeko.log`, which logs `len` bytes at `ptr` in the plugin's memory.
+    fn log(mut caller: Caller<'_, State>, ptr: u32, len: u32) {
+        let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+            return;
+        };
+        let message = memory
+            .data(&caller)
+            .get(ptr as usize..)
+            .and_then(|data| data.get(..len as usize))
+            .map(|message| String::from_utf8_lossy(message).into_owned());
+        if let Some(message) = message {
+            info!("Plugin {}: {}", caller.data().name, message);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use russh::Disconnect;
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::config::{ConfigReceiver, PukekoConfig, ServerEntry};
+use crate::upstream::{self, Upstream, UpstreamHandle};
+
+/// How often connections are checked for having been idle too long.
+const EVICT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Connections that may be shared: to the same server at the same address, authenticated
+/// as the same user with the same key.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PoolKey {
+    server: String,
+    host: String,
+    port: u16,
+    user: String,
+    key: String,
+}
+
+impl PoolKey {
+    fn new(entry: &ServerEntry, user: &str, config: &PukekoConfig) -> anyhow::Result<Self> {
+        let key = entry
+            .key
+            .as_deref()
+            .or(config.upstream_key.as_ref())
+            .with_context(|| format!("No upstream key is configured for {}", entry.name))?;
+        Ok(Self {
+            server: entry.name.clone(),
+            host: entry.host.clone(),
+            port: entry.port,
+            user: entry.user.as_deref().unwrap_or(user).to_string(),
+            key: key.public_key().fingerprint(Default::default()).to_string(),
+        })
+    }
+}
+
+struct Pooled {
+    handle: Arc<UpstreamHandle>,
+    /// Sessions with a channel open on the connection.
+    channels: usize,
+    /// When the last of them closed.
+    idle_since: Instant,
+}
+
+/// Upstream connections authenticated with pukeko's own keys, shared by the sessions to
+/// the same server as the same user so that each only opens a channel instead of
+/// connecting and exchanging keys again.
+#[derive(Default)]
+pub struct UpstreamPool {
+    connections: Mutex<HashMap<PoolKey, Vec<Pooled>>>,
+}
+
+/// A session's share of a pooled connection, given back when dropped.
+pub struct Lease {
+    pub handle: Arc<UpstreamHandle>,
+    key: PoolKey,
+    pool: Arc<UpstreamPool>,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let mut connections = self.pool.connections.lock().unwrap();
+        let pooled = connections.get_mut(&self.key).and_then(|pooled| {
+            pooled
+                .iter_mut()
+                .find(|pooled| Arc::ptr_eq(&pooled.handle, &self.handle))
+        });
+        if let Some(pooled) = pooled {
+            pooled.channels -= 1;
+            if pooled.channels == 0 {
+                pooled.idle_since = Instant::now();
+            }
+        }
+    }
+}
+
+impl Lease {
+    /// Stops the connection being shared with later sessions, as it failed to open a
+    /// channel.
+    pub fn retire(&self) {
+        let mut connections = self.pool.connections.lock().unwrap();
+        if let Some(pooled) = connections.get_mut(&self.key) {
+            pooled.retain(|pooled| !Arc::ptr_eq(&pooled.handle, &self.handle));
+        }
+    }
+}
+
+impl UpstreamPool {
+    /// A connection to `entry` as `user` with a channel to spare, connecting a new one
+    /// when none has. Once the pool has as many as it may keep, the connection is the
+    /// session's own.
+    pub async fn connect(
+        self: &Arc<Self>,
+        entry: &ServerEntry,
+        user: &str,
+        config: &PukekoConfig,
+    ) -> anyhow::Result<Upstream> {
+        let key = PoolKey::new(entry, user, config)?;
+        let limits = config.pool;
+        {
+            let mut connections = self.connections.lock().unwrap();
+            let pooled = connections.entry(key.clone()).or_default();
+            pooled.retain(|pooled| !pooled.handle.is_closed());
+            if let Some(pooled) = pooled
+                .iter_mut()
+                .find(|pooled| pooled.channels < limits.max_channels)
+            {
+                debug!("Reusing connection to {} as {}", entry.name, key.user);
+                pooled.channels += 1;
+                return Ok(Upstream::Shared(Lease {
+                    handle: pooled.handle.clone(),
+                    key,
+                    pool: self.clone(),
+                }));
+            }
+        }
+
+        let handle = upstream::connect(entry, user, config, None, None).await?;
+        let mut connections = self.connections.lock().unwrap();
+        let pooled = connections.entry(key.clone()).or_default();
+        if pooled.len() >= limits.max_connections {
+            debug!(
+                "Not sharing connection to {}, {} already are",
+                entry.name,
+                pooled.len()
+            );
+            return Ok(Upstream::Owned(handle));
+        }
+        let handle = Arc::new(handle);
+        pooled.push(Pooled {
+            handle: handle.clone(),
+            channels: 1,
+            idle_since: Instant::now(),
+        });
+        Ok(Upstream::Shared(Lease {
+            handle,
+            key,
+            pool: self.clone(),
+        }))
+    }
+
+    /// Disconnects connections left without channels for longer than the configured idle
+    /// timeout, or any without channels once pooling is disabled.
+    pub async fn run(self: Arc<Self>, config: ConfigReceiver) {
+        let mut ticks = tokio::time::interval(EVICT_INTERVAL);
+        loop {
+            ticks.tick().await;
+            let limits = config.borrow().pool;
+            let mut idle = Vec::new();
+            self.connections.lock().unwrap().retain(|key, pooled| {
+                pooled.retain(|pooled| {
+                    let timed_out = pooled.idle_since.elapsed() >= limits.idle_timeout;
+                    let expired = pooled.channels == 0 && (timed_out || !limits.enabled);
+                    if expired {
+                        debug!("Closing idle connection to {} as {}", key.server, key.user);
+                        idle.push(pooled.handle.clone());
+                    }
+                    !expired && !pooled.handle.is_closed()
+                });
+                !pooled.is_empty()
+            });
+            for handle in idle {
+                let _ = handle.disconnect(Disconnect::ByApplication, "", "").await;
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+#[cfg(unix)]
+use std::ffi::CString;
+#[cfg(unix)]
+use std::io;
+use std::path::Path;
+
+#[cfg(unix)]
+use anyhow::{Context, bail};
+#[cfg(unix)]
+use tracing::info;
+
+use crate::config::PrivilegesConfig;
+
+/// Switches to the configured user and group, first changing root to `state_directory`
+/// if `chroot` is set. Called once every privileged socket is bound; does nothing without a
+/// user.
+#[cfg(unix)]
+pub fn drop_privileges(config: &PrivilegesConfig, state_directory: &Path) -> anyhow::Result<()> {
+    let Some(user) = &config.user else {
+        return Ok(());
+    };
+    // Names are looked up before changing root, which hides /etc/passwd and /etc/group.
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match &config.group {
+        Some(group) => lookup_group(group)?,
+        None => primary_gid,
+    };
+
+    // SAFETY: geteuid has no preconditions.
+    let euid = unsafe { libc::geteuid() };
+    if euid == uid && !config.chroot {
+        return Ok(());
+    }
+    if euid != 0 {
+        bail!("pukeko must be started as root to switch to user {user}");
+    }
+
+    if config.chroot {
+        std::os::unix::fs::chroot(state_directory)
+            .with_context(|| format!("Failed to change root to {}", state_directory.display()))?;
+        std::env::set_current_dir("/")?;
+    }
+    // SAFETY: the group list is a single valid gid, and the calls have no other
+    // preconditions. glibc and musl apply them to every thread of the process.
+    unsafe {
+        check(libc::setgroups(1, &gid)).context("Failed to set supplementary groups")?;
+        check(libc::setgid(gid)).with_context(|| format!("Failed to switch to group {gid}"))?;
+        check(libc::setuid(uid)).with_context(|| format!("Failed to switch to user {user}"))?;
+        if libc::setuid(0) == 0 {
+            bail!("Could regain root after switching to user {user}");
+        }
+    }
+
+    if config.chroot {
+        info!(
+            "Running as {} ({}:{}) in {}",
+            user,
+            uid,
+            gid,
+            state_directory.display()
+        );
+    } else {
+        info!("Running as {} ({}:{})", user, uid, gid);
+    }
+    Ok(())
+}
+
+/// Off Unix there is nothing to switch to; the config refuses a user.
+#[cfg(not(unix))]
+pub fn drop_privileges(_config: &PrivilegesConfig, _state_directory: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Big enough for the entries of any reasonable passwd or group file.
+#[cfg(unix)]
+const LOOKUP_BUFFER: usize = 16 * 1024;
+
+/// The uid and primary gid of the user `name`.
+#[cfg(unix)]
+fn lookup_user(name: &str) -> anyhow::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = CString::new(name)?;
+    let mut buffer = vec![0; LOOKUP_BUFFER];
+    // SAFETY: passwd is plain data that getpwnam_r fills in.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: every pointer is valid for the duration of the call, and the buffer length
+    // matches the buffer.
+    let error = unsafe {
+        libc::getpwnam_r(
+            c_name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 {
+        return Err(io::Error::from_raw_os_error(error))
+            .with_context(|| format!("Failed to look up user {name}"));
+    }
+    if result.is_null() {
+        bail!("No user named {name}");
+    }
+    Ok((passwd.pw_uid, passwd.pw_gid))
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> anyhow::Result<libc::gid_t> {
+    let c_name = CString::new(name)?;
+    let mut buffer = vec![0; LOOKUP_BUFFER];
+    // SAFETY: group is plain data that getgrnam_r fills in.
+    let mut group: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: as for getpwnam_r above.
+    let error = unsafe {
+        libc::getgrnam_r(
+            c_name.as_ptr(),
+            &mut group,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if error != 0 {
+        return Err(io::Error::from_raw_os_error(error))
+            .with_context(|| format!("Failed to look up group {name}"));
+    }
+    if result.is_null() {
+        bail!("No group named {name}");
+    }
+    Ok(group.gr_gid)
+}
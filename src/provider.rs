@@ -0,0 +1,460 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::Utc;
+use russh::keys::ssh_key::AuthorizedKeys;
+use russh::keys::{Certificate, PublicKey};
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use crate::cert;
+use crate::config::{ConfigReceiver, ServerEntry};
+use crate::inventory::Inventory;
+use crate::ldap::LdapProvider;
+use crate::password;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Passwords checked at once, across every realm. Each argon2 check takes tens of
+/// milliseconds and 19 MiB, so a burst of attempts waits here rather than filling the
+/// blocking pool.
+static PASSWORD_CHECKS: Semaphore = Semaphore::const_new(4);
+
+/// Who a key belongs to.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Identity {
+    /// The user the session acts as when checking access to servers.
+    pub user: String,
+    /// Extra details about the key, recorded in the audit log.
+    pub attributes: BTreeMap<String, String>,
+}
+
+impl Identity {
+    pub fn new(user: impl Into<String>) -> Self {
+        Self {
+            user: user.into(),
+            attributes: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthDecision {
+    Accept(Identity),
+    Reject,
+}
+
+/// Decides which keys users may log in with.
+///
+/// Errors reject the attempt, the same as [`AuthDecision::Reject`], but are logged.
+pub trait AuthProvider: Send + Sync {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        public_key: &'a PublicKey,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>>;
+
+    /// Finds the user `public_key` belongs to, for logins that name only a server such as
+    /// `ssh server@bastion`. Rejects by default, so those logins are refused.
+    fn identify<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let _ = public_key;
+        Box::pin(async { Ok(AuthDecision::Reject) })
+    }
+
+    /// Whether [`verify_password`](Self::verify_password) may accept passwords. Clients are
+    /// only offered password authentication when this is true as the server starts.
+    fn accepts_passwords(&self) -> bool {
+        false
+    }
+
+    /// Verifies a password for `user`. Rejects by default.
+    fn verify_password<'a>(
+        &'a self,
+        user: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let _ = (user, password);
+        Box::pin(async { Ok(AuthDecision::Reject) })
+    }
+
+    /// Whether [`verify_certificate`](Self::verify_certificate) may accept certificates.
+    /// Clients offer the key inside a certificate without it first, so while this is true
+    /// every offered key is let through to the signed attempt.
+    fn accepts_certificates(&self) -> bool {
+        false
+    }
+
+    /// Verifies an OpenSSH user certificate for `user` connecting from `peer`. The
+    /// certificate's own signature has been checked, but not who it was signed by.
+    fn verify_certificate<'a>(
+        &'a self,
+        user: &'a str,
+        certificate: &'a Certificate,
+        peer: Option<IpAddr>,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let _ = (user, certificate, peer);
+        Box::pin(async { Ok(AuthDecision::Reject) })
+    }
+}
+
+/// The inventory of upstream servers and who may reach them.
+pub trait ServerProvider: Send + Sync {
+    /// Servers listed in `user`'s menu, in display order.
+    fn servers(&self, user: &str) -> Vec<ServerEntry>;
+
+    fn server(&self, name: &str) -> Option<ServerEntry>;
+
+    fn can_access(&self, user: &str, server: &str) -> bool;
+
+    /// The config groups `user` is in from outside the config, such as those their
+    /// directory groups are mapped to. None by default.
+    fn groups(&self, _user: &str) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Every server that is health checked. None are by default.
+    fn inventory(&self) -> Vec<ServerEntry> {
+        Vec::new()
+    }
+
+    /// Servers `user` cannot access, which they may ask an admin for.
+    fn requestable(&self, user: &str) -> Vec<ServerEntry> {
+        self.inventory()
+            .into_iter()
+            .filter(|entry| !self.can_access(user, &entry.name))
+            .collect()
+    }
+}
+
+/// Users and servers from the config file, following reloads. Keys are looked up in the
+/// users' `keys` first and then in their `authorized_keys` file, if one is configured.
+/// Certificates are accepted when signed by one of `trusted_user_ca_keys`, or by one of
+/// `trusted_proxy_ca_keys` for a user the proxy they name is allowed in `proxies`. When
+/// `ldap` is configured, keys that are still not found are looked up in the directory last.
+#[derive(Debug, Clone)]
+pub struct ConfigProvider {
+    config: ConfigReceiver,
+    inventory: Option<Arc<Inventory>>,
+    ldap: Arc<LdapProvider>,
+}
+
+impl ConfigProvider {
+    pub fn new(config: ConfigReceiver) -> Self {
+        Self {
+            ldap: Arc::new(LdapProvider::new(config.clone())),
+            config,
+            inventory: None,
+        }
+    }
+
+    /// Also lists the servers from `inventory`, after those in the config which take
+    /// precedence when names clash. Access to them is granted the same way.
+    pub fn with_inventory(mut self, inventory: Arc<Inventory>) -> Self {
+        self.inventory = Some(inventory);
+        self
+    }
+
+    fn all_servers(&self) -> Vec<ServerEntry> {
+        let mut servers = self.config.borrow().servers.clone();
+        if let Some(inventory) = &self.inventory {
+            let mut names: HashSet<String> =
+                servers.iter().map(|entry| entry.name.clone()).collect();
+            servers.extend(
+                inventory
+                    .servers()
+                    .into_iter()
+                    .filter(|entry| names.insert(entry.name.clone())),
+            );
+        }
+        servers
+    }
+}
+
+impl AuthProvider for ConfigProvider {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        public_key: &'a PublicKey,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let (on_file, authorized_keys, ldap) = {
+            let config = self.config.borrow();
+            // Expired keys are rejected rather than looked up elsewhere.
+            let on_file = config
+                .user(user)
+                .and_then(|entry| entry.key(public_key))
+                .map(|key| !config.key_expiry.is_expired(key, Utc::now()));
+            (
+                on_file,
+                config.authorized_keys.clone(),
+                config.ldap.is_some(),
+            )
+        };
+
+        Box::pin(async move {
+            match on_file {
+                Some(true) => return Ok(AuthDecision::Accept(Identity::new(user))),
+                Some(false) => return Ok(AuthDecision::Reject),
+                None => {}
+            }
+            if let Some(template) = authorized_keys {
+                let decision = AuthorizedKeysProvider::new(template)
+                    .verify(user, public_key)
+                    .await?;
+                if decision != AuthDecision::Reject || !ldap {
+                    return Ok(decision);
+                }
+            }
+            if ldap {
+                return self.ldap.verify(user, public_key).await;
+            }
+            Ok(AuthDecision::Reject)
+        })
+    }
+
+    /// Only the users' `keys` are searched. Keys listed for more than one user are
+    /// rejected, since it is unclear who is logging in.
+    fn identify<'a>(
+        &'a self,
+        public_key: &'a PublicKey,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let config = self.config.borrow();
+        let now = Utc::now();
+        let owners: Vec<String> = config
+            .users
+            .iter()
+            .filter(|user| {
+                user.key(public_key)
+                    .is_some_and(|key| !config.key_expiry.is_expired(key, now))
+            })
+            .map(|user| user.name.clone())
+            .collect();
+        drop(config);
+
+        Box::pin(async move {
+            match owners.as_slice() {
+                [user] => Ok(AuthDecision::Accept(Identity::new(user))),
+                [] => Ok(AuthDecision::Reject),
+                _ => {
+                    debug!("Key belongs to several users: {}", owners.join(", "));
+                    Ok(AuthDecision::Reject)
+                }
+            }
+        })
+    }
+
+    fn accepts_passwords(&self) -> bool {
+        self.config
+            .borrow()
+            .users
+            .iter()
+            .any(|user| user.password_hash.is_some())
+    }
+
+    fn verify_password<'a>(
+        &'a self,
+        user: &'a str,
+        password: &'a str,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let hash = self
+            .config
+            .borrow()
+            .user(user)
+            .and_then(|entry| entry.password_hash.clone());
+        let password = password.to_string();
+
+        Box::pin(async move {
+            let Some(hash) = hash else {
+                return Ok(AuthDecision::Reject);
+            };
+            let _permit = PASSWORD_CHECKS.acquire().await?;
+            let verified =
+                tokio::task::spawn_blocking(move || password::verify(&hash, &password)).await?;
+            if verified {
+                Ok(AuthDecision::Accept(Identity::new(user)))
+            } else {
+                Ok(AuthDecision::Reject)
+            }
+        })
+    }
+
+    fn accepts_certificates(&self) -> bool {
+        let config = self.config.borrow();
+        !config.trusted_user_ca_keys.is_empty() || !config.trusted_proxy_ca_keys.is_empty()
+    }
+
+    fn verify_certificate<'a>(
+        &'a self,
+        user: &'a str,
+        certificate: &'a Certificate,
+        peer: Option<IpAddr>,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        let (authorities, proxy_authorities, proxies) = {
+            let config = self.config.borrow();
+            (
+                config.trusted_user_ca_keys.clone(),
+                config.trusted_proxy_ca_keys.clone(),
+                config.proxies.clone(),
+            )
+        };
+        Box::pin(async move {
+            // Certificates naming the user a proxy connects for are only taken from proxies.
+            let proxied = certificate.extensions().contains_key(cert::ORIGINAL_USER);
+            let checked = if proxied {
+                cert::validate_proxy_certificate(
+                    certificate,
+                    user,
+                    peer,
+                    &proxy_authorities,
+                    &proxies,
+                )
+                .map(Some)
+            } else {
+                cert::validate_user_certificate(certificate, user, peer, &authorities).map(|_| None)
+            };
+            let proxy = match checked {
+                Ok(proxy) => proxy,
+                Err(e) => {
+                    debug!("Rejecting certificate for {}: {:#}", user, e);
+                    return Ok(AuthDecision::Reject);
+                }
+            };
+
+            let mut identity = Identity::new(user)
+                .with_attribute("key_id", certificate.key_id())
+                .with_attribute("serial", certificate.serial().to_string())
+                .with_attribute(
+                    "ca_fingerprint",
+                    certificate
+                        .signature_key()
+                        .fingerprint(Default::default())
+                        .to_string(),
+                );
+            if let Some(proxy) = proxy {
+                identity = identity.with_attribute("proxy", proxy);
+                if let Some(address) = certificate.extensions().get(cert::ORIGINAL_ADDRESS) {
+                    identity = identity.with_attribute("original_address", address);
+                }
+            }
+            Ok(AuthDecision::Accept(identity))
+        })
+    }
+}
+
+impl ServerProvider for ConfigProvider {
+    fn servers(&self, user: &str) -> Vec<ServerEntry> {
+        let groups = self.ldap.groups(user);
+        let servers = self.all_servers();
+        let config = self.config.borrow();
+        servers
+            .into_iter()
+            .filter(|entry| config.can_access_server_with_groups(user, &groups, entry))
+            .collect()
+    }
+
+    fn server(&self, name: &str) -> Option<ServerEntry> {
+        let server = self.config.borrow().server(name).cloned();
+        server.or_else(|| self.inventory.as_ref()?.server(name))
+    }
+
+    fn can_access(&self, user: &str, server: &str) -> bool {
+        let groups = self.ldap.groups(user);
+        self.server(server).is_some_and(|entry| {
+            self.config
+                .borrow()
+                .can_access_server_with_groups(user, &groups, &entry)
+        })
+    }
+
+    fn groups(&self, user: &str) -> Vec<String> {
+        self.ldap.groups(user)
+    }
+
+    fn inventory(&self) -> Vec<ServerEntry> {
+        self.all_servers()
+    }
+}
+
+/// Accepts keys listed in per-user OpenSSH authorized_keys files.
+///
+/// `%u` in the path is replaced with the user name, as with sshd's `AuthorizedKeysFile`.
+/// Key options are not enforced, so entries that have any are skipped.
+#[derive(Debug, Clone)]
+pub struct AuthorizedKeysProvider {
+    template: String,
+}
+
+impl AuthorizedKeysProvider {
+    pub fn new(template: impl Into<String>) -> Self {
+        Self {
+            template: template.into(),
+        }
+    }
+
+    fn path(&self, user: &str) -> Option<PathBuf> {
+        let valid = !user.is_empty()
+            && !user.starts_with('.')
+            && user
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+        valid.then(|| PathBuf::from(self.template.replace("%u", user)))
+    }
+}
+
+impl AuthProvider for AuthorizedKeysProvider {
+    fn verify<'a>(
+        &'a self,
+        user: &'a str,
+        public_key: &'a PublicKey,
+    ) -> BoxFuture<'a, anyhow::Result<AuthDecision>> {
+        Box::pin(async move {
+            let Some(path) = self.path(user) else {
+                return Ok(AuthDecision::Reject);
+            };
+            let contents = match tokio::fs::read_to_string(&path).await {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == ErrorKind::NotFound => return Ok(AuthDecision::Reject),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {}", path.display()));
+                }
+            };
+
+            for entry in AuthorizedKeys::new(&contents) {
+                let entry = entry.with_context(|| format!("Invalid key in {}", path.display()))?;
+                if entry.public_key().key_data() != public_key.key_data() {
+                    continue;
+                }
+                if !entry.config_opts().is_empty() {
+                    debug!(
+                        "Skipping key for {} with unsupported options in {}",
+                        user,
+                        path.display()
+                    );
+                    continue;
+                }
+
+                let mut identity = Identity::new(user)
+                    .with_attribute("authorized_keys", path.display().to_string());
+                let comment = entry.public_key().comment();
+                if !comment.is_empty() {
+                    identity = identity.with_attribute("comment", comment);
+                }
+                return Ok(AuthDecision::Accept(identity));
+            }
+
+            Ok(AuthDecision::Reject)
+        })
+    }
+}
@@ -0,0 +1,212 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{Context, bail};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest v1 header allowed by the specification, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header, returning the original client address.
+///
+/// Returns `None` for headers that carry no address, such as health checks sent with the
+/// v2 LOCAL command. Exactly the header is consumed, leaving the SSH stream untouched.
+pub async fn read_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> anyhow::Result<Option<SocketAddr>> {
+    let mut prefix = [0; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if &prefix == b"PROXY" {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        bail!("Connection did not start with a PROXY protocol header")
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            bail!("PROXY protocol v1 header is too long");
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .context("PROXY protocol v1 header is not valid UTF-8")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip: IpAddr = source.parse().context("Invalid PROXY source address")?;
+            let port: u16 = source_port.parse().context("Invalid PROXY source port")?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("Malformed PROXY protocol v1 header {:?}", line),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> anyhow::Result<Option<SocketAddr>> {
+    let mut rest = [0; 11];
+    stream.read_exact(&mut rest).await?;
+    if rest[..7] != V2_SIGNATURE[5..] {
+        bail!("Invalid PROXY protocol v2 signature");
+    }
+
+    let version_command = rest[7];
+    let family = rest[8];
+    let length = u16::from_be_bytes([rest[9], rest[10]]);
+    if version_command >> 4 != 2 {
+        bail!(
+            "Unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+
+    let mut addresses = vec![0; length as usize];
+    stream.read_exact(&mut addresses).await?;
+
+    // The LOCAL command is sent by the proxy itself and carries no client address.
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[0..4])?);
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[0..16])?);
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // Unix sockets and unspecified families have no IP to report.
+        0x0 | 0x3 => Ok(None),
+        _ => bail!("Malformed PROXY protocol v2 address block"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads a header from `input`, returning it with what was left unread.
+    async fn read(input: &[u8]) -> (anyhow::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut stream = input;
+        let header = read_header(&mut stream).await;
+        (header, stream.to_vec())
+    }
+
+    /// A v2 header with `command`, `family` and `addresses`, claiming `length` bytes of them.
+    fn v2(command: u8, family: u8, length: u16, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend(length.to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_headers_give_the_source_address() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 22\r\nSSH-2.0").await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"SSH-2.0");
+
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 22\r\n").await;
+        assert_eq!(
+            header.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        let (header, rest) = read(b"PROXY UNKNOWN\r\nSSH-2.0").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"SSH-2.0");
+    }
+
+    #[tokio::test]
+    async fn malformed_v1_headers_are_refused() {
+        assert!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324")
+                .await
+                .0
+                .is_err()
+        );
+        assert!(read(b"PROXY TCP4 192.0.2.1 56324 22\r\n").await.0.is_err());
+        assert!(
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 22\r\n")
+                .await
+                .0
+                .is_err()
+        );
+        assert!(
+            read(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 22\r\n")
+                .await
+                .0
+                .is_err()
+        );
+        // Longer than the specification allows, even if a CRLF follows.
+        let mut long = b"PROXY TCP6 ".to_vec();
+        long.extend([b'0'; V1_MAX_LENGTH]);
+        long.extend(b"\r\n");
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_headers_give_the_source_address() {
+        let mut ipv4 = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        ipv4.extend(56324u16.to_be_bytes());
+        ipv4.extend(22u16.to_be_bytes());
+        let (header, rest) = read(&[v2(1, 0x11, 12, &ipv4), b"SSH-2.0".to_vec()].concat()).await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"SSH-2.0");
+
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut ipv6 = source.octets().to_vec();
+        ipv6.extend([0; 16]);
+        ipv6.extend(56324u16.to_be_bytes());
+        ipv6.extend(22u16.to_be_bytes());
+        // Trailing TLVs are read past along with the addresses.
+        ipv6.extend([0x04, 0, 1, 0]);
+        let (header, rest) = read(&[v2(1, 0x21, 40, &ipv6), b"SSH-2.0".to_vec()].concat()).await;
+        assert_eq!(
+            header.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+        assert_eq!(rest, b"SSH-2.0");
+    }
+
+    #[tokio::test]
+    async fn v2_headers_without_an_ip_give_no_address() {
+        // LOCAL, whatever the family.
+        let (header, rest) = read(&[v2(0, 0x11, 12, &[0; 12]), b"SSH-2.0".to_vec()].concat()).await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"SSH-2.0");
+        // UNSPEC and Unix sockets.
+        assert_eq!(read(&v2(1, 0x00, 0, &[])).await.0.unwrap(), None);
+        assert_eq!(read(&v2(1, 0x31, 216, &[0; 216])).await.0.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_v2_headers_are_refused() {
+        // Truncated in the signature, and in the addresses.
+        assert!(read(&V2_SIGNATURE[..8]).await.0.is_err());
+        assert!(read(&v2(1, 0x11, 12, &[192, 0, 2, 1])).await.0.is_err());
+        // A length longer than the connection sends.
+        assert!(read(&v2(1, 0x11, u16::MAX, &[0; 12])).await.0.is_err());
+        // Too short for the family, and an unknown family.
+        assert!(read(&v2(1, 0x11, 4, &[192, 0, 2, 1])).await.0.is_err());
+        assert!(read(&v2(1, 0x41, 12, &[0; 12])).await.0.is_err());
+        let mut version_one = v2(1, 0x11, 12, &[0; 12]);
+        version_one[12] = 0x11;
+        assert!(read(&version_one).await.0.is_err());
+        let mut signature = v2(1, 0x11, 12, &[0; 12]);
+        signature[10] = b'X';
+        assert!(read(&signature).await.0.is_err());
+        assert!(read(b"SSH-2.0-OpenSSH").await.0.is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Per-user quotas on forwards: how many may be open at once, how many to one server, and
+//! how long a day the user may spend connected to servers. In a cluster the forwards open
+//! on other nodes count too, while the time is counted by each node.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use chrono::NaiveDate;
+
+use crate::config::QuotaConfig;
+use crate::messages::{self, Messages};
+
+/// The forwards each user has open, and the time they have spent connected to servers
+/// today. Jumps count as forwards.
+#[derive(Debug, Default)]
+pub struct Quotas {
+    users: Mutex<HashMap<String, Usage>>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// The session and server of each open forward, by its permit.
+    open: HashMap<u64, (usize, String)>,
+    next: u64,
+    /// The day in UTC `used` was counted on.
+    day: Option<NaiveDate>,
+    /// Time connected today before the forwards open now were.
+    used: Duration,
+    /// Since when a forward has been open, while any is.
+    active_since: Option<Instant>,
+    /// The servers the user's sessions on other nodes of the cluster forward to.
+    remote: Vec<String>,
+}
+
+impl Usage {
+    /// Starts counting again on a new day.
+    fn roll(&mut self, now: Instant) {
+        let today = chrono::Utc::now().date_naive();
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.used = Duration::ZERO;
+            if self.active_since.is_some() {
+                self.active_since = Some(now);
+            }
+        }
+    }
+
+    fn today(&self, now: Instant) -> Duration {
+        self.used
+            + self
+                .active_since
+                .map_or(Duration::ZERO, |since| now - since)
+    }
+
+    fn forwards(&self) -> usize {
+        self.open.len() + self.remote.len()
+    }
+
+    fn to_server(&self, server: &str) -> usize {
+        let local = self
+            .open
+            .values()
+            .filter(|(_, open)| open == server)
+            .count();
+        local + self.remote.iter().filter(|open| *open == server).count()
+    }
+}
+
+/// What a user has used, shown to admins.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub forwards: usize,
+    pub minutes_today: u64,
+}
+
+/// A quota a forward would have gone over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QuotaExceeded {
+    Forwards { max: usize },
+    ForwardsToServer { server: String, max: usize },
+    DailyMinutes { max: u64 },
+}
+
+impl QuotaExceeded {
+    /// The setting that was exceeded, for the audit log.
+    pub fn quota(&self) -> &'static str {
+        match self {
+            Self::Forwards { .. } => "max_forwards",
+            Self::ForwardsToServer { .. } => "max_forwards_per_server",
+            Self::DailyMinutes { .. } => "daily_minutes",
+        }
+    }
+
+    /// What to tell the user.
+    pub fn message(&self, messages: &Messages) -> String {
+        match self {
+            Self::Forwards { max } => messages::fill(&messages.quota_forwards, &[("max", max)]),
+            Self::ForwardsToServer { server, max } => messages::fill(
+                &messages.quota_forwards_to_server,
+                &[("server", server), ("max", max)],
+            ),
+            Self::DailyMinutes { max } => {
+                messages::fill(&messages.quota_daily_minutes, &[("minutes", max)])
+            }
+        }
+    }
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forwards { max } => write!(f, "{max} forwards are open already"),
+            Self::ForwardsToServer { server, max } => {
+                write!(f, "{max} forwards to {server} are open already")
+            }
+            Self::DailyMinutes { max } => write!(f, "the {max} minutes for today are used up"),
+        }
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// Counts a forward towards its user's quotas until dropped.
+#[derive(Debug)]
+pub struct QuotaPermit {
+    quotas: Arc<Quotas>,
+    user: String,
+    id: u64,
+}
+
+impl Quotas {
+    /// Counts a forward of `session` by `user` to `server`, unless it would go over one of
+    /// `quotas`.
+    pub fn acquire(
+        self: &Arc<Self>,
+        session: usize,
+        user: &str,
+        server: &str,
+        quotas: &QuotaConfig,
+    ) -> Result<QuotaPermit, QuotaExceeded> {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+        let usage = users.entry(user.to_string()).or_default();
+        usage.roll(now);
+
+        if let Some(max) = quotas.daily_minutes
+            && usage.today(now) >= Duration::from_secs(max.saturating_mul(60))
+        {
+            return Err(QuotaExceeded::DailyMinutes { max });
+        }
+        if let Some(max) = quotas.max_forwards
+            && usage.forwards() >= max
+        {
+            return Err(QuotaExceeded::Forwards { max });
+        }
+        if let Some(max) = quotas.max_forwards_per_server
+            && usage.to_server(server) >= max
+        {
+            return Err(QuotaExceeded::ForwardsToServer {
+                server: server.to_string(),
+                max,
+            });
+        }
+
+        let id = usage.next;
+        usage.next += 1;
+        usage.open.insert(id, (session, server.to_string()));
+        usage.active_since.get_or_insert(now);
+        Ok(QuotaPermit {
+            quotas: self.clone(),
+            user: user.to_string(),
+            id,
+        })
+    }
+
+    pub fn usage(&self, user: &str) -> QuotaUsage {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+        let Some(usage) = users.get_mut(user) else {
+            return QuotaUsage::default();
+        };
+        usage.roll(now);
+        QuotaUsage {
+            forwards: usage.forwards(),
+            minutes_today: usage.today(now).as_secs() / 60,
+        }
+    }
+
+    /// Replaces the forwards of each user's sessions on other nodes of the cluster, by the
+    /// servers they forward to.
+    pub fn set_remote(&self, mut forwards: HashMap<String, Vec<String>>) {
+        let mut users = self.users.lock().unwrap();
+        for (user, usage) in users.iter_mut() {
+            usage.remote = forwards.remove(user).unwrap_or_default();
+        }
+        for (user, remote) in forwards {
+            users.entry(user).or_default().remote = remote;
+        }
+    }
+
+    /// The sessions with forwards open for users who have used up their daily minutes,
+    /// as given by `quotas`, with each user and their minutes.
+    pub fn out_of_time(&self, quotas: impl Fn(&str) -> QuotaConfig) -> Vec<(usize, String, u64)> {
+        let now = Instant::now();
+        let mut users = self.users.lock().unwrap();
+        let mut sessions = Vec::new();
+        for (user, usage) in users.iter_mut() {
+            if usage.open.is_empty() {
+                continue;
+            }
+            usage.roll(now);
+            let Some(max) = quotas(user).daily_minutes else {
+                continue;
+            };
+            if usage.today(now) < Duration::from_secs(max.saturating_mul(60)) {
+                continue;
+            }
+            let mut open: Vec<usize> = usage.open.values().map(|(session, _)| *session).collect();
+            open.sort_unstable();
+            open.dedup();
+            sessions.extend(open.into_iter().map(|session| (session, user.clone(), max)));
+        }
+        sessions
+    }
+}
+
+impl Drop for QuotaPermit {
+    fn drop(&mut self) {
+        let now = Instant::now();
+        let mut users = self.quotas.users.lock().unwrap();
+        let Some(usage) = users.get_mut(&self.user) else {
+            return;
+        };
+        usage.roll(now);
+        usage.open.remove(&self.id);
+        if usage.open.is_empty()
+            && let Some(since) = usage.active_since.take()
+        {
+            usage.used += now - since;
+        }
+    }
+}
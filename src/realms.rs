@@ -0,0 +1,129 @@
+//! Realms: separate sets of users and servers served by one deployment. Each connection
+//! logs in to one, by its listener or the `@realm` suffix of its user, and from then on
+//! sees only the realm's config, providers and audit log.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use tokio::sync::watch;
+
+use crate::audit::AuditLog;
+use crate::config::{ConfigReceiver, PukekoConfig, RealmConfig};
+use crate::provider::{AuthProvider, ConfigProvider, ServerProvider};
+
+/// What a connection logs in with, the deployment's or a realm's.
+pub struct Realm {
+    /// None outside any realm.
+    pub name: Option<String>,
+    pub config: ConfigReceiver,
+    pub auth: Arc<dyn AuthProvider>,
+    pub servers: Arc<dyn ServerProvider>,
+    pub audit: Arc<AuditLog>,
+    /// Sends a realm its part of each reloaded config.
+    updates: Option<watch::Sender<Arc<PukekoConfig>>>,
+}
+
+impl Realm {
+    /// The deployment's own users and servers, outside any realm.
+    pub fn deployment(
+        config: ConfigReceiver,
+        auth: Arc<dyn AuthProvider>,
+        servers: Arc<dyn ServerProvider>,
+        audit: Arc<AuditLog>,
+    ) -> Self {
+        Self {
+            name: None,
+            config,
+            auth,
+            servers,
+            audit,
+            updates: None,
+        }
+    }
+
+    /// A realm of the deployment whose audit log is `deployment_audit`, which it also
+    /// records its events to.
+    fn new(realm: &RealmConfig, deployment_audit: Arc<AuditLog>) -> Self {
+        let (updates, config) = watch::channel(realm.config.clone());
+        let provider = Arc::new(ConfigProvider::new(config.clone()));
+        Self {
+            name: Some(realm.name.clone()),
+            config,
+            auth: provider.clone(),
+            servers: provider,
+            audit: Arc::new(AuditLog::within(deployment_audit)),
+            updates: Some(updates),
+        }
+    }
+}
+
+/// The deployment and its configured realms, following reloads.
+pub struct Realms {
+    deployment: Arc<Realm>,
+    realms: RwLock<HashMap<String, Arc<Realm>>>,
+}
+
+impl Realms {
+    pub fn new(deployment: Realm) -> Self {
+        Self {
+            deployment: Arc::new(deployment),
+            realms: RwLock::default(),
+        }
+    }
+
+    /// Sets up the realms in `config`, passing those already set up their new config and
+    /// reopening their audit logs. Connections to realms no longer configured keep the
+    /// config they had.
+    pub fn configure(&self, config: &PukekoConfig) -> anyhow::Result<()> {
+        let previous = self.realms.read().unwrap().clone();
+        let mut realms = HashMap::new();
+        for realm in &config.realms {
+            let entry = match previous.get(&realm.name) {
+                Some(entry) => entry.clone(),
+                None => Arc::new(Realm::new(realm, self.deployment.audit.clone())),
+            };
+            entry.audit.configure(&realm.config.audit)?;
+            if let Some(updates) = &entry.updates {
+                updates.send_replace(realm.config.clone());
+            }
+            realms.insert(realm.name.clone(), entry);
+        }
+        *self.realms.write().unwrap() = realms;
+        Ok(())
+    }
+
+    /// The realm `login` logs in to, with the login's user named as in the realm. Logins
+    /// to a listener set to `listener` are in that realm whether or not their user has
+    /// its suffix, and others are in the realm their user's suffix names, if any. None
+    /// when the listener's realm is no longer configured.
+    pub fn resolve(&self, listener: Option<&str>, login: &str) -> Option<(Arc<Realm>, String)> {
+        let realms = self.realms.read().unwrap();
+        let (user, target) = match login.split_once('+') {
+            Some((user, target)) => (user, Some(target)),
+            None => (login, None),
+        };
+        let Some(listener) = listener else {
+            let realm = user
+                .rsplit_once('@')
+                .and_then(|(_, name)| realms.get(name))
+                .unwrap_or(&self.deployment);
+            return Some((realm.clone(), login.to_string()));
+        };
+
+        let realm = realms.get(listener)?;
+        if in_realm(user, listener) {
+            return Some((realm.clone(), login.to_string()));
+        }
+        let login = match target {
+            Some(target) => format!("{user}@{listener}+{target}"),
+            None => format!("{user}@{listener}"),
+        };
+        Some((realm.clone(), login))
+    }
+}
+
+/// Whether `user` is in `realm`, named `user@realm`.
+pub fn in_realm(user: &str, realm: &str) -> bool {
+    user.strip_suffix(realm)
+        .is_some_and(|user| user.len() > 1 && user.ends_with('@'))
+}
@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+
+use anyhow::Context;
+use russh::server::Handle;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, debug, trace};
+
+use crate::sessions::{ChannelPermit, SessionBytes, SessionUsage};
+
+/// A port the bastion listens on for a client's `ssh -R`. Connections to it are sent back
+/// to the client over forwarded-tcpip channels.
+pub(crate) struct RemoteForward {
+    pub port: u32,
+    task: JoinHandle<()>,
+}
+
+impl RemoteForward {
+    /// Listens on `bind_address`, which the client asked for as `address`. With port 0 the
+    /// system picks one, which is returned in [`port`](Self::port).
+    pub async fn listen(
+        handle: Handle,
+        address: &str,
+        bind_address: &str,
+        port: u32,
+        bytes: Arc<SessionBytes>,
+        usage: Arc<SessionUsage>,
+    ) -> anyhow::Result<Self> {
+        let port = u16::try_from(port).context("Port out of range")?;
+        let listener = TcpListener::bind((bind_address, port))
+            .await
+            .with_context(|| format!("Failed to listen on {bind_address}:{port}"))?;
+        let port = u32::from(listener.local_addr()?.port());
+
+        let address = address.to_string();
+        let task = tokio::spawn(
+            async move {
+                loop {
+                    let (stream, peer) = match listener.accept().await {
+                        Ok(accepted) => accepted,
+                        Err(e) => {
+                            debug!("Stopped accepting on port {}: {:?}", port, e);
+                            return;
+                        }
+                    };
+                    let Some(permit) = usage.open_channel() else {
+                        debug!(
+                            "Refusing {} on port {}, too many channels are open",
+                            peer, port
+                        );
+                        continue;
+                    };
+                    trace!("Forwarding {} from port {}", peer, port);
+                    tokio::spawn(
+                        relay(
+                            handle.clone(),
+                            address.clone(),
+                            port,
+                            stream,
+                            peer,
+                            bytes.clone(),
+                            permit,
+                        )
+                        .in_current_span(),
+                    );
+                }
+            }
+            .in_current_span(),
+        );
+
+        Ok(Self { port, task })
+    }
+}
+
+impl Drop for RemoteForward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn relay(
+    handle: Handle,
+    address: String,
+    port: u32,
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    bytes: Arc<SessionBytes>,
+    // Held until the forwarded connection closes.
+    _permit: ChannelPermit,
+) {
+    let channel = match handle
+        .channel_open_forwarded_tcpip(address, port, peer.ip().to_string(), u32::from(peer.port()))
+        .await
+    {
+        Ok(channel) => channel,
+        Err(e) => {
+            debug!("Client refused forwarded connection: {:?}", e);
+            return;
+        }
+    };
+
+    let mut channel = channel.into_stream();
+    match tokio::io::copy_bidirectional(&mut stream, &mut channel).await {
+        Ok((sent, received)) => {
+            bytes.down.fetch_add(sent, Ordering::Relaxed);
+            bytes.up.fetch_add(received, Ordering::Relaxed);
+            trace!("Forwarded connection from {} closed", peer);
+        }
+        Err(e) => debug!("Forwarded connection from {} failed: {:?}", peer, e),
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+use serde_json::Value;
+use termwiz::caps::Capabilities;
+use termwiz::input::{InputEvent, KeyCode, KeyEvent, Modifiers};
+use termwiz::terminal::{Terminal, UnixTerminal};
+
+/// How far the arrow keys seek.
+const SEEK_STEP: f64 = 5.0;
+const MIN_SPEED: f64 = 1.0 / 16.0;
+const MAX_SPEED: f64 = 16.0;
+
+/// Resets the terminal, so seeking back can replay the output from the start.
+const RESET: &str = "\x1bc";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayOptions {
+    pub speed: f64,
+    /// Longest pause between events, in seconds. Defaults to the recording's own
+    /// `idle_time_limit`, if it has one.
+    pub idle_limit: Option<f64>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            idle_limit: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Header {
+    version: u32,
+    width: Option<u16>,
+    height: Option<u16>,
+    term: Option<TermHeader>,
+    idle_time_limit: Option<f64>,
+}
+
+/// The terminal size in version 3 headers.
+#[derive(Debug, Deserialize)]
+struct TermHeader {
+    cols: u16,
+    rows: u16,
+}
+
+/// A recording's output, with the time each piece was written in seconds from the start.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    pub width: u16,
+    pub height: u16,
+    pub output: Vec<(f64, String)>,
+}
+
+impl Recording {
+    /// Parses an asciicast v2 or v3 file. Events other than output, such as input and
+    /// markers, are skipped, and pauses are shortened to `idle_limit` seconds when set.
+    pub fn parse(contents: &str, idle_limit: Option<f64>) -> anyhow::Result<Self> {
+        let mut lines = contents.lines().enumerate();
+        let header = lines.next().map(|(_, line)| line).unwrap_or_default();
+        let header: Header =
+            serde_json::from_str(header).context("Invalid asciicast header on line 1")?;
+        let (width, height) = match (header.version, header.term) {
+            (2, _) => (
+                header.width.unwrap_or_default(),
+                header.height.unwrap_or_default(),
+            ),
+            (3, Some(term)) => (term.cols, term.rows),
+            (3, None) => bail!("asciicast v3 header has no term"),
+            (version, _) => bail!("Unsupported asciicast version {version}"),
+        };
+        let idle_limit = idle_limit.or(header.idle_time_limit);
+
+        let mut output = Vec::new();
+        let (mut previous, mut time) = (0.0, 0.0);
+        for (i, line) in lines {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (at, kind, data): (f64, String, Value) = serde_json::from_str(line)
+                .with_context(|| format!("Invalid asciicast event on line {}", i + 1))?;
+            // Version 3 times events from the one before, version 2 from the start.
+            let interval = if header.version == 3 {
+                at
+            } else {
+                at - previous
+            };
+            previous = at;
+            time += match idle_limit {
+                Some(limit) => interval.clamp(0.0, limit),
+                None => interval.max(0.0),
+            };
+            if let ("o", Value::String(data)) = (kind.as_str(), data) {
+                output.push((time, data));
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            output,
+        })
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.output
+            .last()
+            .map(|&(time, _)| time)
+            .unwrap_or_default()
+    }
+}
+
+/// Plays the recording at `path` in the terminal until `q` is pressed, pausing at the end.
+/// Space pauses, `+` and `-` double and halve the speed, and the left and right arrows
+/// seek.
+pub fn play(path: &Path, options: &ReplayOptions) -> anyhow::Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    let recording = Recording::parse(&contents, options.idle_limit)
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+    let caps = Capabilities::new_from_env()?;
+    let mut terminal = UnixTerminal::new(caps).context("Failed to open the terminal")?;
+    let size = terminal.get_screen_size()?;
+    if size.cols < recording.width as usize || size.rows < recording.height as usize {
+        eprintln!(
+            "The recording is {}x{}, larger than this {}x{} terminal.",
+            recording.width, recording.height, size.cols, size.rows
+        );
+        std::thread::sleep(Duration::from_secs(2));
+    }
+    terminal.set_raw_mode()?;
+    terminal.enter_alternate_screen()?;
+    terminal.flush()?;
+
+    let mut player = Player::new(&recording, options.speed.clamp(MIN_SPEED, MAX_SPEED));
+    let result = player.run(&mut terminal);
+    print!("\x1b]2;\x07");
+    std::io::stdout().flush()?;
+    result
+}
+
+struct Player<'a> {
+    recording: &'a Recording,
+    out: std::io::Stdout,
+    /// The next output to write.
+    next: usize,
+    /// Where playback was when it last started, paused or changed speed.
+    position: f64,
+    started: Instant,
+    speed: f64,
+    paused: bool,
+}
+
+impl<'a> Player<'a> {
+    fn new(recording: &'a Recording, speed: f64) -> Self {
+        Self {
+            recording,
+            out: std::io::stdout(),
+            next: 0,
+            position: 0.0,
+            started: Instant::now(),
+            speed,
+            paused: false,
+        }
+    }
+
+    fn now(&self) -> f64 {
+        if self.paused {
+            self.position
+        } else {
+            self.position + self.started.elapsed().as_secs_f64() * self.speed
+        }
+    }
+
+    /// Restarts the clock from the current position, as the speed or pause changes.
+    fn rebase(&mut self) {
+        self.position = self.now().min(self.recording.duration());
+        self.started = Instant::now();
+    }
+
+    fn run(&mut self, terminal: &mut UnixTerminal) -> anyhow::Result<()> {
+        self.show_status()?;
+        loop {
+            let now = self.now();
+            self.write_until(now)?;
+
+            let wait = match self.recording.output.get(self.next) {
+                _ if self.paused => None,
+                Some(&(time, _)) => Some(Duration::from_secs_f64(
+                    ((time - now) / self.speed).max(0.0),
+                )),
+                // Stay on the last screen until it is seeked back or closed.
+                None => {
+                    self.position = self.recording.duration();
+                    self.paused = true;
+                    self.show_status()?;
+                    None
+                }
+            };
+            let Some(event) = terminal.poll_input(wait)? else {
+                continue;
+            };
+            let InputEvent::Key(KeyEvent { key, modifiers }) = event else {
+                continue;
+            };
+
+            match key {
+                KeyCode::Char('q') | KeyCode::Escape => return Ok(()),
+                KeyCode::Char('c') if modifiers.contains(Modifiers::CTRL) => return Ok(()),
+                KeyCode::Char(' ') => {
+                    self.rebase();
+                    self.paused = !self.paused;
+                }
+                KeyCode::Char('+') | KeyCode::Char('=') => {
+                    self.rebase();
+                    self.speed = (self.speed * 2.0).min(MAX_SPEED);
+                }
+                KeyCode::Char('-') => {
+                    self.rebase();
+                    self.speed = (self.speed / 2.0).max(MIN_SPEED);
+                }
+                KeyCode::RightArrow => self.seek(self.now() + SEEK_STEP)?,
+                KeyCode::LeftArrow => self.seek(self.now() - SEEK_STEP)?,
+                _ => continue,
+            }
+            self.show_status()?;
+        }
+    }
+
+    /// Writes the output up to `time`.
+    fn write_until(&mut self, time: f64) -> anyhow::Result<()> {
+        let start = self.next;
+        while let Some((at, data)) = self.recording.output.get(self.next) {
+            if *at > time {
+                break;
+            }
+            self.out.write_all(data.as_bytes())?;
+            self.next += 1;
+        }
+        if self.next != start {
+            self.out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Jumps to `time`. Going back clears the screen and writes the output again from the
+    /// start, as there is no way to undo it.
+    fn seek(&mut self, time: f64) -> anyhow::Result<()> {
+        let time = time.clamp(0.0, self.recording.duration());
+        if time < self.now() {
+            self.out.write_all(RESET.as_bytes())?;
+            self.next = 0;
+        }
+        self.write_until(time)?;
+        self.position = time;
+        self.started = Instant::now();
+        Ok(())
+    }
+
+    /// Shows the position and speed in the window title, out of the way of the recording.
+    fn show_status(&mut self) -> anyhow::Result<()> {
+        let clock = |seconds: f64| {
+            let seconds = seconds as u64;
+            format!("{:02}:{:02}", seconds / 60, seconds % 60)
+        };
+        let state = if self.paused { " paused" } else { "" };
+        write!(
+            self.out,
+            "\x1b]2;pukeko replay {} / {} x{}{}\x07",
+            clock(self.now()),
+            clock(self.recording.duration()),
+            self.speed,
+            state
+        )?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,175 @@
+use std::path::Path;
+
+use crate::config::SandboxConfig;
+
+/// Files outside the config read while serving: to resolve host names, look up the user
+/// to switch to, show local times and verify HTTPS certificates, and the libraries glibc
+/// loads for the first of those.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+const SYSTEM_PATHS: &[&str] = &[
+    "/etc/hosts",
+    "/etc/resolv.conf",
+    "/etc/nsswitch.conf",
+    "/etc/host.conf",
+    "/etc/gai.conf",
+    "/etc/services",
+    "/etc/passwd",
+    "/etc/group",
+    "/etc/localtime",
+    "/usr/share/zoneinfo",
+    "/etc/ssl",
+    "/etc/pki",
+    "/etc/ca-certificates",
+    "/usr/share/ca-certificates",
+    "/lib",
+    "/lib64",
+    "/usr/lib",
+    "/usr/lib64",
+];
+
+/// System calls a proxy never makes. Starting programs is the one that matters most; the
+/// rest change the system rather than the process.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_setuid,
+    libc::SYS_setgid,
+    libc::SYS_setreuid,
+    libc::SYS_setregid,
+    libc::SYS_setresuid,
+    libc::SYS_setresgid,
+    libc::SYS_setgroups,
+    libc::SYS_setfsuid,
+    libc::SYS_setfsgid,
+    libc::SYS_capset,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_acct,
+    libc::SYS_quotactl,
+    libc::SYS_settimeofday,
+    libc::SYS_clock_settime,
+    libc::SYS_clock_adjtime,
+    libc::SYS_adjtimex,
+    libc::SYS_sethostname,
+    libc::SYS_setdomainname,
+    libc::SYS_personality,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_fanotify_init,
+    libc::SYS_io_uring_setup,
+    libc::SYS_mknodat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mknod,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_iopl,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_ioperm,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_uselib,
+];
+
+/// Limits the files pukeko can open to the system's and those the config allows. Landlock
+/// only restricts the calling thread and the threads it starts later, so this is called
+/// before the runtime starts any.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub fn restrict_files(config: &SandboxConfig, state_directory: &Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use landlock::{
+        ABI, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+        path_beneath_rules,
+    };
+
+    if !config.landlock {
+        return Ok(());
+    }
+    // Allowed paths must exist, and the state directory is otherwise created on first use.
+    std::fs::create_dir_all(state_directory).with_context(|| {
+        format!(
+            "Failed to create state directory {}",
+            state_directory.display()
+        )
+    })?;
+
+    let abi = ABI::V5;
+    let read = AccessFs::ReadFile | AccessFs::ReadDir;
+    let write = AccessFs::from_all(abi) & !AccessFs::Execute;
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))?
+        .create()?
+        .add_rules(path_beneath_rules(SYSTEM_PATHS, read))?
+        .add_rules(path_beneath_rules(&config.read_paths, read))?
+        .add_rules(path_beneath_rules(
+            std::iter::once(state_directory).chain(config.write_paths.iter().map(|p| p.as_path())),
+            write,
+        ))?
+        .restrict_self()
+        .context("Failed to restrict files with Landlock")?;
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => tracing::info!("Restricted files with Landlock"),
+        RulesetStatus::PartiallyEnforced => {
+            tracing::info!("Restricted files with Landlock, as far as the kernel supports")
+        }
+        RulesetStatus::NotEnforced => {
+            tracing::warn!("The kernel does not support Landlock, files are not restricted")
+        }
+    }
+    Ok(())
+}
+
+/// Refuses the system calls pukeko never makes in every thread, once its sockets are bound
+/// and privileges dropped.
+#[cfg(all(feature = "sandbox", target_os = "linux"))]
+pub fn filter_syscalls(config: &SandboxConfig) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter, TargetArch};
+
+    if !config.seccomp {
+        return Ok(());
+    }
+    let filter = SeccompFilter::new(
+        DENIED_SYSCALLS
+            .iter()
+            .map(|&syscall| (syscall, Vec::new()))
+            .collect(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        TargetArch::try_from(std::env::consts::ARCH)?,
+    )?;
+    let program = BpfProgram::try_from(filter)?;
+    seccompiler::apply_filter_all_threads(&program)
+        .context("Failed to filter system calls with seccomp")?;
+    tracing::info!("Filtering system calls with seccomp");
+    Ok(())
+}
+
+/// Without the sandbox feature, or off Linux, neither can be enabled; the config refuses them.
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+pub fn restrict_files(_config: &SandboxConfig, _state_directory: &Path) -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(all(feature = "sandbox", target_os = "linux")))]
+pub fn filter_syscalls(_config: &SandboxConfig) -> anyhow::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,551 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use russh::Disconnect;
+use russh::server::Handle;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, broadcast};
+
+use crate::geoip::Location;
+use crate::quotas::{QuotaUsage, Quotas};
+use crate::realms;
+
+/// Events buffered for each subscriber before the slowest starts missing them.
+const EVENT_CAPACITY: usize = 256;
+/// Chunks of a session's output buffered for each admin watching it.
+const OUTPUT_CAPACITY: usize = 1024;
+/// How long a session's throughput is averaged over, at least.
+const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Every connected session, shared by the server and its connections. Changes are
+/// broadcast to subscribers as [`SessionEvent`]s.
+pub struct SessionRegistry {
+    sessions: Mutex<BTreeMap<usize, Registered>>,
+    /// The sessions of the other nodes of the cluster, as they last published them.
+    remote: Mutex<Vec<SessionInfo>>,
+    /// Sessions of other nodes to ask them to disconnect, by node and id, with why.
+    remote_disconnects: Mutex<Vec<(String, usize, String)>>,
+    events: broadcast::Sender<SessionEvent>,
+    /// What each user's sessions have used of their quotas, together.
+    quotas: Arc<Quotas>,
+}
+
+/// A change in a session's lifecycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    Connected {
+        id: usize,
+        peer: Option<SocketAddr>,
+    },
+    Authenticated {
+        id: usize,
+        user: String,
+    },
+    /// The session started forwarding to an upstream.
+    Forwarding {
+        id: usize,
+        target: String,
+    },
+    Disconnected {
+        id: usize,
+    },
+    /// A message from an admin to every session, or only those of users in `realm`.
+    Broadcast {
+        from: String,
+        realm: Option<String>,
+        message: String,
+    },
+    /// A message from an admin to every session of one user.
+    Message {
+        user: String,
+        from: String,
+        message: String,
+    },
+    /// An admin started or stopped watching the session's output.
+    Shadowed {
+        id: usize,
+        by: String,
+        watching: bool,
+    },
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        Self {
+            sessions: Mutex::default(),
+            remote: Mutex::default(),
+            remote_disconnects: Mutex::default(),
+            events: broadcast::Sender::new(EVENT_CAPACITY),
+            quotas: Arc::default(),
+        }
+    }
+}
+
+struct Registered {
+    peer: Option<SocketAddr>,
+    location: Option<Location>,
+    started: Instant,
+    user: Option<String>,
+    target: Option<String>,
+    bytes: Arc<SessionBytes>,
+    usage: Arc<SessionUsage>,
+    /// When the throughput was last measured, and the bytes forwarded by then.
+    sampled: (Instant, u64),
+    /// Bytes per second between the last two samples.
+    throughput: u64,
+    /// Output forwarded to the client, copied to admins watching the session.
+    output: broadcast::Sender<Vec<u8>>,
+    /// Set once the SSH handshake completes.
+    handle: Option<Handle>,
+    /// Set when pukeko disconnected the session itself, rather than the client.
+    disconnected: bool,
+}
+
+/// A snapshot of a session, for display.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: usize,
+    /// The node of the cluster the session is on, when it is another.
+    pub node: Option<String>,
+    pub peer: Option<SocketAddr>,
+    pub location: Option<Location>,
+    pub user: Option<String>,
+    pub target: Option<String>,
+    pub duration: Duration,
+    /// Bytes sent by the client.
+    pub bytes_up: u64,
+    /// Bytes sent to the client.
+    pub bytes_down: u64,
+    /// Bytes per second in both directions, over the last second or so.
+    pub throughput: u64,
+    /// Bytes of the client's input waiting to be written to its upstreams.
+    pub buffered: usize,
+    pub channels: usize,
+    /// What the session's user has used of their quotas, across all of their sessions.
+    pub quota_usage: QuotaUsage,
+}
+
+impl SessionInfo {
+    pub fn bytes(&self) -> u64 {
+        self.bytes_up + self.bytes_down
+    }
+
+    /// How the session is named to admins.
+    pub fn name(&self) -> String {
+        session_name(self.node.as_deref(), self.id)
+    }
+}
+
+/// Bytes forwarded by a session, to and from upstreams and through its remote forwards.
+#[derive(Debug, Default)]
+pub struct SessionBytes {
+    /// Sent by the client.
+    pub up: AtomicU64,
+    /// Sent to the client.
+    pub down: AtomicU64,
+}
+
+impl SessionBytes {
+    pub fn up(&self) -> u64 {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn down(&self) -> u64 {
+        self.down.load(Ordering::Relaxed)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.up() + self.down()
+    }
+}
+
+/// What a session holds open, counted as it opens channels and buffers input for its
+/// forwards, to be checked against its limits.
+#[derive(Debug)]
+pub struct SessionUsage {
+    /// Bytes of the client's input taken by its forwards and not yet written upstream.
+    pub buffered: AtomicUsize,
+    channels: AtomicUsize,
+    /// Limits the channels open at once, when `max_channels` is set.
+    max_channels: Option<Arc<Semaphore>>,
+}
+
+impl SessionUsage {
+    fn new(max_channels: Option<usize>) -> Self {
+        Self {
+            buffered: AtomicUsize::new(0),
+            channels: AtomicUsize::new(0),
+            max_channels: max_channels.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.buffered.load(Ordering::Relaxed)
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels.load(Ordering::Relaxed)
+    }
+
+    /// Counts a channel until the returned permit is dropped, or returns None if
+    /// `max_channels` are open already.
+    pub fn open_channel(self: &Arc<Self>) -> Option<ChannelPermit> {
+        let permit = match &self.max_channels {
+            Some(max_channels) => Some(max_channels.clone().try_acquire_owned().ok()?),
+            None => None,
+        };
+        self.channels.fetch_add(1, Ordering::Relaxed);
+        Some(ChannelPermit {
+            usage: self.clone(),
+            _permit: permit,
+        })
+    }
+}
+
+/// A channel counted by [`SessionUsage`], released when dropped.
+#[derive(Debug)]
+pub struct ChannelPermit {
+    usage: Arc<SessionUsage>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ChannelPermit {
+    fn drop(&mut self) {
+        self.usage.channels.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Registered {
+    /// Snapshots the session, measuring its throughput again if the last measurement is
+    /// old enough.
+    fn info(&mut self, id: usize, quotas: &Quotas) -> SessionInfo {
+        let now = Instant::now();
+        let total = self.bytes.total();
+        let (sampled_at, sampled) = self.sampled;
+        let elapsed = now - sampled_at;
+        if elapsed >= THROUGHPUT_INTERVAL {
+            self.throughput = ((total - sampled) as f64 / elapsed.as_secs_f64()) as u64;
+            self.sampled = (now, total);
+        }
+        SessionInfo {
+            id,
+            node: None,
+            peer: self.peer,
+            location: self.location.clone(),
+            user: self.user.clone(),
+            target: self.target.clone(),
+            duration: self.started.elapsed(),
+            bytes_up: self.bytes.up(),
+            bytes_down: self.bytes.down(),
+            throughput: self.throughput,
+            buffered: self.usage.buffered(),
+            channels: self.usage.channels(),
+            quota_usage: self
+                .user
+                .as_deref()
+                .map(|user| quotas.usage(user))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl SessionRegistry {
+    /// Adds a session, returning the counters its forwarded bytes are added to and what it
+    /// holds open, with at most `max_channels` channels at once.
+    pub fn register(
+        &self,
+        id: usize,
+        peer: Option<SocketAddr>,
+        max_channels: Option<usize>,
+    ) -> (Arc<SessionBytes>, Arc<SessionUsage>) {
+        let bytes = Arc::new(SessionBytes::default());
+        let usage = Arc::new(SessionUsage::new(max_channels));
+        self.sessions.lock().unwrap().insert(
+            id,
+            Registered {
+                peer,
+                location: None,
+                started: Instant::now(),
+                user: None,
+                target: None,
+                bytes: bytes.clone(),
+                usage: usage.clone(),
+                sampled: (Instant::now(), 0),
+                throughput: 0,
+                output: broadcast::Sender::new(OUTPUT_CAPACITY),
+                handle: None,
+                disconnected: false,
+            },
+        );
+        self.publish(SessionEvent::Connected { id, peer });
+        (bytes, usage)
+    }
+
+    /// Removes a session, returning what it was last doing.
+    pub fn remove(&self, id: usize) -> Option<SessionInfo> {
+        let mut session = self.sessions.lock().unwrap().remove(&id)?;
+        self.publish(SessionEvent::Disconnected { id });
+        Some(session.info(id, &self.quotas))
+    }
+
+    /// Whether pukeko disconnected the session itself, rather than the client.
+    pub fn was_disconnected(&self, id: usize) -> bool {
+        let sessions = self.sessions.lock().unwrap();
+        sessions
+            .get(&id)
+            .is_some_and(|session| session.disconnected)
+    }
+
+    pub fn info(&self, id: usize) -> Option<SessionInfo> {
+        let mut sessions = self.sessions.lock().unwrap();
+        Some(sessions.get_mut(&id)?.info(id, &self.quotas))
+    }
+
+    pub fn quotas(&self) -> &Arc<Quotas> {
+        &self.quotas
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Sends a message to every session, or those of users in `realm`, returning how many
+    /// there are.
+    pub fn broadcast(&self, from: &str, realm: Option<&str>, message: &str) -> usize {
+        self.publish(SessionEvent::Broadcast {
+            from: from.to_string(),
+            realm: realm.map(str::to_string),
+            message: message.to_string(),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| in_realm(session.user.as_deref(), realm))
+            .count()
+    }
+
+    /// Sends a message to every session of `user`, returning how many there are.
+    pub fn message(&self, user: &str, from: &str, message: &str) -> usize {
+        self.publish(SessionEvent::Message {
+            user: user.to_string(),
+            from: from.to_string(),
+            message: message.to_string(),
+        });
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|session| session.user.as_deref() == Some(user))
+            .count()
+    }
+
+    fn publish(&self, event: SessionEvent) {
+        // Sending only fails when nobody is subscribed.
+        let _ = self.events.send(event);
+    }
+
+    /// Where a session's forwarded output is copied for admins to watch.
+    pub fn output(&self, id: usize) -> Option<broadcast::Sender<Vec<u8>>> {
+        let sessions = self.sessions.lock().unwrap();
+        Some(sessions.get(&id)?.output.clone())
+    }
+
+    /// Starts copying a forwarding session's output to `by`, telling the session. Returns
+    /// None if it has gone or is not forwarding.
+    pub fn shadow(&self, id: usize, by: &str) -> Option<broadcast::Receiver<Vec<u8>>> {
+        let output = {
+            let sessions = self.sessions.lock().unwrap();
+            let session = sessions
+                .get(&id)
+                .filter(|session| session.target.is_some())?;
+            session.output.subscribe()
+        };
+        self.publish(SessionEvent::Shadowed {
+            id,
+            by: by.to_string(),
+            watching: true,
+        });
+        Some(output)
+    }
+
+    pub fn stop_shadowing(&self, id: usize, by: &str) {
+        self.publish(SessionEvent::Shadowed {
+            id,
+            by: by.to_string(),
+            watching: false,
+        });
+    }
+
+    pub fn set_handle(&self, id: usize, handle: Handle) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.handle = Some(handle);
+        }
+    }
+
+    pub fn set_location(&self, id: usize, location: Option<Location>) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.location = location;
+        }
+    }
+
+    pub fn set_user(&self, id: usize, user: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.user = Some(user.to_string());
+        } else {
+            return;
+        }
+        self.publish(SessionEvent::Authenticated {
+            id,
+            user: user.to_string(),
+        });
+    }
+
+    pub fn set_target(&self, id: usize, target: &str) {
+        if let Some(session) = self.sessions.lock().unwrap().get_mut(&id) {
+            session.target = Some(target.to_string());
+        } else {
+            return;
+        }
+        self.publish(SessionEvent::Forwarding {
+            id,
+            target: target.to_string(),
+        });
+    }
+
+    /// Whether the session has logged in, false once it has gone.
+    pub fn is_authenticated(&self, id: usize) -> bool {
+        self.sessions
+            .lock()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|session| session.user.is_some())
+    }
+
+    /// All sessions, oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.list_in(None)
+    }
+
+    /// The sessions of users in `realm`, or all of them when None, oldest first.
+    pub fn list_in(&self, realm: Option<&str>) -> Vec<SessionInfo> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter(|(_, session)| in_realm(session.user.as_deref(), realm))
+            .map(|(&id, session)| session.info(id, &self.quotas))
+            .collect()
+    }
+
+    /// The sessions of users in `realm`, or all of them when None, on this node and then the
+    /// others of the cluster.
+    pub fn cluster_in(&self, realm: Option<&str>) -> Vec<SessionInfo> {
+        let mut sessions = self.list_in(realm);
+        let remote = self.remote.lock().unwrap().clone();
+        sessions.extend(
+            remote
+                .into_iter()
+                .filter(|session| in_realm(session.user.as_deref(), realm))
+                .map(|mut session| {
+                    if let Some(user) = &session.user {
+                        session.quota_usage = self.quotas.usage(user);
+                    }
+                    session
+                }),
+        );
+        sessions
+    }
+
+    /// Another node's session, if it was there when the node last published its sessions.
+    pub fn remote_info(&self, node: &str, id: usize) -> Option<SessionInfo> {
+        let remote = self.remote.lock().unwrap();
+        remote
+            .iter()
+            .find(|session| session.id == id && session.node.as_deref() == Some(node))
+            .cloned()
+    }
+
+    /// Replaces the sessions of the other nodes of the cluster.
+    pub fn set_remote(&self, sessions: Vec<SessionInfo>) {
+        *self.remote.lock().unwrap() = sessions;
+    }
+
+    /// Asks `node` to disconnect its session `id` when the cluster next syncs, returning
+    /// false if the session is not known to be there.
+    pub fn disconnect_remote(&self, node: &str, id: usize, reason: &str) -> bool {
+        if self.remote_info(node, id).is_none() {
+            return false;
+        }
+        self.remote_disconnects
+            .lock()
+            .unwrap()
+            .push((node.to_string(), id, reason.to_string()));
+        true
+    }
+
+    /// Takes the sessions of other nodes waiting to be disconnected.
+    pub fn take_remote_disconnects(&self) -> Vec<(String, usize, String)> {
+        std::mem::take(&mut self.remote_disconnects.lock().unwrap())
+    }
+
+    /// Disconnects a session, returning false if it has already gone.
+    pub async fn disconnect(&self, id: usize, reason: &str) -> bool {
+        let handle = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(&id)
+            .and_then(|session| {
+                session.disconnected = true;
+                session.handle.clone()
+            });
+        match handle {
+            Some(handle) => handle
+                .disconnect(Disconnect::ByApplication, reason.into(), "".into())
+                .await
+                .is_ok(),
+            None => false,
+        }
+    }
+
+    /// Disconnects every session, such as when the shutdown grace period runs out.
+    pub async fn disconnect_all(&self, reason: &str) {
+        let handles: Vec<Handle> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|session| {
+                session.disconnected = true;
+                session.handle.clone()
+            })
+            .collect();
+        for handle in handles {
+            let _ = handle
+                .disconnect(Disconnect::ByApplication, reason.into(), "".into())
+                .await;
+        }
+    }
+}
+
+/// How session `id` is named to admins: its id, after its node when on another of the
+/// cluster.
+pub fn session_name(node: Option<&str>, id: usize) -> String {
+    match node {
+        Some(node) => format!("{node}/{id}"),
+        None => id.to_string(),
+    }
+}
+
+/// Whether a session of `user` is one of those of users in `realm`, which every session is
+/// when None. Sessions yet to log in are in no realm.
+pub fn in_realm(user: Option<&str>, realm: Option<&str>) -> bool {
+    match realm {
+        Some(realm) => user.is_some_and(|user| realms::in_realm(user, realm)),
+        None => true,
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use tokio::sync::{Mutex, broadcast};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, debug, warn};
+
+use crate::audit::{AuditEvent, AuditLog};
+use crate::sessions::SessionRegistry;
+use crate::tui::MenuScreen;
+
+/// An admin watching another session's output in place of their menu. Stopping is
+/// published and audited when this is dropped, so admins disconnecting are covered too.
+pub struct Shadow {
+    /// The admin's own session.
+    id: usize,
+    session: usize,
+    by: String,
+    sessions: Arc<SessionRegistry>,
+    audit: Arc<AuditLog>,
+    task: JoinHandle<()>,
+}
+
+impl Shadow {
+    /// Starts copying `session`'s output to the terminal in `screen` of admin `by`, whose
+    /// own session is `id`. Returns None if the session has ended or is not forwarding.
+    pub fn start(
+        id: usize,
+        by: &str,
+        session: usize,
+        screen: Arc<Mutex<MenuScreen>>,
+        sessions: Arc<SessionRegistry>,
+        audit: Arc<AuditLog>,
+    ) -> Option<Self> {
+        let mut output = sessions.shadow(session, by)?;
+        audit.record(AuditEvent::SessionShadowed {
+            session: id,
+            user: by,
+            shadowed: session,
+            watching: true,
+        });
+        let task = tokio::spawn(async move {
+            loop {
+                let (data, ended) = match output.recv().await {
+                    Ok(data) => (data, false),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        debug!("Watcher of session {} missed {} writes", session, missed);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => {
+                        let notice = format!(
+                            "\r\n[pukeko] Session {session} has ended, press q to return to the menu\r\n"
+                        );
+                        (notice.into_bytes(), true)
+                    }
+                };
+                if let Err(e) = screen.lock().await.terminal.write(&data) {
+                    warn!("Failed to write output of session {}: {:?}", session, e);
+                    break;
+                }
+                if ended {
+                    break;
+                }
+            }
+        }.in_current_span());
+
+        Some(Self {
+            id,
+            session,
+            by: by.to_string(),
+            sessions,
+            audit,
+            task,
+        })
+    }
+
+    pub fn session(&self) -> usize {
+        self.session
+    }
+}
+
+impl Drop for Shadow {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.sessions.stop_shadowing(self.session, &self.by);
+        self.audit.record(AuditEvent::SessionShadowed {
+            session: self.id,
+            user: &self.by,
+            shadowed: self.session,
+            watching: false,
+        });
+    }
+}
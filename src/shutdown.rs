@@ -0,0 +1,79 @@
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone)]
+pub struct ShutdownSignal(watch::Receiver<bool>);
+
+impl ShutdownSignal {
+    pub fn is_shutting_down(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    pub async fn wait(&mut self) {
+        // An error means the sender was dropped, which only happens once the server is gone.
+        let _ = self.0.wait_for(|shutting_down| *shutting_down).await;
+    }
+}
+
+pub struct Shutdown {
+    sender: watch::Sender<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self { sender }
+    }
+}
+
+impl Shutdown {
+    pub fn signal(&self) -> ShutdownSignal {
+        ShutdownSignal(self.sender.subscribe())
+    }
+
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+#[cfg(unix)]
+pub async fn wait_for_signal() -> anyhow::Result<()> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+
+    tokio::select! {
+        _ = sigterm.recv() => info!("Received SIGTERM, shutting down"),
+        _ = sigint.recv() => info!("Received SIGINT, shutting down"),
+    }
+
+    tokio::spawn(async move {
+        // A second signal while draining skips the grace period.
+        tokio::select! {
+            _ = sigterm.recv() => {},
+            _ = sigint.recv() => {},
+        }
+        warn!("Received second shutdown signal, exiting immediately");
+        std::process::exit(1);
+    });
+
+    Ok(())
+}
+
+/// Off Unix there are no signals to shut down on but Ctrl-C.
+#[cfg(not(unix))]
+pub async fn wait_for_signal() -> anyhow::Result<()> {
+    tokio::signal::ctrl_c().await?;
+    info!("Received Ctrl-C, shutting down");
+
+    tokio::spawn(async move {
+        // A second Ctrl-C while draining skips the grace period.
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("Received second shutdown signal, exiting immediately");
+            std::process::exit(1);
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,221 @@
+use std::borrow::Cow;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use rand_core::{OsRng, RngCore};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+use crate::config::ServerEntry;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+/// How long each nameserver is given to answer.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct SrvRecord {
+    priority: u16,
+    weight: u16,
+    port: u16,
+    target: String,
+}
+
+/// `entry` with the host and port its DNS SRV record points to, when it has one, see RFC
+/// 2782. The record is looked up again for every connection, so changes are seen as soon
+/// as the nameserver's cached copy expires.
+pub async fn resolve(entry: &ServerEntry) -> anyhow::Result<Cow<'_, ServerEntry>> {
+    if !entry.srv {
+        return Ok(Cow::Borrowed(entry));
+    }
+    let records = lookup(&entry.host)
+        .await
+        .with_context(|| format!("Failed to look up {}", entry.host))?;
+    let record = pick(records).with_context(|| format!("{} has no targets", entry.host))?;
+    debug!(
+        "Resolved {} to {}:{}",
+        entry.host, record.target, record.port
+    );
+    let mut resolved = entry.clone();
+    resolved.host = record.target;
+    resolved.port = record.port;
+    resolved.srv = false;
+    Ok(Cow::Owned(resolved))
+}
+
+/// Picks a target among those with the lowest priority, at random in proportion to their
+/// weights.
+fn pick(mut records: Vec<SrvRecord>) -> Option<SrvRecord> {
+    let priority = records.iter().map(|record| record.priority).min()?;
+    records.retain(|record| record.priority == priority);
+    let total: u32 = records.iter().map(|record| u32::from(record.weight)).sum();
+    if total == 0 {
+        let i = OsRng.next_u32() as usize % records.len();
+        return Some(records.swap_remove(i));
+    }
+    let mut chosen = OsRng.next_u32() % total;
+    for record in records {
+        let weight = u32::from(record.weight);
+        if chosen < weight {
+            return Some(record);
+        }
+        chosen -= weight;
+    }
+    None
+}
+
+/// Asks the nameservers in resolv.conf for the SRV records of `name`, trying each in turn.
+async fn lookup(name: &str) -> anyhow::Result<Vec<SrvRecord>> {
+    let resolv = tokio::fs::read_to_string(RESOLV_CONF)
+        .await
+        .unwrap_or_default();
+    let mut nameservers: Vec<IpAddr> = resolv
+        .lines()
+        .filter_map(|line| line.strip_prefix("nameserver"))
+        .filter_map(|address| address.trim().parse().ok())
+        .collect();
+    if nameservers.is_empty() {
+        nameservers.push(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    let mut error = None;
+    for nameserver in nameservers {
+        match tokio::time::timeout(QUERY_TIMEOUT, query(name, nameserver)).await {
+            Ok(Ok(records)) => return Ok(records),
+            Ok(Err(e)) => error = Some(e.context(format!("Nameserver {nameserver} failed"))),
+            Err(_) => error = Some(anyhow::anyhow!("Nameserver {nameserver} timed out")),
+        }
+    }
+    Err(error.unwrap_or_else(|| anyhow::anyhow!("No nameserver answered")))
+}
+
+async fn query(name: &str, nameserver: IpAddr) -> anyhow::Result<Vec<SrvRecord>> {
+    let id = OsRng.next_u32() as u16;
+    // Asks for recursion, with one question.
+    let mut request = Vec::with_capacity(name.len() + 18);
+    request.extend_from_slice(&id.to_be_bytes());
+    request.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid name {name}");
+        }
+        request.push(label.len() as u8);
+        request.extend_from_slice(label.as_bytes());
+    }
+    request.push(0);
+    request.extend_from_slice(&TYPE_SRV.to_be_bytes());
+    request.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+    let local: SocketAddr = match nameserver {
+        IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        IpAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect((nameserver, 53)).await?;
+    socket.send(&request).await?;
+
+    let mut response = vec![0; 4096];
+    loop {
+        let len = socket.recv(&mut response).await?;
+        // Stray answers to earlier queries are skipped.
+        if len >= 2 && response[..2] == id.to_be_bytes() {
+            response.truncate(len);
+            break;
+        }
+        trace!("Ignoring DNS response for another query");
+    }
+    parse(&response)
+}
+
+/// Reads the SRV records from a DNS response.
+fn parse(message: &[u8]) -> anyhow::Result<Vec<SrvRecord>> {
+    let header = message.get(..12).context("Response is too short")?;
+    if header[2] & 0x80 == 0 {
+        bail!("Response is not an answer");
+    }
+    if header[2] & 0x02 != 0 {
+        bail!("Response was truncated");
+    }
+    match header[3] & 0x0f {
+        0 => {}
+        3 => bail!("No such name"),
+        code => bail!("Nameserver answered with error {code}"),
+    }
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+
+    let mut position = 12;
+    for _ in 0..questions {
+        position = read_name(message, position)?.1 + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        position = read_name(message, position)?.1;
+        let fixed = message
+            .get(position..position + 10)
+            .context("Response is cut short")?;
+        let kind = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let length = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        let data = position + 10;
+        position = data + length;
+        if position > message.len() {
+            bail!("Response is cut short");
+        }
+        // Answers may include the CNAMEs followed to the records.
+        if kind != TYPE_SRV || length < 7 {
+            continue;
+        }
+        let field = |offset: usize| {
+            u16::from_be_bytes([message[data + offset], message[data + offset + 1]])
+        };
+        let (target, _) = read_name(message, data + 6)?;
+        // A target of "." says the service is not available there.
+        if target.is_empty() {
+            continue;
+        }
+        records.push(SrvRecord {
+            priority: field(0),
+            weight: field(2),
+            port: field(4),
+            target,
+        });
+    }
+    Ok(records)
+}
+
+/// Reads the name at `position`, following compression pointers, and returns it with the
+/// position after it.
+fn read_name(message: &[u8], mut position: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels: Vec<String> = Vec::new();
+    let mut end = None;
+    // Each pointer must go back, so a loop of them cannot go on forever.
+    let mut limit = position;
+    loop {
+        let len = *message.get(position).context("Response is cut short")? as usize;
+        match len {
+            0 => {
+                let end = end.unwrap_or(position + 1);
+                return Ok((labels.join("."), end));
+            }
+            len if len & 0xc0 == 0xc0 => {
+                let low = *message.get(position + 1).context("Response is cut short")? as usize;
+                let pointer = (len & 0x3f) << 8 | low;
+                if pointer >= limit {
+                    bail!("Response has a bad name");
+                }
+                end.get_or_insert(position + 2);
+                limit = pointer;
+                position = pointer;
+            }
+            len => {
+                let label = message
+                    .get(position + 1..position + 1 + len)
+                    .context("Response is cut short")?;
+                labels.push(String::from_utf8_lossy(label).into_owned());
+                position += 1 + len;
+            }
+        }
+    }
+}
@@ -1,32 +1,295 @@
-use std::sync::Arc;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
 
 use ratatui::layout::Rect;
 use russh::keys::ssh_key::{self};
-use russh::{Channel, ChannelId, MethodSet, Pty, SshId, server::*};
-use tokio::sync::Mutex;
-use tracing::{debug, error, info, trace, warn};
+use russh::{Channel, ChannelId, ChannelWriteHalf, MethodSet, Pty, SshId, server::*};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Mutex, broadcast, mpsc, oneshot};
+use tokio::task::JoinSet;
+use tracing::{Instrument, Span, debug, error, field, info, info_span, trace, warn};
+
+use crate::access::{self, AccessRequests, GrantedServers};
+use crate::announcements::Announcements;
+use crate::approval::KeyApprovals;
+use crate::audit::{AuditEvent, AuditLog, AuditSink};
+use crate::authorization::{self, AuthorizationRequest, Decision, Verdict};
+use crate::bandwidth::BandwidthLimits;
+use crate::banner::{self, LastLogin, LastLogins};
+use crate::cert;
+use crate::cluster::{Cluster, SharedState};
+use crate::config::{
+    BannerMode, ConfigReceiver, ConfigUpdater, ForwardRule, ListenerConfig, Protocol, PukekoConfig,
+    ServerEntry,
+};
+use crate::control::{self, Control};
+use crate::detach::DetachedSessions;
+use crate::flow::{self, ChannelOutput};
+use crate::forward::{
+    EscapeCommand, Forward, ForwardKind, ForwardRequest, ForwardStatus, PtyRequest, X11Request,
+};
+use crate::geoip::{GeoIp, Location};
+use crate::health::HealthMonitor;
+use crate::history::History;
+use crate::honeypot::Honeypot;
+use crate::inspect::{ClipboardInspector, PatternInspector, StreamInspector};
+use crate::inventory::Inventory;
+use crate::jump;
+use crate::limits::{ConnectionLimiter, ConnectionPermit};
+use crate::maintenance::Maintenance;
+use crate::messages::{self, Messages};
+use crate::metrics::{self, Metrics};
+use crate::oidc::DeviceLogin;
+use crate::pane::{PaneCommand, Panes};
+use crate::plugins::{LoginAttempt, Plugins};
+use crate::pool::UpstreamPool;
+use crate::privileges;
+use crate::provider::{AuthDecision, AuthProvider, ConfigProvider, Identity, ServerProvider};
+use crate::proxy;
+use crate::quotas::{QuotaExceeded, QuotaPermit};
+use crate::realms::{self, Realm, Realms};
+use crate::remote_forward::RemoteForward;
+use crate::sandbox;
+use crate::sessions::{
+    self, ChannelPermit, SessionBytes, SessionEvent, SessionInfo, SessionRegistry, SessionUsage,
+};
+use crate::shadow::Shadow;
+use crate::shutdown::{self, Shutdown, ShutdownSignal};
+use crate::store::{self, SessionRecord, StoreChange};
+use crate::systemd;
+use crate::telemetry;
+use crate::term::TerminalCaps;
+use crate::totp::UsedCodes;
+use crate::tui::{
+    DISABLE_MOUSE, KeySource, ListedKey, MenuScreen, MenuState, PukekoMenu, Theme, format_bytes,
+    format_duration,
+};
+use crate::upgrade::{self, Handover, UpgradeListener};
+use crate::upstream::{self, UnknownHostKey};
 
-use crate::config::PukekoConfig;
-use crate::tui::{MenuState, PukekoMenu, SshTerminal};
+const SHUTDOWN_NOTICE_DELAY: Duration = Duration::from_secs(2);
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long each keyboard-interactive reply waits for the user to finish signing in.
+const DEVICE_LOGIN_WAIT: Duration = Duration::from_secs(30);
+/// How often sessions are checked against schedules that close them.
+const SCHEDULE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// Wrong verification codes a connection may enter before it may not log in at all.
+const MAX_CODE_FAILURES: usize = 3;
+/// Run with a bare login and a public key, issues a certificate for it.
+const CERT_COMMAND: &str = "pukeko-cert";
+/// How often users' time connected to servers is checked against their daily minutes.
+const QUOTA_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often sessions are checked against the limits on what they hold.
+const SESSION_LIMIT_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_LISTEN_ADDRESS: ([u8; 4], u16) = ([0, 0, 0, 0], 2222);
+/// How many environment variables a channel may set, most of which are never forwarded.
+const MAX_ENV_VARIABLES: usize = 64;
 
-#[derive(Clone)]
 pub struct PukekoServer {
     id: usize,
-    config: Arc<PukekoConfig>,
+    config: ConfigReceiver,
+    listen_address: SocketAddr,
+    systemd: bool,
+    auth: Arc<dyn AuthProvider>,
+    servers: Arc<dyn ServerProvider>,
+    shutdown: Shutdown,
+    metrics: Arc<Metrics>,
+    limiter: Arc<ConnectionLimiter>,
+    /// Verification codes already used, which are not accepted again.
+    used_codes: Arc<UsedCodes>,
+    bandwidth: Arc<BandwidthLimits>,
+    audit: Arc<AuditLog>,
+    last_logins: Arc<LastLogins>,
+    health: Arc<HealthMonitor>,
+    history: Arc<History>,
+    access: Arc<AccessRequests>,
+    approvals: Arc<KeyApprovals>,
+    maintenance: Arc<Maintenance>,
+    announcements: Arc<Announcements>,
+    geoip: Arc<GeoIp>,
+    plugins: Arc<Plugins>,
+    realms: Arc<Realms>,
+    pool: Arc<UpstreamPool>,
+    detached: Arc<DetachedSessions>,
+    /// Servers from the configured inventory sources, listed by the default provider.
+    inventory: Arc<Inventory>,
+    sessions: Arc<SessionRegistry>,
+    updater: Option<ConfigUpdater>,
+    inspectors: Arc<[Arc<dyn StreamInspector>]>,
 }
 
-impl PukekoServer {
-    pub fn new(config: PukekoConfig) -> Self {
-        Self {
+/// Configures a [`PukekoServer`]. Users and servers come from the config unless other
+/// providers are set.
+pub struct PukekoServerBuilder {
+    config: ConfigReceiver,
+    listen_address: SocketAddr,
+    systemd: bool,
+    auth: Option<Arc<dyn AuthProvider>>,
+    servers: Option<Arc<dyn ServerProvider>>,
+    updater: Option<ConfigUpdater>,
+    inspectors: Vec<Arc<dyn StreamInspector>>,
+    audit_sinks: Vec<Arc<dyn AuditSink>>,
+}
+
+impl PukekoServerBuilder {
+    /// The address to listen on when the config has no `listeners`.
+    pub fn listen_address(mut self, address: SocketAddr) -> Self {
+        self.listen_address = address;
+        self
+    }
+
+    /// Whether to use the sockets passed by systemd socket activation instead of binding
+    /// the listeners, and to send it readiness and watchdog notifications. Enabled by
+    /// default, and does nothing unless run by systemd.
+    pub fn systemd(mut self, enabled: bool) -> Self {
+        self.systemd = enabled;
+        self
+    }
+
+    pub fn auth_provider(mut self, provider: Arc<dyn AuthProvider>) -> Self {
+        self.auth = Some(provider);
+        self
+    }
+
+    pub fn server_provider(mut self, provider: Arc<dyn ServerProvider>) -> Self {
+        self.servers = Some(provider);
+        self
+    }
+
+    /// Adds an inspector run on forwarded data, after the config's `inspect` rules.
+    pub fn stream_inspector(mut self, inspector: Arc<dyn StreamInspector>) -> Self {
+        self.inspectors.push(inspector);
+        self
+    }
+
+    /// Adds a sink that is sent every audit event, beside the config's `audit` sinks.
+    pub fn audit_sink(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit_sinks.push(sink);
+        self
+    }
+
+    /// Lets the control socket reload the config and add or remove servers.
+    pub fn config_updater(mut self, updater: ConfigUpdater) -> Self {
+        self.updater = Some(updater);
+        self
+    }
+
+    pub fn build(self) -> PukekoServer {
+        let inventory = Arc::new(Inventory::default());
+        let (mut state_directory, chroot) = {
+            let config = self.config.borrow();
+            (config.state_directory.clone(), config.privileges.chroot)
+        };
+        // After changing root to the state directory its absolute path is gone, so the
+        // state is kept relative to it as the working directory, which stays the same.
+        if chroot {
+            match std::env::set_current_dir(&state_directory) {
+                Ok(()) => state_directory = PathBuf::new(),
+                Err(e) => error!(
+                    "Failed to change to state directory {}: {}",
+                    state_directory.display(),
+                    e
+                ),
+            }
+        }
+        let history = History::load(state_directory.join("history.json"));
+        let access = Arc::new(AccessRequests::load(state_directory.join("access.json")));
+        let approvals = Arc::new(KeyApprovals::load(state_directory.join("keys.json")));
+        let maintenance = Arc::new(Maintenance::load(state_directory.join("maintenance.json")));
+        let announcements = Arc::new(Announcements::load(
+            state_directory.join("announcements.json"),
+        ));
+        let provider =
+            Arc::new(ConfigProvider::new(self.config.clone()).with_inventory(inventory.clone()));
+        // Servers users were granted access to are added to whichever provider is used.
+        let servers = self.servers.unwrap_or(provider.clone());
+        let servers: Arc<dyn ServerProvider> =
+            Arc::new(GrantedServers::new(servers, access.clone()));
+        let auth = self.auth.unwrap_or_else(|| provider.clone());
+        let audit = Arc::new(AuditLog::new(self.audit_sinks));
+        let realms = Arc::new(Realms::new(Realm::deployment(
+            self.config.clone(),
+            auth.clone(),
+            servers.clone(),
+            audit.clone(),
+        )));
+        PukekoServer {
             id: 0,
-            config: Arc::new(config),
+            config: self.config,
+            listen_address: self.listen_address,
+            systemd: self.systemd,
+            auth,
+            servers,
+            shutdown: Shutdown::default(),
+            metrics: Arc::new(Metrics::default()),
+            limiter: Arc::new(ConnectionLimiter::load(state_directory.join("bans.json"))),
+            used_codes: Arc::new(UsedCodes::default()),
+            bandwidth: Arc::new(BandwidthLimits::default()),
+            audit,
+            last_logins: Arc::new(LastLogins::default()),
+            health: Arc::new(HealthMonitor::default()),
+            history: Arc::new(history),
+            access,
+            approvals,
+            maintenance,
+            announcements,
+            geoip: Arc::new(GeoIp::default()),
+            plugins: Arc::new(Plugins::default()),
+            realms,
+            pool: Arc::new(UpstreamPool::default()),
+            detached: Arc::new(DetachedSessions::default()),
+            inventory,
+            sessions: Arc::new(SessionRegistry::default()),
+            updater: self.updater,
+            inspectors: self.inspectors.into(),
+        }
+    }
+}
+
+impl PukekoServer {
+    /// Subscribes to sessions connecting, authenticating, forwarding and disconnecting.
+    pub fn session_events(&self) -> broadcast::Receiver<SessionEvent> {
+        self.sessions.subscribe()
+    }
+
+    pub fn builder(config: ConfigReceiver) -> PukekoServerBuilder {
+        PukekoServerBuilder {
+            config,
+            listen_address: DEFAULT_LISTEN_ADDRESS.into(),
+            systemd: true,
+            auth: None,
+            servers: None,
+            updater: None,
+            inspectors: Vec::new(),
+            audit_sinks: Vec::new(),
         }
     }
 
     pub async fn run(&mut self) -> anyhow::Result<()> {
+        let pukeko_config = self.config.borrow().clone();
+
         let methods = {
             let mut ms = MethodSet::empty();
             ms.push(russh::MethodKind::PublicKey);
+            let realm_passwords = pukeko_config.realms.iter().any(|realm| {
+                realm
+                    .config
+                    .users
+                    .iter()
+                    .any(|user| user.password_hash.is_some())
+            });
+            if self.auth.accepts_passwords() || realm_passwords {
+                ms.push(russh::MethodKind::Password);
+            }
+            if pukeko_config.oidc.is_some() {
+                ms.push(russh::MethodKind::KeyboardInteractive);
+            }
             ms
         };
 
@@ -37,218 +300,4300 @@ impl PukekoServer {
                 env!("CARGO_PKG_VERSION")
             )),
             inactivity_timeout: Some(std::time::Duration::from_secs(3600)),
+            keepalive_interval: pukeko_config.keepalive.interval,
+            keepalive_max: pukeko_config.keepalive.max_missed,
             auth_rejection_time: std::time::Duration::from_millis(100),
             auth_rejection_time_initial: Some(std::time::Duration::from_secs(0)),
-            keys: vec![self.config.server_key.clone()],
+            keys: pukeko_config.host_keys.clone(),
+            preferred: pukeko_config.algorithms.clone(),
             nodelay: true,
             methods,
+            window_size: flow::CLIENT_WINDOW,
             ..Default::default()
         };
-        self.run_on_address(Arc::new(config), ("0.0.0.0", 2222))
-            .await?;
+        self.audit.configure(&pukeko_config.audit)?;
+        self.geoip.configure(pukeko_config.geoip.as_ref())?;
+        self.plugins.configure(&pukeko_config.plugins)?;
+        self.realms.configure(&pukeko_config)?;
+        forget_removed_users(&self.approvals, &pukeko_config);
+        if let Some(cluster) = &pukeko_config.cluster {
+            let shared: Vec<Arc<dyn SharedState>> = vec![
+                self.limiter.clone(),
+                self.access.clone(),
+                self.history.clone(),
+            ];
+            let cluster =
+                Cluster::join(cluster, self.sessions.clone(), self.limiter.clone(), shared)
+                    .await
+                    .context("Failed to join the cluster")?;
+            tokio::spawn(cluster.run());
+        }
+        {
+            let audit = self.audit.clone();
+            let geoip = self.geoip.clone();
+            let plugins = self.plugins.clone();
+            let realms = self.realms.clone();
+            let approvals = self.approvals.clone();
+            let mut config = self.config.clone();
+            tokio::spawn(async move {
+                while config.changed().await.is_ok() {
+                    let config = config.borrow_and_update().clone();
+                    if let Err(e) = audit.configure(&config.audit) {
+                        error!("Keeping previous audit log, reopening failed: {:?}", e);
+                    }
+                    if let Err(e) = geoip.configure(config.geoip.as_ref()) {
+                        error!(
+                            "Keeping previous GeoIP databases, reopening failed: {:?}",
+                            e
+                        );
+                    }
+                    if let Err(e) = plugins.configure(&config.plugins) {
+                        error!("Keeping previous plugins, loading failed: {:?}", e);
+                    }
+                    if let Err(e) = realms.configure(&config) {
+                        error!("Failed to set up realms: {:?}", e);
+                    }
+                    forget_removed_users(&approvals, &config);
+                }
+            });
+        }
+
+        tokio::spawn(
+            self.health
+                .clone()
+                .run(self.config.clone(), self.servers.clone()),
+        );
+        tokio::spawn(self.inventory.clone().run(self.config.clone()));
+        tokio::spawn(self.pool.clone().run(self.config.clone()));
+        tokio::spawn(enforce_schedules(
+            self.realms.clone(),
+            self.sessions.clone(),
+            self.audit.clone(),
+        ));
+        tokio::spawn(enforce_session_limits(
+            self.config.clone(),
+            self.sessions.clone(),
+            self.audit.clone(),
+        ));
+        tokio::spawn(enforce_quotas(
+            self.config.clone(),
+            self.sessions.clone(),
+            self.audit.clone(),
+        ));
+        tokio::spawn(self.plugins.clone().run(self.sessions.subscribe()));
+
+        if let Some(path) = pukeko_config.control_socket.clone() {
+            let control = Arc::new(Control {
+                config: self.config.clone(),
+                updater: self.updater.clone(),
+                sessions: self.sessions.clone(),
+                metrics: self.metrics.clone(),
+                audit: self.audit.clone(),
+                limiter: self.limiter.clone(),
+                access: self.access.clone(),
+                approvals: self.approvals.clone(),
+                maintenance: self.maintenance.clone(),
+                announcements: self.announcements.clone(),
+            });
+            match control::bind(&path) {
+                Ok(listener) => {
+                    tokio::spawn(async move {
+                        if let Err(e) = control::serve(listener, control).await {
+                            error!("Control socket failed: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Control socket failed: {:?}", e),
+            }
+        }
+
+        if pukeko_config.telemetry.is_some() {
+            telemetry::observe(self.metrics.clone());
+        }
+
+        if let Some(address) = pukeko_config.metrics_address {
+            match metrics::bind(address).await {
+                Ok(listener) => {
+                    let metrics = self.metrics.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = metrics::serve(listener, metrics).await {
+                            error!("Metrics listener failed: {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Metrics listener failed: {:?}", e),
+            }
+        }
+
+        let (listeners, handover) = self.listen(&pukeko_config).await?;
+        let upgrade = pukeko_config
+            .upgrade_socket
+            .as_deref()
+            .map(upgrade::bind)
+            .transpose()?;
+        // Everything that may need a privileged port is bound by now.
+        privileges::drop_privileges(&pukeko_config.privileges, &pukeko_config.state_directory)?;
+        sandbox::filter_syscalls(&pukeko_config.sandbox)?;
+        if let Some(handover) = handover {
+            handover.complete().await?;
+        }
+
+        if self.systemd {
+            systemd::notify("READY=1");
+            if let Some(interval) = systemd::watchdog_interval() {
+                debug!("Sending systemd watchdog pings every {:?}", interval);
+                tokio::spawn(async move {
+                    let mut ticks = tokio::time::interval(interval);
+                    loop {
+                        ticks.tick().await;
+                        systemd::notify("WATCHDOG=1");
+                    }
+                });
+            }
+        }
+        self.serve(Arc::new(config), listeners, upgrade).await
+    }
+
+    /// Binds the configured listeners, or takes the sockets systemd passed or the previous
+    /// process hands over. Those get the settings of the listener configured with their
+    /// address, if there is one.
+    async fn listen(
+        &self,
+        config: &PukekoConfig,
+    ) -> anyhow::Result<(Vec<Listener>, Option<Handover>)> {
+        let configured = &config.listeners;
+        let mut inherited = if self.systemd {
+            systemd::take_listeners()?
+        } else {
+            Vec::new()
+        };
+        let mut from = "systemd";
+        let mut handover = None;
+        if inherited.is_empty()
+            && let Some(path) = &config.upgrade_socket
+            && let Some((listeners, taken)) = upgrade::take_over(path).await?
+        {
+            inherited = listeners;
+            from = "the previous process";
+            handover = Some(taken);
+        }
+        if !inherited.is_empty() {
+            let listeners = inherited
+                .into_iter()
+                .map(|listener| {
+                    let address = listener.local_addr()?;
+                    info!("Listening on {} from {}", address, from);
+                    let config = configured.iter().find(|config| config.address == address);
+                    Ok(Listener {
+                        listener: TcpListener::from_std(listener)?,
+                        proxy_protocol: config.and_then(|config| config.proxy_protocol),
+                        realm: config.and_then(|config| config.realm.clone()),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            return Ok((listeners, handover));
+        }
+
+        let default = [ListenerConfig {
+            address: self.listen_address,
+            proxy_protocol: None,
+            realm: None,
+        }];
+        let configured = if configured.is_empty() {
+            &default
+        } else {
+            configured.as_slice()
+        };
+        let mut listeners = Vec::new();
+        for config in configured {
+            let listener = TcpListener::bind(config.address)
+                .await
+                .with_context(|| format!("Failed to listen on {}", config.address))?;
+            info!("Listening on {}", config.address);
+            listeners.push(Listener {
+                listener,
+                proxy_protocol: config.proxy_protocol,
+                realm: config.realm.clone(),
+            });
+        }
+        Ok((listeners, None))
+    }
+
+    async fn serve(
+        &mut self,
+        config: Arc<Config>,
+        listeners: Vec<Listener>,
+        upgrade: Option<UpgradeListener>,
+    ) -> anyhow::Result<()> {
+        let mut sessions = JoinSet::new();
+        let (error_tx, mut error_rx) = tokio::sync::mpsc::unbounded_channel();
+        // Accepted connections from every listener, after reading their PROXY header if one
+        // is expected.
+        let (incoming_tx, mut incoming_rx) = tokio::sync::mpsc::unbounded_channel();
+        let handed_over = upgrade
+            .map(|upgrade| {
+                let sockets: Vec<_> = listeners.iter().map(|l| &l.listener).collect();
+                upgrade.spawn(&sockets)
+            })
+            .transpose()?;
+        let handed_over = async {
+            let handed_over = match handed_over {
+                Some(handed_over) => handed_over.await.is_ok(),
+                None => false,
+            };
+            if !handed_over {
+                std::future::pending::<()>().await;
+            }
+        };
+        tokio::pin!(handed_over);
+        let mut accepting = JoinSet::new();
+        for listener in listeners {
+            accepting.spawn(listener.accept(self.config.clone(), incoming_tx.clone()));
+        }
+
+        let signal = shutdown::wait_for_signal();
+        tokio::pin!(signal);
+
+        loop {
+            tokio::select! {
+                result = &mut signal => {
+                    result?;
+                    break;
+                }
+                () = &mut handed_over => {
+                    info!("A new process took over the listeners");
+                    break;
+                }
+                Some(result) = accepting.join_next() => {
+                    // Listeners only stop when accepting fails.
+                    result??;
+                }
+                Some((socket, peer_addr, realm)) = incoming_rx.recv() => {
+                    let location = self.geoip.lookup(peer_addr.ip());
+                    let refused = {
+                        let config = self.config.borrow();
+                        config.refuses(peer_addr.ip()).map(str::to_string).or_else(|| {
+                            location.as_ref()?.refused_by(config.geoip.as_ref()?)
+                        })
+                    };
+                    let limits = self.config.borrow().limits;
+                    let permit = match refused {
+                        Some(reason) => Err(anyhow::anyhow!(reason)),
+                        None => self.limiter.try_acquire(peer_addr.ip(), &limits),
+                    };
+                    let permit = match permit {
+                        Ok(permit) => permit,
+                        Err(e) => {
+                            warn!("Rejecting connection from {}: {}", peer_addr, e);
+                            self.audit.record(AuditEvent::ConnectionRejected {
+                                peer: peer_addr,
+                                location: location.as_ref(),
+                                reason: e.to_string(),
+                            });
+                            continue;
+                        }
+                    };
+                    let mut handler = self.new_client(Some(peer_addr));
+                    handler.permit = Some(permit);
+                    handler.listener_realm = realm;
+                    if let Some(location) = &location {
+                        handler.span().in_scope(|| debug!("Connecting from {}", location));
+                    }
+                    self.sessions.set_location(self.id, location.clone());
+                    handler.location = location.clone();
+                    let span = handler.span().clone();
+                    let id = self.id;
+                    let login_deadline = tokio::time::Instant::now()
+                        + self.config.borrow().timeouts.login;
+                    let config = config.clone();
+                    let error_tx = error_tx.clone();
+                    let registry = self.sessions.clone();
+                    let metrics = self.metrics.clone();
+                    let audit = self.audit.clone();
+                    let bytes = handler.bytes.clone();
+
+                    sessions.spawn(async move {
+                        // A second handle on the socket, to drop clients that never log in.
+                        // russh only disconnects them politely, which they can ignore.
+                        let (socket, killer) = match split_socket(socket) {
+                            Ok(split) => split,
+                            Err(e) => {
+                                warn!("Failed to set up connection: {:?}", e);
+                                return;
+                            }
+                        };
+                        if config.nodelay
+                            && let Err(e) = socket.set_nodelay(true)
+                        {
+                            warn!("set_nodelay() failed: {:?}", e);
+                        }
+
+                        let started = tokio::time::timeout_at(
+                            login_deadline,
+                            run_stream(config, socket, handler),
+                        );
+                        let session = match started.await {
+                            Ok(Ok(session)) => session,
+                            Ok(Err(e)) => {
+                                debug!("Connection setup failed");
+                                audit.record(AuditEvent::Disconnect {
+                                    session: id,
+                                    peer: Some(peer_addr),
+                                    location: location.as_ref(),
+                                    reason: format!("{e:#}"),
+                                    bytes_up: bytes.up(),
+                                    bytes_down: bytes.down(),
+                                });
+                                let _ = error_tx.send(e);
+                                return;
+                            }
+                            Err(_) => {
+                                debug!("Connection setup timed out");
+                                audit.record(AuditEvent::Disconnect {
+                                    session: id,
+                                    peer: Some(peer_addr),
+                                    location: location.as_ref(),
+                                    reason: "login timed out".to_string(),
+                                    bytes_up: bytes.up(),
+                                    bytes_down: bytes.down(),
+                                });
+                                return;
+                            }
+                        };
+
+                        registry.set_handle(id, session.handle());
+                        metrics.session_started();
+                        // Clients that connect and never log in are dropped, so they cannot
+                        // hold on to a connection until the inactivity timeout.
+                        let login_timeout = tokio::time::sleep_until(login_deadline);
+                        tokio::pin!(session, login_timeout);
+                        let (mut checked, mut timed_out) = (false, false);
+                        let result = loop {
+                            tokio::select! {
+                                result = &mut session => break result,
+                                () = &mut login_timeout, if !checked => {
+                                    checked = true;
+                                    if !registry.is_authenticated(id) {
+                                        info!("Disconnecting, did not log in in time");
+                                        let _ = killer.shutdown(std::net::Shutdown::Both);
+                                        timed_out = true;
+                                    }
+                                }
+                            }
+                        };
+                        let reason = match result {
+                            // Shutting the socket fails the session, which is expected.
+                            _ if timed_out => "login timed out".to_string(),
+                            Ok(()) => "closed".to_string(),
+                            Err(e) => {
+                                let reason = format!("{e:#}");
+                                let _ = error_tx.send(e);
+                                reason
+                            }
+                        };
+                        audit.record(AuditEvent::Disconnect {
+                            session: id,
+                            peer: Some(peer_addr),
+                            location: location.as_ref(),
+                            reason,
+                            bytes_up: bytes.up(),
+                            bytes_down: bytes.down(),
+                        });
+                        metrics.session_ended();
+                        debug!("Connection closed");
+                    }.instrument(span));
+                }
+                Some(error) = error_rx.recv() => {
+                    self.handle_session_error(error);
+                }
+                Some(_) = sessions.join_next(), if !sessions.is_empty() => {}
+            }
+        }
+
+        accepting.shutdown().await;
+        if self.systemd {
+            systemd::notify("STOPPING=1");
+        }
+        self.shutdown.trigger();
+
+        let grace_period = self.config.borrow().shutdown_grace_period;
+        info!(
+            "Waiting up to {:?} for {} active sessions to finish",
+            grace_period,
+            sessions.len()
+        );
+        let drained = tokio::time::timeout(grace_period, async {
+            while sessions.join_next().await.is_some() {}
+        })
+        .await;
+
+        if drained.is_err() {
+            warn!(
+                "Grace period elapsed, disconnecting {} remaining sessions",
+                sessions.len()
+            );
+            self.sessions.disconnect_all("Server shutting down").await;
+            sessions.shutdown().await;
+        }
+
+        info!("Shutdown complete");
         Ok(())
     }
 }
 
+/// Splits an accepted connection into the stream to serve and a duplicate of its socket,
+/// which can shut it down from outside.
+fn split_socket(socket: TcpStream) -> std::io::Result<(TcpStream, std::net::TcpStream)> {
+    let socket = socket.into_std()?;
+    let killer = socket.try_clone()?;
+    Ok((TcpStream::from_std(socket)?, killer))
+}
+
 impl Server for PukekoServer {
     type Handler = ClientConnection;
-    fn new_client(&mut self, saddr: Option<std::net::SocketAddr>) -> Self::Handler {
+    fn new_client(&mut self, saddr: Option<SocketAddr>) -> Self::Handler {
         self.id += 1;
 
-        debug!("{}] Got connection from {:?}", self.id, saddr);
-        ClientConnection::new(self.config.clone(), self.id)
+        self.metrics.connection_opened();
+        let handler = ClientConnection::new(
+            self.config.clone(),
+            self.id,
+            self.shutdown.signal(),
+            self.auth.clone(),
+            self.servers.clone(),
+            self.metrics.clone(),
+            self.limiter.clone(),
+            self.used_codes.clone(),
+            self.bandwidth.clone(),
+            self.audit.clone(),
+            self.last_logins.clone(),
+            self.health.clone(),
+            self.history.clone(),
+            self.access.clone(),
+            self.approvals.clone(),
+            self.maintenance.clone(),
+            self.announcements.clone(),
+            self.pool.clone(),
+            self.detached.clone(),
+            self.sessions.clone(),
+            self.updater.clone(),
+            self.inspectors.clone(),
+            self.plugins.clone(),
+            self.realms.clone(),
+            saddr,
+        );
+        handler.span().in_scope(|| debug!("Got connection"));
+        handler
     }
 
     fn handle_session_error(&mut self, error: <Self::Handler as Handler>::Error) {
+        if let Some(russh::Error::KeepaliveTimeout) = error.downcast_ref() {
+            warn!("Disconnected a client that stopped answering keepalives");
+            return;
+        }
         error!("Session error: {:?}", error);
     }
 }
 
+/// A socket SSH connections are accepted on.
+struct Listener {
+    listener: TcpListener,
+    /// Overrides the config's `proxy_protocol`.
+    proxy_protocol: Option<bool>,
+    /// The realm connections log in to.
+    realm: Option<String>,
+}
+
+impl Listener {
+    /// Accepts connections until accepting fails, sending each to `incoming` once its
+    /// PROXY header has been read, if one is expected.
+    async fn accept(
+        self,
+        config: ConfigReceiver,
+        incoming: mpsc::UnboundedSender<(TcpStream, SocketAddr, Option<String>)>,
+    ) -> anyhow::Result<()> {
+        loop {
+            let (mut socket, peer_addr) = self.listener.accept().await?;
+            let proxy_protocol = self
+                .proxy_protocol
+                .unwrap_or_else(|| config.borrow().proxy_protocol);
+            if !proxy_protocol {
+                let _ = incoming.send((socket, peer_addr, self.realm.clone()));
+                continue;
+            }
+
+            let incoming = incoming.clone();
+            let realm = self.realm.clone();
+            tokio::spawn(async move {
+                let header =
+                    tokio::time::timeout(PROXY_HEADER_TIMEOUT, proxy::read_header(&mut socket))
+                        .await;
+                match header {
+                    Ok(Ok(client_addr)) => {
+                        let client_addr = client_addr.unwrap_or(peer_addr);
+                        trace!("PROXY header from {} for {}", peer_addr, client_addr);
+                        let _ = incoming.send((socket, client_addr, realm));
+                    }
+                    Ok(Err(e)) => warn!("Dropping connection from {}: {:#}", peer_addr, e),
+                    Err(_) => warn!("Dropping connection from {}: no PROXY header", peer_addr),
+                }
+            });
+        }
+    }
+}
+
 pub enum ConnectionState {
     Connected,
-    AtMenu {
-        terminal: SshTerminal,
-        menu: PukekoMenu,
-    },
-    //Forwarding,
+    AtMenu(Arc<Mutex<MenuScreen>>),
+    /// A server was picked from the menu and the upstream is still being connected to.
+    Connecting(Forward, Arc<Mutex<MenuScreen>>),
+    /// Forwarding to an upstream, with the menu it was picked from if it was.
+    Forwarding(Forward, Option<Arc<Mutex<MenuScreen>>>),
+    /// The client suspended the forward with the escape character, and is asked what to do
+    /// next.
+    Suspended(Forward, Option<Arc<Mutex<MenuScreen>>>),
+    /// An admin is watching another session in place of the menu.
+    Shadowing(Shadow, Arc<Mutex<MenuScreen>>),
+    /// The client's terminal is split into panes, each forwarding to a server, with the
+    /// menu hidden behind them.
+    Panes(Arc<Mutex<MenuScreen>>),
 }
 
-pub struct ClientConnection {
-    config: Arc<PukekoConfig>,
-    connection_state: ConnectionState,
-    id: usize,
+/// A session channel the client opened. Clients multiplexing a connection, such as with
+/// OpenSSH's ControlMaster, open several at once, each with its own menu or forward.
+pub struct SessionChannel {
+    state: ConnectionState,
+    /// Forwards left suspended while the client is at the menu or forwarding elsewhere,
+    /// the most recent last.
+    suspended: Vec<Forward>,
+    /// Panes opened from the menu, kept while the client is at the menu or forwarding
+    /// elsewhere.
+    panes: Option<Arc<std::sync::Mutex<Panes>>>,
+    pty: Option<PtyRequest>,
+    /// Variables the client set, filtered per server when forwarding.
+    env: Vec<(String, String)>,
+    /// Text of the menu and prompts, in the locale the client set in `env`.
+    messages: Arc<Messages>,
+    /// Set when the client asked to forward X11 and the user may.
+    x11: Option<X11Request>,
+    pending_exec: Option<Vec<u8>>,
+    pending_host_key: Option<UnknownHostKey>,
+    /// Counts the channel in the session's usage, and towards `max_channels`.
+    _permit: ChannelPermit,
+    /// Dropped with the channel, which ends the tasks drawing its menu.
+    closed: tokio::sync::watch::Sender<()>,
+    /// Writes forwarded output as the client's window allows.
+    writer: ChannelWriteHalf<Msg>,
 }
 
-impl ClientConnection {
-    pub fn new(config: Arc<PukekoConfig>, id: usize) -> Self {
+impl SessionChannel {
+    fn new(
+        state: ConnectionState,
+        permit: ChannelPermit,
+        messages: Arc<Messages>,
+        writer: ChannelWriteHalf<Msg>,
+    ) -> Self {
         Self {
-            config,
-            connection_state: ConnectionState::Connected,
-            id,
+            state,
+            suspended: Vec::new(),
+            panes: None,
+            pty: None,
+            env: Vec::new(),
+            messages,
+            x11: None,
+            pending_exec: None,
+            pending_host_key: None,
+            _permit: permit,
+            closed: tokio::sync::watch::Sender::new(()),
+            writer,
         }
     }
-}
-
-impl Handler for ClientConnection {
-    type Error = anyhow::Error;
 
-    async fn auth_publickey_offered(
-        &mut self,
-        user: &str,
-        public_key: &ssh_key::PublicKey,
-    ) -> Result<Auth, Self::Error> {
-        if public_key == &self.config.user_key {
-            trace!(
-                "{}] Accepting {} offered ssh public key {:?}",
-                self.id,
-                user,
-                public_key.to_openssh()?
-            );
-            Ok(Auth::Accept)
-        } else {
-            trace!(
-                "{}] Rejecting {} offered ssh public key {:?}",
-                self.id,
-                user,
-                public_key.to_openssh()?
+    /// The client's X11 request, if `entry` allows X11 forwarding.
+    fn x11_for(&self, entry: &ServerEntry) -> Option<X11Request> {
+        let x11 = self.x11.clone()?;
+        if !entry.x11_forwarding {
+            debug!(
+                "Not forwarding X11 to {}, which does not allow it",
+                entry.name
             );
-            Ok(Auth::reject())
+            return None;
         }
+        Some(x11)
     }
 
-    async fn auth_publickey(
-        &mut self,
-        user: &str,
-        public_key: &ssh_key::PublicKey,
-    ) -> Result<Auth, Self::Error> {
-        trace!(
-            "{}] User {} requested auth with public key {:?}",
-            self.id,
-            user,
-            public_key.to_openssh()?
-        );
-        info!(
-            "{}] Accepting user {} auth pubkey {:?}",
-            self.id,
-            user,
-            public_key.to_openssh()?
-        );
-        Ok(Auth::Accept)
-    }
-
-    async fn data(
-        &mut self,
-        channel: ChannelId,
-        data: &[u8],
-        session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        match &mut self.connection_state {
-            ConnectionState::AtMenu { terminal, menu } => {
-                menu.handle_data(data).await?;
-                terminal.render(menu)?;
+    /// Moves a connection started from the menu on to forwarding once the upstream is
+    /// ready, or back to the menu if it failed. The failure itself is shown by the task
+    /// spawned in `forward_from_menu`, and retrying it selects the server again.
+    fn settle_connecting(&mut self) {
+        let ConnectionState::Connecting(forward, _) = &self.state else {
+            return;
+        };
 
-                match menu.state() {
-                    MenuState::Closing => {
-                        session.close(channel)?;
-                    }
-                    _ => {}
+        match forward.status() {
+            ForwardStatus::Connecting => {}
+            ForwardStatus::Connected => {
+                let state = std::mem::replace(&mut self.state, ConnectionState::Connected);
+                if let ConnectionState::Connecting(forward, screen) = state {
+                    self.pending_exec = None;
+                    self.state = ConnectionState::Forwarding(forward, Some(screen));
                 }
             }
-            _ => {
-                warn!("{}] Got data without a menu open", self.id);
+            ForwardStatus::Failed(e) => {
+                self.pending_host_key = e.downcast_ref::<UnknownHostKey>().cloned();
+                let state = std::mem::replace(&mut self.state, ConnectionState::Connected);
+                if let ConnectionState::Connecting(_, screen) = state {
+                    self.state = ConnectionState::AtMenu(screen);
+                }
             }
+        }
+    }
+
+    /// Returns an admin watching another session to their menu.
+    async fn stop_shadowing(&mut self) -> anyhow::Result<()> {
+        let state = std::mem::replace(&mut self.state, ConnectionState::Connected);
+        let ConnectionState::Shadowing(shadow, screen) = state else {
+            self.state = state;
+            return Ok(());
         };
+        info!("Stopped watching session {}", shadow.session());
+        drop(shadow);
+
+        {
+            let mut locked = screen.lock().await;
+            locked.menu.cancel_selection();
+            locked.terminal.reset()?;
+            locked.render()?;
+        }
+        self.state = ConnectionState::AtMenu(screen);
         Ok(())
     }
 
-    async fn window_change_request(
-        &mut self,
-        _: ChannelId,
-        col_width: u32,
-        row_height: u32,
-        _: u32,
-        _: u32,
-        _: &mut Session,
-    ) -> Result<(), Self::Error> {
-        let rect = Rect {
-            x: 0,
-            y: 0,
-            width: col_width as u16,
-            height: row_height as u16,
-        };
+    fn forward(&self) -> Option<&Forward> {
+        match &self.state {
+            ConnectionState::Connecting(forward, _)
+            | ConnectionState::Forwarding(forward, _)
+            | ConnectionState::Suspended(forward, _) => Some(forward),
+            _ => None,
+        }
+    }
 
-        match &mut self.connection_state {
-            ConnectionState::AtMenu { terminal, menu } => {
-                trace!("{}] trying to resize menu...", self.id);
-                terminal.resize(rect)?;
-                terminal.render(menu)?;
+    /// Suspends the forward the client typed the escape in, asking what to do next.
+    fn suspend(&mut self, channel: ChannelId, session: &mut Session) -> anyhow::Result<()> {
+        let state = std::mem::replace(&mut self.state, ConnectionState::Connected);
+        let ConnectionState::Forwarding(forward, screen) = state else {
+            self.state = state;
+            return Ok(());
+        };
+        info!("Suspended forward to {}", forward.target());
+        forward.suspend()?;
+        let menu = match &screen {
+            // The menu is drawn on the channel again, so it must outlast the upstream.
+            Some(_) => {
+                forward.keep_channel();
+                self.messages.suspended_menu.as_str()
             }
-            _ => {
-                warn!("{}] Got data without a menu open", self.id);
+            None => "",
+        };
+        let suspended = messages::fill(
+            &self.messages.suspended_prompt,
+            &[("server", &forward.target())],
+        );
+        let prompt = format!("\r\n{suspended}{menu}\r\n");
+        session.data(channel, prompt.into())?;
+        self.state = ConnectionState::Suspended(forward, screen);
+        Ok(())
+    }
+
+    /// Draws the menu on the channel again, listing any suspended forwards to return to.
+    async fn show_menu(
+        &mut self,
+        screen: Arc<Mutex<MenuScreen>>,
+        notice: Option<String>,
+    ) -> anyhow::Result<()> {
+        self.suspended.retain(|forward| !forward.is_closed());
+        let panes = self.panes.as_ref().map(|panes| {
+            let servers = panes.lock().unwrap().targets().join(", ");
+            messages::fill(&self.messages.panes_open, &[("servers", &servers)])
+        });
+        let suspended = match self.suspended.as_slice() {
+            [] => panes
+                .map(|panes| messages::fill(&self.messages.return_to_panes, &[("panes", &panes)])),
+            [.., last] => {
+                let names: Vec<&str> = self.suspended.iter().map(Forward::target).collect();
+                let suspended = messages::fill(
+                    &self.messages.suspended,
+                    &[("servers", &names.join(", ")), ("server", &last.target())],
+                );
+                Some(match panes {
+                    Some(panes) => format!("{panes}. {suspended}"),
+                    None => suspended,
+                })
             }
         };
+        {
+            let mut locked = screen.lock().await;
+            locked.menu.cancel_selection();
+            locked.terminal.reset()?;
+            let notice: Vec<String> = notice.into_iter().chain(suspended).collect();
+            if !notice.is_empty() {
+                locked.menu.set_notice(notice.join(". "));
+            }
+            locked.render()?;
+        }
+        self.state = ConnectionState::AtMenu(screen);
+        Ok(())
+    }
 
+    /// Hides the menu behind the panes.
+    async fn show_panes(&mut self, screen: Arc<Mutex<MenuScreen>>) -> anyhow::Result<()> {
+        let Some(panes) = &self.panes else {
+            self.state = ConnectionState::AtMenu(screen);
+            return Ok(());
+        };
+        {
+            let mut locked = screen.lock().await;
+            locked.menu.show_panes();
+            locked.terminal.reset()?;
+        }
+        panes.lock().unwrap().show(true);
+        self.state = ConnectionState::Panes(screen);
         Ok(())
     }
 
-    async fn pty_request(
-        &mut self,
-        channel: ChannelId,
-        _: &str,
-        col_width: u32,
-        row_height: u32,
-        _: u32,
-        _: u32,
-        _: &[(Pty, u32)],
-        session: &mut Session,
-    ) -> Result<(), Self::Error> {
-        let rect = Rect {
-            x: 0,
-            y: 0,
-            width: col_width as u16,
-            height: row_height as u16,
+    /// Shows the menu again once a forward that kept the channel open for it has closed,
+    /// on the key the client was asked to press. Returns whether it did.
+    async fn return_to_menu(&mut self) -> anyhow::Result<bool> {
+        let ConnectionState::Forwarding(forward, Some(_)) = &self.state else {
+            return Ok(false);
         };
+        if !forward.keeps_channel() || !forward.is_closed() {
+            return Ok(false);
+        }
+        let state = std::mem::replace(&mut self.state, ConnectionState::Connected);
+        if let ConnectionState::Forwarding(forward, Some(screen)) = state {
+            let notice = format!("Disconnected from {}", forward.target());
+            self.show_menu(screen, Some(notice)).await?;
+        }
+        Ok(true)
+    }
+}
 
-        match &mut self.connection_state {
-            ConnectionState::AtMenu { terminal, menu } => {
-                trace!("{}] creating pseudo terminal", self.id);
-                terminal.resize(rect)?;
-                terminal.render(menu)?;
+pub struct ClientConnection {
+    config: ConfigReceiver,
+    id: usize,
+    shutdown: ShutdownSignal,
+    auth: Arc<dyn AuthProvider>,
+    servers: Arc<dyn ServerProvider>,
+    metrics: Arc<Metrics>,
+    limiter: Arc<ConnectionLimiter>,
+    /// Verification codes already used, which are not accepted again.
+    used_codes: Arc<UsedCodes>,
+    bandwidth: Arc<BandwidthLimits>,
+    audit: Arc<AuditLog>,
+    last_logins: Arc<LastLogins>,
+    health: Arc<HealthMonitor>,
+    history: Arc<History>,
+    access: Arc<AccessRequests>,
+    approvals: Arc<KeyApprovals>,
+    maintenance: Arc<Maintenance>,
+    announcements: Arc<Announcements>,
+    pool: Arc<UpstreamPool>,
+    /// Shells kept running after their clients disconnected, for the users to resume.
+    detached: Arc<DetachedSessions>,
+    sessions: Arc<SessionRegistry>,
+    /// Reloads the config after users change their stored keys, and holds the store ended
+    /// sessions are recorded in, if any.
+    updater: Option<ConfigUpdater>,
+    inspectors: Arc<[Arc<dyn StreamInspector>]>,
+    plugins: Arc<Plugins>,
+    realms: Arc<Realms>,
+    /// The realm of the listener the client connected to, which it logs in to.
+    listener_realm: Option<String>,
+    /// The realm the client is logging in to or logged in to, None outside any.
+    realm: Option<String>,
+    /// Bytes forwarded by this connection, shown to admins.
+    bytes: Arc<SessionBytes>,
+    peer_addr: Option<SocketAddr>,
+    /// Where the client connected from, when GeoIP databases are configured.
+    location: Option<Location>,
+    user: Option<String>,
+    /// The user's login before this one, for the banner.
+    previous_login: Option<LastLogin>,
+    target: Option<String>,
+    pending_password: Option<PendingLogin>,
+    second_factor: Option<SecondFactor>,
+    /// Wrong verification codes entered on this connection, up to `MAX_CODE_FAILURES`.
+    code_failures: usize,
+    device_login: Option<PendingDeviceLogin>,
+    /// OIDC sign-ins started on this connection.
+    device_logins: usize,
+    refused: Option<RefusedLogin>,
+    /// When the key the user logged in with expires, if soon enough to warn them.
+    key_expires: Option<chrono::DateTime<chrono::Utc>>,
+    agent_forwarding: bool,
+    /// Whether the user logged in with a verification code as well as their key.
+    verified: bool,
+    /// Wrong passwords tried on this connection, counted towards `honeypot.after_failures`.
+    password_failures: usize,
+    /// Set instead of `user` when the connection was let into the honeypot, which its
+    /// session channels are then run by.
+    honeypot: Option<Honeypot>,
+    /// The session channels each menu or forward runs on. Other channels, such as the
+    /// client's forwarded agent, are driven through their own `Channel` handles.
+    session_channels: HashMap<ChannelId, SessionChannel>,
+    /// Ports listened on for `ssh -R`, by the address the client asked for and the port
+    /// bound, which the server picks for those asking for port 0.
+    remote_forwards: HashMap<(String, u32), RemoteForward>,
+    /// Counts the connection towards the connection limits. Set by the server once the
+    /// limits let it in.
+    permit: Option<ConnectionPermit>,
+    /// The buffered input and channels this connection holds, limited by `max_channels`.
+    usage: Arc<SessionUsage>,
+    /// Entered by the handlers and the tasks they spawn, so each event is tagged with the
+    /// session it belongs to.
+    span: Span,
+}
 
-                session.channel_success(channel)?;
-            }
-            _ => {
-                warn!(
-                    "{}] Attempted to create a pseudo terminal without a terminal handle",
-                    self.id
-                );
-            }
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        self.span.record("bytes", self.bytes.total());
+        self.detach_shells();
+        let Some(info) = self.sessions.remove(self.id) else {
+            return;
+        };
+        if self.honeypot.is_some() {
+            return;
+        }
+        let bytes = info.bytes();
+        let store = self
+            .updater
+            .as_ref()
+            .and_then(ConfigUpdater::store)
+            .cloned();
+        let (Some(store), Some(user)) = (store, info.user) else {
+            return;
         };
 
-        Ok(())
+        let ended_at = chrono::Utc::now().timestamp();
+        let record = SessionRecord {
+            session: info.id,
+            user,
+            peer: info.peer.map(|peer| peer.ip().to_string()),
+            target: info.target,
+            started_at: ended_at - info.duration.as_secs() as i64,
+            ended_at,
+            bytes,
+        };
+        let span = self.span.clone();
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = store.record_session(&record) {
+                span.in_scope(|| warn!("Failed to record session: {:?}", e));
+            }
+        });
     }
+}
 
-    async fn channel_open_session(
-        &mut self,
-        channel: Channel<Msg>,
-        session: &mut Session,
-    ) -> Result<bool, Self::Error> {
-        if matches!(self.connection_state, ConnectionState::Connected) {
-            let (terminal, menu) = PukekoMenu::from_session(channel, session).await?;
-            self.connection_state = ConnectionState::AtMenu { terminal, menu };
-            Ok(true)
-        } else {
-            Ok(false)
+/// A login whose key was accepted but that still has to enter the user's password.
+struct PendingLogin {
+    login: String,
+    target: Option<String>,
+    identity: Identity,
+}
+
+fn password_method() -> MethodSet {
+    let mut methods = MethodSet::empty();
+    methods.push(russh::MethodKind::Password);
+    methods
+}
+
+/// A login whose key was accepted but that still has to enter a TOTP code.
+struct SecondFactor {
+    login: String,
+    target: Option<String>,
+    identity: Identity,
+    secret: Vec<u8>,
+}
+
+fn keyboard_interactive() -> MethodSet {
+    let mut methods = MethodSet::empty();
+    methods.push(russh::MethodKind::KeyboardInteractive);
+    methods
+}
+
+/// A login waiting for the user to sign in to the OIDC provider in their browser.
+struct PendingDeviceLogin {
+    login: String,
+    flow: DeviceLogin,
+}
+
+/// A login refused, such as outside its schedule or with an expired key, which is told
+/// why if it continues with keyboard-interactive.
+struct RefusedLogin {
+    login: String,
+    /// Shown as the title, such as "Access denied".
+    name: &'static str,
+    message: String,
+}
+
+fn device_login_prompt(instructions: String) -> Auth {
+    Auth::Partial {
+        name: Cow::Borrowed("Single sign-on"),
+        instructions: Cow::Owned(instructions),
+        prompts: Cow::Owned(vec![(
+            Cow::Borrowed("Press Enter once you have signed in: "),
+            true,
+        )]),
+    }
+}
+
+/// Forgets the keys approved for and waiting for users no longer in the config, so they
+/// cannot log in with them if added again.
+fn forget_removed_users(approvals: &KeyApprovals, config: &PukekoConfig) {
+    for user in approvals.retain_users(|user| config.user(user).is_some()) {
+        info!(
+            "Forgot the approved and waiting keys of {}, who was removed",
+            user
+        );
+    }
+}
+
+/// Disconnects sessions whose user's schedule has closed, if it is set to `terminate`.
+async fn enforce_schedules(
+    realms: Arc<Realms>,
+    sessions: Arc<SessionRegistry>,
+    audit: Arc<AuditLog>,
+) {
+    let mut interval = tokio::time::interval(SCHEDULE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = chrono::Utc::now();
+        let closed: Vec<_> = sessions
+            .list()
+            .into_iter()
+            .filter_map(|session| {
+                let user = session.user?;
+                let (realm, _) = realms.resolve(None, &user)?;
+                let groups = realm.servers.groups(&user);
+                let config = realm.config.borrow();
+                let schedule = config
+                    .closed_schedule(&user, &groups, now)
+                    .filter(|schedule| schedule.terminate)?;
+                Some((session.id, user, schedule.name.clone()))
+            })
+            .collect();
+        for (id, user, schedule) in closed {
+            info!(
+                "Disconnecting session {} of {}, schedule {} has closed",
+                id, user, schedule
+            );
+            audit.record(AuditEvent::OutsideSchedule {
+                session: id,
+                user: &user,
+                schedule: &schedule,
+                disconnected: true,
+            });
+            sessions
+                .disconnect(id, "Your access window has closed")
+                .await;
         }
     }
+}
 
-    async fn channel_close(
+/// Disconnects sessions holding more of the client's input for their forwards than
+/// `max_session_buffered`.
+async fn enforce_session_limits(
+    config: ConfigReceiver,
+    sessions: Arc<SessionRegistry>,
+    audit: Arc<AuditLog>,
+) {
+    let mut interval = tokio::time::interval(SESSION_LIMIT_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(max) = config.borrow().limits.max_session_buffered else {
+            continue;
+        };
+        let exceeded: Vec<_> = sessions
+            .list()
+            .into_iter()
+            .filter(|session| session.buffered > max)
+            .collect();
+        for session in exceeded {
+            let user = session.user.as_deref().unwrap_or_default();
+            info!(
+                "Disconnecting session {} of {}, holding {} bytes of input over the {} allowed",
+                session.id, user, session.buffered, max
+            );
+            audit.record(AuditEvent::ResourceLimitExceeded {
+                session: session.id,
+                user,
+                limit: "max_session_buffered",
+                usage: session.buffered,
+                max,
+            });
+            sessions
+                .disconnect(session.id, "Too much input is waiting for the server")
+                .await;
+        }
+    }
+}
+
+/// Disconnects the forwarding sessions of users who have used up their daily minutes.
+async fn enforce_quotas(
+    config: ConfigReceiver,
+    sessions: Arc<SessionRegistry>,
+    audit: Arc<AuditLog>,
+) {
+    let mut interval = tokio::time::interval(QUOTA_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let out_of_time = {
+            let config = config.borrow();
+            sessions
+                .quotas()
+                .out_of_time(|user| config.for_user(user).quotas(user))
+        };
+        for (id, user, minutes) in out_of_time {
+            info!(
+                "Disconnecting session {} of {}, its {} minutes for today are used up",
+                id, user, minutes
+            );
+            audit.record(AuditEvent::QuotaExceeded {
+                session: id,
+                user: &user,
+                server: None,
+                quota: "daily_minutes",
+                disconnected: true,
+            });
+            sessions
+                .disconnect(id, "Your time connected for today is used up")
+                .await;
+        }
+    }
+}
+
+/// The session's status, shown when the client types the escape character and `s`.
+fn status_line(target: &str, info: &SessionInfo) -> String {
+    format!(
+        "\r\n[pukeko] {target}: connected for {}, {} up, {} down, {}/s\r\n",
+        format_duration(info.duration),
+        format_bytes(info.bytes_up),
+        format_bytes(info.bytes_down),
+        format_bytes(info.throughput),
+    )
+}
+
+/// Splits a login of the form `user+target` into the user and the requested target.
+fn parse_login(login: &str) -> (&str, Option<&str>) {
+    match login.split_once('+') {
+        Some((user, target)) if !target.is_empty() => (user, Some(target)),
+        _ => (login, None),
+    }
+}
+
+impl ClientConnection {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: ConfigReceiver,
+        id: usize,
+        shutdown: ShutdownSignal,
+        auth: Arc<dyn AuthProvider>,
+        servers: Arc<dyn ServerProvider>,
+        metrics: Arc<Metrics>,
+        limiter: Arc<ConnectionLimiter>,
+        used_codes: Arc<UsedCodes>,
+        bandwidth: Arc<BandwidthLimits>,
+        audit: Arc<AuditLog>,
+        last_logins: Arc<LastLogins>,
+        health: Arc<HealthMonitor>,
+        history: Arc<History>,
+        access: Arc<AccessRequests>,
+        approvals: Arc<KeyApprovals>,
+        maintenance: Arc<Maintenance>,
+        announcements: Arc<Announcements>,
+        pool: Arc<UpstreamPool>,
+        detached: Arc<DetachedSessions>,
+        sessions: Arc<SessionRegistry>,
+        updater: Option<ConfigUpdater>,
+        inspectors: Arc<[Arc<dyn StreamInspector>]>,
+        plugins: Arc<Plugins>,
+        realms: Arc<Realms>,
+        peer_addr: Option<SocketAddr>,
+    ) -> Self {
+        let max_channels = config.borrow().limits.max_channels;
+        let (bytes, usage) = sessions.register(id, peer_addr, max_channels);
+        let span = info_span!(
+            parent: None,
+            "session",
+            session_id = id,
+            peer_addr = peer_addr.map(field::display),
+            user = field::Empty,
+            target = field::Empty,
+            bytes = field::Empty,
+        );
+        Self {
+            config,
+            id,
+            shutdown,
+            auth,
+            servers,
+            metrics,
+            limiter,
+            used_codes,
+            bandwidth,
+            audit,
+            last_logins,
+            health,
+            history,
+            access,
+            approvals,
+            maintenance,
+            announcements,
+            pool,
+            detached,
+            sessions,
+            updater,
+            inspectors,
+            plugins,
+            realms,
+            listener_realm: None,
+            realm: None,
+            bytes,
+            peer_addr,
+            location: None,
+            user: None,
+            previous_login: None,
+            target: None,
+            pending_password: None,
+            second_factor: None,
+            code_failures: 0,
+            device_login: None,
+            device_logins: 0,
+            refused: None,
+            key_expires: None,
+            agent_forwarding: false,
+            verified: false,
+            password_failures: 0,
+            honeypot: None,
+            session_channels: HashMap::new(),
+            remote_forwards: HashMap::new(),
+            permit: None,
+            usage,
+            span,
+        }
+    }
+
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Switches to the realm `login` logs in to, returning the login with its user named
+    /// as in the realm. None when the listener's realm is no longer configured.
+    fn enter_realm(&mut self, login: &str) -> Option<String> {
+        let Some((realm, login)) = self.realms.resolve(self.listener_realm.as_deref(), login)
+        else {
+            warn!(
+                "Refusing {}, realm {} is no longer configured",
+                login,
+                self.listener_realm.as_deref().unwrap_or_default()
+            );
+            return None;
+        };
+        self.config = realm.config.clone();
+        self.auth = realm.auth.clone();
+        self.servers = realm.servers.clone();
+        self.audit = realm.audit.clone();
+        self.realm = realm.name.clone();
+        Some(login)
+    }
+
+    /// Whether an admin of this session may manage session `id`, on `node` when it is
+    /// another of the cluster, which they may only in their own realm.
+    fn manages(&self, node: Option<&str>, id: usize) -> bool {
+        let info = match node {
+            Some(node) => self.sessions.remote_info(node, id),
+            None => self.sessions.info(id),
+        };
+        info.is_some_and(|info| sessions::in_realm(info.user.as_deref(), self.realm.as_deref()))
+    }
+
+    fn is_banned(&self) -> bool {
+        self.peer_addr
+            .is_some_and(|addr| self.limiter.is_banned(addr.ip()))
+    }
+
+    /// Asks the auth provider who `public_key` belongs to, rejecting banned addresses.
+    async fn verify(&self, user: &str, public_key: &ssh_key::PublicKey) -> Option<Identity> {
+        if self.is_banned() {
+            return None;
+        }
+        let identity = self.identity(user, self.auth.verify(user, public_key).await);
+        // Keys an admin approved without a database to add them to, while the user is
+        // still configured.
+        identity.or_else(|| {
+            (self.config.borrow().user(user).is_some()
+                && self.approvals.is_approved(user, public_key))
+            .then(|| Identity::new(user))
+        })
+    }
+
+    /// Whether keys that are not on file may be held for approval. Every offered key is
+    /// then asked to be signed with, whoever the login names, so that the answer does not
+    /// tell a client which users exist.
+    fn holds_keys(&self) -> bool {
+        self.config.borrow().key_approval && !self.is_banned()
+    }
+
+    /// Records a key `login` signed with that is not on file, for an admin to approve,
+    /// and lets the client go on to its other keys. If none are accepted, continuing with
+    /// keyboard-interactive tells the user the key is awaiting approval. Keys are only held
+    /// for known users, but others are refused and told the same.
+    fn hold_for_approval(&mut self, login: &str, public_key: &ssh_key::PublicKey) -> Auth {
+        if !self.holds_keys() {
+            return Auth::reject();
+        }
+        let (user, _) = parse_login(login);
+        let known = self.config.borrow().user(user).is_some();
+        let recorded = if known {
+            let peer = self.peer_addr.map(|addr| addr.ip().to_string());
+            self.approvals.record(user, public_key, peer)
+        } else {
+            // Told the same as a known user, from a request no admin sees.
+            self.approvals
+                .decoy(user, public_key)
+                .map(|decoy| (decoy, false))
+        };
+        let (pending, new) = match recorded {
+            Ok(recorded) => recorded,
+            Err(e) => {
+                warn!("Not holding the key of {} for approval: {:#}", user, e);
+                return self.reject_key();
+            }
+        };
+        if new {
+            info!(
+                "Key {} of {} is awaiting approval as {}",
+                pending.fingerprint, user, pending.id
+            );
+            self.audit.record(AuditEvent::KeyAwaitingApproval {
+                session: self.id,
+                user,
+                fingerprint: &pending.fingerprint,
+                request: pending.id,
+            });
+        }
+        self.refused = Some(RefusedLogin {
+            login: login.to_string(),
+            name: "Key awaiting approval",
+            message: format!(
+                "Your key {} is new to {user}. It is awaiting approval by an admin as \
+                 request {}, after which you can log in with it.",
+                pending.fingerprint, pending.id
+            ),
+        });
+        self.reject_key()
+    }
+
+    /// When the key `login` offered expired, if it is on file for the user and expired.
+    fn key_expired(
+        &self,
+        login: &str,
+        public_key: &ssh_key::PublicKey,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        let (name, _) = parse_login(login);
+        let config = self.config.borrow();
+        let key = config.user(name)?.key(public_key)?;
+        let expiry = config.key_expiry.expiry(key)?;
+        (expiry <= chrono::Utc::now()).then_some(expiry)
+    }
+
+    /// Refuses a key that expired, and lets the client go on to its other keys. If none
+    /// are accepted, continuing with keyboard-interactive tells the user why.
+    fn refuse_expired_key(
+        &mut self,
+        login: &str,
+        public_key: &ssh_key::PublicKey,
+        expired: chrono::DateTime<chrono::Utc>,
+    ) -> Auth {
+        let fingerprint = public_key.fingerprint(Default::default());
+        warn!("Refusing {}, key {} has expired", login, fingerprint);
+        self.refused = Some(RefusedLogin {
+            login: login.to_string(),
+            name: "Key expired",
+            message: format!(
+                "Your key {fingerprint} expired on {}. Ask an admin to add a new one.",
+                expired.format("%Y-%m-%d %H:%M UTC")
+            ),
+        });
+        self.reject_key()
+    }
+
+    /// Rejects a key while leaving the client its other keys, passwords and
+    /// keyboard-interactive, which shows the user why the key was refused.
+    fn reject_key(&self) -> Auth {
+        let mut methods = MethodSet::empty();
+        methods.push(russh::MethodKind::PublicKey);
+        if self.auth.accepts_passwords() {
+            methods.push(russh::MethodKind::Password);
+        }
+        methods.push(russh::MethodKind::KeyboardInteractive);
+        Auth::Reject {
+            proceed_with_methods: Some(methods),
+            partial_success: false,
+        }
+    }
+
+    /// Verifies a login of the form `user`, `user+target` or `target`. A bare login that
+    /// is not a user but names a server is authenticated as whoever owns the key, and
+    /// connects to that server. In a realm, the server is named without the realm's
+    /// suffix.
+    async fn verify_login(
+        &self,
+        login: &str,
+        public_key: &ssh_key::PublicKey,
+    ) -> Option<(Identity, Option<String>)> {
+        let (name, target) = parse_login(login);
+        if let Some(identity) = self.verify(name, public_key).await {
+            return Some((identity, target.map(str::to_string)));
+        }
+        let name = match &self.realm {
+            Some(realm) if realms::in_realm(name, realm) => &name[..name.len() - realm.len() - 1],
+            _ => name,
+        };
+        if target.is_some() || self.is_banned() || self.servers.server(name).is_none() {
+            return None;
+        }
+
+        let identity = self.identity(login, self.auth.identify(public_key).await)?;
+        debug!(
+            "Login {} is a server, identified {} by key",
+            login, identity.user
+        );
+        Some((identity, Some(name.to_string())))
+    }
+
+    async fn verify_certificate(
+        &self,
+        user: &str,
+        certificate: &ssh_key::Certificate,
+    ) -> Option<Identity> {
+        if self.is_banned() {
+            return None;
+        }
+        let peer = self.peer_addr.map(|addr| addr.ip());
+        let decision = self.auth.verify_certificate(user, certificate, peer).await;
+        self.identity(user, decision)
+    }
+
+    fn identity(&self, user: &str, decision: anyhow::Result<AuthDecision>) -> Option<Identity> {
+        match decision {
+            Ok(AuthDecision::Accept(identity)) => Some(identity),
+            Ok(AuthDecision::Reject) => None,
+            Err(e) => {
+                warn!("Failed to verify {}: {:?}", user, e);
+                None
+            }
+        }
+    }
+
+    /// Completes authentication with a key as `identity`, unless the user also needs their
+    /// password in which case the client is asked to continue with it.
+    async fn authenticated(
+        &mut self,
+        login: &str,
+        target: Option<&str>,
+        identity: Identity,
+    ) -> Auth {
+        if self.password_and_key(&identity.user) {
+            debug!("Asking {} for their password", login);
+            self.pending_password = Some(PendingLogin {
+                login: login.to_string(),
+                target: target.map(str::to_string),
+                identity,
+            });
+            return Auth::Reject {
+                proceed_with_methods: Some(password_method()),
+                partial_success: true,
+            };
+        }
+        self.verified(login, target, identity).await
+    }
+
+    /// The country the client connected from, if it is outside `trusted_countries`.
+    fn untrusted_country(&self) -> Option<String> {
+        let config = self.config.borrow();
+        let geoip = config.geoip.as_ref()?;
+        self.location
+            .as_ref()?
+            .untrusted_country(geoip)
+            .map(str::to_string)
+    }
+
+    fn password_and_key(&self, user: &str) -> bool {
+        self.config
+            .borrow()
+            .user(user)
+            .is_some_and(|entry| entry.password_and_key)
+    }
+
+    /// Completes authentication as `identity`, unless the user also needs a TOTP code in
+    /// which case the client is asked to continue with keyboard-interactive. Outside the
+    /// user's schedule, when a plugin refuses it, or from an untrusted country without a
+    /// TOTP secret, it is refused and keyboard-interactive tells them why.
+    async fn verified(&mut self, login: &str, target: Option<&str>, identity: Identity) -> Auth {
+        let groups = self.servers.groups(&identity.user);
+        let closed = self
+            .config
+            .borrow()
+            .closed_schedule(&identity.user, &groups, chrono::Utc::now())
+            .map(|schedule| (schedule.name.clone(), schedule.describe()));
+        if let Some((schedule, hours)) = closed {
+            warn!(
+                "Refusing {}, outside of schedule {}",
+                identity.user, schedule
+            );
+            self.audit.record(AuditEvent::OutsideSchedule {
+                session: self.id,
+                user: &identity.user,
+                schedule: &schedule,
+                disconnected: false,
+            });
+            self.refused = Some(RefusedLogin {
+                login: login.to_string(),
+                name: "Access denied",
+                message: format!("{} may only log in {}.", identity.user, hours),
+            });
+            return Auth::Reject {
+                proceed_with_methods: Some(keyboard_interactive()),
+                partial_success: false,
+            };
+        }
+
+        let attempt = LoginAttempt {
+            user: &identity.user,
+            login,
+            target,
+            peer: self.peer_addr,
+            location: self.location.as_ref(),
+            attributes: &identity.attributes,
+        };
+        if let Some((plugin, reason)) = self.plugins.refuses(&attempt).await {
+            warn!("Refusing {}, plugin {}: {}", identity.user, plugin, reason);
+            self.audit.record(AuditEvent::PluginRefused {
+                session: self.id,
+                user: &identity.user,
+                plugin: &plugin,
+                reason: &reason,
+            });
+            self.refused = Some(RefusedLogin {
+                login: login.to_string(),
+                name: "Access denied",
+                message: reason,
+            });
+            return Auth::Reject {
+                proceed_with_methods: Some(keyboard_interactive()),
+                partial_success: false,
+            };
+        }
+
+        let secret = self
+            .config
+            .borrow()
+            .user(&identity.user)
+            .and_then(|user| user.totp_secret.clone());
+
+        if secret.is_none()
+            && let Some(country) = self.untrusted_country()
+        {
+            warn!(
+                "Refusing {}, logging in from {} without a TOTP secret",
+                identity.user, country
+            );
+            self.audit.record(AuditEvent::UntrustedCountry {
+                session: self.id,
+                user: &identity.user,
+                country: &country,
+            });
+            self.refused = Some(RefusedLogin {
+                login: login.to_string(),
+                name: "Access denied",
+                message: format!(
+                    "Logins from {country} need a verification code, but {} has none set \
+                     up. Ask an admin for one.",
+                    identity.user
+                ),
+            });
+            return Auth::Reject {
+                proceed_with_methods: Some(keyboard_interactive()),
+                partial_success: false,
+            };
+        }
+
+        match secret {
+            Some(secret) => {
+                debug!("Asking {} for a verification code", login);
+                self.second_factor = Some(SecondFactor {
+                    login: login.to_string(),
+                    target: target.map(str::to_string),
+                    identity,
+                    secret,
+                });
+                Auth::Reject {
+                    proceed_with_methods: Some(keyboard_interactive()),
+                    partial_success: true,
+                }
+            }
+            None => {
+                self.logged_in(identity.user, target.map(str::to_string));
+                Auth::Accept
+            }
+        }
+    }
+
+    /// Signs `login` in with the OIDC device flow, which starts when the client first asks
+    /// for keyboard-interactive. Each answer polls for the result, asking again while the
+    /// user has not finished signing in.
+    async fn device_login(&mut self, login: &str, answered: bool) -> anyhow::Result<Auth> {
+        let oidc = self.config.borrow().oidc.clone();
+        let Some(oidc) = oidc.filter(|_| !self.is_banned()) else {
+            return Ok(Auth::reject());
+        };
+
+        let pending = self
+            .device_login
+            .take()
+            .filter(|pending| answered && pending.login == login);
+        let Some(mut pending) = pending else {
+            let allowed = self.device_logins < oidc.max_flows_per_connection
+                && self.peer_addr.is_none_or(|addr| {
+                    self.limiter
+                        .sign_in_started(addr.ip(), oidc.max_flows_per_ip, oidc.flow_window)
+                });
+            if !allowed {
+                warn!("Refusing to start another sign-in for {}", login);
+                return Ok(Auth::reject());
+            }
+            self.device_logins += 1;
+            return match DeviceLogin::start(&oidc).await {
+                Ok(flow) => {
+                    debug!("Asking {} to sign in to {}", login, oidc.issuer);
+                    let prompt = device_login_prompt(flow.instructions());
+                    self.device_login = Some(PendingDeviceLogin {
+                        login: login.to_string(),
+                        flow,
+                    });
+                    Ok(prompt)
+                }
+                Err(e) => {
+                    warn!("Failed to start sign-in for {}: {:?}", login, e);
+                    Ok(Auth::reject())
+                }
+            };
+        };
+
+        let signed_in = match pending.flow.poll(DEVICE_LOGIN_WAIT).await {
+            Ok(Some(signed_in)) => signed_in,
+            Ok(None) => {
+                let instructions = format!("Not signed in yet.\r\n{}", pending.flow.instructions());
+                self.device_login = Some(pending);
+                return Ok(device_login_prompt(instructions));
+            }
+            Err(e) => {
+                warn!("Failed to sign in {}: {:#}", login, e);
+                self.record_auth(login, "keyboard-interactive", None, None);
+                return Ok(Auth::reject());
+            }
+        };
+
+        // As with keys, a bare login naming a server connects to it as whoever signed in.
+        let (name, target) = parse_login(login);
+        let target = match target {
+            _ if name == signed_in.user => target.map(str::to_string),
+            None if self.servers.server(name).is_some() => Some(name.to_string()),
+            _ => {
+                warn!("Login {} signed in as {}", login, signed_in.user);
+                self.record_auth(login, "keyboard-interactive", None, None);
+                return Ok(Auth::reject());
+            }
+        };
+
+        info!(
+            "Accepting user {} signed in as {}",
+            login, signed_in.subject
+        );
+        let identity = Identity::new(signed_in.user)
+            .with_attribute("oidc_issuer", signed_in.issuer)
+            .with_attribute("oidc_subject", signed_in.subject);
+        self.record_auth(login, "keyboard-interactive", None, Some(&identity));
+        Ok(self.verified(login, target.as_deref(), identity).await)
+    }
+
+    /// Whether `login` is one of the honeypot's decoy users.
+    fn is_decoy(&self, login: &str) -> bool {
+        let (name, _) = parse_login(login);
+        self.config
+            .borrow()
+            .honeypot
+            .as_ref()
+            .is_some_and(|honeypot| honeypot.users.iter().any(|user| user == name))
+    }
+
+    /// Lets the client into the honeypot as `login`, which it got into with `method`.
+    fn enter_honeypot(&mut self, login: &str, method: &str) -> Auth {
+        let Some(config) = self.config.borrow().honeypot.clone() else {
+            return Auth::reject();
+        };
+        warn!("Letting {} into the honeypot", login);
+        self.audit.record(AuditEvent::HoneypotEntered {
+            session: self.id,
+            peer: self.peer_addr,
+            location: self.location.as_ref(),
+            login,
+            method,
+        });
+        self.sessions.set_user(self.id, login);
+        self.span.record("user", field::display(login));
+        if let Some(permit) = &mut self.permit {
+            permit.authenticated();
+        }
+        self.honeypot = Some(Honeypot::new(
+            self.id,
+            login.to_string(),
+            config,
+            self.audit.clone(),
+        ));
+        Auth::Accept
+    }
+
+    fn logged_in(&mut self, user: String, target: Option<String>) {
+        let peer = self.peer_addr.map(|addr| addr.ip());
+        self.previous_login = self.last_logins.record(&user, peer);
+        self.sessions.set_user(self.id, &user);
+        self.span.record("user", field::display(&user));
+        if let Some(permit) = &mut self.permit {
+            permit.authenticated();
+        }
+        self.user = Some(user);
+        self.target = target;
+    }
+
+    /// Keeps the shells started from the menu that the client dropped without closing, for
+    /// the user to resume, when `timeouts.detached` is set. Channels closed by the client
+    /// were already removed along with their forwards.
+    fn detach_shells(&mut self) {
+        let Some(grace) = self.config.borrow().timeouts.detached else {
+            return;
+        };
+        let Some(user) = &self.user else {
+            return;
+        };
+        // Sessions terminated by an admin or the shutdown are not kept.
+        if self.shutdown.is_shutting_down() || self.sessions.was_disconnected(self.id) {
+            return;
+        }
+        for (_, mut session_channel) in self.session_channels.drain() {
+            session_channel.settle_connecting();
+            if let ConnectionState::Forwarding(forward, Some(_)) = session_channel.state
+                && forward.is_interactive()
+                && !forward.is_closed()
+            {
+                self.detached.detach(user, forward, grace);
+            }
+        }
+    }
+
+    fn is_admin(&self) -> bool {
+        let user = self.user.as_deref().unwrap_or_default();
+        self.config
+            .borrow()
+            .user(user)
+            .is_some_and(|entry| entry.admin)
+    }
+
+    /// The banner shown in `mode`, from `messages` if it sets one.
+    fn banner(&self, mode: BannerMode, messages: &Messages) -> Option<String> {
+        let config = self.config.borrow();
+        let template = Some(messages.banner.as_str())
+            .filter(|banner| !banner.is_empty())
+            .or(config.banner.text.as_deref())?;
+        (config.banner.mode == mode).then(|| {
+            banner::render(
+                template,
+                self.user.as_deref().unwrap_or_default(),
+                self.peer_addr.map(|addr| addr.ip()),
+                self.previous_login.as_ref(),
+            )
+        })
+    }
+
+    /// Audits an attempt to authenticate, counting it towards a ban if it failed.
+    fn record_auth(
+        &self,
+        user: &str,
+        method: &str,
+        public_key: Option<&ssh_key::PublicKey>,
+        identity: Option<&Identity>,
+    ) {
+        self.audit_auth(user, method, public_key, identity);
+        if identity.is_none() {
+            self.metrics.auth_failed();
+            if let Some(addr) = self.peer_addr {
+                let limits = self.config.borrow().limits;
+                if let Some(ban) = self.limiter.auth_failed(addr.ip(), &limits) {
+                    self.audit.record(AuditEvent::AddressBanned {
+                        address: addr.ip(),
+                        duration_secs: ban.duration,
+                        count: ban.count,
+                    });
+                }
+            }
+        }
+    }
+
+    fn audit_auth(
+        &self,
+        user: &str,
+        method: &str,
+        public_key: Option<&ssh_key::PublicKey>,
+        identity: Option<&Identity>,
+    ) {
+        self.audit.record(AuditEvent::Auth {
+            session: self.id,
+            peer: self.peer_addr,
+            location: self.location.as_ref(),
+            user,
+            method,
+            fingerprint: public_key.map(|key| key.fingerprint(Default::default()).to_string()),
+            accepted: identity.is_some(),
+            attributes: identity
+                .map(|identity| &identity.attributes)
+                .filter(|attributes| !attributes.is_empty()),
+        });
+    }
+
+    fn notify_on_shutdown(
+        &self,
+        screen: Arc<Mutex<MenuScreen>>,
+        channel: ChannelId,
+        mut closed: tokio::sync::watch::Receiver<()>,
+        handle: Handle,
+    ) {
+        let mut shutdown = self.shutdown.clone();
+        tokio::spawn(
+            async move {
+                tokio::select! {
+                    _ = closed.changed() => return,
+                    _ = shutdown.wait() => {}
+                }
+
+                {
+                    let mut screen = screen.lock().await;
+                    // Forwarded sessions are drained by the server rather than closed here.
+                    if !matches!(screen.menu.state(), MenuState::Open) {
+                        return;
+                    }
+
+                    trace!("notifying menu of shutdown");
+                    let notice = screen.menu.messages().shutting_down.clone();
+                    screen.menu.set_notice(notice);
+                    if let Err(e) = screen.render() {
+                        warn!("failed to render shutdown notice: {:?}", e);
+                    }
+                    let _ = screen.terminal.set_mouse(false);
+                }
+
+                tokio::time::sleep(SHUTDOWN_NOTICE_DELAY).await;
+                let _ = handle.close(channel).await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Counts down once the menu has been idle for a while and closes it when time is up.
+    fn close_when_idle(
+        &self,
+        screen: Arc<Mutex<MenuScreen>>,
+        channel: ChannelId,
+        mut closed: tokio::sync::watch::Receiver<()>,
+        handle: Handle,
+    ) {
+        let config = self.config.clone();
+        tokio::spawn(
+            async move {
+                let mut ticks = tokio::time::interval(Duration::from_secs(1));
+                ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                loop {
+                    tokio::select! {
+                        _ = closed.changed() => return,
+                        _ = ticks.tick() => {}
+                    }
+
+                    let timeouts = config.borrow().timeouts;
+                    let mut screen = screen.lock().await;
+                    if !matches!(screen.menu.state(), MenuState::Open) {
+                        continue;
+                    }
+
+                    let idle = screen.menu.idle_for();
+                    let banner = match timeouts.menu_idle {
+                        Some(limit) if idle >= limit => break,
+                        Some(limit) if limit - idle <= timeouts.menu_idle_warning => {
+                            let remaining = (limit - idle).as_secs_f64().ceil();
+                            Some(messages::fill(
+                                &screen.menu.messages().idle_warning,
+                                &[("seconds", &remaining)],
+                            ))
+                        }
+                        _ => None,
+                    };
+                    if screen.menu.banner() != banner.as_deref() {
+                        screen.menu.set_banner(banner);
+                        if let Err(e) = screen.render() {
+                            warn!("failed to render idle countdown: {:?}", e);
+                        }
+                    }
+                }
+
+                info!("Closing idle menu");
+                {
+                    let mut screen = screen.lock().await;
+                    screen.menu.set_banner(None);
+                    let notice = screen.menu.messages().idle_disconnected.clone();
+                    screen.menu.set_notice(notice);
+                    let _ = screen.render();
+                    let _ = screen.terminal.set_mouse(false);
+                }
+                tokio::time::sleep(SHUTDOWN_NOTICE_DELAY).await;
+                let _ = handle.close(channel).await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Renders the menu while it is showing, apart from after input: on config reloads and
+    /// health checks, which may change it, for messages from admins, for input left for
+    /// the next frame, and on a tick while it shows something that changes over time. While
+    /// an admin has the sessions view open it is also rendered on session events.
+    fn render_on_change(
+        &self,
+        screen: Arc<Mutex<MenuScreen>>,
+        mut closed: tokio::sync::watch::Receiver<()>,
+    ) {
+        let mut config = self.config.clone();
+        let mut health = self.health.subscribe();
+        let mut events = self.sessions.subscribe();
+        let user = self.user.clone().unwrap_or_default();
+        // Only admins have the sessions view.
+        let live = self.is_admin();
+        tokio::spawn(
+            async move {
+                let frame_due = screen.lock().await.frame_due();
+                let labelled = screen.lock().await.menu.labelled();
+                // When to redraw what changes over time, kept across other wakeups.
+                let mut next_tick = None;
+                loop {
+                    let interval = screen.lock().await.menu.tick_interval();
+                    next_tick = interval.map(|interval| {
+                        next_tick.unwrap_or_else(|| tokio::time::Instant::now() + interval)
+                    });
+                    let tick = async {
+                        match next_tick {
+                            Some(at) => tokio::time::sleep_until(at).await,
+                            None => std::future::pending().await,
+                        }
+                    };
+                    tokio::select! {
+                        _ = closed.changed() => break,
+                        _ = frame_due.notified() => {
+                            let Some(due) = screen.lock().await.next_frame() else {
+                                continue;
+                            };
+                            tokio::time::sleep_until(due.into()).await;
+                            if screen.lock().await.next_frame().is_none() {
+                                continue;
+                            }
+                        }
+                        _ = tick => next_tick = None,
+                        _ = labelled.notified() => {}
+                        event = events.recv() => match event {
+                            Ok(SessionEvent::Broadcast { from, realm, message })
+                                if sessions::in_realm(Some(&user), realm.as_deref()) =>
+                            {
+                                screen.lock().await.menu.show_message(from, message);
+                            }
+                            Ok(SessionEvent::Message { user: to, from, message }) if to == user => {
+                                screen.lock().await.menu.show_message(from, message);
+                            }
+                            Err(broadcast::error::RecvError::Closed) => break,
+                            _ => {
+                                if !live || !screen.lock().await.menu.showing_sessions() {
+                                    continue;
+                                }
+                            }
+                        },
+                        changed = config.changed() => {
+                            if changed.is_err() {
+                                break;
+                            }
+                            trace!("config reloaded, re-rendering menu");
+                        }
+                        changed = health.changed() => {
+                            if changed.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    let mut screen = screen.lock().await;
+                    // The menu is shown again when the user returns to it, from a forward,
+                    // the panes, or watching another session.
+                    if !matches!(screen.menu.state(), MenuState::Open)
+                        && !screen.menu.is_connecting()
+                    {
+                        continue;
+                    }
+                    if let Err(e) = screen.render() {
+                        warn!("failed to render menu: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Draws the panes whenever their output changes or they are resized, while they are
+    /// showing. Ends with the panes, or the channel.
+    fn render_panes(
+        &self,
+        screen: Arc<Mutex<MenuScreen>>,
+        panes: Weak<std::sync::Mutex<Panes>>,
+        mut closed: tokio::sync::watch::Receiver<()>,
+    ) {
+        let Some(redraw) = panes.upgrade().map(|panes| panes.lock().unwrap().redraw()) else {
+            return;
+        };
+        tokio::spawn(
+            async move {
+                loop {
+                    tokio::select! {
+                        _ = closed.changed() => break,
+                        _ = redraw.notified() => {}
+                    }
+                    let Some(panes) = panes.upgrade() else {
+                        break;
+                    };
+                    let mut screen = screen.lock().await;
+                    let panes = panes.lock().unwrap();
+                    if !panes.showing() {
+                        continue;
+                    }
+                    if let Err(e) = screen.render_panes(&panes) {
+                        warn!("failed to render panes: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// The SSH server `user` may jump to at `host` and `port`, which with `rewrite` may
+    /// also be the server's name, on any port.
+    fn jump_target(&self, user: &str, host: &str, port: u32) -> Option<ServerEntry> {
+        let rewrite = self.config.borrow().proxy_jump.rewrite;
+        self.servers.servers(user).into_iter().find(|entry| {
+            entry.protocol == Protocol::Ssh
+                && ((entry.host == host && u32::from(entry.port) == port)
+                    || (rewrite && entry.name == host))
+        })
+    }
+
+    /// Files the user's request for access to `server`, posting new ones to the webhook.
+    /// Returns what to tell the user.
+    fn request_access(&self, server: &str, reason: &str, messages: &Messages) -> String {
+        let user = self.user.as_deref().unwrap_or_default();
+        let Some(settings) = self.config.borrow().access_requests.clone() else {
+            return messages.requests_disabled.clone();
+        };
+        let (request, new) = self.access.request(user, server, reason);
+        let values: [(&str, &dyn std::fmt::Display); 2] =
+            [("server", &server), ("id", &request.id)];
+        if !new {
+            return messages::fill(&messages.already_requested, &values);
+        }
+
+        info!("{} requested access to {}: {}", user, server, reason);
+        self.audit.record(AuditEvent::AccessRequested {
+            session: self.id,
+            user,
+            server,
+            reason,
+            request: request.id,
+        });
+        if let Some(url) = settings.webhook {
+            access::notify_webhook(url, request.clone());
+        }
+        messages::fill(&messages.requested, &values)
+    }
+
+    /// The user's keys: those on file, then those approved and awaiting approval.
+    fn user_keys(&self) -> Vec<ListedKey> {
+        let user = self.user.as_deref().unwrap_or_default();
+        let stored = self.stored_keys(user).unwrap_or_default();
+        let mut keys = Vec::new();
+        if let Some(entry) = self.config.borrow().user(user) {
+            for key in &entry.keys {
+                let mut stripped = key.key.clone();
+                stripped.set_comment("");
+                let Ok(openssh) = stripped.to_openssh() else {
+                    continue;
+                };
+                let source = if stored.contains(&openssh) {
+                    KeySource::Stored
+                } else {
+                    KeySource::Config
+                };
+                keys.push(ListedKey {
+                    key: openssh,
+                    fingerprint: key.key.fingerprint(Default::default()).to_string(),
+                    comment: key.comment.clone(),
+                    source,
+                });
+            }
+        }
+        for key in self.approvals.approved(user) {
+            let Ok(public_key) = ssh_key::PublicKey::from_openssh(&key) else {
+                continue;
+            };
+            keys.push(ListedKey {
+                key,
+                fingerprint: public_key.fingerprint(Default::default()).to_string(),
+                comment: None,
+                source: KeySource::Approved,
+            });
+        }
+        for pending in self.approvals.pending() {
+            if pending.user == user {
+                keys.push(ListedKey {
+                    key: pending.key,
+                    fingerprint: pending.fingerprint,
+                    comment: None,
+                    source: KeySource::Pending,
+                });
+            }
+        }
+        keys
+    }
+
+    /// The keys of `user` in the database, or `None` when the user is not kept there.
+    fn stored_keys(&self, user: &str) -> Option<Vec<String>> {
+        let store = self.updater.as_ref()?.store()?;
+        match store.users() {
+            Ok(users) => users
+                .into_iter()
+                .find(|stored| stored.name == user)
+                .map(|stored| stored.keys.into_iter().map(|key| key.key).collect()),
+            Err(e) => {
+                warn!("Failed to read stored users: {:#}", e);
+                None
+            }
+        }
+    }
+
+    /// Makes a change to the database and reloads the config so that it takes effect.
+    fn change_store(&self, change: StoreChange) -> anyhow::Result<()> {
+        let updater = self.updater.as_ref().context("No database is configured")?;
+        updater
+            .store()
+            .context("No database is configured")?
+            .apply(&change)?;
+        updater.reload()
+    }
+
+    /// Adds a key the user pasted in the menu, or holds it for an admin to approve when
+    /// key management asks for approval. Returns what to tell the user.
+    fn add_key(&self, pasted: &str, messages: &Messages) -> String {
+        let user = self.user.as_deref().unwrap_or_default();
+        let Some(settings) = self.config.borrow().key_management else {
+            return messages.keys_failed.clone();
+        };
+        let Ok(key) = ssh_key::PublicKey::from_openssh(pasted) else {
+            return messages.invalid_key.clone();
+        };
+        let Ok(openssh) = store::normalize_key(pasted) else {
+            return messages.invalid_key.clone();
+        };
+        let fingerprint = key.fingerprint(Default::default()).to_string();
+        let values: [(&str, &dyn std::fmt::Display); 1] = [("fingerprint", &fingerprint)];
+        if self.user_keys().iter().any(|listed| listed.key == openssh) {
+            return messages::fill(&messages.key_exists, &values);
+        }
+
+        if settings.approval {
+            let peer = self.peer_addr.map(|addr| addr.ip().to_string());
+            let pending = match self.approvals.record(user, &key, peer) {
+                Ok((pending, _)) => pending,
+                Err(e) => {
+                    warn!("Failed to hold the key of {} for approval: {:#}", user, e);
+                    return messages.keys_failed.clone();
+                }
+            };
+            info!(
+                "Key {} added by {} is awaiting approval as {}",
+                fingerprint, user, pending.id
+            );
+            self.audit.record(AuditEvent::KeyAwaitingApproval {
+                session: self.id,
+                user,
+                fingerprint: &fingerprint,
+                request: pending.id,
+            });
+            return messages::fill(&messages.key_awaiting_approval, &values);
+        }
+
+        // Users in the database get the key added there, like any other key.
+        if self.stored_keys(user).is_some() {
+            let comment = Some(key.comment().to_string()).filter(|comment| !comment.is_empty());
+            let change = StoreChange::AddKey {
+                user: user.to_string(),
+                key: openssh,
+                expires: None,
+                comment,
+            };
+            if let Err(e) = self.change_store(change) {
+                warn!("Failed to add a key for {}: {:#}", user, e);
+                return messages.keys_failed.clone();
+            }
+        } else {
+            self.approvals.add(user, &openssh);
+        }
+        info!("{} added key {}", user, fingerprint);
+        self.audit.record(AuditEvent::KeyChanged {
+            session: self.id,
+            user,
+            fingerprint: &fingerprint,
+            added: true,
+        });
+        messages::fill(&messages.key_added, &values)
+    }
+
+    /// Removes one of the user's keys, unless it is in the config file or one of the last
+    /// they must keep. Returns what to tell the user.
+    fn remove_key(&self, key: &str, messages: &Messages) -> String {
+        let user = self.user.as_deref().unwrap_or_default();
+        let Some(settings) = self.config.borrow().key_management else {
+            return messages.keys_failed.clone();
+        };
+        let keys = self.user_keys();
+        let Some(listed) = keys.iter().find(|listed| listed.key == key) else {
+            return messages.keys_failed.clone();
+        };
+        let kept = keys
+            .iter()
+            .filter(|listed| listed.source != KeySource::Pending)
+            .count();
+        match listed.source {
+            KeySource::Config => return messages.config_key.clone(),
+            KeySource::Stored | KeySource::Approved if kept <= settings.min_keys => {
+                return messages::fill(&messages.min_keys, &[("count", &settings.min_keys)]);
+            }
+            KeySource::Stored => {
+                let change = StoreChange::RemoveKey {
+                    user: user.to_string(),
+                    key: key.to_string(),
+                };
+                if let Err(e) = self.change_store(change) {
+                    warn!("Failed to remove a key of {}: {:#}", user, e);
+                    return messages.keys_failed.clone();
+                }
+            }
+            KeySource::Approved | KeySource::Pending => {
+                self.approvals.remove(user, key);
+            }
+        }
+
+        info!("{} removed key {}", user, listed.fingerprint);
+        self.audit.record(AuditEvent::KeyChanged {
+            session: self.id,
+            user,
+            fingerprint: &listed.fingerprint,
+            added: false,
+        });
+        messages::fill(
+            &messages.key_removed,
+            &[("fingerprint", &listed.fingerprint)],
+        )
+    }
+
+    /// Forwards `session_channel` to `entry`, passing on the environment variables and X11
+    /// forwarding `session_channel` was given where the config allows them.
+    #[allow(clippy::too_many_arguments)]
+    async fn start_forward(
+        &self,
+        entry: &ServerEntry,
+        kind: ForwardKind,
+        pty: Option<PtyRequest>,
+        session_channel: &SessionChannel,
+        pane: Option<mpsc::UnboundedSender<Vec<u8>>>,
+        held: Option<oneshot::Receiver<()>>,
+        session: &mut Session,
+    ) -> anyhow::Result<Forward> {
+        let config = self.config.borrow().clone();
+        let user = self.user.as_deref().unwrap_or_default();
+        if !self.servers.can_access(user, &entry.name) {
+            warn!("Denied {} access to {} by ACL", user, entry.name);
+            self.audit.record(AuditEvent::AccessDenied {
+                session: self.id,
+                user,
+                server: &entry.name,
+            });
+            anyhow::bail!("Access to {} is not permitted", entry.name);
+        }
+        if let Some(window) = self.maintenance.blocks(&entry.name, self.is_admin()) {
+            warn!("Denied {} access to {} under maintenance", user, entry.name);
+            match window.ends() {
+                Some(ends) => anyhow::bail!("{} is under maintenance until {}", entry.name, ends),
+                None => anyhow::bail!("{} is under maintenance", entry.name),
+            }
+        }
+        if let Some(policy) = &entry.commands {
+            let command = kind.command();
+            let allowed = matches!(kind, ForwardKind::Exec(_))
+                && command
+                    .as_deref()
+                    .is_some_and(|command| policy.allows(command));
+            if !allowed {
+                match &command {
+                    Some(command) => warn!(
+                        "Denied {} running {} {:?} on {}",
+                        user,
+                        kind.name(),
+                        command,
+                        entry.name
+                    ),
+                    None => warn!("Denied {} a {} on {}", user, kind.name(), entry.name),
+                }
+                self.audit.record(AuditEvent::CommandDenied {
+                    session: self.id,
+                    user,
+                    server: &entry.name,
+                    kind: kind.name(),
+                    command,
+                });
+                anyhow::bail!("Only some commands may be run on {}", entry.name);
+            }
+        }
+        let quota = match self.count_forward(entry, kind.name()) {
+            Ok(quota) => quota,
+            Err(exceeded) => {
+                let messages = config.messages(&session_channel.env);
+                anyhow::bail!(exceeded.message(&messages));
+            }
+        };
+        self.authorize(entry, kind.name(), kind.command()).await?;
+
+        info!("Forwarding {} to {} ({:?})", user, entry.name, kind);
+        self.audit.record(AuditEvent::ForwardStart {
+            session: self.id,
+            user,
+            server: &entry.name,
+            kind: kind.name(),
+            command: kind.command(),
+        });
+        self.sessions.set_target(self.id, &entry.name);
+        self.span.record("target", field::display(&entry.name));
+        let bandwidth = self.bandwidth.forward(&config, user, entry);
+        let mut inspectors: Vec<Arc<dyn StreamInspector>> = Vec::new();
+        if !config.inspect.is_empty() {
+            inspectors.push(Arc::new(PatternInspector::new(config.inspect.clone())));
+        }
+        if let Some(inspector) = ClipboardInspector::new(config.clipboard_policy(entry)) {
+            inspectors.push(Arc::new(inspector));
+        }
+        inspectors.extend(self.plugins.inspector());
+        inspectors.extend(self.inspectors.iter().cloned());
+        let env = session_channel
+            .env
+            .iter()
+            .filter(|(name, _)| config.forwards_env(entry, name))
+            .cloned()
+            .collect();
+        let request = ForwardRequest {
+            session: self.id,
+            entry: entry.clone(),
+            user: user.to_string(),
+            config,
+            kind,
+            pty,
+            agent_forwarding: self.agent_forwarding,
+            x11: session_channel.x11_for(entry),
+            bytes: self.bytes.clone(),
+            usage: self.usage.clone(),
+            bandwidth,
+            events: self.sessions.subscribe(),
+            output: self.sessions.output(self.id),
+            inspectors,
+            pane,
+            pool: self.pool.clone(),
+            held,
+            env,
+            quota,
+        };
+        let forward = Forward::start(
+            request,
+            ChannelOutput::new(session.handle(), &session_channel.writer),
+            self.metrics.clone(),
+            self.audit.clone(),
+        );
+
+        let ready = forward.ready();
+        let history = self.history.clone();
+        let (user, server) = (user.to_string(), entry.name.clone());
+        tokio::spawn(async move {
+            if let ForwardStatus::Connected = ready.await {
+                history.record_connection(&user, &server);
+            }
+        });
+        Ok(forward)
+    }
+
+    /// Counts a `kind` forward to `entry` towards the user's quotas, unless it would go
+    /// over one of them.
+    fn count_forward(&self, entry: &ServerEntry, kind: &str) -> Result<QuotaPermit, QuotaExceeded> {
+        let user = self.user.as_deref().unwrap_or_default();
+        let quotas = self.config.borrow().quotas(user);
+        self.sessions
+            .quotas()
+            .acquire(self.id, user, &entry.name, &quotas)
+            .inspect_err(|exceeded| {
+                warn!("Denied {} a {} on {}: {}", user, kind, entry.name, exceeded);
+                self.audit.record(AuditEvent::QuotaExceeded {
+                    session: self.id,
+                    user,
+                    server: Some(&entry.name),
+                    quota: exceeded.quota(),
+                    disconnected: false,
+                });
+            })
+    }
+
+    /// Asks the authorization hook, if there is one, whether the user may start a `kind`
+    /// forward to `entry`, failing with the reason if not. Forwards are denied when the
+    /// hook fails.
+    async fn authorize(
+        &self,
+        entry: &ServerEntry,
+        kind: &str,
+        command: Option<String>,
+    ) -> anyhow::Result<()> {
+        let Some(authorization) = self.config.borrow().authorization.clone() else {
+            return Ok(());
+        };
+        let user = self.user.as_deref().unwrap_or_default();
+        let request = AuthorizationRequest {
+            session: self.id,
+            user,
+            peer: self.peer_addr,
+            location: self.location.as_ref(),
+            server: &entry.name,
+            host: &entry.host,
+            port: entry.port,
+            group: entry.group.as_deref(),
+            tags: &entry.tags,
+            kind,
+            command,
+            mfa: self.verified,
+        };
+        let reason = match authorization::authorize(&authorization, &request).await {
+            Ok(Verdict {
+                decision: Decision::Allow,
+                ..
+            }) => return Ok(()),
+            Ok(Verdict {
+                decision: Decision::MfaRequired,
+                ..
+            }) if self.verified => return Ok(()),
+            Ok(Verdict {
+                decision: Decision::MfaRequired,
+                reason,
+            }) => reason.unwrap_or_else(|| {
+                format!("{} needs a login with a verification code", entry.name)
+            }),
+            Ok(Verdict {
+                decision: Decision::Deny,
+                reason,
+            }) => reason.unwrap_or_else(|| format!("Access to {} is not permitted", entry.name)),
+            Err(e) => {
+                warn!("Authorization hook failed, denying: {:#}", e);
+                format!("Access to {} could not be checked", entry.name)
+            }
+        };
+        warn!(
+            "Denied {} a {} on {} by the authorization hook: {}",
+            user, kind, entry.name, reason
+        );
+        self.audit.record(AuditEvent::AuthorizationDenied {
+            session: self.id,
+            user,
+            server: &entry.name,
+            kind,
+            reason: &reason,
+        });
+        anyhow::bail!(reason)
+    }
+
+    async fn forward_from_menu(
+        &self,
+        session_channel: &mut SessionChannel,
+        entry: &ServerEntry,
+        screen: Arc<Mutex<MenuScreen>>,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        if let Some(i) = session_channel
+            .suspended
+            .iter()
+            .position(|forward| forward.target() == entry.name && !forward.is_closed())
+        {
+            let forward = session_channel.suspended.remove(i);
+            screen.lock().await.terminal.release()?;
+            return self.resume(session_channel, forward, Some(screen));
+        }
+
+        let kind = match &session_channel.pending_exec {
+            Some(command) => ForwardKind::Exec(command.clone()),
+            None => ForwardKind::Shell,
+        };
+
+        let pty = session_channel.pty.clone();
+        // The menu shows a spinner until the upstream is ready, then hands it the terminal.
+        let (release, held) = oneshot::channel();
+        let forward = match self
+            .start_forward(entry, kind, pty, session_channel, None, Some(held), session)
+            .await
+        {
+            Ok(forward) => forward,
+            Err(e) => {
+                let mut screen = screen.lock().await;
+                screen.menu.cancel_selection();
+                screen.menu.set_notice(format!("{e:#}"));
+                screen.render()?;
+                return Ok(());
+            }
+        };
+
+        {
+            let mut screen = screen.lock().await;
+            screen.menu.set_connecting(Some(entry.name.clone()));
+            screen.render()?;
+        }
+        // The menu is shown again when it closes, to return to those suspended or the panes.
+        if !session_channel.suspended.is_empty() || session_channel.panes.is_some() {
+            forward.keep_channel();
+        }
+
+        let ready = forward.ready();
+        let target = entry.clone();
+        let menu = screen.clone();
+        tokio::spawn(
+            async move {
+                let status = ready.await;
+                let mut screen = menu.lock().await;
+                screen.menu.set_connecting(None);
+                let ForwardStatus::Failed(e) = status else {
+                    if let Err(e) = screen.terminal.release() {
+                        warn!("failed to release terminal: {:?}", e);
+                    }
+                    let _ = release.send(());
+                    return;
+                };
+
+                screen.menu.cancel_selection();
+                if let Some(unknown) = e.downcast_ref::<UnknownHostKey>() {
+                    info!("{}, asking user to confirm", unknown);
+                    let fingerprint = unknown.key.fingerprint(Default::default());
+                    let prompt = messages::fill(
+                        &screen.menu.messages().trust_host_key,
+                        &[("fingerprint", &fingerprint), ("server", &unknown.server)],
+                    );
+                    screen.menu.confirm(prompt);
+                } else {
+                    warn!("Failed to forward to {}: {:?}", target.name, e);
+                    screen.menu.show_failure(target, &e);
+                }
+                if let Err(e) = screen.render() {
+                    warn!("failed to render forward failure: {:?}", e);
+                }
+            }
+            .in_current_span(),
+        );
+
+        session_channel.state = ConnectionState::Connecting(forward, screen);
+        Ok(())
+    }
+
+    /// Connects to `entry` in a new pane, splitting the client's terminal with any panes
+    /// already open.
+    async fn open_pane(
+        &self,
+        session_channel: &mut SessionChannel,
+        entry: &ServerEntry,
+        screen: Arc<Mutex<MenuScreen>>,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        let Some(pty) = session_channel.pty.clone() else {
+            let mut screen = screen.lock().await;
+            screen.menu.cancel_selection();
+            screen
+                .menu
+                .set_notice(session_channel.messages.panes_need_terminal.clone());
+            screen.render()?;
+            return Ok(());
+        };
+
+        let panes = match &session_channel.panes {
+            Some(panes) => panes.clone(),
+            None => {
+                let area = Rect::new(0, 0, pty.col_width as u16, pty.row_height as u16);
+                let prefix = self.config.borrow().pane_prefix;
+                let panes = Arc::new(std::sync::Mutex::new(Panes::new(prefix, area)));
+                self.render_panes(
+                    screen.clone(),
+                    Arc::downgrade(&panes),
+                    session_channel.closed.subscribe(),
+                );
+                session_channel.panes = Some(panes.clone());
+                panes
+            }
+        };
+        let (col_width, row_height) = panes.lock().unwrap().next_size();
+        let pty = PtyRequest {
+            col_width: col_width.into(),
+            row_height: row_height.into(),
+            pix_width: 0,
+            pix_height: 0,
+            ..pty
+        };
+
+        let (output, received) = mpsc::unbounded_channel();
+        let failed = output.clone();
+        let kind = ForwardKind::Shell;
+        let forward = match self
+            .start_forward(
+                entry,
+                kind,
+                Some(pty),
+                session_channel,
+                Some(output),
+                None,
+                session,
+            )
+            .await
+        {
+            Ok(forward) => forward,
+            Err(e) => {
+                if panes.lock().unwrap().targets().is_empty() {
+                    session_channel.panes = None;
+                }
+                let mut screen = screen.lock().await;
+                screen.menu.cancel_selection();
+                screen.menu.set_notice(format!("{e:#}"));
+                screen.render()?;
+                return Ok(());
+            }
+        };
+
+        // Failures are shown in the pane, which closes on the next key.
+        let ready = forward.ready();
+        let target = entry.name.clone();
+        tokio::spawn(
+            async move {
+                if let ForwardStatus::Failed(e) = ready.await {
+                    warn!("Failed to forward to {} in a pane: {:?}", target, e);
+                    let _ = failed.send(format!("pukeko: {e:#}\r\n").into_bytes());
+                }
+            }
+            .in_current_span(),
+        );
+
+        panes.lock().unwrap().add(forward, received);
+        session_channel.show_panes(screen).await
+    }
+
+    /// Acts on the key pressed at the prompt of a suspended forward.
+    async fn suspended_input(
+        &self,
+        session_channel: &mut SessionChannel,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let state = std::mem::replace(&mut session_channel.state, ConnectionState::Connected);
+        let ConnectionState::Suspended(forward, screen) = state else {
+            session_channel.state = state;
+            return Ok(());
+        };
+        let escape = self.config.borrow().escape_char;
+        match (data.first().copied(), screen) {
+            (Some(b'd'), Some(screen)) => {
+                info!("Disconnected from {}", forward.target());
+                let notice = format!("Disconnected from {}", forward.target());
+                drop(forward);
+                session_channel.show_menu(screen, Some(notice)).await?;
+            }
+            (Some(b'd'), None) => {
+                info!("Disconnected from {}", forward.target());
+                // Closing the upstream closes the channel, and with it the session.
+                forward.close()?;
+                session_channel.state = ConnectionState::Forwarding(forward, None);
+            }
+            (Some(b'm'), Some(screen)) => {
+                session_channel.suspended.push(forward);
+                session_channel.show_menu(screen, None).await?;
+            }
+            (Some(key), screen) if key == b'r' || key == b'\r' || Some(key) == escape => {
+                self.resume(session_channel, forward, screen)?;
+            }
+            (_, screen) => session_channel.state = ConnectionState::Suspended(forward, screen),
+        }
+        Ok(())
+    }
+
+    /// Goes back to forwarding to a suspended upstream.
+    fn resume(
+        &self,
+        session_channel: &mut SessionChannel,
+        forward: Forward,
+        screen: Option<Arc<Mutex<MenuScreen>>>,
+    ) -> anyhow::Result<()> {
+        info!("Resumed forward to {}", forward.target());
+        forward.resume()?;
+        // Terminals only signal a change in size, so changing it back and forth has full
+        // screen programs redraw what the prompt or menu covered.
+        if let Some(pty) = &session_channel.pty {
+            let rows = pty.row_height.saturating_sub(1);
+            forward.window_change(pty.col_width, rows, pty.pix_width, pty.pix_height)?;
+            forward.window_change(pty.col_width, pty.row_height, pty.pix_width, pty.pix_height)?;
+        }
+        let current = self.sessions.info(self.id).and_then(|info| info.target);
+        if current.as_deref() != Some(forward.target()) {
+            self.sessions.set_target(self.id, forward.target());
+            self.span.record("target", field::display(forward.target()));
+        }
+        session_channel.state = ConnectionState::Forwarding(forward, screen);
+        Ok(())
+    }
+
+    /// Handles input on a session channel, for its menu or forward.
+    async fn session_data(
+        &self,
+        session_channel: &mut SessionChannel,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        session_channel.settle_connecting();
+        if session_channel.return_to_menu().await? {
+            return Ok(());
+        }
+
+        let (mut shadow, mut escape, mut resume) = (None, None, None);
+        let (mut pane, mut show_panes, mut pane_command) = (None, None, None);
+        let mut reattach = None;
+        let selected = match &session_channel.state {
+            ConnectionState::AtMenu(screen) => {
+                let mut locked = screen.lock().await;
+                locked.menu.handle_data(data).await?;
+                locked.render_input()?;
+                let messages = session_channel.messages.clone();
+
+                match locked.menu.state() {
+                    // Quitting the menu returns to the last suspended forward instead.
+                    MenuState::Closing if !session_channel.suspended.is_empty() => {
+                        locked.menu.reopen();
+                        resume = session_channel
+                            .suspended
+                            .pop()
+                            .map(|forward| (forward, screen.clone()));
+                        None
+                    }
+                    // And then to the panes.
+                    MenuState::Closing if session_channel.panes.is_some() => {
+                        locked.menu.reopen();
+                        show_panes = Some(screen.clone());
+                        None
+                    }
+                    MenuState::Closing => {
+                        // The terminal's own writes are sent by a task, so would arrive
+                        // after the close.
+                        if locked.terminal.is_plain() {
+                            session.exit_status_request(channel, 0)?;
+                        } else {
+                            session.data(channel, DISABLE_MOUSE.into())?;
+                        }
+                        session.close(channel)?;
+                        None
+                    }
+                    MenuState::Selected(entry) => {
+                        self.audit.record(AuditEvent::MenuSelection {
+                            session: self.id,
+                            user: self.user.as_deref().unwrap_or_default(),
+                            server: &entry.name,
+                        });
+                        Some((entry.clone(), screen.clone()))
+                    }
+                    MenuState::Pane(entry) => {
+                        self.audit.record(AuditEvent::MenuSelection {
+                            session: self.id,
+                            user: self.user.as_deref().unwrap_or_default(),
+                            server: &entry.name,
+                        });
+                        pane = Some((entry.clone(), screen.clone()));
+                        None
+                    }
+                    MenuState::Confirmed => match session_channel.pending_host_key.take() {
+                        Some(unknown) => {
+                            let config = self.config.borrow().clone();
+                            let entry = self.servers.server(&unknown.server);
+                            if let Some(entry) = &entry {
+                                upstream::trust_host_key(&config, entry, &unknown.key)?;
+                            }
+                            locked.menu.cancel_selection();
+                            entry.map(|entry| (entry, screen.clone()))
+                        }
+                        None => {
+                            locked.menu.cancel_selection();
+                            None
+                        }
+                    },
+                    MenuState::Terminate { node, id } => {
+                        const REASON: &str = "Terminated by an administrator";
+                        let (node, target) = (node.as_deref(), *id);
+                        let name = sessions::session_name(node, target);
+                        let user = self.user.as_deref().unwrap_or_default();
+                        if !self.is_admin() {
+                            warn!("{} is no longer an admin", user);
+                            locked.menu.set_notice(messages.admins_terminate.clone());
+                        } else if self.manages(node, target)
+                            && match node {
+                                Some(node) => self.sessions.disconnect_remote(node, target, REASON),
+                                None => self.sessions.disconnect(target, REASON).await,
+                            }
+                        {
+                            info!("{} terminated session {}", user, name);
+                            self.audit.record(AuditEvent::SessionTerminated {
+                                session: self.id,
+                                user,
+                                terminated: target,
+                                node,
+                            });
+                            locked
+                                .menu
+                                .set_notice(messages::fill(&messages.terminated, &[("id", &name)]));
+                        } else {
+                            locked.menu.set_notice(messages::fill(
+                                &messages.already_ended,
+                                &[("id", &name)],
+                            ));
+                        }
+                        locked.menu.cancel_selection();
+                        locked.render()?;
+                        None
+                    }
+                    MenuState::Broadcast(message) => {
+                        let message = message.clone();
+                        let user = self.user.as_deref().unwrap_or_default();
+                        if self.is_admin() {
+                            let sessions =
+                                self.sessions
+                                    .broadcast(user, self.realm.as_deref(), &message);
+                            info!("{} messaged {} sessions: {}", user, sessions, message);
+                            self.audit.record(AuditEvent::Broadcast {
+                                session: self.id,
+                                user,
+                                message: &message,
+                            });
+                        } else {
+                            warn!("{} is no longer an admin", user);
+                            locked.menu.set_notice(messages.admins_message.clone());
+                        }
+                        locked.menu.cancel_selection();
+                        locked.render()?;
+                        None
+                    }
+                    &MenuState::Shadow(target) => {
+                        let user = self.user.as_deref().unwrap_or_default();
+                        if !self.is_admin() {
+                            warn!("{} is no longer an admin", user);
+                            locked.menu.set_notice(messages.admins_watch.clone());
+                        } else if self.manages(None, target) {
+                            shadow = Shadow::start(
+                                self.id,
+                                user,
+                                target,
+                                screen.clone(),
+                                self.sessions.clone(),
+                                self.audit.clone(),
+                            );
+                        }
+
+                        if shadow.is_some() {
+                            info!("{} is watching session {}", user, target);
+                            locked.terminal.release()?;
+                            let watching = messages::fill(&messages.watching, &[("id", &target)]);
+                            locked
+                                .terminal
+                                .write(format!("{watching}\r\n").as_bytes())?;
+                        } else {
+                            if self.is_admin() {
+                                locked.menu.set_notice(messages::fill(
+                                    &messages.not_forwarding,
+                                    &[("id", &target)],
+                                ));
+                            }
+                            locked.menu.cancel_selection();
+                            locked.render()?;
+                        }
+                        None
+                    }
+                    &MenuState::Resume(id) => {
+                        let user = self.user.as_deref().unwrap_or_default();
+                        locked.menu.cancel_selection();
+                        match self.detached.resume(id, user) {
+                            Some(forward) => reattach = Some((forward, screen.clone())),
+                            None => {
+                                locked.menu.set_notice(messages.resume_ended.clone());
+                                locked.render()?;
+                            }
+                        }
+                        None
+                    }
+                    MenuState::RequestAccess { server, reason } => {
+                        let notice = self.request_access(server, reason, &messages);
+                        locked.menu.set_notice(notice);
+                        locked.menu.cancel_selection();
+                        locked.render()?;
+                        None
+                    }
+                    MenuState::ListKeys => {
+                        locked.menu.show_keys(self.user_keys());
+                        locked.menu.cancel_selection();
+                        locked.render()?;
+                        None
+                    }
+                    MenuState::AddKey(key) => {
+                        let notice = self.add_key(key, &messages);
+                        locked.menu.show_keys(self.user_keys());
+                        locked.menu.set_notice(notice);
+                        locked.menu.cancel_selection();
+                        locked.render()?;
+                        None
+                    }
+                    MenuState::RemoveKey(key) => {
+                        let notice = self.remove_key(key, &messages);
+                        locked.menu.show_keys(self.user_keys());
+                        locked.menu.set_notice(notice);
+                        locked.menu.cancel_selection();
+                        locked.render()?;
+                        None
+                    }
+                    MenuState::Open | MenuState::Panes => None,
+                }
+            }
+            ConnectionState::Panes(screen) => {
+                if let Some(panes) = &session_channel.panes {
+                    let command = panes.lock().unwrap().input(data)?;
+                    pane_command = command.map(|command| (command, screen.clone()));
+                }
+                None
+            }
+            ConnectionState::Connecting(forward, _) | ConnectionState::Forwarding(forward, _) => {
+                match forward.data(data)? {
+                    Some(EscapeCommand::Status) => {
+                        if let Some(info) = self.sessions.info(self.id) {
+                            forward.message(status_line(forward.target(), &info))?;
+                        }
+                    }
+                    command => escape = command,
+                }
+                None
+            }
+            ConnectionState::Suspended(..) => {
+                escape = Some(EscapeCommand::Suspend);
+                None
+            }
+            ConnectionState::Shadowing(..) => {
+                if data.iter().any(|&b| matches!(b, b'q' | b'\x03' | b'\x1b')) {
+                    session_channel.stop_shadowing().await?;
+                }
+                None
+            }
+            ConnectionState::Connected => {
+                warn!("Got data without a menu open");
+                None
+            }
+        };
+
+        if let Some(shadow) = shadow
+            && let ConnectionState::AtMenu(screen) =
+                std::mem::replace(&mut session_channel.state, ConnectionState::Connected)
+        {
+            session_channel.state = ConnectionState::Shadowing(shadow, screen);
+        }
+
+        match (escape, &session_channel.state) {
+            (Some(EscapeCommand::Suspend), ConnectionState::Suspended(..)) => {
+                self.suspended_input(session_channel, data).await?;
+            }
+            (Some(EscapeCommand::Suspend), _) => session_channel.suspend(channel, session)?,
+            _ => {}
+        }
+
+        if let Some((forward, screen)) = resume {
+            screen.lock().await.terminal.release()?;
+            self.resume(session_channel, forward, Some(screen))?;
+        }
+
+        if let Some((forward, screen)) = reattach {
+            screen.lock().await.terminal.release()?;
+            forward.attach(ChannelOutput::new(
+                session.handle(),
+                &session_channel.writer,
+            ))?;
+            self.resume(session_channel, forward, Some(screen))?;
+        }
+
+        if let Some((entry, screen)) = selected {
+            self.forward_from_menu(session_channel, &entry, screen, session)
+                .await?;
+        }
+
+        if let Some((entry, screen)) = pane {
+            self.open_pane(session_channel, &entry, screen, session)
+                .await?;
+        }
+
+        if let Some(screen) = show_panes {
+            session_channel.show_panes(screen).await?;
+        }
+
+        match pane_command {
+            Some((PaneCommand::Menu, screen)) => {
+                if let Some(panes) = &session_channel.panes {
+                    panes.lock().unwrap().show(false);
+                }
+                session_channel.show_menu(screen, None).await?;
+            }
+            Some((PaneCommand::Closed, screen)) => {
+                session_channel.panes = None;
+                let notice = "Closed the last pane".to_string();
+                session_channel.show_menu(screen, Some(notice)).await?;
+            }
+            None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Starts forwarding a channel that was opened with an explicit target, reporting
+    /// failures to the client the same way as `reject_request`.
+    async fn forward_request(
+        &self,
+        session_channel: &mut SessionChannel,
+        entry: &ServerEntry,
+        kind: ForwardKind,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        let pty = session_channel.pty.clone();
+        let forward = self
+            .start_forward(entry, kind, pty, session_channel, None, None, session)
+            .await?;
+        session.channel_success(channel)?;
+
+        let ready = forward.ready();
+        let handle = session.handle();
+        tokio::spawn(
+            async move {
+                if let ForwardStatus::Failed(e) = ready.await {
+                    warn!("Rejecting request: {:#}", e);
+                    let _ = handle
+                        .extended_data(channel, 1, format!("pukeko: {e:#}\r\n").into())
+                        .await;
+                    let _ = handle.exit_status_request(channel, 1).await;
+                    let _ = handle.close(channel).await;
+                }
+            }
+            .in_current_span(),
+        );
+
+        session_channel.state = ConnectionState::Forwarding(forward, None);
+        Ok(())
+    }
+
+    /// Signs a certificate for `key` with `certificates.ca_key` and writes it to the
+    /// channel. It is valid for each server the user may reach and the authorization hook
+    /// allows, as `login@server` for the login they reach it with, so servers take it for
+    /// their own name alone. It lets the user forward only what they may through pukeko, and
+    /// stops being valid when their schedule closes.
+    async fn issue_certificate(
+        &self,
+        channel: ChannelId,
+        key: &str,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        let Some(certificates) = self.config.borrow().certificates.clone() else {
+            return self
+                .reject_request(channel, "certificates are not issued here", session)
+                .await;
+        };
+        let Ok(key) = ssh_key::PublicKey::from_openssh(key.trim()) else {
+            return self
+                .reject_request(
+                    channel,
+                    &format!("usage: {CERT_COMMAND} <public key>"),
+                    session,
+                )
+                .await;
+        };
+        let user = self.user.as_deref().unwrap_or_default();
+        let now = chrono::Utc::now();
+        let groups = self.servers.groups(user);
+        let (closed, closes) = {
+            let config = self.config.borrow();
+            (
+                config
+                    .closed_schedule(user, &groups, now)
+                    .map(|schedule| (schedule.name.clone(), schedule.describe())),
+                config.schedule_closes(user, &groups, now, certificates.validity),
+            )
+        };
+        if let Some((schedule, hours)) = closed {
+            self.audit.record(AuditEvent::OutsideSchedule {
+                session: self.id,
+                user,
+                schedule: &schedule,
+                disconnected: false,
+            });
+            return self
+                .reject_request(channel, &format!("{user} may only log in {hours}"), session)
+                .await;
+        }
+        let validity = closes
+            .and_then(|closes| (closes - now).to_std().ok())
+            .map_or(certificates.validity, |left| {
+                left.min(certificates.validity)
+            });
+
+        let mut entries = Vec::new();
+        for entry in self.servers.servers(user) {
+            // The holder logs in to the server directly, out of reach of its command
+            // allow-list and of maintenance.
+            if entry.commands.is_some()
+                || self
+                    .maintenance
+                    .blocks(&entry.name, self.is_admin())
+                    .is_some()
+            {
+                continue;
+            }
+            if self.authorize(&entry, "certificate", None).await.is_ok() {
+                entries.push(entry);
+            }
+        }
+        let mut principals: Vec<String> = entries
+            .iter()
+            .map(|entry| {
+                let login = entry.user.as_deref().unwrap_or(user);
+                format!("{login}@{}", entry.name)
+            })
+            .collect();
+        principals.sort();
+        principals.dedup();
+        let permissions = {
+            let config = self.config.borrow();
+            let entry = config.user(user);
+            cert::Permissions {
+                x11_forwarding: entry.is_some_and(|entry| entry.x11_forwarding)
+                    && entries.iter().all(|entry| entry.x11_forwarding),
+                agent_forwarding: entry.is_none_or(|entry| entry.agent_forwarding),
+                port_forwarding: entry.is_some_and(|entry| !entry.remote_forwards.is_empty()),
+            }
+        };
+
+        let key_id = format!("{user}@pukeko");
+        let certificate = match cert::issue_user_certificate(
+            &certificates.ca_key,
+            &key,
+            &key_id,
+            &principals,
+            validity,
+            permissions,
+        ) {
+            Ok(certificate) => certificate,
+            Err(e) => {
+                return self
+                    .reject_request(channel, &format!("{e:#}"), session)
+                    .await;
+            }
+        };
+        let fingerprint = key.fingerprint(Default::default()).to_string();
+        info!(
+            "Issued {} a certificate for {} as {}",
+            user,
+            fingerprint,
+            principals.join(",")
+        );
+        self.audit.record(AuditEvent::CertificateIssued {
+            session: self.id,
+            user,
+            fingerprint: &fingerprint,
+            serial: certificate.serial(),
+            principals: &principals,
+            valid_before: certificate.valid_before(),
+        });
+        session.channel_success(channel)?;
+        session.data(channel, format!("{}\n", certificate.to_openssh()?).into())?;
+        session.exit_status_request(channel, 0)?;
+        session.eof(channel)?;
+        session.close(channel)?;
+        Ok(())
+    }
+
+    async fn reject_request(
+        &self,
+        channel: ChannelId,
+        message: &str,
+        session: &mut Session,
+    ) -> anyhow::Result<()> {
+        warn!("Rejecting request: {}", message);
+        session.extended_data(channel, 1, format!("pukeko: {message}\r\n").into())?;
+        session.exit_status_request(channel, 1)?;
+        session.close(channel)?;
+        Ok(())
+    }
+}
+
+impl Handler for ClientConnection {
+    type Error = anyhow::Error;
+
+    async fn authentication_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        let (messages, pre_auth) = {
+            let config = self.config.borrow();
+            (config.messages.clone(), config.banner.pre_auth.clone())
+        };
+        if let Some(template) = pre_auth {
+            return Ok(Some(banner::render_pre_auth(
+                &template,
+                self.peer_addr.map(|addr| addr.ip()),
+                self.location.as_ref(),
+                chrono::Utc::now(),
+            )));
+        }
+        Ok(self.banner(BannerMode::Auth, &messages))
+    }
+
+    async fn auth_publickey_offered(
+        &mut self,
+        user: &str,
+        public_key: &ssh_key::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.enter_realm(user) else {
+                return Ok(Auth::reject());
+            };
+            let user = user.as_str();
+            // The key may be the subject of a certificate, which is only checked once signed.
+            let certificates = !self.is_banned() && self.auth.accepts_certificates();
+            let decoy = !self.is_banned() && self.is_decoy(user);
+            // Keys that may be held for approval or have expired are signed with first, to
+            // prove the client has the private key before telling it anything.
+            if certificates
+                || decoy
+                || self.verify_login(user, public_key).await.is_some()
+                || self.holds_keys()
+                || self.key_expired(user, public_key).is_some()
+            {
+                trace!(
+                    "Accepting {} offered ssh public key {:?}",
+                    user,
+                    public_key.to_openssh()?
+                );
+                Ok(Auth::Accept)
+            } else {
+                trace!(
+                    "Rejecting {} offered ssh public key {:?}",
+                    user,
+                    public_key.to_openssh()?
+                );
+                // Clients offer each of their keys in turn, so only keys signed with and
+                // refused count as failures.
+                self.audit_auth(user, "publickey", Some(public_key), None);
+                Ok(Auth::reject())
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &ssh_key::PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.enter_realm(user) else {
+                return Ok(Auth::reject());
+            };
+            let user = user.as_str();
+            trace!(
+                "User {} requested auth with public key {:?}",
+                user,
+                public_key.to_openssh()?
+            );
+
+            if !self.is_banned() && self.is_decoy(user) {
+                return Ok(self.enter_honeypot(user, "publickey"));
+            }
+            let Some((identity, target)) = self.verify_login(user, public_key).await else {
+                self.record_auth(user, "publickey", Some(public_key), None);
+                if let Some(expired) = self.key_expired(user, public_key) {
+                    return Ok(self.refuse_expired_key(user, public_key, expired));
+                }
+                return Ok(self.hold_for_approval(user, public_key));
+            };
+            self.key_expires = {
+                let config = self.config.borrow();
+                config
+                    .user(&identity.user)
+                    .and_then(|entry| entry.key(public_key))
+                    .and_then(|key| config.key_expiry.warning(key, chrono::Utc::now()))
+            };
+
+            info!(
+                "Accepting user {} auth pubkey {:?}",
+                user,
+                public_key.to_openssh()?
+            );
+            self.record_auth(user, "publickey", Some(public_key), Some(&identity));
+            Ok(self.authenticated(user, target.as_deref(), identity).await)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn auth_openssh_certificate(
+        &mut self,
+        user: &str,
+        certificate: &ssh_key::Certificate,
+    ) -> Result<Auth, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.enter_realm(user) else {
+                return Ok(Auth::reject());
+            };
+            let user = user.as_str();
+            trace!(
+                "User {} requested auth with certificate {:?}",
+                user,
+                certificate.key_id()
+            );
+
+            let (name, target) = parse_login(user);
+            let public_key = ssh_key::PublicKey::new(certificate.public_key().clone(), "");
+            let Some(identity) = self.verify_certificate(name, certificate).await else {
+                self.record_auth(user, "certificate", Some(&public_key), None);
+                return Ok(Auth::reject());
+            };
+
+            match identity.attributes.get("proxy") {
+                Some(proxy) => info!(
+                    "Accepting user {} through proxy {} certificate {:?} serial {}",
+                    user,
+                    proxy,
+                    certificate.key_id(),
+                    certificate.serial()
+                ),
+                None => info!(
+                    "Accepting user {} certificate {:?} serial {}",
+                    user,
+                    certificate.key_id(),
+                    certificate.serial()
+                ),
+            }
+            self.record_auth(user, "certificate", Some(&public_key), Some(&identity));
+            Ok(self.authenticated(user, target, identity).await)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.enter_realm(user) else {
+                return Ok(Auth::reject());
+            };
+            let user = user.as_str();
+            trace!("User {} requested auth with a password", user);
+
+            if !self.is_banned() && self.is_decoy(user) {
+                return Ok(self.enter_honeypot(user, "password"));
+            }
+            let pending = self
+                .pending_password
+                .take_if(|pending| pending.login == user);
+            let (name, target) = match &pending {
+                Some(pending) => (pending.identity.user.clone(), pending.target.clone()),
+                None => {
+                    let (name, target) = parse_login(user);
+                    (name.to_string(), target.map(str::to_string))
+                }
+            };
+            let decision = if self.is_banned() {
+                Ok(AuthDecision::Reject)
+            } else {
+                self.auth.verify_password(&name, password).await
+            };
+            let verified = self.identity(&name, decision);
+
+            let identity = match (verified, pending) {
+                (Some(_), Some(pending)) => pending.identity,
+                (Some(identity), None) if !self.password_and_key(&identity.user) => identity,
+                // The password is right, but the user's key has to be accepted first. This
+                // is refused as a wrong password would be, but not counted as one.
+                (Some(identity), None) => {
+                    info!("Refusing {}'s password before their key", identity.user);
+                    self.audit_auth(user, "password", None, None);
+                    return Ok(Auth::reject());
+                }
+                (None, pending) => {
+                    warn!("Wrong password for {}", user);
+                    self.record_auth(user, "password", None, None);
+                    // A key was accepted, so let the client try the password again.
+                    if let Some(pending) = pending {
+                        self.pending_password = Some(pending);
+                        return Ok(Auth::Reject {
+                            proceed_with_methods: Some(password_method()),
+                            partial_success: true,
+                        });
+                    }
+                    let after_failures = self
+                        .config
+                        .borrow()
+                        .honeypot
+                        .as_ref()
+                        .and_then(|honeypot| honeypot.after_failures);
+                    if after_failures.is_some_and(|after| self.password_failures >= after)
+                        && !self.is_banned()
+                    {
+                        return Ok(self.enter_honeypot(user, "password"));
+                    }
+                    self.password_failures += 1;
+                    return Ok(Auth::reject());
+                }
+            };
+
+            info!("Accepting user {} password", user);
+            self.record_auth(user, "password", None, Some(&identity));
+            Ok(self.verified(user, target.as_deref(), identity).await)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn auth_keyboard_interactive<'a>(
+        &'a mut self,
+        user: &str,
+        _: &str,
+        response: Option<Response<'a>>,
+    ) -> Result<Auth, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.enter_realm(user) else {
+                return Ok(Auth::reject());
+            };
+            let user = user.as_str();
+            if let Some(refused) = self.refused.take_if(|refused| refused.login == user) {
+                if response.is_some() {
+                    return Ok(Auth::reject());
+                }
+                let (name, message) = (refused.name, refused.message.clone());
+                self.refused = Some(refused);
+                return Ok(Auth::Partial {
+                    name: Cow::Borrowed(name),
+                    instructions: Cow::Owned(message),
+                    prompts: Cow::Owned(Vec::new()),
+                });
+            }
+
+            let pending = self
+                .second_factor
+                .as_ref()
+                .filter(|pending| pending.login == user);
+            let Some(pending) = pending else {
+                return self.device_login(user, response.is_some()).await;
+            };
+            if self.is_banned() {
+                return Ok(Auth::reject());
+            }
+
+            let Some(mut response) = response else {
+                return Ok(Auth::Partial {
+                    name: Cow::Borrowed(""),
+                    instructions: Cow::Borrowed(""),
+                    prompts: Cow::Owned(vec![(Cow::Borrowed("Verification code: "), false)]),
+                });
+            };
+
+            let code = response
+                .next()
+                .map(|code| String::from_utf8_lossy(&code).into_owned())
+                .unwrap_or_default();
+            let accepted = self.used_codes.verify(
+                &pending.identity.user,
+                &pending.secret,
+                &code,
+                SystemTime::now(),
+            );
+            if !accepted {
+                warn!("Wrong verification code for {}", user);
+                self.record_auth(user, "keyboard-interactive", None, None);
+                self.code_failures += 1;
+                if self.code_failures >= MAX_CODE_FAILURES {
+                    warn!("Refusing {}, too many wrong verification codes", user);
+                    self.second_factor = None;
+                    return Ok(Auth::reject());
+                }
+                return Ok(Auth::Reject {
+                    proceed_with_methods: Some(keyboard_interactive()),
+                    partial_success: true,
+                });
+            }
+
+            let Some(pending) = self.second_factor.take() else {
+                return Ok(Auth::reject());
+            };
+            info!("Accepting user {} verification code", user);
+            self.record_auth(user, "keyboard-interactive", None, Some(&pending.identity));
+            self.verified = true;
+            self.logged_in(pending.identity.user, pending.target);
+            Ok(Auth::Accept)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn data(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            if let Some(honeypot) = &mut self.honeypot {
+                honeypot.data(channel, data);
+                return Ok(());
+            }
+            let Some(mut session_channel) = self.session_channels.remove(&channel) else {
+                return Ok(());
+            };
+            let result = self
+                .session_data(&mut session_channel, channel, data, session)
+                .await;
+            self.session_channels.insert(channel, session_channel);
+            result
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn shell_request(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            if let Some(honeypot) = &mut self.honeypot {
+                session.channel_success(channel)?;
+                honeypot.shell(channel);
+                return Ok(());
+            }
+            let Some(target) = self.target.clone() else {
+                // The menu is already running on the channel, and can only be drawn on a
+                // terminal.
+                session.channel_success(channel)?;
+                if let Some(session_channel) = self.session_channels.get(&channel)
+                    && session_channel.pty.is_none()
+                    && let ConnectionState::AtMenu(screen) = &session_channel.state
+                {
+                    debug!("No terminal requested, listing servers as text");
+                    let mut screen = screen.lock().await;
+                    screen.use_plain();
+                    screen.render()?;
+                }
+                return Ok(());
+            };
+
+            let Some(entry) = self.servers.server(&target) else {
+                return self
+                    .reject_request(channel, &format!("unknown target {target}"), session)
+                    .await;
+            };
+
+            let Some(mut session_channel) = self.session_channels.remove(&channel) else {
+                return Ok(());
+            };
+            let forwarded = self
+                .forward_request(
+                    &mut session_channel,
+                    &entry,
+                    ForwardKind::Shell,
+                    channel,
+                    session,
+                )
+                .await;
+            self.session_channels.insert(channel, session_channel);
+            if let Err(e) = forwarded {
+                self.reject_request(channel, &format!("{e:#}"), session)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn exec_request(
+        &mut self,
+        channel: ChannelId,
+        data: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            if let Some(honeypot) = &mut self.honeypot {
+                session.channel_success(channel)?;
+                honeypot.exec(channel, &String::from_utf8_lossy(data));
+                return Ok(());
+            }
+            let target = self.target.clone();
+            let Some(target) = target else {
+                let command = String::from_utf8_lossy(data);
+                let (name, key) = command.split_once(' ').unwrap_or((&command, ""));
+                if name == CERT_COMMAND {
+                    return self.issue_certificate(channel, key, session).await;
+                }
+                if let Some(session_channel) = self.session_channels.get_mut(&channel)
+                    && session_channel.pty.is_some()
+                {
+                    trace!("Deferring exec until a server is selected");
+                    session_channel.pending_exec = Some(data.to_vec());
+                    session.channel_success(channel)?;
+                } else {
+                    self.reject_request(
+                        channel,
+                        "no target selected, connect as user+target to run commands",
+                        session,
+                    )
+                    .await?;
+                }
+                return Ok(());
+            };
+
+            let Some(entry) = self.servers.server(&target) else {
+                return self
+                    .reject_request(channel, &format!("unknown target {target}"), session)
+                    .await;
+            };
+
+            let Some(mut session_channel) = self.session_channels.remove(&channel) else {
+                return Ok(());
+            };
+            let forwarded = self
+                .forward_request(
+                    &mut session_channel,
+                    &entry,
+                    ForwardKind::Exec(data.to_vec()),
+                    channel,
+                    session,
+                )
+                .await;
+            self.session_channels.insert(channel, session_channel);
+            if let Err(e) = forwarded {
+                self.reject_request(channel, &format!("{e:#}"), session)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            if self.honeypot.is_some() {
+                session.channel_failure(channel)?;
+                return Ok(());
+            }
+            let Some(entry) = self
+                .target
+                .as_deref()
+                .and_then(|target| self.servers.server(target))
+            else {
+                return self
+                    .reject_request(
+                        channel,
+                        &format!("no target selected for {name}, connect as user+target"),
+                        session,
+                    )
+                    .await;
+            };
+
+            let Some(mut session_channel) = self.session_channels.remove(&channel) else {
+                return Ok(());
+            };
+            let forwarded = self
+                .forward_request(
+                    &mut session_channel,
+                    &entry,
+                    ForwardKind::Subsystem(name.to_string()),
+                    channel,
+                    session,
+                )
+                .await;
+            self.session_channels.insert(channel, session_channel);
+            if let Err(e) = forwarded {
+                self.reject_request(channel, &format!("{e:#}"), session)
+                    .await?;
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn window_change_request(
+        &mut self,
+        channel: ChannelId,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        _: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let rect = Rect {
+                x: 0,
+                y: 0,
+                width: col_width as u16,
+                height: row_height as u16,
+            };
+
+            let Some(session_channel) = self.session_channels.get_mut(&channel) else {
+                return Ok(());
+            };
+            // Upstreams connected to later get a pty of the current size.
+            if let Some(pty) = &mut session_channel.pty {
+                pty.col_width = col_width;
+                pty.row_height = row_height;
+                pty.pix_width = pix_width;
+                pty.pix_height = pix_height;
+            }
+
+            match &session_channel.state {
+                ConnectionState::AtMenu(screen) => {
+                    trace!("trying to resize menu...");
+                    let mut screen = screen.lock().await;
+                    screen.terminal.resize(rect)?;
+                    screen.render()?;
+                }
+                // Drawn at the new size once back at the menu.
+                ConnectionState::Shadowing(_, screen) => {
+                    screen.lock().await.terminal.resize(rect)?;
+                }
+                // Redrawn once the panes are fitted to the new size, below.
+                ConnectionState::Panes(screen) => {
+                    screen.lock().await.terminal.resize(rect)?;
+                }
+                ConnectionState::Connecting(forward, _)
+                | ConnectionState::Forwarding(forward, _)
+                | ConnectionState::Suspended(forward, _) => {
+                    trace!("resizing upstream pty to {}x{}", col_width, row_height);
+                    forward.window_change(col_width, row_height, pix_width, pix_height)?;
+                }
+                ConnectionState::Connected => {}
+            };
+            // Panes left behind the menu or a forward are fitted too, ready to return to.
+            if let Some(panes) = &session_channel.panes {
+                panes.lock().unwrap().resize(rect);
+            }
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn pty_request(
+        &mut self,
+        channel: ChannelId,
+        term: &str,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+        modes: &[(Pty, u32)],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let rect = Rect {
+                x: 0,
+                y: 0,
+                width: col_width as u16,
+                height: row_height as u16,
+            };
+
+            if self.honeypot.is_some() {
+                session.channel_success(channel)?;
+                return Ok(());
+            }
+            let Some(session_channel) = self.session_channels.get_mut(&channel) else {
+                return Ok(());
+            };
+            session_channel.pty = Some(PtyRequest {
+                term: term.to_string(),
+                col_width,
+                row_height,
+                pix_width,
+                pix_height,
+                modes: modes.to_vec(),
+            });
+
+            match &session_channel.state {
+                ConnectionState::AtMenu(screen) => {
+                    trace!("creating pseudo terminal");
+                    let mut screen = screen.lock().await;
+                    screen.set_caps(TerminalCaps::detect(term));
+                    screen.terminal.resize(rect)?;
+                    screen.render()?;
+
+                    session.channel_success(channel)?;
+                }
+                // Passed on to the upstream when the shell or command is forwarded.
+                ConnectionState::Connected if self.target.is_some() => {
+                    session.channel_success(channel)?;
+                }
+                _ => {
+                    warn!("Attempted to create a pseudo terminal without a terminal handle");
+                }
+            };
+
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn env_request(
+        &mut self,
+        channel: ChannelId,
+        variable_name: &str,
+        variable_value: &str,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(session_channel) = self.session_channels.get_mut(&channel) else {
+                return Ok(());
+            };
+            if session_channel.env.len() >= MAX_ENV_VARIABLES {
+                debug!("Ignoring variable {}, too many were set", variable_name);
+                session.channel_failure(channel)?;
+                return Ok(());
+            }
+            trace!("client set variable {}", variable_name);
+            session_channel
+                .env
+                .retain(|(name, _)| name != variable_name);
+            session_channel
+                .env
+                .push((variable_name.to_string(), variable_value.to_string()));
+            session.channel_success(channel)?;
+
+            if ["LANG", "LC_ALL", "LC_MESSAGES"].contains(&variable_name) {
+                let messages = self.config.borrow().messages(&session_channel.env);
+                if messages != session_channel.messages {
+                    session_channel.messages = messages.clone();
+                    let drawn = session_channel.pty.is_some();
+                    if let ConnectionState::AtMenu(screen) = &session_channel.state {
+                        let screen = screen.clone();
+                        let splash = self.banner(BannerMode::Splash, &messages);
+                        let mut screen = screen.lock().await;
+                        if let Some(splash) = splash.filter(|_| screen.menu.splash().is_some()) {
+                            screen.menu.show_splash(splash);
+                        }
+                        screen.menu.set_messages(messages);
+                        if drawn {
+                            screen.render()?;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            if self.shutdown.is_shutting_down() {
+                return Ok(false);
+            }
+            let Some(permit) = self.usage.open_channel() else {
+                warn!("Refusing session channel, too many channels are open");
+                return Ok(false);
+            };
+
+            // Input is taken from the handler's callbacks instead of the read half.
+            let (_, writer) = channel.split();
+            let channel_id = writer.id();
+            if let Some(honeypot) = &mut self.honeypot {
+                // Output is written through the session's handle, a character at a time.
+                drop(writer);
+                honeypot.open(channel_id, permit, session.handle());
+                Ok(true)
+            } else if self.target.is_some() {
+                // The login named a server, so the session is forwarded by the shell, exec or
+                // subsystem request that follows instead of showing the menu.
+                let messages = self.config.borrow().messages.clone();
+                let session_channel =
+                    SessionChannel::new(ConnectionState::Connected, permit, messages, writer);
+                self.session_channels.insert(channel_id, session_channel);
+                Ok(true)
+            } else {
+                // Replaced once the client sets its locale, which comes after the channel opens.
+                let (theme, keymap, messages) = {
+                    let config = self.config.borrow();
+                    (
+                        Theme::new(config.theme.clone()),
+                        config.keys.clone(),
+                        config.messages.clone(),
+                    )
+                };
+                let screen = Arc::new(Mutex::new(
+                    PukekoMenu::from_session(
+                        channel_id,
+                        session,
+                        self.user.clone().unwrap_or_default(),
+                        self.servers.clone(),
+                        self.metrics.clone(),
+                        self.health.clone(),
+                        self.history.clone(),
+                        self.is_admin().then(|| self.sessions.clone()),
+                        theme,
+                        keymap,
+                        messages.clone(),
+                    )
+                    .await?,
+                ));
+                let themes = self.config.borrow().themes.clone();
+                screen.lock().await.menu.set_themes(themes);
+                screen.lock().await.menu.set_realm(self.realm.clone());
+                screen
+                    .lock()
+                    .await
+                    .menu
+                    .set_maintenance(self.maintenance.clone());
+                screen.lock().await.menu.set_plugins(self.plugins.clone());
+                let announcements = self.config.borrow().announcements.clone();
+                screen
+                    .lock()
+                    .await
+                    .menu
+                    .set_announcements(announcements, self.announcements.clone());
+                if let Some(splash) = self.banner(BannerMode::Splash, &messages) {
+                    screen.lock().await.menu.show_splash(splash);
+                }
+                if self.config.borrow().access_requests.is_some() {
+                    screen.lock().await.menu.enable_access_requests();
+                }
+                let user = self.user.as_deref().unwrap_or_default();
+                if self.config.borrow().key_management.is_some()
+                    && self.config.borrow().user(user).is_some()
+                {
+                    screen.lock().await.menu.enable_key_management();
+                }
+                let confirm_tags = self.config.borrow().confirm_tags.clone();
+                screen.lock().await.menu.set_confirm_tags(confirm_tags);
+                if let Some(expires) = self.key_expires {
+                    let date = expires.format("%Y-%m-%d %H:%M UTC");
+                    screen
+                        .lock()
+                        .await
+                        .menu
+                        .set_notice(messages::fill(&messages.key_expires, &[("date", &date)]));
+                }
+                let user = self.user.as_deref().unwrap_or_default();
+                if let Some(detached) = self.detached.latest(user) {
+                    screen.lock().await.menu.offer_resume(
+                        detached.id,
+                        messages::fill(
+                            &messages.resume,
+                            &[
+                                ("server", &detached.target),
+                                ("age", &format_duration(detached.age)),
+                            ],
+                        ),
+                    );
+                }
+                let session_channel = SessionChannel::new(
+                    ConnectionState::AtMenu(screen.clone()),
+                    permit,
+                    messages,
+                    writer,
+                );
+                let closed = &session_channel.closed;
+                self.notify_on_shutdown(
+                    screen.clone(),
+                    channel_id,
+                    closed.subscribe(),
+                    session.handle(),
+                );
+                self.close_when_idle(
+                    screen.clone(),
+                    channel_id,
+                    closed.subscribe(),
+                    session.handle(),
+                );
+                self.render_on_change(screen, closed.subscribe());
+                self.session_channels.insert(channel_id, session_channel);
+                Ok(true)
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn x11_request(
+        &mut self,
+        channel: ChannelId,
+        single_connection: bool,
+        x11_auth_protocol: &str,
+        x11_auth_cookie: &str,
+        x11_screen_number: u32,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let user = self.user.as_deref().unwrap_or_default();
+            let allowed = self
+                .config
+                .borrow()
+                .user(user)
+                .is_some_and(|entry| entry.x11_forwarding);
+            let Some(session_channel) = self.session_channels.get_mut(&channel) else {
+                return Ok(());
+            };
+            if !allowed {
+                warn!("{} may not forward X11", user);
+                session.channel_failure(channel)?;
+                return Ok(());
+            }
+            trace!("Client asked to forward X11");
+            session_channel.x11 = Some(X11Request {
+                single_connection,
+                protocol: x11_auth_protocol.to_string(),
+                cookie: x11_auth_cookie.to_string(),
+                screen: x11_screen_number,
+            });
+            session.channel_success(channel)?;
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn agent_request(&mut self, _: ChannelId, _: &mut Session) -> Result<bool, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let user = self.user.as_deref().unwrap_or_default();
+            let denied = self
+                .config
+                .borrow()
+                .user(user)
+                .is_some_and(|entry| !entry.agent_forwarding);
+            if denied {
+                warn!("Denied {} forwarding their agent", user);
+                return Ok(false);
+            }
+            trace!("Client forwarded its agent");
+            self.agent_forwarding = true;
+            Ok(true)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        _: &str,
+        _: u32,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.user.clone() else {
+                return Ok(false);
+            };
+            let settings = self.config.borrow().proxy_jump;
+            if !settings.enabled || self.shutdown.is_shutting_down() {
+                return Ok(false);
+            }
+            let Some(entry) = self.jump_target(&user, host_to_connect, port_to_connect) else {
+                warn!(
+                    "Denied {} jumping to {}:{}",
+                    user, host_to_connect, port_to_connect
+                );
+                return Ok(false);
+            };
+            // The client runs its own session on the server, so pukeko has no say in what
+            // is run there.
+            if entry.commands.is_some() {
+                warn!(
+                    "Denied {} jumping to {}, which allows only some commands",
+                    user, entry.name
+                );
+                return Ok(false);
+            }
+            if self
+                .maintenance
+                .blocks(&entry.name, self.is_admin())
+                .is_some()
+            {
+                warn!(
+                    "Denied {} jumping to {} under maintenance",
+                    user, entry.name
+                );
+                return Ok(false);
+            }
+            let Ok(quota) = self.count_forward(&entry, "jump") else {
+                return Ok(false);
+            };
+            if self.authorize(&entry, "jump", None).await.is_err() {
+                return Ok(false);
+            }
+            let Some(permit) = self.usage.open_channel() else {
+                warn!("Refusing jump, too many channels are open");
+                return Ok(false);
+            };
+
+            info!("{} is jumping to {}", user, entry.name);
+            self.audit.record(AuditEvent::ForwardStart {
+                session: self.id,
+                user: &user,
+                server: &entry.name,
+                kind: "jump",
+                command: None,
+            });
+            self.sessions.set_target(self.id, &entry.name);
+            let history = self.history.clone();
+            let bytes = self.bytes.clone();
+            let metrics = self.metrics.clone();
+            let config = self.config.borrow().clone();
+            tokio::spawn(
+                async move {
+                    let stream = match jump::connect(&entry, &user, &config).await {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Failed to jump to {}: {:#}", entry.name, e);
+                            let _ = channel.close().await;
+                            return;
+                        }
+                    };
+                    history.record_connection(&user, &entry.name);
+                    if let Err(e) =
+                        jump::relay(channel, stream, &entry.name, bytes, metrics, permit).await
+                    {
+                        debug!("Jump to {} failed: {:?}", entry.name, e);
+                    }
+                    drop(quota);
+                }
+                .in_current_span(),
+            );
+            Ok(true)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn tcpip_forward(
+        &mut self,
+        address: &str,
+        port: &mut u32,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            let Some(user) = self.user.clone() else {
+                return Ok(false);
+            };
+            let bind_address = ForwardRule::normalize_address(address);
+            let allowed = self
+                .config
+                .borrow()
+                .user(&user)
+                .is_some_and(|entry| entry.may_listen(bind_address, *port));
+            if !allowed {
+                warn!("{} may not listen on {}:{}", user, bind_address, port);
+                return Ok(false);
+            }
+
+            let forward = match RemoteForward::listen(
+                session.handle(),
+                address,
+                bind_address,
+                *port,
+                self.bytes.clone(),
+                self.usage.clone(),
+            )
+            .await
+            {
+                Ok(forward) => forward,
+                Err(e) => {
+                    warn!("{:#}", e);
+                    return Ok(false);
+                }
+            };
+            info!("{} is listening on {}:{}", user, bind_address, forward.port);
+            self.audit.record(AuditEvent::RemoteForward {
+                session: self.id,
+                user: &user,
+                address: bind_address,
+                port: forward.port,
+            });
+            *port = forward.port;
+            self.remote_forwards
+                .insert((address.to_string(), forward.port), forward);
+            Ok(true)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn cancel_tcpip_forward(
+        &mut self,
+        address: &str,
+        port: u32,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let span = self.span.clone();
+        async move {
+            // Forwards the server picked the port for are cancelled with the picked port.
+            if self
+                .remote_forwards
+                .remove(&(address.to_string(), port))
+                .is_none()
+            {
+                return Ok(false);
+            }
+            debug!("Stopped listening on {}:{}", address, port);
+            Ok(true)
+        }
+        .instrument(span)
+        .await
+    }
+
+    async fn channel_eof(
+        &mut self,
+        channel: ChannelId,
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        let span = self.span.clone();
+        async move {
+            if let Some(honeypot) = &mut self.honeypot {
+                honeypot.eof(channel);
+                return Ok(());
+            }
+            let Some(session_channel) = self.session_channels.get(&channel) else {
+                return Ok(());
+            };
+            if let Some(forward) = session_channel.forward() {
+                forward.eof()?;
+            } else if let ConnectionState::AtMenu(screen) = &session_channel.state
+                && screen.lock().await.terminal.is_plain()
+            {
+                // Nothing more can be typed at the list of servers.
+                session.exit_status_request(channel, 0)?;
+                session.close(channel)?;
+            }
+            Ok(())
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Shuts the client's window while its forward has more input than it may hold, so
+    /// the client waits for a slow upstream. The window russh widens to is shared by all of
+    /// a connection's channels, so any of them having no room holds up the rest too.
+    fn adjust_window(&mut self, channel: ChannelId, _current: u32) -> u32 {
+        let full = self
+            .session_channels
+            .get(&channel)
+            .and_then(SessionChannel::forward)
+            .is_some_and(|forward| !forward.has_room());
+        if full {
+            flow::PAUSED_WINDOW
+        } else {
+            flow::CLIENT_WINDOW
+        }
+    }
+
+    async fn channel_close(
         &mut self,
         channel: ChannelId,
         session: &mut Session,
     ) -> anyhow::Result<()> {
-        session.close(channel)?;
-        info!("{}] disconnected", self.id);
-        Ok(())
+        let span = self.span.clone();
+        async move {
+            if let Some(honeypot) = &mut self.honeypot {
+                honeypot.close(channel);
+                session.close(channel)?;
+                return Ok(());
+            }
+            let Some(session_channel) = self.session_channels.remove(&channel) else {
+                return Ok(());
+            };
+            if let Some(forward) = session_channel.forward() {
+                trace!("closing upstream {}", forward.target());
+                let _ = forward.close();
+            }
+            session.close(channel)?;
+            info!("disconnected");
+            Ok(())
+        }
+        .instrument(span)
+        .await
     }
 }
@@ -0,0 +1,257 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use anyhow::Context;
+use russh::keys::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{GroupEntry, Protocol, PukekoConfig, ServerEntry, UserEntry, UserKey};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+/// A user kept in a [`Store`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoredUser {
+    pub name: String,
+    pub admin: bool,
+    pub keys: Vec<StoredKey>,
+    pub groups: Vec<String>,
+    /// Servers granted directly, written as in the config: a name, `tag:<tag>` or `*`.
+    pub servers: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StoredKey {
+    /// The OpenSSH public key, without its comment.
+    pub key: String,
+    /// Unix time the key was added, unknown for keys added before it was recorded.
+    pub added: Option<i64>,
+    /// Unix time the key expires.
+    pub expires: Option<i64>,
+    pub comment: Option<String>,
+}
+
+impl StoredKey {
+    fn entry(&self) -> anyhow::Result<UserKey> {
+        let time = |time: i64| {
+            chrono::DateTime::from_timestamp(time, 0).context("Key time is out of range")
+        };
+        let mut key = UserKey::new(PublicKey::from_openssh(&self.key)?);
+        key.added = self.added.map(time).transpose()?;
+        key.expires = self.expires.map(time).transpose()?;
+        key.comment = self.comment.clone();
+        Ok(key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoredServer {
+    pub name: String,
+    pub host: String,
+    #[serde(default = "default_ssh_port")]
+    pub port: u16,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+fn default_ssh_port() -> u16 {
+    22
+}
+
+impl StoredServer {
+    pub fn entry(&self) -> ServerEntry {
+        ServerEntry {
+            name: self.name.clone(),
+            host: self.host.clone(),
+            port: self.port,
+            protocol: Protocol::Ssh,
+            pod: None,
+            container: None,
+            user: self.user.clone(),
+            key: None,
+            group: self.group.clone(),
+            tags: self.tags.clone(),
+            bandwidth: None,
+            commands: None,
+            pool: true,
+            login_command: None,
+            forward_env: None,
+            x11_forwarding: false,
+            confirm: false,
+            clipboard: None,
+            via: None,
+            srv: false,
+            backends: None,
+        }
+    }
+}
+
+/// Who servers are granted to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Grantee {
+    User(String),
+    Group(String),
+}
+
+/// A change to a [`Store`], as made through the control socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StoreChange {
+    AddUser {
+        name: String,
+        admin: bool,
+    },
+    /// Also removes the user's keys, grants and group memberships.
+    RemoveUser {
+        name: String,
+    },
+    AddKey {
+        user: String,
+        key: String,
+        /// Unix time the key expires.
+        expires: Option<i64>,
+        comment: Option<String>,
+    },
+    RemoveKey {
+        user: String,
+        key: String,
+    },
+    AddServer(StoredServer),
+    RemoveServer {
+        name: String,
+    },
+    Grant {
+        grantee: Grantee,
+        server: String,
+    },
+    Revoke {
+        grantee: Grantee,
+        server: String,
+    },
+    AddMember {
+        user: String,
+        group: String,
+    },
+    RemoveMember {
+        user: String,
+        group: String,
+    },
+}
+
+/// A session that has ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SessionRecord {
+    /// The id the session had while connected, which restarts from 0 with the server.
+    pub session: usize,
+    pub user: String,
+    pub peer: Option<String>,
+    /// The last server the session forwarded to.
+    pub target: Option<String>,
+    /// Unix time the session started.
+    pub started_at: i64,
+    pub ended_at: i64,
+    pub bytes: u64,
+}
+
+/// Users, servers and grants kept outside the config file, and a history of sessions.
+///
+/// What it holds is added to the config every time the config is loaded, after the users
+/// and servers from the file, which take precedence when names clash. Grants to a group
+/// are added to the group of that name in the file, if there is one.
+pub trait Store: Debug + Send + Sync {
+    fn users(&self) -> anyhow::Result<Vec<StoredUser>>;
+
+    fn servers(&self) -> anyhow::Result<Vec<StoredServer>>;
+
+    /// Groups that have been granted servers.
+    fn groups(&self) -> anyhow::Result<Vec<GroupEntry>>;
+
+    /// Makes a change, or fails without making it, such as when adding a user that exists
+    /// or removing one that does not.
+    fn apply(&self, change: &StoreChange) -> anyhow::Result<()>;
+
+    fn record_session(&self, session: &SessionRecord) -> anyhow::Result<()>;
+
+    /// The last `limit` sessions to end, most recent first.
+    fn sessions(&self, limit: usize) -> anyhow::Result<Vec<SessionRecord>>;
+}
+
+/// Opens the store configured by `database`, if any.
+pub fn open(config: &PukekoConfig) -> anyhow::Result<Option<Arc<dyn Store>>> {
+    let Some(path) = &config.database else {
+        return Ok(None);
+    };
+
+    #[cfg(feature = "sqlite")]
+    {
+        Ok(Some(Arc::new(SqliteStore::open(path)?)))
+    }
+    #[cfg(not(feature = "sqlite"))]
+    {
+        anyhow::bail!(
+            "database is set to {}, but pukeko was built without the sqlite feature",
+            path.display()
+        )
+    }
+}
+
+/// Parses an OpenSSH public key and drops its comment, as keys are kept in a store.
+pub fn normalize_key(key: &str) -> anyhow::Result<String> {
+    let mut key = PublicKey::from_openssh(key.trim()).context("Invalid public key")?;
+    key.set_comment("");
+    Ok(key.to_openssh()?)
+}
+
+/// Adds the users, servers and group grants from `store` to `config`.
+pub fn merge(config: &mut PukekoConfig, store: &dyn Store) -> anyhow::Result<()> {
+    for user in store.users()? {
+        if config.user(&user.name).is_some() {
+            continue;
+        }
+        let keys = user
+            .keys
+            .iter()
+            .map(StoredKey::entry)
+            .collect::<anyhow::Result<_>>()
+            .with_context(|| format!("Invalid key for stored user {}", user.name))?;
+        config.users.push(UserEntry {
+            name: user.name,
+            keys,
+            groups: user.groups,
+            servers: user.servers,
+            totp_secret: None,
+            password_hash: None,
+            password_and_key: false,
+            admin: user.admin,
+            remote_forwards: Vec::new(),
+            x11_forwarding: false,
+            agent_forwarding: true,
+            bandwidth: None,
+            schedule: None,
+        });
+    }
+
+    for server in store.servers()? {
+        if config.server(&server.name).is_none() {
+            config.servers.push(server.entry());
+        }
+    }
+
+    for group in store.groups()? {
+        match config
+            .groups
+            .iter_mut()
+            .find(|entry| entry.name == group.name)
+        {
+            Some(entry) => entry.servers.extend(group.servers),
+            None => config.groups.push(group),
+        }
+    }
+    Ok(())
+}
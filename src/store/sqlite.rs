@@ -0,0 +1,376 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, bail};
+use rusqlite::{Connection, OptionalExtension, params};
+use tracing::info;
+
+use super::{Grantee, SessionRecord, Store, StoreChange, StoredKey, StoredServer, StoredUser};
+use crate::config::{GroupEntry, QuotaConfig};
+
+/// Schema changes, applied in order to bring a database up to date. The number applied so
+/// far is kept in `PRAGMA user_version`, so entries must never be changed or removed.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE users (
+        name TEXT PRIMARY KEY,
+        admin INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE TABLE user_keys (
+        user TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        key TEXT NOT NULL,
+        PRIMARY KEY (user, key)
+    );
+    CREATE TABLE memberships (
+        user TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        group_name TEXT NOT NULL,
+        PRIMARY KEY (user, group_name)
+    );
+    CREATE TABLE user_grants (
+        user TEXT NOT NULL REFERENCES users (name) ON DELETE CASCADE,
+        server TEXT NOT NULL,
+        PRIMARY KEY (user, server)
+    );
+    CREATE TABLE group_grants (
+        group_name TEXT NOT NULL,
+        server TEXT NOT NULL,
+        PRIMARY KEY (group_name, server)
+    );
+    CREATE TABLE servers (
+        name TEXT PRIMARY KEY,
+        host TEXT NOT NULL,
+        port INTEGER NOT NULL,
+        user TEXT,
+        group_name TEXT
+    );
+    CREATE TABLE server_tags (
+        server TEXT NOT NULL REFERENCES servers (name) ON DELETE CASCADE,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (server, tag)
+    );
+    CREATE TABLE sessions (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        session INTEGER NOT NULL,
+        user TEXT NOT NULL,
+        peer TEXT,
+        target TEXT,
+        started_at INTEGER NOT NULL,
+        ended_at INTEGER NOT NULL,
+        bytes INTEGER NOT NULL
+    );
+    CREATE INDEX sessions_ended_at ON sessions (ended_at);
+",
+    "
+    ALTER TABLE user_keys ADD COLUMN added INTEGER;
+    ALTER TABLE user_keys ADD COLUMN expires INTEGER;
+    ALTER TABLE user_keys ADD COLUMN comment TEXT;
+",
+];
+
+/// A [`Store`] in an SQLite database, which is created and migrated as it is opened.
+#[derive(Debug)]
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(path)
+            .with_context(|| format!("Failed to open database {}", path.display()))?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrate(&mut connection)
+            .with_context(|| format!("Failed to migrate database {}", path.display()))?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "Database is at version {version}, newer than the {} this pukeko knows",
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let transaction = connection.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", i + 1)?;
+        transaction.commit()?;
+        info!("Migrated database to version {}", i + 1);
+    }
+    Ok(())
+}
+
+/// Fails with `message` unless a statement changed a row.
+fn changed(rows: usize, message: impl FnOnce() -> String) -> anyhow::Result<()> {
+    if rows == 0 {
+        bail!(message());
+    }
+    Ok(())
+}
+
+fn user_exists(connection: &Connection, name: &str) -> anyhow::Result<()> {
+    let exists = connection
+        .query_row("SELECT 1 FROM users WHERE name = ?1", [name], |_| Ok(()))
+        .optional()?;
+    if exists.is_none() {
+        bail!("No stored user named {name}");
+    }
+    Ok(())
+}
+
+/// Collects `(name, value)` rows into lists by name.
+fn grouped(connection: &Connection, sql: &str) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let mut grouped: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut statement = connection.prepare(sql)?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (name, value) = row?;
+        grouped.entry(name).or_default().push(value);
+    }
+    Ok(grouped)
+}
+
+impl Store for SqliteStore {
+    fn users(&self) -> anyhow::Result<Vec<StoredUser>> {
+        let connection = self.connection.lock().unwrap();
+        let mut keys: BTreeMap<String, Vec<StoredKey>> = BTreeMap::new();
+        let mut statement = connection
+            .prepare("SELECT user, key, added, expires, comment FROM user_keys ORDER BY rowid")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                StoredKey {
+                    key: row.get(1)?,
+                    added: row.get(2)?,
+                    expires: row.get(3)?,
+                    comment: row.get(4)?,
+                },
+            ))
+        })?;
+        for row in rows {
+            let (user, key) = row?;
+            keys.entry(user).or_default().push(key);
+        }
+        let mut groups = grouped(
+            &connection,
+            "SELECT user, group_name FROM memberships ORDER BY rowid",
+        )?;
+        let mut grants = grouped(
+            &connection,
+            "SELECT user, server FROM user_grants ORDER BY rowid",
+        )?;
+
+        let mut statement = connection.prepare("SELECT name, admin FROM users ORDER BY name")?;
+        let users = statement.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+        users
+            .map(|user| {
+                let (name, admin): (String, bool) = user?;
+                Ok(StoredUser {
+                    keys: keys.remove(&name).unwrap_or_default(),
+                    groups: groups.remove(&name).unwrap_or_default(),
+                    servers: grants.remove(&name).unwrap_or_default(),
+                    name,
+                    admin,
+                })
+            })
+            .collect()
+    }
+
+    fn servers(&self) -> anyhow::Result<Vec<StoredServer>> {
+        let connection = self.connection.lock().unwrap();
+        let mut tags = grouped(
+            &connection,
+            "SELECT server, tag FROM server_tags ORDER BY rowid",
+        )?;
+
+        let mut statement = connection
+            .prepare("SELECT name, host, port, user, group_name FROM servers ORDER BY name")?;
+        let servers = statement.query_map([], |row| {
+            Ok(StoredServer {
+                name: row.get(0)?,
+                host: row.get(1)?,
+                port: row.get(2)?,
+                user: row.get(3)?,
+                group: row.get(4)?,
+                tags: Vec::new(),
+            })
+        })?;
+        servers
+            .map(|server| {
+                let mut server = server?;
+                server.tags = tags.remove(&server.name).unwrap_or_default();
+                Ok(server)
+            })
+            .collect()
+    }
+
+    fn groups(&self) -> anyhow::Result<Vec<GroupEntry>> {
+        let connection = self.connection.lock().unwrap();
+        let grants = grouped(
+            &connection,
+            "SELECT group_name, server FROM group_grants ORDER BY rowid",
+        )?;
+        Ok(grants
+            .into_iter()
+            .map(|(name, servers)| GroupEntry {
+                name,
+                servers,
+                schedule: None,
+                quotas: QuotaConfig::default(),
+            })
+            .collect())
+    }
+
+    fn apply(&self, change: &StoreChange) -> anyhow::Result<()> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        match change {
+            StoreChange::AddUser { name, admin } => {
+                let rows = transaction.execute(
+                    "INSERT OR IGNORE INTO users (name, admin) VALUES (?1, ?2)",
+                    params![name, admin],
+                )?;
+                changed(rows, || format!("User {name} already exists"))?;
+            }
+            StoreChange::RemoveUser { name } => {
+                let rows = transaction.execute("DELETE FROM users WHERE name = ?1", [name])?;
+                changed(rows, || format!("No stored user named {name}"))?;
+            }
+            StoreChange::AddKey {
+                user,
+                key,
+                expires,
+                comment,
+            } => {
+                user_exists(&transaction, user)?;
+                let rows = transaction.execute(
+                    "INSERT OR IGNORE INTO user_keys (user, key, added, expires, comment)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![user, key, chrono::Utc::now().timestamp(), expires, comment],
+                )?;
+                changed(rows, || format!("User {user} already has the key"))?;
+            }
+            StoreChange::RemoveKey { user, key } => {
+                let rows = transaction.execute(
+                    "DELETE FROM user_keys WHERE user = ?1 AND key = ?2",
+                    [user, key],
+                )?;
+                changed(rows, || format!("User {user} does not have the key"))?;
+            }
+            StoreChange::AddServer(server) => {
+                let rows = transaction.execute(
+                    "INSERT OR IGNORE INTO servers (name, host, port, user, group_name)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        server.name,
+                        server.host,
+                        server.port,
+                        server.user,
+                        server.group
+                    ],
+                )?;
+                changed(rows, || format!("Server {} already exists", server.name))?;
+                for tag in &server.tags {
+                    transaction.execute(
+                        "INSERT OR IGNORE INTO server_tags (server, tag) VALUES (?1, ?2)",
+                        [&server.name, tag],
+                    )?;
+                }
+            }
+            StoreChange::RemoveServer { name } => {
+                let rows = transaction.execute("DELETE FROM servers WHERE name = ?1", [name])?;
+                changed(rows, || format!("No stored server named {name}"))?;
+            }
+            StoreChange::Grant { grantee, server } => {
+                let rows = match grantee {
+                    Grantee::User(user) => {
+                        user_exists(&transaction, user)?;
+                        transaction.execute(
+                            "INSERT OR IGNORE INTO user_grants (user, server) VALUES (?1, ?2)",
+                            [user, server],
+                        )?
+                    }
+                    Grantee::Group(group) => transaction.execute(
+                        "INSERT OR IGNORE INTO group_grants (group_name, server) VALUES (?1, ?2)",
+                        [group, server],
+                    )?,
+                };
+                changed(rows, || format!("{server} is already granted"))?;
+            }
+            StoreChange::Revoke { grantee, server } => {
+                let rows = match grantee {
+                    Grantee::User(user) => transaction.execute(
+                        "DELETE FROM user_grants WHERE user = ?1 AND server = ?2",
+                        [user, server],
+                    )?,
+                    Grantee::Group(group) => transaction.execute(
+                        "DELETE FROM group_grants WHERE group_name = ?1 AND server = ?2",
+                        [group, server],
+                    )?,
+                };
+                changed(rows, || format!("{server} is not granted"))?;
+            }
+            StoreChange::AddMember { user, group } => {
+                user_exists(&transaction, user)?;
+                let rows = transaction.execute(
+                    "INSERT OR IGNORE INTO memberships (user, group_name) VALUES (?1, ?2)",
+                    [user, group],
+                )?;
+                changed(rows, || format!("User {user} is already in group {group}"))?;
+            }
+            StoreChange::RemoveMember { user, group } => {
+                let rows = transaction.execute(
+                    "DELETE FROM memberships WHERE user = ?1 AND group_name = ?2",
+                    [user, group],
+                )?;
+                changed(rows, || format!("User {user} is not in group {group}"))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn record_session(&self, session: &SessionRecord) -> anyhow::Result<()> {
+        self.connection.lock().unwrap().execute(
+            "INSERT INTO sessions (session, user, peer, target, started_at, ended_at, bytes)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                session.session,
+                session.user,
+                session.peer,
+                session.target,
+                session.started_at,
+                session.ended_at,
+                session.bytes
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn sessions(&self, limit: usize) -> anyhow::Result<Vec<SessionRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT session, user, peer, target, started_at, ended_at, bytes FROM sessions
+             ORDER BY ended_at DESC, id DESC LIMIT ?1",
+        )?;
+        let sessions = statement.query_map([limit], |row| {
+            Ok(SessionRecord {
+                session: row.get(0)?,
+                user: row.get(1)?,
+                peer: row.get(2)?,
+                target: row.get(3)?,
+                started_at: row.get(4)?,
+                ended_at: row.get(5)?,
+                bytes: row.get(6)?,
+            })
+        })?;
+        Ok(sessions.collect::<Result<_, _>>()?)
+    }
+}
@@ -0,0 +1,91 @@
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::fd::FromRawFd;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+#[cfg(unix)]
+use anyhow::{Context, bail};
+#[cfg(unix)]
+use tracing::debug;
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3).
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Takes the listening sockets passed by a systemd socket unit, none unless the service
+/// was socket activated.
+#[cfg(unix)]
+pub fn take_listeners() -> anyhow::Result<Vec<TcpListener>> {
+    if !for_this_process("LISTEN_PID") {
+        return Ok(Vec::new());
+    }
+    let count: i32 = match std::env::var("LISTEN_FDS") {
+        Ok(count) => count.parse().context("LISTEN_FDS is not a number")?,
+        Err(_) => return Ok(Vec::new()),
+    };
+    debug!("systemd passed {} sockets", count);
+
+    (LISTEN_FDS_START..LISTEN_FDS_START + count.max(0))
+        .map(|fd| {
+            // SAFETY: systemd passes ownership of the descriptors from LISTEN_FDS_START to
+            // the process named by LISTEN_PID, which was checked above, and nothing else
+            // uses them.
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            if listener.local_addr().is_err() {
+                bail!("Socket {fd} passed by systemd is not a TCP listener");
+            }
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
+
+/// Sends a state change such as `READY=1` to the service manager, see sd_notify(3).
+/// Does nothing when not run by systemd.
+#[cfg(unix)]
+pub fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    let sent = UnixDatagram::unbound().and_then(|socket| {
+        // A leading '@' names a socket in the abstract namespace.
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &address);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(e) = sent {
+        debug!("Failed to notify systemd of {}: {:?}", state, e);
+    }
+}
+
+/// There is no systemd off Unix, so nothing is passed or notified.
+#[cfg(not(unix))]
+pub fn take_listeners() -> anyhow::Result<Vec<TcpListener>> {
+    Ok(Vec::new())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_state: &str) {}
+
+/// How often to send `WATCHDOG=1`, half the watchdog timeout, when the watchdog is enabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    if std::env::var_os("WATCHDOG_PID").is_some() && !for_this_process("WATCHDOG_PID") {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec) / 2)
+}
+
+fn for_this_process(variable: &str) -> bool {
+    std::env::var(variable)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id())
+}
@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+
+use anyhow::{Context, bail};
+use tokio::io::{AsyncReadExt, ReadHalf};
+use tracing::{debug, trace};
+
+use crate::config::{Protocol, PukekoConfig, ServerEntry};
+use crate::dial::{self, Stream};
+use crate::flow::{UpstreamWriter, Written};
+use crate::forward::{ForwardKind, PtyRequest, UpstreamOutput, UpstreamSession};
+use crate::provider::BoxFuture;
+use crate::srv;
+use crate::upstream::CONNECT_TIMEOUT;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const ECHO: u8 = 1;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const TERMINAL_TYPE: u8 = 24;
+const WINDOW_SIZE: u8 = 31;
+
+/// Subnegotiation commands of the terminal type option, see RFC 1091.
+const TERMINAL_TYPE_IS: u8 = 0;
+const TERMINAL_TYPE_SEND: u8 = 1;
+
+/// A plain TCP connection to an upstream, with telnet negotiation for telnet servers. Only
+/// interactive sessions can be bridged, as there is nowhere to send a command.
+pub struct TcpSession {
+    reader: ReadHalf<Box<dyn Stream>>,
+    /// Writing never waits, so telnet replies are not lost when a read is cancelled.
+    writer: UpstreamWriter,
+    telnet: Option<Telnet>,
+    eof: bool,
+    /// Whether the end of the connection has been reported as the session exiting.
+    exited: bool,
+}
+
+impl TcpSession {
+    pub async fn open(
+        entry: &ServerEntry,
+        user: &str,
+        config: &PukekoConfig,
+        kind: &ForwardKind,
+        pty: Option<&PtyRequest>,
+    ) -> anyhow::Result<Self> {
+        if !matches!(kind, ForwardKind::Shell) {
+            bail!(
+                "{} only takes interactive sessions, not {}",
+                entry.name,
+                kind.name()
+            );
+        }
+
+        debug!(
+            "Connecting to upstream {} at {}:{} over {:?}",
+            entry.name, entry.host, entry.port, entry.protocol
+        );
+        let resolved = srv::resolve(entry)
+            .await
+            .with_context(|| format!("Failed to connect to {}", entry.name))?;
+        let stream = tokio::time::timeout(CONNECT_TIMEOUT, dial::dial(&resolved, user, config))
+            .await
+            .with_context(|| format!("Timed out connecting to {}", entry.name))?
+            .with_context(|| format!("Failed to connect to {}", entry.name))?;
+        let (reader, writer) = tokio::io::split(stream);
+
+        let telnet = (entry.protocol == Protocol::Telnet).then(|| Telnet::new(pty));
+        Ok(Self {
+            reader,
+            writer: UpstreamWriter::spawn(writer),
+            telnet,
+            eof: false,
+            exited: false,
+        })
+    }
+}
+
+impl UpstreamSession for TcpSession {
+    fn recv(&mut self) -> BoxFuture<'_, Option<UpstreamOutput>> {
+        Box::pin(async move {
+            let mut buffer = [0; 8192];
+            loop {
+                if self.eof {
+                    // The server closing the connection is as close as it comes to saying
+                    // the session went well.
+                    let exited = std::mem::replace(&mut self.exited, true);
+                    return (!exited).then_some(UpstreamOutput::ExitStatus(0));
+                }
+                let read = match self.reader.read(&mut buffer).await {
+                    Ok(read) => read,
+                    Err(e) => {
+                        trace!("Failed to read upstream: {}", e);
+                        return None;
+                    }
+                };
+                if read == 0 {
+                    self.eof = true;
+                    return Some(UpstreamOutput::Eof);
+                }
+                let Some(telnet) = &mut self.telnet else {
+                    return Some(UpstreamOutput::Data(buffer[..read].to_vec()));
+                };
+                let (data, replies) = telnet.receive(&buffer[..read]);
+                let _ = self.writer.write(replies, None);
+                if !data.is_empty() {
+                    return Some(UpstreamOutput::Data(data));
+                }
+            }
+        })
+    }
+
+    fn send(&mut self, data: Vec<u8>, written: Written) -> anyhow::Result<()> {
+        let data = match &self.telnet {
+            Some(_) => Telnet::encode(&data),
+            None => data,
+        };
+        self.writer.write(data, Some(written))
+    }
+
+    fn eof(&mut self) -> BoxFuture<'_, ()> {
+        self.writer.shutdown();
+        Box::pin(std::future::ready(()))
+    }
+
+    fn window_change(
+        &mut self,
+        col_width: u32,
+        row_height: u32,
+        _pix_width: u32,
+        _pix_height: u32,
+    ) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            if let Some(telnet) = &mut self.telnet {
+                let update = telnet.resize(col_width, row_height);
+                let _ = self.writer.write(update, None);
+            }
+        })
+    }
+
+    /// The writer task finishes what was sent before it, then drops the connection.
+    fn close(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async {})
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TelnetState {
+    Data,
+    /// After a CR, which servers may follow with a NUL that is not part of the data.
+    Return,
+    Command,
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+/// The telnet protocol of RFC 854, answering the server's option requests. The terminal
+/// type and window size of the client's pty are reported, and the server may echo and
+/// suppress go-ahead; everything else is refused.
+#[derive(Debug)]
+struct Telnet {
+    state: TelnetState,
+    term: Option<String>,
+    size: Option<(u16, u16)>,
+    /// Options enabled on this side.
+    local: HashSet<u8>,
+    /// Options enabled on the server's side.
+    remote: HashSet<u8>,
+    subnegotiation: Vec<u8>,
+}
+
+impl Telnet {
+    fn new(pty: Option<&PtyRequest>) -> Self {
+        Self {
+            state: TelnetState::Data,
+            term: pty.map(|pty| pty.term.clone()),
+            size: pty.map(|pty| (clamp(pty.col_width), clamp(pty.row_height))),
+            local: HashSet::new(),
+            remote: HashSet::new(),
+            subnegotiation: Vec::new(),
+        }
+    }
+
+    /// Splits data read from the server into terminal output and the replies to send back.
+    fn receive(&mut self, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(input.len());
+        let mut replies = Vec::new();
+        for &byte in input {
+            match self.state {
+                TelnetState::Return if byte == 0 => self.state = TelnetState::Data,
+                TelnetState::Data | TelnetState::Return => match byte {
+                    IAC => self.state = TelnetState::Command,
+                    b'\r' => {
+                        data.push(byte);
+                        self.state = TelnetState::Return;
+                    }
+                    _ => {
+                        data.push(byte);
+                        self.state = TelnetState::Data;
+                    }
+                },
+                TelnetState::Command => {
+                    self.state = match byte {
+                        IAC => {
+                            data.push(IAC);
+                            TelnetState::Data
+                        }
+                        DO | DONT | WILL | WONT => TelnetState::Negotiate(byte),
+                        SB => {
+                            self.subnegotiation.clear();
+                            TelnetState::Subnegotiation
+                        }
+                        // Go-ahead, no-op and the rest mean nothing to a terminal.
+                        _ => TelnetState::Data,
+                    }
+                }
+                TelnetState::Negotiate(command) => {
+                    self.negotiate(command, byte, &mut replies);
+                    self.state = TelnetState::Data;
+                }
+                TelnetState::Subnegotiation => match byte {
+                    IAC => self.state = TelnetState::SubnegotiationCommand,
+                    _ => self.subnegotiation.push(byte),
+                },
+                TelnetState::SubnegotiationCommand => match byte {
+                    IAC => {
+                        self.subnegotiation.push(IAC);
+                        self.state = TelnetState::Subnegotiation;
+                    }
+                    SE => {
+                        self.subnegotiate(&mut replies);
+                        self.state = TelnetState::Data;
+                    }
+                    _ => self.state = TelnetState::Data,
+                },
+            }
+        }
+        (data, replies)
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8, replies: &mut Vec<u8>) {
+        trace!("Telnet server sent {} {}", command, option);
+        match command {
+            DO if self.local.contains(&option) => {}
+            DO => {
+                let supported = match option {
+                    SUPPRESS_GO_AHEAD => true,
+                    TERMINAL_TYPE => self.term.is_some(),
+                    WINDOW_SIZE => self.size.is_some(),
+                    _ => false,
+                };
+                if supported {
+                    self.local.insert(option);
+                    replies.extend([IAC, WILL, option]);
+                    if option == WINDOW_SIZE {
+                        replies.extend(self.window_size());
+                    }
+                } else {
+                    replies.extend([IAC, WONT, option]);
+                }
+            }
+            DONT => {
+                if self.local.remove(&option) {
+                    replies.extend([IAC, WONT, option]);
+                }
+            }
+            WILL if self.remote.contains(&option) => {}
+            WILL => {
+                if matches!(option, ECHO | SUPPRESS_GO_AHEAD) {
+                    self.remote.insert(option);
+                    replies.extend([IAC, DO, option]);
+                } else {
+                    replies.extend([IAC, DONT, option]);
+                }
+            }
+            _ => {
+                if self.remote.remove(&option) {
+                    replies.extend([IAC, DONT, option]);
+                }
+            }
+        }
+    }
+
+    fn subnegotiate(&mut self, replies: &mut Vec<u8>) {
+        if self.subnegotiation != [TERMINAL_TYPE, TERMINAL_TYPE_SEND]
+            || !self.local.contains(&TERMINAL_TYPE)
+        {
+            return;
+        }
+        let Some(term) = &self.term else {
+            return;
+        };
+        replies.extend([IAC, SB, TERMINAL_TYPE, TERMINAL_TYPE_IS]);
+        replies.extend(term.bytes().filter(|&byte| byte != IAC));
+        replies.extend([IAC, SE]);
+    }
+
+    /// Records the client's new size, returning the update to send if the server asked
+    /// for it.
+    fn resize(&mut self, cols: u32, rows: u32) -> Vec<u8> {
+        self.size = Some((clamp(cols), clamp(rows)));
+        if self.local.contains(&WINDOW_SIZE) {
+            self.window_size()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// The window size subnegotiation of RFC 1073.
+    fn window_size(&self) -> Vec<u8> {
+        let (cols, rows) = self.size.unwrap_or_default();
+        let mut update = vec![IAC, SB, WINDOW_SIZE];
+        for byte in [cols.to_be_bytes(), rows.to_be_bytes()].concat() {
+            update.push(byte);
+            if byte == IAC {
+                update.push(IAC);
+            }
+        }
+        update.extend([IAC, SE]);
+        update
+    }
+
+    /// Escapes client input for the server. A CR on its own, as terminals send for Enter,
+    /// is followed by a NUL as RFC 854 requires.
+    fn encode(input: &[u8]) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(input.len());
+        for (i, &byte) in input.iter().enumerate() {
+            encoded.push(byte);
+            match byte {
+                IAC => encoded.push(IAC),
+                b'\r' if input.get(i + 1) != Some(&b'\n') => encoded.push(0),
+                _ => {}
+            }
+        }
+        encoded
+    }
+}
+
+fn clamp(size: u32) -> u16 {
+    size.min(u16::MAX as u32) as u16
+}
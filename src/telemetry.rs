@@ -0,0 +1,150 @@
+use std::sync::Arc;
+
+use tracing_subscriber::{Layer, Registry};
+
+use crate::config::TelemetryConfig;
+use crate::metrics::Metrics;
+
+/// A layer added to the subscriber once the config has been read.
+pub type TelemetryLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Exports spans and metrics to an OpenTelemetry collector over OTLP/HTTP. Buffered data is
+/// only sent on [`shutdown`](Self::shutdown).
+#[derive(Debug)]
+pub struct Telemetry {
+    #[cfg(feature = "otel")]
+    tracer: opentelemetry_sdk::trace::SdkTracerProvider,
+    #[cfg(feature = "otel")]
+    meter: opentelemetry_sdk::metrics::SdkMeterProvider,
+}
+
+#[cfg(feature = "otel")]
+impl Telemetry {
+    /// Starts the exporters, returning the layer that sends spans to them. Each connection's
+    /// `session` span becomes a trace, with a `forward` span for each upstream it connects
+    /// to.
+    pub fn start(config: &TelemetryConfig) -> anyhow::Result<(Self, TelemetryLayer)> {
+        use anyhow::Context;
+        use opentelemetry::trace::TracerProvider;
+        use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+        use opentelemetry_sdk::Resource;
+        use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+        use opentelemetry_sdk::trace::SdkTracerProvider;
+
+        let resource = Resource::builder()
+            .with_service_name(config.service_name.clone())
+            .build();
+
+        let mut spans = SpanExporter::builder().with_http();
+        let mut metrics = MetricExporter::builder().with_http();
+        if let Some(endpoint) = &config.endpoint {
+            spans = spans.with_endpoint(format!("{endpoint}/v1/traces"));
+            metrics = metrics.with_endpoint(format!("{endpoint}/v1/metrics"));
+        }
+        let spans = spans
+            .build()
+            .context("Failed to create the OTLP span exporter")?;
+        let metrics = metrics
+            .build()
+            .context("Failed to create the OTLP metric exporter")?;
+
+        let tracer = SdkTracerProvider::builder()
+            .with_batch_exporter(spans)
+            .with_resource(resource.clone())
+            .build();
+        let reader = PeriodicReader::builder(metrics)
+            .with_interval(config.metrics_interval)
+            .build();
+        let meter = SdkMeterProvider::builder()
+            .with_reader(reader)
+            .with_resource(resource)
+            .build();
+        opentelemetry::global::set_meter_provider(meter.clone());
+
+        let layer = tracing_opentelemetry::layer().with_tracer(tracer.tracer("pukeko"));
+        Ok((Self { tracer, meter }, Box::new(layer)))
+    }
+
+    /// Sends whatever has not been exported yet and stops the exporters.
+    pub fn shutdown(self) {
+        if let Err(e) = self.tracer.shutdown() {
+            eprintln!("Failed to export the remaining spans: {e}");
+        }
+        if let Err(e) = self.meter.shutdown() {
+            eprintln!("Failed to export the remaining metrics: {e}");
+        }
+    }
+}
+
+/// The config refuses `telemetry` without the feature, so this is never reached.
+#[cfg(not(feature = "otel"))]
+impl Telemetry {
+    pub fn start(config: &TelemetryConfig) -> anyhow::Result<(Self, TelemetryLayer)> {
+        let _ = config;
+        anyhow::bail!("Cannot export telemetry: pukeko was built without the otel feature")
+    }
+
+    pub fn shutdown(self) {}
+}
+
+/// Reports the counters also served on `metrics_address` to the exporter started by
+/// [`Telemetry::start`]. Does nothing if it was not started.
+#[cfg(feature = "otel")]
+pub(crate) fn observe(metrics: Arc<Metrics>) {
+    use opentelemetry::KeyValue;
+
+    let meter = opentelemetry::global::meter("pukeko");
+    let observed = metrics.clone();
+    meter
+        .u64_observable_gauge("pukeko.sessions.active")
+        .with_description("Number of currently connected SSH sessions.")
+        .with_callback(move |observer| observer.observe(observed.snapshot().active_sessions, &[]))
+        .build();
+    let observed = metrics.clone();
+    meter
+        .u64_observable_counter("pukeko.connections")
+        .with_description("Total number of accepted connections.")
+        .with_callback(move |observer| observer.observe(observed.snapshot().total_connections, &[]))
+        .build();
+    let observed = metrics.clone();
+    meter
+        .u64_observable_counter("pukeko.auth.failures")
+        .with_description("Total number of rejected authentication attempts.")
+        .with_callback(move |observer| observer.observe(observed.snapshot().auth_failures, &[]))
+        .build();
+    let observed = metrics.clone();
+    meter
+        .u64_observable_counter("pukeko.forwarded")
+        .with_description("Total bytes forwarded per upstream.")
+        .with_unit("By")
+        .with_callback(move |observer| {
+            let snapshot = observed.snapshot();
+            for (direction, bytes_forwarded) in [
+                ("input", snapshot.bytes_to_upstream),
+                ("output", snapshot.bytes_from_upstream),
+            ] {
+                for (server, bytes) in bytes_forwarded {
+                    let attributes = [
+                        KeyValue::new("server.name", server),
+                        KeyValue::new("direction", direction),
+                    ];
+                    observer.observe(bytes, &attributes);
+                }
+            }
+        })
+        .build();
+    meter
+        .u64_observable_counter("pukeko.menu.selections")
+        .with_description("Total number of times each menu entry was selected.")
+        .with_callback(move |observer| {
+            for (item, count) in metrics.snapshot().menu_selections {
+                observer.observe(count, &[KeyValue::new("item", item)]);
+            }
+        })
+        .build();
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn observe(metrics: Arc<Metrics>) {
+    let _ = metrics;
+}
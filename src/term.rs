@@ -0,0 +1,188 @@
+use ratatui::style::Color;
+
+/// How many colors a terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorDepth {
+    None,
+    Ansi16,
+    Indexed256,
+    TrueColor,
+}
+
+/// What the client's terminal supports, going by the terminal type in its pty request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TerminalCaps {
+    pub colors: ColorDepth,
+    /// Whether it can draw box-drawing characters and symbols, or only ASCII.
+    pub unicode: bool,
+    /// Whether it understands xterm mouse reporting.
+    pub mouse: bool,
+}
+
+/// Assumed until the client requests a pty.
+impl Default for TerminalCaps {
+    fn default() -> Self {
+        Self {
+            colors: ColorDepth::TrueColor,
+            unicode: true,
+            mouse: true,
+        }
+    }
+}
+
+impl TerminalCaps {
+    /// Judges a terminal by its terminfo name.
+    pub fn detect(term: &str) -> Self {
+        let term = term.to_ascii_lowercase();
+        let basic = matches!(
+            term.as_str(),
+            "" | "dumb" | "vt52" | "vt100" | "vt102" | "vt220" | "vt320"
+        );
+        let colors = if basic || term.ends_with("-m") || term.ends_with("-mono") {
+            ColorDepth::None
+        } else if term.ends_with("-direct")
+            || term.contains("truecolor")
+            || matches!(
+                term.as_str(),
+                "xterm-kitty" | "xterm-ghostty" | "alacritty" | "wezterm" | "foot" | "contour"
+            )
+        {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Indexed256
+        } else {
+            ColorDepth::Ansi16
+        };
+        Self {
+            colors,
+            unicode: !basic,
+            mouse: !basic,
+        }
+    }
+}
+
+/// The 16 ANSI colors, with their default xterm values.
+const ANSI: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::Red, (205, 0, 0)),
+    (Color::Green, (0, 205, 0)),
+    (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)),
+    (Color::Magenta, (205, 0, 205)),
+    (Color::Cyan, (0, 205, 205)),
+    (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)),
+    (Color::LightRed, (255, 0, 0)),
+    (Color::LightGreen, (0, 255, 0)),
+    (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)),
+    (Color::LightMagenta, (255, 0, 255)),
+    (Color::LightCyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+/// The closest color to `color` a terminal with `depth` colors can show. Colors are dropped
+/// entirely for terminals without them, which leaves the default.
+pub fn downgrade(color: Color, depth: ColorDepth) -> Color {
+    match (color, depth) {
+        (_, ColorDepth::None) => Color::Reset,
+        (Color::Rgb(r, g, b), ColorDepth::Indexed256) => Color::Indexed(nearest_indexed(r, g, b)),
+        // 256-color escapes are used even for the first 16 indexes, so those are named too.
+        (Color::Indexed(i), ColorDepth::Ansi16) if i < 16 => ANSI[i as usize].0,
+        (Color::Indexed(i), ColorDepth::Ansi16) => nearest_ansi(indexed_rgb(i)),
+        (Color::Rgb(r, g, b), ColorDepth::Ansi16) => nearest_ansi((r, g, b)),
+        _ => color,
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+fn nearest_ansi(rgb: (u8, u8, u8)) -> Color {
+    ANSI.iter()
+        .min_by_key(|(_, ansi)| distance(rgb, *ansi))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
+
+/// The levels of each channel in the 6x6x6 color cube at indexes 16 to 231.
+const CUBE: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn indexed_rgb(i: u8) -> (u8, u8, u8) {
+    match i {
+        0..16 => ANSI[i as usize].1,
+        16..232 => {
+            let i = i - 16;
+            (
+                CUBE[(i / 36) as usize],
+                CUBE[(i / 6 % 6) as usize],
+                CUBE[(i % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + 10 * (i - 232);
+            (level, level, level)
+        }
+    }
+}
+
+/// The closest of the color cube and gray ramp to an RGB color.
+fn nearest_indexed(r: u8, g: u8, b: u8) -> u8 {
+    let level = |c: u8| {
+        (0..CUBE.len())
+            .min_by_key(|&i| (CUBE[i] as i32 - c as i32).abs())
+            .unwrap_or_default() as u8
+    };
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let average = (r as u32 + g as u32 + b as u32) / 3;
+    let gray = 232 + ((average.saturating_sub(3)) / 10).min(23) as u8;
+    [cube, gray]
+        .into_iter()
+        .min_by_key(|&i| distance((r, g, b), indexed_rgb(i)))
+        .unwrap_or(cube)
+}
+
+/// Rewrites the 256-color escapes used even for the 16 ANSI colors, such as `ESC[38;5;1m`,
+/// into the `ESC[31m` form understood by terminals without 256 colors.
+pub fn basic_colors(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut rest = data;
+    while let Some(start) = rest.windows(2).position(|w| w == b"\x1b[") {
+        out.extend_from_slice(&rest[..start + 2]);
+        rest = &rest[start + 2..];
+        let Some(end) = rest.iter().position(|b| (0x40..=0x7e).contains(b)) else {
+            break;
+        };
+        let (params, final_byte) = (&rest[..end], rest[end]);
+        rest = &rest[end..];
+        if final_byte != b'm' {
+            out.extend_from_slice(params);
+            continue;
+        }
+
+        let params: Vec<&[u8]> = params.split(|&b| b == b';').collect();
+        let mut rewritten = Vec::with_capacity(params.len());
+        let mut i = 0;
+        while i < params.len() {
+            let index = params
+                .get(i + 2)
+                .and_then(|n| std::str::from_utf8(n).ok()?.parse::<u8>().ok());
+            match (params[i], params.get(i + 1).copied(), index) {
+                (b"38", Some(b"5"), Some(n @ 0..8)) => rewritten.push((30 + n).to_string()),
+                (b"38", Some(b"5"), Some(n @ 8..16)) => rewritten.push((82 + n).to_string()),
+                (b"48", Some(b"5"), Some(n @ 0..8)) => rewritten.push((40 + n).to_string()),
+                (b"48", Some(b"5"), Some(n @ 8..16)) => rewritten.push((92 + n).to_string()),
+                (param, ..) => {
+                    rewritten.push(String::from_utf8_lossy(param).into_owned());
+                    i += 1;
+                    continue;
+                }
+            }
+            i += 3;
+        }
+        out.extend_from_slice(rewritten.join(";").as_bytes());
+    }
+    out.extend_from_slice(rest);
+    out
+}
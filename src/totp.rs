@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+const STEP_SECONDS: u64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of the current one that are also accepted, to allow for clock drift.
+const SKEW: u64 = 1;
+
+/// Decodes a base32 secret as shown by authenticator apps, ignoring spaces and padding.
+pub fn decode_secret(secret: &str) -> anyhow::Result<Vec<u8>> {
+    let normalized: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    data_encoding::BASE32_NOPAD
+        .decode(normalized.as_bytes())
+        .context("TOTP secret is not valid base32")
+}
+
+/// The step each user's code was last accepted for, shared by every connection, so that
+/// a code seen by someone looking over a user's shoulder cannot be used again.
+#[derive(Debug, Default)]
+pub struct UsedCodes {
+    last: Mutex<HashMap<String, u64>>,
+}
+
+impl UsedCodes {
+    /// Checks a six digit RFC 6238 code using HMAC-SHA1 and 30 second steps, refusing
+    /// codes for the step last accepted for `user` or any before it.
+    pub fn verify(&self, user: &str, secret: &[u8], code: &str, now: SystemTime) -> bool {
+        let mut last = self.last.lock().unwrap();
+        let previous = last.get(user).copied();
+        let Some(step) = step(secret, code, now, previous) else {
+            return false;
+        };
+        last.insert(user.to_string(), step);
+        true
+    }
+}
+
+/// The step `code` is for, among those around `now` that are after `after`.
+fn step(secret: &[u8], code: &str, now: SystemTime, after: Option<u64>) -> Option<u64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code = code.parse::<u32>().ok()?;
+
+    let step = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / STEP_SECONDS;
+    let first = after.map_or(0, |after| after + 1);
+    (step.saturating_sub(SKEW).max(first)..=step + SKEW)
+        .find(|counter| hotp(secret, *counter) == code)
+}
+
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+    value % 10u32.pow(DIGITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    /// The secret of the test vectors in RFC 4226 and RFC 6238 for HMAC-SHA1.
+    const SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    fn verify(secret: &[u8], code: &str, now: SystemTime) -> bool {
+        step(secret, code, now, None).is_some()
+    }
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let codes = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in codes.into_iter().enumerate() {
+            assert_eq!(hotp(SECRET, counter as u64), code, "counter {counter}");
+        }
+    }
+
+    #[test]
+    fn verify_accepts_rfc_6238_codes() {
+        // The last six of the eight digits in the RFC's table.
+        let codes = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for (time, code) in codes {
+            assert!(verify(SECRET, code, at(time)), "{time}");
+        }
+    }
+
+    #[test]
+    fn verify_allows_a_step_of_drift_either_way() {
+        assert!(verify(SECRET, "081804", at(1111111109 + 30)));
+        assert!(verify(SECRET, "081804", at(1111111109 - 30)));
+        assert!(!verify(SECRET, "081804", at(1111111109 + 60)));
+        assert!(!verify(SECRET, "081805", at(1111111109)));
+        assert!(!verify(SECRET, "81804", at(1111111109)));
+        assert!(!verify(SECRET, "08180a", at(1111111109)));
+    }
+
+    #[test]
+    fn codes_are_accepted_once() {
+        let used = UsedCodes::default();
+        assert!(used.verify("alice", SECRET, "081804", at(1111111109)));
+        assert!(!used.verify("alice", SECRET, "081804", at(1111111109)));
+        // Nor is the code for the step before, still within the drift allowed.
+        let earlier = format!("{:06}", hotp(SECRET, 1111111109 / 30 - 1));
+        assert!(!used.verify("alice", SECRET, &earlier, at(1111111109)));
+        // Other users' codes are their own.
+        assert!(used.verify("bob", SECRET, "081804", at(1111111109)));
+        let later = format!("{:06}", hotp(SECRET, 1111111109 / 30 + 1));
+        assert!(used.verify("alice", SECRET, &later, at(1111111109 + 30)));
+    }
+}
@@ -1,152 +1,2205 @@
 use ratatui::style::{Color, Modifier, Style};
-use ratatui::text::Line;
-use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph};
+use ratatui::symbols::{border, scrollbar};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{
+    Block, BorderType, Borders, Cell, Clear, List, ListItem, ListState, Paragraph, Row, Scrollbar,
+    ScrollbarOrientation, ScrollbarState, Table, TableState, Wrap,
+};
 use russh::server::Session;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use ratatui::backend::CrosstermBackend;
-use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::layout::{Constraint, Direction, Layout, Margin, Rect};
 use ratatui::{Frame, Terminal, TerminalOptions, Viewport};
+use russh::ChannelId;
 use russh::server::*;
-use russh::{Channel, ChannelId};
-use tokio::sync::mpsc::{UnboundedSender, unbounded_channel};
+use termwiz::escape::csi::{MouseButton, MouseReport};
+use tokio::sync::Notify;
 use tracing::trace;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-pub struct SshTerminal(Terminal<CrosstermBackend<TerminalHandle>>);
+use crate::announcements::Announcements;
+use crate::config::{Announcement, BorderStyle, ColorMode, Indicators, ServerEntry, ThemeConfig};
+use crate::fuzzy;
+use crate::health::{Health, HealthMonitor};
+use crate::history::{History, SortMode, UserHistory};
+use crate::keymap::{Key, KeyContext, KeyMap, MenuAction};
+use crate::maintenance::{Maintenance, MaintenanceWindow};
+use crate::messages::{self, Messages};
+use crate::metrics::Metrics;
+use crate::pane::Panes;
+use crate::plugins::Plugins;
+use crate::provider::ServerProvider;
+use crate::sessions::{SessionInfo, SessionRegistry};
+use crate::term::{self, ColorDepth, TerminalCaps};
+use crate::upstream::FailureReason;
+
+/// Turns on xterm mouse reporting of clicks and the wheel, in the SGR encoding.
+const ENABLE_MOUSE: &[u8] = b"\x1b[?1000h\x1b[?1006h";
+pub const DISABLE_MOUSE: &[u8] = b"\x1b[?1006l\x1b[?1000l";
+/// Resets the terminal to its initial state, clearing the screen.
+const RESET: &[u8] = b"\x1bc";
+
+/// Two clicks on the same row within this long connect to it.
+const DOUBLE_CLICK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Input arriving faster than this is drawn once per interval rather than once per key.
+const FRAME_INTERVAL: Duration = Duration::from_millis(30);
+
+/// How often the spinner shown while connecting moves on, and its frames.
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+const SPINNER: &[&str] = &["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+const ASCII_SPINNER: &[&str] = &["|", "/", "-", "\\"];
+
+/// Output waiting for a client that is not keeping up is dropped beyond this, for the
+/// newest frame.
+const MAX_PENDING_OUTPUT: usize = 256 * 1024;
+
+pub struct SshTerminal {
+    terminal: Terminal<CrosstermBackend<TerminalHandle>>,
+    /// Whether the client's terminal has been asked to report the mouse.
+    mouse: bool,
+    caps: TerminalCaps,
+    /// Shared with the terminal handle, which rewrites colors for terminals with only 16.
+    basic_colors: Arc<AtomicBool>,
+    outbox: Arc<Outbox>,
+    /// Set when the client has no terminal, so only plain lines of text are written.
+    plain: bool,
+}
 
 impl SshTerminal {
-    pub async fn new(channel: Channel<Msg>, session: &mut Session) -> anyhow::Result<Self> {
-        let terminal_handle = TerminalHandle::start(session.handle(), channel.id()).await;
+    pub async fn new(channel: ChannelId, session: &mut Session) -> anyhow::Result<Self> {
+        let terminal_handle = TerminalHandle::start(session.handle(), channel).await;
+        let basic_colors = terminal_handle.basic_colors.clone();
+        let outbox = terminal_handle.outbox.clone();
 
         let backend = CrosstermBackend::new(terminal_handle);
 
         let options = TerminalOptions {
             viewport: Viewport::Fixed(Rect::default()),
         };
-        Ok(Self(Terminal::with_options(backend, options)?))
+        Ok(Self {
+            terminal: Terminal::with_options(backend, options)?,
+            mouse: false,
+            caps: TerminalCaps::default(),
+            basic_colors,
+            outbox,
+            plain: false,
+        })
     }
 
     pub fn render(&mut self, menu: &mut PukekoMenu) -> anyhow::Result<()> {
-        if matches!(menu.state(), MenuState::Open) {
-            self.0.draw(|frame| menu.render_menu(frame))?;
-        } else {
-            self.0
-                .draw(|frame| frame.render_widget(Clear, frame.area()))?;
+        if self.plain {
+            let output = menu.plain_output();
+            return self.write(output.as_bytes());
         }
-        Ok(())
+        // Nothing is drawn before the client asks for a terminal, so commands run without
+        // one, such as pukeko-cert, are not sent escape codes.
+        if self.terminal.get_frame().area().is_empty() {
+            return Ok(());
+        }
+        self.catch_up()?;
+        self.set_mouse(menu.is_drawn())?;
+        menu.draw(&mut self.terminal)
     }
 
     pub fn resize(&mut self, area: Rect) -> anyhow::Result<()> {
-        self.0.resize(area)?;
+        self.terminal.resize(area)?;
+        Ok(())
+    }
+
+    fn set_caps(&mut self, caps: TerminalCaps) {
+        self.caps = caps;
+        self.basic_colors
+            .store(caps.colors < ColorDepth::Indexed256, Ordering::Relaxed);
+    }
+
+    /// Asks the client's terminal to start or stop reporting the mouse. Reporting must be
+    /// stopped before the terminal is handed on or the channel closed, or the client is
+    /// left with a terminal that prints escape codes when clicked.
+    pub fn set_mouse(&mut self, enabled: bool) -> anyhow::Result<()> {
+        let enabled = enabled && self.caps.mouse;
+        if self.mouse != enabled {
+            let backend = self.terminal.backend_mut();
+            backend.write_all(if enabled { ENABLE_MOUSE } else { DISABLE_MOUSE })?;
+            backend.flush()?;
+            self.mouse = enabled;
+        }
+        Ok(())
+    }
+
+    /// Redraws the screen in full if output was dropped because the client fell behind,
+    /// telling its terminal again whether to report the mouse.
+    fn catch_up(&mut self) -> anyhow::Result<()> {
+        if !self.plain && self.outbox.overflowed.swap(false, Ordering::Relaxed) {
+            trace!("Client fell behind, redrawing in full");
+            let backend = self.terminal.backend_mut();
+            backend.write_all(if self.mouse {
+                ENABLE_MOUSE
+            } else {
+                DISABLE_MOUSE
+            })?;
+            self.terminal.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Clears the screen and restores the cursor before handing the terminal to an upstream.
+    pub fn release(&mut self) -> anyhow::Result<()> {
+        if self.plain {
+            return Ok(());
+        }
+        self.set_mouse(false)?;
+        self.terminal.clear()?;
+        self.terminal.show_cursor()?;
+        Ok(())
+    }
+
+    /// Writes to the client's terminal directly, in order with the menu's own output.
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let backend = self.terminal.backend_mut();
+        backend.write_all(data)?;
+        backend.flush()?;
         Ok(())
     }
+
+    /// Takes the terminal back after something else has written to it, resetting whatever
+    /// modes it was left in so the menu can be drawn again from scratch.
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        if self.plain {
+            return Ok(());
+        }
+        let backend = self.terminal.backend_mut();
+        backend.write_all(RESET)?;
+        backend.flush()?;
+        self.mouse = false;
+        self.terminal.clear()?;
+        Ok(())
+    }
+
+    /// Whether the client has no terminal to send escape codes to.
+    pub fn is_plain(&self) -> bool {
+        self.plain
+    }
+}
+
+pub struct MenuScreen {
+    pub terminal: SshTerminal,
+    pub menu: PukekoMenu,
+    last_frame: Option<Instant>,
+    /// Set when input has changed the menu since it was last drawn.
+    deferred: bool,
+    frame_due: Arc<Notify>,
+}
+
+impl MenuScreen {
+    pub fn render(&mut self) -> anyhow::Result<()> {
+        self.deferred = false;
+        self.last_frame = Some(Instant::now());
+        self.terminal.render(&mut self.menu)
+    }
+
+    /// Renders the menu after input, at most once per frame interval. Input arriving
+    /// sooner after the last frame is left for the next, which is drawn once it is due.
+    pub fn render_input(&mut self) -> anyhow::Result<()> {
+        match self.last_frame {
+            Some(last)
+                if last.elapsed() < FRAME_INTERVAL
+                    && matches!(self.menu.state, MenuState::Open) =>
+            {
+                if !self.deferred {
+                    self.deferred = true;
+                    self.frame_due.notify_one();
+                }
+                Ok(())
+            }
+            _ => self.render(),
+        }
+    }
+
+    /// Notified when input has been left for the next frame.
+    pub fn frame_due(&self) -> Arc<Notify> {
+        self.frame_due.clone()
+    }
+
+    /// When the frame input was left for should be drawn, if it has not been already.
+    pub fn next_frame(&self) -> Option<Instant> {
+        let last = self.last_frame?;
+        self.deferred.then(|| last + FRAME_INTERVAL)
+    }
+
+    /// Draws the panes in place of the menu, in the menu's theme.
+    pub fn render_panes(&mut self, panes: &Panes) -> anyhow::Result<()> {
+        self.terminal.catch_up()?;
+        self.terminal.set_mouse(false)?;
+        let theme = &self.menu.theme;
+        self.terminal
+            .terminal
+            .draw(|frame| panes.render(frame, theme.block(), theme.focused()))?;
+        Ok(())
+    }
+
+    /// Adapts the menu to the client's terminal, once it has requested a pty. Takes effect
+    /// from the next render.
+    pub fn set_caps(&mut self, caps: TerminalCaps) {
+        self.menu.set_caps(caps);
+        self.terminal.set_caps(caps);
+    }
+
+    /// Lists the servers as numbered lines of text instead of drawing the menu, for
+    /// clients that did not ask for a terminal.
+    pub fn use_plain(&mut self) {
+        self.terminal.plain = true;
+        self.menu.use_plain();
+    }
+}
+
+/// What a server's indicator shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Up,
+    Down,
+    Maintenance,
+}
+
+/// Styles for the menu, from the configured theme and the colors the client's terminal
+/// supports.
+#[derive(Debug, Clone)]
+pub struct Theme {
+    config: ThemeConfig,
+    colors: ColorDepth,
+    /// Draw with ASCII only, for terminals without box-drawing characters.
+    ascii: bool,
+}
+
+impl Theme {
+    pub fn new(config: ThemeConfig) -> Self {
+        Self {
+            colors: match config.color {
+                ColorMode::Never => ColorDepth::None,
+                ColorMode::Auto | ColorMode::Always => ColorDepth::TrueColor,
+            },
+            ascii: false,
+            config,
+        }
+    }
+
+    /// Fits colors and symbols to the client's terminal. Colors are only changed when the
+    /// theme leaves the color mode to the terminal.
+    pub fn set_caps(&mut self, caps: TerminalCaps) {
+        if self.config.color == ColorMode::Auto {
+            self.colors = caps.colors;
+        }
+        self.ascii = !caps.unicode;
+    }
+
+    fn fg(&self, color: Color) -> Style {
+        if self.colors == ColorDepth::None {
+            Style::default()
+        } else {
+            Style::default().fg(term::downgrade(color, self.colors))
+        }
+    }
+
+    fn highlight(&self) -> Style {
+        let style = if self.colors == ColorDepth::None {
+            Style::default().add_modifier(Modifier::REVERSED)
+        } else {
+            Style::default()
+                .bg(term::downgrade(self.config.highlight_bg, self.colors))
+                .fg(term::downgrade(self.config.highlight_fg, self.colors))
+        };
+        style.add_modifier(Modifier::BOLD)
+    }
+
+    fn ellipsis(&self) -> &'static str {
+        self.symbol("…", "...")
+    }
+
+    /// Marks a server as up, down or under maintenance. Dots are only told apart by their
+    /// color, so symbols are used instead when the theme asks for them or there are no
+    /// colors.
+    fn indicator(&self, status: Status) -> &'static str {
+        let (unicode, ascii) = match status {
+            Status::Up => ("✓ ", "o "),
+            Status::Down => ("✗ ", "x "),
+            Status::Maintenance => ("◌ ", "- "),
+        };
+        if self.config.indicators == Indicators::Dots && self.colors != ColorDepth::None {
+            self.symbol("● ", ascii)
+        } else {
+            self.symbol(unicode, ascii)
+        }
+    }
+
+    /// `unicode`, or `ascii` on terminals that cannot draw it.
+    fn symbol(&self, unicode: &'static str, ascii: &'static str) -> &'static str {
+        if self.ascii { ascii } else { unicode }
+    }
+
+    fn scrollbar(&self) -> Scrollbar<'static> {
+        let scrollbar = Scrollbar::new(ScrollbarOrientation::VerticalRight);
+        if self.ascii {
+            scrollbar.symbols(scrollbar::Set {
+                track: "|",
+                thumb: "#",
+                begin: "^",
+                end: "v",
+            })
+        } else {
+            scrollbar
+        }
+    }
+
+    /// The border and title of the focused pane.
+    fn focused(&self) -> Style {
+        self.fg(self.config.highlight_bg)
+            .add_modifier(Modifier::BOLD)
+    }
+
+    fn notice(&self) -> Style {
+        self.fg(self.config.notice).add_modifier(Modifier::BOLD)
+    }
+
+    fn block(&self) -> Block<'static> {
+        let block = Block::default().borders(Borders::ALL);
+        if self.ascii {
+            return block.border_set(ASCII_BORDER);
+        }
+        let border_type = match self.config.borders {
+            BorderStyle::Plain => BorderType::Plain,
+            BorderStyle::Rounded => BorderType::Rounded,
+            BorderStyle::Double => BorderType::Double,
+            BorderStyle::Thick => BorderType::Thick,
+        };
+        block.border_type(border_type)
+    }
 }
-#[derive(Debug, Clone, Copy)]
+
+const ASCII_BORDER: border::Set = border::Set {
+    top_left: "+",
+    top_right: "+",
+    bottom_left: "+",
+    bottom_right: "+",
+    vertical_left: "|",
+    vertical_right: "|",
+    horizontal_top: "-",
+    horizontal_bottom: "-",
+};
+
+#[derive(Debug, Clone)]
 pub enum MenuState {
     Open,
+    Selected(ServerEntry),
+    Confirmed,
+    /// An admin confirmed terminating the session with this id, on `node` when it is
+    /// another of the cluster.
+    Terminate {
+        node: Option<String>,
+        id: usize,
+    },
+    /// An admin wrote a message to send to every session.
+    Broadcast(String),
+    /// An admin chose to watch the session with this id.
+    Shadow(usize),
+    /// The user chose to resume the detached session with this id.
+    Resume(u64),
+    /// The user asked for access to a server, for the given reason.
+    RequestAccess {
+        server: String,
+        reason: String,
+    },
+    /// The user asked to list their keys, which are shown with [`PukekoMenu::show_keys`].
+    ListKeys,
+    /// The user pasted a key to add.
+    AddKey(String),
+    /// The user confirmed removing this key.
+    RemoveKey(String),
+    /// The user asked to connect to a server in a new pane.
+    Pane(ServerEntry),
+    /// The panes are showing, with the menu hidden behind them.
+    Panes,
     Closing,
 }
 
+/// A failed connection from the menu.
+#[derive(Debug, Clone)]
+struct Failure {
+    entry: ServerEntry,
+    reason: FailureReason,
+    detail: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum View {
+    Servers,
+    Sessions,
+}
+
+/// A request for access to another server being made: a server is picked from those
+/// the user cannot reach, then the reason is written.
+struct AccessDraft {
+    servers: Vec<ServerEntry>,
+    picker: ListState,
+    /// The reason being written, once a server is picked.
+    reason: Option<String>,
+}
+
+/// One of the user's keys, as listed in the menu.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListedKey {
+    /// The OpenSSH public key, without its comment.
+    pub key: String,
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    pub source: KeySource,
+}
+
+/// Where one of a user's keys is kept, which decides whether they can remove it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeySource {
+    /// The config file, which only admins change.
+    Config,
+    /// The database.
+    Stored,
+    /// Approved by an admin, or added without needing approval, and kept in the state
+    /// directory.
+    Approved,
+    /// Waiting for an admin to approve it.
+    Pending,
+}
+
+/// The user's keys being managed: a key is picked to remove it, or the last row to paste
+/// a new one.
+struct KeyList {
+    keys: Vec<ListedKey>,
+    picker: ListState,
+    /// The key being pasted, once adding one is picked.
+    pasted: Option<String>,
+}
+
+/// A row of the preferences popup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Preference {
+    Theme,
+    Keys,
+}
+
+/// The rows of the preferences popup, with the theme only when there are others to pick.
+fn preference_rows(themes: bool) -> &'static [Preference] {
+    if themes {
+        &[Preference::Theme, Preference::Keys]
+    } else {
+        &[Preference::Keys]
+    }
+}
+
+/// A server that asks for its name to be typed before it is connected to.
+struct PendingConnection {
+    entry: ServerEntry,
+    /// Whether it opens in a new pane rather than replacing the menu.
+    pane: bool,
+    typed: String,
+}
+
+/// The menu for clients without a terminal: a numbered list of servers, and a prompt
+/// read a line at a time.
+#[derive(Default)]
+struct PlainMenu {
+    /// The servers by the numbers they were listed with.
+    servers: Vec<String>,
+    line: Vec<u8>,
+    /// Written on the next render.
+    output: String,
+    /// Whether the server being connected to has been written.
+    connecting: bool,
+}
+
+/// A line in the server list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MenuRow {
+    /// A folder of servers sharing a `group`, which are listed after it unless collapsed.
+    Group {
+        name: String,
+        servers: usize,
+        collapsed: bool,
+    },
+    /// An index into the menu's items.
+    Server(usize),
+}
+
+/// The selected row, kept across rebuilds of the list which renumber its servers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selection {
+    Group(String),
+    Server(String),
+}
+
 struct UI {
     list_state: ListState,
+    /// Rows that fit in the list as last drawn, which is how far paging moves.
+    list_page: usize,
+    /// Like `list_page`, for the sessions table.
+    session_page: usize,
+    /// Where the list was last drawn, including its border, to find the row clicked.
+    list_area: Rect,
+    /// The row last clicked and when, to tell a double click.
+    last_click: Option<(usize, Instant)>,
+    /// The open tag picker, where the first entry clears the tag.
+    tag_picker: Option<ListState>,
+    session_state: TableState,
 }
 
 pub struct PukekoMenu {
     parser: termwiz::escape::parser::Parser,
 
-    items: Vec<String>,
+    user: String,
+    servers: Arc<dyn ServerProvider>,
+    items: Vec<ServerEntry>,
+    /// The servers that match the filter under their groups, in display order.
+    rows: Vec<MenuRow>,
+    /// Groups whose servers are hidden.
+    collapsed: HashSet<String>,
+    /// The search query while filtering with `/`.
+    filter: Option<String>,
+    /// Only servers with this tag are listed, picked with `t`.
+    tag: Option<String>,
+    history: Arc<History>,
+    /// The user's favorites and sort mode, as of the last rebuild of the rows.
+    usage: UserHistory,
     ui: UI,
     state: MenuState,
+    notice: Option<String>,
+    dialog: Option<String>,
+    /// Shown along the bottom of the menu, such as the idle countdown.
+    banner: Option<String>,
+    /// The server being connected to and since when, shown with a spinner until the
+    /// terminal is handed to it.
+    connecting: Option<(String, Instant)>,
+    /// A login notice shown instead of the menu until a key is pressed.
+    splash: Option<String>,
+    last_input: Instant,
+    metrics: Arc<Metrics>,
+    health: Arc<HealthMonitor>,
+    theme: Theme,
+    /// Every session, for admins to manage from the sessions view.
+    sessions: Option<Arc<SessionRegistry>>,
+    /// The realm the user is in, whose sessions are the only ones shown, or None for all.
+    realm: Option<String>,
+    view: View,
+    session_rows: Vec<SessionInfo>,
+    /// The session a terminate dialog is asking about, and its node when another.
+    pending_termination: Option<(Option<String>, usize)>,
+    /// The detached session the user is being asked to resume.
+    pending_resume: Option<u64>,
+    /// A message to every session being written in the sessions view.
+    compose: Option<String>,
+    /// Whether other servers can be requested with `a`.
+    access_requests: bool,
+    access_draft: Option<AccessDraft>,
+    /// Whether users can list, add and remove their keys.
+    key_management: bool,
+    key_list: Option<KeyList>,
+    /// The key a remove dialog is asking about.
+    pending_removal: Option<String>,
+    /// Servers with these tags ask for their name before connecting, like those with
+    /// `confirm` set.
+    confirm_tags: Vec<String>,
+    pending_connection: Option<PendingConnection>,
+    /// A broadcast message and its sender, shown until a key is pressed.
+    message: Option<(String, String)>,
+    /// A server that could not be connected to, offered to retry until a key is pressed.
+    failure: Option<Failure>,
+    keymap: KeyMap,
+    messages: Arc<Messages>,
+    /// Themes the user can pick instead of the configured one, by name.
+    themes: BTreeMap<String, ThemeConfig>,
+    /// The configured theme, to go back to from one picked.
+    default_theme: ThemeConfig,
+    theme_name: Option<String>,
+    /// The client's terminal, once known, which picked themes are fitted to.
+    caps: Option<TerminalCaps>,
+    preferences: Option<ListState>,
+    /// Servers under maintenance, which are marked or left out.
+    maintenance: Option<Arc<Maintenance>>,
+    /// Announcements from the config, shown with those posted from the control socket.
+    announcements: Vec<Announcement>,
+    posted: Option<Arc<Announcements>>,
+    /// Labels plugins give servers, and the plugins to ask again when the servers change.
+    /// Plugins are asked in the background, and `labelled` notified once they answer.
+    plugins: Option<Arc<Plugins>>,
+    labels: Arc<std::sync::Mutex<HashMap<String, String>>>,
+    labelled: Arc<Notify>,
+    /// Whether the keybindings are shown, until a key is pressed.
+    help: bool,
+    /// Set when the client has no terminal to draw the menu on.
+    plain: Option<PlainMenu>,
 }
 
 impl PukekoMenu {
+    #[allow(clippy::too_many_arguments)]
     pub async fn from_session(
-        channel: Channel<Msg>,
+        channel: ChannelId,
         session: &mut Session,
-    ) -> anyhow::Result<(SshTerminal, Self)> {
+        user: String,
+        servers: Arc<dyn ServerProvider>,
+        metrics: Arc<Metrics>,
+        health: Arc<HealthMonitor>,
+        history: Arc<History>,
+        sessions: Option<Arc<SessionRegistry>>,
+        theme: Theme,
+        keymap: KeyMap,
+        messages: Arc<Messages>,
+    ) -> anyhow::Result<MenuScreen> {
         let terminal = SshTerminal::new(channel, session).await?;
+        Ok(MenuScreen {
+            terminal,
+            menu: Self::new(
+                user, servers, metrics, health, history, sessions, theme, keymap, messages,
+            ),
+            last_frame: None,
+            deferred: false,
+            frame_due: Arc::new(Notify::new()),
+        })
+    }
+
+    /// A menu for `user`, drawn with [`PukekoMenu::draw`] on any backend.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        user: String,
+        servers: Arc<dyn ServerProvider>,
+        metrics: Arc<Metrics>,
+        health: Arc<HealthMonitor>,
+        history: Arc<History>,
+        sessions: Option<Arc<SessionRegistry>>,
+        theme: Theme,
+        keymap: KeyMap,
+        messages: Arc<Messages>,
+    ) -> Self {
+        let items = servers.servers(&user);
+        let saved = history.user(&user);
+        let default_theme = theme.config.clone();
+        // The tag the user last filtered by, if servers still have it.
+        let tag = saved
+            .tag
+            .filter(|tag| server_tags(&items).contains(&tag.as_str()));
+        let keymap = saved
+            .keys
+            .and_then(|preset| keymap.with_preset(preset).ok())
+            .unwrap_or(keymap);
+
+        let mut menu = Self {
+            parser: termwiz::escape::parser::Parser::new(),
+            user,
+            servers,
+            items,
+            rows: Vec::new(),
+            collapsed: HashSet::new(),
+            filter: None,
+            tag,
+            history,
+            usage: UserHistory::default(),
+            ui: UI {
+                list_state: ListState::default().with_selected(Some(0)),
+                list_page: 1,
+                session_page: 1,
+                list_area: Rect::default(),
+                last_click: None,
+                tag_picker: None,
+                session_state: TableState::default().with_selected(Some(0)),
+            },
+            state: MenuState::Open,
+            notice: None,
+            dialog: None,
+            banner: None,
+            connecting: None,
+            splash: None,
+            last_input: Instant::now(),
+            metrics,
+            health,
+            theme,
+            sessions,
+            realm: None,
+            view: View::Servers,
+            session_rows: Vec::new(),
+            pending_termination: None,
+            pending_resume: None,
+            compose: None,
+            access_requests: false,
+            access_draft: None,
+            key_management: false,
+            key_list: None,
+            pending_removal: None,
+            confirm_tags: Vec::new(),
+            pending_connection: None,
+            message: None,
+            failure: None,
+            keymap,
+            messages,
+            themes: BTreeMap::new(),
+            default_theme,
+            theme_name: None,
+            caps: None,
+            preferences: None,
+            maintenance: None,
+            announcements: Vec::new(),
+            posted: None,
+            plugins: None,
+            labels: Arc::default(),
+            labelled: Arc::new(Notify::new()),
+            help: false,
+            plain: None,
+        };
+        menu.rebuild_rows(None);
+        menu
+    }
+
+    pub fn state(&self) -> &MenuState {
+        &self.state
+    }
+
+    /// Whether the menu is drawn, rather than a blank screen while a server has the
+    /// terminal.
+    pub fn is_drawn(&self) -> bool {
+        matches!(
+            self.state,
+            MenuState::Open
+                | MenuState::Terminate { .. }
+                | MenuState::Broadcast(_)
+                | MenuState::RequestAccess { .. }
+                | MenuState::ListKeys
+                | MenuState::AddKey(_)
+                | MenuState::RemoveKey(_)
+        ) || self.is_connecting()
+    }
+
+    /// Draws the menu on `terminal`, or clears it when the menu is not shown.
+    pub fn draw<B: ratatui::backend::Backend>(
+        &mut self,
+        terminal: &mut Terminal<B>,
+    ) -> anyhow::Result<()> {
+        if self.is_drawn() {
+            terminal.draw(|frame| self.render_menu(frame))?;
+        } else {
+            terminal.draw(|frame| frame.render_widget(Clear, frame.area()))?;
+        }
+        Ok(())
+    }
+
+    pub fn set_notice(&mut self, notice: impl Into<String>) {
+        self.notice = Some(notice.into());
+    }
+
+    pub fn banner(&self) -> Option<&str> {
+        self.banner.as_deref()
+    }
+
+    pub fn set_banner(&mut self, banner: Option<String>) {
+        self.banner = banner;
+    }
+
+    /// Adapts the theme to the terminal type from the client's PTY request.
+    pub fn set_caps(&mut self, caps: TerminalCaps) {
+        self.caps = Some(caps);
+        self.theme.set_caps(caps);
+    }
+
+    /// Lets the user pick from `themes`, switching to the one they picked last time.
+    pub fn set_themes(&mut self, themes: BTreeMap<String, ThemeConfig>) {
+        self.themes = themes;
+        let saved = self.history.user(&self.user).theme;
+        self.use_theme(saved);
+    }
+
+    pub fn set_maintenance(&mut self, maintenance: Arc<Maintenance>) {
+        self.maintenance = Some(maintenance);
+        self.apply_filter();
+    }
+
+    pub fn set_realm(&mut self, realm: Option<String>) {
+        self.realm = realm;
+    }
+
+    pub fn set_plugins(&mut self, plugins: Arc<Plugins>) {
+        self.plugins = Some(plugins);
+        self.relabel();
+    }
+
+    /// Notified when plugins have labelled the servers, for the menu to be drawn again.
+    pub fn labelled(&self) -> Arc<Notify> {
+        self.labelled.clone()
+    }
+
+    /// Asks the plugins to label the servers again, without waiting for them.
+    fn relabel(&self) {
+        let Some(plugins) = self.plugins.clone() else {
+            return;
+        };
+        let (user, items) = (self.user.clone(), self.items.clone());
+        let (labels, labelled) = (self.labels.clone(), self.labelled.clone());
+        tokio::spawn(async move {
+            *labels.lock().unwrap() = plugins.labels(&user, items).await;
+            labelled.notify_one();
+        });
+    }
+
+    pub fn set_announcements(&mut self, configured: Vec<Announcement>, posted: Arc<Announcements>) {
+        self.announcements = configured;
+        self.posted = Some(posted);
+    }
+
+    /// The messages announced now for `server`, or for `None` those for every server.
+    fn announcements(&self, server: Option<&str>) -> Vec<String> {
+        let Some(posted) = &self.posted else {
+            return Vec::new();
+        };
+        posted
+            .shown(&self.announcements)
+            .into_iter()
+            .filter(|announcement| announcement.server.as_deref() == server)
+            .map(|announcement| announcement.message)
+            .collect()
+    }
+
+    /// The maintenance keeping the user from `server`, which admins may be let through.
+    fn maintenance(&self, server: &str) -> Option<MaintenanceWindow> {
+        self.maintenance
+            .as_ref()?
+            .blocks(server, self.sessions.is_some())
+    }
+
+    /// Switches to the theme named `name`, or the configured one when there is none.
+    fn use_theme(&mut self, name: Option<String>) {
+        let picked = name.and_then(|name| {
+            let config = self.themes.get(&name)?.clone();
+            Some((name, config))
+        });
+        let config = match &picked {
+            Some((_, config)) => config.clone(),
+            None => self.default_theme.clone(),
+        };
+        self.theme = Theme::new(config);
+        if let Some(caps) = self.caps {
+            self.theme.set_caps(caps);
+        }
+        self.theme_name = picked.map(|(name, _)| name);
+    }
+
+    pub fn splash(&self) -> Option<&str> {
+        self.splash.as_deref()
+    }
+
+    pub fn show_splash(&mut self, text: String) {
+        self.splash = Some(text);
+    }
+
+    /// Time since the user last typed anything.
+    pub fn idle_for(&self) -> Duration {
+        self.last_input.elapsed()
+    }
+
+    pub fn set_connecting(&mut self, server: Option<String>) {
+        self.connecting = server.map(|server| (server, Instant::now()));
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        self.connecting.is_some()
+    }
+
+    /// How often the menu should be redrawn for what it shows that changes over time,
+    /// if it shows anything.
+    pub fn tick_interval(&self) -> Option<Duration> {
+        if self.connecting.is_some() {
+            Some(SPINNER_INTERVAL)
+        } else if self.showing_sessions() {
+            // Durations and byte counts change continuously.
+            Some(Duration::from_secs(1))
+        } else {
+            None
+        }
+    }
+
+    /// Shows a yes/no prompt. Accepting it moves the menu to [`MenuState::Confirmed`].
+    pub fn confirm(&mut self, prompt: impl Into<String>) {
+        self.dialog = Some(prompt.into());
+    }
+
+    /// Asks whether to resume the detached session `id`. Accepting it moves the menu to
+    /// [`MenuState::Resume`].
+    pub fn offer_resume(&mut self, id: u64, prompt: impl Into<String>) {
+        self.dialog = Some(prompt.into());
+        self.pending_resume = Some(id);
+    }
+
+    pub fn enable_access_requests(&mut self) {
+        self.access_requests = true;
+    }
+
+    pub fn enable_key_management(&mut self) {
+        self.key_management = true;
+    }
+
+    /// Lists the user's keys, keeping the row picked when the list is already showing.
+    pub fn show_keys(&mut self, keys: Vec<ListedKey>) {
+        let selected = self
+            .key_list
+            .as_ref()
+            .and_then(|list| list.picker.selected())
+            .unwrap_or(0)
+            .min(keys.len());
+        self.key_list = Some(KeyList {
+            keys,
+            picker: ListState::default().with_selected(Some(selected)),
+            pasted: None,
+        });
+    }
+
+    pub fn set_confirm_tags(&mut self, tags: Vec<String>) {
+        self.confirm_tags = tags;
+    }
+
+    pub fn messages(&self) -> &Arc<Messages> {
+        &self.messages
+    }
+
+    /// Changes the text of the menu, such as when the client asks for another locale.
+    pub fn set_messages(&mut self, messages: Arc<Messages>) {
+        self.messages = messages;
+    }
+
+    pub fn show_message(&mut self, from: String, text: String) {
+        self.message = Some((from, text));
+    }
+
+    /// Explains why connecting to `entry` failed. Retrying moves the menu to
+    /// [`MenuState::Selected`] again.
+    pub fn show_failure(&mut self, entry: ServerEntry, error: &anyhow::Error) {
+        self.failure = Some(Failure {
+            entry,
+            reason: FailureReason::of(error),
+            detail: format!("{error:#}"),
+        });
+    }
+
+    /// Whether the sessions view is showing, which changes as sessions transfer data.
+    pub fn showing_sessions(&self) -> bool {
+        self.view == View::Sessions
+    }
+
+    pub fn cancel_selection(&mut self) {
+        if matches!(
+            self.state,
+            MenuState::Selected(_)
+                | MenuState::Confirmed
+                | MenuState::Terminate { .. }
+                | MenuState::Broadcast(_)
+                | MenuState::Shadow(_)
+                | MenuState::Resume(_)
+                | MenuState::RequestAccess { .. }
+                | MenuState::ListKeys
+                | MenuState::AddKey(_)
+                | MenuState::RemoveKey(_)
+                | MenuState::Pane(_)
+                | MenuState::Panes
+        ) {
+            self.state = MenuState::Open;
+        }
+    }
+
+    /// Hides the menu behind the panes.
+    pub fn show_panes(&mut self) {
+        self.state = MenuState::Panes;
+    }
+
+    /// Keeps the menu open after it was quit.
+    pub fn reopen(&mut self) {
+        if matches!(self.state, MenuState::Closing) {
+            self.state = MenuState::Open;
+        }
+    }
+
+    /// Lists the servers as numbered lines for a client without a terminal, which picks
+    /// one by typing its number or name.
+    fn use_plain(&mut self) {
+        self.plain = Some(PlainMenu::default());
+        self.list_plain();
+    }
+
+    fn list_plain(&mut self) {
+        self.refresh_items();
+        let mut output: String = self
+            .announcements(None)
+            .into_iter()
+            .map(|announcement| format!("{announcement}\n"))
+            .collect();
+        let mut servers = Vec::new();
+        for entry in &self.items {
+            let maintenance = self.maintenance(&entry.name);
+            if maintenance.as_ref().is_some_and(|window| window.hidden) {
+                continue;
+            }
+            servers.push(entry.name.clone());
+            let mut line = format!("{:>3}) {}", servers.len(), entry.name);
+            if maintenance.is_some() {
+                line.push_str(&format!(" ({})", self.messages.maintenance));
+            } else if self.health.status(&entry.name) == Some(Health::Down) {
+                line.push_str(&format!(" ({})", self.messages.down));
+            }
+            let announcements = self.announcements(Some(&entry.name));
+            if !announcements.is_empty() {
+                line.push_str(&format!(" - {}", announcements.join("; ")));
+            }
+            output.push_str(&line);
+            output.push('\n');
+        }
+        if let Some(plain) = &mut self.plain {
+            plain.servers = servers;
+            plain.output.push_str(&output);
+        }
+    }
+
+    /// What to write to a client without a terminal: anything the menu has to tell it
+    /// since the last render, then the prompt if it is waiting for a choice.
+    fn plain_output(&mut self) -> String {
+        let Some(plain) = &mut self.plain else {
+            return String::new();
+        };
+        let mut lines: Vec<String> = self.splash.take().into_iter().collect();
+        if let Some((from, text)) = self.message.take() {
+            let title = messages::fill(&self.messages.message_title, &[("from", &from)]);
+            lines.push(format!("{title}: {text}"));
+        }
+        lines.extend(self.notice.take());
+        if let Some(failure) = self.failure.take() {
+            let reason = self.messages.failure(failure.reason);
+            lines.push(format!("{reason}: {}", failure.detail));
+        }
+        match &self.connecting {
+            Some((server, _)) if !plain.connecting => {
+                plain.connecting = true;
+                lines.push(messages::fill(
+                    &self.messages.connecting,
+                    &[("server", server)],
+                ));
+            }
+            Some(_) => {}
+            None => plain.connecting = false,
+        }
+
+        let mut output = std::mem::take(&mut plain.output);
+        for line in lines {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        if output.is_empty() || self.connecting.is_some() || !matches!(self.state, MenuState::Open)
+        {
+            return output;
+        }
+        if let Some(dialog) = &self.dialog {
+            output.push_str(&format!("{dialog} [y/N] "));
+        } else if let Some(pending) = &self.pending_connection {
+            let prompt = messages::fill(
+                &self.messages.confirm_server,
+                &[("server", &pending.entry.name)],
+            );
+            output.push_str(&format!("{prompt} "));
+        } else {
+            output.push_str(&self.messages.plain_prompt);
+        }
+        output
+    }
+
+    /// Reads the lines typed by a client without a terminal.
+    fn handle_plain_data(&mut self, data: &[u8]) {
+        for &byte in data {
+            let Some(plain) = &mut self.plain else {
+                return;
+            };
+            match byte {
+                b'\r' | b'\n' => {
+                    let line = std::mem::take(&mut plain.line);
+                    self.handle_plain_line(String::from_utf8_lossy(&line).trim());
+                }
+                // Ctrl-C and Ctrl-D.
+                0x03 | 0x04 => self.state = MenuState::Closing,
+                0x08 | 0x7f => {
+                    plain.line.pop();
+                }
+                byte => plain.line.push(byte),
+            }
+            // Anything after the choice was meant for the menu, not the server.
+            if !matches!(self.state, MenuState::Open) {
+                return;
+            }
+        }
+    }
+
+    fn handle_plain_line(&mut self, line: &str) {
+        if line.is_empty() {
+            return;
+        }
+        if self.dialog.is_some() {
+            self.handle_key(Key::Char(line.chars().next().unwrap_or('n')));
+            return;
+        }
+        if let Some(pending) = &mut self.pending_connection {
+            pending.typed = line.to_string();
+            self.handle_confirmation_key(Key::Enter);
+            return;
+        }
+        if matches!(line, "q" | "quit" | "exit") {
+            self.state = MenuState::Closing;
+            return;
+        }
+        let name = match line.parse::<usize>() {
+            Ok(number) => self
+                .plain
+                .as_ref()
+                .and_then(|plain| plain.servers.get(number.checked_sub(1)?))
+                .map(String::as_str),
+            Err(_) => Some(line),
+        };
+        let entry = name.and_then(|name| self.items.iter().find(|entry| entry.name == name));
+        match entry {
+            Some(entry) => self.connect(entry.clone(), false),
+            None => {
+                self.notice = Some(messages::fill(
+                    &self.messages.plain_unknown,
+                    &[("server", &line)],
+                ))
+            }
+        }
+    }
+
+    fn selected_row(&self) -> Option<&MenuRow> {
+        self.ui.list_state.selected().and_then(|i| self.rows.get(i))
+    }
+
+    fn selected_item(&self) -> Option<&ServerEntry> {
+        match self.selected_row()? {
+            MenuRow::Server(i) => Some(&self.items[*i]),
+            MenuRow::Group { .. } => None,
+        }
+    }
+
+    fn selection(&self) -> Option<Selection> {
+        self.selected_row().map(|row| self.row_selection(row))
+    }
+
+    fn row_selection(&self, row: &MenuRow) -> Selection {
+        match row {
+            MenuRow::Group { name, .. } => Selection::Group(name.clone()),
+            MenuRow::Server(i) => Selection::Server(self.items[*i].name.clone()),
+        }
+    }
+
+    /// Reloads the servers from the provider, which may have changed since the last render.
+    fn refresh_items(&mut self) {
+        let items = self.servers.servers(&self.user);
+        if items != self.items {
+            let selection = self.selection();
+            self.items = items;
+            self.relabel();
+            self.rebuild_rows(selection);
+        }
+    }
+
+    /// Recomputes the rows from the filter, keeping the selected row if it still matches.
+    fn apply_filter(&mut self) {
+        self.rebuild_rows(self.selection());
+    }
+
+    fn rebuild_rows(&mut self, selection: Option<Selection>) {
+        let query = self.filter.as_deref().unwrap_or_default();
+
+        let mut matches: Vec<(i64, usize)> = self
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| self.tag.as_ref().is_none_or(|tag| entry.tags.contains(tag)))
+            .filter(|(_, entry)| {
+                self.maintenance(&entry.name)
+                    .is_none_or(|window| !window.hidden)
+            })
+            .filter_map(|(i, entry)| {
+                std::iter::once(&entry.name)
+                    .chain(std::iter::once(&entry.host))
+                    .chain(&entry.group)
+                    .chain(&entry.tags)
+                    .filter_map(|field| fuzzy::score(query, field))
+                    .max()
+                    .map(|score| (score, i))
+            })
+            .collect();
+        self.usage = self.history.user(&self.user);
+        matches.sort_by(|&(score, i), &(other_score, other)| {
+            other_score
+                .cmp(&score)
+                .then_with(|| {
+                    self.usage
+                        .compare(&self.items[i].name, &self.items[other].name)
+                })
+                .then(i.cmp(&other))
+        });
+
+        // Each group is listed where its best match is, with the number of matches in it.
+        let mut groups: HashMap<&str, (usize, usize)> = HashMap::new();
+        for (position, &(_, i)) in matches.iter().enumerate() {
+            if let Some(group) = &self.items[i].group {
+                groups.entry(group).or_insert((position, 0)).1 += 1;
+            }
+        }
+        let mut order: Vec<(usize, usize)> = matches
+            .iter()
+            .enumerate()
+            .map(|(position, &(_, i))| match &self.items[i].group {
+                Some(group) => (groups[group.as_str()].0, i),
+                None => (position, i),
+            })
+            .collect();
+        order.sort_by_key(|&(position, _)| position);
+
+        // Groups are always expanded while filtering, so every match shows.
+        let filtering = !query.is_empty();
+        let mut rows = Vec::new();
+        let mut current = None;
+        for (_, i) in order {
+            let group = self.items[i].group.as_deref();
+            let collapsed = group.is_some_and(|group| !filtering && self.collapsed.contains(group));
+            if let Some(name) = group
+                && current != group
+            {
+                rows.push(MenuRow::Group {
+                    name: name.to_string(),
+                    servers: groups[name].1,
+                    collapsed,
+                });
+            }
+            current = group;
+            if !collapsed {
+                rows.push(MenuRow::Server(i));
+            }
+        }
+        self.rows = rows;
+
+        let index = selection
+            .and_then(|selection| {
+                self.rows
+                    .iter()
+                    .position(|row| self.row_selection(row) == selection)
+            })
+            .unwrap_or(0);
+        self.ui.list_state.select(Some(index));
+    }
+
+    fn open_tag_picker(&mut self) {
+        let tags = server_tags(&self.items);
+        if tags.is_empty() {
+            self.notice = Some(self.messages.no_tags.clone());
+            return;
+        }
+        let selected = self
+            .tag
+            .as_deref()
+            .and_then(|tag| tags.iter().position(|&t| t == tag))
+            .map_or(0, |i| i + 1);
+        self.ui.tag_picker = Some(ListState::default().with_selected(Some(selected)));
+    }
+
+    /// Handles a key while the tag picker is open. Returns false when it is closed.
+    fn handle_tag_key(&mut self, action: Option<MenuAction>) -> bool {
+        let entries = server_tags(&self.items).len() + 1;
+        let Some(picker) = &mut self.ui.tag_picker else {
+            return false;
+        };
+        let selected = picker.selected().unwrap_or(0);
+
+        match action {
+            Some(MenuAction::Up) => picker.select(Some((selected + entries - 1) % entries)),
+            Some(MenuAction::Down) => picker.select(Some((selected + 1) % entries)),
+            Some(MenuAction::Select) => {
+                self.ui.tag_picker = None;
+                self.tag = selected
+                    .checked_sub(1)
+                    .and_then(|i| server_tags(&self.items).get(i).map(|tag| tag.to_string()));
+                self.history.set_tag(&self.user, self.tag.clone());
+                self.apply_filter();
+            }
+            Some(MenuAction::Quit | MenuAction::Tags) => self.ui.tag_picker = None,
+            _ => {}
+        }
+        true
+    }
+
+    fn open_access_request(&mut self) {
+        let servers = self.servers.requestable(&self.user);
+        if servers.is_empty() {
+            self.notice = Some(self.messages.nothing_to_request.clone());
+            return;
+        }
+        self.access_draft = Some(AccessDraft {
+            servers,
+            picker: ListState::default().with_selected(Some(0)),
+            reason: None,
+        });
+    }
+
+    /// Handles a key while requesting access. Returns false when no request is being made.
+    fn handle_access_key(&mut self, key: Key, action: Option<MenuAction>) -> bool {
+        let Some(draft) = &mut self.access_draft else {
+            return false;
+        };
+        let selected = draft.picker.selected().unwrap_or(0);
+
+        if let Some(reason) = &mut draft.reason {
+            match key {
+                Key::Char(c) => reason.push(c),
+                Key::Backspace => {
+                    reason.pop();
+                }
+                Key::Enter if !reason.trim().is_empty() => {
+                    self.state = MenuState::RequestAccess {
+                        server: draft.servers[selected].name.clone(),
+                        reason: reason.trim().to_string(),
+                    };
+                    self.access_draft = None;
+                }
+                _ => {}
+            }
+            return true;
+        }
+
+        let entries = draft.servers.len();
+        match action {
+            Some(MenuAction::Up) => draft
+                .picker
+                .select(Some((selected + entries - 1) % entries)),
+            Some(MenuAction::Down) => draft.picker.select(Some((selected + 1) % entries)),
+            Some(MenuAction::Select) => draft.reason = Some(String::new()),
+            Some(MenuAction::Quit | MenuAction::Request) => self.access_draft = None,
+            _ => {}
+        }
+        true
+    }
+
+    /// Handles a key while the preferences are open. Picking one moves it to the next
+    /// choice and saves it for the user. Returns false when they are not open.
+    fn handle_preferences_key(&mut self, action: Option<MenuAction>) -> bool {
+        let Some(picker) = &mut self.preferences else {
+            return false;
+        };
+        let rows = preference_rows(!self.themes.is_empty());
+        let selected = picker.selected().unwrap_or(0).min(rows.len() - 1);
+
+        match action {
+            Some(MenuAction::Up) => picker.select(Some((selected + rows.len() - 1) % rows.len())),
+            Some(MenuAction::Down) => picker.select(Some((selected + 1) % rows.len())),
+            Some(MenuAction::Select) => match rows[selected] {
+                Preference::Theme => {
+                    // From the configured theme through the others by name, then back.
+                    let next = match &self.theme_name {
+                        None => self.themes.keys().next(),
+                        Some(name) => self
+                            .themes
+                            .range::<str, _>((Bound::Excluded(name.as_str()), Bound::Unbounded))
+                            .next()
+                            .map(|(name, _)| name),
+                    }
+                    .cloned();
+                    self.history.set_theme(&self.user, next.clone());
+                    self.use_theme(next);
+                }
+                Preference::Keys => {
+                    // Presets that conflict with the configured bindings are skipped; the
+                    // current one does not, so this ends at the latest back at it.
+                    let mut preset = self.keymap.base();
+                    let keymap = loop {
+                        preset = preset.next();
+                        if let Ok(keymap) = self.keymap.with_preset(preset) {
+                            break keymap;
+                        }
+                    };
+                    self.history.set_keys(&self.user, preset);
+                    self.keymap = keymap;
+                }
+            },
+            Some(MenuAction::Quit | MenuAction::Preferences) => self.preferences = None,
+            _ => {}
+        }
+        true
+    }
+
+    /// Handles a key while the user's keys are listed. Returns false when they are not.
+    fn handle_keys_key(&mut self, key: Key, action: Option<MenuAction>) -> bool {
+        let Some(list) = &mut self.key_list else {
+            return false;
+        };
+        let selected = list.picker.selected().unwrap_or(0);
+
+        if let Some(pasted) = &mut list.pasted {
+            match key {
+                Key::Char(c) => pasted.push(c),
+                Key::Backspace => {
+                    pasted.pop();
+                }
+                Key::Enter if !pasted.trim().is_empty() => {
+                    self.state = MenuState::AddKey(pasted.trim().to_string());
+                    list.pasted = None;
+                }
+                _ => {}
+            }
+            return true;
+        }
+
+        // The row after the keys adds one.
+        let entries = list.keys.len() + 1;
+        match action {
+            Some(MenuAction::Up) => list.picker.select(Some((selected + entries - 1) % entries)),
+            Some(MenuAction::Down) => list.picker.select(Some((selected + 1) % entries)),
+            Some(MenuAction::Select) => match list.keys.get(selected) {
+                None => list.pasted = Some(String::new()),
+                Some(listed) if listed.source == KeySource::Config => {
+                    self.notice = Some(self.messages.config_key.clone());
+                }
+                Some(listed) => {
+                    self.dialog = Some(messages::fill(
+                        &self.messages.remove_key,
+                        &[("fingerprint", &listed.fingerprint)],
+                    ));
+                    self.pending_removal = Some(listed.key.clone());
+                }
+            },
+            Some(MenuAction::Quit | MenuAction::Keys) => self.key_list = None,
+            _ => {}
+        }
+        true
+    }
+
+    /// Handles a key while a server's name is being typed to connect to it. Returns false
+    /// when there is none.
+    fn handle_confirmation_key(&mut self, key: Key) -> bool {
+        let Some(pending) = &mut self.pending_connection else {
+            return false;
+        };
+
+        match key {
+            Key::Char(c) => pending.typed.push(c),
+            Key::Backspace => {
+                pending.typed.pop();
+            }
+            Key::Enter => {
+                let pending = self.pending_connection.take().unwrap();
+                if pending.typed.trim() == pending.entry.name {
+                    self.state = if pending.pane {
+                        MenuState::Pane(pending.entry)
+                    } else {
+                        MenuState::Selected(pending.entry)
+                    };
+                } else {
+                    self.notice = Some(messages::fill(
+                        &self.messages.wrong_server,
+                        &[("server", &pending.entry.name)],
+                    ));
+                }
+            }
+            _ => {}
+        }
+        true
+    }
 
-        Ok((
-            terminal,
-            Self {
-                parser: termwiz::escape::parser::Parser::new(),
-                items: vec!["Hello".into(), "World".into(), "memes".into()],
-                ui: UI {
-                    list_state: ListState::default().with_selected(Some(0)),
-                },
-                state: MenuState::Open,
+    /// Connects to `entry`, first asking for its name if it is a server to be careful with.
+    fn connect(&mut self, entry: ServerEntry, pane: bool) {
+        if let Some(window) = self.maintenance(&entry.name) {
+            let server: [(&str, &dyn std::fmt::Display); 1] = [("server", &entry.name)];
+            let notice = match window.ends() {
+                Some(ends) => messages::fill(
+                    &self.messages.under_maintenance_until,
+                    &[server[0], ("until", &ends)],
+                ),
+                None => messages::fill(&self.messages.under_maintenance, &server),
+            };
+            self.notice = Some(match window.reason {
+                Some(reason) => format!("{notice}: {reason}"),
+                None => notice,
+            });
+            return;
+        }
+        self.metrics.menu_selected(&entry.name);
+        if entry.confirm || entry.tags.iter().any(|tag| self.confirm_tags.contains(tag)) {
+            self.pending_connection = Some(PendingConnection {
+                entry,
+                pane,
+                typed: String::new(),
+            });
+        } else if pane {
+            self.state = MenuState::Pane(entry);
+        } else {
+            self.state = MenuState::Selected(entry);
+        }
+    }
+
+    /// Hides the servers of the selected group, or the group of the selected server.
+    fn collapse_selected(&mut self) {
+        let group = match self.selected_row() {
+            Some(MenuRow::Group { name, .. }) => name.clone(),
+            Some(MenuRow::Server(i)) => match &self.items[*i].group {
+                Some(group) => group.clone(),
+                None => return,
             },
-        ))
+            None => return,
+        };
+        self.collapsed.insert(group.clone());
+        self.rebuild_rows(Some(Selection::Group(group)));
     }
 
-    pub fn state(&self) -> &MenuState {
-        &self.state
+    fn expand_selected(&mut self) {
+        if let Some(MenuRow::Group { name, .. }) = self.selected_row() {
+            let name = name.clone();
+            self.collapsed.remove(&name);
+            self.rebuild_rows(Some(Selection::Group(name)));
+        }
     }
 
     fn render_menu(&mut self, f: &mut Frame) {
+        self.refresh_items();
+
+        // The frame starts blank, and only the cells that changed since the last are sent.
         let area = f.area();
-        f.render_widget(Clear, area);
 
-        let paragraph = Paragraph::new(format!("Counter: "))
+        if let Some(splash) = &self.splash {
+            let paragraph = Paragraph::new(splash.as_str())
+                .wrap(Wrap { trim: false })
+                .block(
+                    self.theme
+                        .block()
+                        .title(self.messages.splash_title.as_str()),
+                );
+            f.render_widget(paragraph, area);
+            return;
+        }
+
+        if self.view == View::Sessions {
+            self.render_sessions(f, area);
+        } else {
+            self.render_servers(f, area);
+        }
+
+        if let Some((server, since)) = &self.connecting {
+            let frames = if self.theme.ascii {
+                ASCII_SPINNER
+            } else {
+                SPINNER
+            };
+            let frame = since.elapsed().as_millis() / SPINNER_INTERVAL.as_millis();
+            let spinner = frames[frame as usize % frames.len()];
+            let connecting = messages::fill(&self.messages.connecting, &[("server", server)]);
+            render_popup(
+                f,
+                &self.theme,
+                &self.messages.connecting_title,
+                &format!("{spinner} {connecting}"),
+            );
+        } else if self.help {
+            render_help(
+                f,
+                &self.theme,
+                &self.keymap,
+                &self.messages,
+                self.sessions.is_some(),
+                self.access_requests,
+                self.key_management,
+            );
+        } else if let Some((from, text)) = &self.message {
+            let title = messages::fill(&self.messages.message_title, &[("from", from)]);
+            render_popup(f, &self.theme, &title, text);
+        } else if let Some(failure) = &self.failure {
+            let text = format!("{}\n\n{}", failure.detail, self.messages.retry);
+            render_popup(f, &self.theme, self.messages.failure(failure.reason), &text);
+        }
+    }
+
+    fn render_servers(&mut self, f: &mut Frame, area: Rect) {
+        let paragraph = Paragraph::new("Counter: ")
             .alignment(ratatui::layout::Alignment::Center)
-            .style(Style::default().fg(Color::Green));
+            .style(self.theme.fg(self.theme.config.accent));
+
+        let messages = &self.messages;
+        let mut hints = vec![
+            self.hint(MenuAction::Quit, &messages.hint_quit),
+            self.hint(MenuAction::Help, &messages.hint_help),
+            self.hint(MenuAction::Filter, &messages.hint_filter),
+        ];
+        if self.items.iter().any(|entry| !entry.tags.is_empty()) {
+            hints.push(self.hint(MenuAction::Tags, &messages.hint_tags));
+        }
+        if self
+            .rows
+            .iter()
+            .any(|row| matches!(row, MenuRow::Group { .. }))
+        {
+            let collapse = self.keymap.keys(MenuAction::Collapse).first();
+            let expand = self.keymap.keys(MenuAction::Expand).first();
+            if let (Some(&collapse), Some(&expand)) = (collapse, expand) {
+                hints.push(Some(format!(
+                    "{}/{} {}",
+                    key_label(collapse),
+                    key_label(expand),
+                    messages.hint_fold
+                )));
+            }
+        }
+        if self.access_requests {
+            hints.push(self.hint(MenuAction::Request, &messages.hint_request));
+        }
+        if self.key_management {
+            hints.push(self.hint(MenuAction::Keys, &messages.hint_keys));
+        }
+        if self.sessions.is_some() {
+            hints.push(self.hint(MenuAction::Sessions, &messages.hint_sessions));
+        }
+        let mut block = self.theme.block().title(hint_title(messages, hints));
+        if let Some(banner) = &self.banner {
+            block = block.title_bottom(
+                Line::from(format!(" {banner} "))
+                    .style(self.theme.notice())
+                    .centered(),
+            );
+        }
 
-        let block = Block::default()
-            .title("Press 'q' to quit")
-            .borders(Borders::ALL);
+        // Announcements for every server go above the list, taking up to a third of the
+        // screen.
+        let announcements = self.announcements(None);
+        let announcement_chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length((announcements.len() as u16).min(block.inner(area).height / 3)),
+                Constraint::Fill(1),
+            ])
+            .split(block.inner(area));
 
+        // The list takes the middle half of the screen, growing to the full height when
+        // there are more rows than fit.
+        let inner = announcement_chunks[1];
+        let list_height = (self.rows.len() as u16 + 2)
+            .max(inner.height / 2)
+            .min(inner.height);
         let vertical_chunks = Layout::default()
             .direction(Direction::Vertical)
             .constraints(
                 [
-                    Constraint::Percentage(25),
                     Constraint::Fill(1),
-                    Constraint::Percentage(25),
+                    Constraint::Length(list_height),
+                    Constraint::Fill(1),
                 ]
                 .as_ref(),
             )
-            .split(block.inner(area));
+            .split(inner);
 
         let middle_vertical_chunk = vertical_chunks[1];
 
+        // The list takes the middle three fifths of the screen, widening to fit long names.
+        let symbol_width = self.theme.config.highlight_symbol.width();
+        let widest = self
+            .rows
+            .iter()
+            .map(|row| self.row_line(row, usize::MAX).width())
+            .max()
+            .unwrap_or_default();
+        let list_width = (middle_vertical_chunk.width * 3 / 5)
+            .max((widest + symbol_width + 2) as u16)
+            .min(middle_vertical_chunk.width);
         let horizontal_chunks = Layout::default()
             .direction(Direction::Horizontal)
             .constraints([
-                Constraint::Percentage(20),
                 Constraint::Fill(1),
-                Constraint::Percentage(20),
+                Constraint::Length(list_width),
+                Constraint::Fill(1),
             ])
             .split(middle_vertical_chunk);
 
         let center_block = horizontal_chunks[1];
 
+        // Less the borders and the highlight symbol.
+        let row_width = (center_block.width as usize).saturating_sub(symbol_width + 2);
         let items: Vec<ListItem> = self
-            .items
+            .rows
             .iter()
-            .map(|i| ListItem::new(Line::from(i.clone())))
+            .map(|row| ListItem::new(self.row_line(row, row_width)))
             .collect();
 
+        let mut list_block = self.theme.block().title(self.theme.config.title.clone());
+        if let Some(tag) = &self.tag {
+            list_block = list_block.title_bottom(format!("#{tag} "));
+        }
+        if let Some(filter) = &self.filter {
+            list_block = list_block.title_bottom(format!("/{filter}_"));
+        }
+        if self.usage.sort != SortMode::Listed {
+            list_block = list_block.title_bottom(
+                Line::from(format!(" {} ", self.messages.sort(self.usage.sort))).right_aligned(),
+            );
+        }
+
         let list = List::new(items)
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("Select Server"),
-            )
-            .highlight_style(
-                Style::default()
-                    .bg(Color::LightGreen)
-                    .fg(Color::Black)
-                    .add_modifier(Modifier::BOLD),
-            )
-            .highlight_symbol(">> ");
+            .block(list_block)
+            .highlight_style(self.theme.highlight())
+            .highlight_symbol(&self.theme.config.highlight_symbol);
 
         f.render_widget(paragraph.block(block), area);
+        let bar = announcement_chunks[0];
+        let lines: Vec<Line> = announcements
+            .iter()
+            .map(|announcement| {
+                let width = (bar.width as usize).saturating_sub(2);
+                Line::from(format!(
+                    " {} ",
+                    truncate(announcement, width, self.theme.ellipsis())
+                ))
+            })
+            .collect();
+        f.render_widget(
+            Paragraph::new(lines)
+                .alignment(ratatui::layout::Alignment::Center)
+                .style(self.theme.notice()),
+            bar,
+        );
         f.render_stateful_widget(list, center_block, &mut self.ui.list_state);
+
+        let page = center_block.height.saturating_sub(2) as usize;
+        self.ui.list_page = page.max(1);
+        self.ui.list_area = center_block;
+        if self.rows.len() > page {
+            let mut scrollbar =
+                ScrollbarState::new(self.rows.len() - page).position(self.ui.list_state.offset());
+            f.render_stateful_widget(
+                self.theme.scrollbar(),
+                center_block.inner(Margin {
+                    vertical: 1,
+                    horizontal: 0,
+                }),
+                &mut scrollbar,
+            );
+        }
+
+        if let Some(picker) = &mut self.ui.tag_picker {
+            let mut entries = vec![self.messages.all_servers.as_str()];
+            entries.extend(server_tags(&self.items));
+            render_picker(f, &self.theme, &self.messages.tag_title, &entries, picker);
+        }
+
+        if let Some(draft) = &mut self.access_draft {
+            let selected = draft.picker.selected().unwrap_or(0);
+            match &draft.reason {
+                Some(reason) => {
+                    let server = &draft.servers[selected].name;
+                    let question =
+                        messages::fill(&self.messages.request_reason, &[("server", server)]);
+                    let prompt = format!("{question} {reason}_");
+                    render_notice(f, &self.theme, &prompt);
+                }
+                None => {
+                    let entries: Vec<&str> = draft
+                        .servers
+                        .iter()
+                        .map(|entry| entry.name.as_str())
+                        .collect();
+                    render_picker(
+                        f,
+                        &self.theme,
+                        &self.messages.request_title,
+                        &entries,
+                        &mut draft.picker,
+                    );
+                }
+            }
+        }
+
+        if let Some(picker) = &mut self.preferences {
+            let theme = self
+                .theme_name
+                .as_deref()
+                .unwrap_or(&self.messages.default_theme);
+            let entries: Vec<String> = preference_rows(!self.themes.is_empty())
+                .iter()
+                .map(|row| match row {
+                    Preference::Theme => {
+                        messages::fill(&self.messages.preference_theme, &[("theme", &theme)])
+                    }
+                    Preference::Keys => messages::fill(
+                        &self.messages.preference_keys,
+                        &[("preset", &self.keymap.base().name())],
+                    ),
+                })
+                .collect();
+            let entries: Vec<&str> = entries.iter().map(String::as_str).collect();
+            render_picker(
+                f,
+                &self.theme,
+                &self.messages.preferences_title,
+                &entries,
+                picker,
+            );
+        }
+
+        if let Some(list) = &mut self.key_list {
+            match &list.pasted {
+                Some(pasted) => {
+                    // A key is too long to show whole, so only the end pasted so far is.
+                    const SHOWN: usize = 32;
+                    let skip = pasted.chars().count().saturating_sub(SHOWN);
+                    let shown: String = pasted.chars().skip(skip).collect();
+                    let ellipsis = if skip > 0 { self.theme.ellipsis() } else { "" };
+                    let prompt = format!("{} {ellipsis}{shown}_", self.messages.paste_key);
+                    render_notice(f, &self.theme, &prompt);
+                }
+                None => {
+                    let mut entries: Vec<String> = list
+                        .keys
+                        .iter()
+                        .map(|listed| {
+                            let source = self.messages.key_source(listed.source);
+                            match &listed.comment {
+                                Some(comment) => {
+                                    format!("{} {comment} ({source})", listed.fingerprint)
+                                }
+                                None => format!("{} ({source})", listed.fingerprint),
+                            }
+                        })
+                        .collect();
+                    entries.push(self.messages.add_key.clone());
+                    let entries: Vec<&str> = entries.iter().map(String::as_str).collect();
+                    render_picker(
+                        f,
+                        &self.theme,
+                        &self.messages.keys_title,
+                        &entries,
+                        &mut list.picker,
+                    );
+                }
+            }
+        }
+
+        if let Some(pending) = &self.pending_connection {
+            let entry = &pending.entry;
+            let label = entry
+                .tags
+                .iter()
+                .find(|tag| self.confirm_tags.contains(tag))
+                .map(|tag| format!("{} ", tag.to_uppercase()))
+                .unwrap_or_default();
+            let server = format!("{label}{}", entry.name);
+            let question = messages::fill(&self.messages.confirm_server, &[("server", &server)]);
+            render_notice(f, &self.theme, &format!("{question} {}_", pending.typed));
+        } else if let Some(dialog) = &self.dialog {
+            render_notice(
+                f,
+                &self.theme,
+                &format!("{dialog} {}", self.messages.yes_no),
+            );
+        } else if let Some(notice) = &self.notice {
+            render_notice(f, &self.theme, notice);
+        }
+    }
+
+    /// A row of the server list, with names shortened to fit `width` columns.
+    fn row_line(&self, row: &MenuRow, width: usize) -> Line<'static> {
+        match row {
+            MenuRow::Group {
+                name,
+                servers,
+                collapsed,
+            } => {
+                let marker = if *collapsed {
+                    self.theme.symbol("▸", "+")
+                } else {
+                    self.theme.symbol("▾", "-")
+                };
+                let count = format!(" ({servers})");
+                let name = truncate(
+                    name,
+                    width.saturating_sub(2 + count.width()),
+                    self.theme.ellipsis(),
+                );
+                Line::from(format!("{marker} {name}{count}"))
+                    .style(Style::default().add_modifier(Modifier::BOLD))
+            }
+            MenuRow::Server(i) => {
+                let entry = &self.items[*i];
+                let favorite = self.usage.is_favorite(&entry.name);
+                let indent = entry.group.is_some();
+                let reserved = if favorite { 2 } else { 0 } + if indent { 2 } else { 0 };
+                let width = width.saturating_sub(reserved);
+                // Labels from plugins and announcements follow the name, taking up to half
+                // the row.
+                let announcement = self
+                    .labels
+                    .lock()
+                    .unwrap()
+                    .get(&entry.name)
+                    .cloned()
+                    .into_iter()
+                    .chain(self.announcements(Some(&entry.name)))
+                    .collect::<Vec<_>>()
+                    .join("; ");
+                let announcement = truncate(
+                    &announcement,
+                    (width / 2).saturating_sub(1),
+                    self.theme.ellipsis(),
+                )
+                .into_owned();
+                let announcement_width = match announcement.width() {
+                    0 => 0,
+                    used => used + 1,
+                };
+                let mut line = server_line(
+                    &self.theme,
+                    &entry.name,
+                    self.health.status(&entry.name),
+                    &self.messages.down,
+                    self.maintenance(&entry.name)
+                        .map(|_| self.messages.maintenance.as_str()),
+                    width.saturating_sub(announcement_width),
+                );
+                if favorite {
+                    line.spans.push(Span::raw(self.theme.symbol(" ★", " *")));
+                }
+                if !announcement.is_empty() {
+                    line.spans.push(Span::styled(
+                        format!(" {announcement}"),
+                        self.theme.fg(self.theme.config.notice),
+                    ));
+                }
+                if indent {
+                    line.spans.insert(0, Span::raw("  "));
+                }
+                line
+            }
+        }
+    }
+
+    fn render_sessions(&mut self, f: &mut Frame, area: Rect) {
+        if let Some(sessions) = &self.sessions {
+            self.session_rows = sessions.cluster_in(self.realm.as_deref());
+        }
+        let selected = self.ui.session_state.selected().unwrap_or(0);
+        self.ui.session_state.select(Some(
+            selected.min(self.session_rows.len().saturating_sub(1)),
+        ));
+
+        let messages = &self.messages;
+        let header = Row::new([
+            messages.column_id.as_str(),
+            &messages.column_user,
+            &messages.column_from,
+            &messages.column_target,
+            &messages.column_duration,
+            &messages.column_up,
+            &messages.column_down,
+            &messages.column_rate,
+            &messages.column_forwards,
+            &messages.column_today,
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = self.session_rows.iter().map(|info| {
+            Row::new([
+                Cell::from(info.name()),
+                Cell::from(info.user.clone().unwrap_or_else(|| "-".to_string())),
+                Cell::from(match (info.peer, &info.location) {
+                    (Some(peer), Some(location)) => format!("{} {location}", peer.ip()),
+                    (Some(peer), None) => peer.ip().to_string(),
+                    (None, _) => "-".to_string(),
+                }),
+                Cell::from(
+                    info.target
+                        .clone()
+                        .unwrap_or_else(|| messages.at_menu.clone()),
+                ),
+                Cell::from(format_duration(info.duration)),
+                Cell::from(format_bytes(info.bytes_up)),
+                Cell::from(format_bytes(info.bytes_down)),
+                Cell::from(format!("{}/s", format_bytes(info.throughput))),
+                Cell::from(info.quota_usage.forwards.to_string()),
+                Cell::from(format!("{}m", info.quota_usage.minutes_today)),
+            ])
+        });
+        // Sessions on other nodes of a cluster are named after their node too.
+        let id_width = self
+            .session_rows
+            .iter()
+            .map(|info| info.name().width())
+            .fold(6, usize::max);
+        let widths = [
+            Constraint::Length(id_width as u16),
+            Constraint::Fill(1),
+            Constraint::Length(28),
+            Constraint::Fill(1),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(12),
+            Constraint::Length(6),
+            Constraint::Length(7),
+        ];
+
+        let footer = match &self.compose {
+            Some(text) => {
+                let text = format!("{text}_");
+                let compose = messages::fill(&messages.message_everyone, &[("text", &text)]);
+                format!(" {compose} ")
+            }
+            None => {
+                let count = self.session_rows.len();
+                let count = messages::fill(&messages.session_count, &[("count", &count)]);
+                format!(" {count} ")
+            }
+        };
+        let table = Table::new(rows, widths)
+            .header(header)
+            .block(
+                self.theme
+                    .block()
+                    .title(hint_title(
+                        messages,
+                        vec![
+                            self.hint(MenuAction::Quit, &messages.hint_quit),
+                            self.hint(MenuAction::Shadow, &messages.hint_watch),
+                            self.hint(MenuAction::Terminate, &messages.hint_terminate),
+                            self.hint(MenuAction::Message, &messages.hint_message),
+                            self.hint(MenuAction::Sessions, &messages.hint_servers),
+                        ],
+                    ))
+                    .title_bottom(footer),
+            )
+            .row_highlight_style(self.theme.highlight())
+            .highlight_symbol(self.theme.config.highlight_symbol.as_str());
+        f.render_stateful_widget(table, area, &mut self.ui.session_state);
+        // Less the borders and the header.
+        self.ui.session_page = (area.height.saturating_sub(3) as usize).max(1);
+
+        if let Some(dialog) = &self.dialog {
+            render_notice(
+                f,
+                &self.theme,
+                &format!("{dialog} {}", self.messages.yes_no),
+            );
+        } else if let Some(notice) = &self.notice {
+            render_notice(f, &self.theme, notice);
+        }
+    }
+
+    fn select_session_down(&mut self) {
+        let i = self.ui.session_state.selected().map_or(0, |i| i + 1);
+        self.ui
+            .session_state
+            .select(Some(if i >= self.session_rows.len() { 0 } else { i }));
+    }
+
+    fn select_session_up(&mut self) {
+        let i = match self.ui.session_state.selected() {
+            Some(0) | None => self.session_rows.len().saturating_sub(1),
+            Some(i) => i - 1,
+        };
+        self.ui.session_state.select(Some(i));
+    }
+
+    /// Describes the first key bound to `action`, or None when it is unbound.
+    fn hint(&self, action: MenuAction, what: &str) -> Option<String> {
+        let key = self.keymap.keys(action).first()?;
+        Some(format!("{} {what}", key_label(*key)))
+    }
+
+    fn confirm_termination(&mut self) {
+        let Some(info) = self
+            .ui
+            .session_state
+            .selected()
+            .and_then(|i| self.session_rows.get(i))
+        else {
+            return;
+        };
+        let user = info
+            .user
+            .as_deref()
+            .unwrap_or(&self.messages.unauthenticated);
+        self.dialog = Some(messages::fill(
+            &self.messages.terminate_session,
+            &[("id", &info.name()), ("user", &user)],
+        ));
+        self.pending_termination = Some((info.node.clone(), info.id));
+    }
+
+    /// Handles a key in the sessions view. Returns false for keys shared with the servers view.
+    fn handle_sessions_key(&mut self, key: Key, action: Option<MenuAction>) -> bool {
+        if let Some(text) = &mut self.compose {
+            match key {
+                Key::Char(c) => text.push(c),
+                Key::Backspace => {
+                    text.pop();
+                }
+                Key::Enter => {
+                    let text = self.compose.take().unwrap_or_default();
+                    if !text.trim().is_empty() {
+                        self.state = MenuState::Broadcast(text);
+                    }
+                }
+                _ => {}
+            }
+            return true;
+        }
+
+        match action {
+            Some(MenuAction::Up) => self.select_session_up(),
+            Some(MenuAction::Down) => self.select_session_down(),
+            Some(
+                action @ (MenuAction::PageUp
+                | MenuAction::PageDown
+                | MenuAction::First
+                | MenuAction::Last),
+            ) => {
+                let current = self.ui.session_state.selected().unwrap_or(0);
+                let i = paged(
+                    action,
+                    current,
+                    self.session_rows.len(),
+                    self.ui.session_page,
+                );
+                self.ui.session_state.select(Some(i));
+            }
+            Some(MenuAction::Terminate) => self.confirm_termination(),
+            Some(MenuAction::Message) => self.compose = Some(String::new()),
+            Some(MenuAction::Shadow) => {
+                if let Some(info) = self
+                    .ui
+                    .session_state
+                    .selected()
+                    .and_then(|i| self.session_rows.get(i))
+                {
+                    if info.node.is_some() {
+                        let notice =
+                            messages::fill(&self.messages.watch_remote, &[("id", &info.name())]);
+                        self.set_notice(notice);
+                    } else {
+                        self.state = MenuState::Shadow(info.id);
+                    }
+                }
+            }
+            Some(MenuAction::Quit | MenuAction::Help) => return false,
+            _ => {}
+        }
+        true
     }
 
     fn select_item_down(&mut self) {
         let ui = &mut self.ui;
         let i = if let Some(current_selected) = ui.list_state.selected() {
-            if current_selected >= self.items.len() - 1 {
+            if current_selected + 1 >= self.rows.len() {
                 0
             } else {
                 current_selected + 1
@@ -161,7 +2214,7 @@ impl PukekoMenu {
         let ui = &mut self.ui;
         let i = if let Some(current_selected) = ui.list_state.selected() {
             if current_selected == 0 {
-                self.items.len()
+                self.rows.len().saturating_sub(1)
             } else {
                 current_selected - 1
             }
@@ -171,59 +2224,635 @@ impl PukekoMenu {
         ui.list_state.select(Some(i));
     }
 
-    pub async fn handle_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
-        use termwiz::escape::{
-            Action,
-            csi::{CSI, Cursor},
+    fn select_page(&mut self, action: MenuAction) {
+        let current = self.ui.list_state.selected().unwrap_or(0);
+        let i = paged(action, current, self.rows.len(), self.ui.list_page);
+        self.ui.list_state.select(Some(i));
+    }
+
+    fn select_current_item(&mut self) {
+        if let Some(MenuRow::Group { collapsed, .. }) = self.selected_row() {
+            if *collapsed {
+                self.expand_selected();
+            } else {
+                self.collapse_selected();
+            }
+            return;
+        }
+        if let Some(item) = self.selected_item().cloned() {
+            self.connect(item, false);
+        }
+    }
+
+    /// Handles a key while the filter is being typed. Returns false for keys that
+    /// should fall through to normal menu navigation.
+    fn handle_filter_key(&mut self, key: Key) -> bool {
+        let Some(filter) = &mut self.filter else {
+            return false;
         };
 
-        let mut data = data;
-        while let Some((action, bytes_consumed)) = self.parser.parse_first(data) {
-            data = &data[bytes_consumed..];
+        match key {
+            Key::Char(c) => filter.push(c),
+            Key::Backspace => {
+                filter.pop();
+            }
+            _ => return false,
+        }
+        self.apply_filter();
+        self.ui.list_state.select(Some(0));
+        true
+    }
+
+    fn clear_filter(&mut self) {
+        let filtered = self.filter.take().is_some();
+        if self.tag.take().is_some() || filtered {
+            self.apply_filter();
+        }
+    }
 
-            match action {
-                Action::Print('q') => {
-                    self.state = MenuState::Closing;
+    /// Clicking a server selects it and clicking it again soon after connects, while the
+    /// wheel moves the selection. The mouse is ignored while a popup is open.
+    fn handle_mouse(&mut self, report: &MouseReport) {
+        let &MouseReport::SGR1006 {
+            x, y, ref button, ..
+        } = report
+        else {
+            return;
+        };
+        if self.dialog.is_some()
+            || self.ui.tag_picker.is_some()
+            || self.compose.is_some()
+            || self.access_draft.is_some()
+            || self.key_list.is_some()
+            || self.preferences.is_some()
+            || self.pending_connection.is_some()
+        {
+            return;
+        }
+
+        let wheel = match button {
+            MouseButton::Button4Press => Some(MenuAction::PageUp),
+            MouseButton::Button5Press => Some(MenuAction::PageDown),
+            _ => None,
+        };
+        if let Some(action) = wheel {
+            // A row per notch, without wrapping.
+            match self.view {
+                View::Servers => {
+                    let current = self.ui.list_state.selected().unwrap_or(0);
+                    let i = paged(action, current, self.rows.len(), 1);
+                    self.ui.list_state.select(Some(i));
                 }
-                Action::CSI(CSI::Cursor(Cursor::Up(_))) | Action::Print('k') => {
-                    self.select_item_up();
+                View::Sessions => {
+                    let current = self.ui.session_state.selected().unwrap_or(0);
+                    let i = paged(action, current, self.session_rows.len(), 1);
+                    self.ui.session_state.select(Some(i));
                 }
-                Action::CSI(CSI::Cursor(Cursor::Down(_))) | Action::Print('j') => {
-                    self.select_item_down();
+            }
+            return;
+        }
+
+        if !matches!(button, MouseButton::Button1Press) || self.view != View::Servers {
+            return;
+        }
+
+        // Reports count from 1, and the list's first row is under its border.
+        let area = self.ui.list_area.inner(Margin {
+            vertical: 1,
+            horizontal: 1,
+        });
+        let (column, line) = (x.saturating_sub(1), y.saturating_sub(1));
+        if !area.contains((column, line).into()) {
+            return;
+        }
+        let row = self.ui.list_state.offset() + usize::from(line - area.y);
+        if row >= self.rows.len() {
+            return;
+        }
+
+        let now = Instant::now();
+        let double = self.ui.last_click.is_some_and(|(last, at)| {
+            last == row && now.duration_since(at) < DOUBLE_CLICK_INTERVAL
+        });
+        self.ui.list_state.select(Some(row));
+        if double {
+            self.ui.last_click = None;
+            self.select_current_item();
+        } else {
+            self.ui.last_click = Some((row, now));
+        }
+    }
+
+    pub async fn handle_data(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        use termwiz::escape::{Action, CSI, Esc, EscCode};
+
+        self.last_input = Instant::now();
+        self.banner = None;
+
+        if self.plain.is_some() {
+            self.handle_plain_data(data);
+            return Ok(());
+        }
+
+        if let Some(failure) = self.failure.take() {
+            if matches!(data, b"r" | b"R" | b"\r" | b"\n") {
+                self.state = MenuState::Selected(failure.entry);
+            }
+            return Ok(());
+        }
+
+        if self.splash.take().is_some()
+            || self.message.take().is_some()
+            || std::mem::take(&mut self.help)
+        {
+            return Ok(());
+        }
+
+        // A lone escape is the Esc key; the parser would hold on to it waiting for a sequence.
+        if data == b"\x1b" {
+            self.notice = None;
+            self.dialog = None;
+            self.pending_termination = None;
+            self.pending_resume = None;
+            self.pending_removal = None;
+            self.compose = None;
+            self.pending_connection = None;
+            if self.ui.tag_picker.take().is_none()
+                && self.access_draft.take().is_none()
+                && self.key_list.take().is_none()
+                && self.preferences.take().is_none()
+            {
+                if self.tag.is_some() {
+                    self.history.set_tag(&self.user, None);
                 }
-                _ => {}
+                self.clear_filter();
             }
+            return Ok(());
+        }
+
+        // Most terminals send DEL for backspace, which the parser drops.
+        let data: Vec<u8> = data
+            .iter()
+            .map(|&b| if b == 0x7f { 0x08 } else { b })
+            .collect();
 
+        let mut data = &data[..];
+        let mut after_ss3 = false;
+        while let Some((action, bytes_consumed)) = self.parser.parse_first(data) {
+            data = &data[bytes_consumed..];
+            self.notice = None;
             trace!("Ansi code {:?}", action);
+
+            if let Action::CSI(CSI::Mouse(report)) = &action {
+                self.handle_mouse(report);
+                continue;
+            }
+
+            let keys: Vec<Key> = match &action {
+                // Pasted text can arrive as a string rather than a character at a time.
+                Action::PrintString(text) => text.chars().map(Key::Char).collect(),
+                action => Key::parse(action, after_ss3).into_iter().collect(),
+            };
+            after_ss3 = matches!(action, Action::Esc(Esc::Code(EscCode::SingleShiftG3)));
+            for key in keys {
+                self.handle_key(key);
+            }
         }
 
         Ok(())
     }
+
+    fn handle_key(&mut self, key: Key) {
+        if self.dialog.is_some() {
+            let accepted = matches!(key, Key::Char('y' | 'Y'));
+            let removal = self.pending_removal.take();
+            match (self.pending_termination.take(), self.pending_resume.take()) {
+                (Some((node, id)), _) if accepted => self.state = MenuState::Terminate { node, id },
+                (_, Some(id)) if accepted => self.state = MenuState::Resume(id),
+                (None, None) if accepted => {
+                    self.state = match removal {
+                        Some(key) => MenuState::RemoveKey(key),
+                        None => MenuState::Confirmed,
+                    };
+                }
+                _ => {}
+            }
+            self.dialog = None;
+            return;
+        }
+
+        if self.handle_confirmation_key(key) {
+            return;
+        }
+
+        let action = self.keymap.action(key, self.view == View::Sessions);
+        if self.handle_tag_key(action)
+            || self.handle_access_key(key, action)
+            || self.handle_keys_key(key, action)
+            || self.handle_preferences_key(action)
+            || self.handle_filter_key(key)
+        {
+            return;
+        }
+
+        if action == Some(MenuAction::Sessions) && self.sessions.is_some() {
+            self.clear_filter();
+            self.view = match self.view {
+                View::Servers => View::Sessions,
+                View::Sessions => View::Servers,
+            };
+            return;
+        }
+
+        if self.view == View::Sessions && self.handle_sessions_key(key, action) {
+            return;
+        }
+
+        match action {
+            Some(MenuAction::Filter) => self.filter = Some(String::new()),
+            Some(MenuAction::Tags) => self.open_tag_picker(),
+            Some(MenuAction::Request) if self.access_requests => self.open_access_request(),
+            Some(MenuAction::Keys) if self.key_management => self.state = MenuState::ListKeys,
+            Some(MenuAction::Sort) => {
+                self.history.set_sort(&self.user, self.usage.sort.next());
+                self.apply_filter();
+            }
+            Some(MenuAction::Favorite) => {
+                if let Some(entry) = self.selected_item() {
+                    self.history.toggle_favorite(&self.user, &entry.name);
+                    self.apply_filter();
+                }
+            }
+            Some(MenuAction::Preferences) => {
+                self.preferences = Some(ListState::default().with_selected(Some(0)));
+            }
+            Some(MenuAction::Help) => self.help = true,
+            Some(MenuAction::Quit) => self.state = MenuState::Closing,
+            Some(MenuAction::Up) => self.select_item_up(),
+            Some(MenuAction::Down) => self.select_item_down(),
+            Some(
+                action @ (MenuAction::PageUp
+                | MenuAction::PageDown
+                | MenuAction::First
+                | MenuAction::Last),
+            ) => self.select_page(action),
+            Some(MenuAction::Collapse) => self.collapse_selected(),
+            Some(MenuAction::Expand) => self.expand_selected(),
+            Some(MenuAction::Select) => self.select_current_item(),
+            Some(MenuAction::Pane) => {
+                if let Some(item) = self.selected_item().cloned() {
+                    self.connect(item, true);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The row `action` moves to from `current`, a page at a time or to either end, without
+/// wrapping.
+fn paged(action: MenuAction, current: usize, rows: usize, page: usize) -> usize {
+    let last = rows.saturating_sub(1);
+    match action {
+        MenuAction::PageUp => current.saturating_sub(page),
+        MenuAction::PageDown => (current + page).min(last),
+        MenuAction::First => 0,
+        MenuAction::Last => last,
+        _ => current,
+    }
+}
+
+/// Quotes printable keys, so hints read as "'q' to quit" but "Tab for sessions".
+fn key_label(key: Key) -> String {
+    match key {
+        Key::Char(' ') => key.to_string(),
+        Key::Char(c) => format!("'{c}'"),
+        key => key.to_string(),
+    }
+}
+
+fn hint_title(messages: &Messages, hints: Vec<Option<String>>) -> String {
+    let hints: Vec<String> = hints.into_iter().flatten().collect();
+    if hints.is_empty() {
+        return String::new();
+    }
+    messages::fill(&messages.hints, &[("keys", &hints.join(", "))])
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+/// A server's name, preceded by its status indicator and followed by its latency once it has
+/// been health checked. The name is shortened for the line to fit `width` columns.
+fn server_line(
+    theme: &Theme,
+    name: &str,
+    health: Option<Health>,
+    down: &str,
+    maintenance: Option<&str>,
+    width: usize,
+) -> Line<'static> {
+    let muted = theme.fg(theme.config.muted);
+    let name_span = |suffix: &str| {
+        let width = width.saturating_sub(suffix.width() + 2);
+        Span::raw(truncate(name, width, theme.ellipsis()).into_owned())
+    };
+    if let Some(maintenance) = maintenance {
+        let label = format!(" {maintenance}");
+        return Line::from(vec![
+            Span::styled(theme.indicator(Status::Maintenance), muted),
+            name_span(&label),
+            Span::styled(label, muted),
+        ]);
+    }
+    match health {
+        Some(Health::Up { latency }) => {
+            let latency = format!(" {}ms", latency.as_millis());
+            Line::from(vec![
+                Span::styled(theme.indicator(Status::Up), theme.fg(theme.config.healthy)),
+                name_span(&latency),
+                Span::styled(latency, muted),
+            ])
+        }
+        Some(Health::Down) => Line::from(vec![
+            Span::styled(
+                theme.indicator(Status::Down),
+                theme.fg(theme.config.unhealthy),
+            ),
+            name_span(&format!(" {down}")),
+            Span::styled(format!(" {down}"), muted),
+        ]),
+        None => Line::from(truncate(name, width, theme.ellipsis()).into_owned()),
+    }
+}
+
+/// Shortens `text` to `width` columns, ending it with `ellipsis` if it was cut. Wide
+/// characters take two columns, and grapheme clusters such as emoji sequences are kept whole.
+fn truncate<'a>(text: &'a str, width: usize, ellipsis: &str) -> Cow<'a, str> {
+    if text.width() <= width {
+        return Cow::Borrowed(text);
+    }
+    let budget = width.saturating_sub(ellipsis.width());
+    let mut used = 0;
+    let mut shortened = String::new();
+    for grapheme in text.graphemes(true) {
+        used += grapheme.width();
+        if used > budget {
+            break;
+        }
+        shortened.push_str(grapheme);
+    }
+    if ellipsis.width() <= width {
+        shortened.push_str(ellipsis);
+    }
+    Cow::Owned(shortened)
+}
+
+/// Every tag of `items`, sorted.
+fn server_tags(items: &[ServerEntry]) -> Vec<&str> {
+    let mut tags: Vec<&str> = items
+        .iter()
+        .flat_map(|entry| &entry.tags)
+        .map(String::as_str)
+        .collect();
+    tags.sort_unstable();
+    tags.dedup();
+    tags
+}
+
+fn render_picker(
+    f: &mut Frame,
+    theme: &Theme,
+    title: &str,
+    entries: &[&str],
+    state: &mut ListState,
+) {
+    const SYMBOL_WIDTH: u16 = 3;
+
+    let area = f.area();
+    let longest = entries
+        .iter()
+        .map(|entry| entry.chars().count())
+        .max()
+        .unwrap_or_default() as u16;
+    let width = (longest.max(title.chars().count() as u16) + SYMBOL_WIDTH + 4)
+        .max(16)
+        .min(area.width);
+    let height = (entries.len() as u16 + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+
+    let list = List::new(entries.iter().copied())
+        .block(theme.block().title(title.to_string()))
+        .highlight_style(theme.highlight())
+        .highlight_symbol(&theme.config.highlight_symbol);
+
+    f.render_widget(Clear, popup);
+    f.render_stateful_widget(list, popup, state);
+}
+
+/// Lists every bound key by what it does. The sessions keys are only shown to admins, and
+/// the request and keys keys only when access can be requested and keys managed.
+fn render_help(
+    f: &mut Frame,
+    theme: &Theme,
+    keymap: &KeyMap,
+    messages: &Messages,
+    admin: bool,
+    requests: bool,
+    key_management: bool,
+) {
+    let mut contexts = vec![
+        KeyContext::Navigation,
+        KeyContext::Servers,
+        KeyContext::Menu,
+    ];
+    if admin {
+        contexts.push(KeyContext::Sessions);
+    }
+
+    let mut lines = Vec::new();
+    for context in contexts {
+        let bold = Style::default().add_modifier(Modifier::BOLD);
+        lines.push(Line::from(messages.context(context)).style(bold));
+        for (action, keys) in keymap.bindings() {
+            if action.context() != context
+                || keys.is_empty()
+                || (action == MenuAction::Request && !requests)
+                || (action == MenuAction::Keys && !key_management)
+            {
+                continue;
+            }
+            let keys: Vec<String> = keys.iter().map(Key::to_string).collect();
+            lines.push(Line::from(vec![
+                Span::styled(
+                    format!("  {:<12} ", keys.join(" ")),
+                    theme.fg(theme.config.accent),
+                ),
+                Span::raw(messages.action(action)),
+            ]));
+        }
+        if context == KeyContext::Menu {
+            lines.push(Line::from(vec![
+                Span::styled(format!("  {:<12} ", "Esc"), theme.fg(theme.config.accent)),
+                Span::raw(messages.help_escape.as_str()),
+            ]));
+        }
+    }
+
+    let area = f.area();
+    let width = lines
+        .iter()
+        .map(|line| line.width() as u16 + 4)
+        .max()
+        .unwrap_or_default()
+        .max(34)
+        .min(area.width);
+    let height = (lines.len() as u16 + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+
+    let paragraph = Paragraph::new(lines).block(theme.block().title(messages.help_title.as_str()));
+    f.render_widget(Clear, popup);
+    f.render_widget(paragraph, popup);
+}
+
+fn render_notice(f: &mut Frame, theme: &Theme, notice: &str) {
+    let area = f.area();
+    let width = (notice.len() as u16 + 4).min(area.width);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + area.height.saturating_sub(3) / 2,
+        width,
+        height: 3.min(area.height),
+    };
+
+    let paragraph = Paragraph::new(notice)
+        .alignment(ratatui::layout::Alignment::Center)
+        .style(theme.notice())
+        .block(theme.block());
+
+    f.render_widget(Clear, popup);
+    f.render_widget(paragraph, popup);
+}
+
+/// Text in a box sized to fit it, wrapping long lines.
+fn render_popup(f: &mut Frame, theme: &Theme, title: &str, text: &str) {
+    const MAX_WIDTH: u16 = 64;
+
+    let area = f.area();
+    let longest = text
+        .lines()
+        .chain(std::iter::once(title))
+        .map(|line| line.width())
+        .max()
+        .unwrap_or_default() as u16;
+    let width = (longest + 4).min(MAX_WIDTH).min(area.width);
+    let inner_width = width.saturating_sub(2).max(1) as usize;
+    let lines: usize = text
+        .lines()
+        .map(|line| line.width().div_ceil(inner_width).max(1))
+        .sum();
+    let height = (lines as u16 + 2).min(area.height);
+    let popup = Rect {
+        x: area.x + (area.width - width) / 2,
+        y: area.y + (area.height - height) / 2,
+        width,
+        height,
+    };
+
+    let paragraph = Paragraph::new(text)
+        .wrap(Wrap { trim: false })
+        .style(theme.notice())
+        .block(theme.block().title(title));
+
+    f.render_widget(Clear, popup);
+    f.render_widget(paragraph, popup);
+}
+
+/// Output flushed by the terminal and not yet sent to the client. Frames flushed while
+/// earlier ones wait are appended to them, so a client that falls behind is sent them at
+/// once.
+#[derive(Default)]
+struct Outbox {
+    pending: std::sync::Mutex<Vec<u8>>,
+    ready: Notify,
+    /// Set when waiting output was dropped, until the screen is next redrawn in full.
+    overflowed: AtomicBool,
+    /// Set once the channel is gone, or the terminal.
+    closed: AtomicBool,
 }
 
 struct TerminalHandle {
-    sender: UnboundedSender<Vec<u8>>,
+    outbox: Arc<Outbox>,
     sink: Vec<u8>,
+    /// Whether the terminal only has the 16 ANSI colors.
+    basic_colors: Arc<AtomicBool>,
 }
 
 impl TerminalHandle {
     async fn start(handle: Handle, channel_id: ChannelId) -> Self {
-        let (sender, mut receiver) = unbounded_channel::<Vec<u8>>();
+        let outbox = Arc::new(Outbox::default());
+        let sending = outbox.clone();
         tokio::spawn(async move {
-            while let Some(data) = receiver.recv().await {
-                let result = handle.data(channel_id, data.into()).await;
-                if result.is_err() {
+            loop {
+                sending.ready.notified().await;
+                // Checked before taking the output, so none flushed before the terminal
+                // was dropped is left unsent.
+                let closed = sending.closed.load(Ordering::Acquire);
+                let data = std::mem::take(&mut *sending.pending.lock().unwrap());
+                if !data.is_empty() && handle.data(channel_id, data.into()).await.is_err() {
+                    sending.closed.store(true, Ordering::Release);
+                    break;
+                }
+                if closed {
                     break;
                 }
             }
         });
         Self {
-            sender,
+            outbox,
             sink: Vec::new(),
+            basic_colors: Arc::new(AtomicBool::new(false)),
         }
     }
 }
 
+impl Drop for TerminalHandle {
+    fn drop(&mut self) {
+        self.outbox.closed.store(true, Ordering::Release);
+        self.outbox.ready.notify_one();
+    }
+}
+
 // The crossterm backend writes to the terminal handle.
 impl std::io::Write for TerminalHandle {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
@@ -232,15 +2861,36 @@ impl std::io::Write for TerminalHandle {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        let result = self.sender.send(self.sink.clone());
-        if result.is_err() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::BrokenPipe,
-                result.unwrap_err(),
-            ));
+        if self.outbox.closed.load(Ordering::Acquire) {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
         }
+        if self.sink.is_empty() {
+            return Ok(());
+        }
+        let frame = if self.basic_colors.load(Ordering::Relaxed) {
+            let frame = term::basic_colors(&self.sink);
+            self.sink.clear();
+            frame
+        } else {
+            std::mem::take(&mut self.sink)
+        };
 
-        self.sink.clear();
+        let mut pending = self.outbox.pending.lock().unwrap();
+        if pending.is_empty() {
+            *pending = frame;
+        } else if pending.len() + frame.len() <= MAX_PENDING_OUTPUT {
+            pending.extend_from_slice(&frame);
+        } else {
+            // The client is not keeping up. What it has yet to be sent is dropped for the
+            // newest frame, and the screen redrawn in full on the next render.
+            *pending = frame;
+            self.outbox.overflowed.store(true, Ordering::Relaxed);
+        }
+        drop(pending);
+        self.outbox.ready.notify_one();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod snapshot;
@@ -0,0 +1,298 @@
+//! Draws the menu on a test backend and feeds it keys as a client would type them, to
+//! check what it shows.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand_core::{OsRng, RngCore};
+use ratatui::Terminal;
+use ratatui::backend::TestBackend;
+use tokio::sync::watch;
+
+use super::{MenuState, PukekoMenu, Theme};
+use crate::config::PukekoConfig;
+use crate::health::{Health, HealthMonitor};
+use crate::history::History;
+use crate::metrics::Metrics;
+use crate::provider::ConfigProvider;
+
+const DOWN: &str = "\x1b[B";
+const LEFT: &str = "\x1b[D";
+const ENTER: &str = "\r";
+const ESC: &str = "\x1b";
+
+/// The menu of a user of a config, drawn on a terminal of a fixed size.
+struct Harness {
+    menu: PukekoMenu,
+    terminal: Terminal<TestBackend>,
+    health: Arc<HealthMonitor>,
+    directory: PathBuf,
+    _config: watch::Sender<Arc<PukekoConfig>>,
+}
+
+impl Harness {
+    /// The menu `user` sees with `config`, the contents of a config file which is loaded
+    /// from a directory of its own.
+    fn new(config: &str, user: &str) -> Self {
+        let directory = std::env::temp_dir().join(format!(
+            "pukeko-menu-{}-{}",
+            std::process::id(),
+            OsRng.next_u32()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("pukeko.toml");
+        std::fs::write(&path, format!("state_directory = \"state\"\n{config}")).unwrap();
+        let config = PukekoConfig::load(&path).unwrap();
+        let (config_sender, receiver) = watch::channel(Arc::new(config.clone()));
+        let health = Arc::new(HealthMonitor::default());
+
+        let menu = PukekoMenu::new(
+            user.to_string(),
+            Arc::new(ConfigProvider::new(receiver)),
+            Arc::new(Metrics::default()),
+            health.clone(),
+            Arc::new(History::load(directory.join("history.json"))),
+            None,
+            Theme::new(config.theme.clone()),
+            config.keys.clone(),
+            config.messages.clone(),
+        );
+        Self {
+            menu,
+            terminal: Terminal::new(TestBackend::new(60, 12)).unwrap(),
+            health,
+            directory,
+            _config: config_sender,
+        }
+    }
+
+    /// Sends `keys` in one read, as a terminal sends a key's escape sequence.
+    async fn press(&mut self, keys: &str) -> &mut Self {
+        self.menu.handle_data(keys.as_bytes()).await.unwrap();
+        self
+    }
+
+    /// Sends `text` a character at a time.
+    async fn type_text(&mut self, text: &str) -> &mut Self {
+        for c in text.chars() {
+            self.press(&c.to_string()).await;
+        }
+        self
+    }
+
+    /// Draws the menu and checks the screen reads `expected`, ignoring the spaces at the
+    /// end of each line and the line break it starts with.
+    #[track_caller]
+    fn assert_screen(&mut self, expected: &str) {
+        self.menu.draw(&mut self.terminal).unwrap();
+        let buffer = self.terminal.backend().buffer();
+        let screen: Vec<String> = buffer
+            .content()
+            .chunks(buffer.area.width as usize)
+            .map(|row| {
+                let line: String = row.iter().map(|cell| cell.symbol()).collect();
+                line.trim_end().to_string()
+            })
+            .collect();
+        let expected: Vec<&str> = expected
+            .strip_prefix('\n')
+            .unwrap_or(expected)
+            .lines()
+            .map(str::trim_end)
+            .collect();
+        assert_eq!(screen, expected, "\n{}", screen.join("\n"));
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+const SERVERS: &str = r#"
+[[users]]
+name = "tester"
+servers = ["web-01", "web-02", "db-01", "bastion"]
+
+[[servers]]
+name = "web-01"
+host = "10.0.0.1"
+group = "web"
+tags = ["prod"]
+
+[[servers]]
+name = "web-02"
+host = "10.0.0.2"
+group = "web"
+
+[[servers]]
+name = "db-01"
+host = "10.0.1.1"
+group = "db"
+tags = ["prod"]
+
+[[servers]]
+name = "bastion"
+host = "10.0.2.1"
+"#;
+
+#[tokio::test]
+async fn servers_are_listed_under_their_groups() {
+    let mut harness = Harness::new(SERVERS, "tester");
+    harness.assert_screen(
+        r#"
+┌Press 'q' to quit, '?' for help, '/' to filter, 't' for ta┐
+│                         Counter:                         │
+│            ┌Select Server───────────────────┐            │
+│            │>> ▾ web (2)                    │            │
+│            │     web-01                     │            │
+│            │     web-02                     │            │
+│            │   ▾ db (1)                     │            │
+│            │     db-01                      │            │
+│            │   bastion                      │            │
+│            └────────────────────────────────┘            │
+│                                                          │
+└──────────────────────────────────────────────────────────┘
+"#,
+    );
+}
+
+#[tokio::test]
+async fn filtering_narrows_the_list() {
+    let mut harness = Harness::new(SERVERS, "tester");
+    harness.press("/").await.type_text("db").await;
+    harness.assert_screen(
+        r#"
+┌Press 'q' to quit, '?' for help, '/' to filter, 't' for ta┐
+│                         Counter:                         │
+│                                                          │
+│                                                          │
+│            ┌Select Server───────────────────┐            │
+│            │>> ▾ db (1)                     │            │
+│            │     db-01                      │            │
+│            │                                │            │
+│            └/db_────────────────────────────┘            │
+│                                                          │
+│                                                          │
+└──────────────────────────────────────────────────────────┘
+"#,
+    );
+}
+
+#[tokio::test]
+async fn escape_clears_the_filter() {
+    let mut harness = Harness::new(SERVERS, "tester");
+    harness
+        .press("/")
+        .await
+        .type_text("db")
+        .await
+        .press(ESC)
+        .await;
+    harness.assert_screen(
+        r#"
+┌Press 'q' to quit, '?' for help, '/' to filter, 't' for ta┐
+│                         Counter:                         │
+│            ┌Select Server───────────────────┐            │
+│            │   ▾ web (2)                    │            │
+│            │     web-01                     │            │
+│            │     web-02                     │            │
+│            │>> ▾ db (1)                     │            │
+│            │     db-01                      │            │
+│            │   bastion                      │            │
+│            └────────────────────────────────┘            │
+│                                                          │
+└──────────────────────────────────────────────────────────┘
+"#,
+    );
+}
+
+#[tokio::test]
+async fn collapsed_groups_hide_their_servers() {
+    let mut harness = Harness::new(SERVERS, "tester");
+    harness.press(LEFT).await.press(DOWN).await;
+    harness.assert_screen(
+        r#"
+┌Press 'q' to quit, '?' for help, '/' to filter, 't' for ta┐
+│                         Counter:                         │
+│                                                          │
+│            ┌Select Server───────────────────┐            │
+│            │   ▸ web (2)                    │            │
+│            │>> ▾ db (1)                     │            │
+│            │     db-01                      │            │
+│            │   bastion                      │            │
+│            └────────────────────────────────┘            │
+│                                                          │
+│                                                          │
+└──────────────────────────────────────────────────────────┘
+"#,
+    );
+}
+
+#[tokio::test]
+async fn help_lists_the_key_bindings() {
+    let mut harness = Harness::new(SERVERS, "tester");
+    harness.press("?").await;
+    harness.assert_screen(
+        r#"
+┌Press any key to close────────────────────────────────────┐
+│Navigation                                                │
+│  ↑ k          Move up                                    │
+│  ↓ j          Move down                                  │
+│  PgUp         Move up a page                             │
+│  PgDn         Move down a page                           │
+│  Home         Go to the first row                        │
+│  End          Go to the last row                         │
+│Servers                                                   │
+│  Enter        Connect, or fold and unfold a group        │
+│  p            Connect in a new pane, next to those open  │
+└──────────────────────────────────────────────────────────┘
+"#,
+    );
+}
+
+#[tokio::test]
+async fn enter_selects_the_highlighted_server() {
+    let mut harness = Harness::new(SERVERS, "tester");
+    harness
+        .press(DOWN)
+        .await
+        .press(DOWN)
+        .await
+        .press(ENTER)
+        .await;
+    assert!(
+        matches!(harness.menu.state(), MenuState::Selected(entry) if entry.name == "web-02"),
+        "{:?}",
+        harness.menu.state()
+    );
+}
+
+#[tokio::test]
+async fn high_contrast_marks_status_with_symbols() {
+    let config = format!("[theme]\npalette = \"high-contrast\"\n{SERVERS}");
+    let mut harness = Harness::new(&config, "tester");
+    let up = Health::Up {
+        latency: Duration::from_millis(12),
+    };
+    harness.health.set_status("web-01", up);
+    harness.health.set_status("web-02", Health::Down);
+    harness.assert_screen(
+        r#"
+┌Press 'q' to quit, '?' for help, '/' to filter, 't' for ta┐
+│                         Counter:                         │
+│            ┌Select Server───────────────────┐            │
+│            │>> ▾ web (2)                    │            │
+│            │     ✓ web-01 12ms              │            │
+│            │     ✗ web-02 down              │            │
+│            │   ▾ db (1)                     │            │
+│            │     db-01                      │            │
+│            │   bastion                      │            │
+│            └────────────────────────────────┘            │
+│                                                          │
+└──────────────────────────────────────────────────────────┘
+"#,
+    );
+}
@@ -0,0 +1,272 @@
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::Path;
+
+#[cfg(unix)]
+use anyhow::{Context, bail};
+#[cfg(unix)]
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::oneshot;
+#[cfg(unix)]
+use tracing::{debug, info, warn};
+
+/// Most listening sockets handed over at once.
+#[cfg(unix)]
+const MAX_LISTENERS: usize = 64;
+
+/// Sent by the new process once it is serving on the sockets it took.
+#[cfg(unix)]
+const SERVING: u8 = 1;
+
+/// The upgrade socket of the running process, which hands its listening sockets to a newer
+/// one started with the same config.
+pub struct UpgradeListener {
+    #[cfg(unix)]
+    listener: UnixListener,
+    #[cfg(not(unix))]
+    never: std::convert::Infallible,
+}
+
+/// A handover from the previous process, which goes on accepting connections on the
+/// sockets it handed over until it is completed.
+pub struct Handover {
+    #[cfg(unix)]
+    stream: UnixStream,
+    #[cfg(not(unix))]
+    never: std::convert::Infallible,
+}
+
+/// Binds the upgrade socket at `path`, replacing a stale one, so only the owner can use it.
+#[cfg(unix)]
+pub fn bind(path: &Path) -> anyhow::Result<UpgradeListener> {
+    let listener = crate::control::bind_private(path)
+        .with_context(|| format!("Failed to bind upgrade socket {}", path.display()))?;
+    info!("Accepting upgrades on {}", path.display());
+    Ok(UpgradeListener { listener })
+}
+
+/// Takes the listening sockets from the process serving the upgrade socket at `path`, if
+/// one is.
+#[cfg(unix)]
+pub async fn take_over(path: &Path) -> anyhow::Result<Option<(Vec<TcpListener>, Handover)>> {
+    let stream = match UnixStream::connect(path).await {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            debug!("No process to take over from at {}: {}", path.display(), e);
+            return Ok(None);
+        }
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to connect to {}", path.display()));
+        }
+    };
+    let fds = stream
+        .async_io(Interest::READABLE, || receive_fds(stream.as_raw_fd()))
+        .await
+        .with_context(|| format!("Failed to take the listeners over {}", path.display()))?;
+    if fds.is_empty() {
+        bail!("The process at {} handed over no listeners", path.display());
+    }
+    let listeners = fds
+        .into_iter()
+        .map(|fd| {
+            let listener = TcpListener::from(fd);
+            if listener.local_addr().is_err() {
+                bail!("A socket handed over is not a TCP listener");
+            }
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(Some((listeners, Handover { stream })))
+}
+
+#[cfg(unix)]
+impl Handover {
+    /// Tells the previous process this one is serving, so it stops accepting connections
+    /// and drains its sessions.
+    pub async fn complete(mut self) -> anyhow::Result<()> {
+        self.stream
+            .write_all(&[SERVING])
+            .await
+            .context("Failed to tell the previous process to stop accepting")
+    }
+}
+
+#[cfg(unix)]
+impl UpgradeListener {
+    /// Hands duplicates of `sockets` to each new process that connects, until one says it
+    /// is serving on them, which the returned receiver is then sent.
+    pub fn spawn(
+        self,
+        sockets: &[&tokio::net::TcpListener],
+    ) -> anyhow::Result<oneshot::Receiver<()>> {
+        let fds = sockets
+            .iter()
+            .map(|socket| socket.as_fd().try_clone_to_owned())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let (handed_over, receiver) = oneshot::channel();
+        tokio::spawn(async move {
+            match self.hand_over(&fds).await {
+                Ok(()) => {
+                    let _ = handed_over.send(());
+                }
+                Err(e) => warn!("Upgrade socket failed: {:?}", e),
+            }
+        });
+        Ok(receiver)
+    }
+
+    async fn hand_over(self, fds: &[OwnedFd]) -> anyhow::Result<()> {
+        let fds: Vec<RawFd> = fds.iter().map(AsRawFd::as_raw_fd).collect();
+        loop {
+            let (mut stream, _) = self.listener.accept().await?;
+            let sent = stream
+                .async_io(Interest::WRITABLE, || send_fds(stream.as_raw_fd(), &fds))
+                .await;
+            if let Err(e) = sent {
+                warn!("Failed to hand the listeners over: {:?}", e);
+                continue;
+            }
+            info!("Handed the listeners to a new process");
+            let mut reply = [0];
+            match stream.read(&mut reply).await {
+                Ok(1) if reply[0] == SERVING => return Ok(()),
+                _ => warn!("The new process went away before serving, carrying on"),
+            }
+        }
+    }
+}
+
+/// Sends `fds` over `socket` with a byte of data, as ancillary data needs some.
+#[cfg(unix)]
+fn send_fds(socket: RawFd, fds: &[RawFd]) -> std::io::Result<()> {
+    let payload = [fds.len() as u8];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let fds_len = std::mem::size_of_val(fds) as libc::c_uint;
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    // u64s keep the buffer aligned for the cmsghdr written into it.
+    let mut control = vec![0u64; space.div_ceil(8)];
+
+    // SAFETY: msghdr is plain data, for which all zeroes is valid.
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = space as _;
+    // SAFETY: the control buffer is large enough for one header and `fds`, as sized by
+    // CMSG_SPACE, so the first header and its data are within it.
+    unsafe {
+        let header = libc::CMSG_FIRSTHDR(&message);
+        (*header).cmsg_level = libc::SOL_SOCKET;
+        (*header).cmsg_type = libc::SCM_RIGHTS;
+        (*header).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        std::ptr::copy_nonoverlapping(
+            fds.as_ptr(),
+            libc::CMSG_DATA(header).cast::<RawFd>(),
+            fds.len(),
+        );
+    }
+    // SAFETY: the message points to the payload and control buffer, which outlive the call.
+    if unsafe { libc::sendmsg(socket, &message, 0) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receives the descriptors sent by [`send_fds`] over `socket`.
+#[cfg(unix)]
+fn receive_fds(socket: RawFd) -> std::io::Result<Vec<OwnedFd>> {
+    let mut payload = [0u8];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr().cast(),
+        iov_len: payload.len(),
+    };
+    let fds_len = (MAX_LISTENERS * std::mem::size_of::<RawFd>()) as libc::c_uint;
+    // SAFETY: CMSG_SPACE only computes a size.
+    let space = unsafe { libc::CMSG_SPACE(fds_len) } as usize;
+    let mut control = vec![0u64; space.div_ceil(8)];
+
+    // SAFETY: msghdr is plain data, for which all zeroes is valid.
+    let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+    message.msg_iov = &mut iov;
+    message.msg_iovlen = 1;
+    message.msg_control = control.as_mut_ptr().cast();
+    message.msg_controllen = space as _;
+    #[cfg(target_os = "linux")]
+    let flags = libc::MSG_CMSG_CLOEXEC;
+    #[cfg(not(target_os = "linux"))]
+    let flags = 0;
+    // SAFETY: the message points to the payload and control buffer, which outlive the call.
+    let received = unsafe { libc::recvmsg(socket, &mut message, flags) };
+    if received < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+
+    let mut fds = Vec::new();
+    // SAFETY: the kernel filled in the headers within msg_controllen, which CMSG_FIRSTHDR
+    // and CMSG_NXTHDR stay inside, and each SCM_RIGHTS header is followed by as many open
+    // descriptors as fit in its length, which are now owned by this process.
+    unsafe {
+        let mut header = libc::CMSG_FIRSTHDR(&message);
+        while !header.is_null() {
+            if (*header).cmsg_level == libc::SOL_SOCKET && (*header).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(header).cast::<RawFd>();
+                let len = ((*header).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / std::mem::size_of::<RawFd>();
+                for i in 0..len {
+                    fds.push(OwnedFd::from_raw_fd(data.add(i).read_unaligned()));
+                }
+            }
+            header = libc::CMSG_NXTHDR(&message, header);
+        }
+    }
+    if message.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(std::io::Error::other(
+            "more listeners than can be taken over",
+        ));
+    }
+    Ok(fds)
+}
+
+/// Off Unix there are no descriptors to pass, so nothing is taken over.
+#[cfg(not(unix))]
+pub fn bind(_path: &Path) -> anyhow::Result<UpgradeListener> {
+    anyhow::bail!("upgrade_socket is only supported on Unix")
+}
+
+#[cfg(not(unix))]
+pub async fn take_over(_path: &Path) -> anyhow::Result<Option<(Vec<TcpListener>, Handover)>> {
+    Ok(None)
+}
+
+#[cfg(not(unix))]
+impl Handover {
+    pub async fn complete(self) -> anyhow::Result<()> {
+        match self.never {}
+    }
+}
+
+#[cfg(not(unix))]
+impl UpgradeListener {
+    pub fn spawn(
+        self,
+        _sockets: &[&tokio::net::TcpListener],
+    ) -> anyhow::Result<oneshot::Receiver<()>> {
+        match self.never {}
+    }
+}
@@ -0,0 +1,551 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use russh::client::{self, Msg};
+use russh::keys::agent::client::AgentClient;
+use russh::keys::{PrivateKeyWithHashAlg, PublicKey, known_hosts, ssh_key};
+use russh::server::Handle;
+use russh::{ChannelMsg, ChannelReadHalf, ChannelWriteHalf, Disconnect};
+use tracing::{Instrument, Span, debug, info, trace, warn};
+
+use crate::config::{HostKeyPolicy, PukekoConfig, ServerEntry};
+use crate::dial;
+use crate::flow::{UpstreamWriter, Written};
+use crate::forward::{ForwardKind, PtyRequest, UpstreamOutput, UpstreamSession, X11Request};
+use crate::pool::{Lease, UpstreamPool};
+use crate::provider::BoxFuture;
+use crate::srv;
+
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returned when an upstream presents a host key that is not yet in known_hosts
+/// and the host key policy allows it to be trusted on first use.
+#[derive(Debug, Clone)]
+pub struct UnknownHostKey {
+    pub server: String,
+    pub key: PublicKey,
+}
+
+impl fmt::Display for UnknownHostKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Unknown host key {} for {}",
+            self.key.fingerprint(Default::default()),
+            self.server
+        )
+    }
+}
+
+impl std::error::Error for UnknownHostKey {}
+
+/// Returned when an upstream is reached but turns pukeko away.
+#[derive(Debug, Clone)]
+pub enum Rejected {
+    HostKey(String),
+    Authentication(String),
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::HostKey(message) | Rejected::Authentication(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Why a forward failed, in the terms shown to the user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    Timeout,
+    Refused,
+    HostKey,
+    Authentication,
+    Other,
+}
+
+impl FailureReason {
+    pub fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return FailureReason::Timeout;
+            }
+            if cause.is::<UnknownHostKey>() {
+                return FailureReason::HostKey;
+            }
+            if let Some(rejected) = cause.downcast_ref::<Rejected>() {
+                return match rejected {
+                    Rejected::HostKey(_) => FailureReason::HostKey,
+                    Rejected::Authentication(_) => FailureReason::Authentication,
+                };
+            }
+            let io = match cause.downcast_ref::<russh::Error>() {
+                Some(russh::Error::IO(e)) => Some(e),
+                _ => cause.downcast_ref::<std::io::Error>(),
+            };
+            match io.map(std::io::Error::kind) {
+                Some(std::io::ErrorKind::ConnectionRefused) => return FailureReason::Refused,
+                Some(std::io::ErrorKind::TimedOut) => return FailureReason::Timeout,
+                _ => {}
+            }
+        }
+        FailureReason::Other
+    }
+}
+
+pub struct UpstreamHandler {
+    name: String,
+    host: String,
+    port: u16,
+    known_hosts: PathBuf,
+    policy: HostKeyPolicy,
+    /// The downstream session whose agent is forwarded on to the upstream, if any.
+    agent: Option<Handle>,
+    /// The downstream session X11 connections from the upstream are relayed to, if any.
+    x11: Option<Handle>,
+    /// The downstream session's span, as the client's callbacks run outside of it.
+    span: Span,
+}
+
+impl client::Handler for UpstreamHandler {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &ssh_key::PublicKey,
+    ) -> Result<bool, Self::Error> {
+        match known_hosts::check_known_hosts_path(
+            &self.host,
+            self.port,
+            server_public_key,
+            &self.known_hosts,
+        ) {
+            Ok(true) => Ok(true),
+            Ok(false) => match self.policy {
+                HostKeyPolicy::Strict => Err(Rejected::HostKey(format!(
+                    "Host key for {} is not in {}",
+                    self.name,
+                    self.known_hosts.display()
+                ))
+                .into()),
+                HostKeyPolicy::Tofu => Err(UnknownHostKey {
+                    server: self.name.clone(),
+                    key: server_public_key.clone(),
+                }
+                .into()),
+            },
+            Err(russh::keys::Error::KeyChanged { line }) => {
+                self.span.in_scope(|| {
+                    warn!(
+                        "Host key for {} changed, presented {} but {} line {} differs",
+                        self.name,
+                        server_public_key.fingerprint(Default::default()),
+                        self.known_hosts.display(),
+                        line
+                    )
+                });
+                Err(Rejected::HostKey(format!("Host key mismatch for {}", self.name)).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: russh::Channel<client::Msg>,
+        _: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let Some(downstream) = self.agent.clone() else {
+            self.span.in_scope(|| {
+                warn!(
+                    "{} opened an agent channel without agent forwarding",
+                    self.name
+                )
+            });
+            return Ok(());
+        };
+
+        let name = self.name.clone();
+        tokio::spawn(
+            async move {
+                let agent = match downstream.channel_open_agent().await {
+                    Ok(agent) => agent,
+                    Err(e) => {
+                        debug!("Failed to open client agent for {}: {:?}", name, e);
+                        return;
+                    }
+                };
+                trace!("Relaying agent requests from {}", name);
+                splice(channel, agent).await;
+            }
+            .instrument(self.span.clone()),
+        );
+        Ok(())
+    }
+
+    async fn server_channel_open_x11(
+        &mut self,
+        channel: russh::Channel<client::Msg>,
+        originator_address: &str,
+        originator_port: u32,
+        _: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        let Some(downstream) = self.x11.clone() else {
+            self.span
+                .in_scope(|| warn!("{} opened an X11 channel without X11 forwarding", self.name));
+            return Ok(());
+        };
+
+        let name = self.name.clone();
+        let originator_address = originator_address.to_string();
+        tokio::spawn(
+            async move {
+                let display = match downstream
+                    .channel_open_x11(originator_address, originator_port)
+                    .await
+                {
+                    Ok(display) => display,
+                    Err(e) => {
+                        debug!("Failed to open client X11 display for {}: {:?}", name, e);
+                        return;
+                    }
+                };
+                trace!("Relaying an X11 connection from {}", name);
+                splice(channel, display).await;
+            }
+            .instrument(self.span.clone()),
+        );
+        Ok(())
+    }
+}
+
+/// Relays a channel the upstream opened to one opened to the client in its place. Stops as
+/// soon as either side is done so that both channels are closed on drop, otherwise the
+/// client waits on its channel after the session has ended.
+async fn splice(upstream: russh::Channel<Msg>, downstream: russh::Channel<russh::server::Msg>) {
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream.into_stream());
+    let (mut downstream_read, mut downstream_write) = tokio::io::split(downstream.into_stream());
+    tokio::select! {
+        _ = tokio::io::copy(&mut upstream_read, &mut downstream_write) => {}
+        _ = tokio::io::copy(&mut downstream_read, &mut upstream_write) => {}
+    }
+}
+
+pub fn trust_host_key(
+    config: &PukekoConfig,
+    entry: &ServerEntry,
+    key: &PublicKey,
+) -> anyhow::Result<()> {
+    info!(
+        "Trusting host key {} for {}",
+        key.fingerprint(Default::default()),
+        entry.name
+    );
+    known_hosts::learn_known_hosts_path(&entry.host, entry.port, key, &config.known_hosts)?;
+    Ok(())
+}
+
+pub type UpstreamHandle = client::Handle<UpstreamHandler>;
+
+/// A connection a session runs on, either its own or shared through the pool.
+pub enum Upstream {
+    Owned(UpstreamHandle),
+    Shared(Lease),
+}
+
+impl Upstream {
+    fn handle(&self) -> &UpstreamHandle {
+        match self {
+            Upstream::Owned(handle) => handle,
+            Upstream::Shared(lease) => &lease.handle,
+        }
+    }
+
+    /// Disconnects the connection if it is the session's own. Shared ones are left open
+    /// for other sessions until they have been idle for a while.
+    async fn close(self) {
+        if let Upstream::Owned(handle) = self {
+            let _ = handle.disconnect(Disconnect::ByApplication, "", "").await;
+        }
+    }
+}
+
+/// Connects and authenticates to `entry` as `user`. Keys from the client's forwarded
+/// agent are tried first when `agent` is given, falling back to the configured upstream key.
+/// X11 connections the upstream opens are relayed to `x11`.
+pub async fn connect(
+    entry: &ServerEntry,
+    user: &str,
+    config: &PukekoConfig,
+    agent: Option<&Handle>,
+    x11: Option<&Handle>,
+) -> anyhow::Result<UpstreamHandle> {
+    let user = entry.user.as_deref().unwrap_or(user);
+    let key = entry.key.as_deref().or(config.upstream_key.as_ref());
+    if agent.is_none() && key.is_none() {
+        bail!("No upstream key is configured for {}", entry.name);
+    }
+    let resolved = srv::resolve(entry)
+        .await
+        .with_context(|| format!("Failed to connect to {}", entry.name))?;
+    let entry = resolved.as_ref();
+
+    let client_config = Arc::new(client::Config {
+        inactivity_timeout: None,
+        keepalive_interval: config.keepalive.interval,
+        keepalive_max: config.keepalive.max_missed,
+        preferred: config.algorithms.clone(),
+        ..Default::default()
+    });
+    let handler = UpstreamHandler {
+        name: entry.name.clone(),
+        host: entry.host.clone(),
+        port: entry.port,
+        known_hosts: config.known_hosts.clone(),
+        policy: config.host_key_policy,
+        agent: agent.cloned(),
+        x11: x11.cloned(),
+        span: Span::current(),
+    };
+
+    debug!(
+        "Connecting to upstream {} at {}:{} as {}",
+        entry.name, entry.host, entry.port, user
+    );
+    let connect = async {
+        let stream = dial::dial(entry, user, config).await?;
+        anyhow::Ok(client::connect_stream(client_config, stream, handler).await?)
+    };
+    let mut handle = tokio::time::timeout(CONNECT_TIMEOUT, connect)
+        .await
+        .with_context(|| format!("Timed out connecting to {}", entry.name))?
+        .with_context(|| format!("Failed to connect to {}", entry.name))?;
+
+    let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+
+    if let Some(downstream) = agent {
+        if authenticate_with_agent(&mut handle, user, hash_alg, downstream).await? {
+            return Ok(handle);
+        }
+        debug!("Upstream {} rejected all forwarded agent keys", entry.name);
+    }
+
+    let authenticated = match key {
+        Some(key) => handle
+            .authenticate_publickey(
+                user,
+                PrivateKeyWithHashAlg::new(Arc::new(key.clone()), hash_alg),
+            )
+            .await?
+            .success(),
+        None => false,
+    };
+
+    if !authenticated {
+        return Err(Rejected::Authentication(format!(
+            "Upstream {} rejected authentication as {}",
+            entry.name, user
+        ))
+        .into());
+    }
+
+    Ok(handle)
+}
+
+async fn authenticate_with_agent(
+    handle: &mut UpstreamHandle,
+    user: &str,
+    hash_alg: Option<ssh_key::HashAlg>,
+    downstream: &Handle,
+) -> anyhow::Result<bool> {
+    let channel = downstream
+        .channel_open_agent()
+        .await
+        .context("Failed to open the forwarded agent")?;
+    let mut agent = AgentClient::connect(channel.into_stream());
+
+    for key in agent.request_identities().await? {
+        trace!(
+            "Trying agent key {} for {}",
+            key.fingerprint(Default::default()),
+            user
+        );
+        let result = handle
+            .authenticate_publickey_with(user, key, hash_alg, &mut agent)
+            .await?;
+        if result.success() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// A session on an SSH upstream, with the same pty and command as the client's.
+pub struct SshSession {
+    upstream: Upstream,
+    reader: ChannelReadHalf,
+    writer: ChannelWriteHalf<Msg>,
+    /// Writes the client's input as the channel's window allows.
+    input: UpstreamWriter,
+}
+
+impl SshSession {
+    #[allow(clippy::too_many_arguments)]
+    pub async fn open(
+        entry: &ServerEntry,
+        user: &str,
+        config: &PukekoConfig,
+        kind: &ForwardKind,
+        pty: Option<&PtyRequest>,
+        env: &[(String, String)],
+        agent: Option<&Handle>,
+        x11: Option<(&X11Request, &Handle)>,
+        pool: &Arc<UpstreamPool>,
+    ) -> anyhow::Result<Self> {
+        // Sessions authenticating with the client's agent keep their connection to
+        // themselves, as it is the user's rather than pukeko's, as do those relaying X11
+        // connections to their client.
+        let display = x11.map(|(_, downstream)| downstream);
+        let upstream = if config.pool.enabled && entry.pool && agent.is_none() && x11.is_none() {
+            pool.connect(entry, user, config).await?
+        } else {
+            Upstream::Owned(connect(entry, user, config, agent, display).await?)
+        };
+        let (upstream, channel) = match upstream.handle().channel_open_session().await {
+            Ok(channel) => (upstream, channel),
+            Err(e) => {
+                let Upstream::Shared(lease) = &upstream else {
+                    return Err(e.into());
+                };
+                debug!(
+                    "Shared connection to {} failed to open a channel, connecting again: {}",
+                    entry.name, e
+                );
+                lease.retire();
+                let handle = connect(entry, user, config, agent, display).await?;
+                let channel = handle.channel_open_session().await?;
+                (Upstream::Owned(handle), channel)
+            }
+        };
+
+        if agent.is_some() {
+            channel.agent_forward(false).await?;
+        }
+        if let Some((x11, _)) = x11 {
+            channel
+                .request_x11(
+                    false,
+                    x11.single_connection,
+                    x11.protocol.as_str(),
+                    x11.cookie.as_str(),
+                    x11.screen,
+                )
+                .await?;
+        }
+        // Servers ignore variables they do not accept, such as those missing from sshd's
+        // AcceptEnv.
+        for (name, value) in env {
+            channel
+                .set_env(false, name.as_str(), value.as_str())
+                .await?;
+        }
+
+        if let Some(pty) = pty {
+            channel
+                .request_pty(
+                    false,
+                    &pty.term,
+                    pty.col_width,
+                    pty.row_height,
+                    pty.pix_width,
+                    pty.pix_height,
+                    &pty.modes,
+                )
+                .await?;
+        }
+
+        match kind {
+            ForwardKind::Shell => channel.request_shell(false).await?,
+            ForwardKind::Exec(command) => channel.exec(false, command.clone()).await?,
+            ForwardKind::Subsystem(name) => channel.request_subsystem(false, name).await?,
+        }
+
+        let (reader, writer) = channel.split();
+        Ok(Self {
+            upstream,
+            reader,
+            input: UpstreamWriter::spawn(writer.make_writer()),
+            writer,
+        })
+    }
+}
+
+impl UpstreamSession for SshSession {
+    fn recv(&mut self) -> BoxFuture<'_, Option<UpstreamOutput>> {
+        Box::pin(async move {
+            loop {
+                return match self.reader.wait().await? {
+                    ChannelMsg::Data { data } => Some(UpstreamOutput::Data(data.to_vec())),
+                    ChannelMsg::ExtendedData { data, ext } => {
+                        Some(UpstreamOutput::ExtendedData(data.to_vec(), ext))
+                    }
+                    ChannelMsg::ExitStatus { exit_status } => {
+                        Some(UpstreamOutput::ExitStatus(exit_status))
+                    }
+                    ChannelMsg::ExitSignal {
+                        signal_name,
+                        core_dumped,
+                        error_message,
+                        lang_tag,
+                    } => Some(UpstreamOutput::ExitSignal {
+                        signal: signal_name,
+                        core_dumped,
+                        message: error_message,
+                        lang: lang_tag,
+                    }),
+                    ChannelMsg::Eof => Some(UpstreamOutput::Eof),
+                    ChannelMsg::Close => None,
+                    _ => continue,
+                };
+            }
+        })
+    }
+
+    fn send(&mut self, data: Vec<u8>, written: Written) -> anyhow::Result<()> {
+        self.input.write(data, Some(written))
+    }
+
+    /// Sent after the input still being written.
+    fn eof(&mut self) -> BoxFuture<'_, ()> {
+        self.input.shutdown();
+        Box::pin(std::future::ready(()))
+    }
+
+    fn window_change(
+        &mut self,
+        col_width: u32,
+        row_height: u32,
+        pix_width: u32,
+        pix_height: u32,
+    ) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            let _ = self
+                .writer
+                .window_change(col_width, row_height, pix_width, pix_height)
+                .await;
+        })
+    }
+
+    fn close(self: Box<Self>) -> BoxFuture<'static, ()> {
+        Box::pin(async move {
+            let _ = self.writer.close().await;
+            self.upstream.close().await;
+        })
+    }
+}
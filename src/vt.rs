@@ -0,0 +1,555 @@
+use ratatui::buffer::{Buffer, Cell};
+use ratatui::layout::{Position, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use termwiz::cell::{Blink, Intensity, Underline};
+use termwiz::color::ColorSpec;
+use termwiz::escape::csi::{
+    Cursor, DecPrivateMode, DecPrivateModeCode, Edit, EraseInDisplay, EraseInLine, Mode, Sgr,
+};
+use termwiz::escape::parser::Parser;
+use termwiz::escape::{Action, CSI, ControlCode, Esc, EscCode};
+use unicode_width::UnicodeWidthChar;
+
+const TAB_WIDTH: u16 = 8;
+
+/// Where the cursor was saved, and with which style.
+#[derive(Debug, Clone, Copy)]
+struct SavedCursor {
+    x: u16,
+    y: u16,
+    style: Style,
+}
+
+/// A terminal emulated in memory, from the output of a shell or program, so it can be
+/// drawn into part of the client's terminal rather than the whole of it. Covers what
+/// shells and the usual full screen programs rely on: cursor movement, erasing,
+/// scrolling regions, colors and the alternate screen.
+pub struct VirtualTerminal {
+    parser: Parser,
+    width: u16,
+    height: u16,
+    rows: Vec<Vec<Cell>>,
+    /// The main screen, kept while a program draws on the alternate one.
+    main_screen: Option<Vec<Vec<Cell>>>,
+    x: u16,
+    y: u16,
+    /// Set after printing in the last column, so the next character starts a new line.
+    wrap_pending: bool,
+    saved: Option<SavedCursor>,
+    style: Style,
+    /// The rows line feeds scroll, inclusive, which programs narrow to keep a status
+    /// line in place.
+    scroll_top: u16,
+    scroll_bottom: u16,
+    cursor_visible: bool,
+    /// Whether the G0 and G1 character sets are DEC line drawing, and G1 is in use.
+    line_drawing: [bool; 2],
+    shifted: bool,
+    last_char: Option<char>,
+}
+
+impl VirtualTerminal {
+    pub fn new(width: u16, height: u16) -> Self {
+        let (width, height) = (width.max(1), height.max(1));
+        Self {
+            parser: Parser::new(),
+            width,
+            height,
+            rows: vec![blank_row(width, Style::default()); usize::from(height)],
+            main_screen: None,
+            x: 0,
+            y: 0,
+            wrap_pending: false,
+            saved: None,
+            style: Style::default(),
+            scroll_top: 0,
+            scroll_bottom: height - 1,
+            cursor_visible: true,
+            line_drawing: [false; 2],
+            shifted: false,
+            last_char: None,
+        }
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        (self.width, self.height)
+    }
+
+    /// Changes the size, keeping what fits of the screen from its top left.
+    pub fn resize(&mut self, width: u16, height: u16) {
+        let (width, height) = (width.max(1), height.max(1));
+        if (width, height) == (self.width, self.height) {
+            return;
+        }
+        let fit = |rows: &mut Vec<Vec<Cell>>| {
+            // Rows lost off the bottom are scrolled off the top instead, as shells keep
+            // their prompt at the bottom.
+            if rows.len() > usize::from(height) {
+                rows.drain(..rows.len() - usize::from(height));
+            }
+            for row in rows.iter_mut() {
+                row.resize(usize::from(width), Cell::default());
+            }
+            rows.resize(usize::from(height), blank_row(width, Style::default()));
+        };
+        let cut = (self.y + 1).saturating_sub(height);
+        fit(&mut self.rows);
+        if let Some(main) = &mut self.main_screen {
+            fit(main);
+        }
+        self.width = width;
+        self.height = height;
+        self.y = self.y.saturating_sub(cut).min(height - 1);
+        self.x = self.x.min(width - 1);
+        self.wrap_pending = false;
+        self.scroll_top = 0;
+        self.scroll_bottom = height - 1;
+    }
+
+    /// Applies output from the program.
+    pub fn feed(&mut self, data: &[u8]) {
+        for action in self.parser.parse_as_vec(data) {
+            self.apply(action);
+        }
+    }
+
+    /// Where the program last left the cursor, unless it hid it.
+    pub fn cursor(&self) -> Option<Position> {
+        self.cursor_visible.then_some(Position::new(self.x, self.y))
+    }
+
+    /// Draws the screen into `area` of `buf`, cropping it if the area is smaller.
+    pub fn render(&self, area: Rect, buf: &mut Buffer) {
+        for (y, row) in self.rows.iter().enumerate().take(usize::from(area.height)) {
+            for (x, cell) in row.iter().enumerate().take(usize::from(area.width)) {
+                let position = (area.x + x as u16, area.y + y as u16);
+                if let Some(target) = buf.cell_mut(position) {
+                    *target = cell.clone();
+                }
+            }
+        }
+    }
+
+    fn apply(&mut self, action: Action) {
+        match action {
+            Action::Print(c) => self.print(c),
+            Action::PrintString(text) => text.chars().for_each(|c| self.print(c)),
+            Action::Control(code) => self.control(code),
+            Action::CSI(CSI::Sgr(sgr)) => self.sgr(sgr),
+            Action::CSI(CSI::Cursor(cursor)) => self.cursor_movement(cursor),
+            Action::CSI(CSI::Edit(edit)) => self.edit(edit),
+            Action::CSI(CSI::Mode(mode)) => self.mode(mode),
+            Action::Esc(Esc::Code(code)) => self.escape(code),
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, c: char) {
+        let c = if self.line_drawing[usize::from(self.shifted)] {
+            line_drawing(c)
+        } else {
+            c
+        };
+        let width = c.width().unwrap_or(0) as u16;
+        if width == 0 {
+            return;
+        }
+        if self.wrap_pending || self.x + width > self.width {
+            self.x = 0;
+            self.line_feed();
+        }
+        let style = self.style;
+        let row = &mut self.rows[usize::from(self.y)];
+        let x = usize::from(self.x);
+        row[x].reset();
+        row[x].set_char(c).set_style(style);
+        // The cell a wide character spills into is drawn by the character itself.
+        if width == 2 && x + 1 < row.len() {
+            row[x + 1].reset();
+            row[x + 1].set_symbol("").set_style(style);
+        }
+        self.last_char = Some(c);
+        if self.x + width >= self.width {
+            self.x = self.width - 1;
+            self.wrap_pending = true;
+        } else {
+            self.x += width;
+        }
+    }
+
+    fn control(&mut self, code: ControlCode) {
+        match code {
+            ControlCode::LineFeed | ControlCode::VerticalTab | ControlCode::FormFeed => {
+                self.line_feed()
+            }
+            ControlCode::CarriageReturn => self.move_to(0, self.y),
+            ControlCode::Backspace => self.move_to(self.x.saturating_sub(1), self.y),
+            ControlCode::HorizontalTab => {
+                let next = (self.x / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.move_to(next.min(self.width - 1), self.y);
+            }
+            ControlCode::ShiftOut => self.shifted = true,
+            ControlCode::ShiftIn => self.shifted = false,
+            _ => {}
+        }
+    }
+
+    fn escape(&mut self, code: EscCode) {
+        match code {
+            EscCode::Index => self.line_feed(),
+            EscCode::NextLine => {
+                self.move_to(0, self.y);
+                self.line_feed();
+            }
+            EscCode::ReverseIndex => self.reverse_line_feed(),
+            EscCode::DecSaveCursorPosition => self.save_cursor(),
+            EscCode::DecRestoreCursorPosition => self.restore_cursor(),
+            EscCode::FullReset => *self = Self::new(self.width, self.height),
+            EscCode::DecLineDrawingG0 => self.line_drawing[0] = true,
+            EscCode::AsciiCharacterSetG0 | EscCode::UkCharacterSetG0 => {
+                self.line_drawing[0] = false
+            }
+            EscCode::DecLineDrawingG1 => self.line_drawing[1] = true,
+            EscCode::AsciiCharacterSetG1 | EscCode::UkCharacterSetG1 => {
+                self.line_drawing[1] = false
+            }
+            _ => {}
+        }
+    }
+
+    fn cursor_movement(&mut self, cursor: Cursor) {
+        let (x, y) = (self.x, self.y);
+        match cursor {
+            Cursor::Up(n) | Cursor::LinePositionBackward(n) => {
+                // Stops at the top margin when starting below it, as terminals do.
+                let top = if y >= self.scroll_top {
+                    self.scroll_top
+                } else {
+                    0
+                };
+                self.move_to(x, y.saturating_sub(clamp(n)).max(top));
+            }
+            Cursor::Down(n) | Cursor::LinePositionForward(n) => {
+                let bottom = if y <= self.scroll_bottom {
+                    self.scroll_bottom
+                } else {
+                    self.height - 1
+                };
+                self.move_to(x, y.saturating_add(clamp(n)).min(bottom));
+            }
+            Cursor::Left(n) | Cursor::CharacterPositionBackward(n) => {
+                self.move_to(x.saturating_sub(clamp(n)), y)
+            }
+            Cursor::Right(n) | Cursor::CharacterPositionForward(n) => {
+                self.move_to(x.saturating_add(clamp(n)), y)
+            }
+            Cursor::NextLine(n) => self.move_to(0, y.saturating_add(clamp(n))),
+            Cursor::PrecedingLine(n) => self.move_to(0, y.saturating_sub(clamp(n))),
+            Cursor::CharacterAbsolute(col) | Cursor::CharacterPositionAbsolute(col) => {
+                self.move_to(position(col.as_zero_based()), y)
+            }
+            Cursor::LinePositionAbsolute(line) => self.move_to(x, position(line.saturating_sub(1))),
+            Cursor::Position { line, col } | Cursor::CharacterAndLinePosition { line, col } => self
+                .move_to(
+                    position(col.as_zero_based()),
+                    position(line.as_zero_based()),
+                ),
+            Cursor::ForwardTabulation(n) => {
+                let next = (x / TAB_WIDTH + clamp(n)).saturating_mul(TAB_WIDTH);
+                self.move_to(next, y);
+            }
+            Cursor::BackwardTabulation(n) => {
+                let previous = (x.div_ceil(TAB_WIDTH)).saturating_sub(clamp(n)) * TAB_WIDTH;
+                self.move_to(previous, y);
+            }
+            Cursor::SetTopAndBottomMargins { top, bottom } => {
+                let top = position(top.as_zero_based()).min(self.height - 1);
+                let bottom = position(bottom.as_zero_based()).min(self.height - 1);
+                if top < bottom {
+                    self.scroll_top = top;
+                    self.scroll_bottom = bottom;
+                } else {
+                    self.scroll_top = 0;
+                    self.scroll_bottom = self.height - 1;
+                }
+                self.move_to(0, 0);
+            }
+            Cursor::SaveCursor => self.save_cursor(),
+            Cursor::RestoreCursor => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn edit(&mut self, edit: Edit) {
+        let blank = self.blank();
+        let (x, y) = (usize::from(self.x), usize::from(self.y));
+        let width = usize::from(self.width);
+        match edit {
+            Edit::EraseInLine(erase) => {
+                let row = &mut self.rows[y];
+                let range = match erase {
+                    EraseInLine::EraseToEndOfLine => x..width,
+                    EraseInLine::EraseToStartOfLine => 0..x + 1,
+                    EraseInLine::EraseLine => 0..width,
+                };
+                row[range].fill(blank);
+            }
+            Edit::EraseInDisplay(erase) => {
+                let rows = match erase {
+                    EraseInDisplay::EraseToEndOfDisplay => {
+                        self.rows[y][x..].fill(blank.clone());
+                        y + 1..self.rows.len()
+                    }
+                    EraseInDisplay::EraseToStartOfDisplay => {
+                        self.rows[y][..=x].fill(blank.clone());
+                        0..y
+                    }
+                    EraseInDisplay::EraseDisplay => 0..self.rows.len(),
+                    EraseInDisplay::EraseScrollback => 0..0,
+                };
+                for row in &mut self.rows[rows] {
+                    row.fill(blank.clone());
+                }
+            }
+            Edit::EraseCharacter(n) => {
+                let end = (x + n.max(1) as usize).min(width);
+                self.rows[y][x..end].fill(blank);
+            }
+            Edit::DeleteCharacter(n) => {
+                let row = &mut self.rows[y];
+                let n = (n.max(1) as usize).min(width - x);
+                row.drain(x..x + n);
+                row.resize(width, blank);
+            }
+            Edit::InsertCharacter(n) => {
+                let row = &mut self.rows[y];
+                let n = (n.max(1) as usize).min(width - x);
+                row.splice(x..x, std::iter::repeat_n(blank, n));
+                row.truncate(width);
+            }
+            Edit::InsertLine(n) => {
+                if (self.scroll_top..=self.scroll_bottom).contains(&self.y) {
+                    self.scroll_down_from(self.y, clamp(n));
+                    self.move_to(0, self.y);
+                }
+            }
+            Edit::DeleteLine(n) => {
+                if (self.scroll_top..=self.scroll_bottom).contains(&self.y) {
+                    self.scroll_up_from(self.y, clamp(n));
+                    self.move_to(0, self.y);
+                }
+            }
+            Edit::ScrollUp(n) => self.scroll_up_from(self.scroll_top, clamp(n)),
+            Edit::ScrollDown(n) => self.scroll_down_from(self.scroll_top, clamp(n)),
+            Edit::Repeat(n) => {
+                if let Some(c) = self.last_char {
+                    for _ in 0..n.max(1).min(u32::from(self.width)) {
+                        self.print(c);
+                    }
+                }
+            }
+        }
+    }
+
+    fn mode(&mut self, mode: Mode) {
+        let (set, code) = match mode {
+            Mode::SetDecPrivateMode(DecPrivateMode::Code(code)) => (true, code),
+            Mode::ResetDecPrivateMode(DecPrivateMode::Code(code)) => (false, code),
+            _ => return,
+        };
+        match code {
+            DecPrivateModeCode::ShowCursor => self.cursor_visible = set,
+            DecPrivateModeCode::ClearAndEnableAlternateScreen if set => {
+                self.save_cursor();
+                self.alternate_screen(true);
+            }
+            DecPrivateModeCode::ClearAndEnableAlternateScreen => {
+                self.alternate_screen(false);
+                self.restore_cursor();
+            }
+            DecPrivateModeCode::EnableAlternateScreen
+            | DecPrivateModeCode::OptEnableAlternateScreen => self.alternate_screen(set),
+            DecPrivateModeCode::SaveCursor if set => self.save_cursor(),
+            DecPrivateModeCode::SaveCursor => self.restore_cursor(),
+            _ => {}
+        }
+    }
+
+    fn sgr(&mut self, sgr: Sgr) {
+        let style = &mut self.style;
+        match sgr {
+            Sgr::Reset => *style = Style::default(),
+            Sgr::Intensity(intensity) => {
+                *style = style.remove_modifier(Modifier::BOLD | Modifier::DIM);
+                match intensity {
+                    Intensity::Bold => *style = style.add_modifier(Modifier::BOLD),
+                    Intensity::Half => *style = style.add_modifier(Modifier::DIM),
+                    Intensity::Normal => {}
+                }
+            }
+            Sgr::Underline(Underline::None) => *style = style.remove_modifier(Modifier::UNDERLINED),
+            Sgr::Underline(_) => *style = style.add_modifier(Modifier::UNDERLINED),
+            Sgr::Blink(Blink::None) => {
+                *style = style.remove_modifier(Modifier::SLOW_BLINK | Modifier::RAPID_BLINK)
+            }
+            Sgr::Blink(_) => *style = style.add_modifier(Modifier::SLOW_BLINK),
+            Sgr::Italic(on) => *style = toggle(*style, Modifier::ITALIC, on),
+            Sgr::Inverse(on) => *style = toggle(*style, Modifier::REVERSED, on),
+            Sgr::Invisible(on) => *style = toggle(*style, Modifier::HIDDEN, on),
+            Sgr::StrikeThrough(on) => *style = toggle(*style, Modifier::CROSSED_OUT, on),
+            Sgr::Foreground(color) => style.fg = color_of(color),
+            Sgr::Background(color) => style.bg = color_of(color),
+            _ => {}
+        }
+    }
+
+    fn move_to(&mut self, x: u16, y: u16) {
+        self.x = x.min(self.width - 1);
+        self.y = y.min(self.height - 1);
+        self.wrap_pending = false;
+    }
+
+    fn line_feed(&mut self) {
+        if self.y == self.scroll_bottom {
+            self.scroll_up_from(self.scroll_top, 1);
+        } else if self.y + 1 < self.height {
+            self.y += 1;
+        }
+        self.wrap_pending = false;
+    }
+
+    fn reverse_line_feed(&mut self) {
+        if self.y == self.scroll_top {
+            self.scroll_down_from(self.scroll_top, 1);
+        } else {
+            self.y = self.y.saturating_sub(1);
+        }
+        self.wrap_pending = false;
+    }
+
+    /// Moves the rows from `top` to the bottom margin up by `n`, blanking those below.
+    fn scroll_up_from(&mut self, top: u16, n: u16) {
+        let (top, bottom) = (usize::from(top), usize::from(self.scroll_bottom));
+        let n = usize::from(n).min(bottom + 1 - top);
+        let blank = blank_row(self.width, self.style_for_blank());
+        self.rows[top..=bottom].rotate_left(n);
+        self.rows[bottom + 1 - n..=bottom].fill(blank);
+    }
+
+    /// Moves the rows from `top` to the bottom margin down by `n`, blanking those above.
+    fn scroll_down_from(&mut self, top: u16, n: u16) {
+        let (top, bottom) = (usize::from(top), usize::from(self.scroll_bottom));
+        let n = usize::from(n).min(bottom + 1 - top);
+        let blank = blank_row(self.width, self.style_for_blank());
+        self.rows[top..=bottom].rotate_right(n);
+        self.rows[top..top + n].fill(blank);
+    }
+
+    fn alternate_screen(&mut self, enable: bool) {
+        match (enable, self.main_screen.take()) {
+            (true, None) => {
+                let blank = blank_row(self.width, Style::default());
+                let main = std::mem::replace(&mut self.rows, vec![blank; usize::from(self.height)]);
+                self.main_screen = Some(main);
+            }
+            (false, Some(main)) => self.rows = main,
+            (_, main) => self.main_screen = main,
+        }
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved = Some(SavedCursor {
+            x: self.x,
+            y: self.y,
+            style: self.style,
+        });
+    }
+
+    fn restore_cursor(&mut self) {
+        let saved = self.saved.unwrap_or(SavedCursor {
+            x: 0,
+            y: 0,
+            style: Style::default(),
+        });
+        self.move_to(saved.x, saved.y);
+        self.style = saved.style;
+    }
+
+    /// Erased cells take the current background, as in xterm.
+    fn style_for_blank(&self) -> Style {
+        Style::default().bg(self.style.bg.unwrap_or(Color::Reset))
+    }
+
+    fn blank(&self) -> Cell {
+        let mut cell = Cell::default();
+        cell.set_style(self.style_for_blank());
+        cell
+    }
+}
+
+fn blank_row(width: u16, style: Style) -> Vec<Cell> {
+    let mut cell = Cell::default();
+    cell.set_style(style);
+    vec![cell; usize::from(width)]
+}
+
+/// Counts from escape sequences, which are at least 1, in the range of a screen.
+fn clamp(n: u32) -> u16 {
+    n.clamp(1, u32::from(u16::MAX)) as u16
+}
+
+/// A row or column from an escape sequence, counted from 0.
+fn position(n: u32) -> u16 {
+    n.min(u32::from(u16::MAX)) as u16
+}
+
+fn toggle(style: Style, modifier: Modifier, on: bool) -> Style {
+    if on {
+        style.add_modifier(modifier)
+    } else {
+        style.remove_modifier(modifier)
+    }
+}
+
+fn color_of(color: ColorSpec) -> Option<Color> {
+    Some(match color {
+        ColorSpec::Default => Color::Reset,
+        ColorSpec::PaletteIndex(index) => Color::Indexed(index),
+        ColorSpec::TrueColor(rgba) => {
+            let channel = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+            Color::Rgb(channel(rgba.0), channel(rgba.1), channel(rgba.2))
+        }
+    })
+}
+
+/// The box drawing character for `c` in the DEC special graphics set.
+fn line_drawing(c: char) -> char {
+    match c {
+        '`' => '◆',
+        'a' => '▒',
+        'f' => '°',
+        'g' => '±',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        c => c,
+    }
+}
@@ -0,0 +1,62 @@
+//! Logs in to a bastion that holds new keys for approval with keys it has not seen,
+//! checking users that do not exist are told the same as those that do.
+
+mod common;
+
+use std::sync::Arc;
+
+use common::Bastion;
+use rand_core::OsRng;
+use russh::client::KeyboardInteractiveAuthResponse;
+use russh::keys::{Algorithm, PrivateKey, PrivateKeyWithHashAlg};
+use serde_json::json;
+
+/// Logs in as `login` with a new key, then with keyboard-interactive, returning what the
+/// bastion says with the key's fingerprint, the user and the request's id left out.
+async fn told(bastion: &Bastion, login: &str) -> String {
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+    let fingerprint = key.public_key().fingerprint(Default::default()).to_string();
+    let mut session = bastion.connect_unauthenticated().await;
+    let authenticated = session
+        .authenticate_publickey(login, PrivateKeyWithHashAlg::new(Arc::new(key), None))
+        .await
+        .unwrap();
+    assert!(!authenticated.success());
+    let response = session
+        .authenticate_keyboard_interactive_start(login, None)
+        .await
+        .unwrap();
+    let KeyboardInteractiveAuthResponse::InfoRequest {
+        name, instructions, ..
+    } = response
+    else {
+        panic!("{login} was not told anything: {response:?}");
+    };
+    let told = format!("{name}: {instructions}")
+        .replace(&fingerprint, "<fingerprint>")
+        .replace(login, "<user>");
+    let id = told
+        .split("request ")
+        .nth(1)
+        .and_then(|rest| rest.split(',').next())
+        .unwrap_or_else(|| panic!("{told}"));
+    told.replace(&format!("request {id},"), "request <id>,")
+}
+
+#[tokio::test]
+async fn users_that_do_not_exist_are_told_their_key_is_awaiting_approval_too() {
+    let bastion = Bastion::start_with_settings("key_approval = true", "").await;
+    let known = told(&bastion, "tester").await;
+    let unknown = told(&bastion, "nobody").await;
+    assert!(known.starts_with("Key awaiting approval: "), "{known}");
+    assert_eq!(known, unknown);
+
+    let pending = bastion.control("keys.pending", json!({})).await;
+    let users: Vec<_> = pending
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|pending| pending["user"].as_str().unwrap())
+        .collect();
+    assert_eq!(users, ["tester"]);
+}
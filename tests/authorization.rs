@@ -0,0 +1,70 @@
+//! Asks an authorization hook about forwards through a bastion, which runs them only if
+//! it allows them.
+
+mod common;
+
+use common::Bastion;
+
+/// A hook that reads the request and prints `answer`.
+fn hook(answer: &str) -> String {
+    format!(
+        r#"
+[authorization]
+command = ["sh", "-c", "cat > /dev/null; echo \"$0\"", '{answer}']
+"#
+    )
+}
+
+#[tokio::test]
+async fn allowed_forwards_run() {
+    let bastion = Bastion::start_with(&hook(r#"{"decision": "allow"}"#)).await;
+    let (output, _, exit_status) = bastion.exec("tester+upstream", "download 100", b"").await;
+    assert_eq!(output.len(), 100);
+    assert_eq!(exit_status, Some(0));
+}
+
+#[tokio::test]
+async fn open_policy_agent_results_are_understood() {
+    let bastion = Bastion::start_with(&hook(r#"{"result": {"decision": "allow"}}"#)).await;
+    let (output, _, _) = bastion.exec("tester+upstream", "download 100", b"").await;
+    assert_eq!(output.len(), 100);
+}
+
+#[tokio::test]
+async fn denied_forwards_are_refused_with_the_reason() {
+    let bastion = Bastion::start_with(&hook(
+        r#"{"decision": "deny", "reason": "Change freeze until Monday"}"#,
+    ))
+    .await;
+    let (output, errors, exit_status) = bastion.exec("tester+upstream", "download 100", b"").await;
+    assert!(output.is_empty());
+    assert!(errors.contains("Change freeze until Monday"), "{errors}");
+    assert_eq!(exit_status, Some(1));
+}
+
+#[tokio::test]
+async fn forwards_needing_mfa_are_refused_without_it() {
+    let bastion = Bastion::start_with(&hook(r#"{"decision": "mfa_required"}"#)).await;
+    let (output, errors, _) = bastion.exec("tester+upstream", "download 100", b"").await;
+    assert!(output.is_empty());
+    assert!(errors.contains("verification code"), "{errors}");
+}
+
+#[tokio::test]
+async fn failing_hooks_deny() {
+    let bastion = Bastion::start_with(
+        r#"
+[authorization]
+command = ["sh", "-c", "exit 1"]
+"#,
+    )
+    .await;
+    let (output, errors, exit_status) = bastion.exec("tester+upstream", "download 100", b"").await;
+    assert!(output.is_empty());
+    assert!(errors.contains("could not be checked"), "{errors}");
+    assert_eq!(exit_status, Some(1));
+
+    let bastion = Bastion::start_with(&hook("not a decision")).await;
+    let (output, _, _) = bastion.exec("tester+upstream", "download 100", b"").await;
+    assert!(output.is_empty());
+}
@@ -0,0 +1,31 @@
+//! Connects through a bastion to a server whose first address cannot be reached, checking
+//! the next is used instead.
+
+mod common;
+
+use common::Bastion;
+
+#[tokio::test]
+async fn unreachable_backends_are_skipped() {
+    // Nothing listens on a port just given up, so connecting to it is refused.
+    let unused = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let bastion = Bastion::start_with(&format!(
+        r#"
+[[servers]]
+name = "pair"
+host = "127.0.0.1"
+port = {unused}
+backends = ["127.0.0.1:{{upstream_port}}"]
+tags = ["test"]
+"#
+    ))
+    .await;
+
+    let (output, _, exit_status) = bastion.exec("tester+pair", "cat", b"hello").await;
+    assert_eq!(output, b"hello");
+    assert_eq!(exit_status, Some(0));
+}
@@ -0,0 +1,59 @@
+//! Connects to a bastion with a pre-authentication banner, checking it is sent before
+//! logging in with its words filled in.
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::Bastion;
+use russh::client::{self, Session};
+use russh::keys::PublicKey;
+use tokio::sync::mpsc;
+
+/// Sends on the banners the bastion sends.
+struct BannerClient(mpsc::UnboundedSender<String>);
+
+impl client::Handler for BannerClient {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    async fn auth_banner(&mut self, banner: &str, _: &mut Session) -> Result<(), Self::Error> {
+        let _ = self.0.send(banner.to_string());
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn pre_auth_banner_is_sent_before_login() {
+    let bastion = Bastion::start_with(
+        r#"
+[banner]
+text = "Welcome {user}"
+pre_auth = "Authorized use only. Connection from {source_ip} is monitored."
+"#,
+    )
+    .await;
+    // Wait for the bastion to listen.
+    drop(bastion.connect_unauthenticated().await);
+
+    let (banners, mut received) = mpsc::unbounded_channel();
+    let config = Arc::new(client::Config::default());
+    let mut session = client::connect(config, ("127.0.0.1", bastion.port), BannerClient(banners))
+        .await
+        .unwrap();
+    let auth = session.authenticate_none("tester").await.unwrap();
+    assert!(!auth.success());
+
+    let banner = tokio::time::timeout(Duration::from_secs(10), received.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        banner,
+        "Authorized use only. Connection from 127.0.0.1 is monitored."
+    );
+}
@@ -0,0 +1,39 @@
+//! Runs `pukeko bench` against a bastion in the same process, through the menu and on to
+//! an upstream that echoes what it is sent.
+
+mod common;
+
+use std::time::Duration;
+
+use common::Bastion;
+use pukeko::bench::{self, BenchOptions, Operation};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn simulated_sessions_succeed() {
+    let bastion = Bastion::start().await;
+    // Waits for the bastion to listen.
+    bastion.connect().await;
+
+    let report = bench::run(BenchOptions {
+        target: format!("127.0.0.1:{}", bastion.port),
+        user: "tester".to_string(),
+        key: bastion.key_path.clone(),
+        clients: 4,
+        duration: Duration::from_secs(2),
+        keystrokes: 3,
+        server: Some("upstream".to_string()),
+        traffic: 64 * 1024,
+        chunk: 16 * 1024,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(report.failed(), 0, "{report}");
+    assert!(report.sessions > 0);
+    let sessions = report.sessions as usize;
+    assert_eq!(report.count(Operation::Connect), sessions * 2);
+    assert_eq!(report.count(Operation::Menu), sessions);
+    assert_eq!(report.count(Operation::Keystroke), sessions * 3);
+    assert_eq!(report.count(Operation::Echo), sessions * 4);
+}
@@ -0,0 +1,165 @@
+//! Issues certificates with `pukeko-cert` through a bastion, checking what they are valid
+//! for.
+
+mod common;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::Bastion;
+use rand_core::{OsRng, RngCore};
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{Algorithm, Certificate, PrivateKey};
+use serde_json::json;
+
+const VALIDITY: u64 = 300;
+
+/// Starts a bastion issuing certificates with `extra` added to its config, returning it
+/// with the certificate authority.
+async fn start(extra: &str) -> (Bastion, PrivateKey) {
+    let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+    let ca_path = std::env::temp_dir().join(format!(
+        "pukeko-ca-{}-{}",
+        std::process::id(),
+        OsRng.next_u32()
+    ));
+    ca.write_openssh_file(&ca_path, LineEnding::LF).unwrap();
+    let bastion = Bastion::start_with(&format!(
+        "[certificates]\nca_key = {:?}\nvalidity = {VALIDITY}\n{extra}",
+        ca_path.display().to_string()
+    ))
+    .await;
+    let _ = std::fs::remove_file(&ca_path);
+    (bastion, ca)
+}
+
+/// Has `login` issued a certificate for a new key, failing if it is not.
+async fn issue(bastion: &Bastion, login: &str) -> Certificate {
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+    let public_key = key.public_key().to_openssh().unwrap();
+    let command = format!("pukeko-cert {public_key}");
+    let (output, errors, exit_status) = bastion.exec(login, &command, b"").await;
+    assert_eq!(exit_status, Some(0), "{errors}");
+    let certificate = Certificate::from_openssh(String::from_utf8(output).unwrap().trim()).unwrap();
+    assert_eq!(certificate.public_key(), key.public_key().key_data());
+    certificate
+}
+
+#[tokio::test]
+async fn certificates_are_issued_for_the_users_logins() {
+    let (bastion, ca) = start("").await;
+    let certificate = issue(&bastion, "tester").await;
+    certificate
+        .validate([&ca.public_key().fingerprint(Default::default())])
+        .unwrap();
+    assert!(certificate.cert_type().is_user());
+    // The upstream sets no user, so is logged in to with the user's own name.
+    assert_eq!(certificate.valid_principals(), ["tester@upstream"]);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expires_in = Duration::from_secs(certificate.valid_before()) - now;
+    assert!(
+        expires_in <= Duration::from_secs(VALIDITY),
+        "{expires_in:?}"
+    );
+    let extensions = certificate.extensions();
+    assert!(extensions.contains_key("permit-pty"));
+    assert!(extensions.contains_key("permit-agent-forwarding"));
+    // The user may not forward ports or X11 through the bastion either.
+    assert!(!extensions.contains_key("permit-port-forwarding"));
+    assert!(!extensions.contains_key("permit-X11-forwarding"));
+}
+
+#[tokio::test]
+async fn certificates_are_refused_without_an_authority() {
+    let bastion = Bastion::start().await;
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+    let public_key = key.public_key().to_openssh().unwrap();
+    let command = format!("pukeko-cert {public_key}");
+    let (output, errors, exit_status) = bastion.exec("tester", &command, b"").await;
+    assert!(output.is_empty());
+    assert!(errors.contains("not issued"), "{errors}");
+    assert_eq!(exit_status, Some(1));
+}
+
+#[tokio::test]
+async fn certificates_leave_out_servers_the_authorization_hook_denies() {
+    let (bastion, _) = start(
+        r#"
+[[servers]]
+name = "second"
+host = "127.0.0.1"
+port = {upstream_port}
+tags = ["test"]
+
+[authorization]
+command = ["sh", "-c", '''
+if grep -q '"server":"upstream"'; then
+    echo '{"decision": "deny"}'
+else
+    echo '{"decision": "allow"}'
+fi''']
+"#,
+    )
+    .await;
+    let certificate = issue(&bastion, "tester").await;
+    assert_eq!(certificate.valid_principals(), ["tester@second"]);
+}
+
+#[tokio::test]
+async fn certificates_follow_the_users_schedule_and_forwarding_rules() {
+    // Opened an hour ago, and closes two to three minutes from now.
+    let now = chrono::Utc::now();
+    let opens = (now - chrono::TimeDelta::hours(1)).format("%H:%M");
+    let closes = (now + chrono::TimeDelta::minutes(3)).format("%H:%M");
+    let (bastion, _) = start(&format!(
+        r#"
+[[users]]
+name = "scheduled"
+servers = ["upstream"]
+keys = ["{{client_key}}"]
+schedule = "closing"
+agent_forwarding = false
+remote_forwards = ["127.0.0.1:*"]
+
+[[schedules]]
+name = "closing"
+start = "{opens}"
+end = "{closes}"
+timezone = "UTC"
+"#
+    ))
+    .await;
+    let certificate = issue(&bastion, "scheduled").await;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    let expires_in = Duration::from_secs(certificate.valid_before()) - now;
+    assert!(expires_in <= Duration::from_secs(180), "{expires_in:?}");
+    let extensions = certificate.extensions();
+    assert!(!extensions.contains_key("permit-agent-forwarding"));
+    assert!(extensions.contains_key("permit-port-forwarding"));
+}
+
+#[tokio::test]
+async fn certificates_leave_out_servers_under_maintenance_or_limited_to_some_commands() {
+    let (bastion, _) = start(
+        r#"
+[[servers]]
+name = "locked"
+host = "127.0.0.1"
+port = {upstream_port}
+tags = ["test"]
+
+[[servers]]
+name = "limited"
+host = "127.0.0.1"
+port = {upstream_port}
+tags = ["test"]
+commands = ["uptime"]
+"#,
+    )
+    .await;
+    bastion
+        .control("maintenance.start", json!({"server": "locked"}))
+        .await;
+    // Logging in to either directly would get around what the bastion holds them to.
+    let certificate = issue(&bastion, "tester").await;
+    assert_eq!(certificate.valid_principals(), ["tester@upstream"]);
+}
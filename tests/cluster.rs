@@ -0,0 +1,95 @@
+//! Runs two bastions as nodes of a cluster, checking a forward open through one counts
+//! towards the user's quota on the other, and that an address one bans the other refuses.
+//! Needs a PostgreSQL server to create a database on, given as a URL by
+//! PUKEKO_TEST_POSTGRES, and is ignored unless run with `--ignored`. A local server without
+//! TLS is reached with `?sslmode=disable` on the URL.
+
+#![cfg(feature = "postgres")]
+
+mod common;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{Bastion, Client, drain};
+use rand_core::{OsRng, RngCore};
+use russh::Channel;
+use russh::client::{self, Msg};
+
+/// Longer than it takes one node to publish a change and another to read it.
+const SYNCED: Duration = Duration::from_secs(3);
+
+/// Runs `cat` on the upstream in a new session, returning the session with its channel.
+async fn cat(bastion: &Bastion) -> (client::Handle<Client>, Channel<Msg>) {
+    let session = bastion.connect().await;
+    let channel = session.channel_open_session().await.unwrap();
+    channel.exec(true, "cat").await.unwrap();
+    (session, channel)
+}
+
+/// Starts a node named `node` of the cluster kept in the database at `url`.
+async fn node(url: &str, node: &str) -> Bastion {
+    Bastion::start_with(&format!(
+        r#"
+[quotas]
+max_forwards = 1
+
+[limits]
+max_auth_failures = 1
+
+[cluster]
+url = "{url}"
+node = "{node}"
+sync_interval = 1
+node_timeout = 5
+"#
+    ))
+    .await
+}
+
+#[tokio::test]
+#[ignore = "needs PUKEKO_TEST_POSTGRES"]
+async fn nodes_share_sessions_and_bans() {
+    let url = std::env::var("PUKEKO_TEST_POSTGRES").expect("PUKEKO_TEST_POSTGRES is not set");
+    let (admin, connection) = tokio_postgres::connect(&url, tokio_postgres::NoTls)
+        .await
+        .unwrap();
+    tokio::spawn(connection);
+    let database = format!("pukeko_test_{}_{}", std::process::id(), OsRng.next_u32());
+    admin
+        .batch_execute(&format!("CREATE DATABASE {database}"))
+        .await
+        .unwrap();
+    let separator = if url.contains('?') { '&' } else { '?' };
+    let cluster_url = format!("{url}{separator}dbname={database}");
+
+    let a = node(&cluster_url, "a").await;
+    let b = node(&cluster_url, "b").await;
+
+    let (_first_session, first) = cat(&a).await;
+    first.data(&b"hello"[..]).await.unwrap();
+    tokio::time::sleep(SYNCED).await;
+    let (_second_session, second) = cat(&b).await;
+    let (_, errors, exit_status) = drain(second).await;
+    assert!(errors.starts_with("pukeko: "), "{errors:?}");
+    assert_eq!(exit_status, Some(1));
+
+    let mut session = a.connect_unauthenticated().await;
+    let authenticated = session
+        .authenticate_password("tester+upstream", "wrong")
+        .await
+        .unwrap();
+    assert!(!authenticated.success());
+    tokio::time::sleep(SYNCED).await;
+    let config = Arc::new(client::Config::default());
+    assert!(
+        client::connect(config, ("127.0.0.1", b.port), Client)
+            .await
+            .is_err()
+    );
+
+    drop((a, b));
+    let _ = admin
+        .batch_execute(&format!("DROP DATABASE {database} WITH (FORCE)"))
+        .await;
+}
@@ -0,0 +1,335 @@
+//! A bastion and an upstream it connects to, both in the test's process.
+
+// Each test uses only some of it.
+#![allow(dead_code)]
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pukeko::{PukekoConfig, PukekoServer};
+use rand_core::{OsRng, RngCore};
+use russh::keys::ssh_key::LineEnding;
+use russh::keys::{Algorithm, PrivateKey, PrivateKeyWithHashAlg, PublicKey};
+use russh::server::{Auth, Msg, Server as _, Session};
+use russh::{Channel, ChannelId, ChannelMsg, client, server};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixStream};
+
+pub const CHUNK: usize = 32 * 1024;
+
+/// A bastion in front of an upstream that sends or counts as many bytes as asked, or
+/// echoes what it is sent back.
+pub struct Bastion {
+    directory: PathBuf,
+    pub port: u16,
+    /// File the key users authenticate with is written to.
+    pub key_path: PathBuf,
+    key: Arc<PrivateKey>,
+}
+
+impl Bastion {
+    pub async fn start() -> Self {
+        Self::start_with("").await
+    }
+
+    /// Starts a bastion with `extra` added to the end of its config, with
+    /// `{upstream_port}` in it replaced by the upstream's port and `{client_key}` by the
+    /// key users authenticate with. Servers it adds are allowed to `tester` when tagged
+    /// `test`.
+    pub async fn start_with(extra: &str) -> Self {
+        Self::start_with_settings("", extra).await
+    }
+
+    /// Like [`start_with`](Self::start_with), with `settings` added among the config's
+    /// top-level keys, before any table.
+    pub async fn start_with_settings(settings: &str, extra: &str) -> Self {
+        let directory = std::env::temp_dir().join(format!(
+            "pukeko-test-{}-{}",
+            std::process::id(),
+            OsRng.next_u32()
+        ));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let upstream_host_key = random_key();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_port = listener.local_addr().unwrap().port();
+        let config = Arc::new(server::Config {
+            keys: vec![upstream_host_key.clone()],
+            ..Default::default()
+        });
+        tokio::spawn(async move { Upstream.run_on_socket(config, &listener).await });
+
+        let upstream_key = directory.join("upstream_key");
+        random_key()
+            .write_openssh_file(&upstream_key, LineEnding::LF)
+            .unwrap();
+        std::fs::write(
+            directory.join("known_hosts"),
+            format!(
+                "[127.0.0.1]:{upstream_port} {}\n",
+                upstream_host_key.public_key().to_openssh().unwrap()
+            ),
+        )
+        .unwrap();
+        let key = random_key();
+        let key_path = directory.join("client_key");
+        key.write_openssh_file(&key_path, LineEnding::LF).unwrap();
+        let extra = extra
+            .replace("{upstream_port}", &upstream_port.to_string())
+            .replace("{client_key}", &key.public_key().to_openssh().unwrap());
+        let config_path = directory.join("pukeko.toml");
+        std::fs::write(
+            &config_path,
+            format!(
+                r#"
+upstream_key = "upstream_key"
+known_hosts = "known_hosts"
+state_directory = "state"
+control_socket = "control.sock"
+{settings}
+
+[[users]]
+name = "tester"
+servers = ["upstream", "tag:test"]
+keys = ["{}"]
+
+[[servers]]
+name = "upstream"
+host = "127.0.0.1"
+port = {upstream_port}
+
+{extra}
+"#,
+                key.public_key().to_openssh().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let config = PukekoConfig::load(&config_path).unwrap();
+        let (config_sender, config) = tokio::sync::watch::channel(Arc::new(config));
+        tokio::spawn(async move {
+            let _config_sender = config_sender;
+            PukekoServer::builder(config)
+                .listen_address(([127, 0, 0, 1], port).into())
+                .systemd(false)
+                .build()
+                .run()
+                .await
+        });
+        Self {
+            directory,
+            port,
+            key_path,
+            key: Arc::new(key),
+        }
+    }
+
+    pub async fn connect(&self) -> client::Handle<Client> {
+        self.connect_as("tester+upstream").await
+    }
+
+    /// Connects and logs in as `login`, such as a bare `tester` for the menu.
+    pub async fn connect_as(&self, login: &str) -> client::Handle<Client> {
+        let mut session = self.connect_unauthenticated().await;
+        let authenticated = session
+            .authenticate_publickey(login, PrivateKeyWithHashAlg::new(self.key.clone(), None))
+            .await
+            .unwrap();
+        assert!(authenticated.success());
+        session
+    }
+
+    /// Runs `command` as `login`, sending `input` and then EOF unless `input` is empty.
+    /// Returns its output, its errors and how it exited.
+    pub async fn exec(
+        &self,
+        login: &str,
+        command: &str,
+        input: &[u8],
+    ) -> (Vec<u8>, String, Option<u32>) {
+        let session = self.connect_as(login).await;
+        let channel = session.channel_open_session().await.unwrap();
+        channel.exec(true, command).await.unwrap();
+        if !input.is_empty() {
+            channel.data(input).await.unwrap();
+            channel.eof().await.unwrap();
+        }
+        drain(channel).await
+    }
+
+    /// Calls `method` with `params` on the bastion's control socket, returning the result.
+    pub async fn control(&self, method: &str, params: serde_json::Value) -> serde_json::Value {
+        // Bound before the bastion listens for SSH, so waiting for that waits for this.
+        drop(self.connect_unauthenticated().await);
+        let stream = UnixStream::connect(self.directory.join("control.sock"))
+            .await
+            .unwrap();
+        let (reader, mut writer) = stream.into_split();
+        let request = serde_json::json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+            "id": 1,
+        });
+        writer
+            .write_all(format!("{request}\n").as_bytes())
+            .await
+            .unwrap();
+        let mut response = String::new();
+        BufReader::new(reader)
+            .read_line(&mut response)
+            .await
+            .unwrap();
+        let mut response: serde_json::Value = serde_json::from_str(&response).unwrap();
+        assert!(response["error"].is_null(), "{response}");
+        response["result"].take()
+    }
+
+    /// Connects without logging in, waiting for the bastion to start listening.
+    pub async fn connect_unauthenticated(&self) -> client::Handle<Client> {
+        let config = Arc::new(client::Config::default());
+        let mut attempts = 0;
+        loop {
+            match client::connect(config.clone(), ("127.0.0.1", self.port), Client).await {
+                Ok(session) => return session,
+                Err(_) if attempts < 50 => {
+                    attempts += 1;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                Err(e) => panic!("Failed to connect to the bastion: {e}"),
+            }
+        }
+    }
+}
+
+/// Reads `channel` until it closes, returning its output, its errors and how it exited.
+pub async fn drain(mut channel: Channel<client::Msg>) -> (Vec<u8>, String, Option<u32>) {
+    let (mut output, mut errors, mut exit_status) = (Vec::new(), String::new(), None);
+    while let Some(message) = channel.wait().await {
+        match message {
+            ChannelMsg::Data { data } => output.extend_from_slice(&data),
+            ChannelMsg::ExtendedData { data, .. } => {
+                errors.push_str(&String::from_utf8_lossy(&data))
+            }
+            ChannelMsg::ExitStatus {
+                exit_status: status,
+            } => exit_status = Some(status),
+            ChannelMsg::Close => break,
+            _ => {}
+        }
+    }
+    (output, errors, exit_status)
+}
+
+impl Drop for Bastion {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+fn random_key() -> PrivateKey {
+    PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()
+}
+
+pub struct Client;
+
+impl client::Handler for Client {
+    type Error = russh::Error;
+
+    async fn check_server_key(&mut self, _: &PublicKey) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+#[derive(Clone)]
+struct Upstream;
+
+impl server::Server for Upstream {
+    type Handler = UpstreamSession;
+
+    fn new_client(&mut self, _: Option<std::net::SocketAddr>) -> UpstreamSession {
+        UpstreamSession {
+            channels: Default::default(),
+        }
+    }
+}
+
+struct UpstreamSession {
+    channels: std::collections::HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl server::Handler for UpstreamSession {
+    type Error = russh::Error;
+
+    async fn auth_publickey(&mut self, _: &str, _: &PublicKey) -> Result<Auth, Self::Error> {
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn exec_request(
+        &mut self,
+        id: ChannelId,
+        command: &[u8],
+        session: &mut Session,
+    ) -> Result<(), Self::Error> {
+        session.channel_success(id)?;
+        let command = String::from_utf8_lossy(command).into_owned();
+        let channel = self.channels.remove(&id).unwrap();
+        let handle = session.handle();
+        tokio::spawn(async move {
+            let (mut reader, writer) = channel.split();
+            if let Some(size) = command.strip_prefix("download ") {
+                // Unread, it would hold up the connection once full of window adjustments.
+                drop(reader);
+                let size: u64 = size.parse().unwrap();
+                // Waits for the bastion's window, so is only as fast as the client reads.
+                let mut output = writer.make_writer();
+                let chunk = vec![0; CHUNK];
+                let mut sent = 0;
+                while sent < size {
+                    let len = CHUNK.min((size - sent) as usize);
+                    output.write_all(&chunk[..len]).await.unwrap();
+                    sent += len as u64;
+                }
+            } else if command == "cat" {
+                while let Some(message) = reader.wait().await {
+                    match message {
+                        ChannelMsg::Data { data } => {
+                            let _ = writer.data(&data[..]).await;
+                        }
+                        ChannelMsg::Eof => break,
+                        _ => {}
+                    }
+                }
+            } else {
+                let mut uploaded = 0;
+                while let Some(message) = reader.wait().await {
+                    match message {
+                        ChannelMsg::Data { data } => uploaded += data.len() as u64,
+                        ChannelMsg::Eof => break,
+                        _ => {}
+                    }
+                }
+                let _ = writer.data(uploaded.to_string().as_bytes()).await;
+            }
+            let _ = handle.exit_status_request(id, 0).await;
+            let _ = handle.eof(id).await;
+            let _ = handle.close(id).await;
+        });
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+//! Asks a bastion to listen for `ssh -R` forwards, checking each is kept and cancelled by
+//! the port it was bound to, and jumps through it with `ssh -J`.
+
+mod common;
+
+use common::Bastion;
+use serde_json::json;
+use tokio::net::TcpStream;
+
+#[tokio::test]
+async fn forwards_to_ports_the_bastion_picks_are_kept_apart() {
+    let bastion = Bastion::start_with(
+        r#"
+[[users]]
+name = "forwarder"
+servers = ["upstream"]
+keys = ["{client_key}"]
+remote_forwards = ["127.0.0.1:*"]
+"#,
+    )
+    .await;
+    let mut session = bastion.connect_as("forwarder").await;
+    let first = session.tcpip_forward("127.0.0.1", 0).await.unwrap();
+    let second = session.tcpip_forward("127.0.0.1", 0).await.unwrap();
+    assert_ne!(first, second);
+
+    session
+        .cancel_tcpip_forward("127.0.0.1", first)
+        .await
+        .unwrap();
+    // Cancelling the first leaves the second listening.
+    TcpStream::connect(("127.0.0.1", second as u16))
+        .await
+        .unwrap();
+    assert!(
+        session
+            .cancel_tcpip_forward("127.0.0.1", first)
+            .await
+            .is_err()
+    );
+    session
+        .cancel_tcpip_forward("127.0.0.1", second)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn jumps_to_servers_under_maintenance_are_refused() {
+    let bastion = Bastion::start_with(
+        r#"
+[proxy_jump]
+enabled = true
+rewrite = true
+"#,
+    )
+    .await;
+    let session = bastion.connect_as("tester").await;
+    session
+        .channel_open_direct_tcpip("upstream", 22, "127.0.0.1", 0)
+        .await
+        .unwrap();
+
+    bastion
+        .control("maintenance.start", json!({"server": "upstream"}))
+        .await;
+    assert!(
+        session
+            .channel_open_direct_tcpip("upstream", 22, "127.0.0.1", 0)
+            .await
+            .is_err()
+    );
+}
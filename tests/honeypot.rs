@@ -0,0 +1,92 @@
+//! Logs in to a bastion's honeypot as a decoy user and by guessing passwords, checking it
+//! shows only fake servers and refuses everything.
+
+mod common;
+
+use std::time::Duration;
+
+use common::Bastion;
+use russh::client::Msg;
+use russh::{Channel, ChannelMsg};
+
+const HONEYPOT: &str = r#"
+[honeypot]
+users = ["root"]
+after_failures = 2
+servers = ["db-prod-01", "vault-01"]
+delay = 0
+"#;
+
+/// Reads `channel` until its output ends with `expected`, returning all of it.
+async fn read_until(channel: &mut Channel<Msg>, expected: &str) -> String {
+    let mut output = String::new();
+    while !output.ends_with(expected) {
+        let message = tokio::time::timeout(Duration::from_secs(10), channel.wait())
+            .await
+            .unwrap_or_else(|_| panic!("Timed out waiting for {expected:?} in {output:?}"));
+        match message {
+            Some(ChannelMsg::Data { data }) => output.push_str(&String::from_utf8_lossy(&data)),
+            Some(_) => {}
+            None => panic!("Closed before {expected:?} in {output:?}"),
+        }
+    }
+    output
+}
+
+#[tokio::test]
+async fn decoy_users_get_a_menu_of_fake_servers() {
+    let bastion = Bastion::start_with(HONEYPOT).await;
+    let session = bastion.connect_as("root").await;
+    let mut channel = session.channel_open_session().await.unwrap();
+    channel
+        .request_pty(true, "xterm", 80, 24, 0, 0, &[])
+        .await
+        .unwrap();
+    channel.request_shell(true).await.unwrap();
+
+    let menu = read_until(&mut channel, "Server: ").await;
+    assert!(menu.contains("1) db-prod-01"), "{menu}");
+    assert!(menu.contains("2) vault-01"), "{menu}");
+    assert!(!menu.contains("upstream"), "{menu}");
+
+    channel.data(&b"1\r"[..]).await.unwrap();
+    read_until(&mut channel, "root@db-prod-01:~$ ").await;
+    channel.data(&b"cat /etc/shadow\r"[..]).await.unwrap();
+    let output = read_until(&mut channel, "root@db-prod-01:~$ ").await;
+    assert!(output.contains("cat: Permission denied"), "{output}");
+
+    channel.data(&b"exit\r"[..]).await.unwrap();
+    read_until(&mut channel, "Server: ").await;
+}
+
+#[tokio::test]
+async fn decoy_users_cannot_run_commands() {
+    let bastion = Bastion::start_with(HONEYPOT).await;
+    let (output, _, exit_status) = bastion.exec("root+upstream", "id", b"").await;
+    assert_eq!(output, b"id: Permission denied\n");
+    assert_eq!(exit_status, Some(1));
+}
+
+#[tokio::test]
+async fn wrong_passwords_lead_into_the_honeypot() {
+    let bastion = Bastion::start_with(HONEYPOT).await;
+    let mut session = bastion.connect_unauthenticated().await;
+    for _ in 0..2 {
+        let auth = session
+            .authenticate_password("tester", "hunter2")
+            .await
+            .unwrap();
+        assert!(!auth.success());
+    }
+    let auth = session
+        .authenticate_password("tester", "hunter2")
+        .await
+        .unwrap();
+    assert!(auth.success());
+
+    let mut channel = session.channel_open_session().await.unwrap();
+    channel.request_shell(true).await.unwrap();
+    let menu = read_until(&mut channel, "Server: ").await;
+    assert!(menu.contains("db-prod-01"), "{menu}");
+    assert!(!menu.contains("upstream"), "{menu}");
+}
@@ -0,0 +1,64 @@
+//! Fails to log in to a bastion that bans addresses after failed logins, checking what
+//! counts towards the ban.
+
+mod common;
+
+use std::sync::Arc;
+
+use common::Bastion;
+use rand_core::OsRng;
+use russh::keys::{Algorithm, PrivateKey, PrivateKeyWithHashAlg};
+
+#[tokio::test]
+async fn offered_keys_are_not_counted_as_failed_logins() {
+    let bastion = Bastion::start_with(
+        r#"
+[limits]
+max_auth_failures = 3
+"#,
+    )
+    .await;
+    // Keys the bastion refuses when offered are never signed with, so are not failed
+    // logins, however many the client has.
+    let mut session = bastion.connect_unauthenticated().await;
+    for _ in 0..3 {
+        let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let authenticated = session
+            .authenticate_publickey(
+                "tester+upstream",
+                PrivateKeyWithHashAlg::new(Arc::new(key), None),
+            )
+            .await
+            .unwrap();
+        assert!(!authenticated.success());
+    }
+    bastion.connect().await;
+}
+
+#[tokio::test]
+async fn right_passwords_sent_before_the_key_are_not_counted_as_failed_logins() {
+    let bastion = Bastion::start_with(&format!(
+        r#"
+[limits]
+max_auth_failures = 2
+
+[[users]]
+name = "both"
+servers = ["upstream"]
+keys = ["{{client_key}}"]
+password_hash = "{}"
+password_and_key = true
+"#,
+        pukeko::password::hash("secret").unwrap()
+    ))
+    .await;
+    let mut session = bastion.connect_unauthenticated().await;
+    for _ in 0..2 {
+        let authenticated = session
+            .authenticate_password("both+upstream", "secret")
+            .await
+            .unwrap();
+        assert!(!authenticated.success());
+    }
+    bastion.connect().await;
+}
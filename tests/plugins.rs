@@ -0,0 +1,100 @@
+//! Logs in to a bastion with plugins that refuse logins or fail to answer, checking the
+//! login is refused and the user told why.
+
+#![cfg(feature = "wasm")]
+
+mod common;
+
+use std::sync::Arc;
+
+use common::Bastion;
+use rand_core::{OsRng, RngCore};
+use russh::client::KeyboardInteractiveAuthResponse;
+use russh::keys::PrivateKeyWithHashAlg;
+use russh::keys::ssh_key::PrivateKey;
+
+const REPLY: &str = r#"{"allow":false,"reason":"Outside business hours"}"#;
+
+/// A plugin refusing every login with `REPLY`, written in the text format.
+fn refusing_plugin() -> String {
+    let data = REPLY.replace('"', "\\\"");
+    format!(
+        r#"(module
+  (memory (export "memory") 1)
+  (data (i32.const 0) "{data}")
+  (func (export "pukeko_api_version") (result i32) i32.const 1)
+  (func (export "pukeko_alloc") (param i32) (result i32) i32.const 1024)
+  (func (export "pukeko_on_auth") (param i32 i32) (result i64)
+    i64.const {len}))
+"#,
+        len = REPLY.len()
+    )
+}
+
+/// A plugin whose `pukeko_on_auth` traps rather than answering.
+const FAILING_PLUGIN: &str = r#"(module
+  (memory (export "memory") 1)
+  (func (export "pukeko_api_version") (result i32) i32.const 1)
+  (func (export "pukeko_alloc") (param i32) (result i32) i32.const 1024)
+  (func (export "pukeko_on_auth") (param i32 i32) (result i64)
+    unreachable))
+"#;
+
+/// Logs in to a bastion running `plugin`, which must refuse the login, returning what the
+/// user is told.
+async fn refused_by(plugin: &str) -> (String, String) {
+    let directory = std::env::temp_dir().join(format!(
+        "pukeko-plugin-{}-{}",
+        std::process::id(),
+        OsRng.next_u32()
+    ));
+    std::fs::create_dir_all(&directory).unwrap();
+    let path = directory.join("plugin.wat");
+    std::fs::write(&path, plugin).unwrap();
+
+    let bastion = Bastion::start_with(&format!(
+        r#"
+[[plugins]]
+path = "{}"
+"#,
+        path.display()
+    ))
+    .await;
+    let key = PrivateKey::read_openssh_file(&bastion.key_path).unwrap();
+    let mut session = bastion.connect_unauthenticated().await;
+    let authenticated = session
+        .authenticate_publickey(
+            "tester+upstream",
+            PrivateKeyWithHashAlg::new(Arc::new(key), None),
+        )
+        .await
+        .unwrap();
+    assert!(!authenticated.success());
+
+    let response = session
+        .authenticate_keyboard_interactive_start("tester+upstream", None)
+        .await
+        .unwrap();
+    let KeyboardInteractiveAuthResponse::InfoRequest {
+        name, instructions, ..
+    } = response
+    else {
+        panic!("Expected to be told why, got {response:?}");
+    };
+    let _ = std::fs::remove_dir_all(&directory);
+    (name, instructions)
+}
+
+#[tokio::test]
+async fn plugins_refuse_logins() {
+    let (name, instructions) = refused_by(&refusing_plugin()).await;
+    assert_eq!(name, "Access denied");
+    assert_eq!(instructions, "Outside business hours");
+}
+
+#[tokio::test]
+async fn plugins_that_fail_refuse_logins() {
+    let (name, instructions) = refused_by(FAILING_PLUGIN).await;
+    assert_eq!(name, "Access denied");
+    assert_eq!(instructions, "Access denied");
+}
@@ -0,0 +1,47 @@
+//! Opens forwards through a bastion past a user's quota, checking the one over it is refused
+//! with the reason and that closing another makes room.
+
+mod common;
+
+use common::{Bastion, Client, drain};
+use russh::Channel;
+use russh::client::{self, Msg};
+
+/// Runs `cat` on the upstream in a new session, returning the session with its channel.
+async fn cat(bastion: &Bastion) -> (client::Handle<Client>, Channel<Msg>) {
+    let session = bastion.connect().await;
+    let channel = session.channel_open_session().await.unwrap();
+    channel.exec(true, "cat").await.unwrap();
+    (session, channel)
+}
+
+#[tokio::test]
+async fn forwards_past_the_quota_are_refused() {
+    let bastion = Bastion::start_with(
+        r#"
+[quotas]
+max_forwards_per_server = 1
+"#,
+    )
+    .await;
+
+    let (_first_session, first) = cat(&bastion).await;
+    first.data(&b"hello"[..]).await.unwrap();
+
+    let (_second_session, second) = cat(&bastion).await;
+    let (_, errors, exit_status) = drain(second).await;
+    assert_eq!(
+        errors,
+        "pukeko: You already have as many connections to upstream open as allowed (1)\r\n"
+    );
+    assert_eq!(exit_status, Some(1));
+
+    first.eof().await.unwrap();
+    let (output, _, exit_status) = drain(first).await;
+    assert_eq!(output, b"hello");
+    assert_eq!(exit_status, Some(0));
+
+    let (_third_session, again) = cat(&bastion).await;
+    again.eof().await.unwrap();
+    assert_eq!(drain(again).await.2, Some(0));
+}
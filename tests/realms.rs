@@ -0,0 +1,140 @@
+//! Logs in to a bastion with a realm, checking the realm's users reach its servers and
+//! nothing else, and that users outside it cannot reach them.
+
+mod common;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use common::Bastion;
+use rand_core::{OsRng, RngCore};
+use russh::keys::ssh_key::{Certificate, LineEnding, PrivateKey};
+use russh::keys::{Algorithm, PrivateKeyWithHashAlg};
+
+/// Runs `cat` on `login`'s server with `input`, returning its output and exit status.
+async fn cat(bastion: &Bastion, login: &str, input: &[u8]) -> (Vec<u8>, Option<u32>) {
+    let (output, _, exit_status) = bastion.exec(login, "cat", input).await;
+    (output, exit_status)
+}
+
+/// Writes a new certificate authority, returning it with the path it was written to.
+fn authority() -> (PrivateKey, PathBuf) {
+    let ca = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+    let path = std::env::temp_dir().join(format!(
+        "pukeko-ca-{}-{}",
+        std::process::id(),
+        OsRng.next_u32()
+    ));
+    ca.write_openssh_file(&path, LineEnding::LF).unwrap();
+    (ca, path)
+}
+
+/// Whether `login` is let in.
+async fn logs_in(bastion: &Bastion, login: &str) -> bool {
+    let key = PrivateKey::read_openssh_file(&bastion.key_path).unwrap();
+    let mut session = bastion.connect_unauthenticated().await;
+    session
+        .authenticate_publickey(login, PrivateKeyWithHashAlg::new(Arc::new(key), None))
+        .await
+        .unwrap()
+        .success()
+}
+
+#[tokio::test]
+async fn realms_keep_their_users_and_servers_apart() {
+    let bastion = Bastion::start_with(
+        r#"
+[[realms]]
+name = "payments"
+
+[[realms.users]]
+name = "alice"
+servers = ["ledger"]
+keys = ["{client_key}"]
+
+[[realms.servers]]
+name = "ledger"
+host = "127.0.0.1"
+port = {upstream_port}
+"#,
+    )
+    .await;
+
+    let (output, exit_status) = cat(&bastion, "alice@payments+ledger", b"hello").await;
+    assert_eq!(output, b"hello");
+    assert_eq!(exit_status, Some(0));
+
+    let (output, exit_status) = cat(&bastion, "alice@payments+upstream", b"hello").await;
+    assert!(output.is_empty());
+    assert_ne!(exit_status, Some(0));
+
+    assert!(!logs_in(&bastion, "alice+ledger").await);
+    assert!(!logs_in(&bastion, "tester@payments+ledger").await);
+
+    let (output, exit_status) = cat(&bastion, "tester+ledger", b"hello").await;
+    assert!(output.is_empty());
+    assert_ne!(exit_status, Some(0));
+}
+
+#[tokio::test]
+async fn realms_are_issued_certificates_only_by_their_own_authority() {
+    let (deployment_ca, deployment_ca_path) = authority();
+    let (realm_ca, realm_ca_path) = authority();
+    let bastion = Bastion::start_with(&format!(
+        r#"
+[certificates]
+ca_key = {deployment_ca_path:?}
+
+[[realms]]
+name = "payments"
+
+[[realms.users]]
+name = "alice"
+servers = ["ledger"]
+keys = ["{{client_key}}"]
+
+[[realms.servers]]
+name = "ledger"
+host = "127.0.0.1"
+port = {{upstream_port}}
+
+[[realms]]
+name = "billing"
+
+[realms.certificates]
+ca_key = {realm_ca_path:?}
+
+[[realms.users]]
+name = "bob"
+servers = ["invoices"]
+keys = ["{{client_key}}"]
+
+[[realms.servers]]
+name = "invoices"
+host = "127.0.0.1"
+port = {{upstream_port}}
+"#
+    ))
+    .await;
+    let _ = std::fs::remove_file(&deployment_ca_path);
+    let _ = std::fs::remove_file(&realm_ca_path);
+
+    let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+    let command = format!("pukeko-cert {}", key.public_key().to_openssh().unwrap());
+
+    let (output, errors, exit_status) = bastion.exec("alice@payments", &command, b"").await;
+    assert!(output.is_empty());
+    assert!(errors.contains("not issued"), "{errors}");
+    assert_eq!(exit_status, Some(1));
+
+    let (output, errors, exit_status) = bastion.exec("bob@billing", &command, b"").await;
+    assert_eq!(exit_status, Some(0), "{errors}");
+    let certificate = Certificate::from_openssh(String::from_utf8(output).unwrap().trim()).unwrap();
+    let fingerprint = |ca: &PrivateKey| ca.public_key().fingerprint(Default::default());
+    certificate.validate([&fingerprint(&realm_ca)]).unwrap();
+    assert!(
+        certificate
+            .validate([&fingerprint(&deployment_ca)])
+            .is_err()
+    );
+}
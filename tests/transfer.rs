@@ -0,0 +1,93 @@
+//! Pushes large transfers through a bastion to an upstream in the same process, checking
+//! that neither direction is buffered in memory when one side is slower than the other.
+
+mod common;
+
+use common::{Bastion, CHUNK};
+use russh::ChannelMsg;
+use tokio::io::AsyncWriteExt;
+
+/// How much the process may grow by during a transfer, which is far less than is sent.
+const MAX_GROWTH: u64 = 128 * 1024 * 1024;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_transfers_are_not_buffered() {
+    transfer(256 * 1024 * 1024).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[ignore = "takes minutes without --release"]
+async fn multi_gigabyte_transfers_are_not_buffered() {
+    transfer(4 * 1024 * 1024 * 1024).await;
+}
+
+async fn transfer(size: u64) {
+    let bastion = Bastion::start().await;
+
+    let before = rss();
+    let session = bastion.connect().await;
+    let mut download = session.channel_open_session().await.unwrap();
+    download
+        .exec(true, format!("download {size}"))
+        .await
+        .unwrap();
+    let mut received = 0;
+    while let Some(message) = download.wait().await {
+        match message {
+            ChannelMsg::Data { data } => received += data.len() as u64,
+            ChannelMsg::Close => break,
+            _ => {}
+        }
+    }
+    assert_eq!(received, size);
+    let growth = peak_rss() - before;
+    assert!(growth < MAX_GROWTH, "grew by {growth} bytes downloading");
+
+    let before = rss();
+    let upload = session.channel_open_session().await.unwrap();
+    upload.exec(true, "upload").await.unwrap();
+    // Read while writing, as russh holds up the connection once a channel's unread
+    // messages fill its buffer.
+    let (mut reader, writer) = upload.split();
+    let reply = tokio::spawn(async move {
+        let mut reply = Vec::new();
+        while let Some(message) = reader.wait().await {
+            match message {
+                ChannelMsg::Data { data } => reply.extend_from_slice(&data),
+                ChannelMsg::Close => break,
+                _ => {}
+            }
+        }
+        reply
+    });
+    let mut input = writer.make_writer();
+    let chunk = vec![0; CHUNK];
+    let mut sent = 0;
+    while sent < size {
+        let len = CHUNK.min((size - sent) as usize);
+        input.write_all(&chunk[..len]).await.unwrap();
+        sent += len as u64;
+    }
+    input.shutdown().await.unwrap();
+    let reply = reply.await.unwrap();
+    assert_eq!(String::from_utf8_lossy(&reply), size.to_string());
+    let growth = peak_rss() - before;
+    assert!(growth < MAX_GROWTH, "grew by {growth} bytes uploading");
+}
+
+fn rss() -> u64 {
+    status_kilobytes("VmRSS:") * 1024
+}
+
+fn peak_rss() -> u64 {
+    status_kilobytes("VmHWM:") * 1024
+}
+
+fn status_kilobytes(field: &str) -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(field))
+        .and_then(|value| value.trim().trim_end_matches("kB").trim().parse().ok())
+        .unwrap()
+}